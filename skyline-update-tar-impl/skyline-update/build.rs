@@ -0,0 +1,24 @@
+use std::process::Command;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Output of a command, None if it can't be run (no git, not a checkout, ...)
+fn command_output(program: &str, args: &[&str]) -> Option<String> {
+    let output = Command::new(program).args(args).output().ok()?;
+    let ok = output.status.success();
+    let output = String::from_utf8(output.stdout).ok().filter(|_| ok)?;
+
+    Some(output.trim().to_owned()).filter(|output| !output.is_empty())
+}
+
+fn main() {
+    let commit = command_output("git", &["rev-parse", "--short", "HEAD"])
+        .unwrap_or_else(|| String::from("unknown commit"));
+    let timestamp = command_output("date", &["-u", "+%Y-%m-%dT%H:%M:%SZ"])
+        .unwrap_or_else(|| {
+            let secs = SystemTime::now().duration_since(UNIX_EPOCH).map(|time| time.as_secs()).unwrap_or(0);
+            format!("{} seconds after the unix epoch", secs)
+        });
+
+    println!("cargo:rustc-env=BUILD_COMMIT={}", commit);
+    println!("cargo:rustc-env=BUILD_TIMESTAMP={}", timestamp);
+}
@@ -0,0 +1,73 @@
+//! Installs the example plugins hosted by update-server's `host` example into a folder standing
+//! in for the SD card, printing each step.
+//!
+//! ```text
+//! cargo run -p skyline-update --features logger --example client -- <server ip or hostname> <port> [sandbox folder] [--json] [--platform <name>]
+//! ```
+
+mod walkthrough;
+
+use std::path::PathBuf;
+use std::time::Instant;
+
+use skyline_update::{ExitStatus, PluginStatus, StatusLine, UpdateClient};
+
+const USAGE: &str = "usage: client <server ip or hostname> <port> [sandbox folder] [--json] [--platform <name>]
+
+    --json             print a single-line JSON status last, with the status, versions, bytes
+                       installed and duration
+    --platform <name>  platform to ask for the files of: switch, emulator or pc (the default)";
+
+fn main() {
+    skyline_update::init_logger(log::LevelFilter::Info).unwrap();
+
+    let mut args: Vec<String> = std::env::args().skip(1).collect();
+    if args.iter().any(|arg| arg == "--help") {
+        println!("{}\n\n{}", USAGE, ExitStatus::help());
+        return
+    }
+    let json = args.iter().any(|arg| arg == "--json");
+    args.retain(|arg| arg != "--json");
+    let platform = match args.iter().position(|arg| arg == "--platform") {
+        Some(idx) if idx + 1 < args.len() => args.drain(idx..idx + 2).nth(1).unwrap(),
+        Some(_) => {
+            println!("{}", USAGE);
+            std::process::exit(ExitStatus::Usage.code());
+        }
+        None => String::from("pc"),
+    };
+
+    let (host, port) = match (args.first(), args.get(1).and_then(|port| port.parse().ok())) {
+        (Some(host), Some(port)) => (host, port),
+        _ => {
+            println!("{}", USAGE);
+            std::process::exit(ExitStatus::Usage.code());
+        }
+    };
+    let sandbox = args.get(2)
+        .map(PathBuf::from)
+        .unwrap_or_else(|| std::env::temp_dir().join("skyline-update-example-sd"));
+
+    println!("Installing into {}", sandbox.display());
+    let start = Instant::now();
+    let client = UpdateClient::with_host(host).with_port(port).with_platform(&platform);
+    let reports = walkthrough::run(&client, &sandbox);
+
+    let failed = reports.iter().filter(|(_, report)| report.status().is_err()).count();
+    if failed > 0 {
+        println!("{} plugin(s) failed to update", failed);
+    }
+
+    let status = StatusLine {
+        plugins: walkthrough::PLUGINS.iter()
+            .zip(&reports)
+            .map(|(&(name, version), (_, report))| PluginStatus::from_report(name, version, report))
+            .collect(),
+        duration: start.elapsed(),
+    };
+    if json {
+        println!("{}", status.to_json());
+    }
+
+    std::process::exit(status.status().code());
+}
@@ -0,0 +1,60 @@
+//! A full check and install of the example plugins, the way a plugin would update itself at
+//! boot, into a sandbox instead of the SD card. Shared by the `client` example and the
+//! integration test which keeps the examples working.
+
+use std::path::Path;
+
+use skyline_update::{ExitStatus, FileOutcome, ImageSelection, SandboxInstaller, UpdateClient, UpdateReport};
+
+/// The plugins hosted by the `host` example of update-server, with the version pretended to be
+/// installed so both are offered an update
+pub const PLUGINS: &[(&str, &str)] = &[
+    ("example_loose_files", "0.9.0"),
+    ("example_folder", "0.9.0"),
+];
+
+/// Check every example plugin for an update and install it into `sandbox`, printing each step.
+/// Returns the report of every plugin.
+pub fn run(client: &UpdateClient, sandbox: &Path) -> Vec<(String, UpdateReport)> {
+    let installer = SandboxInstaller::new(sandbox);
+
+    PLUGINS.iter()
+        .map(|&(name, version)| {
+            println!("== {} (installed version {})", name, version);
+
+            // what a plugin browser would show, only the icon is downloaded
+            match client.get_metadata_with_images(name, ImageSelection::IconOnly) {
+                Some((metadata, images)) => println!(
+                    "metadata: {:?}, {:?}, {} of {} image(s) downloaded",
+                    metadata.display_name.as_ref().or(metadata.name.as_ref()),
+                    metadata.description,
+                    images.len(),
+                    metadata.image_count,
+                ),
+                None => println!("metadata: none"),
+            }
+
+            let report = client.custom_check_update_report(name, version, false, &installer);
+            if let Some(response) = &report.response {
+                println!("server: {:?}, version {} of {}", response.code, response.new_plugin_version, response.display_name());
+            }
+            for file in &report.files {
+                let outcome = match &file.outcome {
+                    FileOutcome::Installed => String::from("installed"),
+                    FileOutcome::AlreadyInstalled => String::from("already installed"),
+                    FileOutcome::Skipped => String::from("skipped"),
+                    FileOutcome::Failed(e) => format!("failed: {}", e),
+                    FileOutcome::RolledBack => String::from("rolled back"),
+                    FileOutcome::Excluded => String::from("left out"),
+                };
+                println!("    {:?}: {}", file.install_location, outcome);
+            }
+            println!(
+                "status: {} (request {:?}, download {:?}, install {:?})",
+                ExitStatus::from(&report.status()).name(), report.timings.request, report.timings.download, report.timings.install
+            );
+
+            (name.to_owned(), report)
+        })
+        .collect()
+}
@@ -0,0 +1,471 @@
+//! Async variants of the client functions, for plugins already running a tokio runtime.
+//! Enabled by the `tokio` feature.
+//!
+//! Requests and downloads use `tokio::net::TcpStream`, so no thread is blocked while waiting
+//! on the server. Requests are encoded, responses parsed and archives extracted by the same
+//! code as the blocking functions, extraction running on tokio's blocking threads. Files are
+//! downloaded into memory one at a time and installed in order. Resuming interrupted updates,
+//! staging downloads, backups, archives streamed while downloading and TLS are only done by the
+//! blocking functions.
+//!
+//! ```no_run
+//! # async fn run() {
+//! let ip = "127.0.0.1".parse().unwrap();
+//! skyline_update::asynchronous::check_update_async(ip, "plugin_name", env!("CARGO_PKG_VERSION"), false).await;
+//! # }
+//! ```
+
+use std::future::Future;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::time::Instant;
+
+use log::{error, info, warn};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::time::timeout;
+use update_protocol::{Request, ResponseCode};
+
+use crate::{
+    changes, check_download_size, check_sha256, completion_message, decode_response, download_error, failure, encode_request, finish_archive, inline_file, install_path_of,
+    log_version_once, notification_message, rate_limit_delay, reason_suffix, resolve_locations_at, response_case_collision, resume, server_message, signing, skip, skyline_mismatch, storage,
+    update_request, ArchiveDecision, BetaPreference, DefaultInstaller, FileOutcome, FileReport, InstallError, Installer,
+    SandboxInstaller, UpdateClient, UpdateError, UpdateFile, UpdateReport, UpdateResponse, UpdateStatus,
+    DEFAULT_INSTALL_ROOT, DOWNLOAD_CHUNK, MAX_RESPONSE_LEN,
+};
+
+/// Future returned by the async methods of `AsyncInstaller`
+pub type InstallFuture<'a, T> = Pin<Box<dyn Future<Output = T> + Send + 'a>>;
+
+/// An installer for use with `custom_check_update_async`, the async counterpart of
+/// `Installer`. Methods which may wait on the user or the SD card return a future, build one
+/// with `Box::pin(async move { ... })`.
+pub trait AsyncInstaller: Send + Sync {
+    fn should_update<'a>(&'a self, response: &'a UpdateResponse) -> InstallFuture<'a, bool>;
+
+    fn install_file(&self, path: PathBuf, buf: Vec<u8>) -> InstallFuture<'_, Result<(), InstallError>>;
+
+    /// Root the relative install locations of an update are resolved against, see
+    /// `Installer::install_root`
+    fn install_root(&self, _response: &UpdateResponse) -> String {
+        String::from(DEFAULT_INSTALL_ROOT)
+    }
+
+    /// What to do with a `.tar` or `.zip` file once it has been installed, see
+    /// `Installer::handle_archive`
+    fn handle_archive(&self, _file: &UpdateFile, _path: &Path) -> ArchiveDecision {
+        ArchiveDecision::ExtractNow
+    }
+
+    /// Called when the plugin can't be updated by the updater, see
+    /// `Installer::on_update_notification`. Unlike with `Installer`, it is called on every check
+    /// rather than once per version.
+    fn on_update_notification(&self, response: &UpdateResponse, _changelog: Option<&str>) {
+        info!("[updater] {}", notification_message(response));
+    }
+
+    /// Called as a file is downloaded, see `Installer::on_progress`
+    fn on_progress(&self, _file: &UpdateFile, _bytes_downloaded: usize, _total_bytes: usize) {}
+
+    /// See `Installer::allow_case_collisions`
+    fn allow_case_collisions(&self) -> bool {
+        false
+    }
+
+    /// See `Installer::allow_outdated_skyline`
+    fn allow_outdated_skyline(&self) -> bool {
+        false
+    }
+
+    /// See `Installer::keep_archives`
+    fn keep_archives(&self) -> bool {
+        false
+    }
+}
+
+/// Installs with the blocking `DefaultInstaller` on tokio's blocking threads
+impl AsyncInstaller for DefaultInstaller {
+    fn should_update<'a>(&'a self, response: &'a UpdateResponse) -> InstallFuture<'a, bool> {
+        let response = response.clone();
+        Box::pin(async move {
+            tokio::task::spawn_blocking(move || Installer::should_update(&DefaultInstaller, &response))
+                .await
+                .unwrap_or(false)
+        })
+    }
+
+    fn install_file(&self, path: PathBuf, buf: Vec<u8>) -> InstallFuture<'_, Result<(), InstallError>> {
+        Box::pin(async move {
+            let installing = {
+                let path = path.clone();
+                tokio::task::spawn_blocking(move || Installer::install_file(&DefaultInstaller, path, buf))
+            };
+            installing.await.unwrap_or_else(|e| Err(InstallError::other(path, e.to_string())))
+        })
+    }
+
+    fn on_update_notification(&self, response: &UpdateResponse, changelog: Option<&str>) {
+        Installer::on_update_notification(self, response, changelog)
+    }
+
+    fn on_progress(&self, file: &UpdateFile, bytes_downloaded: usize, total_bytes: usize) {
+        Installer::on_progress(self, file, bytes_downloaded, total_bytes)
+    }
+}
+
+/// Installs into the sandbox with `tokio::fs`. Updates aren't backed up, as with every
+/// `AsyncInstaller`.
+impl AsyncInstaller for SandboxInstaller {
+    fn should_update<'a>(&'a self, _: &'a UpdateResponse) -> InstallFuture<'a, bool> {
+        Box::pin(async { true })
+    }
+
+    fn install_file(&self, path: PathBuf, buf: Vec<u8>) -> InstallFuture<'_, Result<(), InstallError>> {
+        let path = self.path(&path);
+        Box::pin(async move {
+            let parent = path.parent().ok_or_else(|| InstallError::other(&path, "it has no parent directory"))?;
+            tokio::fs::create_dir_all(parent).await.map_err(|e| InstallError::new(&path, e).with_context("creating its directory"))?;
+            tokio::fs::write(&path, buf).await.map_err(|e| InstallError::new(path, e))
+        })
+    }
+
+    fn handle_archive(&self, file: &UpdateFile, path: &Path) -> ArchiveDecision {
+        Installer::handle_archive(self, file, path)
+    }
+}
+
+/// Connect to `port` of the client's server within its request timeout. Clients using TLS are
+/// refused rather than connected to in plaintext.
+async fn connect(client: &UpdateClient, port: u16) -> Result<TcpStream, UpdateError> {
+    if client.uses_tls() {
+        return Err(UpdateError::Tls(String::from("TLS is only supported by the blocking functions")))
+    }
+    if client.uses_custom_transport() {
+        return Err(UpdateError::Connect(String::from("custom transports are only supported by the blocking functions")))
+    }
+
+    match timeout(client.timeouts().request, TcpStream::connect((client.host(), port))).await {
+        Ok(Ok(stream)) => Ok(stream),
+        Ok(Err(e)) => Err(UpdateError::Connect(format!("{}:{}: {}", client.host(), port, e))),
+        Err(_) => Err(UpdateError::Connect(format!("{}:{}: timed out", client.host(), port))),
+    }
+}
+
+/// Send a request to the main port and parse the response, retrying once if it is rate
+/// limited, see `crate::send_update_request`
+async fn send_update_request(client: &UpdateClient, name: &str, request: &Request) -> UpdateReport {
+    let report = send_update_request_once(client, name, request).await;
+    match rate_limit_delay(client, name, &report) {
+        Some(delay) => {
+            tokio::time::sleep(delay).await;
+            send_update_request_once(client, name, request).await
+        }
+        None => report,
+    }
+}
+
+/// Send a request to the main port once and parse the response
+async fn send_update_request_once(client: &UpdateClient, name: &str, request: &Request) -> UpdateReport {
+    log_version_once();
+
+    let mut report = UpdateReport::default();
+    let start = Instant::now();
+
+    let packet = match encode_request(name, request) {
+        Some(packet) => packet,
+        None => {
+            report.error = Some(UpdateError::InvalidRequest(None));
+            return report
+        }
+    };
+    let mut stream = match connect(client, client.port()).await {
+        Ok(stream) => stream,
+        Err(e) => {
+            error!("[{} updater] Failed to connect to update server {}", name, client.host());
+            error!("[{} updater] {}", name, e);
+            report.error = Some(e);
+            return report
+        }
+    };
+
+    let mut string = String::new();
+    let exchange = async {
+        stream.write_all(packet.as_bytes()).await?;
+        (&mut stream).take(MAX_RESPONSE_LEN).read_to_string(&mut string).await
+    };
+    let timed_out = timeout(client.timeouts().request, exchange).await.is_err();
+    report.timings.request = start.elapsed();
+
+    if timed_out {
+        warn!("[{} updater] Update server did not respond within {:?}", name, client.timeouts().request);
+        report.error = Some(UpdateError::Timeout);
+    } else {
+        match decode_response(name, string) {
+            Ok(response) => report.response = Some(response),
+            Err(e) => report.error = Some(e),
+        }
+    }
+
+    report
+}
+
+/// Download a file into memory, see `crate::download_to`. Every read has to arrive within the
+/// client's download timeout.
+async fn download<I>(client: &UpdateClient, port: u16, file: &UpdateFile, installer: &I) -> Result<Vec<u8>, UpdateError>
+    where I: AsyncInstaller,
+{
+    let index = file.download_index;
+    let failed = |reason: String| UpdateError::Download { index, reason: download_error(index, reason) };
+    let mut stream = match connect(client, port).await {
+        Ok(stream) => stream,
+        Err(e) => return Err(failed(format!("failed to connect to download port {}: {}", port, e))),
+    };
+    if let Err(e) = stream.write_all(&u64::to_be_bytes(index)).await {
+        return Err(failed(e.to_string()))
+    }
+
+    let mut buf = Vec::with_capacity(file.size);
+    let mut chunk = vec![0; DOWNLOAD_CHUNK];
+    loop {
+        if client.is_cancelled() {
+            info!("[updater] Download of file {} cancelled", index);
+            return Err(UpdateError::Cancelled)
+        }
+        match timeout(client.timeouts().download, stream.read(&mut chunk)).await {
+            Ok(Ok(0)) => break,
+            Ok(Ok(len)) if buf.len() + len > file.size => {
+                return Err(failed(format!("the server sent more than the expected {} bytes", file.size)))
+            }
+            Ok(Ok(len)) => {
+                buf.extend_from_slice(&chunk[..len]);
+                installer.on_progress(file, buf.len(), file.size);
+            }
+            Ok(Err(e)) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+            Ok(Err(e)) => return Err(failed(e.to_string())),
+            Err(_) => return Err(failed(format!("it stalled for longer than {:?}", client.timeouts().download))),
+        }
+    }
+
+    if buf.len() != file.size {
+        return Err(failed(format!("the server closed it after {} of the expected {} bytes", buf.len(), file.size)))
+    }
+
+    Ok(buf)
+}
+
+/// Download, install and extract a single file, see `crate::fetch_file` and
+/// `crate::install_downloaded`
+async fn update_file<I>(client: &UpdateClient, download_port: u16, file: &UpdateFile, installer: &I, report: &mut UpdateReport) -> Result<Option<(ArchiveDecision, Vec<PathBuf>)>, UpdateError>
+    where I: AsyncInstaller,
+{
+    check_download_size(client, file)?;
+    let start = Instant::now();
+    let buf = match inline_file(file) {
+        Some(buf) => {
+            installer.on_progress(file, buf.len(), file.size);
+            buf
+        }
+        None => download(client, download_port, file, installer).await?,
+    };
+    report.timings.download += start.elapsed();
+    check_sha256(file, || resume::sha256_of(&buf[..]).unwrap_or_default())?;
+
+    let path = install_path_of(file)?;
+    let start = Instant::now();
+    if let Err(e) = installer.install_file(path.clone(), buf).await {
+        warn!("[updater] {}", e);
+        return Err(UpdateError::Install { path: path.display().to_string(), reason: e.reason() })
+    }
+
+    let mut archive = None;
+    if storage::is_archive(&path) {
+        let decision = installer.handle_archive(file, &path);
+        let (allow_case_collisions, keep_archives) = (installer.allow_case_collisions(), installer.keep_archives());
+        let extracting = {
+            let (path, decision) = (path.clone(), decision.clone());
+            tokio::task::spawn_blocking(move || finish_archive(&path, &decision, allow_case_collisions, keep_archives))
+        };
+        match extracting.await.unwrap_or_else(|e| Err(e.to_string())) {
+            Ok(extracted) => archive = Some((decision, extracted)),
+            Err(reason) => return Err(UpdateError::Install { path: path.display().to_string(), reason }),
+        }
+    }
+    report.timings.install += start.elapsed();
+
+    Ok(archive)
+}
+
+/// Install every file of an update in order, stopping at the first failure
+async fn update_files<I>(client: &UpdateClient, response: &UpdateResponse, installer: &I, report: &mut UpdateReport)
+    where I: AsyncInstaller,
+{
+    let download_port = response.download_port.unwrap_or_else(|| client.download_port());
+
+    let mut failed = false;
+    for file in &response.required_files {
+        let (mut archive, mut extracted) = (None, vec![]);
+        let outcome = if failed {
+            FileOutcome::Skipped
+        } else {
+            match update_file(client, download_port, file, installer, report).await {
+                Ok(handled) => {
+                    if let Some((decision, files)) = handled {
+                        archive = Some(decision);
+                        extracted = files;
+                    }
+                    FileOutcome::Installed
+                }
+                Err(e) => {
+                    failed = true;
+                    FileOutcome::Failed(e)
+                }
+            }
+        };
+        report.files.push(FileReport {
+            install_location: file.install_location.clone(),
+            outcome,
+            archive,
+            extracted,
+        });
+    }
+}
+
+/// Act on the server's response, see `crate::handle_response`
+async fn handle_response<I>(client: &UpdateClient, name: &str, response: &UpdateResponse, installer: &I, report: &mut UpdateReport)
+    where I: AsyncInstaller,
+{
+    match response.code {
+        ResponseCode::Update => {
+            if skip::is_skipped(&skip::skipped_dir(), response) {
+                info!("[{} updater] Not asking about {}, it was skipped", name, response.new_plugin_version);
+                report.skipped = true;
+                return
+            }
+            report.skyline = skyline_mismatch(response, client.skyline_version());
+            if let Some(mismatch) = &report.skyline {
+                if !installer.allow_outdated_skyline() {
+                    warn!("[{} updater] Not installing {}: {}", name, response.new_plugin_version, mismatch);
+                    report.skyline_outdated = true;
+                    return
+                }
+                warn!("[{} updater] {}, its files may not load until skyline is updated", name, mismatch);
+            }
+            // signed as sent, before install locations are resolved
+            if let Some(public_key) = client.public_key() {
+                if let Err(e) = signing::verify_response(response, public_key) {
+                    report.error = Some(e);
+                    return
+                }
+            }
+            let response = match resolve_locations_at(response, &installer.install_root(response)) {
+                Ok(resolved) => resolved,
+                Err(e) => {
+                    warn!("[{} updater] Refusing to install update: {}", name, e);
+                    report.error = Some(e);
+                    return
+                }
+            };
+
+            let collision = response_case_collision(&response).filter(|_| !installer.allow_case_collisions());
+            if let Some(collision) = collision {
+                warn!("[{} updater] Refusing to install update: {}", name, collision);
+                report.error = Some(collision);
+            } else if installer.should_update(&response).await {
+                update_files(client, &response, installer, report).await;
+                report.installed = report.files.iter().all(|file| file.outcome.succeeded());
+                if report.installed {
+                    report.removed.files = changes::delete_listed_files(&response, &installer.install_root(&response));
+                    info!("[updater] {}", completion_message(&report.files));
+                } else {
+                    report.error = report.files.iter().find_map(|file| match &file.outcome {
+                        FileOutcome::Failed(e) => Some(e.clone()),
+                        _ => None,
+                    });
+                    error!("[{} updater] Failed to install update ({}), files may be left in a broken state.", name, failure(report));
+                }
+            }
+        }
+        ResponseCode::NotifyOnly => installer.on_update_notification(response, response.changelog.as_deref()),
+        ResponseCode::InvalidRequest => error!("[{} updater] Failed to send a valid request to the server{}", name, reason_suffix(response)),
+        ResponseCode::PluginNotFound => error!("Plugin '{}' could not be found on the update server{}", name, reason_suffix(response)),
+        ResponseCode::VersionNotFound => error!("[{} updater] The version asked for could not be found on the update server{}", name, reason_suffix(response)),
+        ResponseCode::RateLimited => warn!("[{} updater] {}", name, UpdateError::RateLimited { retry_after: response.retry_after }),
+        ResponseCode::ServerError => error!("[{} updater] {}", name, UpdateError::ServerError(server_message(response))),
+        ResponseCode::NoUpdate => {}
+        _ => error!("Unexpected response"),
+    }
+}
+
+impl UpdateClient {
+    /// See `check_update_async`
+    pub async fn check_update_async(&self, name: &str, version: &str, beta: impl Into<BetaPreference>) -> bool {
+        self.custom_check_update_async(name, version, beta, &DefaultInstaller).await
+    }
+
+    /// See `custom_check_update_async`
+    pub async fn custom_check_update_async<I>(&self, name: &str, version: &str, beta: impl Into<BetaPreference>, installer: &I) -> bool
+        where I: AsyncInstaller,
+    {
+        self.custom_check_update_report_async(name, version, beta, installer).await.installed
+    }
+
+    /// See `custom_check_update_report_async`
+    pub async fn custom_check_update_report_async<I>(&self, name: &str, version: &str, beta: impl Into<BetaPreference>, installer: &I) -> UpdateReport
+        where I: AsyncInstaller,
+    {
+        let request = update_request(self, name, version, self.allows_beta(beta.into(), name), None, None);
+        let mut report = send_update_request(self, name, &request).await;
+        if let Some(response) = report.response.take() {
+            handle_response(self, name, &response, installer, &mut report).await;
+            report.response = Some(response);
+        }
+
+        report
+    }
+
+    /// See `try_custom_check_update_async`
+    pub async fn try_custom_check_update_async<I>(&self, name: &str, version: &str, beta: impl Into<BetaPreference>, installer: &I) -> Result<UpdateStatus, UpdateError>
+        where I: AsyncInstaller,
+    {
+        self.custom_check_update_report_async(name, version, beta, installer).await.status()
+    }
+
+    /// See `get_update_info_async`
+    pub async fn get_update_info_async(&self, name: &str, version: &str, beta: impl Into<BetaPreference>) -> Option<UpdateResponse> {
+        let request = update_request(self, name, version, self.allows_beta(beta.into(), name), None, None);
+        send_update_request(self, name, &request).await.response
+    }
+}
+
+/// Install an update using the default installer, see `crate::check_update`
+pub async fn check_update_async(ip: IpAddr, name: &str, version: &str, beta: impl Into<BetaPreference>) -> bool {
+    UpdateClient::new(ip).check_update_async(name, version, beta).await
+}
+
+/// Install an update with a custom installer implementation, see `crate::custom_check_update`
+pub async fn custom_check_update_async<I>(ip: IpAddr, name: &str, version: &str, beta: impl Into<BetaPreference>, installer: &I) -> bool
+    where I: AsyncInstaller,
+{
+    UpdateClient::new(ip).custom_check_update_async(name, version, beta, installer).await
+}
+
+/// Install an update with a custom installer implementation, reporting the server's response
+/// and the outcome of every file, see `crate::custom_check_update_report`
+pub async fn custom_check_update_report_async<I>(ip: IpAddr, name: &str, version: &str, beta: impl Into<BetaPreference>, installer: &I) -> UpdateReport
+    where I: AsyncInstaller,
+{
+    UpdateClient::new(ip).custom_check_update_report_async(name, version, beta, installer).await
+}
+
+/// Install an update, telling apart the ways it can fail, see `crate::try_custom_check_update`
+pub async fn try_custom_check_update_async<I>(ip: IpAddr, name: &str, version: &str, beta: impl Into<BetaPreference>, installer: &I) -> Result<UpdateStatus, UpdateError>
+    where I: AsyncInstaller,
+{
+    UpdateClient::new(ip).try_custom_check_update_async(name, version, beta, installer).await
+}
+
+/// Ask the server for an update without installing it, see `crate::get_update_info`
+pub async fn get_update_info_async(ip: IpAddr, name: &str, version: &str, beta: impl Into<BetaPreference>) -> Option<UpdateResponse> {
+    UpdateClient::new(ip).get_update_info_async(name, version, beta).await
+}
@@ -0,0 +1,499 @@
+use std::cell::Cell;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+use update_protocol::ResponseCode;
+
+use crate::{
+    ArchiveDecision, BetaPreference, InstallError, InstalledManifest, Installer, SkylineMismatch, UpdateChoice, UpdateClient, UpdateError, UpdateFile, UpdatePrompt,
+    UpdateReport, UpdateResponse, changes, handle_response, request_update, skip,
+};
+
+/// What to do with every update found by a batch check
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BatchDecision {
+    InstallAll,
+    DeclineAll,
+    /// Ask about every update separately, through `Installer::should_update_prompt`
+    AskEach,
+}
+
+/// Every update found by a batch check, when asking whether to install them
+pub struct BatchPrompt<'a> {
+    pub updates: Vec<&'a UpdateResponse>,
+    /// Combined size of every file of every update, in bytes
+    pub total_size: usize,
+}
+
+impl<'a> BatchPrompt<'a> {
+    pub(crate) fn new(updates: Vec<&'a UpdateResponse>) -> Self {
+        let total_size = updates.iter()
+            .flat_map(|response| &response.required_files)
+            .map(|file| file.size)
+            .fold(0, usize::saturating_add);
+
+        BatchPrompt { updates, total_size }
+    }
+
+    /// Display name and new version of every plugin to be updated
+    pub fn versions(&self) -> impl Iterator<Item = (&str, &str)> + '_ {
+        self.updates.iter().map(|response| (response.display_name(), response.new_plugin_version.as_str()))
+    }
+}
+
+/// Outcome of a batch check, one report per plugin in the order they were requested
+#[derive(Debug, Clone, Default)]
+pub struct BatchReport {
+    pub plugins: Vec<(String, UpdateReport)>,
+}
+
+impl BatchReport {
+    /// Plugins which were updated
+    pub fn installed(&self) -> Vec<&str> {
+        self.plugins.iter()
+            .filter(|(_, report)| report.installed)
+            .map(|(name, _)| name.as_str())
+            .collect()
+    }
+
+    /// Plugins whose check or install failed, with why
+    pub fn failed(&self) -> Vec<(&str, UpdateError)> {
+        self.plugins.iter()
+            .filter_map(|(name, report)| Some((name.as_str(), report.status().err()?)))
+            .collect()
+    }
+}
+
+/// Forwards to the batch's installer, adding up the progress of every download into progress
+/// of the whole batch
+struct BatchInstaller<'a, I> {
+    inner: &'a I,
+    total_size: usize,
+    /// Bytes of every file finished so far, files are downloaded one at a time
+    finished: Cell<usize>,
+}
+
+impl<I: Installer> Installer for BatchInstaller<'_, I> {
+    fn should_update(&self, response: &UpdateResponse) -> bool {
+        self.inner.should_update(response)
+    }
+
+    fn should_update_prompt(&self, prompt: &UpdatePrompt) -> bool {
+        self.inner.should_update_prompt(prompt)
+    }
+
+    fn update_choice(&self, prompt: &UpdatePrompt) -> UpdateChoice {
+        self.inner.update_choice(prompt)
+    }
+
+    fn install_file(&self, path: PathBuf, buf: Vec<u8>) -> Result<(), InstallError> {
+        self.inner.install_file(path, buf)
+    }
+
+    fn install_file_streamed(&self, path: PathBuf, reader: &mut dyn Read, size: usize) -> Result<(), InstallError> {
+        self.inner.install_file_streamed(path, reader, size)
+    }
+
+    fn install_root(&self, response: &UpdateResponse) -> String {
+        self.inner.install_root(response)
+    }
+
+    fn installed_sha256(&self, path: &Path) -> Option<String> {
+        self.inner.installed_sha256(path)
+    }
+
+    fn handle_archive(&self, file: &UpdateFile, path: &Path) -> ArchiveDecision {
+        self.inner.handle_archive(file, path)
+    }
+
+    fn on_update_notification(&self, response: &UpdateResponse, changelog: Option<&str>) {
+        self.inner.on_update_notification(response, changelog)
+    }
+
+    fn should_install_file(&self, file: &UpdateFile) -> bool {
+        self.inner.should_install_file(file)
+    }
+
+    fn on_update_start(&self, response: &UpdateResponse) {
+        self.inner.on_update_start(response)
+    }
+
+    fn on_update_complete(&self, response: &UpdateResponse, success: bool) {
+        self.inner.on_update_complete(response, success)
+    }
+
+    fn on_storage_unavailable(&self, response: &UpdateResponse) {
+        self.inner.on_storage_unavailable(response)
+    }
+
+    fn skyline_version(&self) -> Option<String> {
+        self.inner.skyline_version()
+    }
+
+    fn allow_outdated_skyline(&self) -> bool {
+        self.inner.allow_outdated_skyline()
+    }
+
+    fn on_skyline_outdated(&self, response: &UpdateResponse, mismatch: &SkylineMismatch) {
+        self.inner.on_skyline_outdated(response, mismatch)
+    }
+
+    fn on_progress(&self, file: &UpdateFile, bytes_downloaded: usize, total_bytes: usize) {
+        self.inner.on_progress(file, bytes_downloaded, total_bytes);
+
+        self.inner.on_batch_progress(self.finished.get().saturating_add(bytes_downloaded), self.total_size);
+        if bytes_downloaded >= total_bytes {
+            self.finished.set(self.finished.get().saturating_add(total_bytes));
+        }
+    }
+
+    fn remove_stale_files(&self) -> bool {
+        self.inner.remove_stale_files()
+    }
+
+    fn should_uninstall(&self, plugin_name: &str, manifest: &InstalledManifest) -> bool {
+        self.inner.should_uninstall(plugin_name, manifest)
+    }
+
+    fn prune_empty_dirs(&self) -> bool {
+        self.inner.prune_empty_dirs()
+    }
+
+    fn allow_case_collisions(&self) -> bool {
+        self.inner.allow_case_collisions()
+    }
+
+    fn keep_archives(&self) -> bool {
+        self.inner.keep_archives()
+    }
+
+    fn stream_archives(&self) -> bool {
+        self.inner.stream_archives()
+    }
+
+    fn on_extract_progress(&self, file: &UpdateFile, entries_extracted: usize) {
+        self.inner.on_extract_progress(file, entries_extracted)
+    }
+
+    fn stage_downloads(&self) -> bool {
+        self.inner.stage_downloads()
+    }
+
+    fn should_update_batch(&self, prompt: &BatchPrompt) -> BatchDecision {
+        self.inner.should_update_batch(prompt)
+    }
+
+    fn on_batch_progress(&self, bytes_downloaded: usize, total_bytes: usize) {
+        self.inner.on_batch_progress(bytes_downloaded, total_bytes)
+    }
+}
+
+pub(crate) fn check_updates_batch_on<I>(client: &UpdateClient, plugins: &[(&str, &str)], beta: BetaPreference, installer: &I) -> BatchReport
+    where I: Installer,
+{
+    let allow_beta: Vec<_> = plugins.iter().map(|&(name, _)| client.allows_beta(beta, name)).collect();
+    let mut reports: Vec<_> = plugins.iter()
+        .zip(&allow_beta)
+        .map(|(&(name, version), &allow_beta)| (name.to_owned(), request_update(client, name, version, allow_beta.into(), installer)))
+        .collect();
+
+    let updates: Vec<_> = reports.iter()
+        .filter_map(|(_, report)| report.response.clone())
+        .filter(|response| response.code == ResponseCode::Update)
+        .filter(|response| !changes::offers_rolled_back(&changes::installed_dir(), response))
+        .filter(|response| !skip::is_skipped(&skip::skipped_dir(), response))
+        .collect();
+    let prompt = BatchPrompt::new(updates.iter().collect());
+    let decision = if prompt.updates.is_empty() {
+        BatchDecision::AskEach
+    } else {
+        installer.should_update_batch(&prompt)
+    };
+
+    let batch_installer = BatchInstaller {
+        inner: installer,
+        total_size: prompt.total_size,
+        finished: Cell::new(0),
+    };
+
+    // once the SD card is gone or the updates are cancelled, every remaining install would
+    // stop the same way
+    let mut stopped: Option<UpdateError> = None;
+
+    for ((name, report), &allow_beta) in reports.iter_mut().zip(&allow_beta) {
+        let response = match report.response.take() {
+            Some(response) => response,
+            None => continue,
+        };
+
+        // each plugin installs on its own, a failure leaves the others as they are
+        match (decision, &stopped) {
+            (BatchDecision::DeclineAll, _) if response.code == ResponseCode::Update => {}
+            (_, Some(error)) if response.code == ResponseCode::Update => report.error = Some(error.clone()),
+            _ => handle_response(client, name, &response, &batch_installer, report, allow_beta, decision == BatchDecision::InstallAll),
+        }
+        if matches!(&report.error, Some(UpdateError::StorageUnavailable { .. }) | Some(UpdateError::Cancelled)) {
+            stopped = report.error.clone();
+        }
+        report.response = Some(response);
+    }
+
+    BatchReport { plugins: reports }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::cell::RefCell;
+
+    use update_protocol::{InstallLocation, Request};
+
+    use crate::transport::test::MockTransport;
+    use crate::{changes, ExitStatus, PORT};
+
+    /// Unique per test and test run, as the manifests of installed plugins are shared
+    fn plugin_name(test: &str, name: &str) -> String {
+        format!("batch_{}_{}_{}", test, name, std::process::id())
+    }
+
+    fn update(name: String, files: &[(&str, &str)]) -> UpdateResponse {
+        UpdateResponse {
+            code: ResponseCode::Update,
+            update_plugin: true,
+            plugin_name: name,
+            new_plugin_version: "2.0.0".into(),
+            required_files: files.iter()
+                .enumerate()
+                .map(|(i, &(path, data))| UpdateFile {
+                    install_location: InstallLocation::AbsolutePath(path.into()),
+                    download_index: i as u64,
+                    size: data.len(),
+                    inline_data: Some(base64::encode(data)),
+                    sha256: None,
+                    signature: None,
+                })
+                .collect(),
+            ..Default::default()
+        }
+    }
+
+    /// A client answered with the response of each plugin in `names` in turn, the plugins
+    /// without one being up to date
+    fn fixture_client(responses: &[UpdateResponse], names: &[String]) -> (UpdateClient, MockTransport) {
+        let transport = names.iter().fold(MockTransport::default(), |transport, name| {
+            let response = responses.iter()
+                .find(|response| &response.plugin_name == name)
+                .cloned()
+                .unwrap_or_else(UpdateResponse::no_update);
+            transport.respond(PORT, &response)
+        });
+        let client = UpdateClient::new("127.0.0.1".parse().unwrap()).with_transport(transport.clone());
+
+        (client, transport)
+    }
+
+    /// The plugins every update request was sent for, in order
+    fn asked_for(transport: &MockTransport) -> Vec<String> {
+        transport.sent().into_iter()
+            .filter(|(port, _)| *port == PORT)
+            .map(|(_, sent)| match serde_json::from_slice(&sent).unwrap() {
+                Request::Update { plugin_name, .. } => plugin_name,
+                other => panic!("unexpected request {:?}", other),
+            })
+            .collect()
+    }
+
+    /// The plugins of the fixture client: two updates, one plugin already up to date, and an
+    /// update whose file can't be downloaded
+    fn fixture(test: &str) -> (Vec<UpdateResponse>, Vec<String>) {
+        let names: Vec<_> = ["a", "b", "current", "broken"].iter().map(|name| plugin_name(test, name)).collect();
+
+        // nothing answers its download
+        let mut broken = update(names[3].clone(), &[("sd:/broken/plugin.nro", "")]);
+        broken.required_files[0].inline_data = None;
+        broken.required_files[0].size = 100;
+
+        let responses = vec![
+            update(names[0].clone(), &[("sd:/a/plugin.nro", "aaaa"), ("sd:/a/config.toml", "a")]),
+            update(names[1].clone(), &[("sd:/b/plugin.nro", "bbbbbb")]),
+            broken,
+        ];
+
+        (responses, names)
+    }
+
+    /// The `(name, version)` of every update offered in a batch prompt, and their total size
+    type Prompt = (Vec<(String, String)>, usize);
+
+    #[derive(Default)]
+    struct BatchInstallerStub {
+        decision: Option<BatchDecision>,
+        decline: Option<String>,
+        prompted: RefCell<Vec<Prompt>>,
+        asked: RefCell<Vec<String>>,
+        installed: RefCell<Vec<PathBuf>>,
+        batch_progress: Cell<(usize, usize)>,
+    }
+
+    impl Installer for BatchInstallerStub {
+        fn should_update(&self, response: &UpdateResponse) -> bool {
+            self.asked.borrow_mut().push(response.plugin_name.clone());
+            self.decline.as_ref() != Some(&response.plugin_name)
+        }
+
+        fn install_file(&self, path: PathBuf, _: Vec<u8>) -> Result<(), InstallError> {
+            self.installed.borrow_mut().push(path);
+            Ok(())
+        }
+
+        fn should_update_batch(&self, prompt: &BatchPrompt) -> BatchDecision {
+            let versions = prompt.versions().map(|(name, version)| (name.to_owned(), version.to_owned())).collect();
+            self.prompted.borrow_mut().push((versions, prompt.total_size));
+            self.decision.unwrap_or(BatchDecision::AskEach)
+        }
+
+        fn on_batch_progress(&self, bytes_downloaded: usize, total_bytes: usize) {
+            self.batch_progress.set((bytes_downloaded, total_bytes));
+        }
+    }
+
+    fn run_batch(test: &str, installer: &BatchInstallerStub) -> (BatchReport, Vec<String>) {
+        let (responses, names) = fixture(test);
+        let (client, transport) = fixture_client(&responses, &names);
+        let plugins: Vec<_> = names.iter().map(|name| (name.as_str(), "1.0.0")).collect();
+
+        let report = check_updates_batch_on(&client, &plugins, BetaPreference::No, installer);
+        assert_eq!(asked_for(&transport), names);
+
+        (report, names)
+    }
+
+    /// Outcome of every plugin, without the details of the installed ones
+    fn statuses(report: &BatchReport) -> Vec<Result<ExitStatus, UpdateError>> {
+        report.plugins.iter().map(|(_, report)| report.status().map(|status| ExitStatus::from(&status))).collect()
+    }
+
+    #[test]
+    fn test_batch_prompt_display_names() {
+        let named = UpdateResponse {
+            display_name: Some("HewDraw Remix".into()),
+            ..update("HDR-Release".into(), &[("sd:/a.txt", "a")])
+        };
+        let unnamed = update("other".into(), &[("sd:/b.txt", "bb")]);
+        let prompt = BatchPrompt::new(vec![&named, &unnamed]);
+
+        assert_eq!(prompt.versions().collect::<Vec<_>>(), vec![("HewDraw Remix", "2.0.0"), ("other", "2.0.0")]);
+        assert_eq!(prompt.total_size, 3);
+    }
+
+    #[test]
+    fn test_batch_install_all() {
+        let installer = BatchInstallerStub { decision: Some(BatchDecision::InstallAll), ..Default::default() };
+        let (report, names) = run_batch("install_all", &installer);
+
+        // one combined prompt, no per-plugin ones
+        let prompted = installer.prompted.borrow();
+        assert_eq!(prompted.len(), 1);
+        let versions: Vec<_> = prompted[0].0.iter().map(|(name, version)| (name.as_str(), version.as_str())).collect();
+        assert_eq!(versions, vec![(names[0].as_str(), "2.0.0"), (names[1].as_str(), "2.0.0"), (names[3].as_str(), "2.0.0")]);
+        assert_eq!(prompted[0].1, 5 + 6 + 100);
+        assert!(installer.asked.borrow().is_empty());
+
+        // the broken plugin fails on its own
+        assert_eq!(report.installed(), vec![names[0].as_str(), names[1].as_str()]);
+        let statuses = statuses(&report);
+        assert_eq!(statuses[..3], [Ok(ExitStatus::Updated), Ok(ExitStatus::Updated), Ok(ExitStatus::Success)]);
+        assert!(matches!(statuses[3], Err(UpdateError::Download { index: 0, .. })));
+        let failed = report.failed();
+        assert_eq!(failed.len(), 1);
+        assert!(matches!(failed[0], (name, UpdateError::Download { index: 0, .. }) if name == names[3]));
+        assert_eq!(installer.installed.borrow().len(), 3);
+        assert_eq!(installer.batch_progress.get(), (5 + 6, 5 + 6 + 100));
+
+        // a manifest per installed plugin
+        let dir = changes::installed_dir();
+        assert_eq!(changes::load_manifest(&dir, &names[0]).unwrap().files.len(), 2);
+        assert_eq!(changes::load_manifest(&dir, &names[1]).unwrap().files.len(), 1);
+        assert_eq!(changes::load_manifest(&dir, &names[3]), None);
+        for name in &names {
+            let _ = std::fs::remove_file(dir.join(name));
+        }
+    }
+
+    #[test]
+    fn test_batch_decline_all() {
+        let installer = BatchInstallerStub { decision: Some(BatchDecision::DeclineAll), ..Default::default() };
+        let (report, _) = run_batch("decline_all", &installer);
+
+        assert!(report.installed().is_empty());
+        assert!(installer.installed.borrow().is_empty());
+        assert_eq!(statuses(&report), vec![
+            Ok(ExitStatus::Declined),
+            Ok(ExitStatus::Declined),
+            Ok(ExitStatus::Success),
+            Ok(ExitStatus::Declined),
+        ]);
+    }
+
+    /// Installs into a directory standing in for the SD card, which is removed on the first write
+    struct EjectingInstaller {
+        sd: PathBuf,
+        attempted: RefCell<Vec<PathBuf>>,
+    }
+
+    impl Installer for EjectingInstaller {
+        fn should_update(&self, _: &UpdateResponse) -> bool {
+            true
+        }
+
+        fn install_file(&self, path: PathBuf, buf: Vec<u8>) -> Result<(), InstallError> {
+            let _ = std::fs::remove_dir_all(&self.sd);
+            self.attempted.borrow_mut().push(path.clone());
+            std::fs::write(&path, buf).map_err(|e| InstallError::new(path, e))
+        }
+
+        fn should_update_batch(&self, _: &BatchPrompt) -> BatchDecision {
+            BatchDecision::InstallAll
+        }
+    }
+
+    #[test]
+    fn test_batch_storage_unavailable() {
+        let sd = std::env::temp_dir().join(format!("skyline-update-batch-sd-{}", std::process::id()));
+        std::fs::create_dir_all(&sd).unwrap();
+        let path = |name: &str| sd.join(name).to_string_lossy().into_owned();
+
+        let names = vec![plugin_name("storage_unavailable", "a"), plugin_name("storage_unavailable", "b")];
+        let responses = vec![
+            update(names[0].clone(), &[(&path("a.nro"), "a")]),
+            update(names[1].clone(), &[(&path("b.nro"), "b")]),
+        ];
+        let (client, transport) = fixture_client(&responses, &names);
+        let plugins: Vec<_> = names.iter().map(|name| (name.as_str(), "1.0.0")).collect();
+        let installer = EjectingInstaller { sd: sd.clone(), attempted: Default::default() };
+
+        let report = check_updates_batch_on(&client, &plugins, BetaPreference::No, &installer);
+        assert_eq!(asked_for(&transport), names);
+
+        // the second plugin isn't attempted once the SD card is gone
+        let lost = UpdateError::StorageUnavailable { path: path("a.nro") };
+        assert_eq!(*installer.attempted.borrow(), vec![PathBuf::from(path("a.nro"))]);
+        assert_eq!(statuses(&report), vec![Err(lost.clone()), Err(lost)]);
+        assert!(report.installed().is_empty());
+    }
+
+    #[test]
+    fn test_batch_ask_each() {
+        let installer = BatchInstallerStub { decline: Some(plugin_name("ask_each", "b")), ..Default::default() };
+        let (report, names) = run_batch("ask_each", &installer);
+
+        assert_eq!(*installer.asked.borrow(), vec![names[0].clone(), names[1].clone(), names[3].clone()]);
+        assert_eq!(report.installed(), vec![names[0].as_str()]);
+        assert_eq!(statuses(&report)[1], Ok(ExitStatus::Declined));
+
+        let dir = changes::installed_dir();
+        for name in &names {
+            let _ = std::fs::remove_file(dir.join(name));
+        }
+    }
+}
@@ -0,0 +1,129 @@
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// Cancels the updates of an `UpdateClient` from another thread, such as a cancel button shown
+/// while downloading. Clones cancel the same updates.
+///
+/// ```no_run
+/// let cancel = skyline_update::CancelToken::new();
+/// let client = skyline_update::UpdateClient::new("127.0.0.1".parse().unwrap()).with_cancel_token(cancel.clone());
+///
+/// // on another thread, once the user backs out
+/// cancel.cancel();
+/// ```
+///
+/// Downloads stop after the chunk being read, and nothing is installed from then on. The
+/// update is reported as `UpdateStatus::Cancelled`. Files installed before it was cancelled are
+/// kept, and the next attempt at the same version resumes after them, unless the installer
+/// rolls them back with `Installer::rollback`.
+#[derive(Debug, Clone, Default)]
+pub struct CancelToken {
+    cancelled: Arc<AtomicBool>,
+}
+
+impl CancelToken {
+    pub fn new() -> Self {
+        CancelToken::default()
+    }
+
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::SeqCst)
+    }
+}
+
+/// Tokens are equal if they cancel the same updates
+impl PartialEq for CancelToken {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.cancelled, &other.cancelled)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::io::{Read, Write};
+    use std::path::PathBuf;
+    use std::time::Duration;
+    use update_protocol::{InstallLocation, UpdateFile, UpdateResponse};
+    use crate::test::{inline_response, RecordingInstaller};
+    use crate::{update_files, FileOutcome, InstallError, Installer, Timings, UpdateClient, UpdateError, UpdateReport, UpdateStatus, DOWNLOAD_CHUNK};
+
+    /// Cancels the updates of its client once the first bytes of a download arrive
+    struct CancellingInstaller {
+        cancel: CancelToken,
+        recording: RecordingInstaller,
+    }
+
+    impl Installer for CancellingInstaller {
+        fn should_update(&self, _: &UpdateResponse) -> bool {
+            true
+        }
+
+        fn install_file(&self, path: PathBuf, buf: Vec<u8>) -> Result<(), InstallError> {
+            self.recording.install_file(path, buf)
+        }
+
+        fn on_progress(&self, file: &UpdateFile, bytes_downloaded: usize, total_bytes: usize) {
+            if bytes_downloaded > 0 {
+                self.cancel.cancel();
+            }
+            self.recording.on_progress(file, bytes_downloaded, total_bytes);
+        }
+    }
+
+    #[test]
+    fn test_cancel_download() {
+        let downloads = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut response = inline_response(&[("sd:/b.txt", "b")]);
+        response.required_files.insert(0, UpdateFile {
+            install_location: InstallLocation::AbsolutePath("sd:/a.bin".into()),
+            download_index: 0,
+            size: 2 * DOWNLOAD_CHUNK,
+            inline_data: None,
+            sha256: None,
+            signature: None,
+        });
+        response.download_port = Some(downloads.local_addr().unwrap().port());
+
+        // the rest of the download only arrives once the client stopped reading
+        let server = std::thread::spawn(move || {
+            let (mut socket, _) = downloads.accept().unwrap();
+            let mut index = [0; 8];
+            socket.read_exact(&mut index).unwrap();
+            socket.write_all(&[1; 100]).unwrap();
+            std::thread::sleep(Duration::from_millis(200));
+            let _ = socket.write_all(&vec![1; 2 * DOWNLOAD_CHUNK - 100]);
+        });
+
+        let cancel = CancelToken::new();
+        let client = UpdateClient::new("127.0.0.1".parse().unwrap()).with_cancel_token(cancel.clone());
+        let installer = CancellingInstaller { cancel, recording: RecordingInstaller::default() };
+        let files = update_files(&client, &response, &installer, &mut Timings::default());
+        server.join().unwrap();
+
+        let outcomes: Vec<_> = files.into_iter().map(|file| file.outcome).collect();
+        assert_eq!(outcomes, vec![FileOutcome::Failed(UpdateError::Cancelled), FileOutcome::Skipped]);
+        assert!(installer.recording.installed.borrow().is_empty());
+
+        let report = UpdateReport { response: Some(response), error: Some(UpdateError::Cancelled), ..Default::default() };
+        assert_eq!(report.status(), Ok(UpdateStatus::Cancelled));
+    }
+
+    #[test]
+    fn test_cancel_before_update() {
+        let response = inline_response(&[("sd:/a.txt", "a")]);
+        let cancel = CancelToken::new();
+        let client = UpdateClient::new("127.0.0.1".parse().unwrap()).with_cancel_token(cancel.clone());
+        assert!(!client.is_cancelled());
+
+        cancel.cancel();
+        let installer = RecordingInstaller::default();
+        let files = update_files(&client, &response, &installer, &mut Timings::default());
+        assert_eq!(files[0].outcome, FileOutcome::Failed(UpdateError::Cancelled));
+        assert!(installer.installed.borrow().is_empty());
+    }
+}
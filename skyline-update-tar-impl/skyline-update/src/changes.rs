@@ -0,0 +1,788 @@
+use std::path::{Path, PathBuf};
+
+use log::{info, warn};
+use update_protocol::UpdateResponse;
+
+use crate::storage::{install_path, is_archive};
+
+/// A file installed by the last update of a plugin
+#[derive(Debug, Clone, PartialEq)]
+pub struct InstalledFile {
+    /// Normalized install path
+    pub path: String,
+    pub size: u64,
+    /// Files extracted from it if it is an archive
+    pub extracted: Vec<String>,
+}
+
+/// What the last installed update of a plugin consisted of, as recorded by the client
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct InstalledManifest {
+    pub files: Vec<InstalledFile>,
+    /// Installed version, None for manifests recorded before versions were
+    pub version: Option<String>,
+    /// Version this one was rolled back from with `rollback`, which isn't installed again
+    /// until a newer version is offered
+    pub rolled_back_from: Option<String>,
+}
+
+impl InstalledManifest {
+    /// Manifest of a response once every one of its files is installed
+    pub fn from_response(response: &UpdateResponse) -> Self {
+        let files = response.required_files.iter()
+            .filter_map(|file| Some(InstalledFile { path: install_path(file)?, size: file.size as u64, extracted: vec![] }))
+            .collect();
+        let version = Some(response.new_plugin_version.clone()).filter(|version| !version.is_empty());
+
+        InstalledManifest { files, version, rolled_back_from: None }
+    }
+
+    /// Parse a manifest written by `to_string`, one `<size> <path>` per line after the
+    /// `# version <version>` and `# rolled back from <version>` notes, each archive followed by an
+    /// `# extracted <path>` line per file extracted from it. Older clients skip the notes.
+    pub fn parse(text: &str) -> Self {
+        let note = |prefix: &str| text.lines()
+            .find_map(|line| line.strip_prefix(prefix))
+            .map(str::to_owned);
+        let mut files: Vec<InstalledFile> = vec![];
+        for line in text.lines() {
+            if let Some(path) = line.strip_prefix("# extracted ") {
+                if let Some(archive) = files.last_mut() {
+                    archive.extracted.push(path.to_owned());
+                }
+                continue
+            }
+
+            let mut parts = line.splitn(2, ' ');
+            let size = parts.next().and_then(|size| size.parse().ok());
+            if let (Some(size), Some(path)) = (size, parts.next()) {
+                files.push(InstalledFile { path: path.to_owned(), size, extracted: vec![] });
+            }
+        }
+
+        InstalledManifest {
+            files,
+            version: note("# version "),
+            rolled_back_from: note("# rolled back from "),
+        }
+    }
+
+    fn get(&self, path: &str) -> Option<&InstalledFile> {
+        self.files.iter().find(|file| file.path == path)
+    }
+
+    /// Record the files extracted from the archives of the update, by the install path of their
+    /// archive. Archives missing from `extracted` weren't extracted again, such as ones found
+    /// already installed when resuming, and keep the files `previous` recorded for them.
+    pub(crate) fn record_extracted(&mut self, extracted: &[(String, Vec<String>)], previous: Option<&InstalledManifest>) {
+        for file in &mut self.files {
+            file.extracted = match extracted.iter().find(|(archive, _)| *archive == file.path) {
+                Some((_, files)) => files.clone(),
+                None => previous.and_then(|previous| previous.get(&file.path))
+                    .map(|installed| installed.extracted.clone())
+                    .unwrap_or_default(),
+            };
+        }
+    }
+}
+
+impl std::fmt::Display for InstalledManifest {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        if let Some(version) = &self.version {
+            writeln!(f, "# version {}", version)?;
+        }
+        if let Some(version) = &self.rolled_back_from {
+            writeln!(f, "# rolled back from {}", version)?;
+        }
+        for file in &self.files {
+            writeln!(f, "{} {}", file.size, file.path)?;
+            for path in &file.extracted {
+                writeln!(f, "# extracted {}", path)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Install paths an update adds, replaces and removes compared to what is installed
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ChangeSet {
+    /// Not part of the installed version
+    pub added: Vec<String>,
+    /// Part of the installed version with a different size
+    pub replaced: Vec<String>,
+    /// Part of the installed version but not of the update. Archives stand for their
+    /// extracted folder as well.
+    pub removed: Vec<String>,
+    /// Part of the installed version with the same size. Only sizes are recorded, so these
+    /// are still downloaded and may differ in content.
+    pub unchanged: Vec<String>,
+}
+
+impl ChangeSet {
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.replaced.is_empty() && self.removed.is_empty() && self.unchanged.is_empty()
+    }
+
+    /// Deepest directory containing every path of the old and new version, the furthest up
+    /// empty directories are pruned to
+    pub fn install_root(&self) -> Option<String> {
+        let mut paths = self.added.iter()
+            .chain(&self.replaced)
+            .chain(&self.removed)
+            .chain(&self.unchanged);
+
+        let mut root = parent_dir(paths.next()?)?;
+        for path in paths {
+            while !is_within(path, &root) {
+                root = parent_dir(&root)?;
+            }
+        }
+
+        Some(root)
+    }
+}
+
+impl std::fmt::Display for ChangeSet {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let groups = [("+", &self.added), ("~", &self.replaced), ("-", &self.removed), ("=", &self.unchanged)];
+        for (marker, paths) in groups.iter() {
+            for path in paths.iter() {
+                writeln!(f, "{} {}", marker, path)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Compare an update against the manifest of the installed version. Without a manifest every
+/// file of the update is considered added, as nothing is known about what is installed.
+pub fn compute_change_set(local_manifest: Option<&InstalledManifest>, response: &UpdateResponse) -> ChangeSet {
+    let mut changes = ChangeSet::default();
+    let new_paths: Vec<_> = response.required_files.iter()
+        .filter_map(|file| Some((install_path(file)?, file.size as u64)))
+        .collect();
+
+    for (path, size) in &new_paths {
+        match local_manifest.and_then(|manifest| manifest.get(path)) {
+            None => changes.added.push(path.clone()),
+            Some(installed) if installed.size == *size => changes.unchanged.push(path.clone()),
+            Some(_) => changes.replaced.push(path.clone()),
+        }
+    }
+
+    if let Some(manifest) = local_manifest {
+        changes.removed = manifest.files.iter()
+            .filter(|file| !new_paths.iter().any(|(path, _)| path == &file.path))
+            .map(|file| file.path.clone())
+            .collect();
+    }
+
+    changes
+}
+
+/// Directory holding the manifest of the installed version of every plugin updated through
+/// the updater
+pub(crate) fn installed_dir() -> PathBuf {
+    if cfg!(target_os = "switch") {
+        PathBuf::from("sd:/skyline-update/installed")
+    } else {
+        std::env::temp_dir().join("skyline-update/installed")
+    }
+}
+
+/// File in `dir` holding what the updater remembers about a plugin. Plugin names come from the
+/// server, so anything but letters, digits, `-`, `_` and `.` is replaced to keep the file inside
+/// `dir`.
+pub(crate) fn record_path(dir: &Path, plugin_name: &str) -> PathBuf {
+    let mut name: String = plugin_name.chars()
+        .map(|c| if c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.' { c } else { '_' })
+        .collect();
+    if name.is_empty() || name.starts_with('.') {
+        name.insert(0, '_');
+    }
+
+    dir.join(name)
+}
+
+pub(crate) fn load_manifest(dir: &Path, plugin_name: &str) -> Option<InstalledManifest> {
+    std::fs::read_to_string(record_path(dir, plugin_name))
+        .or_else(|_| std::fs::read_to_string(temp_manifest_path(dir, plugin_name)))
+        .ok()
+        .map(|text| InstalledManifest::parse(&text))
+}
+
+/// Whether `response` offers the version the installed one was rolled back from
+pub(crate) fn offers_rolled_back(dir: &Path, response: &UpdateResponse) -> bool {
+    load_manifest(dir, &response.plugin_name)
+        .and_then(|manifest| manifest.rolled_back_from)
+        .map_or(false, |version| version == response.new_plugin_version)
+}
+
+/// File a manifest is written to before it is moved into place. Records never start with a `.`,
+/// so it can't be another plugin's.
+fn temp_manifest_path(dir: &Path, plugin_name: &str) -> PathBuf {
+    let path = record_path(dir, plugin_name);
+    dir.join(format!(".{}.tmp", path.file_name().unwrap_or_default().to_string_lossy()))
+}
+
+/// Write the manifest of `plugin_name` to a temporary file first and move it into place, so an
+/// update interrupted while saving never leaves half of a manifest. The SD card can't rename
+/// over a file, so the previous manifest is deleted first, and `load_manifest` falls back to
+/// the temporary file if it was interrupted in between.
+pub(crate) fn save_manifest(dir: &Path, plugin_name: &str, manifest: &InstalledManifest) {
+    let (path, temp) = (record_path(dir, plugin_name), temp_manifest_path(dir, plugin_name));
+    let result = std::fs::create_dir_all(dir)
+        .and_then(|_| std::fs::write(&temp, manifest.to_string()))
+        .and_then(|_| std::fs::rename(&temp, &path).or_else(|_| {
+            std::fs::remove_file(&path)?;
+            std::fs::rename(&temp, &path)
+        }));
+    if result.is_err() {
+        let _ = std::fs::remove_file(&temp);
+        warn!("[updater] Failed to record installed files of {}", plugin_name);
+    }
+}
+
+/// Forget what was installed for `plugin_name`, once it was uninstalled
+pub(crate) fn remove_manifest(dir: &Path, plugin_name: &str) {
+    let _ = std::fs::remove_file(temp_manifest_path(dir, plugin_name));
+    if let Err(e) = std::fs::remove_file(record_path(dir, plugin_name)) {
+        warn!("[updater] Failed to remove the installed files record of {}: {}", plugin_name, e);
+    }
+}
+
+/// Parent of a normalized path, keeping the root separator (`sd:/a` -> `sd:/`). None for a root.
+fn parent_dir(path: &str) -> Option<String> {
+    let path = update_protocol::normalize_path(path).ok()?;
+    let idx = path.rfind('/')?;
+    if idx + 1 == path.len() {
+        return None
+    }
+
+    let parent = &path[..idx];
+    if parent.is_empty() || parent.ends_with(':') {
+        Some(path[..=idx].to_owned())
+    } else {
+        Some(parent.to_owned())
+    }
+}
+
+/// Whether a path is `dir` or inside it, both normalized
+fn is_within(path: &str, dir: &str) -> bool {
+    let path = match update_protocol::normalize_path(path) {
+        Ok(path) => path,
+        Err(_) => return false,
+    };
+
+    if dir.ends_with('/') {
+        path.starts_with(dir)
+    } else {
+        path == dir || (path.starts_with(dir) && path[dir.len()..].starts_with('/'))
+    }
+}
+
+/// Remove the directories above `removed` which are now empty, up to but never including
+/// `boundary`. Returns the removed directories.
+pub(crate) fn prune_empty_dirs(removed: &str, boundary: &str) -> Vec<String> {
+    let mut pruned = vec![];
+
+    let mut dir = parent_dir(removed);
+    while let Some(current) = dir {
+        if current == boundary || !is_within(&current, boundary) || std::fs::remove_dir(&current).is_err() {
+            break
+        }
+        dir = parent_dir(&current);
+        pruned.push(current);
+    }
+
+    pruned
+}
+
+/// Everything deleted after an update
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Removed {
+    pub files: Vec<String>,
+    pub dirs: Vec<String>,
+}
+
+/// Delete the files the server listed in `UpdateResponse::files_to_delete`, once every file of
+/// `response` is installed. Relative paths are resolved against `root`. Only files inside `root`
+/// or a folder the update installs to are deleted, so a server can clean up after its plugin
+/// but not anywhere else on the SD card, and paths stepping out of a folder with `..` are
+/// refused as archive entries are. Returns the deleted paths.
+pub(crate) fn delete_listed_files(response: &UpdateResponse, root: &str) -> Vec<String> {
+    let installed: Vec<String> = response.required_files.iter().filter_map(install_path).collect();
+    let root = update_protocol::normalize_path(root).unwrap_or_else(|_| root.to_owned());
+    // never the root of the SD card a file is installed at the top of
+    let allowed: Vec<String> = std::iter::once(root.clone())
+        .chain(installed.iter().filter_map(|path| parent_dir(path)))
+        .filter(|dir| !dir.ends_with('/'))
+        .collect();
+
+    let mut deleted = vec![];
+    for listed in &response.files_to_delete {
+        let path = match deletion_path(listed, &root, &allowed) {
+            Ok(path) => path,
+            Err(reason) => {
+                warn!("[{} updater] Not deleting {}: {}", response.plugin_name, listed, reason);
+                continue
+            }
+        };
+        if installed.contains(&path) {
+            warn!("[{} updater] Not deleting {}: the update just installed it", response.plugin_name, path);
+            continue
+        }
+
+        match std::fs::remove_file(&path) {
+            Ok(()) => {
+                info!("[updater] Deleted {}", path);
+                deleted.push(path);
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => info!("[{} updater] {} was already deleted", response.plugin_name, path),
+            Err(e) => warn!("[{} updater] Failed to delete {}: {}", response.plugin_name, path, e),
+        }
+    }
+
+    deleted
+}
+
+/// The path `listed` deletes, or why it is refused, see `delete_listed_files`
+fn deletion_path(listed: &str, root: &str, allowed: &[String]) -> Result<String, String> {
+    let path = update_protocol::normalize_path(listed).map_err(|e| e.to_string())?;
+    if path.split('/').any(|component| component == "..") {
+        return Err(String::from("it steps out of a folder with '..'"))
+    }
+    let path = if path.contains(':') || path.starts_with('/') {
+        path
+    } else {
+        update_protocol::normalize_path(&format!("{}/{}", root, path)).map_err(|e| e.to_string())?
+    };
+
+    if allowed.iter().any(|dir| path != *dir && is_within(&path, dir)) {
+        Ok(path)
+    } else {
+        Err(String::from("it is outside of the install root and the folders the update installs to"))
+    }
+}
+
+/// Files extracted from the archives of the previous version which the update's copies of the
+/// same archives no longer contain. Archives the update removed aren't included, `remove_files`
+/// deletes them with their whole folder.
+pub(crate) fn stale_entries(previous: &InstalledManifest, installed: &InstalledManifest) -> Vec<String> {
+    let kept: std::collections::HashSet<&str> = installed.files.iter()
+        .flat_map(|file| std::iter::once(&file.path).chain(&file.extracted))
+        .map(String::as_str)
+        .collect();
+
+    previous.files.iter()
+        .filter(|file| installed.get(&file.path).is_some())
+        .flat_map(|file| &file.extracted)
+        .filter(|path| !kept.contains(path.as_str()))
+        .cloned()
+        .collect()
+}
+
+/// Delete the files an update removed, archives together with their extracted folder, and the
+/// `stale_entries` of the archives it kept. With a `prune_boundary`, directories left empty are
+/// removed as well, up to the boundary.
+pub(crate) fn remove_files(changes: &ChangeSet, stale_entries: &[String], prune_boundary: Option<&str>) -> Removed {
+    let mut removed = Removed::default();
+
+    for path in &changes.removed {
+        let file = Path::new(path);
+        if is_archive(file) {
+            let extracted = file.with_extension("");
+            if std::fs::remove_dir_all(&extracted).is_ok() {
+                removed.dirs.push(extracted.display().to_string());
+            }
+        }
+    }
+    for path in changes.removed.iter().chain(stale_entries) {
+        if std::fs::remove_file(path).is_ok() {
+            info!("[updater] Removed {}", path);
+            removed.files.push(path.clone());
+        }
+    }
+
+    // only once every file is gone, so directories shared by several files can be pruned
+    if let Some(boundary) = prune_boundary {
+        for path in &removed.files {
+            removed.dirs.extend(prune_empty_dirs(path, boundary));
+        }
+    }
+
+    removed
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use update_protocol::{UpdateFile, InstallLocation};
+    use crate::test::{download_response, inline_response, mock_client, tar_of, RecordingInstaller};
+    use crate::transport::test::MockTransport;
+    use crate::{handle_response, InstallError, Installer, UpdateClient, UpdateReport, PORT};
+
+    fn response(files: &[(&str, usize)]) -> UpdateResponse {
+        UpdateResponse {
+            required_files: files.iter()
+                .enumerate()
+                .map(|(i, &(path, size))| UpdateFile {
+                    install_location: InstallLocation::AbsolutePath(path.into()),
+                    download_index: i as u64,
+                    size,
+                    inline_data: None,
+                    sha256: None,
+                    signature: None,
+                })
+                .collect(),
+            ..Default::default()
+        }
+    }
+
+    fn manifest(files: &[(&str, u64)]) -> InstalledManifest {
+        InstalledManifest {
+            files: files.iter().map(|&(path, size)| InstalledFile { path: path.into(), size, extracted: vec![] }).collect(),
+            ..Default::default()
+        }
+    }
+
+    fn paths(paths: &[&str]) -> Vec<String> {
+        paths.iter().map(|&path| path.to_owned()).collect()
+    }
+
+    #[test]
+    fn test_no_local_manifest() {
+        let changes = compute_change_set(None, &response(&[("sd:/plugin.nro", 100), ("sd:/config.toml", 10)]));
+
+        assert_eq!(changes, ChangeSet {
+            added: paths(&["sd:/plugin.nro", "sd:/config.toml"]),
+            ..Default::default()
+        });
+    }
+
+    #[test]
+    fn test_change_set() {
+        let installed = manifest(&[("sd:/plugin.nro", 100), ("sd:/config.toml", 10), ("sd:/old.txt", 5)]);
+        let response = response(&[("sd:/plugin.nro", 120), ("sd:/config.toml", 10), ("sd:/new.txt", 5)]);
+
+        assert_eq!(compute_change_set(Some(&installed), &response), ChangeSet {
+            added: paths(&["sd:/new.txt"]),
+            replaced: paths(&["sd:/plugin.nro"]),
+            removed: paths(&["sd:/old.txt"]),
+            unchanged: paths(&["sd:/config.toml"]),
+        });
+    }
+
+    #[test]
+    fn test_restructured_layout() {
+        // same files moved to new folders, with the paths spelled differently by the server
+        let installed = manifest(&[("sd:/mods/a/plugin.nro", 100), ("sd:/mods/a/data.bin", 50)]);
+        let response = response(&[("sd:\\mods\\b\\plugin.nro", 100), ("sd:/mods//a/data.bin/", 50)]);
+
+        assert_eq!(compute_change_set(Some(&installed), &response), ChangeSet {
+            added: paths(&["sd:/mods/b/plugin.nro"]),
+            removed: paths(&["sd:/mods/a/plugin.nro"]),
+            unchanged: paths(&["sd:/mods/a/data.bin"]),
+            ..Default::default()
+        });
+    }
+
+    #[test]
+    fn test_archive_paths() {
+        let installed = manifest(&[("sd:/ultimate/mods/base.tar", 4000), ("sd:/ultimate/mods/skins.tar", 2000)]);
+        let response = response(&[("sd:/ultimate/mods/base.tar", 5000), ("sd:/ultimate/mods/stages.tar", 1000)]);
+
+        let changes = compute_change_set(Some(&installed), &response);
+        assert_eq!(changes.replaced, paths(&["sd:/ultimate/mods/base.tar"]));
+        assert_eq!(changes.added, paths(&["sd:/ultimate/mods/stages.tar"]));
+        assert_eq!(changes.removed, paths(&["sd:/ultimate/mods/skins.tar"]));
+    }
+
+    #[test]
+    fn test_remove_archive_with_folder() {
+        let dir = std::env::temp_dir().join(format!("skyline-update-changes-{}", std::process::id()));
+        let archive = dir.join("skins.tar");
+        std::fs::create_dir_all(dir.join("skins/fighter")).unwrap();
+        std::fs::write(dir.join("skins/fighter/model.bin"), "model").unwrap();
+        std::fs::write(&archive, "archive").unwrap();
+
+        let removed = remove_files(&ChangeSet { removed: vec![archive.display().to_string()], ..Default::default() }, &[], None);
+
+        assert_eq!(removed.files, vec![archive.display().to_string()]);
+        assert_eq!(removed.dirs, vec![dir.join("skins").display().to_string()]);
+        assert!(!archive.exists());
+        assert!(!dir.join("skins").exists());
+        let _ = std::fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn test_delete_listed_files() {
+        let dir = std::env::temp_dir().join(format!("skyline-update-delete-{}", std::process::id()));
+        let path = |name: &str| dir.join(name).display().to_string();
+        for name in &["root/old.txt", "mods/old.prc", "mods/new.prc", "other/secret.txt"] {
+            std::fs::create_dir_all(dir.join(name).parent().unwrap()).unwrap();
+            std::fs::write(dir.join(name), "a").unwrap();
+        }
+
+        let mut response = response(&[(&path("mods/new.prc"), 1)]);
+        response.files_to_delete = vec![
+            String::from("old.txt"),
+            path("mods/old.prc"),
+            path("mods/missing.prc"),
+            // outside of the install root and the folders the update installs to
+            path("mods/../other/secret.txt"),
+            path("other/secret.txt"),
+            // installed by the update itself
+            path("mods/new.prc"),
+        ];
+        let deleted = delete_listed_files(&response, &path("root"));
+
+        assert_eq!(deleted, vec![path("root/old.txt"), path("mods/old.prc")]);
+        assert!(dir.join("other/secret.txt").exists());
+        assert!(dir.join("mods/new.prc").exists());
+        let _ = std::fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn test_deletion_path() {
+        let allowed = paths(&["sd:/atmosphere/contents/01006A800016E000", "sd:/ultimate/mods"]);
+        let root = "sd:/atmosphere/contents/01006A800016E000";
+
+        assert_eq!(deletion_path("romfs/old.arc", root, &allowed), Ok("sd:/atmosphere/contents/01006A800016E000/romfs/old.arc".into()));
+        assert_eq!(deletion_path("sd:\\ultimate\\mods\\old", root, &allowed), Ok("sd:/ultimate/mods/old".into()));
+        assert!(deletion_path("sd:/ultimate/mods", root, &allowed).is_err());
+        assert!(deletion_path("sd:/ultimate/modsextra/old", root, &allowed).is_err());
+        assert!(deletion_path("sd:/atmosphere/config/system_settings.ini", root, &allowed).is_err());
+        assert!(deletion_path("../../config.ini", root, &allowed).is_err());
+        assert!(deletion_path("", root, &allowed).is_err());
+    }
+
+    #[test]
+    fn test_manifest_round_trip() {
+        let installed = InstalledManifest::from_response(&response(&[("sd:/plugin.nro", 100), ("sd:/path with spaces.txt", 3)]));
+
+        assert_eq!(installed, manifest(&[("sd:/plugin.nro", 100), ("sd:/path with spaces.txt", 3)]));
+        assert_eq!(InstalledManifest::parse(&installed.to_string()), installed);
+
+        let dir = std::env::temp_dir().join(format!("skyline-update-installed-{}", std::process::id()));
+        assert_eq!(load_manifest(&dir, "test_plugin"), None);
+        save_manifest(&dir, "test_plugin", &installed);
+        assert_eq!(load_manifest(&dir, "test_plugin"), Some(installed.clone()));
+
+        // saving again replaces it, without leaving the temporary file behind
+        let updated = manifest(&[("sd:/plugin.nro", 120)]);
+        save_manifest(&dir, "test_plugin", &updated);
+        assert_eq!(load_manifest(&dir, "test_plugin"), Some(updated));
+        assert!(!temp_manifest_path(&dir, "test_plugin").exists());
+
+        // interrupted between deleting the old manifest and moving the new one into place
+        std::fs::rename(record_path(&dir, "test_plugin"), temp_manifest_path(&dir, "test_plugin")).unwrap();
+        assert_eq!(load_manifest(&dir, "test_plugin").unwrap().files[0].size, 120);
+        let _ = std::fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn test_extracted_entries() {
+        let mut previous = manifest(&[("sd:/mods/skins.tar", 100), ("sd:/mods/plugin.nro", 10), ("sd:/mods/old.tar", 5)]);
+        previous.files[0].extracted = paths(&["sd:/mods/skins/a.bin", "sd:/mods/skins/b.bin"]);
+        previous.files[2].extracted = paths(&["sd:/mods/old/c.bin"]);
+
+        let text = previous.to_string();
+        assert!(text.starts_with("100 sd:/mods/skins.tar\n# extracted sd:/mods/skins/a.bin\n# extracted sd:/mods/skins/b.bin\n10 sd:/mods/plugin.nro\n"), "{}", text);
+        assert_eq!(InstalledManifest::parse(&text), previous);
+
+        // the archive extracted again lost `a.bin`, the one found already installed keeps its entries
+        let mut installed = InstalledManifest::from_response(&response(&[("sd:/mods/skins.tar", 90), ("sd:/mods/plugin.nro", 10), ("sd:/mods/old.tar", 5)]));
+        installed.record_extracted(&[("sd:/mods/skins.tar".into(), paths(&["sd:/mods/skins/b.bin"]))], Some(&previous));
+        assert_eq!(installed.files[0].extracted, paths(&["sd:/mods/skins/b.bin"]));
+        assert_eq!(installed.files[2].extracted, paths(&["sd:/mods/old/c.bin"]));
+        assert_eq!(stale_entries(&previous, &installed), paths(&["sd:/mods/skins/a.bin"]));
+
+        // entries of an archive the update removed go with its folder instead
+        let installed = manifest(&[("sd:/mods/plugin.nro", 10)]);
+        assert_eq!(stale_entries(&previous, &installed), Vec::<String>::new());
+    }
+
+    #[test]
+    fn test_manifest_notes() {
+        let mut installed = InstalledManifest::from_response(&UpdateResponse {
+            new_plugin_version: "1.1.0".into(),
+            ..response(&[("sd:/plugin.nro", 100)])
+        });
+        installed.rolled_back_from = Some("1.2.0".into());
+
+        let text = installed.to_string();
+        assert_eq!(text, "# version 1.1.0\n# rolled back from 1.2.0\n100 sd:/plugin.nro\n");
+        assert_eq!(InstalledManifest::parse(&text), installed);
+
+        // manifests from before the notes
+        assert_eq!(InstalledManifest::parse("100 sd:/plugin.nro\n"), manifest(&[("sd:/plugin.nro", 100)]));
+    }
+
+    #[test]
+    fn test_record_path() {
+        let dir = Path::new("sd:/skyline-update/installed");
+
+        assert_eq!(record_path(dir, "my-plugin_1.2"), dir.join("my-plugin_1.2"));
+        assert_eq!(record_path(dir, "../../atmosphere/hosts"), dir.join("_.._.._atmosphere_hosts"));
+        assert_eq!(record_path(dir, ".."), dir.join("_.."));
+        assert_eq!(record_path(dir, "/etc/passwd"), dir.join("_etc_passwd"));
+        assert_eq!(record_path(dir, ""), dir.join("_"));
+    }
+
+    #[test]
+    fn test_parent_dir() {
+        assert_eq!(parent_dir("sd:/a/b.txt").as_deref(), Some("sd:/a"));
+        assert_eq!(parent_dir("sd:/a").as_deref(), Some("sd:/"));
+        assert_eq!(parent_dir("sd:/a/").as_deref(), Some("sd:/"));
+        assert_eq!(parent_dir("sd:\\a\\b").as_deref(), Some("sd:/a"));
+        assert_eq!(parent_dir("sd:/"), None);
+        assert_eq!(parent_dir("sd:"), None);
+        assert_eq!(parent_dir("/tmp/a").as_deref(), Some("/tmp"));
+        assert_eq!(parent_dir("/tmp").as_deref(), Some("/"));
+        assert_eq!(parent_dir("/"), None);
+    }
+
+    #[test]
+    fn test_install_root() {
+        let changes = ChangeSet {
+            added: paths(&["sd:/ultimate/mods/my_mod/new/a.bin"]),
+            removed: paths(&["sd:/ultimate/mods/my_mod/old/deep/b.bin"]),
+            unchanged: paths(&["sd:/ultimate/mods/my_mod/c.bin/"]),
+            ..Default::default()
+        };
+        assert_eq!(changes.install_root().as_deref(), Some("sd:/ultimate/mods/my_mod"));
+
+        // no common folder, never further up than the root of the sd card
+        let changes = ChangeSet {
+            added: paths(&["sd:/atmosphere/plugin.nro", "sd:/ultimate/config.toml"]),
+            ..Default::default()
+        };
+        assert_eq!(changes.install_root().as_deref(), Some("sd:/"));
+
+        // a sibling folder sharing a name prefix isn't inside the root
+        let changes = ChangeSet {
+            added: paths(&["sd:/mods/a/x.bin", "sd:/mods/ab/y.bin"]),
+            ..Default::default()
+        };
+        assert_eq!(changes.install_root().as_deref(), Some("sd:/mods"));
+
+        assert_eq!(ChangeSet::default().install_root(), None);
+    }
+
+    #[test]
+    fn test_prune_empty_dirs() {
+        let dir = std::env::temp_dir().join(format!("skyline-update-prune-{}", std::process::id()));
+        let root = dir.join("mods/my_mod");
+        let path = |relative: &str| root.join(relative).display().to_string();
+
+        for folder in &["old/deep/deeper", "old/other", "kept/sub"] {
+            std::fs::create_dir_all(root.join(folder)).unwrap();
+        }
+        for file in &["old/deep/deeper/a.bin", "old/deep/b.bin", "old/other/c.bin", "kept/sub/d.bin", "kept/e.bin"] {
+            std::fs::write(root.join(file), "data").unwrap();
+        }
+
+        let changes = ChangeSet {
+            removed: vec![path("old/deep/deeper/a.bin"), path("old/deep/b.bin"), path("old/other/c.bin"), path("kept/sub/d.bin")],
+            unchanged: vec![path("kept/e.bin")],
+            ..Default::default()
+        };
+        let boundary = changes.install_root().unwrap();
+        assert_eq!(boundary, root.display().to_string());
+
+        let mut removed = remove_files(&changes, &[], Some(&boundary));
+        removed.dirs.sort();
+
+        assert_eq!(removed.files.len(), 4);
+        assert_eq!(removed.dirs, vec![path("kept/sub"), path("old"), path("old/deep"), path("old/deep/deeper"), path("old/other")]);
+        assert!(root.join("kept/e.bin").exists());
+        // the boundary itself stays, even when empty
+        std::fs::remove_file(root.join("kept/e.bin")).unwrap();
+        assert_eq!(prune_empty_dirs(&path("kept/e.bin"), &boundary), vec![path("kept")]);
+        assert!(root.exists());
+
+        let _ = std::fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn test_delete_listed_files_after_install() {
+        let dir = std::env::temp_dir().join(format!("skyline-update-delete-listed-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let old = dir.join("old.txt");
+        std::fs::write(&old, "old").unwrap();
+
+        let name = format!("delete_listed_{}", std::process::id());
+        let response = UpdateResponse {
+            files_to_delete: vec![String::from("old.txt")],
+            ..download_response(&name, &[("sd:/a.txt", "hello")])
+        };
+        let installer = RecordingInstaller { install_root: Some(dir.display().to_string()), ..Default::default() };
+
+        // nothing is deleted while a file of the update failed
+        let transport = MockTransport::default().respond(PORT, &response).reply(PORT + 1, "hel");
+        let report = mock_client(&transport).custom_check_update_report(&name, "1.0.0", false, &installer);
+        assert!(!report.installed);
+        assert!(report.removed.files.is_empty());
+        assert!(old.exists());
+
+        let transport = MockTransport::default().respond(PORT, &response).reply(PORT + 1, "hello");
+        let report = mock_client(&transport).custom_check_update_report(&name, "1.0.0", false, &installer);
+        assert!(report.installed, "{:?}", report.error);
+        assert_eq!(report.removed.files, vec![old.display().to_string()]);
+        assert!(!old.exists());
+
+        let _ = std::fs::remove_file(record_path(&installed_dir(), &name));
+        let _ = std::fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn test_remove_stale_entries() {
+        struct PruningInstaller;
+
+        impl Installer for PruningInstaller {
+            fn should_update(&self, _: &UpdateResponse) -> bool {
+                true
+            }
+
+            fn install_file(&self, path: PathBuf, buf: Vec<u8>) -> Result<(), InstallError> {
+                std::fs::create_dir_all(path.parent().unwrap()).map_err(|e| InstallError::new(&path, e))?;
+                std::fs::write(&path, buf).map_err(|e| InstallError::new(path, e))
+            }
+
+            fn remove_stale_files(&self) -> bool {
+                true
+            }
+        }
+
+        let dir = std::env::temp_dir().join(format!("skyline-update-stale-entries-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let location = dir.join("mods/romfs.tar").to_string_lossy().into_owned();
+        let name = format!("stale_entries_{}", std::process::id());
+        let _ = std::fs::remove_file(record_path(&installed_dir(), &name));
+        let install = |entries: &[&str]| {
+            let response = UpdateResponse { plugin_name: name.clone(), ..inline_response(&[(&location, &tar_of(entries))]) };
+            let mut report = UpdateReport { response: Some(response.clone()), ..Default::default() };
+            handle_response(&UpdateClient::new("127.0.0.1".parse().unwrap()), &name, &response, &PruningInstaller, &mut report, false, true);
+            report
+        };
+
+        install(&["inner/a.txt", "inner/b.txt", "other/c.txt"]);
+        let manifest = load_manifest(&installed_dir(), &name).unwrap();
+        assert_eq!(manifest.files[0].extracted.len(), 3);
+
+        // entries the new version of the archive lacks are deleted, with the folders they leave empty
+        let report = install(&["inner/b.txt"]);
+        assert!(report.status().unwrap().is_installed());
+        assert_eq!(report.removed.files.len(), 2);
+        assert!(!dir.join("mods/romfs/inner/a.txt").exists());
+        assert!(!dir.join("mods/romfs/other").exists());
+        assert_eq!(std::fs::read(dir.join("mods/romfs/inner/b.txt")).unwrap(), b"hello");
+        let manifest = load_manifest(&installed_dir(), &name).unwrap();
+        assert_eq!(manifest.files[0].extracted, vec![dir.join("mods/romfs/inner/b.txt").display().to_string()]);
+
+        let _ = std::fs::remove_file(record_path(&installed_dir(), &name));
+        let _ = std::fs::remove_dir_all(dir);
+    }
+}
@@ -0,0 +1,627 @@
+use std::collections::HashMap;
+use std::io::prelude::*;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc;
+use std::time::Duration;
+
+use log::{error, warn};
+
+use crate::{
+    batch, check_update_report_on, check_update_to_version_on, download, download_unsized, preferences, read_response, request_metadata, rollback_on, update,
+    update_request, MAX_METADATA_FILE_SIZE,
+    BatchReport, BetaPreference, CancelToken, DefaultInstaller, DialogConfig, ImageSelection, Installer, PlannedFile, PluginMetadata, UpdateError, UpdateReport,
+    UpdateResponse, UpdateStatus, VerifyReport, PORT,
+};
+use crate::keep_alive::KeepAlive;
+use crate::tls::Connection;
+use crate::transport::{SharedTransport, Stream, Transport};
+#[cfg(feature = "tls")]
+use crate::TlsConfig;
+
+/// How long the client waits on the update server before giving up. Both must be nonzero.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Timeouts {
+    /// Connecting to either port, and every read or write of a request to the main port
+    pub request: Duration,
+    /// Every read of a download. A download may take longer overall as long as data keeps arriving.
+    pub download: Duration,
+}
+
+impl Default for Timeouts {
+    fn default() -> Self {
+        Timeouts {
+            request: Duration::from_secs(5),
+            download: Duration::from_secs(30),
+        }
+    }
+}
+
+/// An update server at a given address or hostname and port. Files are downloaded from the
+/// port after it, unless the server says otherwise. The free functions of this crate use the
+/// default port 45000.
+///
+/// ```no_run
+/// let client = skyline_update::UpdateClient::new("127.0.0.1".parse().unwrap()).with_port(46000);
+/// client.check_update("plugin_name", env!("CARGO_PKG_VERSION"), false);
+/// ```
+#[derive(Debug, Clone, PartialEq)]
+pub struct UpdateClient {
+    /// IP address or hostname, resolved on every connection
+    host: String,
+    port: u16,
+    timeouts: Timeouts,
+    /// Preferences file read for `BetaPreference::FromConfig`
+    preferences: PathBuf,
+    /// Sent with every update request, see `with_platform`
+    platform: Option<String>,
+    cancel: CancelToken,
+    /// Downloads run at the same time, see `with_max_concurrent_downloads`
+    max_concurrent_downloads: usize,
+    /// Sent with every update request, see `with_options`
+    options: HashMap<String, String>,
+    /// Sent with every update request, see `with_skyline_version`
+    skyline_version: Option<String>,
+    /// Sent with every update request, see `with_channel`
+    channel: Option<String>,
+    /// Largest file downloaded, see `with_max_download_size`
+    max_download_size: usize,
+    keep_alive: KeepAlive,
+    /// Key every file has to be signed with, see `with_public_key`
+    public_key: Option<[u8; 32]>,
+    /// Longest wait before retrying a rate limited request, see `with_rate_limit_retry`
+    rate_limit_retry: Option<Duration>,
+    /// How the server is connected to, see `with_transport`
+    transport: SharedTransport,
+    /// Connect over TLS, see `with_tls`
+    #[cfg(feature = "tls")]
+    tls: Option<TlsConfig>,
+}
+
+/// Downloads run at the same time unless told otherwise. The console stays conservative with a
+/// single connection.
+const DEFAULT_CONCURRENT_DOWNLOADS: usize = if cfg!(target_os = "switch") { 1 } else { 4 };
+
+/// Largest file of an update downloaded unless told otherwise, 1 GiB
+pub const DEFAULT_MAX_DOWNLOAD_SIZE: usize = 1 << 30;
+
+impl UpdateClient {
+    pub fn new(ip: IpAddr) -> Self {
+        UpdateClient::with_host(&ip.to_string())
+    }
+
+    /// A server at a hostname such as `updates.example.com`, or an IP address. The hostname is
+    /// resolved whenever the client connects and every address it resolves to is tried in order.
+    pub fn with_host(host: &str) -> Self {
+        UpdateClient {
+            host: host.to_owned(),
+            port: PORT,
+            timeouts: Timeouts::default(),
+            preferences: preferences::preferences_path(),
+            platform: None,
+            cancel: CancelToken::default(),
+            max_concurrent_downloads: DEFAULT_CONCURRENT_DOWNLOADS,
+            options: HashMap::new(),
+            skyline_version: None,
+            channel: None,
+            max_download_size: DEFAULT_MAX_DOWNLOAD_SIZE,
+            keep_alive: KeepAlive::default(),
+            public_key: None,
+            rate_limit_retry: None,
+            transport: SharedTransport::default(),
+            #[cfg(feature = "tls")]
+            tls: None,
+        }
+    }
+
+    /// Use a server listening on `port` rather than the default
+    pub fn with_port(self, port: u16) -> Self {
+        UpdateClient { port, ..self }
+    }
+
+    /// Wait on the server for longer or shorter than the default 5 seconds per request and
+    /// 30 seconds per download read
+    pub fn with_timeouts(self, timeouts: Timeouts) -> Self {
+        UpdateClient { timeouts, ..self }
+    }
+
+    /// Read `BetaPreference::FromConfig` from the preferences file at `path` rather than
+    /// `sd:/skyline-update/preferences.toml`
+    pub fn with_preferences(self, path: impl Into<PathBuf>) -> Self {
+        UpdateClient { preferences: path.into(), ..self }
+    }
+
+    /// Say which platform the plugin runs on, one of `update_protocol::PLATFORMS`, so the
+    /// server only sends the files meant for it. Plugins which can tell they are running on an
+    /// emulator pass `"emulator"`. Servers assume `"switch"` unless told otherwise.
+    pub fn with_platform(self, platform: &str) -> Self {
+        UpdateClient { platform: Some(platform.to_owned()), ..self }
+    }
+
+    /// Let `cancel` stop updates partway, see `CancelToken`
+    pub fn with_cancel_token(self, cancel: CancelToken) -> Self {
+        UpdateClient { cancel, ..self }
+    }
+
+    /// Download up to `max` files of an update at the same time over separate connections,
+    /// rather than 1 on the console and 4 elsewhere. Files are still installed one at a time and
+    /// in order, and every `Installer` callback is made from the thread checking for updates.
+    pub fn with_max_concurrent_downloads(self, max: usize) -> Self {
+        UpdateClient { max_concurrent_downloads: max.max(1), ..self }
+    }
+
+    /// Send flags of the plugin's own with every update request, such as which package or
+    /// region the user picked, see `UpdateRequestOptions::plugin_options`
+    pub fn with_options(self, options: HashMap<String, String>) -> Self {
+        UpdateClient { options, ..self }
+    }
+
+    /// Say which version of skyline is running, so the server can flag updates which need a newer
+    /// one, which aren't installed, see `UpdateReport::skyline`. `Installer::skyline_version` is used instead when the
+    /// installer knows it.
+    pub fn with_skyline_version(self, version: &str) -> Self {
+        UpdateClient { skyline_version: Some(version.to_owned()), ..self }
+    }
+
+    /// Ask for the newest version on a named release channel such as `"nightly"`, or on the
+    /// stable channel, whatever is asked for betas. Servers from before protocol version 7 only
+    /// go by whether betas are asked for.
+    pub fn with_channel(self, channel: &str) -> Self {
+        UpdateClient { channel: Some(channel.to_owned()), ..self }
+    }
+
+    /// Refuse to download files of an update larger than `max` bytes rather than 1 GiB, for
+    /// plugins which legitimately ship huge packs. A file over the limit fails with
+    /// `UpdateError::TooLarge` before any of it is downloaded.
+    pub fn with_max_download_size(self, max: usize) -> Self {
+        UpdateClient { max_download_size: max, ..self }
+    }
+
+    /// Reach the server through `transport` rather than over TCP, such as a tunnel or a
+    /// transport answering from memory in tests. The blocking functions use it for every
+    /// request and download, the async ones refuse clients which were given one.
+    pub fn with_transport(self, transport: impl Transport + 'static) -> Self {
+        UpdateClient { transport: SharedTransport::new(transport), ..self }
+    }
+
+    /// Only install updates whose every file was signed with the private key of `public_key`,
+    /// the `signing.pub` written by `update-server keygen`. An update with a file which isn't
+    /// signed, or whose signature doesn't match, fails with `UpdateError::Signature` before any
+    /// of it is downloaded, and archives are checked before they are extracted rather than
+    /// extracted as they download.
+    pub fn with_public_key(self, public_key: &[u8; 32]) -> Self {
+        UpdateClient { public_key: Some(*public_key), ..self }
+    }
+
+    /// When the server answers an update request with `ResponseCode::RateLimited` and asks to
+    /// be asked again within `max_wait`, wait and send the request once more rather than
+    /// failing with `UpdateError::RateLimited`. Requests are never retried more than once.
+    pub fn with_rate_limit_retry(self, max_wait: Duration) -> Self {
+        UpdateClient { rate_limit_retry: Some(max_wait), ..self }
+    }
+
+    /// Connect to both the main and the download port over TLS, checking the server's
+    /// certificate as `tls` says. A server which doesn't serve TLS, or whose certificate isn't
+    /// trusted, fails with `UpdateError::Tls`. Async checks refuse clients using TLS, see
+    /// `asynchronous`.
+    #[cfg(feature = "tls")]
+    pub fn with_tls(self, tls: TlsConfig) -> Self {
+        UpdateClient { tls: Some(tls), ..self }
+    }
+
+    pub fn host(&self) -> &str {
+        &self.host
+    }
+
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+
+    pub fn timeouts(&self) -> Timeouts {
+        self.timeouts
+    }
+
+    pub fn preferences(&self) -> &Path {
+        &self.preferences
+    }
+
+    pub fn platform(&self) -> Option<&str> {
+        self.platform.as_deref()
+    }
+
+    pub fn cancel_token(&self) -> &CancelToken {
+        &self.cancel
+    }
+
+    pub fn max_concurrent_downloads(&self) -> usize {
+        self.max_concurrent_downloads
+    }
+
+    pub fn options(&self) -> &HashMap<String, String> {
+        &self.options
+    }
+
+    pub fn skyline_version(&self) -> Option<&str> {
+        self.skyline_version.as_deref()
+    }
+
+    pub fn channel(&self) -> Option<&str> {
+        self.channel.as_deref()
+    }
+
+    pub fn max_download_size(&self) -> usize {
+        self.max_download_size
+    }
+
+    pub fn public_key(&self) -> Option<&[u8; 32]> {
+        self.public_key.as_ref()
+    }
+
+    pub fn rate_limit_retry(&self) -> Option<Duration> {
+        self.rate_limit_retry
+    }
+
+    #[cfg(feature = "tls")]
+    pub fn tls(&self) -> Option<&TlsConfig> {
+        self.tls.as_ref()
+    }
+
+    /// Whether the client connects over TLS, which the async functions refuse
+    #[cfg(feature = "tokio")]
+    pub(crate) fn uses_tls(&self) -> bool {
+        #[cfg(feature = "tls")]
+        return self.tls.is_some();
+        #[cfg(not(feature = "tls"))]
+        return false;
+    }
+
+    /// Download connections kept open between files, shared with every clone of the client
+    pub(crate) fn keep_alive(&self) -> &KeepAlive {
+        &self.keep_alive
+    }
+
+    pub(crate) fn is_cancelled(&self) -> bool {
+        self.cancel.is_cancelled()
+    }
+
+    /// Whether `plugin` is offered beta versions
+    pub(crate) fn allows_beta(&self, beta: BetaPreference, plugin: &str) -> bool {
+        beta.allows_beta(&self.preferences, plugin)
+    }
+
+    /// Port files are downloaded from when the server doesn't name one
+    pub fn download_port(&self) -> u16 {
+        self.port + 1
+    }
+
+    /// Whether the client was given a transport of its own, see `with_transport`, which
+    /// the async functions refuse
+    #[cfg(any(test, feature = "tokio"))]
+    pub(crate) fn uses_custom_transport(&self) -> bool {
+        self.transport.is_custom()
+    }
+
+    fn connect(&self, port: u16, read_timeout: Duration) -> Result<Connection, UpdateError> {
+        let stream = self.transport.get().connect(&self.host, port, read_timeout, self.timeouts.request)?;
+        self.wrap(stream)
+    }
+
+    /// Wrap a connection in TLS if the client was told to. A failed handshake isn't retried on
+    /// the next address, the certificate would be the same.
+    fn wrap(&self, stream: Box<dyn Stream>) -> Result<Connection, UpdateError> {
+        #[cfg(feature = "tls")]
+        if let Some(tls) = &self.tls {
+            return tls.connect(stream)
+        }
+
+        Ok(Connection::Plain(stream))
+    }
+
+    /// Connect to the main port for a single request
+    pub(crate) fn connect_request(&self) -> Result<Connection, UpdateError> {
+        self.connect(self.port, self.timeouts.request)
+    }
+
+    /// Connect to a download port for a single download
+    pub(crate) fn connect_download(&self, port: u16) -> Result<Connection, UpdateError> {
+        self.connect(port, self.timeouts.download)
+    }
+
+    /// See `crate::check_update`
+    pub fn check_update(&self, name: &str, version: &str, beta: impl Into<BetaPreference>) -> bool {
+        self.custom_check_update(name, version, beta, &DefaultInstaller)
+    }
+
+    /// See `crate::check_update_with_dialog`
+    pub fn check_update_with_dialog(&self, name: &str, version: &str, beta: impl Into<BetaPreference>, config: &DialogConfig) -> bool {
+        crate::dialog::with_dialog(config, version, || self.check_update(name, version, beta))
+    }
+
+    /// See `crate::custom_check_update`
+    pub fn custom_check_update<I>(&self, name: &str, version: &str, beta: impl Into<BetaPreference>, installer: &I) -> bool
+        where I: Installer,
+    {
+        self.custom_check_update_report(name, version, beta, installer).installed
+    }
+
+    /// See `crate::custom_check_update_report`
+    pub fn custom_check_update_report<I>(&self, name: &str, version: &str, beta: impl Into<BetaPreference>, installer: &I) -> UpdateReport
+        where I: Installer,
+    {
+        check_update_report_on(self, name, version, beta.into(), installer)
+    }
+
+    /// See `crate::try_check_update`
+    pub fn try_check_update(&self, name: &str, version: &str, beta: impl Into<BetaPreference>) -> Result<UpdateStatus, UpdateError> {
+        self.try_custom_check_update(name, version, beta, &DefaultInstaller)
+    }
+
+    /// See `crate::try_custom_check_update`
+    pub fn try_custom_check_update<I>(&self, name: &str, version: &str, beta: impl Into<BetaPreference>, installer: &I) -> Result<UpdateStatus, UpdateError>
+        where I: Installer,
+    {
+        self.custom_check_update_report(name, version, beta, installer).status()
+    }
+
+    /// See `crate::check_updates_batch`
+    pub fn check_updates_batch<I>(&self, plugins: &[(&str, &str)], beta: impl Into<BetaPreference>, installer: &I) -> BatchReport
+        where I: Installer,
+    {
+        batch::check_updates_batch_on(self, plugins, beta.into(), installer)
+    }
+
+    /// See `crate::check_update_background`
+    pub fn check_update_background(&self, name: &str, version: &str, beta: impl Into<BetaPreference>) -> mpsc::Receiver<Result<UpdateStatus, UpdateError>> {
+        let (sender, receiver) = mpsc::channel();
+        let (client, name, version, beta) = (self.clone(), name.to_owned(), version.to_owned(), beta.into());
+        std::thread::spawn(move || {
+            let _ = sender.send(client.try_check_update(&name, &version, beta));
+        });
+
+        receiver
+    }
+
+    /// See `crate::custom_check_update_background`
+    pub fn custom_check_update_background<I>(&self, name: &str, version: &str, beta: impl Into<BetaPreference>, installer: I) -> mpsc::Receiver<UpdateReport>
+        where I: Installer + Send + 'static,
+    {
+        let (sender, receiver) = mpsc::channel();
+        let (client, name, version, beta) = (self.clone(), name.to_owned(), version.to_owned(), beta.into());
+        std::thread::spawn(move || {
+            let _ = sender.send(client.custom_check_update_report(&name, &version, beta, &installer));
+        });
+
+        receiver
+    }
+
+    /// See `crate::check_update_to_version`
+    pub fn check_update_to_version(&self, name: &str, current: &str, target: &str) -> bool {
+        self.custom_check_update_to_version_report(name, current, target, &DefaultInstaller).installed
+    }
+
+    /// `check_update_to_version` with a custom installer, reporting the server's response and
+    /// the outcome of every file
+    pub fn custom_check_update_to_version_report<I>(&self, name: &str, current: &str, target: &str, installer: &I) -> UpdateReport
+        where I: Installer,
+    {
+        check_update_to_version_on(self, name, current, target, installer)
+    }
+
+    /// See `crate::rollback`
+    pub fn rollback<I>(&self, name: &str, installer: &I) -> UpdateReport
+        where I: Installer,
+    {
+        rollback_on(self, name, installer)
+    }
+
+    /// See `crate::verify_install`
+    pub fn verify_install(&self, name: &str, version: &str) -> Option<VerifyReport> {
+        self.custom_verify_install(name, version, false, &DefaultInstaller)
+    }
+
+    /// `verify_install`, hashing the installed files with `Installer::installed_sha256`. With
+    /// `repair`, the files found damaged or missing are downloaded again and installed through
+    /// `installer`, without asking, see `VerifyReport::repair`.
+    pub fn custom_verify_install<I>(&self, name: &str, version: &str, repair: bool, installer: &I) -> Option<VerifyReport>
+        where I: Installer,
+    {
+        crate::verify::verify_install(self, name, version, repair, installer)
+    }
+
+    /// Ask the server for an update without installing it, see `install_update`
+    pub fn get_update_info(&self, name: &str, version: &str, beta: impl Into<BetaPreference>) -> Option<UpdateResponse> {
+        let mut stream = match self.connect_request() {
+            Ok(stream) => stream,
+            Err(e) => {
+                error!("[{} updater] {}", name, e);
+                return None
+            }
+        };
+        let packet = serde_json::to_string(&update_request(self, name, version, self.allows_beta(beta.into(), name), None, None)).ok()?;
+
+        let _ = stream.write_fmt(format_args!("{}\n", packet));
+        let mut string = String::new();
+        if let Err(e) = read_response(&mut stream, &mut string) {
+            error!("[{} updater] Failed to read update server response: {}", name, e);
+            return None
+        }
+
+        serde_json::from_str(&string).ok()
+    }
+
+    /// See `crate::plan_update`
+    pub fn plan_update(&self, name: &str, version: &str, allow_beta: bool) -> Option<Vec<PlannedFile>> {
+        crate::dry_run::plan_update(self, name, version, allow_beta)
+    }
+
+    /// Install an update previously returned by `get_update_info`
+    pub fn install_update(&self, info: &UpdateResponse) -> bool {
+        self.custom_install_update(info, &DefaultInstaller)
+    }
+
+    /// See `crate::custom_install_update`
+    pub fn custom_install_update<I>(&self, info: &UpdateResponse, installer: &I) -> bool
+        where I: Installer,
+    {
+        update(self, info, installer).installed
+    }
+
+    /// See `crate::try_custom_install_update`
+    pub fn try_custom_install_update<I>(&self, info: &UpdateResponse, installer: &I) -> Result<UpdateStatus, UpdateError>
+        where I: Installer,
+    {
+        update(self, info, installer).status()
+    }
+
+    /// See `crate::get_metadata`
+    pub fn get_metadata(&self, name: &str, allow_beta: bool) -> Option<PluginMetadata> {
+        request_metadata(self, name, Some(allow_beta))
+    }
+
+    /// See `crate::get_changelog`
+    pub fn get_changelog(&self, metadata: &PluginMetadata) -> Option<String> {
+        let changelog = download_unsized(self, self.download_port(), metadata.changelog_index, MAX_METADATA_FILE_SIZE)?;
+        if changelog.is_empty() {
+            return None
+        }
+
+        match String::from_utf8(changelog) {
+            Ok(changelog) => Some(changelog),
+            Err(e) => {
+                warn!("[updater] Changelog is not valid UTF-8: {}", e);
+                None
+            }
+        }
+    }
+
+    /// See `crate::get_image`. Servers which don't list the size of every image are read
+    /// until they close the connection.
+    pub fn get_image(&self, metadata: &PluginMetadata, index: usize) -> Option<Vec<u8>> {
+        match metadata.images.get(index) {
+            Some(image) => download(self, self.download_port(), image.download_index, image.size, |_| {}).ok(),
+            None if (index as u64) < metadata.image_count => {
+                download_unsized(self, self.download_port(), metadata.images_index + index as u64, MAX_METADATA_FILE_SIZE)
+            }
+            None => None,
+        }
+    }
+
+    /// See `crate::get_metadata_with_images`
+    pub fn get_metadata_with_images(&self, name: &str, selection: ImageSelection) -> Option<(PluginMetadata, Vec<Vec<u8>>)> {
+        let metadata = request_metadata(self, name, None)?;
+
+        let images = selection.select(&metadata.images)
+            .into_iter()
+            .map(|image| download(self, self.download_port(), image.download_index, image.size, |_| {}).ok())
+            .collect::<Option<Vec<_>>>()?;
+
+        Some((metadata, images))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use update_protocol::{ImageEntry, Request, ResponseCode};
+    use crate::test::{mock_client, sent_requests, versioned_response};
+    use crate::transport::test::MockTransport;
+    use crate::{changes, InstallError};
+
+    #[test]
+    fn test_beta_from_config() {
+        let dir = std::env::temp_dir().join(format!("skyline-update-beta-config-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let preferences = dir.join("preferences.toml");
+        let _ = std::fs::remove_file(&preferences);
+
+        // a beta-only version, offered to the requests which allow betas
+        let beta = versioned_response("beta_plugin", "2.0.0-beta", None);
+        let transport = MockTransport::default()
+            .respond(PORT, &UpdateResponse::no_update())
+            .respond(PORT, &beta)
+            .respond(PORT, &UpdateResponse::no_update())
+            .respond(PORT, &beta);
+        let client = mock_client(&transport).with_preferences(&preferences);
+        let offered = || client.get_update_info("beta_plugin", "1.0.0", BetaPreference::FromConfig).unwrap().code;
+
+        assert_eq!(offered(), ResponseCode::NoUpdate);
+        std::fs::write(&preferences, "beta = false\n\n[plugins.beta_plugin]\nbeta = true\n").unwrap();
+        assert_eq!(offered(), ResponseCode::Update);
+        std::fs::write(&preferences, "beta = true\n\n[plugins.beta_plugin]\nbeta = false\n").unwrap();
+        assert_eq!(offered(), ResponseCode::NoUpdate);
+
+        // a bool ignores the file
+        assert_eq!(client.get_update_info("beta_plugin", "1.0.0", true).unwrap().code, ResponseCode::Update);
+        let asked: Vec<_> = sent_requests(&transport).into_iter()
+            .map(|request| match request {
+                Request::Update { beta, .. } => beta,
+                other => panic!("unexpected request {:?}", other),
+            })
+            .collect();
+        assert_eq!(asked, vec![Some(false), Some(true), Some(false), Some(true)]);
+
+        let _ = std::fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn test_background_check() {
+        /// Holds up installing until the test releases it
+        struct HeldInstaller {
+            released: std::sync::Mutex<std::sync::mpsc::Receiver<()>>,
+        }
+
+        impl Installer for HeldInstaller {
+            fn should_update(&self, _: &UpdateResponse) -> bool {
+                true
+            }
+
+            fn install_file(&self, _: PathBuf, _: Vec<u8>) -> Result<(), InstallError> {
+                self.released.lock().unwrap().recv().unwrap();
+                Ok(())
+            }
+        }
+
+        let name = format!("background_{}", std::process::id());
+        let transport = MockTransport::default().respond(PORT, &versioned_response(&name, "1.0.0", None));
+        let (release, released) = std::sync::mpsc::channel();
+        let installer = HeldInstaller { released: std::sync::Mutex::new(released) };
+        let results = mock_client(&transport).custom_check_update_background(&name, "0.9.0", false, installer);
+
+        // the caller is back while the install is held up
+        std::thread::sleep(Duration::from_millis(100));
+        assert_eq!(results.try_recv().err(), Some(std::sync::mpsc::TryRecvError::Empty));
+
+        release.send(()).unwrap();
+        let report = results.recv_timeout(Duration::from_secs(10)).unwrap();
+        assert!(report.status().unwrap().is_installed());
+        let _ = std::fs::remove_file(changes::record_path(&changes::installed_dir(), &name));
+    }
+
+    #[test]
+    fn test_metadata_files() {
+        let files = vec![b"icon".to_vec(), b"screenshot".to_vec(), b"Fixed everything".to_vec(), vec![]];
+        let transport = MockTransport::default().serve(PORT + 1, files);
+        let client = mock_client(&transport);
+
+        let mut metadata = PluginMetadata {
+            name: None,
+            description: None,
+            images_index: 0,
+            image_count: 2,
+            changelog_index: 2,
+            images: vec![ImageEntry { download_index: 0, size: 4, original_size: 4 }],
+            homepage: None,
+            display_name: None,
+        };
+        assert_eq!(client.get_image(&metadata, 0).unwrap(), b"icon");
+        // the second image isn't listed, so it is read until the server closes the connection
+        assert_eq!(client.get_image(&metadata, 1).unwrap(), b"screenshot");
+        assert_eq!(client.get_image(&metadata, 2), None);
+        assert_eq!(client.get_changelog(&metadata).unwrap(), "Fixed everything");
+        // a plugin without a changelog is served an empty one
+        metadata.changelog_index = 3;
+        assert_eq!(client.get_changelog(&metadata), None);
+
+        assert_eq!(transport.downloads(PORT + 1), vec![0, 1, 2, 3]);
+    }
+}
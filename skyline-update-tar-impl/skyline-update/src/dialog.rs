@@ -0,0 +1,209 @@
+use std::cell::RefCell;
+
+use update_protocol::UpdateResponse;
+
+use crate::storage;
+
+/// Longest message shown by a `DialogConfig`, so a long template or plugin name doesn't overflow
+/// the dialog
+const MAX_MESSAGE_CHARS: usize = 800;
+
+/// Longest button label, past which it no longer fits its button
+const MAX_BUTTON_CHARS: usize = 32;
+
+/// Text of the dialog asking whether to install an update, for plugins which aren't in English.
+/// Use it with `check_update_with_dialog`.
+///
+/// The template is shown with these placeholders replaced, any other text in braces is kept as
+/// it is:
+/// * `{name}` - name of the plugin
+/// * `{current_version}` - version installed
+/// * `{new_version}` - version offered
+/// * `{download_size}` - size of the files to download, such as "1.5 MB"
+#[derive(Debug, Clone, PartialEq)]
+pub struct DialogConfig {
+    pub template: String,
+    /// Labels of the buttons installing and declining the update, "Yes" and "No" unless set
+    pub buttons: Option<(String, String)>,
+}
+
+impl DialogConfig {
+    pub fn new(template: impl Into<String>) -> Self {
+        DialogConfig { template: template.into(), buttons: None }
+    }
+
+    /// Label the buttons installing and declining the update
+    pub fn with_buttons(mut self, yes: impl Into<String>, no: impl Into<String>) -> Self {
+        self.buttons = Some((yes.into(), no.into()));
+        self
+    }
+
+    /// Message shown for `response` replacing `current_version`, at most `MAX_MESSAGE_CHARS`
+    /// characters
+    pub(crate) fn message(&self, response: &UpdateResponse, current_version: &str) -> String {
+        let download_size = response.required_files.iter().map(|file| file.size as u64).sum::<u64>();
+        let message = fill(&self.template, &[
+            ("name", response.display_name()),
+            ("current_version", current_version),
+            ("new_version", &response.new_plugin_version),
+            ("download_size", &storage::format_size(download_size)),
+        ]);
+
+        truncate(&message, MAX_MESSAGE_CHARS)
+    }
+
+    /// Labels of the yes and no buttons, at most `MAX_BUTTON_CHARS` characters
+    pub(crate) fn button_labels(&self) -> Option<(String, String)> {
+        self.buttons.as_ref()
+            .map(|(yes, no)| (truncate(yes, MAX_BUTTON_CHARS), truncate(no, MAX_BUTTON_CHARS)))
+    }
+}
+
+impl Default for DialogConfig {
+    /// The text shown by `check_update`
+    fn default() -> Self {
+        DialogConfig::new("An update for {name} has been found.\n\nWould you like to download it?")
+    }
+}
+
+/// Replace every `{key}` in `template` with its value, keeping unknown keys and unclosed braces
+fn fill(template: &str, values: &[(&str, &str)]) -> String {
+    let mut filled = String::with_capacity(template.len());
+    let mut rest = template;
+
+    while let Some(start) = rest.find('{') {
+        filled.push_str(&rest[..start]);
+        let placeholder = &rest[start..];
+        let value = placeholder.find('}').and_then(|end| {
+            let key = &placeholder[1..end];
+            values.iter().find(|(k, _)| *k == key).map(|(_, value)| (*value, end))
+        });
+        match value {
+            Some((value, end)) => {
+                filled.push_str(value);
+                rest = &placeholder[end + 1..];
+            }
+            None => {
+                filled.push('{');
+                rest = &placeholder[1..];
+            }
+        }
+    }
+    filled.push_str(rest);
+
+    filled
+}
+
+/// At most `max` characters of `text`, ending in `...` if anything was cut off
+fn truncate(text: &str, max: usize) -> String {
+    if text.chars().count() <= max {
+        return text.to_owned()
+    }
+
+    let mut truncated: String = text.chars().take(max - 3).collect();
+    truncated.truncate(truncated.trim_end().len());
+    truncated.push_str("...");
+    truncated
+}
+
+thread_local! {
+    /// Dialog and installed version of the update being checked for on this thread by
+    /// `check_update_with_dialog`, as `DefaultInstaller` has no place of its own to keep them
+    static CURRENT: RefCell<Option<(DialogConfig, String)>> = RefCell::new(None);
+}
+
+/// Run `f` with `DefaultInstaller` asking through `config`, restoring the dialog set before
+pub(crate) fn with_dialog<R>(config: &DialogConfig, current_version: &str, f: impl FnOnce() -> R) -> R {
+    let previous = CURRENT.with(|current| current.replace(Some((config.clone(), current_version.to_owned()))));
+    let result = f();
+    CURRENT.with(|current| *current.borrow_mut() = previous);
+
+    result
+}
+
+/// Message and button labels to ask about `response` with, None unless called from
+/// `with_dialog`
+pub(crate) fn current(response: &UpdateResponse) -> Option<(String, Option<(String, String)>)> {
+    CURRENT.with(|current| {
+        current.borrow().as_ref().map(|(config, current_version)| (config.message(response, current_version), config.button_labels()))
+    })
+}
+
+/// Ask with the message and labels of `current`
+#[cfg(target_os = "switch")]
+pub(crate) fn ask(message: String, buttons: Option<(String, String)>) -> bool {
+    match buttons {
+        Some((yes, no)) => skyline_web::Dialog::new(message, no, yes).show() == skyline_web::DialogOption::Right,
+        None => skyline_web::Dialog::yes_no(message),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use update_protocol::{InstallLocation, UpdateFile};
+
+    fn response() -> UpdateResponse {
+        UpdateResponse {
+            plugin_name: "test_plugin".to_owned(),
+            new_plugin_version: "1.1.0".to_owned(),
+            required_files: vec![UpdateFile {
+                install_location: InstallLocation::AbsolutePath("sd:/test.txt".to_owned()),
+                download_index: 0,
+                size: 1536,
+                inline_data: None,
+                sha256: None,
+                signature: None,
+            }],
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_dialog_message() {
+        let config = DialogConfig::new("{name} {current_version} -> {new_version} ({download_size})");
+        assert_eq!(config.message(&response(), "1.0.0"), "test_plugin 1.0.0 -> 1.1.0 (1.5 KB)");
+
+        assert_eq!(
+            DialogConfig::default().message(&response(), "1.0.0"),
+            "An update for test_plugin has been found.\n\nWould you like to download it?"
+        );
+    }
+
+    #[test]
+    fn test_dialog_missing_placeholders() {
+        // placeholders left out are fine, unknown ones and stray braces are shown as they are
+        assert_eq!(DialogConfig::new("Mise à jour ?").message(&response(), "1.0.0"), "Mise à jour ?");
+        assert_eq!(
+            DialogConfig::new("{nom} {name} {} {new_version").message(&response(), "1.0.0"),
+            "{nom} test_plugin {} {new_version"
+        );
+        assert_eq!(DialogConfig::new("{{name}}").message(&response(), "1.0.0"), "{test_plugin}");
+    }
+
+    #[test]
+    fn test_dialog_long_strings() {
+        let config = DialogConfig::new("{name}: {new_version}");
+        let mut response = response();
+        response.display_name = Some("ü".repeat(MAX_MESSAGE_CHARS * 2));
+
+        let message = config.message(&response, "1.0.0");
+        assert_eq!(message.chars().count(), MAX_MESSAGE_CHARS);
+        assert!(message.ends_with("ü..."));
+
+        let config = config.with_buttons("Oui".repeat(20), "Non");
+        let (yes, no) = config.button_labels().unwrap();
+        assert_eq!(yes.chars().count(), MAX_BUTTON_CHARS);
+        assert_eq!(no, "Non");
+    }
+
+    #[test]
+    fn test_with_dialog() {
+        assert_eq!(current(&response()), None);
+
+        let config = DialogConfig::new("{current_version}").with_buttons("Ja", "Nein");
+        let shown = with_dialog(&config, "1.0.0", || current(&response()));
+        assert_eq!(shown, Some(("1.0.0".to_owned(), Some(("Ja".to_owned(), "Nein".to_owned())))));
+        assert_eq!(current(&response()), None);
+    }
+}
@@ -0,0 +1,157 @@
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, UdpSocket};
+use std::time::{Duration, Instant};
+
+use log::{info, warn};
+use update_protocol::{DiscoveryResponse, DISCOVERY_MAGIC, DISCOVERY_PORT};
+
+use crate::UpdateClient;
+
+/// How long `check_update_discovered` waits for servers to answer
+pub const DISCOVERY_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Longest answer read, enough for the names of a few thousand plugins
+const MAX_RESPONSE_LEN: usize = 64 * 1024;
+
+/// An update server on the local network which answered `discover_servers`
+#[derive(Debug, Clone, PartialEq)]
+pub struct DiscoveredServer {
+    /// Address the answer came from
+    pub ip: IpAddr,
+    pub name: String,
+    /// Port update requests are accepted on
+    pub port: u16,
+    /// Names of the plugins hosted
+    pub plugins: Vec<String>,
+    pub server_version: Option<String>,
+}
+
+impl DiscoveredServer {
+    /// Whether the server hosts `plugin_name`
+    pub fn hosts(&self, plugin_name: &str) -> bool {
+        self.plugins.iter().any(|plugin| plugin == plugin_name)
+    }
+
+    /// A client for the server
+    pub fn client(&self) -> UpdateClient {
+        UpdateClient::new(self.ip).with_port(self.port)
+    }
+}
+
+/// Broadcast on the local network for update servers started with `--discoverable`, collecting
+/// every answer received within `timeout`, in the order they arrived. Meant for development,
+/// so a plugin finds the server on the developer's PC without a hardcoded IP.
+pub fn discover_servers(timeout: Duration) -> Vec<DiscoveredServer> {
+    discover_at(SocketAddr::from((Ipv4Addr::BROADCAST, DISCOVERY_PORT)), timeout)
+}
+
+/// Send the discovery datagram to `target` and collect the answers, see `discover_servers`
+pub(crate) fn discover_at(target: SocketAddr, timeout: Duration) -> Vec<DiscoveredServer> {
+    let socket = match bind_broadcast(target) {
+        Ok(socket) => socket,
+        Err(e) => {
+            warn!("[updater] Failed to look for update servers: {}", e);
+            return vec![]
+        }
+    };
+
+    let mut servers: Vec<DiscoveredServer> = vec![];
+    let mut buf = vec![0; MAX_RESPONSE_LEN];
+    let deadline = Instant::now() + timeout;
+    while let Some(left) = deadline.checked_duration_since(Instant::now()) {
+        // a zero timeout is refused, the deadline passed then
+        if socket.set_read_timeout(Some(left)).is_err() {
+            break
+        }
+        let (len, from) = match socket.recv_from(&mut buf) {
+            Ok(received) => received,
+            // timed out, or the network went away
+            Err(_) => break,
+        };
+
+        match serde_json::from_slice::<DiscoveryResponse>(&buf[..len]) {
+            Ok(response) => {
+                let server = DiscoveredServer {
+                    ip: from.ip(),
+                    name: response.name,
+                    port: response.port,
+                    plugins: response.plugins,
+                    server_version: response.server_version,
+                };
+                // a server on several interfaces may answer more than once
+                if !servers.iter().any(|known| known.ip == server.ip && known.port == server.port) {
+                    info!("[updater] Found update server {} at {}:{}", server.name, server.ip, server.port);
+                    servers.push(server);
+                }
+            }
+            Err(e) => warn!("[updater] Ignoring invalid discovery answer from {}: {}", from, e),
+        }
+    }
+
+    servers
+}
+
+fn bind_broadcast(target: SocketAddr) -> std::io::Result<UdpSocket> {
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))?;
+    socket.set_broadcast(true)?;
+    socket.send_to(DISCOVERY_MAGIC, target)?;
+
+    Ok(socket)
+}
+
+/// The first server answering within `timeout` which hosts `plugin_name`
+pub(crate) fn find_server(plugin_name: &str, timeout: Duration) -> Option<DiscoveredServer> {
+    let server = discover_servers(timeout).into_iter().find(|server| server.hosts(plugin_name));
+    if server.is_none() {
+        warn!("[{} updater] No update server on the local network hosts the plugin", plugin_name);
+    }
+
+    server
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_discover_at() {
+        let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = server.local_addr().unwrap();
+        let answers = std::thread::spawn(move || {
+            let mut buf = [0; 64];
+            let (len, from) = server.recv_from(&mut buf).unwrap();
+            assert_eq!(&buf[..len], DISCOVERY_MAGIC);
+
+            let response = DiscoveryResponse {
+                name: "desk".into(),
+                port: 46000,
+                plugins: vec!["test_plugin".into()],
+                server_version: None,
+            };
+            let response = serde_json::to_vec(&response).unwrap();
+            server.send_to(b"not json", from).unwrap();
+            server.send_to(&response, from).unwrap();
+            server.send_to(&response, from).unwrap();
+        });
+
+        let servers = discover_at(addr, Duration::from_millis(500));
+        answers.join().unwrap();
+
+        // the invalid answer is ignored and the repeated one counted once
+        assert_eq!(servers.len(), 1);
+        assert_eq!(servers[0].ip, addr.ip());
+        assert_eq!(servers[0].name, "desk");
+        assert!(servers[0].hosts("test_plugin"));
+        assert!(!servers[0].hosts("other_plugin"));
+        assert_eq!(servers[0].client().port(), 46000);
+    }
+
+    #[test]
+    fn test_discover_nothing() {
+        // nobody listens there, so the client gives up once the timeout passed
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = socket.local_addr().unwrap();
+        let start = Instant::now();
+        assert!(discover_at(addr, Duration::from_millis(100)).is_empty());
+        assert!(start.elapsed() < Duration::from_secs(5));
+    }
+}
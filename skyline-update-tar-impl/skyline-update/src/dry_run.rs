@@ -0,0 +1,236 @@
+use std::io::Cursor;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use log::{error, warn};
+use update_protocol::ResponseCode;
+
+use crate::{storage, ArchiveDecision, InstallError, Installer, Timings, UpdateClient, UpdateFile, UpdateResponse};
+
+/// A file an update would install
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PlannedFile {
+    /// Where the file would be installed
+    pub path: PathBuf,
+    pub size: usize,
+    /// For archives, every file it would be extracted to and its size. Entries which would be
+    /// skipped when extracting, such as `../evil.txt`, aren't listed.
+    pub entries: Vec<(PathBuf, u64)>,
+}
+
+/// Records which files an update would install and where instead of writing them, see
+/// `plan_update`. Every update is accepted.
+///
+/// Archives are listed rather than extracted, so nothing ends up on the SD card. Used with
+/// `custom_check_update` the client still records the update as installed, `plan_update`
+/// doesn't.
+#[derive(Debug, Default)]
+pub struct DryRunInstaller {
+    planned: Mutex<Vec<PlannedFile>>,
+}
+
+impl DryRunInstaller {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The files recorded so far, in the order they would be installed
+    pub fn planned(&self) -> Vec<PlannedFile> {
+        self.planned.lock().unwrap().clone()
+    }
+
+    /// Take the files recorded so far, leaving the installer empty
+    pub fn take(&self) -> Vec<PlannedFile> {
+        std::mem::take(&mut *self.planned.lock().unwrap())
+    }
+}
+
+impl Installer for DryRunInstaller {
+    fn should_update(&self, _: &UpdateResponse) -> bool {
+        true
+    }
+
+    fn install_file(&self, path: PathBuf, buf: Vec<u8>) -> Result<(), InstallError> {
+        let entries = if storage::is_archive(&path) {
+            archive_entries(&path, &buf)
+        } else {
+            vec![]
+        };
+
+        self.planned.lock().unwrap().push(PlannedFile { path, size: buf.len(), entries });
+        Ok(())
+    }
+
+    fn installed_sha256(&self, _: &Path) -> Option<String> {
+        None
+    }
+
+    /// The archive was never written, its entries were listed by `install_file` instead
+    fn handle_archive(&self, _: &UpdateFile, _: &Path) -> ArchiveDecision {
+        ArchiveDecision::LeaveArchived
+    }
+}
+
+/// Every file the archive at `path` would be extracted to, along with its size
+fn archive_entries(path: &Path, buf: &[u8]) -> Vec<(PathBuf, u64)> {
+    let to = path.with_extension("");
+    let entries = if storage::is_zip(path) {
+        zip_entries(buf).map_err(|e| e.to_string())
+    } else {
+        tar_entries(buf).map_err(|e| e.to_string())
+    };
+
+    match entries {
+        Ok(entries) => entries.into_iter()
+            .filter_map(|(name, size)| match crate::enclosed_entry_path(&name) {
+                Some(name) => Some((to.join(name), size)),
+                None => {
+                    warn!("[updater] '{}' in {} would be skipped, it would be extracted outside of {}", name.display(), path.display(), to.display());
+                    None
+                }
+            })
+            .collect(),
+        Err(e) => {
+            warn!("[updater] Failed to list the entries of {}: {}", path.display(), e);
+            vec![]
+        }
+    }
+}
+
+fn tar_entries(buf: &[u8]) -> std::io::Result<Vec<(PathBuf, u64)>> {
+    let mut entries = vec![];
+    for entry in tar::Archive::new(buf).entries()? {
+        let entry = entry?;
+        if entry.header().entry_type().is_file() {
+            entries.push((entry.path()?.into_owned(), entry.size()));
+        }
+    }
+
+    Ok(entries)
+}
+
+fn zip_entries(buf: &[u8]) -> zip::result::ZipResult<Vec<(PathBuf, u64)>> {
+    let mut zip = zip::ZipArchive::new(Cursor::new(buf))?;
+    let mut entries = vec![];
+    for i in 0..zip.len() {
+        let entry = zip.by_index(i)?;
+        if entry.is_file() {
+            entries.push((PathBuf::from(entry.name()), entry.size()));
+        }
+    }
+
+    Ok(entries)
+}
+
+/// Download every file of `response` into memory and list where it would be installed.
+/// Nothing is written, not even the resume progress or the installed manifest an update
+/// normally leaves behind.
+pub(crate) fn plan_files(client: &UpdateClient, response: &UpdateResponse, installer: &DryRunInstaller) -> Option<Vec<PlannedFile>> {
+    let resolved = match crate::resolve_locations(response, installer) {
+        Ok(resolved) => resolved,
+        Err(e) => {
+            warn!("[{} updater] {}", response.plugin_name, e);
+            return None
+        }
+    };
+    let download_port = resolved.download_port.unwrap_or_else(|| client.download_port());
+
+    let mut timings = Timings::default();
+    for file in &resolved.required_files {
+        let planned = crate::fetch_file(client, download_port, file, installer, None, &mut timings)
+            .and_then(|downloaded| crate::install_downloaded(file, downloaded, installer, &mut timings));
+        if let Err(e) = planned {
+            error!("[{} updater] Failed to plan the update: {}", response.plugin_name, e);
+            return None
+        }
+    }
+
+    Some(installer.take())
+}
+
+/// Ask the server for an update and list the files it would install, without installing
+/// anything. None if there is no update or it couldn't be downloaded, see `crate::plan_update`
+pub(crate) fn plan_update(client: &UpdateClient, name: &str, version: &str, allow_beta: bool) -> Option<Vec<PlannedFile>> {
+    let response = client.get_update_info(name, version, allow_beta)?;
+    if response.code != ResponseCode::Update {
+        warn!("[{} updater] No update to plan: {:?}", name, response.code);
+        return None
+    }
+
+    plan_files(client, &response, &DryRunInstaller::new())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test::{mock_client, raw_tar_of, versioned_response};
+    use crate::transport::test::MockTransport;
+    use crate::{changes, resume, PORT};
+    use update_protocol::InstallLocation;
+
+    fn tar_of(files: &[(&str, &str)]) -> Vec<u8> {
+        let mut builder = tar::Builder::new(vec![]);
+        for (name, data) in files {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(data.len() as u64);
+            header.set_mode(0o644);
+            header.set_cksum();
+            builder.append_data(&mut header, name, data.as_bytes()).unwrap();
+        }
+
+        builder.into_inner().unwrap()
+    }
+
+    #[test]
+    fn test_dry_run_lists_archive_entries() {
+        let installer = DryRunInstaller::new();
+        let archive = tar_of(&[("a.txt", "hello"), ("inner/b.txt", "hi")]);
+        let archive_len = archive.len();
+
+        installer.install_file("sd:/plugin/data.tar".into(), archive).unwrap();
+        installer.install_file("sd:/plugin/plugin.nro".into(), b"nro".to_vec()).unwrap();
+
+        assert_eq!(installer.take(), vec![
+            PlannedFile {
+                path: "sd:/plugin/data.tar".into(),
+                size: archive_len,
+                entries: vec![("sd:/plugin/data/a.txt".into(), 5), ("sd:/plugin/data/inner/b.txt".into(), 2)],
+            },
+            PlannedFile { path: "sd:/plugin/plugin.nro".into(), size: 3, entries: vec![] },
+        ]);
+        assert!(installer.planned().is_empty());
+        assert!(!Path::new("sd:/plugin").exists());
+    }
+
+    #[test]
+    fn test_plan_update() {
+        let name = format!("plan_{}", std::process::id());
+        let archive = raw_tar_of(&["a.txt", "inner/b.txt"]);
+        let archive_len = archive.len();
+
+        let mut response = versioned_response(&name, "1.0.0", None);
+        response.required_files.push(UpdateFile {
+            install_location: InstallLocation::AbsolutePath("sd:/plan/data.tar".into()),
+            download_index: 1,
+            size: archive_len,
+            inline_data: None,
+            sha256: None,
+            signature: None,
+        });
+        let transport = MockTransport::default().respond(PORT, &response).reply(PORT + 1, archive);
+
+        let plan = mock_client(&transport).plan_update(&name, "0.9.0", false).unwrap();
+        assert!(transport.is_done());
+
+        assert_eq!(plan, vec![
+            PlannedFile { path: "sd:/rollback/plugin.nro".into(), size: 5, entries: vec![] },
+            PlannedFile {
+                path: "sd:/plan/data.tar".into(),
+                size: archive_len,
+                entries: vec![("sd:/plan/data/a.txt".into(), 5), ("sd:/plan/data/inner/b.txt".into(), 5)],
+            },
+        ]);
+        assert!(resume::load_progress(&resume::progress_dir(), &name).is_none());
+        assert!(changes::load_manifest(&changes::installed_dir(), &name).is_none());
+    }
+}
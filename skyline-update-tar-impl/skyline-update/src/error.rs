@@ -0,0 +1,198 @@
+use std::fmt;
+use std::io;
+use std::path::PathBuf;
+
+use update_protocol::UpdateResponse;
+
+/// Outcome of a check for updates which didn't fail
+#[derive(Debug, Clone, PartialEq)]
+// returned once per check, boxing the response would only make it harder to match on
+#[allow(clippy::large_enum_variant)]
+pub enum UpdateStatus {
+    /// No newer version is available
+    UpToDate,
+    /// A newer version was installed, see `UpdateReport::installed_paths`
+    Installed {
+        /// The response the update was installed from, as received
+        response: UpdateResponse,
+        installed_paths: Vec<PathBuf>,
+    },
+    /// A newer version is available, but the installer chose not to install it
+    Declined,
+    /// A newer version is available which the updater can't install, the user was told where
+    /// to get it (or was already told before)
+    Notified,
+    /// A newer version is available, but it requires a newer skyline than the one running and
+    /// wasn't installed, see `UpdateReport::skyline`
+    SkylineOutdated,
+    /// A newer version is available, but installing it was cancelled with a `CancelToken`.
+    /// Files before the one being installed may already be installed.
+    Cancelled,
+}
+
+impl UpdateStatus {
+    /// Whether a newer version was installed
+    pub fn is_installed(&self) -> bool {
+        matches!(self, UpdateStatus::Installed { .. })
+    }
+}
+
+/// Why a check for updates failed
+#[derive(Debug, Clone, PartialEq)]
+pub enum UpdateError {
+    /// The update server's hostname couldn't be resolved
+    Resolve { host: String, reason: String },
+    /// The update server couldn't be reached
+    Connect(String),
+    /// The update server accepted the connection but didn't respond in time
+    Timeout,
+    /// The TLS handshake with the update server failed, such as its certificate not being
+    /// trusted or not valid for the server name, see `UpdateClient::with_tls`
+    Tls(String),
+    /// The update server's response couldn't be understood
+    Parse(String),
+    /// The update server answered with a response code this version of the updater doesn't
+    /// know, as sent. The server is likely newer than the updater.
+    UnknownResponseCode(String),
+    /// The update server can't answer right now and asked to be asked again after
+    /// `retry_after` seconds, or later if it didn't say, see
+    /// `UpdateClient::with_rate_limit_retry`
+    RateLimited { retry_after: Option<u64> },
+    /// The update server failed to answer the request, for the reason it sent
+    ServerError(String),
+    /// The server offered an update without any files to install, see
+    /// `UpdateResponse::is_empty_update`. Nothing was installed.
+    EmptyUpdate,
+    /// A file of the update couldn't be downloaded, `reason` says why. Files before it may
+    /// already be installed.
+    Download { index: u64, reason: String },
+    /// The server no longer hosts a file of the update, as its plugins were reloaded since it
+    /// offered the update. The update is asked for once more before this is reported.
+    FileGone { index: u64 },
+    /// A downloaded file didn't match the SHA-256 the server sent for it, and wasn't installed.
+    /// Files before it may already be installed.
+    Checksum { index: u64, expected: String, actual: String },
+    /// A file of the update wasn't signed with the plugin author's key, see
+    /// `UpdateClient::with_public_key`. Nothing of the update was downloaded.
+    Signature { index: u64, reason: String },
+    /// A file of the update couldn't be installed. Files before it may already be installed.
+    Install { path: String, reason: String },
+    /// A file of the update is larger than `UpdateClient::with_max_download_size` allows, and
+    /// wasn't downloaded. Files before it may already be installed.
+    TooLarge { path: String, size: usize, limit: usize },
+    /// The SD card was removed or remounted while installing `path`, which wasn't installed.
+    /// Files before it may already be installed, nothing after it was attempted.
+    StorageUnavailable { path: String },
+    /// The server didn't understand the request, for the reason it sent if it sent one
+    InvalidRequest(Option<String>),
+    /// The server doesn't host the plugin, with the reason it sent if it sent one, such as a
+    /// plugin with a similar name it does host
+    PluginNotFound(Option<String>),
+    /// The server hosts the plugin but not the version asked for with
+    /// `UpdateClient::check_update_to_version` or to roll back to, with the reason it sent if it
+    /// sent one
+    VersionNotFound(Option<String>),
+    /// The server has no version of the plugin to roll back to, or predates rolling back
+    RollbackUnavailable,
+    /// The update was cancelled with a `CancelToken`. `UpdateReport::status` reports it as
+    /// `UpdateStatus::Cancelled` rather than as an error.
+    Cancelled,
+}
+
+impl fmt::Display for UpdateError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            UpdateError::Resolve { host, reason } => write!(f, "failed to resolve the update server {}: {}", host, reason),
+            UpdateError::Connect(e) => write!(f, "failed to connect to the update server: {}", e),
+            UpdateError::Timeout => f.write_str("the update server did not respond in time"),
+            UpdateError::Tls(e) => write!(f, "failed to establish a secure connection to the update server: {}", e),
+            UpdateError::Parse(e) => write!(f, "failed to parse the update server's response: {}", e),
+            UpdateError::UnknownResponseCode(code) => write!(f, "the update server answered with an unknown response code {}", code),
+            UpdateError::RateLimited { retry_after: Some(seconds) } => write!(f, "the update server is busy, try again in {} seconds", seconds),
+            UpdateError::RateLimited { retry_after: None } => f.write_str("the update server is busy, try again later"),
+            UpdateError::ServerError(e) => write!(f, "the update server failed to answer: {}", e),
+            UpdateError::EmptyUpdate => f.write_str("the update server offered an update without any files"),
+            UpdateError::Download { index, reason } => write!(f, "failed to download file {}: {}", index, reason),
+            UpdateError::FileGone { index } => write!(f, "failed to download file {}: the update changed on the server", index),
+            UpdateError::Checksum { index, expected, actual } => write!(f, "file {} was corrupted while downloading: expected sha256 {}, got {}", index, expected, actual),
+            UpdateError::Signature { index, reason } => write!(f, "refusing to install the update: file {} {}", index, reason),
+            UpdateError::Install { path, reason } => write!(f, "failed to install {}: {}", path, reason),
+            UpdateError::TooLarge { path, size, limit } => write!(f, "refusing to download {}: it is {} bytes, over the limit of {} bytes", path, size, limit),
+            UpdateError::StorageUnavailable { path } => write!(f, "the SD card became unavailable while installing {}", path),
+            UpdateError::InvalidRequest(None) => f.write_str("the update server rejected the request as invalid"),
+            UpdateError::InvalidRequest(Some(reason)) => write!(f, "the update server rejected the request as invalid: {}", reason),
+            UpdateError::PluginNotFound(None) => f.write_str("the plugin could not be found on the update server"),
+            UpdateError::PluginNotFound(Some(reason)) => write!(f, "the plugin could not be found on the update server: {}", reason),
+            UpdateError::VersionNotFound(None) => f.write_str("the version asked for could not be found on the update server"),
+            UpdateError::VersionNotFound(Some(reason)) => write!(f, "the version asked for could not be found on the update server: {}", reason),
+            UpdateError::RollbackUnavailable => f.write_str("the update server has no version of the plugin to roll back to"),
+            UpdateError::Cancelled => f.write_str("the update was cancelled"),
+        }
+    }
+}
+
+impl std::error::Error for UpdateError {}
+
+/// Why `uninstall` deleted nothing
+#[derive(Debug, Clone, PartialEq)]
+pub enum UninstallError {
+    /// The updater has no record of installing the plugin, or it was uninstalled already
+    NotInstalled,
+    /// `Installer::should_uninstall` said no
+    Declined,
+}
+
+impl fmt::Display for UninstallError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            UninstallError::NotInstalled => write!(f, "the plugin wasn't installed by the updater"),
+            UninstallError::Declined => write!(f, "uninstalling was declined"),
+        }
+    }
+}
+
+impl std::error::Error for UninstallError {}
+
+/// Why an `Installer` failed to install a file, reported as `UpdateError::Install`
+#[derive(Debug)]
+pub struct InstallError {
+    pub path: PathBuf,
+    pub source: io::Error,
+    /// What the installer was doing when it failed, such as `"backing up the old file"`
+    pub context: Option<String>,
+}
+
+impl InstallError {
+    pub fn new(path: impl Into<PathBuf>, source: io::Error) -> Self {
+        InstallError { path: path.into(), source, context: None }
+    }
+
+    /// A failure without an `io::Error` behind it, such as an installer refusing a file
+    pub fn other(path: impl Into<PathBuf>, reason: impl Into<String>) -> Self {
+        InstallError::new(path, io::Error::new(io::ErrorKind::Other, reason.into()))
+    }
+
+    pub fn with_context(self, context: impl Into<String>) -> Self {
+        InstallError { context: Some(context.into()), ..self }
+    }
+
+    /// Why installing failed, without the path
+    pub fn reason(&self) -> String {
+        match &self.context {
+            Some(context) => format!("{}: {}", context, self.source),
+            None => self.source.to_string(),
+        }
+    }
+}
+
+impl fmt::Display for InstallError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "failed to install {}: {}", self.path.display(), self.reason())
+    }
+}
+
+impl std::error::Error for InstallError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(&self.source)
+    }
+}
@@ -0,0 +1,325 @@
+use std::fmt::Write;
+use std::time::Duration;
+
+use update_protocol::{ResponseCode, UpdateResponse};
+
+use crate::{FileOutcome, UpdateError, UpdateReport, UpdateStatus};
+
+/// Exit code of a command line tool checking for or verifying updates, for scripts. Codes are
+/// grouped by tens so related outcomes stay close. Changing a code is a breaking change.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum ExitStatus {
+    /// Up to date, or every check passed
+    Success = 0,
+    /// The tool itself failed, such as a missing file it needed
+    Failure = 1,
+    /// Invalid arguments
+    Usage = 2,
+    /// A newer version was installed
+    Updated = 10,
+    /// A newer version is available, but wasn't installed
+    Declined = 20,
+    /// A newer version is available which the updater can't install
+    Notified = 21,
+    /// A newer version is available, but installing it was cancelled
+    Cancelled = 22,
+    /// The server couldn't be resolved, reached, didn't respond in time or asked to be asked
+    /// again later, or a download failed or was corrupted
+    Network = 30,
+    /// The server's response couldn't be understood, or it rejected the request, failed to
+    /// answer it, didn't host the plugin or had no version to roll back to
+    Server = 40,
+    /// A file couldn't be installed, or didn't match what the server hosts once installed
+    Install = 50,
+    /// The SD card became unavailable while installing
+    StorageUnavailable = 51,
+}
+
+impl ExitStatus {
+    /// Every status, in the order of their codes
+    pub const ALL: [ExitStatus; 11] = [
+        ExitStatus::Success,
+        ExitStatus::Failure,
+        ExitStatus::Usage,
+        ExitStatus::Updated,
+        ExitStatus::Declined,
+        ExitStatus::Notified,
+        ExitStatus::Cancelled,
+        ExitStatus::Network,
+        ExitStatus::Server,
+        ExitStatus::Install,
+        ExitStatus::StorageUnavailable,
+    ];
+
+    pub fn code(self) -> i32 {
+        self as i32
+    }
+
+    /// Name of the status in the `--json` status line
+    pub fn name(self) -> &'static str {
+        match self {
+            ExitStatus::Success => "success",
+            ExitStatus::Failure => "failure",
+            ExitStatus::Usage => "usage",
+            ExitStatus::Updated => "updated",
+            ExitStatus::Declined => "declined",
+            ExitStatus::Notified => "notified",
+            ExitStatus::Cancelled => "cancelled",
+            ExitStatus::Network => "network_error",
+            ExitStatus::Server => "server_error",
+            ExitStatus::Install => "install_error",
+            ExitStatus::StorageUnavailable => "storage_unavailable",
+        }
+    }
+
+    fn description(self) -> &'static str {
+        match self {
+            ExitStatus::Success => "up to date, or every check passed",
+            ExitStatus::Failure => "the tool itself failed",
+            ExitStatus::Usage => "invalid arguments",
+            ExitStatus::Updated => "a newer version was installed",
+            ExitStatus::Declined => "a newer version is available but wasn't installed",
+            ExitStatus::Notified => "a newer version is available which the updater can't install",
+            ExitStatus::Cancelled => "a newer version is available but installing it was cancelled",
+            ExitStatus::Network => "the server couldn't be reached, or a download failed",
+            ExitStatus::Server => "the server's response was invalid, or it doesn't host the plugin",
+            ExitStatus::Install => "a file couldn't be installed, or doesn't match the server's once installed",
+            ExitStatus::StorageUnavailable => "the SD card became unavailable while installing",
+        }
+    }
+
+    /// The exit codes section of a `--help` text, one code per line
+    pub fn help() -> String {
+        let mut help = String::from("Exit codes:\n");
+        for status in ExitStatus::ALL.iter() {
+            let _ = writeln!(help, "    {:>2}  {:<20} {}", status.code(), status.name(), status.description());
+        }
+
+        help
+    }
+
+    /// The status of several checks, the highest code wins
+    pub fn combine(self, other: ExitStatus) -> ExitStatus {
+        self.max(other)
+    }
+}
+
+impl From<&UpdateStatus> for ExitStatus {
+    fn from(status: &UpdateStatus) -> Self {
+        match status {
+            UpdateStatus::UpToDate => ExitStatus::Success,
+            UpdateStatus::Installed { .. } => ExitStatus::Updated,
+            UpdateStatus::Declined | UpdateStatus::SkylineOutdated => ExitStatus::Declined,
+            UpdateStatus::Notified => ExitStatus::Notified,
+            UpdateStatus::Cancelled => ExitStatus::Cancelled,
+        }
+    }
+}
+
+impl From<&UpdateError> for ExitStatus {
+    fn from(error: &UpdateError) -> Self {
+        match error {
+            UpdateError::Resolve { .. } | UpdateError::Connect(_) | UpdateError::Timeout | UpdateError::Tls(_) | UpdateError::RateLimited { .. } | UpdateError::Download { .. } | UpdateError::FileGone { .. } | UpdateError::Checksum { .. } => ExitStatus::Network,
+            UpdateError::Parse(_) | UpdateError::UnknownResponseCode(_) | UpdateError::ServerError(_) | UpdateError::EmptyUpdate | UpdateError::Signature { .. } | UpdateError::TooLarge { .. } | UpdateError::InvalidRequest(_) | UpdateError::PluginNotFound(_) | UpdateError::VersionNotFound(_) | UpdateError::RollbackUnavailable => ExitStatus::Server,
+            UpdateError::Install { .. } => ExitStatus::Install,
+            UpdateError::StorageUnavailable { .. } => ExitStatus::StorageUnavailable,
+            UpdateError::Cancelled => ExitStatus::Cancelled,
+        }
+    }
+}
+
+impl From<&Result<UpdateStatus, UpdateError>> for ExitStatus {
+    fn from(result: &Result<UpdateStatus, UpdateError>) -> Self {
+        match result {
+            Ok(status) => ExitStatus::from(status),
+            Err(error) => ExitStatus::from(error),
+        }
+    }
+}
+
+/// The outcome of checking a single plugin, as reported in the `--json` status line
+#[derive(Debug, Clone, PartialEq)]
+pub struct PluginStatus {
+    pub name: String,
+    pub status: ExitStatus,
+    pub current_version: String,
+    /// Version the server offered, if it offered one
+    pub new_version: Option<String>,
+    /// Size of the files installed
+    pub bytes: u64,
+    pub error: Option<String>,
+}
+
+impl PluginStatus {
+    pub fn from_report(name: &str, current_version: &str, report: &UpdateReport) -> Self {
+        let status = report.status();
+        let new_version = report.response.as_ref()
+            .filter(|response| response.code == ResponseCode::Update || response.code == ResponseCode::NotifyOnly)
+            .map(|response| response.new_plugin_version.clone());
+
+        PluginStatus {
+            name: name.to_owned(),
+            status: ExitStatus::from(&status),
+            current_version: current_version.to_owned(),
+            new_version,
+            bytes: report.response.as_ref().map(|response| installed_bytes(response, report)).unwrap_or(0),
+            error: status.err().map(|e| e.to_string()),
+        }
+    }
+
+    fn to_json(&self) -> serde_json::Value {
+        serde_json::json!({
+            "name": self.name,
+            "status": self.status.name(),
+            "code": self.status.code(),
+            "current_version": self.current_version,
+            "new_version": self.new_version,
+            "bytes": self.bytes,
+            "error": self.error,
+        })
+    }
+}
+
+/// Size of the files of `response` installed by this attempt
+fn installed_bytes(response: &UpdateResponse, report: &UpdateReport) -> u64 {
+    response.required_files.iter()
+        .zip(&report.files)
+        .filter(|(_, file)| file.outcome == FileOutcome::Installed)
+        .map(|(file, _)| file.size as u64)
+        .sum()
+}
+
+/// Summary of a whole run, printed as a single JSON line last by `--json`
+#[derive(Debug, Clone, PartialEq)]
+pub struct StatusLine {
+    pub plugins: Vec<PluginStatus>,
+    pub duration: Duration,
+}
+
+impl StatusLine {
+    /// Status of the run, see `ExitStatus::combine`. Success if no plugin was checked.
+    pub fn status(&self) -> ExitStatus {
+        self.plugins.iter().fold(ExitStatus::Success, |status, plugin| status.combine(plugin.status))
+    }
+
+    pub fn to_json(&self) -> String {
+        serde_json::json!({
+            "status": self.status().name(),
+            "code": self.status().code(),
+            "bytes": self.plugins.iter().map(|plugin| plugin.bytes).sum::<u64>(),
+            "duration_ms": self.duration.as_millis() as u64,
+            "plugins": self.plugins.iter().map(PluginStatus::to_json).collect::<Vec<_>>(),
+        }).to_string()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::FileReport;
+    use update_protocol::{InstallLocation, UpdateFile};
+
+    /// Scripts rely on these, changing one is a breaking change
+    #[test]
+    fn test_exit_codes() {
+        let codes: Vec<_> = ExitStatus::ALL.iter().map(|status| (status.code(), status.name())).collect();
+        assert_eq!(codes, vec![
+            (0, "success"),
+            (1, "failure"),
+            (2, "usage"),
+            (10, "updated"),
+            (20, "declined"),
+            (21, "notified"),
+            (22, "cancelled"),
+            (30, "network_error"),
+            (40, "server_error"),
+            (50, "install_error"),
+            (51, "storage_unavailable"),
+        ]);
+
+        let installed = UpdateStatus::Installed { response: Default::default(), installed_paths: vec![] };
+        let statuses = [UpdateStatus::UpToDate, installed, UpdateStatus::Declined, UpdateStatus::Notified, UpdateStatus::Cancelled];
+        let codes: Vec<_> = statuses.iter().map(|status| ExitStatus::from(status).code()).collect();
+        assert_eq!(codes, vec![0, 10, 20, 21, 22]);
+
+        let errors = [
+            UpdateError::Resolve { host: "a".into(), reason: "b".into() },
+            UpdateError::Connect("a".into()),
+            UpdateError::Timeout,
+            UpdateError::Tls("a".into()),
+            UpdateError::RateLimited { retry_after: Some(1) },
+            UpdateError::Download { index: 0, reason: String::new() },
+            UpdateError::FileGone { index: 0 },
+            UpdateError::Checksum { index: 0, expected: "a".into(), actual: "b".into() },
+            UpdateError::Parse("a".into()),
+            UpdateError::UnknownResponseCode("a".into()),
+            UpdateError::ServerError("a".into()),
+            UpdateError::EmptyUpdate,
+            UpdateError::Signature { index: 0, reason: "a".into() },
+            UpdateError::TooLarge { path: "a".into(), size: 2, limit: 1 },
+            UpdateError::InvalidRequest(None),
+            UpdateError::PluginNotFound(Some("a".into())),
+            UpdateError::VersionNotFound(None),
+            UpdateError::RollbackUnavailable,
+            UpdateError::Install { path: "a".into(), reason: "b".into() },
+            UpdateError::StorageUnavailable { path: "a".into() },
+            UpdateError::Cancelled,
+        ];
+        let codes: Vec<_> = errors.iter().map(|error| ExitStatus::from(error).code()).collect();
+        assert_eq!(codes, vec![30, 30, 30, 30, 30, 30, 30, 30, 40, 40, 40, 40, 40, 40, 40, 40, 40, 40, 50, 51, 22]);
+    }
+
+    #[test]
+    fn test_help() {
+        let help = ExitStatus::help();
+        assert_eq!(help.lines().count(), ExitStatus::ALL.len() + 1);
+        assert!(help.contains("    10  updated"), "{}", help);
+    }
+
+    #[test]
+    fn test_status_line() {
+        let file = |path: &str, size| UpdateFile {
+            install_location: InstallLocation::AbsolutePath(path.into()),
+            download_index: 0,
+            size,
+            inline_data: None,
+            sha256: None,
+            signature: None,
+        };
+        let report = UpdateReport {
+            response: Some(UpdateResponse {
+                code: ResponseCode::Update,
+                new_plugin_version: "1.1.0".into(),
+                required_files: vec![file("sd:/a.txt", 100), file("sd:/b.txt", 20), file("sd:/c.txt", 3)],
+                ..Default::default()
+            }),
+            installed: true,
+            files: [FileOutcome::Installed, FileOutcome::AlreadyInstalled, FileOutcome::Installed].iter()
+                .map(|outcome| FileReport { install_location: InstallLocation::Unknown, outcome: outcome.clone(), archive: None, extracted: vec![] })
+                .collect(),
+            ..Default::default()
+        };
+        let updated = PluginStatus::from_report("a", "1.0.0", &report);
+        assert_eq!(updated.status, ExitStatus::Updated);
+        assert_eq!(updated.new_version.as_deref(), Some("1.1.0"));
+        assert_eq!(updated.bytes, 103);
+
+        let failed = PluginStatus::from_report("b", "1.0.0", &UpdateReport { error: Some(UpdateError::Timeout), ..Default::default() });
+        assert_eq!(failed.status, ExitStatus::Network);
+
+        let line = StatusLine { plugins: vec![updated, failed], duration: Duration::from_millis(1500) };
+        assert_eq!(line.status(), ExitStatus::Network);
+
+        let json = line.to_json();
+        assert!(!json.contains('\n'));
+        let json: serde_json::Value = serde_json::from_str(&json).unwrap();
+        assert_eq!(json["status"], "network_error");
+        assert_eq!(json["code"], 30);
+        assert_eq!(json["bytes"], 103);
+        assert_eq!(json["duration_ms"], 1500);
+        assert_eq!(json["plugins"][0]["new_version"], "1.1.0");
+        assert_eq!(json["plugins"][1]["error"], UpdateError::Timeout.to_string());
+
+        assert_eq!(StatusLine { plugins: vec![], duration: Duration::default() }.status(), ExitStatus::Success);
+    }
+}
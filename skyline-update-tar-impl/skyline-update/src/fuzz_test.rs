@@ -0,0 +1,95 @@
+//! Throws mutated server responses at everything the client does with a response before
+//! downloading anything, from the seeds in `fuzz-corpus`. A hostile or broken server must not
+//! be able to panic a console.
+
+use super::*;
+use update_protocol::{fuzz, PingResponse};
+
+fn corpus(name: &str) -> Vec<Vec<u8>> {
+    fuzz::load_corpus(&Path::new(env!("CARGO_MANIFEST_DIR")).join("../fuzz-corpus").join(name))
+}
+
+/// Declines every update, so handling a response never reaches the network
+struct DecliningInstaller;
+
+impl Installer for DecliningInstaller {
+    fn should_update(&self, _: &UpdateResponse) -> bool {
+        false
+    }
+
+    fn install_file(&self, path: PathBuf, _: Vec<u8>) -> Result<(), InstallError> {
+        Err(InstallError::other(path, "every update is declined"))
+    }
+}
+
+fn exercise(response: &UpdateResponse) {
+    serde_json::to_string(response).unwrap();
+
+    let _ = response_case_collision(response);
+    let _ = skyline_mismatch(response, Some("0.3.0"));
+    let _ = notification_message(response);
+    for file in &response.required_files {
+        let _ = inline_file(file);
+        let _ = file.install_location.normalized();
+        let _ = storage::install_path(file);
+    }
+
+    let manifest = InstalledManifest::from_response(response);
+    let _ = InstalledManifest::parse(&manifest.to_string());
+    let _ = compute_change_set(Some(&manifest), response).install_root();
+    let _ = compute_change_set(None, response).install_root();
+    let _ = storage::estimate_storage(response, &vec![Some(u64::MAX); response.required_files.len()]);
+    let _ = BatchPrompt::new(vec![response]).total_size;
+
+    let dir = changes::installed_dir();
+    assert_eq!(changes::record_path(&dir, &response.plugin_name).parent(), Some(dir.as_path()));
+
+    // notify-only responses write a file per plugin name, which is covered by `record_path`
+    if response.code != ResponseCode::NotifyOnly {
+        let client = UpdateClient::new("127.0.0.1".parse().unwrap());
+        let mut report = UpdateReport::default();
+        handle_response(&client, "fuzz_plugin", response, &DecliningInstaller, &mut report, false, false);
+        assert!(!report.installed);
+    }
+}
+
+#[test]
+fn fuzz_responses() {
+    fuzz::run(&corpus("responses"), |input| {
+        let string = String::from_utf8_lossy(input);
+
+        if let Ok(response) = serde_json::from_str::<UpdateResponse>(&string) {
+            exercise(&response);
+        }
+        if let Ok(metadata) = serde_json::from_str::<PluginMetadata>(&string) {
+            for selection in &[ImageSelection::IconOnly, ImageSelection::MaxBytes(1024), ImageSelection::All] {
+                let _ = selection.select(&metadata.images);
+            }
+        }
+        let _ = serde_json::from_str::<PingResponse>(&string);
+    });
+}
+
+#[test]
+fn test_response_read_bounded() {
+    let mut string = String::new();
+    read_response(std::io::repeat(b'a'), &mut string).unwrap();
+    assert_eq!(string.len() as u64, MAX_RESPONSE_LEN);
+}
+
+#[test]
+fn test_oversized_sizes() {
+    let file = |size| UpdateFile {
+        install_location: InstallLocation::AbsolutePath("sd:/mods/huge.tar".into()),
+        download_index: 0,
+        size,
+        inline_data: None,
+        sha256: None,
+        signature: None,
+    };
+    let response = UpdateResponse { required_files: vec![file(usize::MAX), file(usize::MAX)], ..Default::default() };
+
+    let estimate = storage::estimate_storage(&response, &[Some(u64::MAX), Some(1)]).unwrap();
+    assert_eq!((estimate.before, estimate.after), (u64::MAX, u64::MAX));
+    assert_eq!(BatchPrompt::new(vec![&response, &response]).total_size, usize::MAX);
+}
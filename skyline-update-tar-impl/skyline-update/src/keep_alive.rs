@@ -0,0 +1,167 @@
+use std::sync::{Arc, Mutex};
+
+use crate::tls::Connection;
+
+/// Download connections kept open between the files of an update, see
+/// `update_protocol::KEEP_ALIVE_INDEX`. Clones share them, so the threads prefetching files
+/// reuse them too.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct KeepAlive {
+    state: Arc<Mutex<State>>,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    /// Download ports of servers which said they keep connections alive
+    ports: Vec<u16>,
+    idle: Vec<(u16, Connection)>,
+}
+
+impl KeepAlive {
+    /// Keep connections to `port` open between files from now on
+    pub(crate) fn enable(&self, port: u16) {
+        let mut state = self.state.lock().unwrap();
+        if !state.ports.contains(&port) {
+            state.ports.push(port);
+        }
+    }
+
+    /// Download every file from `port` over a connection of its own again, closing the idle
+    /// connections to it. Done once an update is over, or once the server closed a connection
+    /// it was asked to keep open.
+    pub(crate) fn disable(&self, port: u16) {
+        let mut state = self.state.lock().unwrap();
+        state.ports.retain(|&kept| kept != port);
+        state.idle.retain(|(kept, _)| *kept != port);
+    }
+
+    pub(crate) fn is_enabled(&self, port: u16) -> bool {
+        self.state.lock().unwrap().ports.contains(&port)
+    }
+
+    /// An idle connection to `port`, None if there is none
+    pub(crate) fn take(&self, port: u16) -> Option<Connection> {
+        let mut state = self.state.lock().unwrap();
+        let position = state.idle.iter().position(|(kept, _)| *kept == port)?;
+        Some(state.idle.swap_remove(position).1)
+    }
+
+    /// Hand a connection back once a file was downloaded over it, closing it instead if
+    /// connections to `port` stopped being kept alive meanwhile
+    pub(crate) fn put(&self, port: u16, stream: Connection) {
+        let mut state = self.state.lock().unwrap();
+        if state.ports.contains(&port) {
+            state.idle.push((port, stream));
+        }
+    }
+}
+
+/// Connections are equal if they are shared, as with `CancelToken`
+impl PartialEq for KeepAlive {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.state, &other.state)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::{Read, Write};
+    use update_protocol::{InstallLocation, UpdateFile, UpdateResponse};
+    use crate::test::{short_timeouts, test_file, RecordingInstaller};
+    use crate::{update_files, FileOutcome, Timings, UpdateClient, DOWNLOAD_CHUNK};
+
+    /// A response with a file of `size` bytes at every download index, downloaded from `port`
+    fn keep_alive_response(plugin_name: &str, sizes: &[usize], port: u16) -> UpdateResponse {
+        UpdateResponse {
+            plugin_name: plugin_name.into(),
+            required_files: sizes.iter()
+                .enumerate()
+                .map(|(index, &size)| UpdateFile {
+                    install_location: InstallLocation::AbsolutePath(format!("sd:/keep_alive/{}.txt", index)),
+                    download_index: index as u64,
+                    ..test_file(size, None)
+                })
+                .collect(),
+            download_port: Some(port),
+            keep_alive_downloads: true,
+            ..Default::default()
+        }
+    }
+
+    fn read_index(socket: &mut std::net::TcpStream) -> Option<u64> {
+        let mut index = [0; 8];
+        socket.read_exact(&mut index).ok()?;
+        Some(u64::from_be_bytes(index))
+    }
+
+    /// The data of the file at `index`, every byte of it `index`
+    fn indexed_data(index: u64, sizes: &[usize]) -> Vec<u8> {
+        vec![index as u8; sizes[index as usize]]
+    }
+
+    #[test]
+    fn test_keep_alive_downloads() {
+        let sizes = [10, 2 * DOWNLOAD_CHUNK + 5, 30];
+        let downloads = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let response = keep_alive_response("keep_alive_plugin", &sizes, downloads.local_addr().unwrap().port());
+
+        // a single connection serves every file
+        let server = std::thread::spawn(move || {
+            let (mut socket, _) = downloads.accept().unwrap();
+            assert_eq!(read_index(&mut socket), Some(update_protocol::KEEP_ALIVE_INDEX));
+            let mut served = vec![];
+            while let Some(index) = read_index(&mut socket) {
+                let data = indexed_data(index, &sizes);
+                socket.write_all(&(data.len() as u64).to_be_bytes()).unwrap();
+                socket.write_all(&data).unwrap();
+                served.push(index);
+            }
+            served
+        });
+
+        let client = UpdateClient::new("127.0.0.1".parse().unwrap()).with_max_concurrent_downloads(1).with_timeouts(short_timeouts());
+        let installer = RecordingInstaller::default();
+        let files = update_files(&client, &response, &installer, &mut Timings::default());
+        assert!(files.iter().all(|file| file.outcome == FileOutcome::Installed), "{:?}", files);
+
+        // the connection is closed once the update is done
+        assert_eq!(server.join().unwrap(), vec![0, 1, 2]);
+        let installed: Vec<_> = installer.installed.borrow().iter().map(|(_, data)| data.clone()).collect();
+        assert_eq!(installed, (0..3).map(|index| indexed_data(index, &sizes)).collect::<Vec<_>>());
+        assert!(!client.keep_alive().is_enabled(response.download_port.unwrap()));
+    }
+
+    #[test]
+    fn test_keep_alive_fallback() {
+        let sizes = [10, 20, 30];
+        let downloads = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let response = keep_alive_response("keep_alive_fallback_plugin", &sizes, downloads.local_addr().unwrap().port());
+
+        // the first file is sent on a kept-alive connection, which is closed right after it
+        let server = std::thread::spawn(move || {
+            let (mut socket, _) = downloads.accept().unwrap();
+            assert_eq!(read_index(&mut socket), Some(update_protocol::KEEP_ALIVE_INDEX));
+            let data = indexed_data(read_index(&mut socket).unwrap(), &sizes);
+            socket.write_all(&(data.len() as u64).to_be_bytes()).unwrap();
+            socket.write_all(&data).unwrap();
+            drop(socket);
+
+            // every later file gets a connection of its own
+            let mut served = vec![];
+            for _ in 1..sizes.len() {
+                let (mut socket, _) = downloads.accept().unwrap();
+                let index = read_index(&mut socket).unwrap();
+                socket.write_all(&indexed_data(index, &sizes)).unwrap();
+                served.push(index);
+            }
+            served
+        });
+
+        let client = UpdateClient::new("127.0.0.1".parse().unwrap()).with_max_concurrent_downloads(1).with_timeouts(short_timeouts());
+        let installer = RecordingInstaller::default();
+        let files = update_files(&client, &response, &installer, &mut Timings::default());
+        assert!(files.iter().all(|file| file.outcome == FileOutcome::Installed), "{:?}", files);
+        assert_eq!(server.join().unwrap(), vec![1, 2]);
+        assert_eq!(installer.installed.borrow().len(), 3);
+    }
+}
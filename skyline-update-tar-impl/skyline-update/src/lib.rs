@@ -1,232 +1,4730 @@
 use std::path::{PathBuf, Path};
 use std::io::prelude::*;
-use std::net::{TcpStream, IpAddr};
+use std::net::IpAddr;
 use std::io::Read;
+use std::time::{Duration, Instant};
+use std::collections::HashMap;
 
-use update_protocol::{Request, ResponseCode};
+use log::{error, info, warn};
+use update_protocol::{Request, ResponseCode, UpdateRequestOptions};
 
-pub use update_protocol::UpdateResponse;
+pub use update_protocol::{UpdateResponse, UpdateFile, InstallLocation, PluginMetadata, ImageEntry};
+
+mod storage;
+pub use storage::StorageEstimate;
+
+mod changes;
+pub use changes::{compute_change_set, ChangeSet, InstalledFile, InstalledManifest, Removed};
+
+mod error;
+pub use error::{InstallError, UninstallError, UpdateError, UpdateStatus};
+
+mod batch;
+pub use batch::{BatchDecision, BatchPrompt, BatchReport};
+
+mod client;
+pub use client::{Timeouts, UpdateClient, DEFAULT_MAX_DOWNLOAD_SIZE};
+
+mod sandbox;
+pub use sandbox::SandboxInstaller;
+
+mod dry_run;
+pub use dry_run::{DryRunInstaller, PlannedFile};
+
+mod resume;
+use resume::Progress;
+
+mod exit_status;
+pub use exit_status::{ExitStatus, PluginStatus, StatusLine};
+
+mod preferences;
+pub use preferences::BetaPreference;
+
+mod skip;
+pub use skip::UpdateChoice;
+
+mod cancel;
+pub use cancel::CancelToken;
+
+mod transaction;
+
+mod uninstall;
+pub use uninstall::{custom_uninstall, uninstall, UninstallReport};
+
+mod verify;
+pub use verify::{FileStatus, VerifiedFile, VerifyReport};
+
+mod progress;
+
+mod dialog;
+pub use dialog::DialogConfig;
+
+mod discovery;
+pub use discovery::{discover_servers, DiscoveredServer, DISCOVERY_TIMEOUT};
+
+mod tls;
+#[cfg(feature = "tls")]
+pub use tls::TlsConfig;
+
+mod signing;
+
+mod transport;
+pub use transport::{Stream, TcpTransport, Transport};
+
+mod prefetch;
+use prefetch::Prefetch;
+
+mod keep_alive;
+
+#[cfg(feature = "tokio")]
+pub mod asynchronous;
+
+#[cfg(feature = "logger")]
+mod logger;
+#[cfg(feature = "logger")]
+pub use logger::init_logger;
+
+#[cfg(test)]
+mod fuzz_test;
 
 const PORT: u16 = 45000;
 
+/// Where `InstallLocation::Relative` files are installed unless the installer says otherwise,
+/// Smash Ultimate's contents folder
+pub const DEFAULT_INSTALL_ROOT: &str = "sd:/atmosphere/contents/01006A800016E000";
+
+/// Size of the reads from the download socket, progress is reported after each
+const DOWNLOAD_CHUNK: usize = 64 * 1024;
+
+/// Longest response read from the main port, so a misbehaving server can't exhaust memory
+const MAX_RESPONSE_LEN: u64 = 16 * 1024 * 1024;
+
+/// Read a whole response from the main port, stopping at `MAX_RESPONSE_LEN`
+fn read_response<R: Read>(stream: R, string: &mut String) -> std::io::Result<usize> {
+    stream.take(MAX_RESPONSE_LEN).read_to_string(string)
+}
+
+/// Version of skyline-update with the commit and time it was built, for plugins to include in
+/// their logs and bug reports
+pub fn updater_version() -> &'static str {
+    concat!(env!("CARGO_PKG_VERSION"), " (", env!("BUILD_COMMIT"), ", built ", env!("BUILD_TIMESTAMP"), ")")
+}
+
+/// Print the updater version the first time an update is checked for
+fn log_version_once() {
+    static LOGGED: std::sync::Once = std::sync::Once::new();
+    LOGGED.call_once(|| info!("[updater] skyline-update {}", updater_version()));
+}
+
 pub struct DefaultInstaller;
 
 #[cfg(not(target_os = "switch"))]
 impl Installer for DefaultInstaller {
-    fn should_update(&self, _: &UpdateResponse) -> bool {
+    fn should_update(&self, response: &UpdateResponse) -> bool {
+        if let Some((message, _)) = dialog::current(response) {
+            info!("[updater] {}", message);
+        }
         true
     }
 
-    fn install_file(&self, path: PathBuf, buf: Vec<u8>) -> Result<(), ()> {
-        println!("Installing {} bytes to path {}", buf.len(), path.display());
+    fn on_update_start(&self, response: &UpdateResponse) {
+        progress::start(response);
+    }
+
+    fn on_progress(&self, file: &UpdateFile, bytes_downloaded: usize, total_bytes: usize) {
+        progress::update(file, bytes_downloaded, total_bytes);
+    }
+
+    fn install_file(&self, path: PathBuf, buf: Vec<u8>) -> Result<(), InstallError> {
+        info!("Installing {} bytes to path {}", buf.len(), path.display());
 
         if let Ok(string) = String::from_utf8(buf) {
-            println!("As string: {:?}", string);
+            log::debug!("As string: {:?}", string);
         }
 
         Ok(())
     }
+
+    fn stage_downloads(&self) -> bool {
+        true
+    }
+
+    fn on_update_complete(&self, response: &UpdateResponse, success: bool) {
+        progress::finish();
+        if success {
+            info!("{}", update_complete_message(response));
+        }
+    }
 }
 
 #[cfg(target_os = "switch")]
 impl Installer for DefaultInstaller {
     fn should_update(&self, response: &UpdateResponse) -> bool {
+        if let Some((message, buttons)) = dialog::current(response) {
+            return dialog::ask(message, buttons)
+        }
         skyline_web::Dialog::yes_no(format!(
             "An update for {} has been found.\n\nWould you like to download it?",
-            response.plugin_name
+            response.display_name()
+        ))
+    }
+
+    fn should_update_prompt(&self, prompt: &UpdatePrompt) -> bool {
+        if let Some((message, buttons)) = dialog::current(prompt.response) {
+            return dialog::ask(message, buttons)
+        }
+
+        let storage = prompt.storage
+            .map(|estimate| format!("\n\nStorage used: {}", estimate))
+            .unwrap_or_default();
+        let skyline = prompt.skyline.as_ref()
+            .map(|mismatch| format!("\n\n{}. Its files may not load until skyline is updated.", mismatch))
+            .unwrap_or_default();
+
+        let message = match &prompt.changelog {
+            Some(changelog) => format!(
+                "Version {} of {} has been found.\n\nWhat's new:\n{}{}{}\n\nWould you like to download it?",
+                prompt.response.new_plugin_version, prompt.response.display_name(), changelog_excerpt(changelog), storage, skyline
+            ),
+            None => format!(
+                "An update for {} has been found.{}{}\n\nWould you like to download it?",
+                prompt.response.display_name(), storage, skyline
+            ),
+        };
+        skyline_web::Dialog::yes_no(message)
+    }
+
+    /// The dialog only has two buttons, so skipping the version is offered once it is declined
+    fn update_choice(&self, prompt: &UpdatePrompt) -> UpdateChoice {
+        if self.should_update_prompt(prompt) {
+            return UpdateChoice::Install
+        }
+
+        let skip = skyline_web::Dialog::yes_no(format!(
+            "Skip version {} of {}?\n\nYou won't be asked about it again, only about newer versions.",
+            prompt.response.new_plugin_version, prompt.response.display_name()
+        ));
+        if skip {
+            UpdateChoice::SkipVersion
+        } else {
+            UpdateChoice::NotNow
+        }
+    }
+
+    fn on_update_notification(&self, response: &UpdateResponse, _changelog: Option<&str>) {
+        skyline_web::Dialog::ok(notification_message(response));
+    }
+
+    fn on_storage_unavailable(&self, response: &UpdateResponse) {
+        skyline_web::Dialog::ok(storage_unavailable_message(response));
+    }
+
+    fn on_skyline_outdated(&self, response: &UpdateResponse, mismatch: &SkylineMismatch) {
+        skyline_web::Dialog::ok(skyline_outdated_message(response, mismatch));
+    }
+
+    fn should_uninstall(&self, plugin_name: &str, manifest: &InstalledManifest) -> bool {
+        let files = manifest.files.iter().map(|file| 1 + file.extracted.len()).sum::<usize>();
+        skyline_web::Dialog::yes_no(format!(
+            "Uninstall {}?\n\nThe {} files installed by the updater will be deleted.",
+            plugin_name, files
         ))
     }
 
-    fn install_file(&self, path: PathBuf, buf: Vec<u8>) -> Result<(), ()> {
-        if path.parent().ok_or(()) != Ok(Path::new("sd:")) {
-            let _ = std::fs::create_dir_all(path.parent().ok_or(())?);
+    fn on_update_start(&self, response: &UpdateResponse) {
+        progress::start(response);
+    }
+
+    fn on_update_complete(&self, response: &UpdateResponse, success: bool) {
+        progress::finish();
+        if success {
+            skyline_web::Dialog::ok(update_complete_message(response));
+        }
+    }
+
+    fn on_progress(&self, file: &UpdateFile, bytes_downloaded: usize, total_bytes: usize) {
+        progress::update(file, bytes_downloaded, total_bytes);
+    }
+
+    fn should_update_batch(&self, prompt: &BatchPrompt) -> BatchDecision {
+        let plugins: String = prompt.versions()
+            .map(|(name, version)| format!("\n{} {}", name, version))
+            .collect();
+
+        let install = skyline_web::Dialog::yes_no(format!(
+            "Updates have been found for:\n{}\n\nDownload size: {}\n\nWould you like to download them?",
+            plugins, storage::format_size(prompt.total_size as u64)
+        ));
+        if install {
+            BatchDecision::InstallAll
+        } else {
+            BatchDecision::DeclineAll
+        }
+    }
+
+    fn install_file(&self, path: PathBuf, buf: Vec<u8>) -> Result<(), InstallError> {
+        prepare_install(&path)?;
+        if let Err(e) = std::fs::write(&path, buf) {
+            warn!("[updater] Error writing file to sd: {}", e);
+            Err(InstallError::new(path, e))
+        } else {
+            Ok(())
         }
-        if let Err(e) = std::fs::write(path, buf) {
-            println!("[updater] Error writing file to sd: {}", e);
-            Err(())
+    }
+
+    fn install_file_streamed(&self, path: PathBuf, reader: &mut dyn Read, _size: usize) -> Result<(), InstallError> {
+        prepare_install(&path)?;
+        let result = std::fs::File::create(&path).and_then(|mut file| std::io::copy(reader, &mut file));
+        if let Err(e) = result {
+            warn!("[updater] Error writing file to sd: {}", e);
+            Err(InstallError::new(path, e))
         } else {
             Ok(())
         }
     }
+
+    fn stream_archives(&self) -> bool {
+        true
+    }
+
+    fn on_extract_progress(&self, file: &UpdateFile, entries_extracted: usize) {
+        if entries_extracted % 100 == 0 {
+            info!("[updater] Extracting file {}: {} entries", file.download_index, entries_extracted);
+        }
+    }
+
+    fn stage_downloads(&self) -> bool {
+        true
+    }
+
+    fn begin_transaction(&self, response: &UpdateResponse) {
+        transaction::begin(&response.plugin_name);
+    }
+
+    fn commit(&self, _response: &UpdateResponse) {
+        transaction::commit();
+    }
+
+    fn rollback(&self, _response: &UpdateResponse) -> bool {
+        transaction::rollback()
+    }
 }
 
-/// An installer for use with custom_check_update
-pub trait Installer {
-    fn should_update(&self, response: &UpdateResponse) -> bool;
-    fn install_file(&self, path: PathBuf, buf: Vec<u8>) -> Result<(), ()>;
+/// Create the directories `path` goes in and back up the file it replaces
+#[cfg(target_os = "switch")]
+fn prepare_install(path: &Path) -> Result<(), InstallError> {
+    let parent = path.parent().ok_or_else(|| InstallError::other(path, "it has no parent directory"))?;
+    if parent != Path::new("sd:") {
+        let _ = std::fs::create_dir_all(parent);
+    }
+    if let Err(e) = transaction::backup(path) {
+        warn!("[updater] Failed to back up {}: {}", path.display(), e);
+        return Err(InstallError::new(path, e).with_context("backing up the file it replaces"))
+    }
+    Ok(())
 }
 
-fn update<I>(ip: IpAddr, response: &UpdateResponse, installer: &I) -> bool
-    where I: Installer,
-{
-    for file in &response.required_files {
-        if let Ok(mut stream) = TcpStream::connect((ip, PORT + 1)) {
-            let mut buf = vec![];
-            let _ = stream.write_all(&u64::to_be_bytes(file.download_index));
-            if let Err(e) = stream.read_to_end(&mut buf) {
-                println!("[updater] Error downloading file: {}", e);
-                return false
-            }
-            let path: PathBuf = match &file.install_location {
-                update_protocol::InstallLocation::AbsolutePath(path) => path.into(),
-                _ => return false
-            };
-            println!("Downloaded file: {:#?}", path.clone());
+/// What to do with a `.tar` or `.zip` file once it has been installed
+#[derive(Debug, Clone, PartialEq)]
+pub enum ArchiveDecision {
+    /// Extract next to the archive, into its path without the `.tar` or `.zip` extension
+    ExtractNow,
+    /// Keep the archive as installed, for the plugin to extract itself later
+    LeaveArchived,
+    /// Extract into the given directory instead
+    ExtractTo(PathBuf),
+}
 
-            if installer.install_file(path.clone(), buf.clone()).is_err() {
-                return false
-            }
+/// Everything known about an update when asking whether to install it
+pub struct UpdatePrompt<'a> {
+    pub response: &'a UpdateResponse,
+    /// Storage used by the plugin's files before and after the update, None if the plugin's
+    /// current files couldn't be found
+    pub storage: Option<StorageEstimate>,
+    /// Install paths added, replaced and removed compared to the last version installed
+    /// through the updater
+    pub changes: ChangeSet,
+    /// Changelog listed in the plugin's metadata, None if it has none or it couldn't be
+    /// downloaded. See `changelog_excerpt` to show it in a dialog.
+    pub changelog: Option<String>,
+    /// Set if the update requires a newer skyline than the one running
+    pub skyline: Option<SkylineMismatch>,
+}
 
-            if path.extension().unwrap() == "tar" {
-                println!("Extracting tar file: {:#?}", &path);
+/// Lines of a changelog shown when asking whether to install an update
+const CHANGELOG_EXCERPT_LINES: usize = 12;
 
-                let path_str = path.to_str().unwrap();
-                /* Remove .tar extension from path */
-                let extract_to_path = Path::new(&path_str.clone()[..path_str.chars().count()-4]);
+/// Characters of a changelog shown when asking whether to install an update, so long lines
+/// don't overflow the dialog either
+const CHANGELOG_EXCERPT_CHARS: usize = 600;
 
-                let mut ar = tar::Archive::new(std::fs::File::open(path.clone()).unwrap());
-                let _ = ar.unpack(extract_to_path.clone());
-                println!("tarball extracted to path: {:#?}", extract_to_path);
+/// The start of a changelog, at most `CHANGELOG_EXCERPT_LINES` lines and
+/// `CHANGELOG_EXCERPT_CHARS` characters, ending in `...` if anything was cut off. Blank lines
+/// around it are dropped.
+pub fn changelog_excerpt(changelog: &str) -> String {
+    let changelog = changelog.trim();
+    let mut excerpt: String = changelog.lines()
+        .take(CHANGELOG_EXCERPT_LINES)
+        .collect::<Vec<_>>()
+        .join("\n")
+        .chars()
+        .take(CHANGELOG_EXCERPT_CHARS)
+        .collect();
 
-                /*
-                for file in ar.entries().unwrap() {
-                    let file = file.unwrap();
-                    println!("file name: {:#?}", file.header().path().unwrap());
-                }
-                */
+    let truncated = changelog.lines().count() > CHANGELOG_EXCERPT_LINES
+        || (excerpt.chars().count() == CHANGELOG_EXCERPT_CHARS && changelog.chars().count() > CHANGELOG_EXCERPT_CHARS);
+    if truncated {
+        excerpt.truncate(excerpt.trim_end().len());
+        excerpt.push_str("...");
+    }
 
-                //let mut zip_file = std::fs::File::open(path.as_path()).unwrap();
-                //let mut zip = zip::read::ZipArchive::new(zip_file).unwrap(); // this errors for some godforsaken reason
-                
-                //for i in 0..zip.len() {
-                //    let f = zip.by_index(i).unwrap(); // zip.comment could be used for storing path?
-                //    println!("ZipFile name: {}", f.name());
-                   
-                //}
-                
-            }
+    excerpt
+}
 
-            stream.flush().unwrap();
-            stream.shutdown(std::net::Shutdown::Both).unwrap();
-        } else {
-            println!("[updater] Failed to connect to port {}", PORT + 1);
-            return false
-        }
+/// Download the changelog of the version an update offers, for the update prompt. The metadata
+/// is asked for with betas if the update was, so it is the changelog of the version offered.
+/// Any failure is logged and leaves the prompt without a changelog.
+fn fetch_changelog(client: &UpdateClient, response: &UpdateResponse, allow_beta: bool) -> Option<String> {
+    if response.changelog.is_some() {
+        return response.changelog.clone()
     }
-    println!("[updater] finished updating plugin.");
-    true
+
+    let metadata = request_metadata(client, &response.plugin_name, Some(allow_beta))?;
+    client.get_changelog(&metadata)
 }
 
-/// Install an update with a custom installer implementation
-pub fn custom_check_update<I>(ip: IpAddr, name: &str, version: &str, allow_beta: bool, installer: &I) -> bool
+/// Ask whether to install an update, remembering the version if the user chose to skip it
+fn ask_to_install<I>(installer: &I, prompt: &UpdatePrompt) -> bool
     where I: Installer,
 {
-    match TcpStream::connect((ip, PORT)) {
-        Ok(mut stream) =>  {
-            if let Ok(packet) = serde_json::to_string(&Request::Update {
-                beta: Some(allow_beta),
-                plugin_name: name.to_owned(),
-                plugin_version: version.to_owned(),
-                options: None,
-            }) {
-                let _ = stream.write_fmt(format_args!("{}\n", packet));
-                let mut string = String::new();
-                let _ = stream.read_to_string(&mut string);
-
-                if let Ok(response) = serde_json::from_str::<UpdateResponse>(&string) {
-                    match response.code {
-                        ResponseCode::NoUpdate => return false,
-                        ResponseCode::Update => {
-                            if installer.should_update(&response) {
-                                let success = update(ip, &response, installer);
-
-                                if !success {
-                                    println!("[{} updater] Failed to install update, files may be left in a broken state.", name);
-                                }
-
-                                success
-                            } else {
-                                false
-                            }
-                        }
-                        ResponseCode::InvalidRequest => {
-                            println!("[{} updater] Failed to send a valid request to the server", name);
-                            false
-                        }
-                        ResponseCode::PluginNotFound => {
-                            println!("Plugin '{}' could not be found on the update server", name);
-                            false
-                        }
-                        _ => {
-                            println!("Unexpected response");
-                            false
-                        }
-                    }
-                } else {
-                    println!("[{} updater] Failed to parse update server response: {:?}", name, string);
-                    false
-                }
-            } else {
-                println!("[{} updater] Failed to encode packet", name);
-                false
-            }
-        }
-        Err(e) => {
-            println!("[{} updater] Failed to connect to update server {}", name, ip);
-            println!("[{} updater] {:?}", name, e);
+    match installer.update_choice(prompt) {
+        UpdateChoice::Install => true,
+        UpdateChoice::NotNow => false,
+        UpdateChoice::SkipVersion => {
+            info!("[{} updater] Skipping {}, it won't be asked about again", prompt.response.plugin_name, prompt.response.new_plugin_version);
+            skip::skip_version(&skip::skipped_dir(), prompt.response);
             false
         }
     }
 }
 
-/// Install an update using the default installer
-///
-/// ## Args
-/// * ip - IP address of server
-/// * name - name of plugin to update
-/// * version - current version of plugin
-/// * allow_beta - allow beta versions to be offered
-pub fn check_update(ip: IpAddr, name: &str, version: &str, allow_beta: bool) -> bool {
-    custom_check_update(ip, name, version, allow_beta, &DefaultInstaller)
+/// An installer for use with custom_check_update
+pub trait Installer {
+    fn should_update(&self, response: &UpdateResponse) -> bool;
+
+    /// Ask whether to install an update, defaults to `should_update`
+    fn should_update_prompt(&self, prompt: &UpdatePrompt) -> bool {
+        self.should_update(prompt.response)
+    }
+
+    /// Ask whether to install an update, also letting the user skip its version. A skipped
+    /// version is remembered in `sd:/skyline-update/skipped` and never asked about again, the
+    /// check reporting `UpdateStatus::UpToDate` instead, while newer versions are asked about as
+    /// usual. Defaults to `should_update_prompt`, which never skips.
+    fn update_choice(&self, prompt: &UpdatePrompt) -> UpdateChoice {
+        if self.should_update_prompt(prompt) {
+            UpdateChoice::Install
+        } else {
+            UpdateChoice::NotNow
+        }
+    }
+
+    fn install_file(&self, path: PathBuf, buf: Vec<u8>) -> Result<(), InstallError>;
+
+    /// Install a downloaded file of `size` bytes by reading it from `reader`, so it never has
+    /// to be held in memory whole. Defaults to reading it into a buffer for `install_file`.
+    fn install_file_streamed(&self, path: PathBuf, reader: &mut dyn Read, size: usize) -> Result<(), InstallError> {
+        let mut buf = Vec::with_capacity(size);
+        if let Err(e) = reader.read_to_end(&mut buf) {
+            return Err(InstallError::new(path, e).with_context("reading the download"))
+        }
+        self.install_file(path, buf)
+    }
+
+    /// Root the relative install locations of an update are resolved against, for homebrew
+    /// installing elsewhere than `DEFAULT_INSTALL_ROOT`
+    fn install_root(&self, _response: &UpdateResponse) -> String {
+        String::from(DEFAULT_INSTALL_ROOT)
+    }
+
+    /// Lowercase hex SHA-256 of the file installed at `path`, None if there is none. Files an
+    /// interrupted update installed are only skipped on the next attempt if this still matches,
+    /// defaults to hashing the file on disk.
+    fn installed_sha256(&self, path: &Path) -> Option<String> {
+        let file = std::fs::File::open(path).ok()?;
+        resume::sha256_of(std::io::BufReader::new(file)).ok()
+    }
+
+    /// Called after an archive has been installed to `path`, defaults to extracting it
+    fn handle_archive(&self, _file: &UpdateFile, _path: &Path) -> ArchiveDecision {
+        ArchiveDecision::ExtractNow
+    }
+
+    /// Called once per version when the plugin can't be updated by the updater and the user
+    /// should get the new version themselves. Nothing is downloaded.
+    fn on_update_notification(&self, response: &UpdateResponse, _changelog: Option<&str>) {
+        info!("[updater] {}", notification_message(response));
+    }
+
+    /// Whether to download and install a file of an update, such as to leave out optional files
+    /// the user opted out of. Called before connecting to download it, with its install
+    /// location resolved by `resolve_path`. Files left out are reported as
+    /// `FileOutcome::Excluded` and don't fail the update. Defaults to installing every file.
+    fn should_install_file(&self, _file: &UpdateFile) -> bool {
+        true
+    }
+
+    /// Called before the first file of an update is downloaded, such as to unmount what the
+    /// update replaces
+    fn on_update_start(&self, _response: &UpdateResponse) {}
+
+    /// Called once an update begun with `on_update_start` stopped, after the last file was
+    /// installed or the first one failed. `success` is whether every file is installed.
+    fn on_update_complete(&self, _response: &UpdateResponse, _success: bool) {}
+
+    /// Called when the SD card became unavailable while installing an update. The files
+    /// after the one being written were not attempted and the update wasn't recorded as
+    /// installed.
+    fn on_storage_unavailable(&self, response: &UpdateResponse) {
+        warn!("[updater] {}", storage_unavailable_message(response));
+    }
+
+    /// Version of the skyline loader currently running, if known. Sent to the server so it
+    /// can flag updates which need a newer skyline.
+    fn skyline_version(&self) -> Option<String> {
+        None
+    }
+
+    /// Whether to still offer updates which require a newer skyline than the one running, see
+    /// `UpdatePrompt::skyline`. Defaults to refusing them, as their files would fail to load.
+    fn allow_outdated_skyline(&self) -> bool {
+        false
+    }
+
+    /// Called when an update wasn't installed because it requires a newer skyline, see
+    /// `allow_outdated_skyline`
+    fn on_skyline_outdated(&self, response: &UpdateResponse, mismatch: &SkylineMismatch) {
+        warn!("[updater] {}", skyline_outdated_message(response, mismatch));
+    }
+
+    /// Called as a file is downloaded, after every read from the download socket, with the
+    /// `UpdateFile::size` as the total. Files sent inline are reported once, complete.
+    fn on_progress(&self, _file: &UpdateFile, _bytes_downloaded: usize, _total_bytes: usize) {}
+
+    /// Whether to delete files of the previously installed version which are no longer part
+    /// of the plugin once an update is installed. Defaults to keeping them.
+    fn remove_stale_files(&self) -> bool {
+        false
+    }
+
+    /// Ask whether `uninstall` may delete the files recorded for `plugin_name` in `manifest`,
+    /// before anything is deleted. Defaults to true.
+    fn should_uninstall(&self, _plugin_name: &str, _manifest: &InstalledManifest) -> bool {
+        true
+    }
+
+    /// Whether to also delete the directories left empty by removed files, up to the deepest
+    /// directory containing every file of the plugin. Defaults to pruning them.
+    fn prune_empty_dirs(&self) -> bool {
+        true
+    }
+
+    /// Whether to install updates containing paths which only differ by case, which overwrite
+    /// each other on the SD card. Defaults to refusing them.
+    fn allow_case_collisions(&self) -> bool {
+        false
+    }
+
+    /// Whether to keep an archive once the client extracted it, such as to roll back by hand.
+    /// Defaults to deleting it. Archives which failed to extract are always kept.
+    fn keep_archives(&self) -> bool {
+        false
+    }
+
+    /// Whether to extract archives as they are downloaded, without ever writing the `.tar`
+    /// file. `install_file` and `handle_archive` aren't called for them, they are extracted into
+    /// their path without the extension as with `ArchiveDecision::ExtractNow`. Defaults to
+    /// installing the archive and extracting it afterwards. Zip archives list their entries at
+    /// the end, so they are always installed and extracted afterwards, as are archives with a
+    /// hash, which is checked before any of it is extracted.
+    fn stream_archives(&self) -> bool {
+        false
+    }
+
+    /// Called after every entry extracted from an archive streamed with `stream_archives`,
+    /// alongside `on_progress` for the bytes downloaded
+    fn on_extract_progress(&self, _file: &UpdateFile, _entries_extracted: usize) {}
+
+    /// Whether to download every file of an update into `sd:/skyline-update/downloads` before
+    /// installing any, so a failed download leaves the installed version untouched. Archives
+    /// which would be streamed are extracted from there instead. Defaults to installing each
+    /// file as soon as it is downloaded, which needs no room for the whole update.
+    fn stage_downloads(&self) -> bool {
+        false
+    }
+
+    /// Called before the first file of an update is installed. Installers which can undo an
+    /// update, such as by backing up every file they replace, start doing so here. Archives
+    /// streamed with `stream_archives` are extracted by the client and aren't undone.
+    fn begin_transaction(&self, _response: &UpdateResponse) {}
+
+    /// Called once every file of an update begun with `begin_transaction` was installed
+    fn commit(&self, _response: &UpdateResponse) {}
+
+    /// Called instead of `commit` when a file of the update failed, to undo every file installed
+    /// since `begin_transaction`. Returns whether they were undone, defaults to false as nothing
+    /// is.
+    fn rollback(&self, _response: &UpdateResponse) -> bool {
+        false
+    }
+
+    /// Ask whether to install every update found by `check_updates_batch` at once, defaults
+    /// to asking about each one separately
+    fn should_update_batch(&self, _prompt: &BatchPrompt) -> BatchDecision {
+        BatchDecision::AskEach
+    }
+
+    /// Called alongside `on_progress` during `check_updates_batch`, with the bytes downloaded
+    /// for the whole batch and the combined size of every update
+    fn on_batch_progress(&self, _bytes_downloaded: usize, _total_bytes: usize) {}
 }
 
-pub fn get_update_info(ip: IpAddr, name: &str, version: &str, allow_beta: bool) -> Option<UpdateResponse> {
-    match TcpStream::connect((ip, PORT)) {
-        Ok(mut stream) =>  {
-            if let Ok(packet) = serde_json::to_string(&Request::Update {
-                beta: Some(allow_beta),
-                plugin_name: name.to_owned(),
-                plugin_version: version.to_owned(),
-                options: None,
-            }) {
-                let _ = stream.write_fmt(format_args!("{}\n", packet));
-                let mut string = String::new();
-                let _ = stream.read_to_string(&mut string);
+/// An update which requires a newer skyline than the one running. Its files may not load until
+/// skyline is updated as well.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SkylineMismatch {
+    /// Version of the plugin offered
+    pub plugin_version: String,
+    /// Skyline versions the update works with, such as `>=0.3.0`, None if the server only said
+    /// skyline needs updating
+    pub required: Option<String>,
+    /// Version of skyline running, None if the client wasn't told, see
+    /// `UpdateClient::with_skyline_version`
+    pub installed: Option<String>,
+}
 
-                if let Ok(response) = serde_json::from_str::<UpdateResponse>(&string) {
-                    Some(response)
-                } else {
-                    None
-                }
-            } else {
-                None
-            }
+impl std::fmt::Display for SkylineMismatch {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match (&self.required, &self.installed) {
+            (Some(required), Some(installed)) => write!(
+                f, "Version {} requires skyline {}, but {} is installed", self.plugin_version, required, installed
+            ),
+            (Some(required), None) => write!(f, "Version {} requires skyline {}", self.plugin_version, required),
+            (None, _) => write!(f, "Version {} requires a newer skyline", self.plugin_version),
         }
-        Err(_) => None,
     }
 }
 
-pub fn install_update(ip: IpAddr, info: &UpdateResponse) -> bool {
-    update(ip, info, &DefaultInstaller)
+/// Whether an update requires a newer skyline than `skyline_version`, as the server flagged it
+/// with `UpdateResponse::update_skyline` or by its `skyline_requirement`
+fn skyline_mismatch(response: &UpdateResponse, skyline_version: Option<&str>) -> Option<SkylineMismatch> {
+    // a skyline sent along with the update is the one the plugin runs on once it is installed
+    let satisfied = response.skyline_requirement.as_ref()
+        .zip(response.new_skyline_version.as_deref().or(skyline_version))
+        .and_then(|(requirement, version)| update_protocol::requirement_satisfied(requirement, version));
+
+    match satisfied {
+        Some(true) => None,
+        Some(false) => Some(()),
+        None => Some(()).filter(|_| response.update_skyline),
+    }?;
+
+    Some(SkylineMismatch {
+        plugin_version: response.new_plugin_version.clone(),
+        required: response.skyline_requirement.clone(),
+        installed: skyline_version.map(str::to_owned),
+    })
 }
 
-#[cfg(test)]
-mod test {
-    use super::*;
+/// Version of skyline running, as the installer or the client were told
+fn running_skyline_version<I: Installer>(client: &UpdateClient, installer: &I) -> Option<String> {
+    installer.skyline_version().or_else(|| client.skyline_version().map(str::to_owned))
+}
 
-    #[test]
-    fn test_install() {
-        println!("{}", serde_json::to_string(&Request::Update { plugin_name: "test_name".into(), plugin_version: "1.0.0".into(), beta: None, options: None }).unwrap());
-        check_update("127.0.0.1".parse().unwrap(), "test_plugin", "0.9.0", true);
+/// Text telling the user an update wasn't installed as it needs a newer skyline
+fn skyline_outdated_message(response: &UpdateResponse, mismatch: &SkylineMismatch) -> String {
+    format!("{} wasn't updated.\n\n{}. Update skyline to install it.", response.display_name(), mismatch)
+}
+
+/// Text telling the user where to get a notify-only update
+fn notification_message(response: &UpdateResponse) -> String {
+    match &response.homepage {
+        Some(homepage) => format!(
+            "Version {} of {} is available.\n\nGet it from {}",
+            response.new_plugin_version, response.display_name(), homepage
+        ),
+        None => format!(
+            "Version {} of {} is available.",
+            response.new_plugin_version, response.display_name()
+        ),
+    }
+}
+
+/// Text telling the user an update was installed and takes effect once the game restarts
+fn update_complete_message(response: &UpdateResponse) -> String {
+    format!(
+        "{} was updated to version {}.\n\nRestart the game to use it.",
+        response.display_name(), response.new_plugin_version
+    )
+}
+
+/// Text telling the user an update was interrupted by the SD card going away
+fn storage_unavailable_message(response: &UpdateResponse) -> String {
+    format!(
+        "The SD card became unavailable while updating {}.\n\nThe update was not finished. \
+        Reinsert the SD card and restart to try again.",
+        response.display_name()
+    )
+}
+
+/// Directory files are downloaded to before they are installed
+fn download_dir() -> PathBuf {
+    if cfg!(target_os = "switch") {
+        PathBuf::from("sd:/skyline-update/downloads")
+    } else {
+        std::env::temp_dir().join("skyline-update/downloads")
+    }
+}
+
+/// Directory remembering the last version each notify-only plugin was announced at, so
+/// users are told about every version once instead of on every boot
+fn notified_dir() -> PathBuf {
+    if cfg!(target_os = "switch") {
+        PathBuf::from("sd:/skyline-update/notified")
+    } else {
+        std::env::temp_dir().join("skyline-update/notified")
+    }
+}
+
+/// Show a `NotifyOnly` response unless this version was already announced. Returns whether
+/// the installer was notified.
+fn notify_update<I>(dir: &Path, response: &UpdateResponse, installer: &I) -> bool
+    where I: Installer,
+{
+    let path = changes::record_path(dir, &response.plugin_name);
+    let already_notified = std::fs::read_to_string(&path)
+        .map(|version| version.trim() == response.new_plugin_version)
+        .unwrap_or(false);
+
+    if already_notified {
+        return false
+    }
+
+    installer.on_update_notification(response, response.changelog.as_deref());
+
+    if std::fs::create_dir_all(dir).and_then(|_| std::fs::write(&path, &response.new_plugin_version)).is_err() {
+        warn!("[updater] Failed to remember notified version of {}", response.plugin_name);
+    }
+
+    true
+}
+
+/// Decode a file the server embedded in the response, if present and intact
+fn inline_file(file: &UpdateFile) -> Option<Vec<u8>> {
+    let data = base64::decode(file.inline_data.as_ref()?).ok()?;
+    if data.len() == file.size {
+        Some(data)
+    } else {
+        warn!("[updater] Inline file is {} bytes, expected {}. Downloading instead.", data.len(), file.size);
+        None
+    }
+}
+
+/// Whether a socket error is a read or write timing out, which is reported differently per platform
+fn is_timeout(e: &std::io::Error) -> bool {
+    matches!(e.kind(), std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut)
+}
+
+/// A file downloaded into `download_dir`, deleted once dropped
+struct TempDownload {
+    path: PathBuf,
+    size: usize,
+    /// Lowercase hex SHA-256 of the file, computed while downloading
+    sha256: String,
+}
+
+impl TempDownload {
+    /// Download a file of `size` bytes to a new file, see `download_to`
+    fn download<F>(client: &UpdateClient, port: u16, index: u64, size: usize, on_progress: F) -> Result<Self, String>
+        where F: FnMut(usize),
+    {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        static NEXT_ID: AtomicUsize = AtomicUsize::new(0);
+
+        let dir = download_dir();
+        let path = dir.join(format!("{}-{}.part", std::process::id(), NEXT_ID.fetch_add(1, Ordering::Relaxed)));
+        let file = std::fs::create_dir_all(&dir).and_then(|_| std::fs::File::create(&path));
+        let file = file.map_err(|e| download_error(index, format!("failed to create {}: {}", path.display(), e)))?;
+
+        // created first so a failed download is cleaned up too
+        let mut download = TempDownload { path, size: 0, sha256: String::new() };
+        let mut writer = resume::HashingWriter::new(file);
+        download.size = download_to(client, port, index, size, &mut writer, on_progress)?;
+        download.sha256 = writer.finish();
+
+        Ok(download)
+    }
+}
+
+impl Drop for TempDownload {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+/// A file of an update, ready to be installed
+enum Downloaded {
+    Inline(Vec<u8>),
+    Temp(TempDownload),
+}
+
+/// Download a file of `size` bytes into memory, see `download_to`
+fn download<F>(client: &UpdateClient, port: u16, index: u64, size: usize, on_progress: F) -> Result<Vec<u8>, String>
+    where F: FnMut(usize),
+{
+    let mut buf = vec![];
+    download_to(client, port, index, size, &mut buf, on_progress)?;
+
+    Ok(buf)
+}
+
+/// Download a file of `size` bytes into `out`, calling `on_progress` with the number of bytes
+/// received so far after every read. Returns the size of the file, or why the download failed,
+/// such as the server sending more or fewer bytes than `size` or the client's updates being
+/// cancelled.
+///
+/// Connections to servers which keep them alive are reused for the next file. Once such a
+/// server closes one instead, every file is downloaded over a connection of its own again.
+fn download_to<W, F>(client: &UpdateClient, port: u16, index: u64, size: usize, out: &mut W, mut on_progress: F) -> Result<usize, String>
+    where W: Write,
+          F: FnMut(usize),
+{
+    if client.keep_alive().is_enabled(port) {
+        match download_kept_alive(client, port, index, size, out, &mut on_progress) {
+            Some(result) => return result,
+            None => {
+                info!("[updater] The server closed a download connection it was asked to keep open, using a connection per file");
+                client.keep_alive().disable(port);
+            }
+        }
+    }
+
+    let mut stream = open_download(client, port, index)?;
+    let downloaded = receive(client, &mut stream, index, size, out, &mut on_progress, false);
+
+    let _ = stream.flush();
+    stream.shutdown();
+
+    downloaded
+}
+
+/// Download a file over a connection kept open for the next one, reusing an idle connection if
+/// there is one. None if the server closed the connection without sending any of the file.
+fn download_kept_alive<W, F>(client: &UpdateClient, port: u16, index: u64, size: usize, out: &mut W, on_progress: &mut F) -> Option<Result<usize, String>>
+    where W: Write,
+          F: FnMut(usize),
+{
+    let mut stream = match client.keep_alive().take(port) {
+        Some(stream) => stream,
+        None => match connect_download(client, port, index) {
+            Ok(mut stream) => {
+                let _ = stream.write_all(&u64::to_be_bytes(update_protocol::KEEP_ALIVE_INDEX));
+                stream
+            }
+            Err(reason) => return Some(Err(reason)),
+        },
+    };
+    if stream.write_all(&u64::to_be_bytes(index)).is_err() {
+        return None
+    }
+
+    let mut len = [0; 8];
+    match stream.read_exact(&mut len) {
+        Ok(()) => {}
+        Err(e) if is_timeout(&e) => {
+            return Some(Err(download_error(index, format!("it stalled for longer than {:?}", client.timeouts().download))))
+        }
+        Err(_) => return None,
+    }
+    let len = u64::from_be_bytes(len);
+    if len == update_protocol::MISSING_FILE_LEN {
+        return Some(Err(download_error(index, String::from(FILE_GONE))))
+    }
+    if len != size as u64 {
+        return Some(Err(download_error(index, format!("the server is sending {} bytes, expected {}", len, size))))
+    }
+
+    let downloaded = receive(client, &mut stream, index, size, out, on_progress, true);
+    if downloaded.is_ok() {
+        client.keep_alive().put(port, stream);
+    }
+
+    Some(downloaded)
+}
+
+/// Read a file of `size` bytes from a download connection into `out`, see `download_to`. On a
+/// kept-alive connection the file ends after `size` bytes, otherwise once the server closes
+/// the connection.
+fn receive<R, W, F>(client: &UpdateClient, stream: &mut R, index: u64, size: usize, out: &mut W, on_progress: &mut F, kept_alive: bool) -> Result<usize, String>
+    where R: Read,
+          W: Write,
+          F: FnMut(usize),
+{
+    let mut downloaded = 0;
+    let mut chunk = vec![0; DOWNLOAD_CHUNK];
+    loop {
+        if kept_alive && downloaded == size {
+            break
+        }
+        // the next file follows right after this one on a kept-alive connection
+        let want = if kept_alive { (size - downloaded).min(DOWNLOAD_CHUNK) } else { DOWNLOAD_CHUNK };
+        if client.is_cancelled() {
+            info!("[updater] Download of file {} cancelled", index);
+            return Err(String::from("the download was cancelled"))
+        }
+        match stream.read(&mut chunk[..want]) {
+            Ok(0) => break,
+            Ok(len) if downloaded + len > size => {
+                return Err(download_error(index, format!("the server sent more than the expected {} bytes", size)))
+            }
+            Ok(len) => {
+                if let Err(e) = out.write_all(&chunk[..len]) {
+                    return Err(download_error(index, format!("failed to store it: {}", e)))
+                }
+                downloaded += len;
+                on_progress(downloaded);
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+            Err(e) if is_timeout(&e) => {
+                return Err(download_error(index, format!("it stalled for longer than {:?}", client.timeouts().download)))
+            }
+            Err(e) => return Err(download_error(index, e.to_string())),
+        }
+    }
+
+    // servers close the connection right away for an index they don't host
+    if !kept_alive && downloaded == 0 && size > 0 {
+        return Err(download_error(index, String::from(FILE_GONE)))
+    }
+    // a server closing the connection early still ends the download, which would otherwise be
+    // installed truncated
+    if downloaded != size {
+        return Err(download_error(index, format!("the server closed it after {} of the expected {} bytes", downloaded, size)))
+    }
+
+    Ok(downloaded)
+}
+
+/// Log why downloading file `index` failed, returning it for the error reporting the failure
+fn download_error(index: u64, reason: String) -> String {
+    warn!("[updater] Failed to download file {}: {}", index, reason);
+    reason
+}
+
+/// Why a download failed when the server doesn't host the file, reported as
+/// `UpdateError::FileGone`
+const FILE_GONE: &str = "the server no longer hosts it";
+
+/// The error reporting a failed download of file `index`
+fn download_failure(index: u64, reason: String) -> UpdateError {
+    if reason == FILE_GONE {
+        UpdateError::FileGone { index }
+    } else {
+        UpdateError::Download { index, reason }
+    }
+}
+
+/// Connect to a download port and ask for the file at `index`
+fn open_download(client: &UpdateClient, port: u16, index: u64) -> Result<tls::Connection, String> {
+    let mut stream = connect_download(client, port, index)?;
+    let _ = stream.write_all(&u64::to_be_bytes(index));
+
+    Ok(stream)
+}
+
+/// Connect to a download port to download the file at `index`
+fn connect_download(client: &UpdateClient, port: u16, index: u64) -> Result<tls::Connection, String> {
+    client.connect_download(port).map_err(|e| {
+        let reason = download_error(index, format!("failed to connect to download port {}: {}", port, e));
+        info!(
+            "[updater] The update server itself was reachable. If it is behind a port forward, \
+             check that port {} is forwarded as well.",
+            port
+        );
+        reason
+    })
+}
+
+/// A download read as it arrives, for archives extracted while downloading
+struct DownloadReader<F> {
+    stream: tls::Connection,
+    downloaded: usize,
+    size: usize,
+    cancel: CancelToken,
+    on_progress: F,
+    /// Why reading the download failed, to tell it apart from failing to extract
+    error: Option<String>,
+}
+
+impl<F: FnMut(usize)> Read for DownloadReader<F> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if self.cancel.is_cancelled() {
+            return Err(std::io::Error::new(std::io::ErrorKind::Other, "the update was cancelled"))
+        }
+        let result = match self.stream.read(buf) {
+            Ok(len) if self.downloaded + len > self.size => Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("the server sent more than the expected {} bytes", self.size),
+            )),
+            Ok(0) if self.downloaded == 0 && self.size > 0 && !buf.is_empty() => {
+                Err(std::io::Error::new(std::io::ErrorKind::NotFound, FILE_GONE))
+            }
+            Ok(0) if self.downloaded != self.size && !buf.is_empty() => Err(std::io::Error::new(
+                std::io::ErrorKind::UnexpectedEof,
+                format!("the server closed the download after {} of the expected {} bytes", self.downloaded, self.size),
+            )),
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => return Err(e),
+            result => result,
+        };
+        match &result {
+            Ok(len) if *len > 0 => {
+                self.downloaded += len;
+                (self.on_progress)(self.downloaded);
+            }
+            Ok(_) => {}
+            Err(e) => self.error = Some(e.to_string()),
+        }
+
+        result
+    }
+}
+
+/// What happened to a single file of an update
+#[derive(Debug, Clone, PartialEq)]
+pub enum FileOutcome {
+    Installed,
+    /// Installed by an earlier attempt at the same update which was interrupted, and left as is
+    AlreadyInstalled,
+    /// Not installed because an earlier file failed, or any file failed to download with
+    /// `Installer::stage_downloads`
+    Skipped,
+    Failed(UpdateError),
+    /// Installed, then undone by `Installer::rollback` because a later file failed
+    RolledBack,
+    /// Not downloaded because `Installer::should_install_file` left it out
+    Excluded,
+}
+
+impl FileOutcome {
+    /// Whether the file is in place, installed by this attempt or an earlier one
+    pub fn is_installed(&self) -> bool {
+        matches!(self, FileOutcome::Installed | FileOutcome::AlreadyInstalled)
+    }
+
+    /// Whether the file was handled as the installer asked: it is in place, or the installer
+    /// left it out
+    pub fn succeeded(&self) -> bool {
+        self.is_installed() || *self == FileOutcome::Excluded
+    }
+}
+
+/// Number of files installed by an update and left out by the installer, for the log once it
+/// is installed
+fn completion_message(files: &[FileReport]) -> String {
+    let excluded = files.iter().filter(|file| file.outcome == FileOutcome::Excluded).count();
+    format!("finished updating plugin: {} files installed, {} skipped by the installer.", files.len() - excluded, excluded)
+}
+
+/// Files extracted from every archive of an update which was extracted, by the install path of
+/// the archive, see `InstalledManifest::record_extracted`
+fn extracted_files(response: &UpdateResponse, files: &[FileReport]) -> Vec<(String, Vec<String>)> {
+    response.required_files.iter()
+        .zip(files)
+        .filter(|(_, report)| report.archive.is_some())
+        .filter_map(|(file, report)| {
+            let extracted = report.extracted.iter().map(|path| path.display().to_string()).collect();
+            Some((storage::install_path(file)?, extracted))
+        })
+        .collect()
+}
+
+/// Why an update failed, for the log
+fn failure(report: &UpdateReport) -> String {
+    report.error.as_ref().map_or_else(|| String::from("no file failed"), ToString::to_string)
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct FileReport {
+    pub install_location: InstallLocation,
+    pub outcome: FileOutcome,
+    /// How the file was handled if it is an archive
+    pub archive: Option<ArchiveDecision>,
+    /// Files extracted from it if it is an archive which was extracted
+    pub extracted: Vec<PathBuf>,
+}
+
+/// Time spent in each phase of an update
+#[derive(Debug, Clone, Default)]
+pub struct Timings {
+    /// Connecting to the server, sending the request and reading the response
+    pub request: Duration,
+    pub download: Duration,
+    pub install: Duration,
+}
+
+/// Everything that happened during a single check-and-install
+#[derive(Debug, Clone, Default)]
+pub struct UpdateReport {
+    /// The response exactly as received, None if no valid response was received
+    pub response: Option<UpdateResponse>,
+    /// Whether an update was offered, accepted, and every file of it installed
+    pub installed: bool,
+    pub files: Vec<FileReport>,
+    /// Files of the previous version deleted after installing, and directories pruned with them
+    pub removed: Removed,
+    /// What went wrong, if anything did
+    pub error: Option<UpdateError>,
+    pub timings: Timings,
+    /// Set if the update offered requires a newer skyline than the one running, whether it was
+    /// installed or not
+    pub skyline: Option<SkylineMismatch>,
+    /// Set if the version offered was skipped by the user before, so they weren't asked about it,
+    /// see `Installer::update_choice`
+    pub skipped: bool,
+    /// Set if the update wasn't installed because it requires a newer skyline, see
+    /// `Installer::allow_outdated_skyline`
+    pub skyline_outdated: bool,
+}
+
+impl UpdateReport {
+    /// Summarize the report as the outcome of the check
+    pub fn status(&self) -> Result<UpdateStatus, UpdateError> {
+        match &self.error {
+            Some(UpdateError::Cancelled) => return Ok(UpdateStatus::Cancelled),
+            Some(error) => return Err(error.clone()),
+            None => {}
+        }
+
+        match self.response.as_ref().map(|response| &response.code) {
+            Some(ResponseCode::NoUpdate) => Ok(UpdateStatus::UpToDate),
+            Some(ResponseCode::Update) if self.installed => Ok(UpdateStatus::Installed {
+                response: self.response.clone().unwrap_or_default(),
+                installed_paths: self.installed_paths(),
+            }),
+            Some(ResponseCode::Update) if self.skipped => Ok(UpdateStatus::UpToDate),
+            Some(ResponseCode::Update) if self.skyline_outdated => Ok(UpdateStatus::SkylineOutdated),
+            Some(ResponseCode::Update) => Ok(UpdateStatus::Declined),
+            Some(ResponseCode::NotifyOnly) => Ok(UpdateStatus::Notified),
+            Some(ResponseCode::InvalidRequest) => Err(UpdateError::InvalidRequest(self.response.as_ref().and_then(|response| response.message.clone()))),
+            Some(ResponseCode::PluginNotFound) => Err(UpdateError::PluginNotFound(self.response.as_ref().and_then(|response| response.message.clone()))),
+            Some(ResponseCode::VersionNotFound) => Err(UpdateError::VersionNotFound(self.response.as_ref().and_then(|response| response.message.clone()))),
+            Some(ResponseCode::RateLimited) => Err(UpdateError::RateLimited {
+                retry_after: self.response.as_ref().and_then(|response| response.retry_after),
+            }),
+            Some(ResponseCode::ServerError) => Err(UpdateError::ServerError(
+                self.response.as_ref().map(server_message).unwrap_or_default()
+            )),
+            Some(code) => Err(UpdateError::Parse(format!("unexpected response code {:?}", code))),
+            None => Err(UpdateError::Parse(String::from("no response"))),
+        }
+    }
+
+    /// Path of every file the update installed, followed by the files extracted from it if it
+    /// is an archive. Archives extracted and then deleted are left out.
+    pub fn installed_paths(&self) -> Vec<PathBuf> {
+        self.files.iter()
+            .filter(|file| file.outcome.is_installed())
+            .flat_map(|file| {
+                let path = match &file.install_location {
+                    InstallLocation::AbsolutePath(path) => Some(PathBuf::from(path)),
+                    _ => None,
+                };
+                let archive = path.filter(|path| file.extracted.is_empty() || path.exists());
+                archive.into_iter().chain(file.extracted.iter().cloned())
+            })
+            .collect()
+    }
+
+    /// Whether the update failed and the files it installed were undone, see
+    /// `Installer::rollback`
+    pub fn rolled_back(&self) -> bool {
+        self.files.iter().any(|file| file.outcome == FileOutcome::RolledBack)
+    }
+}
+
+/// Error naming the first two paths of a response which only differ by case
+fn response_case_collision(response: &UpdateResponse) -> Option<UpdateError> {
+    let paths: Vec<_> = response.required_files.iter().filter_map(storage::install_path).collect();
+    let (first, second) = update_protocol::case_collisions(paths.iter().map(String::as_str)).into_iter().next()?;
+
+    Some(UpdateError::Install {
+        path: second,
+        reason: format!("same file on the SD card as '{}'", first),
+    })
+}
+
+/// Error naming the first two entries of an archive which only differ by case
+fn archive_case_collision(archive: &Path) -> Result<Option<String>, String> {
+    let file = std::fs::File::open(archive)
+        .map_err(|e| format!("failed to open {}: {}", archive.display(), e))?;
+    let mut archive_reader = tar::Archive::new(file);
+    let entries = archive_reader.entries()
+        .map_err(|e| format!("failed to read {}: {}", archive.display(), e))?;
+
+    let mut names = vec![];
+    for entry in entries {
+        let entry = entry.map_err(|e| format!("failed to read {}: {}", archive.display(), e))?;
+        let path = entry.path().map_err(|e| format!("failed to read {}: {}", archive.display(), e))?;
+        names.push(path.to_string_lossy().into_owned());
+    }
+
+    Ok(update_protocol::case_collisions(names.iter().map(String::as_str))
+        .into_iter()
+        .next()
+        .map(|(first, second)| format!(
+            "{} contains '{}' and '{}', which are the same file on the SD card",
+            archive.display(), first, second
+        )))
+}
+
+/// Extract an archive into `to`, returning the path of every file extracted from it
+fn extract(archive: &Path, to: &Path, allow_case_collisions: bool) -> Result<Vec<PathBuf>, String> {
+    if storage::is_zip(archive) {
+        return extract_zip(archive, to, allow_case_collisions)
+    }
+
+    info!("Extracting tar file: {:#?}", archive);
+
+    if !allow_case_collisions {
+        if let Some(collision) = archive_case_collision(archive)? {
+            return Err(collision)
+        }
+    }
+
+    let file = std::fs::File::open(archive)
+        .map_err(|e| format!("failed to open {}: {}", archive.display(), e))?;
+    let error = |e: std::io::Error| format!("failed to extract {}: {}", archive.display(), e);
+
+    std::fs::create_dir_all(to).map_err(error)?;
+    let mut extracted = vec![];
+    for entry in tar::Archive::new(file).entries().map_err(error)? {
+        extracted.extend(unpack_tar_entry(archive, entry.map_err(error)?, to).map_err(error)?);
+    }
+
+    info!("tarball extracted to path: {:#?}", to);
+    Ok(extracted)
+}
+
+/// Path of an archive entry relative to the folder it is extracted to, None if it would land
+/// outside of it, such as `../evil.txt` or an absolute path
+fn enclosed_entry_path(name: &Path) -> Option<PathBuf> {
+    use std::path::Component;
+
+    let mut path = PathBuf::new();
+    for component in name.components() {
+        match component {
+            Component::Normal(part) => path.push(part),
+            Component::CurDir => {}
+            Component::ParentDir | Component::RootDir | Component::Prefix(_) => return None,
+        }
+    }
+
+    Some(path)
+}
+
+/// Extract a single tar entry into `to`. Archives come from whichever server the plugin points
+/// at, so entries which would be written outside of `to`, including links, are skipped and logged.
+/// Returns the path of the extracted file, None for directories and skipped entries.
+fn unpack_tar_entry<R: Read>(archive: &Path, mut entry: tar::Entry<R>, to: &Path) -> std::io::Result<Option<PathBuf>> {
+    let name = entry.path()?.into_owned();
+    let kind = entry.header().entry_type();
+    let enclosed = match enclosed_entry_path(&name) {
+        Some(enclosed) if !kind.is_symlink() && !kind.is_hard_link() => enclosed,
+        _ => {
+            warn!("[updater] Skipping '{}' in {}, it would be extracted outside of {}", name.display(), archive.display(), to.display());
+            return Ok(None)
+        }
+    };
+
+    entry.unpack_in(to)?;
+    Ok(Some(to.join(enclosed)).filter(|_| !kind.is_dir()))
+}
+
+/// Extract a zip archive into `to`. Zip archives list their entries at the end, so the archive
+/// is read into memory and opened from there rather than seeking around the file on the SD card.
+fn extract_zip(archive: &Path, to: &Path, allow_case_collisions: bool) -> Result<Vec<PathBuf>, String> {
+    info!("Extracting zip file: {:#?}", archive);
+
+    let buf = std::fs::read(archive)
+        .map_err(|e| format!("failed to open {}: {}", archive.display(), e))?;
+    let mut zip = zip::ZipArchive::new(std::io::Cursor::new(buf))
+        .map_err(|e| format!("failed to read {}: {}", archive.display(), e))?;
+
+    if !allow_case_collisions {
+        if let Some((first, second)) = update_protocol::case_collisions(zip.file_names()).into_iter().next() {
+            return Err(format!(
+                "{} contains '{}' and '{}', which are the same file on the SD card",
+                archive.display(), first, second
+            ))
+        }
+    }
+
+    let extracted = unpack_zip(archive, &mut zip, to)
+        .map_err(|e| format!("failed to extract {}: {}", archive.display(), e))?;
+
+    info!("zip extracted to path: {:#?}", to);
+    Ok(extracted)
+}
+
+/// Extract every entry of `zip` into `to`, skipping and logging entries which would be written
+/// outside of it like `unpack_tar_entry` does. Returns the path of every file extracted.
+fn unpack_zip<R: Read + Seek>(archive: &Path, zip: &mut zip::ZipArchive<R>, to: &Path) -> zip::result::ZipResult<Vec<PathBuf>> {
+    std::fs::create_dir_all(to)?;
+    let mut extracted = vec![];
+    for i in 0..zip.len() {
+        let mut entry = zip.by_index(i)?;
+        let path = match entry.enclosed_name().and_then(enclosed_entry_path) {
+            Some(name) => to.join(name),
+            None => {
+                warn!("[updater] Skipping '{}' in {}, it would be extracted outside of {}", entry.name(), archive.display(), to.display());
+                continue
+            }
+        };
+
+        if entry.is_dir() {
+            std::fs::create_dir_all(&path)?;
+            continue
+        }
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        std::io::copy(&mut entry, &mut std::fs::File::create(&path)?)?;
+        extracted.push(path);
+    }
+
+    Ok(extracted)
+}
+
+/// Extract an archive read from `reader` into `to` entry by entry, calling `on_entry` with the
+/// number of entries extracted so far, and return the path of every file extracted. Collisions can
+/// only be found once both entries are read, so entries before a collision or any other failure
+/// are left extracted.
+fn extract_streamed<R, F>(archive: &Path, reader: R, to: &Path, allow_case_collisions: bool, mut on_entry: F) -> Result<Vec<PathBuf>, String>
+    where R: Read,
+          F: FnMut(usize),
+{
+    let error = |e: std::io::Error| format!("failed to extract {}: {}", archive.display(), e);
+
+    std::fs::create_dir_all(to).map_err(error)?;
+    let mut reader = tar::Archive::new(reader);
+    let mut seen = std::collections::HashMap::new();
+    let mut extracted = vec![];
+    for (i, entry) in reader.entries().map_err(error)?.enumerate() {
+        let entry = entry.map_err(error)?;
+        let name = entry.path().map_err(error)?.to_string_lossy().into_owned();
+
+        if !allow_case_collisions {
+            let normalized = update_protocol::normalize_path(&name).unwrap_or_else(|_| name.clone());
+            let first = seen.entry(normalized.to_lowercase()).or_insert_with(|| normalized.clone());
+            if *first != normalized {
+                return Err(format!(
+                    "{} contains '{}' and '{}', which are the same file on the SD card",
+                    archive.display(), first, name
+                ))
+            }
+        }
+
+        extracted.extend(unpack_tar_entry(archive, entry, to).map_err(error)?);
+        on_entry(i + 1);
+    }
+
+    info!("tarball extracted to path: {:#?}", to);
+    Ok(extracted)
+}
+
+/// Path `file` is installed at if the installer extracts it as it downloads, see
+/// `Installer::stream_archives`. Archives with a hash to check, and any archive of clients
+/// checking signatures, are only extracted once the whole of them was checked.
+fn streamed_archive_path<I: Installer>(client: &UpdateClient, file: &UpdateFile, installer: &I) -> Option<PathBuf> {
+    if !installer.stream_archives() || client.public_key().is_some() || file.sha256.is_some() {
+        return None
+    }
+
+    match file.install_location.normalized() {
+        Ok(InstallLocation::AbsolutePath(path)) if storage::is_archive(Path::new(&path)) && !storage::is_zip(Path::new(&path)) => Some(path.into()),
+        _ => None,
+    }
+}
+
+fn update_file<I>(client: &UpdateClient, download_port: u16, file: &UpdateFile, installer: &I, prefetch: Option<&mut Prefetch>, timings: &mut Timings) -> Result<Option<(ArchiveDecision, Vec<PathBuf>)>, UpdateError>
+    where I: Installer,
+{
+    if let Some(path) = streamed_archive_path(client, file, installer) {
+        return stream_archive(client, download_port, file, path, installer, timings)
+    }
+
+    let downloaded = fetch_file(client, download_port, file, installer, prefetch, timings)?;
+    install_downloaded(file, downloaded, installer, timings)
+}
+
+/// Download a file of an update, or decode it if it was sent inline, and check it against the
+/// hash the server sent for it. Files `prefetch` is downloading are waited for instead.
+fn fetch_file<I>(client: &UpdateClient, download_port: u16, file: &UpdateFile, installer: &I, prefetch: Option<&mut Prefetch>, timings: &mut Timings) -> Result<Downloaded, UpdateError>
+    where I: Installer,
+{
+    check_download_size(client, file)?;
+    let start = Instant::now();
+    let on_progress = |file: &UpdateFile, downloaded| installer.on_progress(file, downloaded, file.size);
+    let downloaded = match inline_file(file) {
+        Some(buf) => {
+            installer.on_progress(file, buf.len(), file.size);
+            Ok(Downloaded::Inline(buf))
+        }
+        None => match prefetch.and_then(|prefetch| prefetch.wait(file.download_index, on_progress)) {
+            Some(download) => download.map(Downloaded::Temp),
+            None => TempDownload::download(client, download_port, file.download_index, file.size, |downloaded| on_progress(file, downloaded))
+                .map(Downloaded::Temp),
+        },
+    };
+    if client.is_cancelled() {
+        return Err(UpdateError::Cancelled)
+    }
+    let downloaded = downloaded.map_err(|reason| download_failure(file.download_index, reason))?;
+    timings.download += start.elapsed();
+    check_sha256(file, || match &downloaded {
+        Downloaded::Inline(buf) => resume::sha256_of(&buf[..]).unwrap_or_default(),
+        Downloaded::Temp(download) => download.sha256.clone(),
+    })?;
+
+    Ok(downloaded)
+}
+
+/// Refuse a file the server says is larger than the client's download limit before any of it
+/// is downloaded, as downloads held in memory would otherwise run the console out of it
+fn check_download_size(client: &UpdateClient, file: &UpdateFile) -> Result<(), UpdateError> {
+    let limit = client.max_download_size();
+    if file.size <= limit {
+        return Ok(())
+    }
+
+    let path = install_path_of(file)
+        .map(|path| path.display().to_string())
+        .unwrap_or_else(|_| format!("{:?}", file.install_location));
+    warn!("[updater] Refusing to download {}: it is {} bytes, over the limit of {} bytes", path, file.size, limit);
+    Err(UpdateError::TooLarge { path, size: file.size, limit })
+}
+
+/// Path to install a file at, once its location was resolved
+fn install_path_of(file: &UpdateFile) -> Result<PathBuf, UpdateError> {
+    match file.install_location.normalized() {
+        Ok(InstallLocation::AbsolutePath(path)) => Ok(path.into()),
+        Ok(location) => {
+            warn!("[updater] Refusing to install file: unsupported install location {:?}", location);
+            Err(unsupported_location(&location))
+        }
+        Err(e) => {
+            warn!("[updater] Refusing to install file: {}", e);
+            Err(UpdateError::Install { path: format!("{:?}", file.install_location), reason: e.to_string() })
+        }
+    }
+}
+
+/// Extract an installed archive as `decision` says, deleting it afterwards unless
+/// `keep_archives`. An archive which fails to extract is kept to extract by hand. Returns the
+/// files extracted from it.
+fn finish_archive(path: &Path, decision: &ArchiveDecision, allow_case_collisions: bool, keep_archives: bool) -> Result<Vec<PathBuf>, String> {
+    let extracted = match decision {
+        ArchiveDecision::ExtractNow => extract(path, &path.with_extension(""), allow_case_collisions),
+        ArchiveDecision::ExtractTo(to) => extract(path, to, allow_case_collisions),
+        ArchiveDecision::LeaveArchived => {
+            info!("Leaving {:#?} archived", path);
+            Ok(vec![])
+        }
+    };
+    if let Err(e) = &extracted {
+        warn!("[updater] Failed to extract {}, it was kept to extract by hand: {}", path.display(), e);
+    } else if *decision != ArchiveDecision::LeaveArchived && !keep_archives {
+        remove_extracted_archive(path);
+    }
+
+    extracted
+}
+
+/// Install a file fetched by `fetch_file` and extract it if it is an archive, returning how the
+/// archive was handled and the files extracted from it
+fn install_downloaded<I>(file: &UpdateFile, downloaded: Downloaded, installer: &I, timings: &mut Timings) -> Result<Option<(ArchiveDecision, Vec<PathBuf>)>, UpdateError>
+    where I: Installer,
+{
+    let path = install_path_of(file)?;
+    info!("Downloaded file: {:#?}", path.clone());
+
+    let root = storage::existing_ancestor(&path);
+    let install_error = |reason: String| match &root {
+        Some(root) if storage::storage_vanished(root) => UpdateError::StorageUnavailable { path: path.display().to_string() },
+        _ => UpdateError::Install { path: path.display().to_string(), reason },
+    };
+
+    let start = Instant::now();
+
+    // only reached for archives which would have been streamed when they were staged or have a
+    // hash to check, so they are extracted from the checked file rather than installed first
+    if installer.stream_archives() && storage::is_archive(&path) && !storage::is_zip(&path) {
+        let to = path.with_extension("");
+        let allow_case_collisions = installer.allow_case_collisions();
+        let on_entry = |entries| installer.on_extract_progress(file, entries);
+        let extracted = match &downloaded {
+            Downloaded::Inline(buf) => extract_streamed(&path, &buf[..], &to, allow_case_collisions, on_entry),
+            Downloaded::Temp(download) => std::fs::File::open(&download.path)
+                .map_err(|e| format!("failed to open {}: {}", download.path.display(), e))
+                .and_then(|staged| extract_streamed(&path, std::io::BufReader::new(staged), &to, allow_case_collisions, on_entry)),
+        };
+        timings.install += start.elapsed();
+
+        return match extracted {
+            Ok(extracted) => Ok(Some((ArchiveDecision::ExtractNow, extracted))),
+            Err(e) => {
+                warn!("[updater] {}", e);
+                Err(install_error(e))
+            }
+        }
+    }
+
+    let installed = match downloaded {
+        Downloaded::Inline(buf) => installer.install_file(path.clone(), buf),
+        Downloaded::Temp(download) => std::fs::File::open(&download.path)
+            .map_err(|e| InstallError::new(&path, e).with_context(format!("opening the download at {}", download.path.display())))
+            .and_then(|file| installer.install_file_streamed(path.clone(), &mut std::io::BufReader::new(file), download.size)),
+    };
+    if let Err(e) = installed {
+        warn!("[updater] {}", e);
+        return Err(install_error(e.reason()))
+    }
+
+    let mut archive = None;
+    if storage::is_archive(&path) {
+        let decision = installer.handle_archive(file, &path);
+        let extracted = finish_archive(&path, &decision, installer.allow_case_collisions(), installer.keep_archives())
+            .map_err(install_error)?;
+        archive = Some((decision, extracted));
+    }
+
+    timings.install += start.elapsed();
+
+    Ok(archive)
+}
+
+/// Extract an archive into its path without the extension as it downloads, see
+/// `Installer::stream_archives`
+fn stream_archive<I>(client: &UpdateClient, download_port: u16, file: &UpdateFile, path: PathBuf, installer: &I, timings: &mut Timings) -> Result<Option<(ArchiveDecision, Vec<PathBuf>)>, UpdateError>
+    where I: Installer,
+{
+    check_download_size(client, file)?;
+    // downloading and extracting overlap, so all of it counts as installing
+    let start = Instant::now();
+    let to = path.with_extension("");
+    let root = storage::existing_ancestor(&to);
+    info!("Extracting {:#?} as it downloads", &path);
+
+    let allow_case_collisions = installer.allow_case_collisions();
+    let on_entry = |entries| installer.on_extract_progress(file, entries);
+    let (extracted, download_error) = match inline_file(file) {
+        Some(buf) => {
+            installer.on_progress(file, buf.len(), file.size);
+            (extract_streamed(&path, &buf[..], &to, allow_case_collisions, on_entry), None)
+        }
+        None => {
+            let stream = open_download(client, download_port, file.download_index)
+                .map_err(|reason| download_failure(file.download_index, reason))?;
+            let mut reader = DownloadReader {
+                stream,
+                downloaded: 0,
+                size: file.size,
+                cancel: client.cancel_token().clone(),
+                on_progress: |downloaded| installer.on_progress(file, downloaded, file.size),
+                error: None,
+            };
+            let extracted = extract_streamed(&path, &mut reader, &to, allow_case_collisions, on_entry);
+            reader.stream.shutdown();
+            (extracted, reader.error)
+        }
+    };
+    timings.install += start.elapsed();
+
+    match (extracted, download_error) {
+        (Ok(extracted), _) => Ok(Some((ArchiveDecision::ExtractNow, extracted))),
+        (Err(_), _) if client.is_cancelled() => {
+            info!("[updater] Extracting {} cancelled, the entries before were left extracted", path.display());
+            Err(UpdateError::Cancelled)
+        }
+        (Err(_), Some(e)) => {
+            warn!("[updater] Failed to download {}: {}", path.display(), e);
+            Err(download_failure(file.download_index, e))
+        }
+        (Err(e), None) => {
+            warn!("[updater] {}", e);
+            match &root {
+                Some(root) if storage::storage_vanished(root) => Err(UpdateError::StorageUnavailable { path: path.display().to_string() }),
+                _ => Err(UpdateError::Install { path: path.display().to_string(), reason: e }),
+            }
+        }
+    }
+}
+
+/// Fail if the server sent a SHA-256 for `file` and the one of what was downloaded, computed by
+/// `actual`, differs, so a download corrupted on the way is never installed
+fn check_sha256<F: FnOnce() -> String>(file: &UpdateFile, actual: F) -> Result<(), UpdateError> {
+    let expected = match &file.sha256 {
+        Some(expected) => expected,
+        None => return Ok(()),
+    };
+
+    let actual = actual();
+    if actual.eq_ignore_ascii_case(expected) {
+        Ok(())
+    } else {
+        warn!("[updater] File {} was corrupted while downloading, expected sha256 {} but got {}", file.download_index, expected, actual);
+        Err(UpdateError::Checksum { index: file.download_index, expected: expected.clone(), actual })
+    }
+}
+
+/// Delete an archive once extracted. The update is installed by then, so failing to delete it
+/// only leaves the archive behind.
+fn remove_extracted_archive(archive: &Path) {
+    if let Err(e) = std::fs::remove_file(archive) {
+        warn!("[updater] Failed to remove {} after extracting it: {}", archive.display(), e);
+    }
+}
+
+fn unsupported_location(location: &InstallLocation) -> UpdateError {
+    UpdateError::Install {
+        path: format!("{:?}", location),
+        reason: String::from("unsupported install location, a newer version of the updater may support it"),
+    }
+}
+
+/// The update with every relative install location resolved against the installer's root,
+/// or the error of the first location which can't be installed to. Updates without any files
+/// are refused, see `UpdateResponse::is_empty_update`.
+fn resolve_locations<I>(response: &UpdateResponse, installer: &I) -> Result<UpdateResponse, UpdateError>
+    where I: Installer,
+{
+    resolve_locations_at(response, &installer.install_root(response))
+}
+
+/// Resolve the relative install locations of an update against `root`
+fn resolve_locations_at(response: &UpdateResponse, root: &str) -> Result<UpdateResponse, UpdateError> {
+    if response.is_empty_update() {
+        return Err(UpdateError::EmptyUpdate)
+    }
+    let mut resolved = response.clone();
+
+    for file in &mut resolved.required_files {
+        file.install_location = match file.install_location.resolve(root) {
+            Ok(location @ InstallLocation::AbsolutePath(_)) => location,
+            Ok(location) => return Err(unsupported_location(&location)),
+            Err(e) => return Err(UpdateError::Install { path: format!("{:?}", file.install_location), reason: e.to_string() }),
+        };
+    }
+
+    Ok(resolved)
+}
+
+/// Whether an earlier attempt at the same update installed `file`, and it is still intact
+fn already_installed<I>(progress: &Progress, file: &UpdateFile, installer: &I) -> bool
+    where I: Installer,
+{
+    progress.contains(file) && storage::install_path(file)
+        .and_then(|path| installer.installed_sha256(Path::new(&path)))
+        .as_ref() == file.sha256.as_ref()
+}
+
+/// Download every file of an update which isn't installed yet before installing any, see
+/// `Installer::stage_downloads`. The files are staged in `download_dir`, or in memory if they
+/// were sent inline, and None for files an earlier attempt installed or which aren't `wanted`.
+/// If one fails, the staged files are deleted and the outcome of every file is returned instead.
+#[allow(clippy::too_many_arguments)]
+fn stage_files<I>(client: &UpdateClient, download_port: u16, response: &UpdateResponse, wanted: &[bool], progress: &Progress, installer: &I, mut prefetch: Option<&mut Prefetch>, timings: &mut Timings) -> Result<Vec<Option<Downloaded>>, Vec<FileReport>>
+    where I: Installer,
+{
+    let mut staged = vec![];
+    for (i, file) in response.required_files.iter().enumerate() {
+        let result = if !wanted[i] {
+            Ok(None)
+        } else if client.is_cancelled() {
+            Err(UpdateError::Cancelled)
+        } else if already_installed(progress, file, installer) {
+            installer.on_progress(file, file.size, file.size);
+            Ok(None)
+        } else {
+            fetch_file(client, download_port, file, installer, prefetch.as_deref_mut(), timings).map(Some)
+        };
+
+        match result {
+            Ok(downloaded) => staged.push(downloaded),
+            Err(e) => {
+                warn!("[{} updater] Nothing was installed, {}", response.plugin_name, e);
+                let files = response.required_files.iter()
+                    .enumerate()
+                    .map(|(j, file)| FileReport {
+                        install_location: file.install_location.clone(),
+                        outcome: match staged.get(j) {
+                            _ if !wanted[j] => FileOutcome::Excluded,
+                            Some(None) => FileOutcome::AlreadyInstalled,
+                            _ if j == i => FileOutcome::Failed(e.clone()),
+                            _ => FileOutcome::Skipped,
+                        },
+                        archive: None,
+                        extracted: vec![],
+                    })
+                    .collect();
+                return Err(files)
+            }
+        }
+    }
+
+    Ok(staged)
+}
+
+/// Install every file of an update in order, stopping at the first failure. Files an earlier,
+/// interrupted attempt at the same update installed are skipped, see `resume::Progress`.
+fn update_files<I>(client: &UpdateClient, response: &UpdateResponse, installer: &I, timings: &mut Timings) -> Vec<FileReport>
+    where I: Installer,
+{
+    let download_port = response.download_port.unwrap_or_else(|| client.download_port());
+    if response.keep_alive_downloads {
+        client.keep_alive().enable(download_port);
+    }
+
+    let files = install_files(client, response, installer, timings);
+
+    // connections aren't kept open past the update
+    client.keep_alive().disable(download_port);
+
+    files
+}
+
+/// See `update_files`
+fn install_files<I>(client: &UpdateClient, response: &UpdateResponse, installer: &I, timings: &mut Timings) -> Vec<FileReport>
+    where I: Installer,
+{
+    let mut failed = false;
+    let download_port = response.download_port.unwrap_or_else(|| client.download_port());
+
+    let progress_dir = resume::progress_dir();
+    let mut progress = resume::load_progress(&progress_dir, &response.plugin_name)
+        .filter(|progress| progress.matches(response))
+        .unwrap_or_else(|| Progress::new(response));
+
+    installer.on_update_start(response);
+
+    let wanted: Vec<bool> = response.required_files.iter()
+        .map(|file| installer.should_install_file(file))
+        .collect();
+
+    // files recorded as installed are most likely intact and skipped, so they aren't fetched
+    // ahead, nor are archives extracted as they download
+    let mut prefetch = Some(client.max_concurrent_downloads())
+        .filter(|&connections| connections > 1)
+        .map(|connections| {
+            let files = response.required_files.iter()
+                .zip(&wanted)
+                .filter(|(_, &wanted)| wanted)
+                .map(|(file, _)| file)
+                .filter(|file| file.inline_data.is_none() && !progress.contains(file))
+                .filter(|file| file.size <= client.max_download_size())
+                .filter(|file| installer.stage_downloads() || streamed_archive_path(client, file, installer).is_none())
+                .cloned()
+                .collect();
+            Prefetch::start(client, download_port, files, connections)
+        });
+
+    let mut staged = if installer.stage_downloads() {
+        match stage_files(client, download_port, response, &wanted, &progress, installer, prefetch.as_mut(), timings) {
+            Ok(staged) => Some(staged.into_iter()),
+            Err(files) => {
+                installer.on_update_complete(response, false);
+                return files
+            }
+        }
+    } else {
+        None
+    };
+
+    installer.begin_transaction(response);
+    let mut files: Vec<FileReport> = response.required_files.iter()
+        .zip(wanted)
+        .map(|(file, wanted)| {
+            // Some(None) if the file was found already installed while staging
+            let staged_file = staged.as_mut().map(|staged| staged.next().flatten());
+            let (mut archive, mut extracted) = (None, vec![]);
+            let outcome = if failed {
+                FileOutcome::Skipped
+            } else if !wanted {
+                FileOutcome::Excluded
+            } else if client.is_cancelled() {
+                failed = true;
+                FileOutcome::Failed(UpdateError::Cancelled)
+            } else if matches!(staged_file, Some(None)) || (staged_file.is_none() && already_installed(&progress, file, installer)) {
+                if staged_file.is_none() {
+                    installer.on_progress(file, file.size, file.size);
+                }
+                FileOutcome::AlreadyInstalled
+            } else {
+                let result = match staged_file {
+                    Some(Some(downloaded)) => install_downloaded(file, downloaded, installer, timings),
+                    _ => update_file(client, download_port, file, installer, prefetch.as_mut(), timings),
+                };
+                match result {
+                    Ok(handled) => {
+                        if let Some((decision, files)) = handled {
+                            archive = Some(decision);
+                            extracted = files;
+                        }
+                        if progress.push(file) {
+                            resume::save_progress(&progress_dir, &response.plugin_name, &progress);
+                        }
+                        FileOutcome::Installed
+                    }
+                    Err(e) => {
+                        failed = true;
+                        FileOutcome::Failed(e)
+                    }
+                }
+            };
+
+            FileReport {
+                install_location: file.install_location.clone(),
+                outcome,
+                archive,
+                extracted,
+            }
+        })
+        .collect();
+
+    if !failed {
+        installer.commit(response);
+        resume::clear_progress(&progress_dir, &response.plugin_name);
+    } else if installer.rollback(response) {
+        // the files recorded as installed were undone, so the next attempt starts over
+        warn!("[{} updater] Restored the files replaced before the update failed", response.plugin_name);
+        resume::clear_progress(&progress_dir, &response.plugin_name);
+        for file in files.iter_mut().filter(|file| file.outcome == FileOutcome::Installed) {
+            file.outcome = FileOutcome::RolledBack;
+        }
+    }
+    installer.on_update_complete(response, !failed);
+
+    files
+}
+
+/// Install an update received earlier, without asking the installer first. As the update was picked
+/// to install, it's installed even if the version was rolled back from or skipped.
+fn update<I>(client: &UpdateClient, response: &UpdateResponse, installer: &I) -> UpdateReport
+    where I: Installer,
+{
+    let mut report = UpdateReport {
+        response: Some(response.clone()),
+        ..Default::default()
+    };
+    install_offered_update(client, &response.plugin_name, response, installer, &mut report, false, true);
+
+    report
+}
+
+fn update_request(client: &UpdateClient, name: &str, version: &str, allow_beta: bool, skyline_version: Option<String>, pinned_version: Option<&str>) -> Request {
+    let mut options = UpdateRequestOptions::default();
+    options.protocol_version = Some(update_protocol::PROTOCOL_VERSION);
+    options.accept_inline = true;
+    options.skyline_version = skyline_version.or_else(|| client.skyline_version().map(str::to_owned));
+    options.client_version = Some(updater_version().to_owned());
+    options.pinned_version = pinned_version.map(str::to_owned);
+    options.platform = client.platform().map(str::to_owned);
+    options.plugin_options = client.options().clone();
+    options.channel = client.channel().map(str::to_owned);
+
+    Request::Update {
+        beta: Some(allow_beta),
+        plugin_name: name.to_owned(),
+        plugin_version: version.to_owned(),
+        options: Some(options),
+    }
+}
+
+/// Install an update with a custom installer implementation, reporting the server's response,
+/// the outcome of every file, and how long each phase took.
+pub fn custom_check_update_report<I>(ip: IpAddr, name: &str, version: &str, beta: impl Into<BetaPreference>, installer: &I) -> UpdateReport
+    where I: Installer,
+{
+    UpdateClient::new(ip).custom_check_update_report(name, version, beta, installer)
+}
+
+fn check_update_report_on<I>(client: &UpdateClient, name: &str, version: &str, beta: BetaPreference, installer: &I) -> UpdateReport
+    where I: Installer,
+{
+    let allow_beta = client.allows_beta(beta, name);
+    let mut report = request_update(client, name, version, allow_beta.into(), installer);
+    if let Some(response) = report.response.take() {
+        handle_response(client, name, &response, installer, &mut report, allow_beta, false);
+        report.response = Some(response);
+    }
+
+    // the server reloaded its plugins since answering, the files it offers now are asked for
+    // once more. The same version was already agreed to.
+    if let Some(UpdateError::FileGone { .. }) = report.error {
+        info!("[{} updater] The update changed on the server while downloading it, asking for it again", name);
+        let offered = report.response.take().map(|response| response.new_plugin_version);
+        report = request_update(client, name, version, allow_beta.into(), installer);
+        if let Some(response) = report.response.take() {
+            let confirmed = offered.as_ref() == Some(&response.new_plugin_version);
+            handle_response(client, name, &response, installer, &mut report, allow_beta, confirmed);
+            report.response = Some(response);
+        }
+    }
+
+    report
+}
+
+/// Ask the server for an update, without acting on the response
+fn request_update<I>(client: &UpdateClient, name: &str, version: &str, beta: BetaPreference, installer: &I) -> UpdateReport
+    where I: Installer,
+{
+    send_update_request(client, name, &update_request(client, name, version, client.allows_beta(beta, name), running_skyline_version(client, installer), None))
+}
+
+/// The line sent to the main port for `request`, None if it couldn't be encoded
+fn encode_request(name: &str, request: &Request) -> Option<String> {
+    match serde_json::to_string(request) {
+        Ok(packet) => Some(format!("{}\n", packet)),
+        Err(_) => {
+            error!("[{} updater] Failed to encode packet", name);
+            None
+        }
+    }
+}
+
+/// Parse the server's answer to an update request
+fn decode_response(name: &str, string: String) -> Result<UpdateResponse, UpdateError> {
+    serde_json::from_str::<UpdateResponse>(&string).map_err(|_| {
+        if let Some(code) = unknown_response_code(&string) {
+            error!("[{} updater] Update server answered with unknown response code {}, it may be newer than the updater", name, code);
+            return UpdateError::UnknownResponseCode(code)
+        }
+        error!("[{} updater] Failed to parse update server response: {:?}", name, string);
+        UpdateError::Parse(string)
+    })
+}
+
+/// The code of a response which is otherwise well formed but whose code this version of the
+/// protocol doesn't know, as sent
+fn unknown_response_code(string: &str) -> Option<String> {
+    let response = serde_json::from_str::<serde_json::Value>(string).ok()?;
+    let code = response.get("code")?;
+    if serde_json::from_value::<ResponseCode>(code.clone()).is_ok() {
+        return None
+    }
+
+    Some(code.as_str().map(str::to_owned).unwrap_or_else(|| code.to_string()))
+}
+
+/// The reason a `ResponseCode::ServerError` response gives
+fn server_message(response: &UpdateResponse) -> String {
+    response.message.clone().unwrap_or_else(|| String::from("no reason given"))
+}
+
+/// The reason a response rejecting the request gives, as `": <reason>"` to append to a log
+/// message, or nothing if it gave none
+fn reason_suffix(response: &UpdateResponse) -> String {
+    response.message.as_ref()
+        .map(|message| format!(": {}", message))
+        .unwrap_or_default()
+}
+
+/// How long to wait before sending a request the server rate limited once more, None if it
+/// isn't retried, see `UpdateClient::with_rate_limit_retry`
+fn rate_limit_delay(client: &UpdateClient, name: &str, report: &UpdateReport) -> Option<Duration> {
+    let response = report.response.as_ref().filter(|response| response.code == ResponseCode::RateLimited)?;
+    let delay = Duration::from_secs(response.retry_after?);
+    if delay > client.rate_limit_retry()? {
+        return None
+    }
+
+    info!("[{} updater] Update server is busy, asking again in {:?}", name, delay);
+    Some(delay)
+}
+
+/// Send an update request to the server, reporting its response. A request the server rate
+/// limits is sent once more if the client is willing to wait as long as the server asks.
+fn send_update_request(client: &UpdateClient, name: &str, request: &Request) -> UpdateReport {
+    let report = send_update_request_once(client, name, request);
+    match rate_limit_delay(client, name, &report) {
+        Some(delay) => {
+            std::thread::sleep(delay);
+            send_update_request_once(client, name, request)
+        }
+        None => report,
+    }
+}
+
+/// Send an update request to the server once, reporting its response
+fn send_update_request_once(client: &UpdateClient, name: &str, request: &Request) -> UpdateReport {
+    log_version_once();
+
+    let mut report = UpdateReport::default();
+    let start = Instant::now();
+
+    match client.connect_request() {
+        Ok(mut stream) =>  {
+            if let Some(packet) = encode_request(name, request) {
+                let _ = stream.write_all(packet.as_bytes());
+                let mut string = String::new();
+                let timed_out = read_response(&mut stream, &mut string).err().filter(is_timeout);
+                report.timings.request = start.elapsed();
+
+                if timed_out.is_some() {
+                    warn!("[{} updater] Update server did not respond within {:?}", name, client.timeouts().request);
+                    report.error = Some(UpdateError::Timeout);
+                } else {
+                    match decode_response(name, string) {
+                        Ok(response) => report.response = Some(response),
+                        Err(e) => report.error = Some(e),
+                    }
+                }
+            } else {
+                report.error = Some(UpdateError::InvalidRequest(None));
+            }
+        }
+        Err(e @ UpdateError::Resolve { .. }) => {
+            error!("[{} updater] {}", name, e);
+            report.error = Some(e);
+        }
+        Err(e) => {
+            error!("[{} updater] Failed to connect to update server {}", name, client.host());
+            error!("[{} updater] {}", name, e);
+            report.error = Some(e);
+        }
+    }
+
+    report
+}
+
+/// Act on the server's response: install an update (asking the installer first unless
+/// `confirmed`), show a notification, or log an error. `allow_beta` is whether the update was
+/// asked for with betas, to ask for its changelog the same way.
+fn handle_response<I>(client: &UpdateClient, name: &str, response: &UpdateResponse, installer: &I, report: &mut UpdateReport, allow_beta: bool, confirmed: bool)
+    where I: Installer,
+{
+    match response.code {
+        ResponseCode::NoUpdate => {}
+        ResponseCode::Update => {
+            if !declined_offer(name, response, report) {
+                install_offered_update(client, name, response, installer, report, allow_beta, confirmed);
+            }
+        }
+        ResponseCode::NotifyOnly => {
+            notify_update(&notified_dir(), response, installer);
+        }
+        ResponseCode::InvalidRequest => {
+            error!("[{} updater] Failed to send a valid request to the server{}", name, reason_suffix(response));
+        }
+        ResponseCode::PluginNotFound => {
+            error!("Plugin '{}' could not be found on the update server{}", name, reason_suffix(response));
+        }
+        ResponseCode::VersionNotFound => {
+            error!("[{} updater] The version asked for could not be found on the update server{}", name, reason_suffix(response));
+        }
+        ResponseCode::RateLimited => {
+            warn!("[{} updater] {}", name, UpdateError::RateLimited { retry_after: response.retry_after });
+        }
+        ResponseCode::ServerError => {
+            error!("[{} updater] {}", name, UpdateError::ServerError(server_message(response)));
+        }
+        _ => {
+            error!("Unexpected response");
+        }
+    }
+}
+
+/// Whether the version `response` offers was rolled back from or skipped, and so isn't installed
+fn declined_offer(name: &str, response: &UpdateResponse, report: &mut UpdateReport) -> bool {
+    if changes::offers_rolled_back(&changes::installed_dir(), response) {
+        warn!("[{} updater] Not installing {}, it was rolled back from", name, response.new_plugin_version);
+        return true
+    }
+    if skip::is_skipped(&skip::skipped_dir(), response) {
+        info!("[{} updater] Not asking about {}, it was skipped", name, response.new_plugin_version);
+        report.skipped = true;
+        return true
+    }
+
+    false
+}
+
+/// Install the update `response` offers, asking the installer first unless `confirmed`. Shared
+/// by every way of installing an update, so each is checked and recorded the same way.
+fn install_offered_update<I>(client: &UpdateClient, name: &str, response: &UpdateResponse, installer: &I, report: &mut UpdateReport, allow_beta: bool, confirmed: bool)
+    where I: Installer,
+{
+    // checked as received, before anyone is asked about it or any of it is downloaded, as the
+    // server signs install locations before they are resolved
+    if let Some(public_key) = client.public_key() {
+        if let Err(e) = signing::verify_response(response, public_key) {
+            report.error = Some(e);
+            return
+        }
+    }
+    let response = match resolve_locations(response, installer) {
+        Ok(resolved) => resolved,
+        Err(e) => {
+            warn!("[{} updater] Refusing to install update: {}", name, e);
+            report.error = Some(e);
+            return
+        }
+    };
+    let response = &response;
+
+    let installed_dir = changes::installed_dir();
+    report.skyline = skyline_mismatch(response, running_skyline_version(client, installer).as_deref());
+    if let Some(mismatch) = &report.skyline {
+        if !installer.allow_outdated_skyline() {
+            warn!("[{} updater] Not installing {}: {}", name, response.new_plugin_version, mismatch);
+            installer.on_skyline_outdated(response, mismatch);
+            report.skyline_outdated = true;
+            return
+        }
+        warn!("[{} updater] {}, its files may not load until skyline is updated", name, mismatch);
+    }
+    let previous = changes::load_manifest(&installed_dir, &response.plugin_name);
+    let prompt = UpdatePrompt {
+        response,
+        storage: storage::estimate_storage(response, &storage::current_footprint(response)),
+        changes: compute_change_set(previous.as_ref(), response),
+        // nobody is asked about confirmed updates, so there is no need for it
+        changelog: if confirmed { None } else { fetch_changelog(client, response, allow_beta) },
+        skyline: report.skyline.clone(),
+    };
+    let collision = response_case_collision(response).filter(|_| !installer.allow_case_collisions());
+    if let Some(collision) = collision {
+        warn!("[{} updater] Refusing to install update: {}", name, collision);
+        report.error = Some(collision);
+    } else if confirmed || ask_to_install(installer, &prompt) {
+        report.files = update_files(client, response, installer, &mut report.timings);
+        report.installed = report.files.iter().all(|file| file.outcome.succeeded());
+
+        if report.installed {
+            let mut installed = InstalledManifest::from_response(response);
+            installed.record_extracted(&extracted_files(response, &report.files), previous.as_ref());
+            if installer.remove_stale_files() {
+                let boundary = if installer.prune_empty_dirs() {
+                    prompt.changes.install_root()
+                } else {
+                    None
+                };
+                let stale = previous.as_ref().map(|previous| changes::stale_entries(previous, &installed)).unwrap_or_default();
+                report.removed = changes::remove_files(&prompt.changes, &stale, boundary.as_deref());
+            }
+            report.removed.files.extend(changes::delete_listed_files(response, &installer.install_root(response)));
+            changes::save_manifest(&installed_dir, &response.plugin_name, &installed);
+            info!("[updater] {}", completion_message(&report.files));
+        } else {
+            report.error = report.files.iter().find_map(|file| match &file.outcome {
+                FileOutcome::Failed(e) => Some(e.clone()),
+                _ => None,
+            });
+            if let Some(UpdateError::StorageUnavailable { path }) = &report.error {
+                warn!("[{} updater] SD card became unavailable while installing {}, stopping the update.", name, path);
+                installer.on_storage_unavailable(response);
+            } else if report.error == Some(UpdateError::Cancelled) && report.rolled_back() {
+                info!("[{} updater] Update cancelled, the files it replaced were restored.", name);
+            } else if report.error == Some(UpdateError::Cancelled) {
+                info!("[{} updater] Update cancelled, the next attempt resumes after the files already installed.", name);
+            } else if report.rolled_back() {
+                error!("[{} updater] Failed to install update ({}), the files it replaced were restored.", name, failure(report));
+            } else {
+                error!("[{} updater] Failed to install update ({}), files may be left in a broken state.", name, failure(report));
+            }
+        }
+    }
+}
+
+/// Roll a plugin back to the known good version its server offers (`UpdateResponse::rollback_version`),
+/// such as from a "something broke" menu entry or a boot loop detector. The rollback is
+/// installed without asking `Installer::should_update`. The version rolled back from is
+/// recorded in the plugin's `InstalledManifest` and isn't installed again until a newer
+/// version is offered.
+pub fn rollback<I>(ip: IpAddr, name: &str, installer: &I) -> UpdateReport
+    where I: Installer,
+{
+    UpdateClient::new(ip).rollback(name, installer)
+}
+
+fn rollback_on<I>(client: &UpdateClient, name: &str, installer: &I) -> UpdateReport
+    where I: Installer,
+{
+    let installed_dir = changes::installed_dir();
+    let installed_version = changes::load_manifest(&installed_dir, name).and_then(|manifest| manifest.version);
+    let current_version = installed_version.as_deref().unwrap_or("0.0.0");
+
+    // the server names the version to roll back to in any answer about the newest version
+    let mut report = request_update(client, name, current_version, BetaPreference::No, installer);
+    let latest = match &report.response {
+        Some(response) => response,
+        None => return report,
+    };
+    let target = match &latest.rollback_version {
+        Some(target) => target.clone(),
+        None => {
+            // keep the server's own error if it doesn't host the plugin or rejected the request
+            if matches!(latest.code, ResponseCode::NoUpdate | ResponseCode::Update | ResponseCode::NotifyOnly) {
+                warn!("[{} updater] The update server has no version to roll back to", name);
+                report.error = Some(UpdateError::RollbackUnavailable);
+            }
+            return report
+        }
+    };
+    let rolled_back_from = installed_version.clone()
+        .or_else(|| Some(latest.new_plugin_version.clone()).filter(|_| latest.code == ResponseCode::Update));
+
+    info!("[{} updater] Rolling back to {}", name, target);
+    let request = update_request(client, name, current_version, false, running_skyline_version(client, installer), Some(&target));
+    let mut report = send_update_request(client, name, &request);
+    let response = match report.response.take() {
+        Some(response) => response,
+        None => return report,
+    };
+
+    if response.code == ResponseCode::Update && response.new_plugin_version != target {
+        // servers from before pinned versions offer the newest version instead
+        warn!("[{} updater] The update server offered {} instead of {}, not rolling back", name, response.new_plugin_version, target);
+        report.error = Some(UpdateError::RollbackUnavailable);
+    } else {
+        handle_response(client, name, &response, installer, &mut report, false, true);
+    }
+
+    if report.installed {
+        let mut manifest = changes::load_manifest(&installed_dir, &response.plugin_name).unwrap_or_default();
+        manifest.rolled_back_from = rolled_back_from;
+        changes::save_manifest(&installed_dir, &response.plugin_name, &manifest);
+    }
+    report.response = Some(response);
+
+    report
+}
+
+/// Install a specific version of a plugin, whether it is newer or older than the current one,
+/// such as from a "downgrade" button reinstalling a known good build after a bad release.
+/// Versions which only differ in build metadata are the same version. A version the user
+/// skipped or rolled back from is still installed, as it was asked for. A `target` the server
+/// doesn't host is reported as `UpdateError::VersionNotFound`.
+///
+/// ## Args
+/// * ip - IP address of server
+/// * name - name of plugin to update
+/// * current - current version of plugin
+/// * target - version to install
+pub fn check_update_to_version(ip: IpAddr, name: &str, current: &str, target: &str) -> bool {
+    UpdateClient::new(ip).check_update_to_version(name, current, target)
+}
+
+/// Whether two versions are the same, ignoring build metadata
+fn same_version(a: &str, b: &str) -> bool {
+    a.split('+').next() == b.split('+').next()
+}
+
+fn check_update_to_version_on<I>(client: &UpdateClient, name: &str, current: &str, target: &str, installer: &I) -> UpdateReport
+    where I: Installer,
+{
+    // betas too, the version was asked for by name
+    let request = update_request(client, name, current, true, running_skyline_version(client, installer), Some(target));
+    let mut report = send_update_request(client, name, &request);
+    let response = match report.response.take() {
+        Some(response) => response,
+        None => return report,
+    };
+
+    if response.code == ResponseCode::Update && !same_version(&response.new_plugin_version, target) {
+        // servers from before pinned versions offer the newest version instead
+        warn!("[{} updater] The update server offered {} instead of {}, not installing it", name, response.new_plugin_version, target);
+        report.error = Some(UpdateError::VersionNotFound(Some(format!("version {} isn't hosted", target))));
+    } else if response.code == ResponseCode::Update {
+        // asked for by name, so versions skipped or rolled back from are installed too
+        install_offered_update(client, name, &response, installer, &mut report, true, false);
+    } else {
+        handle_response(client, name, &response, installer, &mut report, true, false);
+    }
+    report.response = Some(response);
+
+    report
+}
+
+/// Compare the installed files of `version` of a plugin against the server's copy of that
+/// version, hashing each one where the default installer puts it. Nothing is downloaded but the
+/// list of files, see `UpdateClient::custom_verify_install` to reinstall the damaged ones.
+/// None if the server doesn't offer `version`.
+pub fn verify_install(ip: IpAddr, name: &str, version: &str) -> Option<VerifyReport> {
+    UpdateClient::new(ip).verify_install(name, version)
+}
+
+/// Install an update with a custom installer implementation
+pub fn custom_check_update<I>(ip: IpAddr, name: &str, version: &str, beta: impl Into<BetaPreference>, installer: &I) -> bool
+    where I: Installer,
+{
+    custom_check_update_report(ip, name, version, beta, installer).installed
+}
+
+/// `check_update`, sending flags of the plugin's own for the server to choose files by, see
+/// `UpdateClient::with_options`
+pub fn check_update_with_options(ip: IpAddr, name: &str, version: &str, beta: impl Into<BetaPreference>, options: HashMap<String, String>) -> bool {
+    UpdateClient::new(ip).with_options(options).check_update(name, version, beta)
+}
+
+/// `custom_check_update` with flags of the plugin's own, see `check_update_with_options`
+pub fn custom_check_update_with_options<I>(ip: IpAddr, name: &str, version: &str, beta: impl Into<BetaPreference>, installer: &I, options: HashMap<String, String>) -> bool
+    where I: Installer,
+{
+    UpdateClient::new(ip).with_options(options).custom_check_update(name, version, beta, installer)
+}
+
+/// `check_update`, telling the server which version of skyline is running so updates which
+/// need a newer one aren't installed, see `UpdateClient::with_skyline_version`
+pub fn check_update_with_skyline_version(ip: IpAddr, name: &str, version: &str, beta: impl Into<BetaPreference>, skyline_version: &str) -> bool {
+    UpdateClient::new(ip).with_skyline_version(skyline_version).check_update(name, version, beta)
+}
+
+/// `get_update_info` with flags of the plugin's own, see `check_update_with_options`
+pub fn get_update_info_with_options(ip: IpAddr, name: &str, version: &str, beta: impl Into<BetaPreference>, options: HashMap<String, String>) -> Option<UpdateResponse> {
+    UpdateClient::new(ip).with_options(options).get_update_info(name, version, beta)
+}
+
+/// `check_update` for the newest version on a named release channel such as `"nightly"`, see
+/// `UpdateClient::with_channel`
+pub fn check_update_channel(ip: IpAddr, name: &str, version: &str, channel: &str) -> bool {
+    UpdateClient::new(ip).with_channel(channel).check_update(name, version, false)
+}
+
+/// `custom_check_update` on a named release channel, see `check_update_channel`
+pub fn custom_check_update_channel<I>(ip: IpAddr, name: &str, version: &str, channel: &str, installer: &I) -> bool
+    where I: Installer,
+{
+    UpdateClient::new(ip).with_channel(channel).custom_check_update(name, version, false, installer)
+}
+
+/// Check a list of `(name, current version)` plugins for updates, then ask about all updates
+/// found at once with `Installer::should_update_batch` and install them. Every plugin is
+/// installed on its own, one failing doesn't affect the others.
+pub fn check_updates_batch<I>(ip: IpAddr, plugins: &[(&str, &str)], beta: impl Into<BetaPreference>, installer: &I) -> BatchReport
+    where I: Installer,
+{
+    UpdateClient::new(ip).check_updates_batch(plugins, beta, installer)
+}
+
+/// Install an update with a custom installer implementation, telling apart the ways it can
+/// fail and why nothing was installed
+pub fn try_custom_check_update<I>(ip: IpAddr, name: &str, version: &str, beta: impl Into<BetaPreference>, installer: &I) -> Result<UpdateStatus, UpdateError>
+    where I: Installer,
+{
+    custom_check_update_report(ip, name, version, beta, installer).status()
+}
+
+/// Install an update using the default installer, see `check_update` and `try_custom_check_update`
+pub fn try_check_update(ip: IpAddr, name: &str, version: &str, beta: impl Into<BetaPreference>) -> Result<UpdateStatus, UpdateError> {
+    try_custom_check_update(ip, name, version, beta, &DefaultInstaller)
+}
+
+/// Install an update using the default installer
+///
+/// ## Args
+/// * ip - IP address of server
+/// * name - name of plugin to update
+/// * version - current version of plugin
+/// * beta - whether beta versions are offered, `true`, `false` or `BetaPreference::FromConfig`
+pub fn check_update(ip: IpAddr, name: &str, version: &str, beta: impl Into<BetaPreference>) -> bool {
+    custom_check_update(ip, name, version, beta, &DefaultInstaller)
+}
+
+/// `check_update` with the name and version in the calling crate's `Cargo.toml`, so the version
+/// sent is never left behind after a release. Pass whether betas are offered (stable only
+/// unless given) and an installer to use in place of the default one, as `custom_check_update`
+/// takes.
+///
+/// ```no_run
+/// # let installer = skyline_update::DefaultInstaller;
+/// let ip = "127.0.0.1".parse().unwrap();
+/// skyline_update::check_update!(ip);
+/// skyline_update::check_update!(ip, skyline_update::BetaPreference::FromConfig);
+/// skyline_update::check_update!(ip, false, &installer);
+/// ```
+///
+/// Plugins hosted under a name other than their package's call `check_update_auto`, which the
+/// macro expands to, themselves.
+#[macro_export]
+macro_rules! check_update {
+    ($ip:expr) => {
+        $crate::check_update!($ip, false)
+    };
+    ($ip:expr, $beta:expr) => {
+        $crate::check_update_auto($ip, env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION"), $beta, &$crate::DefaultInstaller)
+    };
+    ($ip:expr, $beta:expr, $installer:expr) => {
+        $crate::check_update_auto($ip, env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION"), $beta, $installer)
+    };
+}
+
+/// What `check_update!` expands to, for plugins hosted under a name other than their package's.
+/// A function can't read the calling crate's `Cargo.toml`, so pass the version as
+/// `env!("CARGO_PKG_VERSION")` to keep it in step with releases.
+///
+/// ```no_run
+/// # let ip = "127.0.0.1".parse().unwrap();
+/// let installer = skyline_update::DefaultInstaller;
+/// skyline_update::check_update_auto(ip, "hosted_name", env!("CARGO_PKG_VERSION"), false, &installer);
+/// ```
+pub fn check_update_auto<I>(ip: IpAddr, hosted_name: &str, package_version: &str, beta: impl Into<BetaPreference>, installer: &I) -> bool
+    where I: Installer,
+{
+    custom_check_update(ip, hosted_name, package_version, beta, installer)
+}
+
+/// `check_update` offering beta versions only if the user opted in from
+/// `sd:/skyline-update/preferences.toml`, see `BetaPreference::FromConfig`
+pub fn check_update_configured(ip: IpAddr, name: &str, version: &str) -> bool {
+    check_update(ip, name, version, BetaPreference::FromConfig)
+}
+
+/// `check_update`, asking whether to install the update with the text of `config` rather than
+/// the default English one
+pub fn check_update_with_dialog(ip: IpAddr, name: &str, version: &str, beta: impl Into<BetaPreference>, config: &DialogConfig) -> bool {
+    UpdateClient::new(ip).check_update_with_dialog(name, version, beta, config)
+}
+
+/// `check_update` against the first update server on the local network which hosts the plugin,
+/// see `discover_servers`. False if none answered within `DISCOVERY_TIMEOUT`.
+pub fn check_update_discovered(name: &str, version: &str, beta: impl Into<BetaPreference>) -> bool {
+    discovery::find_server(name, DISCOVERY_TIMEOUT)
+        .map_or(false, |server| server.client().check_update(name, version, beta))
+}
+
+pub fn get_update_info(ip: IpAddr, name: &str, version: &str, beta: impl Into<BetaPreference>) -> Option<UpdateResponse> {
+    UpdateClient::new(ip).get_update_info(name, version, beta)
+}
+
+/// List the files an update would install and where, including every file archives would be
+/// extracted to, without installing anything. The files are downloaded into memory, so nothing
+/// is left behind on the SD card. None if there is no update, or it couldn't be downloaded.
+pub fn plan_update(ip: IpAddr, name: &str, version: &str, allow_beta: bool) -> Option<Vec<PlannedFile>> {
+    UpdateClient::new(ip).plan_update(name, version, allow_beta)
+}
+
+/// `try_check_update` on a thread of its own, so plugin init and the game's boot aren't held up
+/// while the update downloads. The outcome is sent over the returned channel once the check is
+/// done, poll it with `try_recv` or show it once in game.
+///
+/// Every `Installer` callback is made from that thread. On the console the default installer
+/// asks whether to update with a skyline_web dialog, which must be safe to open from that
+/// thread at the time. Plugins checking once the game is running should use
+/// `custom_check_update_background` with an installer which doesn't prompt, and show the
+/// outcome themselves.
+pub fn check_update_background(ip: IpAddr, name: &str, version: &str, beta: impl Into<BetaPreference>) -> std::sync::mpsc::Receiver<Result<UpdateStatus, UpdateError>> {
+    UpdateClient::new(ip).check_update_background(name, version, beta)
+}
+
+/// `custom_check_update_report` on a thread of its own, which `installer` is moved to, see
+/// `check_update_background`
+pub fn custom_check_update_background<I>(ip: IpAddr, name: &str, version: &str, beta: impl Into<BetaPreference>, installer: I) -> std::sync::mpsc::Receiver<UpdateReport>
+    where I: Installer + Send + 'static,
+{
+    UpdateClient::new(ip).custom_check_update_background(name, version, beta, installer)
+}
+
+pub fn install_update(ip: IpAddr, info: &UpdateResponse) -> bool {
+    UpdateClient::new(ip).install_update(info)
+}
+
+/// Install an update previously returned by `get_update_info` with a custom installer, such as
+/// after asking the user in a UI of its own. `Installer::should_update` isn't called.
+pub fn custom_install_update<I>(ip: IpAddr, info: &UpdateResponse, installer: &I) -> bool
+    where I: Installer,
+{
+    UpdateClient::new(ip).custom_install_update(info, installer)
+}
+
+/// `custom_install_update`, telling apart the ways it can fail, see `try_custom_check_update`
+pub fn try_custom_install_update<I>(ip: IpAddr, info: &UpdateResponse, installer: &I) -> Result<UpdateStatus, UpdateError>
+    where I: Installer,
+{
+    UpdateClient::new(ip).try_custom_install_update(info, installer)
+}
+
+/// `check_update` for a server at a hostname, such as `updates.example.com`. Every address the
+/// hostname resolves to is tried in order. A hostname which can't be resolved is logged, and
+/// reported as `UpdateError::Resolve` by `UpdateClient::try_check_update`.
+pub fn check_update_host(host: &str, name: &str, version: &str, beta: impl Into<BetaPreference>) -> bool {
+    UpdateClient::with_host(host).check_update(name, version, beta)
+}
+
+/// `custom_check_update` for a server at a hostname, see `check_update_host`
+pub fn custom_check_update_host<I>(host: &str, name: &str, version: &str, beta: impl Into<BetaPreference>, installer: &I) -> bool
+    where I: Installer,
+{
+    UpdateClient::with_host(host).custom_check_update(name, version, beta, installer)
+}
+
+/// `get_update_info` for a server at a hostname, see `check_update_host`
+pub fn get_update_info_host(host: &str, name: &str, version: &str, beta: impl Into<BetaPreference>) -> Option<UpdateResponse> {
+    UpdateClient::with_host(host).get_update_info(name, version, beta)
+}
+
+/// `install_update` for a server at a hostname, see `check_update_host`
+pub fn install_update_host(host: &str, info: &UpdateResponse) -> bool {
+    UpdateClient::with_host(host).install_update(info)
+}
+
+/// `custom_install_update` for a server at a hostname, see `check_update_host`
+pub fn custom_install_update_host<I>(host: &str, info: &UpdateResponse, installer: &I) -> bool
+    where I: Installer,
+{
+    UpdateClient::with_host(host).custom_install_update(info, installer)
+}
+
+/// `check_update_host` over TLS, for a server started with `--tls-cert` and `--tls-key`. The
+/// server's certificate has to be valid for `TlsConfig::server_name` and trusted as `tls` says,
+/// otherwise nothing is sent and `UpdateClient::try_check_update` reports `UpdateError::Tls`.
+#[cfg(feature = "tls")]
+pub fn check_update_tls(host: &str, tls: &TlsConfig, name: &str, version: &str, beta: impl Into<BetaPreference>) -> bool {
+    UpdateClient::with_host(host).with_tls(tls.clone()).check_update(name, version, beta)
+}
+
+/// `custom_check_update` over TLS, see `check_update_tls`
+#[cfg(feature = "tls")]
+pub fn custom_check_update_tls<I>(host: &str, tls: &TlsConfig, name: &str, version: &str, beta: impl Into<BetaPreference>, installer: &I) -> bool
+    where I: Installer,
+{
+    UpdateClient::with_host(host).with_tls(tls.clone()).custom_check_update(name, version, beta, installer)
+}
+
+/// `try_check_update` over TLS, see `check_update_tls`
+#[cfg(feature = "tls")]
+pub fn try_check_update_tls(host: &str, tls: &TlsConfig, name: &str, version: &str, beta: impl Into<BetaPreference>) -> Result<UpdateStatus, UpdateError> {
+    UpdateClient::with_host(host).with_tls(tls.clone()).try_check_update(name, version, beta)
+}
+
+/// `check_update`, only installing files signed with the private key of `public_key`, the
+/// `signing.pub` written by `update-server keygen`, such as `include_bytes!("signing.pub")`.
+/// Updates with a file which isn't signed or whose signature doesn't match are refused before
+/// anything is downloaded, see `UpdateClient::with_public_key`.
+pub fn check_update_signed(ip: IpAddr, public_key: &[u8; 32], name: &str, version: &str, beta: impl Into<BetaPreference>) -> bool {
+    UpdateClient::new(ip).with_public_key(public_key).check_update(name, version, beta)
+}
+
+/// `custom_check_update` checking signatures, see `check_update_signed`
+pub fn custom_check_update_signed<I>(ip: IpAddr, public_key: &[u8; 32], name: &str, version: &str, beta: impl Into<BetaPreference>, installer: &I) -> bool
+    where I: Installer,
+{
+    UpdateClient::new(ip).with_public_key(public_key).custom_check_update(name, version, beta, installer)
+}
+
+/// Which metadata images `get_metadata_with_images` should download
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ImageSelection {
+    None,
+    /// Only the first image, which is treated as the plugin's icon
+    IconOnly,
+    /// Every image no larger than the given number of bytes
+    MaxBytes(usize),
+    All,
+}
+
+impl ImageSelection {
+    fn select<'a>(&self, images: &'a [ImageEntry]) -> Vec<&'a ImageEntry> {
+        match *self {
+            ImageSelection::None => vec![],
+            ImageSelection::IconOnly => images.iter().take(1).collect(),
+            ImageSelection::MaxBytes(max) => images.iter().filter(|image| image.size <= max).collect(),
+            ImageSelection::All => images.iter().collect(),
+        }
+    }
+}
+
+fn request_metadata(client: &UpdateClient, name: &str, beta: Option<bool>) -> Option<PluginMetadata> {
+    let mut stream = match client.connect_request() {
+        Ok(stream) => stream,
+        Err(e) => {
+            error!("[{} updater] Failed to connect to update server: {}", name, e);
+            return None
+        }
+    };
+    let packet = serde_json::to_string(&Request::Metadata {
+        plugin_name: name.to_owned(),
+        beta,
+    }).ok()?;
+
+    let _ = stream.write_fmt(format_args!("{}\n", packet));
+    let mut string = String::new();
+    if let Err(e) = read_response(&mut stream, &mut string) {
+        error!("[{} updater] Failed to read update server response: {}", name, e);
+        return None
+    }
+
+    match serde_json::from_str(&string) {
+        Ok(metadata) => Some(metadata),
+        Err(e) => {
+            // servers which have no metadata to send say why with an UpdateResponse
+            match serde_json::from_str::<UpdateResponse>(&string) {
+                Ok(response) => warn!("[{} updater] Update server has no metadata for the plugin{}", name, reason_suffix(&response)),
+                Err(_) => warn!("[{} updater] Failed to parse plugin metadata: {}", name, e),
+            }
+            None
+        }
+    }
+}
+
+/// Largest changelog or image downloaded without the server announcing its size
+const MAX_METADATA_FILE_SIZE: usize = 16 * 1024 * 1024;
+
+/// Download a file whose size the server doesn't announce, such as a changelog, until the
+/// server closes the connection. None if the download failed or is larger than `max_size`.
+fn download_unsized(client: &UpdateClient, port: u16, index: u64, max_size: usize) -> Option<Vec<u8>> {
+    let stream = open_download(client, port, index).ok()?;
+
+    let mut buf = vec![];
+    match stream.take(max_size as u64 + 1).read_to_end(&mut buf) {
+        Ok(len) if len > max_size => {
+            warn!("[updater] File {} is larger than the {} bytes allowed", index, max_size);
+            None
+        }
+        Ok(_) => Some(buf),
+        Err(e) if is_timeout(&e) => {
+            warn!("[updater] Download stalled for longer than {:?}, giving up", client.timeouts().download);
+            None
+        }
+        Err(e) => {
+            warn!("[updater] Error downloading file: {}", e);
+            None
+        }
+    }
+}
+
+/// Get the metadata of a plugin, such as its description, to show before it is installed.
+/// None if the server couldn't be reached or doesn't host the plugin.
+pub fn get_metadata(ip: IpAddr, name: &str, allow_beta: bool) -> Option<PluginMetadata> {
+    UpdateClient::new(ip).get_metadata(name, allow_beta)
+}
+
+/// Download the changelog listed in a plugin's metadata, None if it has none
+pub fn get_changelog(ip: IpAddr, metadata: &PluginMetadata) -> Option<String> {
+    UpdateClient::new(ip).get_changelog(metadata)
+}
+
+/// Download the image at `index` among those listed in a plugin's metadata, the first being
+/// its icon. None if there is no such image.
+pub fn get_image(ip: IpAddr, metadata: &PluginMetadata, index: usize) -> Option<Vec<u8>> {
+    UpdateClient::new(ip).get_image(metadata, index)
+}
+
+/// Get the metadata of a plugin along with the selected subset of its images. Images are
+/// returned in the order the server lists them, only the selected ones are downloaded.
+pub fn get_metadata_with_images(ip: IpAddr, name: &str, selection: ImageSelection) -> Option<(PluginMetadata, Vec<Vec<u8>>)> {
+    UpdateClient::new(ip).get_metadata_with_images(name, selection)
+}
+
+#[cfg(test)]
+pub(crate) mod test {
+    use super::*;
+    use transport::test::MockTransport;
+
+    /// A client answered by `transport` instead of a server, downloading one file at a time so
+    /// downloads take the replies in order
+    pub(crate) fn mock_client(transport: &MockTransport) -> UpdateClient {
+        UpdateClient::new("127.0.0.1".parse().unwrap())
+            .with_transport(transport.clone())
+            .with_max_concurrent_downloads(1)
+    }
+
+    /// The requests sent to the main port, in order
+    pub(crate) fn sent_requests(transport: &MockTransport) -> Vec<Request> {
+        transport.sent().into_iter()
+            .filter(|(port, _)| *port == PORT)
+            .map(|(_, sent)| serde_json::from_slice(&sent).unwrap())
+            .collect()
+    }
+
+    /// The version pinned by every update request sent
+    pub(crate) fn pinned_versions(transport: &MockTransport) -> Vec<Option<String>> {
+        sent_requests(transport).into_iter()
+            .map(|request| match request {
+                Request::Update { options, .. } => options.and_then(|options| options.pinned_version),
+                other => panic!("unexpected request {:?}", other),
+            })
+            .collect()
+    }
+
+    /// An update of `name` to 1.1.0 with a file downloaded for each of `files`
+    pub(crate) fn download_response(name: &str, files: &[(&str, &str)]) -> UpdateResponse {
+        let mut response = UpdateResponse {
+            plugin_name: name.into(),
+            new_plugin_version: "1.1.0".into(),
+            // sent along, so no metadata is asked for
+            changelog: Some(String::new()),
+            ..inline_response(files)
+        };
+        for (file, (_, data)) in response.required_files.iter_mut().zip(files) {
+            file.inline_data = None;
+            file.sha256 = Some(resume::sha256_of(data.as_bytes()).unwrap());
+        }
+
+        response
+    }
+
+    #[test]
+    fn test_install() {
+        let name = format!("mock_install_{}", std::process::id());
+        let response = download_response(&name, &[("sd:/a.txt", "hello"), ("sd:/b.txt", "world!")]);
+        let transport = MockTransport::default()
+            .respond(PORT, &response)
+            .reply(PORT + 1, "hello")
+            .reply(PORT + 1, "world!");
+
+        let installer = RecordingInstaller::default();
+        let status = mock_client(&transport).try_custom_check_update(&name, "1.0.0", false, &installer);
+        assert!(matches!(&status, Ok(UpdateStatus::Installed { installed_paths, .. }) if installed_paths.len() == 2), "{:?}", status);
+        assert_eq!(*installer.installed.borrow(), vec![
+            (PathBuf::from("sd:/a.txt"), b"hello".to_vec()),
+            (PathBuf::from("sd:/b.txt"), b"world!".to_vec()),
+        ]);
+        assert!(transport.is_done());
+
+        // the request names the plugin, and each download asks for its file
+        let sent = transport.sent();
+        match serde_json::from_slice(&sent[0].1).unwrap() {
+            Request::Update { plugin_name, plugin_version, .. } => assert_eq!((plugin_name.as_str(), plugin_version.as_str()), (name.as_str(), "1.0.0")),
+            other => panic!("unexpected request {:?}", other),
+        }
+        assert_eq!(sent[1], (PORT + 1, u64::to_be_bytes(0).to_vec()));
+        assert_eq!(sent[2], (PORT + 1, u64::to_be_bytes(1).to_vec()));
+
+        let _ = std::fs::remove_file(changes::record_path(&changes::installed_dir(), &name));
+    }
+
+    #[test]
+    fn test_mock_no_update() {
+        let transport = MockTransport::default().respond(PORT, &UpdateResponse { code: ResponseCode::NoUpdate, ..Default::default() });
+
+        let installer = RecordingInstaller::default();
+        assert_eq!(mock_client(&transport).try_custom_check_update("test_plugin", "1.0.0", false, &installer), Ok(UpdateStatus::UpToDate));
+        assert!(installer.installed.borrow().is_empty());
+        // nothing is downloaded
+        assert_eq!(transport.sent().len(), 1);
+    }
+
+    #[test]
+    fn test_mock_plugin_not_found() {
+        let transport = MockTransport::default()
+            .respond(PORT, &UpdateResponse { code: ResponseCode::PluginNotFound, ..Default::default() })
+            .respond(PORT, &UpdateResponse::plugin_not_found().with_message("did you mean 'missing-plugin'?"));
+        let client = mock_client(&transport);
+
+        let installer = RecordingInstaller::default();
+        assert_eq!(client.try_custom_check_update("missing_plugin", "1.0.0", false, &installer), Err(UpdateError::PluginNotFound(None)));
+        let error = client.try_custom_check_update("missing_plugin", "1.0.0", false, &installer).unwrap_err();
+        assert_eq!(error, UpdateError::PluginNotFound(Some(String::from("did you mean 'missing-plugin'?"))));
+        assert_eq!(error.to_string(), "the plugin could not be found on the update server: did you mean 'missing-plugin'?");
+        assert!(installer.installed.borrow().is_empty());
+    }
+
+    #[test]
+    fn test_mock_invalid_request() {
+        let transport = MockTransport::default()
+            .respond(PORT, &UpdateResponse::invalid_request().with_message("version '1.2' is not valid semver"));
+
+        let installer = RecordingInstaller::default();
+        let error = mock_client(&transport).try_custom_check_update("test_plugin", "1.2", false, &installer).unwrap_err();
+        assert_eq!(error, UpdateError::InvalidRequest(Some(String::from("version '1.2' is not valid semver"))));
+        assert_eq!(error.to_string(), "the update server rejected the request as invalid: version '1.2' is not valid semver");
+    }
+
+    #[test]
+    fn test_mock_malformed_response() {
+        let transport = MockTransport::default()
+            .reply(PORT, "{\"code\": ")
+            .reply(PORT, "not json");
+        let client = mock_client(&transport);
+
+        let installer = RecordingInstaller::default();
+        assert_eq!(client.try_custom_check_update("test_plugin", "1.0.0", false, &installer), Err(UpdateError::Parse(String::from("{\"code\": "))));
+        assert_eq!(client.get_update_info("test_plugin", "1.0.0", false), None);
+        assert!(transport.is_done());
+    }
+
+    #[test]
+    fn test_mock_rate_limited() {
+        let transport = MockTransport::default()
+            .respond(PORT, &UpdateResponse::rate_limited(30))
+            .respond(PORT, &UpdateResponse { code: ResponseCode::RateLimited, ..Default::default() });
+        let client = mock_client(&transport);
+
+        let installer = RecordingInstaller::default();
+        assert_eq!(client.try_custom_check_update("test_plugin", "1.0.0", false, &installer), Err(UpdateError::RateLimited { retry_after: Some(30) }));
+        // the client won't wait that long, or wasn't told how long to wait
+        let client = client.with_rate_limit_retry(Duration::from_secs(10));
+        assert_eq!(client.try_custom_check_update("test_plugin", "1.0.0", false, &installer), Err(UpdateError::RateLimited { retry_after: None }));
+        assert!(transport.is_done());
+        assert_eq!(transport.sent().len(), 2);
+    }
+
+    #[test]
+    fn test_mock_rate_limited_retry() {
+        let transport = MockTransport::default()
+            .respond(PORT, &UpdateResponse::rate_limited(0))
+            .respond(PORT, &UpdateResponse::no_update());
+        let client = mock_client(&transport).with_rate_limit_retry(Duration::from_secs(1));
+
+        let installer = RecordingInstaller::default();
+        assert_eq!(client.try_custom_check_update("test_plugin", "1.0.0", false, &installer), Ok(UpdateStatus::UpToDate));
+        assert!(transport.is_done());
+
+        // only retried once
+        let transport = MockTransport::default()
+            .respond(PORT, &UpdateResponse::rate_limited(0))
+            .respond(PORT, &UpdateResponse::rate_limited(0))
+            .respond(PORT, &UpdateResponse::no_update());
+        let client = mock_client(&transport).with_rate_limit_retry(Duration::from_secs(1));
+        assert_eq!(client.try_custom_check_update("test_plugin", "1.0.0", false, &installer), Err(UpdateError::RateLimited { retry_after: Some(0) }));
+        assert!(!transport.is_done());
+    }
+
+    #[test]
+    fn test_mock_server_error() {
+        let transport = MockTransport::default()
+            .respond(PORT, &UpdateResponse::server_error("plugin failed to load"))
+            .respond(PORT, &UpdateResponse { code: ResponseCode::ServerError, ..Default::default() });
+        let client = mock_client(&transport);
+
+        let installer = RecordingInstaller::default();
+        assert_eq!(client.try_custom_check_update("test_plugin", "1.0.0", false, &installer), Err(UpdateError::ServerError(String::from("plugin failed to load"))));
+        assert_eq!(client.try_custom_check_update("test_plugin", "1.0.0", false, &installer), Err(UpdateError::ServerError(String::from("no reason given"))));
+        assert!(installer.installed.borrow().is_empty());
+    }
+
+    #[test]
+    fn test_mock_unknown_response_code() {
+        let transport = MockTransport::default()
+            .reply(PORT, r#"{"code":"Maintenance","update_plugin":false,"update_skyline":false,"plugin_name":"","new_plugin_version":"","new_skyline_version":null,"required_files":[]}"#)
+            .reply(PORT, r#"{"code":7}"#);
+        let client = mock_client(&transport);
+
+        let installer = RecordingInstaller::default();
+        assert_eq!(client.try_custom_check_update("test_plugin", "1.0.0", false, &installer), Err(UpdateError::UnknownResponseCode(String::from("Maintenance"))));
+        assert_eq!(client.try_custom_check_update("test_plugin", "1.0.0", false, &installer), Err(UpdateError::UnknownResponseCode(String::from("7"))));
+        // a known code in a malformed response is still a parse error
+        assert_eq!(unknown_response_code(r#"{"code":"NoUpdate"}"#), None);
+    }
+
+    #[test]
+    fn test_mock_short_download() {
+        let name = format!("mock_short_{}", std::process::id());
+        let transport = MockTransport::default()
+            .respond(PORT, &download_response(&name, &[("sd:/a.txt", "hello")]))
+            .reply(PORT + 1, "hel");
+
+        let installer = RecordingInstaller::default();
+        assert_eq!(mock_client(&transport).try_custom_check_update(&name, "1.0.0", false, &installer), Err(UpdateError::Download {
+            index: 0,
+            reason: String::from("the server closed it after 3 of the expected 5 bytes"),
+        }));
+        assert!(installer.installed.borrow().is_empty());
+    }
+
+    #[test]
+    fn test_mock_file_gone() {
+        let name = format!("mock_gone_{}", std::process::id());
+        let response = download_response(&name, &[("sd:/a.txt", "hello")]);
+        // the server reloaded between answering and the download, then offers the file again
+        let transport = MockTransport::default()
+            .respond(PORT, &response)
+            .reply(PORT + 1, "")
+            .respond(PORT, &response)
+            .reply(PORT + 1, "hello");
+
+        let installer = RecordingInstaller::default();
+        let status = mock_client(&transport).try_custom_check_update(&name, "1.0.0", false, &installer);
+        assert!(status.as_ref().map_or(false, UpdateStatus::is_installed), "{:?}", status);
+        assert_eq!(*installer.installed.borrow(), vec![(PathBuf::from("sd:/a.txt"), b"hello".to_vec())]);
+        assert!(transport.is_done());
+
+        // and is only asked for once more
+        let transport = MockTransport::default()
+            .respond(PORT, &response)
+            .reply(PORT + 1, "")
+            .respond(PORT, &response)
+            .reply(PORT + 1, "");
+        let status = mock_client(&transport).try_custom_check_update(&name, "1.0.0", false, &RecordingInstaller::default());
+        assert_eq!(status, Err(UpdateError::FileGone { index: 0 }));
+        assert!(transport.is_done());
+    }
+
+    #[test]
+    fn test_mock_update_to_version() {
+        let name = format!("mock_target_{}", std::process::id());
+        let response = UpdateResponse {
+            new_plugin_version: "1.0.0+build.5".into(),
+            ..download_response(&name, &[("sd:/a.txt", "hello")])
+        };
+        let transport = MockTransport::default()
+            .respond(PORT, &response)
+            .reply(PORT + 1, "hello");
+
+        // an older version, the same one whatever its build metadata
+        let installer = RecordingInstaller::default();
+        let report = mock_client(&transport).custom_check_update_to_version_report(&name, "1.1.0", "1.0.0", &installer);
+        assert!(report.installed, "{:?}", report.error);
+        assert_eq!(*installer.installed.borrow(), vec![(PathBuf::from("sd:/a.txt"), b"hello".to_vec())]);
+        match serde_json::from_slice(&transport.sent()[0].1).unwrap() {
+            Request::Update { beta, options, .. } => {
+                assert_eq!(beta, Some(true));
+                assert_eq!(options.unwrap().pinned_version.as_deref(), Some("1.0.0"));
+            }
+            other => panic!("unexpected request {:?}", other),
+        }
+
+        // servers from before pinned versions offer the newest version instead
+        let transport = MockTransport::default().respond(PORT, &download_response(&name, &[("sd:/a.txt", "hello")]));
+        let installer = RecordingInstaller::default();
+        let report = mock_client(&transport).custom_check_update_to_version_report(&name, "1.1.0", "1.0.0", &installer);
+        assert_eq!(report.status(), Err(UpdateError::VersionNotFound(Some(String::from("version 1.0.0 isn't hosted")))));
+        assert!(installer.installed.borrow().is_empty());
+
+        // newer servers say so themselves
+        let response = UpdateResponse::version_not_found().with_message("version 1.0.0 of 'test_plugin' isn't hosted on the stable channel");
+        let transport = MockTransport::default().respond(PORT, &response);
+        let report = mock_client(&transport).custom_check_update_to_version_report(&name, "1.1.0", "1.0.0", &installer);
+        assert_eq!(report.status(), Err(UpdateError::VersionNotFound(response.message.clone())));
+        assert!(installer.installed.borrow().is_empty());
+    }
+
+    #[test]
+    fn test_mock_changelog_channel() {
+        let name = format!("mock_changelog_channel_{}", std::process::id());
+        // a beta build versioned like a stable one, so only the caller knows it is a beta
+        let response = UpdateResponse { changelog: None, ..download_response(&name, &[("sd:/a.txt", "hello")]) };
+
+        for &beta in [true, false].iter() {
+            let transport = MockTransport::default()
+                .respond(PORT, &response)
+                .reply(PORT, "{}")
+                .reply(PORT + 1, "hello");
+            let report = mock_client(&transport).custom_check_update_report(&name, "1.0.0", beta, &RecordingInstaller::default());
+            assert!(report.installed, "{:?}", report.error);
+            let metadata_request = transport.sent().into_iter().filter(|(port, _)| *port == PORT).nth(1).unwrap();
+            match serde_json::from_slice(&metadata_request.1).unwrap() {
+                Request::Metadata { beta: asked, .. } => assert_eq!(asked, Some(beta)),
+                other => panic!("unexpected request {:?}", other),
+            }
+        }
+
+        let _ = std::fs::remove_file(changes::record_path(&changes::installed_dir(), &name));
+    }
+
+    #[test]
+    fn test_mock_update_to_skipped_version() {
+        let name = format!("mock_target_skipped_{}", std::process::id());
+        let installed_dir = changes::installed_dir();
+        let response = download_response(&name, &[("sd:/a.txt", "hello")]);
+        skip::skip_version(&skip::skipped_dir(), &response);
+        changes::save_manifest(&installed_dir, &name, &InstalledManifest { rolled_back_from: Some("1.1.0".into()), ..Default::default() });
+        let transport = MockTransport::default()
+            .respond(PORT, &response)
+            .respond(PORT, &response)
+            .reply(PORT + 1, "hello");
+        let client = mock_client(&transport);
+
+        // not offered by checking for updates
+        let installer = RecordingInstaller::default();
+        let report = client.custom_check_update_report(&name, "1.0.0", false, &installer);
+        assert!(!report.installed);
+        assert!(installer.installed.borrow().is_empty());
+
+        // but installed when asked for by name
+        let report = client.custom_check_update_to_version_report(&name, "1.0.0", "1.1.0", &installer);
+        assert!(report.installed, "{:?}", report.error);
+        assert!(!report.skipped);
+        assert_eq!(*installer.installed.borrow(), vec![(PathBuf::from("sd:/a.txt"), b"hello".to_vec())]);
+        assert!(transport.is_done());
+
+        let _ = std::fs::remove_file(changes::record_path(&skip::skipped_dir(), &name));
+        let _ = std::fs::remove_file(changes::record_path(&installed_dir, &name));
+    }
+
+    #[test]
+    fn test_mock_empty_update() {
+        let name = format!("mock_empty_{}", std::process::id());
+        let response = download_response(&name, &[]);
+        let transport = MockTransport::default().respond(PORT, &response).respond(PORT, &response);
+        let client = mock_client(&transport);
+
+        // nobody is asked about it, and it isn't reported as installed
+        let installer = RecordingInstaller::default();
+        let report = client.custom_check_update_report(&name, "1.0.0", false, &installer);
+        assert_eq!(report.error, Some(UpdateError::EmptyUpdate));
+        assert!(!report.installed);
+        assert_eq!(report.status(), Err(UpdateError::EmptyUpdate));
+        assert!(installer.lifecycle.borrow().is_empty());
+
+        let info = client.get_update_info(&name, "1.0.0", false).unwrap();
+        assert!(info.is_empty_update());
+        assert!(!client.custom_install_update(&info, &installer));
+        assert!(transport.is_done());
+        assert!(!download_response(&name, &[("sd:/a.txt", "a")]).is_empty_update());
+    }
+
+    #[test]
+    fn test_check_update_macro() {
+        // the macro can only reach the default port, so listen on it at an address of its own
+        let ip: IpAddr = "127.45.0.1".parse().unwrap();
+        let listener = match std::net::TcpListener::bind((ip, PORT)) {
+            Ok(listener) => listener,
+            // not every platform routes the whole loopback range
+            Err(_) => return,
+        };
+        let server = std::thread::spawn(move || {
+            (0..3).map(|_| {
+                let (mut socket, _) = listener.accept().unwrap();
+                let mut line = String::new();
+                std::io::BufReader::new(&socket).read_line(&mut line).unwrap();
+                socket.write_all(serde_json::to_string(&UpdateResponse::no_update()).unwrap().as_bytes()).unwrap();
+                match serde_json::from_str(&line).unwrap() {
+                    Request::Update { plugin_name, plugin_version, beta, .. } => (plugin_name, plugin_version, beta),
+                    other => panic!("unexpected request {:?}", other),
+                }
+            }).collect::<Vec<_>>()
+        });
+
+        assert!(!crate::check_update!(ip));
+        assert!(!crate::check_update!(ip, true, &RecordingInstaller::default()));
+        assert!(!check_update_auto(ip, "hosted_name", env!("CARGO_PKG_VERSION"), false, &RecordingInstaller::default()));
+
+        let name = String::from(env!("CARGO_PKG_NAME"));
+        let version = String::from(env!("CARGO_PKG_VERSION"));
+        assert_eq!(server.join().unwrap(), vec![
+            (name.clone(), version.clone(), Some(false)),
+            (name, version.clone(), Some(true)),
+            (String::from("hosted_name"), version, Some(false)),
+        ]);
+    }
+
+    #[test]
+    fn test_mock_get_update_info() {
+        let response = download_response("test_plugin", &[("sd:/a.txt", "hello")]);
+        let transport = MockTransport::default().respond(PORT, &response);
+
+        assert_eq!(mock_client(&transport).get_update_info("test_plugin", "1.0.0", false), Some(response));
+        // the async functions can't use it
+        assert!(mock_client(&transport).uses_custom_transport());
+        assert!(!UpdateClient::new("127.0.0.1".parse().unwrap()).uses_custom_transport());
+    }
+
+    pub(crate) fn test_file(size: usize, inline_data: Option<&str>) -> UpdateFile {
+        UpdateFile {
+            install_location: InstallLocation::AbsolutePath("sd:/test.txt".into()),
+            download_index: 0,
+            size,
+            inline_data: inline_data.map(String::from),
+            sha256: None,
+            signature: None,
+        }
+    }
+
+    #[test]
+    fn test_inline_file() {
+        assert_eq!(inline_file(&test_file(5, Some("aGVsbG8="))), Some(b"hello".to_vec()));
+
+        // size mismatch or garbage falls back to downloading
+        assert_eq!(inline_file(&test_file(6, Some("aGVsbG8="))), None);
+        assert_eq!(inline_file(&test_file(5, Some("not base64!"))), None);
+        assert_eq!(inline_file(&test_file(5, None)), None);
+    }
+
+    /// Records installed files instead of writing them, failing on the given path
+    #[derive(Default)]
+    pub(crate) struct RecordingInstaller {
+        pub(crate) installed: std::cell::RefCell<Vec<(PathBuf, Vec<u8>)>>,
+        pub(crate) progress: std::cell::RefCell<Vec<(u64, usize, usize)>>,
+        pub(crate) notified: std::cell::RefCell<Vec<(String, Option<String>)>>,
+        /// `None` when an update started, its success once it completed
+        pub(crate) lifecycle: std::cell::RefCell<Vec<Option<bool>>>,
+        pub(crate) fail_on: Option<PathBuf>,
+        pub(crate) exclude: Option<String>,
+        pub(crate) install_root: Option<String>,
+        pub(crate) stage_downloads: bool,
+    }
+
+    impl Installer for RecordingInstaller {
+        fn should_update(&self, _: &UpdateResponse) -> bool {
+            true
+        }
+
+        fn install_file(&self, path: PathBuf, buf: Vec<u8>) -> Result<(), InstallError> {
+            if self.fail_on.as_ref() == Some(&path) {
+                return Err(InstallError::other(path, "refused by the test"))
+            }
+            self.installed.borrow_mut().push((path, buf));
+            Ok(())
+        }
+
+        fn install_root(&self, _: &UpdateResponse) -> String {
+            self.install_root.clone().unwrap_or_else(|| String::from(DEFAULT_INSTALL_ROOT))
+        }
+
+        fn on_update_notification(&self, response: &UpdateResponse, changelog: Option<&str>) {
+            self.notified.borrow_mut().push((response.new_plugin_version.clone(), changelog.map(String::from)));
+        }
+
+        fn on_progress(&self, file: &UpdateFile, bytes_downloaded: usize, total_bytes: usize) {
+            self.progress.borrow_mut().push((file.download_index, bytes_downloaded, total_bytes));
+        }
+
+        fn stage_downloads(&self) -> bool {
+            self.stage_downloads
+        }
+
+        fn should_install_file(&self, file: &UpdateFile) -> bool {
+            self.exclude.is_none() || storage::install_path(file) != self.exclude
+        }
+
+        fn on_update_start(&self, _: &UpdateResponse) {
+            self.lifecycle.borrow_mut().push(None);
+        }
+
+        fn on_update_complete(&self, _: &UpdateResponse, success: bool) {
+            self.lifecycle.borrow_mut().push(Some(success));
+        }
+    }
+
+    pub(crate) fn inline_response(files: &[(&str, &str)]) -> UpdateResponse {
+        UpdateResponse {
+            code: ResponseCode::Update,
+            update_plugin: true,
+            plugin_name: "test_plugin".into(),
+            new_plugin_version: "1.0.0".into(),
+            required_files: files.iter()
+                .enumerate()
+                .map(|(i, (path, data))| UpdateFile {
+                    install_location: InstallLocation::AbsolutePath(path.to_string()),
+                    download_index: i as u64,
+                    size: data.len(),
+                    inline_data: Some(base64::encode(data)),
+                    sha256: None,
+                    signature: None,
+                })
+                .collect(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_install_without_extension() {
+        let response = inline_response(&[("noext", "a"), ("sd:/skyline/plugins/libplugin", "b"), ("sd:/plugin/config", "c")]);
+
+        let files = update_files(&UpdateClient::new("127.0.0.1".parse().unwrap()), &response, &DefaultInstaller, &mut Timings::default());
+
+        assert!(files.iter().all(|file| file.outcome == FileOutcome::Installed && file.archive.is_none()));
+    }
+
+    pub(crate) fn versioned_response(plugin_name: &str, version: &str, rollback_version: Option<&str>) -> UpdateResponse {
+        UpdateResponse {
+            plugin_name: plugin_name.into(),
+            new_plugin_version: version.into(),
+            rollback_version: rollback_version.map(String::from),
+            ..inline_response(&[("sd:/rollback/plugin.nro", version)])
+        }
+    }
+
+    #[test]
+    fn test_install_error_reason() {
+        struct ReadOnlyInstaller;
+
+        impl Installer for ReadOnlyInstaller {
+            fn should_update(&self, _: &UpdateResponse) -> bool {
+                true
+            }
+
+            fn install_file(&self, path: PathBuf, _: Vec<u8>) -> Result<(), InstallError> {
+                let e = std::io::Error::new(std::io::ErrorKind::PermissionDenied, "read-only");
+                Err(InstallError::new(path, e).with_context("opening it for writing"))
+            }
+        }
+
+        let name = format!("read_only_{}", std::process::id());
+        let transport = MockTransport::default().respond(PORT, &versioned_response(&name, "1.1.0", None));
+
+        assert_eq!(mock_client(&transport).try_custom_check_update(&name, "1.0.0", false, &ReadOnlyInstaller), Err(UpdateError::Install {
+            path: "sd:/rollback/plugin.nro".into(),
+            reason: "opening it for writing: read-only".into(),
+        }));
+
+        let e = InstallError::other("sd:/a.txt", "full");
+        assert_eq!(e.to_string(), "failed to install sd:/a.txt: full");
+    }
+
+    #[test]
+    fn test_rollback() {
+        let name = format!("rollback_{}", std::process::id());
+        let installed_dir = changes::installed_dir();
+        let transport = MockTransport::default()
+            .respond(PORT, &versioned_response(&name, "1.2.0", Some("1.1.0")))
+            .respond(PORT, &versioned_response(&name, "1.1.0", None));
+
+        let installer = RecordingInstaller::default();
+        let report = mock_client(&transport).rollback(&name, &installer);
+        assert_eq!(pinned_versions(&transport), vec![None, Some(String::from("1.1.0"))]);
+        assert!(report.status().unwrap().is_installed());
+        assert_eq!(*installer.installed.borrow(), vec![(PathBuf::from("sd:/rollback/plugin.nro"), b"1.1.0".to_vec())]);
+
+        let manifest = changes::load_manifest(&installed_dir, &name).unwrap();
+        assert_eq!(manifest.version.as_deref(), Some("1.1.0"));
+        assert_eq!(manifest.rolled_back_from.as_deref(), Some("1.2.0"));
+
+        // the broken version isn't installed again, a newer one is
+        let client = UpdateClient::new("127.0.0.1".parse().unwrap());
+        let mut report = UpdateReport::default();
+        handle_response(&client, &name, &versioned_response(&name, "1.2.0", None), &installer, &mut report, false, false);
+        assert!(!report.installed);
+        handle_response(&client, &name, &versioned_response(&name, "1.3.0", None), &installer, &mut report, false, false);
+        assert!(report.installed);
+        assert_eq!(changes::load_manifest(&installed_dir, &name).unwrap().rolled_back_from, None);
+
+        let _ = std::fs::remove_file(changes::record_path(&installed_dir, &name));
+    }
+
+    #[test]
+    fn test_rollback_unavailable() {
+        let name = format!("rollback_unavailable_{}", std::process::id());
+
+        // nothing to roll back to
+        let transport = MockTransport::default().respond(PORT, &versioned_response(&name, "1.2.0", None));
+        let installer = RecordingInstaller::default();
+        let report = mock_client(&transport).rollback(&name, &installer);
+        assert!(transport.is_done());
+        assert_eq!(report.status(), Err(UpdateError::RollbackUnavailable));
+
+        // a server from before pinned versions offers the newest version again
+        let transport = MockTransport::default()
+            .respond(PORT, &versioned_response(&name, "1.2.0", Some("1.1.0")))
+            .respond(PORT, &versioned_response(&name, "1.2.0", Some("1.1.0")));
+        let report = mock_client(&transport).rollback(&name, &installer);
+        assert!(transport.is_done());
+        assert_eq!(report.status(), Err(UpdateError::RollbackUnavailable));
+        assert!(installer.installed.borrow().is_empty());
+        assert_eq!(changes::load_manifest(&changes::installed_dir(), &name), None);
+    }
+
+    #[test]
+    fn test_file_outcomes() {
+        let response = inline_response(&[("sd:/a.txt", "a"), ("sd:/b.txt", "bb")]);
+        let installer = RecordingInstaller::default();
+
+        let files = update_files(&UpdateClient::new("127.0.0.1".parse().unwrap()), &response, &installer, &mut Timings::default());
+
+        assert!(files.iter().all(|file| file.outcome == FileOutcome::Installed));
+        assert_eq!(*installer.installed.borrow(), vec![
+            (PathBuf::from("sd:/a.txt"), b"a".to_vec()),
+            (PathBuf::from("sd:/b.txt"), b"bb".to_vec()),
+        ]);
+    }
+
+    #[test]
+    fn test_update_lifecycle() {
+        let client = UpdateClient::new("127.0.0.1".parse().unwrap());
+        let response = inline_response(&[("sd:/a.txt", "a"), ("sd:/b.txt", "b")]);
+
+        let installer = RecordingInstaller::default();
+        update_files(&client, &response, &installer, &mut Timings::default());
+        assert_eq!(*installer.lifecycle.borrow(), vec![None, Some(true)]);
+
+        let installer = RecordingInstaller { fail_on: Some("sd:/b.txt".into()), ..Default::default() };
+        update_files(&client, &response, &installer, &mut Timings::default());
+        assert_eq!(*installer.lifecycle.borrow(), vec![None, Some(false)]);
+
+        // a staged download failing completes the update before anything is installed
+        let response = staged_response();
+        let transport = MockTransport::default().reply(PORT + 1, "hel");
+        let installer = RecordingInstaller { stage_downloads: true, ..Default::default() };
+        update_files(&mock_client(&transport), &response, &installer, &mut Timings::default());
+        assert_eq!(*installer.lifecycle.borrow(), vec![None, Some(false)]);
+        assert!(installer.installed.borrow().is_empty());
+
+        assert!(update_complete_message(&response).contains("1.0.0"));
+    }
+
+    #[test]
+    fn test_request_options() {
+        let mut options = HashMap::new();
+        options.insert(String::from("package"), String::from("lite"));
+        let client = UpdateClient::new("127.0.0.1".parse().unwrap()).with_options(options.clone());
+
+        match update_request(&client, "test_plugin", "1.0.0", false, None, None) {
+            Request::Update { options: Some(sent), .. } => assert_eq!(sent.plugin_options, options),
+            request => panic!("unexpected request {:?}", request),
+        }
+    }
+
+    #[test]
+    fn test_deferred_custom_install() {
+        let response = inline_response(&[("sd:/a.txt", "a"), ("sd:/b.txt", "b")]);
+        let client = UpdateClient::new("127.0.0.1".parse().unwrap());
+
+        let installer = RecordingInstaller::default();
+        assert!(client.custom_install_update(&response, &installer));
+        assert_eq!(installer.installed.borrow().len(), 2);
+        assert_eq!(*installer.progress.borrow(), vec![(0, 1, 1), (1, 1, 1)]);
+        assert_eq!(*installer.lifecycle.borrow(), vec![None, Some(true)]);
+
+        let installer = RecordingInstaller { fail_on: Some("sd:/b.txt".into()), ..Default::default() };
+        assert_eq!(client.try_custom_install_update(&response, &installer), Err(UpdateError::Install {
+            path: "sd:/b.txt".into(),
+            reason: "refused by the test".into(),
+        }));
+        assert_eq!(*installer.lifecycle.borrow(), vec![None, Some(false)]);
+
+        // files are downloaded from the port after the client's when the server doesn't name one
+        let transport = MockTransport::default().reply(5001, "abc");
+        let response = UpdateResponse {
+            code: ResponseCode::Update,
+            plugin_name: "test_plugin".into(),
+            new_plugin_version: "1.0.0".into(),
+            required_files: vec![UpdateFile {
+                install_location: InstallLocation::AbsolutePath("sd:/c.txt".into()),
+                ..test_file(3, None)
+            }],
+            ..Default::default()
+        };
+        let installer = RecordingInstaller::default();
+        let installed_paths = vec![PathBuf::from("sd:/c.txt")];
+        assert_eq!(
+            mock_client(&transport).with_port(5000).try_custom_install_update(&response, &installer),
+            Ok(UpdateStatus::Installed { response: response.clone(), installed_paths })
+        );
+        assert!(transport.is_done());
+        assert_eq!(*installer.installed.borrow(), vec![(PathBuf::from("sd:/c.txt"), b"abc".to_vec())]);
+    }
+
+    #[test]
+    fn test_deferred_install_checks() {
+        let name = format!("deferred_checks_{}", std::process::id());
+        let installed_dir = changes::installed_dir();
+        let response = UpdateResponse { plugin_name: name.clone(), ..inline_response(&[("sd:/deferred.txt", "a")]) };
+        let client = UpdateClient::new("127.0.0.1".parse().unwrap());
+
+        // a version the user skipped or rolled back from is still installed when asked for
+        skip::skip_version(&skip::skipped_dir(), &response);
+        let installer = RecordingInstaller::default();
+        let report = update(&client, &response, &installer);
+        assert!(!report.skipped);
+        assert!(report.installed);
+        let _ = std::fs::remove_file(changes::record_path(&skip::skipped_dir(), &name));
+        changes::save_manifest(&installed_dir, &name, &InstalledManifest { rolled_back_from: Some("1.0.0".into()), ..Default::default() });
+        assert!(client.custom_install_update(&response, &installer));
+        assert_eq!(installer.installed.borrow().len(), 2);
+        installer.installed.borrow_mut().clear();
+        let _ = std::fs::remove_file(changes::record_path(&installed_dir, &name));
+
+        // nor is one needing a newer skyline, or with files which only differ by case
+        let outdated = UpdateResponse { skyline_requirement: Some(">=0.3.0".into()), ..response.clone() };
+        let report = update(&client.clone().with_skyline_version("0.2.0"), &outdated, &installer);
+        assert_eq!(report.status(), Ok(UpdateStatus::SkylineOutdated));
+        let colliding = UpdateResponse { plugin_name: name.clone(), ..inline_response(&[("sd:/Deferred.txt", "a"), ("sd:/deferred.txt", "b")]) };
+        assert!(matches!(client.try_custom_install_update(&colliding, &installer), Err(UpdateError::Install { .. })));
+        assert!(installer.installed.borrow().is_empty());
+
+        // what was installed is recorded, as for an update installed when checking
+        assert!(client.custom_install_update(&response, &installer));
+        let manifest = changes::load_manifest(&installed_dir, &name).unwrap();
+        assert_eq!(manifest.version.as_deref(), Some("1.0.0"));
+        assert_eq!(manifest.files.len(), 1);
+
+        let _ = std::fs::remove_file(changes::record_path(&installed_dir, &name));
+    }
+
+    #[test]
+    fn test_changelog_excerpt() {
+        assert_eq!(changelog_excerpt("\n# 1.1.0\n\n* Fixed everything\n\n"), "# 1.1.0\n\n* Fixed everything");
+
+        let long: String = (0..20).map(|i| format!("* change {}\n", i)).collect();
+        let excerpt = changelog_excerpt(&long);
+        assert_eq!(excerpt.lines().count(), CHANGELOG_EXCERPT_LINES);
+        assert!(excerpt.ends_with("change 11..."));
+
+        let excerpt = changelog_excerpt(&"a".repeat(CHANGELOG_EXCERPT_CHARS * 2));
+        assert_eq!(excerpt.len(), CHANGELOG_EXCERPT_CHARS + 3);
+        assert_eq!(changelog_excerpt(&"a".repeat(CHANGELOG_EXCERPT_CHARS)), "a".repeat(CHANGELOG_EXCERPT_CHARS));
+    }
+
+    #[test]
+    fn test_excluded_files() {
+        let mut response = inline_response(&[("sd:/a.txt", "a"), ("sd:/lang/fr.txt", "fr"), ("sd:/c.txt", "c")]);
+        // nothing serves the excluded file, so connecting to download it would fail
+        response.required_files[1].inline_data = None;
+        response.download_port = Some(1);
+        let client = UpdateClient::new("127.0.0.1".parse().unwrap());
+
+        for &stage_downloads in &[false, true] {
+            let installer = RecordingInstaller {
+                exclude: Some("sd:/lang/fr.txt".into()),
+                stage_downloads,
+                ..Default::default()
+            };
+            let files = update_files(&client, &response, &installer, &mut Timings::default());
+
+            let outcomes: Vec<_> = files.iter().map(|file| &file.outcome).collect();
+            assert_eq!(outcomes, [&FileOutcome::Installed, &FileOutcome::Excluded, &FileOutcome::Installed]);
+            assert!(files.iter().all(|file| file.outcome.succeeded()));
+            assert_eq!(installer.installed.borrow().len(), 2);
+            assert_eq!(*installer.lifecycle.borrow(), vec![None, Some(true)]);
+            assert!(completion_message(&files).contains("2 files installed, 1 skipped"));
+        }
+    }
+
+    #[test]
+    fn test_file_outcomes_failure() {
+        let response = inline_response(&[("sd:/a.txt", "a"), ("sd:/b.txt", "b"), ("sd:/c.txt", "c")]);
+        let installer = RecordingInstaller { fail_on: Some("sd:/b.txt".into()), ..Default::default() };
+
+        let outcomes: Vec<_> = update_files(&UpdateClient::new("127.0.0.1".parse().unwrap()), &response, &installer, &mut Timings::default())
+            .into_iter()
+            .map(|file| file.outcome)
+            .collect();
+
+        assert_eq!(outcomes, vec![
+            FileOutcome::Installed,
+            FileOutcome::Failed(UpdateError::Install {
+                path: "sd:/b.txt".into(),
+                reason: "refused by the test".into(),
+            }),
+            FileOutcome::Skipped,
+        ]);
+        assert_eq!(installer.installed.borrow().len(), 1);
+    }
+
+    #[test]
+    fn test_relative_locations() {
+        let mut response = inline_response(&[("sd:/a.txt", "a"), ("", "b")]);
+        response.required_files[1].install_location = InstallLocation::Relative("romfs/b.txt".into());
+        let client = UpdateClient::new("127.0.0.1".parse().unwrap());
+
+        let installer = RecordingInstaller::default();
+        assert!(update(&client, &response, &installer).installed);
+        assert_eq!(installer.installed.borrow()[1].0, PathBuf::from("sd:/atmosphere/contents/01006A800016E000/romfs/b.txt"));
+
+        let installer = RecordingInstaller { install_root: Some("sd:/switch/homebrew".into()), ..Default::default() };
+        assert!(update(&client, &response, &installer).installed);
+        assert_eq!(installer.installed.borrow()[1].0, PathBuf::from("sd:/switch/homebrew/romfs/b.txt"));
+    }
+
+    #[test]
+    fn test_unsupported_location() {
+        let mut response = inline_response(&[("sd:/a.txt", "a"), ("", "b")]);
+        response.required_files[1].install_location = InstallLocation::Unknown;
+        let installer = RecordingInstaller::default();
+
+        // nothing is installed, even the files before it
+        let mut report = UpdateReport::default();
+        handle_response(&UpdateClient::new("127.0.0.1".parse().unwrap()), "test_plugin", &response, &installer, &mut report, false, true);
+        assert!(installer.installed.borrow().is_empty());
+        assert!(report.files.is_empty());
+        assert_eq!(report.error, Some(unsupported_location(&InstallLocation::Unknown)));
+    }
+
+    /// Writes files into a directory standing in for the SD card, which is removed right
+    /// before writing `vanish_on`
+    struct VanishingInstaller {
+        sd: PathBuf,
+        vanish_on: PathBuf,
+        unavailable: std::cell::RefCell<Vec<String>>,
+    }
+
+    impl Installer for VanishingInstaller {
+        fn should_update(&self, _: &UpdateResponse) -> bool {
+            true
+        }
+
+        fn install_file(&self, path: PathBuf, buf: Vec<u8>) -> Result<(), InstallError> {
+            if path == self.vanish_on {
+                let _ = std::fs::remove_dir_all(&self.sd);
+            }
+            std::fs::write(&path, buf).map_err(|e| InstallError::new(path, e))
+        }
+
+        fn on_storage_unavailable(&self, response: &UpdateResponse) {
+            self.unavailable.borrow_mut().push(response.plugin_name.clone());
+        }
+    }
+
+    #[test]
+    fn test_storage_unavailable() {
+        let sd = std::env::temp_dir().join(format!("skyline-update-sd-{}", std::process::id()));
+        std::fs::create_dir_all(&sd).unwrap();
+        let path = |name: &str| sd.join(name).to_string_lossy().into_owned();
+
+        let mut response = inline_response(&[(&path("a.txt"), "a"), (&path("b.txt"), "b"), (&path("c.txt"), "c")]);
+        response.plugin_name = format!("storage_unavailable_{}", std::process::id());
+        let installer = VanishingInstaller {
+            sd: sd.clone(),
+            vanish_on: path("b.txt").into(),
+            unavailable: Default::default(),
+        };
+
+        let mut report = UpdateReport::default();
+        handle_response(&UpdateClient::new("127.0.0.1".parse().unwrap()), &response.plugin_name, &response, &installer, &mut report, false, true);
+
+        // the remaining file isn't attempted and the update isn't recorded as installed
+        let lost = UpdateError::StorageUnavailable { path: path("b.txt") };
+        let outcomes: Vec<_> = report.files.iter().map(|file| file.outcome.clone()).collect();
+        assert_eq!(outcomes, vec![FileOutcome::Installed, FileOutcome::Failed(lost.clone()), FileOutcome::Skipped]);
+        assert_eq!(report.status(), Err(lost));
+        assert_eq!(*installer.unavailable.borrow(), vec![response.plugin_name.clone()]);
+        assert_eq!(changes::load_manifest(&changes::installed_dir(), &response.plugin_name), None);
+    }
+
+    /// Writes files to disk and answers every archive with the same decision
+    struct DiskInstaller {
+        decision: ArchiveDecision,
+        keep_archives: bool,
+    }
+
+    impl Installer for DiskInstaller {
+        fn should_update(&self, _: &UpdateResponse) -> bool {
+            true
+        }
+
+        fn install_file(&self, path: PathBuf, buf: Vec<u8>) -> Result<(), InstallError> {
+            std::fs::create_dir_all(path.parent().unwrap()).map_err(|e| InstallError::new(&path, e))?;
+            std::fs::write(&path, buf).map_err(|e| InstallError::new(path, e))
+        }
+
+        fn handle_archive(&self, _: &UpdateFile, _: &Path) -> ArchiveDecision {
+            self.decision.clone()
+        }
+
+        fn keep_archives(&self) -> bool {
+            self.keep_archives
+        }
+    }
+
+    pub(crate) fn tar_of(files: &[&str]) -> String {
+        let mut builder = tar::Builder::new(vec![]);
+        let data = b"hello";
+        for name in files {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(data.len() as u64);
+            header.set_mode(0o644);
+            header.set_cksum();
+            builder.append_data(&mut header, name, &data[..]).unwrap();
+        }
+
+        String::from_utf8(builder.into_inner().unwrap()).unwrap()
+    }
+
+    fn test_archive() -> String {
+        tar_of(&["inner/test.txt"])
+    }
+
+    fn install_archive(name: &str, decision: ArchiveDecision) -> (PathBuf, Vec<FileReport>) {
+        install_archive_with(name, &test_archive(), &DiskInstaller { decision, keep_archives: false })
+    }
+
+    fn install_archive_with<I: Installer>(name: &str, archive: &str, installer: &I) -> (PathBuf, Vec<FileReport>) {
+        let dir = std::env::temp_dir().join(format!("skyline-update-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+
+        let location = dir.join("mods/romfs.tar").to_string_lossy().into_owned();
+        let response = inline_response(&[(&location, archive)]);
+
+        let files = update_files(&UpdateClient::new("127.0.0.1".parse().unwrap()), &response, installer, &mut Timings::default());
+        (dir, files)
+    }
+
+    #[test]
+    fn test_archive_extract_now() {
+        let (dir, files) = install_archive("extract-now", ArchiveDecision::ExtractNow);
+
+        assert_eq!(files[0].outcome, FileOutcome::Installed);
+        assert_eq!(files[0].archive, Some(ArchiveDecision::ExtractNow));
+        assert!(!dir.join("mods/romfs.tar").exists());
+        assert_eq!(std::fs::read(dir.join("mods/romfs/inner/test.txt")).unwrap(), b"hello");
+
+        let _ = std::fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn test_archive_kept() {
+        let installer = DiskInstaller { decision: ArchiveDecision::ExtractNow, keep_archives: true };
+        let (dir, files) = install_archive_with("keep-archive", &test_archive(), &installer);
+
+        assert_eq!(files[0].outcome, FileOutcome::Installed);
+        assert!(dir.join("mods/romfs.tar").exists());
+        assert_eq!(std::fs::read(dir.join("mods/romfs/inner/test.txt")).unwrap(), b"hello");
+
+        let _ = std::fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn test_archive_kept_on_failure() {
+        let archive = tar_of(&["inner/Test.txt", "inner/test.txt"]);
+        let installer = DiskInstaller { decision: ArchiveDecision::ExtractNow, keep_archives: false };
+        let (dir, files) = install_archive_with("failed-archive", &archive, &installer);
+
+        assert!(matches!(files[0].outcome, FileOutcome::Failed(_)), "{:?}", files[0].outcome);
+        assert!(dir.join("mods/romfs.tar").exists());
+        assert!(!dir.join("mods/romfs").exists());
+
+        let _ = std::fs::remove_dir_all(dir);
+    }
+
+    /// Zip every file below `folder` the way the zip-impl update-server does, names relative to
+    /// the folder
+    fn zip_folder(folder: &Path) -> Vec<u8> {
+        fn add(zip: &mut zip::ZipWriter<std::io::Cursor<Vec<u8>>>, folder: &Path, dir: &Path) {
+            for entry in std::fs::read_dir(dir).unwrap() {
+                let path = entry.unwrap().path();
+                if path.is_dir() {
+                    add(zip, folder, &path);
+                    continue
+                }
+
+                let name = path.strip_prefix(folder).unwrap().to_string_lossy().replace('\\', "/");
+                zip.start_file(name, zip::write::FileOptions::default()).unwrap();
+                zip.write_all(&std::fs::read(&path).unwrap()).unwrap();
+            }
+        }
+
+        let mut zip = zip::ZipWriter::new(std::io::Cursor::new(vec![]));
+        add(&mut zip, folder, folder);
+        zip.finish().unwrap().into_inner()
+    }
+
+    /// A zip of a fixture folder, and the folder it was made from
+    fn zip_fixture(dir: &Path) -> (PathBuf, Vec<u8>) {
+        let fixture = dir.join("fixture");
+        std::fs::create_dir_all(fixture.join("inner/deeper")).unwrap();
+        std::fs::write(fixture.join("top.txt"), b"top").unwrap();
+        std::fs::write(fixture.join("inner/test.txt"), b"hello").unwrap();
+        std::fs::write(fixture.join("inner/deeper/data.bin"), vec![0xffu8; 4096]).unwrap();
+
+        let zip = zip_folder(&fixture);
+        (fixture, zip)
+    }
+
+    fn assert_extracted_fixture(fixture: &Path, to: &Path) {
+        for file in &["top.txt", "inner/test.txt", "inner/deeper/data.bin"] {
+            assert_eq!(std::fs::read(to.join(file)).unwrap(), std::fs::read(fixture.join(file)).unwrap(), "{}", file);
+        }
+    }
+
+    fn zip_response(location: &Path, zip: &[u8]) -> UpdateResponse {
+        let mut response = inline_response(&[(&location.to_string_lossy(), "")]);
+        response.required_files[0].inline_data = Some(base64::encode(zip));
+        response.required_files[0].size = zip.len();
+        response
+    }
+
+    #[test]
+    fn test_zip_round_trip() {
+        let dir = std::env::temp_dir().join(format!("skyline-update-zip-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let (fixture, zip) = zip_fixture(&dir);
+
+        let location = dir.join("mods/romfs.zip");
+        let installer = DiskInstaller { decision: ArchiveDecision::ExtractNow, keep_archives: false };
+        let files = update_files(&UpdateClient::new("127.0.0.1".parse().unwrap()), &zip_response(&location, &zip), &installer, &mut Timings::default());
+
+        assert_eq!(files[0].outcome, FileOutcome::Installed);
+        assert_eq!(files[0].archive, Some(ArchiveDecision::ExtractNow));
+        assert!(!location.exists());
+        assert_extracted_fixture(&fixture, &dir.join("mods/romfs"));
+
+        let _ = std::fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn test_zip_downloaded() {
+        let dir = std::env::temp_dir().join(format!("skyline-update-zip-download-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let (fixture, zip) = zip_fixture(&dir);
+
+        // installed from the temporary download, and never streamed as zips can't be
+        let transport = MockTransport::default().reply(PORT + 1, zip.clone());
+        let location = dir.join("mods/romfs.zip");
+        let mut response = zip_response(&location, &zip);
+        response.required_files[0].inline_data = None;
+
+        let installer = SandboxInstaller::new(dir.join("sd"));
+        let files = update_files(&mock_client(&transport), &response, &installer, &mut Timings::default());
+        assert!(transport.is_done());
+
+        assert_eq!(files[0].outcome, FileOutcome::Installed);
+        assert_extracted_fixture(&fixture, &installer.path(&location).with_extension(""));
+
+        let _ = std::fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn test_zip_failures() {
+        let dir = std::env::temp_dir().join(format!("skyline-update-zip-failures-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let mut zip = zip::ZipWriter::new(std::io::Cursor::new(vec![]));
+        for name in &["inner/Test.txt", "inner/test.txt"] {
+            zip.start_file(*name, zip::write::FileOptions::default()).unwrap();
+            zip.write_all(b"hello").unwrap();
+        }
+        let archive = dir.join("collision.zip");
+        std::fs::write(&archive, zip.finish().unwrap().into_inner()).unwrap();
+        let e = extract(&archive, &dir.join("collision"), false).unwrap_err();
+        assert!(e.contains("inner/Test.txt"), "{}", e);
+        assert!(extract(&archive, &dir.join("collision"), true).is_ok());
+
+        let truncated = dir.join("truncated.zip");
+        std::fs::write(&truncated, &std::fs::read(&archive).unwrap()[..40]).unwrap();
+        assert!(extract(&truncated, &dir.join("truncated"), false).is_err());
+
+        let _ = std::fs::remove_dir_all(dir);
+    }
+
+    /// A tar of the given entries with their names written as is, as `tar::Builder` refuses
+    /// names leaving the archive's folder
+    pub(crate) fn raw_tar_of(files: &[&str]) -> Vec<u8> {
+        let mut builder = tar::Builder::new(vec![]);
+        for name in files {
+            let mut header = tar::Header::new_old();
+            header.as_old_mut().name[..name.len()].copy_from_slice(name.as_bytes());
+            header.set_size(5);
+            header.set_mode(0o644);
+            header.set_entry_type(tar::EntryType::Regular);
+            header.set_cksum();
+            builder.append(&header, &b"hello"[..]).unwrap();
+        }
+
+        builder.into_inner().unwrap()
+    }
+
+    #[test]
+    fn test_extract_path_traversal() {
+        let dir = std::env::temp_dir().join(format!("skyline-update-traversal-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let escaped = dir.join("absolute.txt").to_string_lossy().into_owned();
+        let names = ["../evil.txt", "inner/../../evil.txt", &escaped, "./inner/ok.txt"];
+
+        let tar = raw_tar_of(&names);
+        let archive = dir.join("evil.tar");
+        std::fs::write(&archive, &tar).unwrap();
+        // only the entry extracted is reported
+        let extracted = extract(&archive, &dir.join("extracted/tar"), false).unwrap();
+        assert_eq!(extracted, vec![dir.join("extracted/tar/inner/ok.txt")]);
+        let extracted = extract_streamed(&archive, &tar[..], &dir.join("extracted/streamed"), false, |_| {}).unwrap();
+        assert_eq!(extracted, vec![dir.join("extracted/streamed/inner/ok.txt")]);
+
+        let mut zip = zip::ZipWriter::new(std::io::Cursor::new(vec![]));
+        for name in &names {
+            zip.start_file(*name, zip::write::FileOptions::default()).unwrap();
+            zip.write_all(b"hello").unwrap();
+        }
+        let archive = dir.join("evil.zip");
+        std::fs::write(&archive, zip.finish().unwrap().into_inner()).unwrap();
+        assert_eq!(extract(&archive, &dir.join("extracted/zip"), false).unwrap(), vec![dir.join("extracted/zip/inner/ok.txt")]);
+
+        for kind in &["tar", "streamed", "zip"] {
+            let to = dir.join("extracted").join(kind);
+            assert_eq!(std::fs::read(to.join("inner/ok.txt")).unwrap(), b"hello", "{}", kind);
+            assert!(!to.join("inner/evil.txt").exists(), "{}", kind);
+        }
+        assert!(!dir.join("evil.txt").exists());
+        assert!(!dir.join("extracted/evil.txt").exists());
+        assert!(!dir.join("absolute.txt").exists());
+
+        assert_eq!(enclosed_entry_path(Path::new("./a/b.txt")), Some(PathBuf::from("a/b.txt")));
+        assert_eq!(enclosed_entry_path(Path::new("a/../b.txt")), None);
+        assert_eq!(enclosed_entry_path(Path::new("/a.txt")), None);
+
+        let _ = std::fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn test_rollback_failed_update() {
+        let dir = std::env::temp_dir().join(format!("skyline-update-rollback-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let installer = SandboxInstaller::new(dir.join("sd"));
+        std::fs::create_dir_all(dir.join("sd/plugin")).unwrap();
+        std::fs::write(dir.join("sd/plugin/a.txt"), b"old a").unwrap();
+        std::fs::write(dir.join("sd/plugin/b.txt"), b"old b").unwrap();
+
+        // b.txt is a file, so nothing can be installed below it
+        let mut response = inline_response(&[
+            ("sd:/plugin/a.txt", "new a"),
+            ("sd:/plugin/c.txt", "new c"),
+            ("sd:/plugin/b.txt/d.txt", "d"),
+            ("sd:/plugin/e.txt", "e"),
+        ]);
+        response.plugin_name = format!("rollback_{}", std::process::id());
+        let files = update_files(&UpdateClient::new("127.0.0.1".parse().unwrap()), &response, &installer, &mut Timings::default());
+
+        let outcomes: Vec<_> = files.iter().map(|file| &file.outcome).collect();
+        assert_eq!(outcomes[..2], [&FileOutcome::RolledBack, &FileOutcome::RolledBack]);
+        assert!(matches!(outcomes[2], FileOutcome::Failed(UpdateError::Install { .. })), "{:?}", outcomes[2]);
+        assert_eq!(outcomes[3], &FileOutcome::Skipped);
+        assert!(UpdateReport { files, ..Default::default() }.rolled_back());
+
+        assert_eq!(std::fs::read(dir.join("sd/plugin/a.txt")).unwrap(), b"old a");
+        assert_eq!(std::fs::read(dir.join("sd/plugin/b.txt")).unwrap(), b"old b");
+        assert!(!dir.join("sd/plugin/c.txt").exists());
+
+        // once it succeeds the new files are kept
+        response.required_files.remove(2);
+        let files = update_files(&UpdateClient::new("127.0.0.1".parse().unwrap()), &response, &installer, &mut Timings::default());
+        assert!(files.iter().all(|file| file.outcome == FileOutcome::Installed));
+        assert_eq!(std::fs::read(dir.join("sd/plugin/a.txt")).unwrap(), b"new a");
+        assert!(!transaction::backup_dir().join(&response.plugin_name).exists());
+
+        let _ = std::fs::remove_dir_all(dir);
+    }
+
+    /// Writes files to disk and extracts archives as they download, recording extraction progress
+    #[derive(Default)]
+    struct ExtractingInstaller {
+        extracted: std::cell::RefCell<Vec<usize>>,
+        stage_downloads: bool,
+    }
+
+    impl Installer for ExtractingInstaller {
+        fn should_update(&self, _: &UpdateResponse) -> bool {
+            true
+        }
+
+        fn install_file(&self, path: PathBuf, buf: Vec<u8>) -> Result<(), InstallError> {
+            std::fs::create_dir_all(path.parent().unwrap()).map_err(|e| InstallError::new(&path, e))?;
+            std::fs::write(&path, buf).map_err(|e| InstallError::new(path, e))
+        }
+
+        fn handle_archive(&self, _: &UpdateFile, _: &Path) -> ArchiveDecision {
+            panic!("streamed archives are never installed whole")
+        }
+
+        fn stream_archives(&self) -> bool {
+            true
+        }
+
+        fn on_extract_progress(&self, _: &UpdateFile, entries_extracted: usize) {
+            self.extracted.borrow_mut().push(entries_extracted);
+        }
+
+        fn stage_downloads(&self) -> bool {
+            self.stage_downloads
+        }
+    }
+
+    #[test]
+    fn test_stream_inline_archive() {
+        let installer = ExtractingInstaller::default();
+        let (dir, files) = install_archive_with("stream-inline", &tar_of(&["inner/a.txt", "inner/b.txt"]), &installer);
+
+        assert_eq!(files[0].outcome, FileOutcome::Installed);
+        assert_eq!(files[0].archive, Some(ArchiveDecision::ExtractNow));
+        assert!(!dir.join("mods/romfs.tar").exists());
+        assert_eq!(std::fs::read(dir.join("mods/romfs/inner/b.txt")).unwrap(), b"hello");
+        assert_eq!(*installer.extracted.borrow(), vec![1, 2]);
+
+        let _ = std::fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn test_stream_downloaded_archive() {
+        let dir = std::env::temp_dir().join(format!("skyline-update-stream-download-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+
+        let archive = tar_of(&["inner/test.txt"]).into_bytes();
+        let transport = MockTransport::default().reply(PORT + 1, archive.clone());
+        let location = dir.join("mods/romfs.tar").to_string_lossy().into_owned();
+        let mut response = inline_response(&[(&location, "")]);
+        response.required_files[0].inline_data = None;
+        response.required_files[0].size = archive.len();
+
+        let installer = ExtractingInstaller::default();
+        let files = update_files(&mock_client(&transport), &response, &installer, &mut Timings::default());
+        assert!(transport.is_done());
+
+        assert_eq!(files[0].outcome, FileOutcome::Installed);
+        assert!(!dir.join("mods/romfs.tar").exists());
+        assert_eq!(std::fs::read(dir.join("mods/romfs/inner/test.txt")).unwrap(), b"hello");
+        assert_eq!(*installer.extracted.borrow(), vec![1]);
+
+        let _ = std::fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn test_staged_archive() {
+        let dir = std::env::temp_dir().join(format!("skyline-update-staged-archive-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+
+        let archive = tar_of(&["inner/test.txt"]).into_bytes();
+        let location = dir.join("mods/romfs.tar").to_string_lossy().into_owned();
+        let mut response = inline_response(&[(&location, "")]);
+        response.required_files[0].inline_data = None;
+        response.required_files[0].size = archive.len();
+        let installer = ExtractingInstaller { stage_downloads: true, ..Default::default() };
+
+        // the archive is only extracted once the file after it downloaded
+        let transport = MockTransport::default().reply(PORT + 1, archive.clone());
+        response.required_files.push(UpdateFile { download_index: 1, ..test_file(5, None) });
+        let files = update_files(&mock_client(&transport), &response, &installer, &mut Timings::default());
+        assert_eq!(files[0].outcome, FileOutcome::Skipped);
+        assert!(!dir.join("mods/romfs").exists());
+
+        let transport = MockTransport::default().reply(PORT + 1, archive.clone());
+        response.required_files.pop();
+        let files = update_files(&mock_client(&transport), &response, &installer, &mut Timings::default());
+        assert!(transport.is_done());
+
+        assert_eq!(files[0].outcome, FileOutcome::Installed);
+        assert_eq!(files[0].archive, Some(ArchiveDecision::ExtractNow));
+        assert!(!dir.join("mods/romfs.tar").exists());
+        assert_eq!(std::fs::read(dir.join("mods/romfs/inner/test.txt")).unwrap(), b"hello");
+
+        let _ = std::fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn test_stream_archive_failures() {
+        let installer = ExtractingInstaller::default();
+        let (dir, files) = install_archive_with("stream-collision", &tar_of(&["inner/Test.txt", "inner/test.txt"]), &installer);
+        match &files[0].outcome {
+            FileOutcome::Failed(UpdateError::Install { reason, .. }) => assert!(reason.contains("inner/Test.txt"), "{}", reason),
+            other => panic!("expected an install error, got {:?}", other),
+        }
+        let _ = std::fs::remove_dir_all(dir);
+
+        // the server sending more than it announced is a failed download
+        let dir = std::env::temp_dir().join(format!("skyline-update-stream-oversized-{}", std::process::id()));
+        let archive = tar_of(&["inner/test.txt"]).into_bytes();
+        let transport = MockTransport::default().reply(PORT + 1, archive.clone());
+        let location = dir.join("mods/romfs.tar").to_string_lossy().into_owned();
+        let mut response = inline_response(&[(&location, "")]);
+        response.required_files[0].inline_data = None;
+        response.required_files[0].size = archive.len() - 1024;
+
+        let files = update_files(&mock_client(&transport), &response, &installer, &mut Timings::default());
+        assert!(matches!(&files[0].outcome, FileOutcome::Failed(UpdateError::Download { index: 0, .. })));
+
+        // and so is the server closing the download early
+        let transport = MockTransport::default().reply(PORT + 1, &archive[..700]);
+        response.required_files[0].size = archive.len();
+
+        let files = update_files(&mock_client(&transport), &response, &installer, &mut Timings::default());
+        assert!(matches!(&files[0].outcome, FileOutcome::Failed(UpdateError::Download { index: 0, .. })));
+
+        let _ = std::fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn test_archive_leave_archived() {
+        let (dir, files) = install_archive("leave-archived", ArchiveDecision::LeaveArchived);
+
+        assert_eq!(files[0].outcome, FileOutcome::Installed);
+        assert_eq!(files[0].archive, Some(ArchiveDecision::LeaveArchived));
+        assert!(dir.join("mods/romfs.tar").exists());
+        assert!(!dir.join("mods/romfs").exists());
+
+        let _ = std::fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn test_report_status() {
+        let report = |code: ResponseCode, installed: bool| UpdateReport {
+            response: Some(UpdateResponse { code, ..Default::default() }),
+            installed,
+            ..Default::default()
+        };
+
+        assert_eq!(report(ResponseCode::NoUpdate, false).status(), Ok(UpdateStatus::UpToDate));
+        let installed = report(ResponseCode::Update, true);
+        assert_eq!(installed.status(), Ok(UpdateStatus::Installed { response: installed.response.clone().unwrap(), installed_paths: vec![] }));
+        assert_eq!(report(ResponseCode::Update, false).status(), Ok(UpdateStatus::Declined));
+        assert_eq!(report(ResponseCode::NotifyOnly, false).status(), Ok(UpdateStatus::Notified));
+        assert_eq!(report(ResponseCode::PluginNotFound, false).status(), Err(UpdateError::PluginNotFound(None)));
+        assert_eq!(report(ResponseCode::InvalidRequest, false).status(), Err(UpdateError::InvalidRequest(None)));
+        assert_eq!(report(ResponseCode::RateLimited, false).status(), Err(UpdateError::RateLimited { retry_after: None }));
+        assert_eq!(report(ResponseCode::ServerError, false).status(), Err(UpdateError::ServerError(String::from("no reason given"))));
+
+        let failed = UpdateReport {
+            error: Some(UpdateError::Download { index: 3, reason: "connection reset".into() }),
+            ..report(ResponseCode::Update, false)
+        };
+        assert_eq!(failed.status(), Err(UpdateError::Download { index: 3, reason: "connection reset".into() }));
+    }
+
+    #[test]
+    fn test_connect_failure_status() {
+        // nothing listens on the port of a dropped listener
+        let port = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+        let report = check_update_report_on(&UpdateClient::new("127.0.0.1".parse().unwrap()).with_port(port), "test_plugin", "1.0.0", BetaPreference::No, &DefaultInstaller);
+
+        match report.status() {
+            Err(UpdateError::Connect(_)) => {}
+            other => panic!("expected a connect error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_hostname() {
+        use std::net::TcpListener;
+
+        // localhost may resolve to ::1 first, which nothing listens on, before 127.0.0.1
+        let main = TcpListener::bind("127.0.0.1:0").unwrap();
+        let client = UpdateClient::with_host("localhost").with_port(main.local_addr().unwrap().port());
+        assert_eq!(client.host(), "localhost");
+
+        let server = std::thread::spawn(move || {
+            let (mut socket, _) = main.accept().unwrap();
+            let mut request = String::new();
+            std::io::BufReader::new(&socket).read_line(&mut request).unwrap();
+            socket.write_all(serde_json::to_string(&UpdateResponse::no_update()).unwrap().as_bytes()).unwrap();
+        });
+
+        let report = check_update_report_on(&client, "test_plugin", "1.0.0", BetaPreference::No, &DefaultInstaller);
+        server.join().unwrap();
+        assert_eq!(report.status(), Ok(UpdateStatus::UpToDate));
+    }
+
+    #[test]
+    fn test_unresolvable_host() {
+        // the .invalid top level domain never resolves
+        let client = UpdateClient::with_host("updates.skyline-update.invalid");
+        let report = check_update_report_on(&client, "test_plugin", "1.0.0", BetaPreference::No, &DefaultInstaller);
+
+        match report.status() {
+            Err(UpdateError::Resolve { host, .. }) => assert_eq!(host, "updates.skyline-update.invalid"),
+            other => panic!("expected a resolve error, got {:?}", other),
+        }
+        assert!(client.get_update_info("test_plugin", "1.0.0", false).is_none());
+    }
+
+    pub(crate) fn short_timeouts() -> Timeouts {
+        Timeouts { request: Duration::from_millis(200), download: Duration::from_millis(200) }
+    }
+
+    #[test]
+    fn test_request_timeout() {
+        // connections are completed by the OS but nothing ever answers them
+        let hung = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let client = UpdateClient::new("127.0.0.1".parse().unwrap())
+            .with_port(hung.local_addr().unwrap().port())
+            .with_timeouts(short_timeouts());
+
+        let start = Instant::now();
+        let report = check_update_report_on(&client, "test_plugin", "1.0.0", BetaPreference::No, &DefaultInstaller);
+        assert_eq!(report.status(), Err(UpdateError::Timeout));
+        assert!(client.get_update_info("test_plugin", "1.0.0", false).is_none());
+        assert!(start.elapsed() < Duration::from_secs(5));
+    }
+
+    #[test]
+    fn test_download_timeout() {
+        let hung = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let client = UpdateClient::new("127.0.0.1".parse().unwrap()).with_timeouts(short_timeouts());
+        let response = UpdateResponse {
+            required_files: vec![test_file(5, None)],
+            download_port: Some(hung.local_addr().unwrap().port()),
+            ..Default::default()
+        };
+
+        let start = Instant::now();
+        let files = update_files(&client, &response, &RecordingInstaller::default(), &mut Timings::default());
+        assert_eq!(files[0].outcome, FileOutcome::Failed(UpdateError::Download { index: 0, reason: "it stalled for longer than 200ms".into() }));
+        assert!(start.elapsed() < Duration::from_secs(5));
+    }
+
+    #[test]
+    fn test_download_too_large() {
+        // nothing listens on the download port, so a download attempt would fail differently
+        let response = UpdateResponse {
+            required_files: vec![test_file(5, None), test_file(DEFAULT_MAX_DOWNLOAD_SIZE + 1, None)],
+            download_port: Some(1),
+            ..Default::default()
+        };
+        let client = UpdateClient::new("127.0.0.1".parse().unwrap()).with_max_download_size(4);
+
+        let installer = RecordingInstaller::default();
+        let files = update_files(&client, &response, &installer, &mut Timings::default());
+        assert_eq!(files[0].outcome, FileOutcome::Failed(UpdateError::TooLarge { path: "sd:/test.txt".into(), size: 5, limit: 4 }));
+        assert_eq!(files[1].outcome, FileOutcome::Skipped);
+        assert!(installer.installed.borrow().is_empty());
+
+        let client = UpdateClient::new("127.0.0.1".parse().unwrap());
+        assert_eq!(client.max_download_size(), DEFAULT_MAX_DOWNLOAD_SIZE);
+        assert!(matches!(check_download_size(&client, &response.required_files[1]), Err(UpdateError::TooLarge { .. })));
+        assert!(check_download_size(&client, &response.required_files[0]).is_ok());
+    }
+
+    #[test]
+    fn test_download_longer_than_expected() {
+        use std::net::TcpListener;
+
+        let downloads = TcpListener::bind("127.0.0.1:0").unwrap();
+        let response = UpdateResponse {
+            required_files: vec![test_file(5, None)],
+            download_port: Some(downloads.local_addr().unwrap().port()),
+            ..Default::default()
+        };
+
+        let server = std::thread::spawn(move || {
+            let (mut socket, _) = downloads.accept().unwrap();
+            let mut index = [0; 8];
+            socket.read_exact(&mut index).unwrap();
+            let _ = socket.write_all(&[0; 1024 * 1024]);
+        });
+
+        let installer = RecordingInstaller::default();
+        let files = update_files(&UpdateClient::new("127.0.0.1".parse().unwrap()), &response, &installer, &mut Timings::default());
+        server.join().unwrap();
+        assert_eq!(files[0].outcome, FileOutcome::Failed(UpdateError::Download { index: 0, reason: "the server sent more than the expected 5 bytes".into() }));
+        assert!(installer.installed.borrow().is_empty());
+    }
+
+    #[test]
+    fn test_download_shorter_than_expected() {
+        let transport = MockTransport::default().reply(PORT + 1, "hel");
+        let mut response = inline_response(&[("sd:/test.txt", "")]);
+        response.required_files[0] = test_file(5, None);
+
+        let installer = RecordingInstaller::default();
+        let files = update_files(&mock_client(&transport), &response, &installer, &mut Timings::default());
+
+        assert_eq!(files[0].outcome, FileOutcome::Failed(UpdateError::Download { index: 0, reason: "the server closed it after 3 of the expected 5 bytes".into() }));
+        assert!(installer.installed.borrow().is_empty());
+    }
+
+    #[test]
+    fn test_checksum_mismatch() {
+        let expected = resume::sha256_of(&b"hello"[..]).unwrap();
+        let corrupted = |actual: &[u8]| UpdateError::Checksum {
+            index: 0,
+            expected: expected.clone(),
+            actual: resume::sha256_of(actual).unwrap(),
+        };
+        let transport = MockTransport::default().reply(PORT + 1, "hellx").reply(PORT + 1, "hello");
+        let client = mock_client(&transport);
+
+        let mut response = inline_response(&[("sd:/test.txt", "")]);
+        response.required_files[0] = UpdateFile { sha256: Some(expected.clone()), ..test_file(5, None) };
+
+        let installer = RecordingInstaller::default();
+        let files = update_files(&client, &response, &installer, &mut Timings::default());
+        assert_eq!(files[0].outcome, FileOutcome::Failed(corrupted(b"hellx")));
+        assert!(installer.installed.borrow().is_empty());
+
+        response.required_files[0].inline_data = Some(base64::encode("jello"));
+        let files = update_files(&client, &response, &installer, &mut Timings::default());
+        assert_eq!(files[0].outcome, FileOutcome::Failed(corrupted(b"jello")));
+        assert!(installer.installed.borrow().is_empty());
+
+        // hashes are compared however the server cased them
+        response.required_files[0].inline_data = None;
+        response.required_files[0].sha256 = Some(expected.to_uppercase());
+        let files = update_files(&client, &response, &installer, &mut Timings::default());
+        assert!(transport.is_done());
+        assert_eq!(files[0].outcome, FileOutcome::Installed);
+    }
+
+    #[test]
+    fn test_stream_archive_checksum_mismatch() {
+        let dir = std::env::temp_dir().join(format!("skyline-update-stream-checksum-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+
+        let archive = tar_of(&["inner/test.txt"]).into_bytes();
+        let transport = MockTransport::default().reply(PORT + 1, archive.clone());
+        let location = dir.join("mods/romfs.tar").to_string_lossy().into_owned();
+        let mut response = inline_response(&[(&location, "")]);
+        response.required_files[0].inline_data = None;
+        response.required_files[0].size = archive.len();
+        response.required_files[0].sha256 = Some(resume::sha256_of(&b"another archive"[..]).unwrap());
+
+        let files = update_files(&mock_client(&transport), &response, &ExtractingInstaller::default(), &mut Timings::default());
+        assert!(matches!(files[0].outcome, FileOutcome::Failed(UpdateError::Checksum { index: 0, .. })), "{:?}", files[0].outcome);
+        // checked before anything was extracted into the live folder
+        assert!(!dir.join("mods/romfs").exists());
+
+        let mut response = inline_response(&[(&location, &tar_of(&["inner/test.txt"]))]);
+        response.required_files[0].sha256 = Some(resume::sha256_of(&b"another archive"[..]).unwrap());
+        let files = update_files(&UpdateClient::new("127.0.0.1".parse().unwrap()), &response, &ExtractingInstaller::default(), &mut Timings::default());
+        assert!(matches!(files[0].outcome, FileOutcome::Failed(UpdateError::Checksum { index: 0, .. })), "{:?}", files[0].outcome);
+        assert!(!dir.join("mods/romfs").exists());
+
+        // a matching hash is extracted once the whole archive was checked
+        let archive = tar_of(&["inner/test.txt"]);
+        response.required_files[0].sha256 = Some(resume::sha256_of(archive.as_bytes()).unwrap());
+        let files = update_files(&UpdateClient::new("127.0.0.1".parse().unwrap()), &response, &ExtractingInstaller::default(), &mut Timings::default());
+        assert_eq!(files[0].outcome, FileOutcome::Installed);
+        assert_eq!(std::fs::read(dir.join("mods/romfs/inner/test.txt")).unwrap(), b"hello");
+
+        let _ = std::fs::remove_dir_all(dir);
+    }
+
+    /// An inline file followed by a file of 5 bytes downloaded from the download port
+    fn staged_response() -> UpdateResponse {
+        let mut response = inline_response(&[("sd:/a.txt", "a")]);
+        response.required_files.push(UpdateFile {
+            install_location: InstallLocation::AbsolutePath("sd:/b.txt".into()),
+            ..test_file(5, None)
+        });
+
+        response
+    }
+
+    #[test]
+    fn test_staged_downloads() {
+        let transport = MockTransport::default().reply(PORT + 1, "hello");
+        let installer = RecordingInstaller { stage_downloads: true, ..Default::default() };
+        let files = update_files(&mock_client(&transport), &staged_response(), &installer, &mut Timings::default());
+
+        assert!(files.iter().all(|file| file.outcome == FileOutcome::Installed));
+        assert_eq!(*installer.installed.borrow(), vec![
+            (PathBuf::from("sd:/a.txt"), b"a".to_vec()),
+            (PathBuf::from("sd:/b.txt"), b"hello".to_vec()),
+        ]);
+    }
+
+    #[test]
+    fn test_staged_download_failure() {
+        // installed as it downloads, the first file is in place before the second fails
+        let transport = MockTransport::default().reply(PORT + 1, "hel").reply(PORT + 1, "hel");
+        let installer = RecordingInstaller::default();
+        let files = update_files(&mock_client(&transport), &staged_response(), &installer, &mut Timings::default());
+        assert_eq!(files[0].outcome, FileOutcome::Installed);
+        assert_eq!(installer.installed.borrow().len(), 1);
+
+        // staged, nothing is installed
+        let installer = RecordingInstaller { stage_downloads: true, ..Default::default() };
+        let files = update_files(&mock_client(&transport), &staged_response(), &installer, &mut Timings::default());
+        assert!(transport.is_done());
+
+        let outcomes: Vec<_> = files.into_iter().map(|file| file.outcome).collect();
+        assert_eq!(outcomes, vec![FileOutcome::Skipped, FileOutcome::Failed(UpdateError::Download { index: 0, reason: "the server closed it after 3 of the expected 5 bytes".into() })]);
+        assert!(installer.installed.borrow().is_empty());
+    }
+
+    /// Reads downloaded files from the stream it is given, remembering how each file was installed
+    #[derive(Default)]
+    struct StreamingInstaller {
+        buffered: std::cell::RefCell<Vec<PathBuf>>,
+        streamed: std::cell::RefCell<Vec<(PathBuf, Vec<u8>, usize)>>,
+    }
+
+    impl Installer for StreamingInstaller {
+        fn should_update(&self, _: &UpdateResponse) -> bool {
+            true
+        }
+
+        fn install_file(&self, path: PathBuf, _: Vec<u8>) -> Result<(), InstallError> {
+            self.buffered.borrow_mut().push(path);
+            Ok(())
+        }
+
+        fn install_file_streamed(&self, path: PathBuf, reader: &mut dyn Read, size: usize) -> Result<(), InstallError> {
+            let mut data = vec![];
+            reader.read_to_end(&mut data).map_err(|e| InstallError::new(&path, e))?;
+            self.streamed.borrow_mut().push((path, data, size));
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_install_streamed() {
+        let data: Vec<u8> = (0..200_000).map(|i| i as u8).collect();
+        let transport = MockTransport::default().reply(PORT + 1, data.clone());
+        let response = UpdateResponse {
+            required_files: vec![
+                test_file(2, Some(&base64::encode("hi"))),
+                UpdateFile {
+                    install_location: InstallLocation::AbsolutePath("sd:/romfs.bin".into()),
+                    download_index: 1,
+                    size: data.len(),
+                    inline_data: None,
+                    sha256: None,
+                    signature: None,
+                },
+            ],
+            ..Default::default()
+        };
+
+        let installer = StreamingInstaller::default();
+        let files = update_files(&mock_client(&transport), &response, &installer, &mut Timings::default());
+        assert!(transport.is_done());
+
+        // only downloads are streamed, inline files are already in memory
+        assert!(files.iter().all(|file| file.outcome == FileOutcome::Installed));
+        assert_eq!(*installer.buffered.borrow(), vec![PathBuf::from("sd:/test.txt")]);
+        assert_eq!(*installer.streamed.borrow(), vec![(PathBuf::from("sd:/romfs.bin"), data.clone(), data.len())]);
+    }
+
+    #[test]
+    fn test_temp_download_removed() {
+        let transport = MockTransport::default().reply(PORT + 1, "hello");
+        let download = TempDownload::download(&mock_client(&transport), PORT + 1, 0, 5, |_| {}).unwrap();
+
+        assert_eq!(download.size, 5);
+        assert_eq!(std::fs::read(&download.path).unwrap(), b"hello");
+
+        let path = download.path.clone();
+        drop(download);
+        assert!(!path.exists());
+    }
+
+    /// Writes files to disk, failing on the given path
+    pub(crate) struct InterruptedInstaller {
+        pub(crate) fail_on: Option<PathBuf>,
+    }
+
+    impl Installer for InterruptedInstaller {
+        fn should_update(&self, _: &UpdateResponse) -> bool {
+            true
+        }
+
+        fn install_file(&self, path: PathBuf, buf: Vec<u8>) -> Result<(), InstallError> {
+            if self.fail_on.as_ref() == Some(&path) {
+                return Err(InstallError::other(path, "interrupted by the test"))
+            }
+            std::fs::create_dir_all(path.parent().unwrap()).map_err(|e| InstallError::new(&path, e))?;
+            std::fs::write(&path, buf).map_err(|e| InstallError::new(path, e))
+        }
+    }
+
+    pub(crate) fn hashed_response(name: &str, dir: &Path, files: &[Vec<u8>]) -> UpdateResponse {
+        UpdateResponse {
+            code: ResponseCode::Update,
+            plugin_name: format!("{}_{}", name, std::process::id()),
+            new_plugin_version: "1.0.0".into(),
+            required_files: files.iter()
+                .enumerate()
+                .map(|(i, data)| UpdateFile {
+                    install_location: InstallLocation::AbsolutePath(dir.join(format!("file{}.bin", i)).to_string_lossy().into_owned()),
+                    download_index: i as u64,
+                    size: data.len(),
+                    inline_data: None,
+                    sha256: Some(resume::sha256_of(&data[..]).unwrap()),
+                    signature: None,
+                })
+                .collect(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_response_case_collision() {
+        let response = inline_response(&[("sd:/Mods/foo.prc", "a"), ("sd:/mods/bar.prc", "b"), ("sd:/mods/FOO.prc", "c")]);
+        let collision = response_case_collision(&response).unwrap().to_string();
+        assert!(collision.contains("sd:/Mods/foo.prc") && collision.contains("sd:/mods/FOO.prc"), "{}", collision);
+
+        let response = inline_response(&[("sd:/mods/foo.prc", "a"), ("sd:/mods/bar.prc", "b")]);
+        assert_eq!(response_case_collision(&response), None);
+    }
+
+    #[test]
+    fn test_archive_case_collision() {
+        let dir = std::env::temp_dir().join(format!("skyline-update-case-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let mut builder = tar::Builder::new(vec![]);
+        for name in &["romfs/fighter/Model.nutexb", "romfs/fighter/model.nutexb"] {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(1);
+            header.set_mode(0o644);
+            header.set_cksum();
+            builder.append_data(&mut header, name, &b"x"[..]).unwrap();
+        }
+        let archive = dir.join("skins.tar");
+        std::fs::write(&archive, builder.into_inner().unwrap()).unwrap();
+
+        let error = extract(&archive, &dir.join("skins"), false).unwrap_err();
+        assert!(error.contains("romfs/fighter/Model.nutexb") && error.contains("romfs/fighter/model.nutexb"), "{}", error);
+        assert!(!dir.join("skins").exists());
+
+        assert_eq!(extract(&archive, &dir.join("skins"), true).map(|extracted| extracted.len()), Ok(2));
+
+        let _ = std::fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn test_archive_extract_to() {
+        let staging = std::env::temp_dir().join(format!("skyline-update-staging-{}", std::process::id()));
+        let (dir, files) = install_archive("extract-to", ArchiveDecision::ExtractTo(staging.clone()));
+
+        assert_eq!(files[0].outcome, FileOutcome::Installed);
+        assert_eq!(files[0].archive, Some(ArchiveDecision::ExtractTo(staging.clone())));
+        assert!(!dir.join("mods/romfs").exists());
+        assert!(!dir.join("mods/romfs.tar").exists());
+        assert_eq!(std::fs::read(staging.join("inner/test.txt")).unwrap(), b"hello");
+
+        let _ = std::fs::remove_dir_all(dir);
+        let _ = std::fs::remove_dir_all(staging);
+    }
+
+    #[test]
+    fn test_image_selection() {
+        let images: Vec<ImageEntry> = [100, 5000, 300]
+            .iter()
+            .enumerate()
+            .map(|(i, &size)| ImageEntry { download_index: 10 + i as u64, size, original_size: size })
+            .collect();
+        let indexes = |selection: ImageSelection| {
+            selection.select(&images).iter().map(|image| image.download_index).collect::<Vec<_>>()
+        };
+
+        assert_eq!(indexes(ImageSelection::None), Vec::<u64>::new());
+        assert_eq!(indexes(ImageSelection::IconOnly), vec![10]);
+        assert_eq!(indexes(ImageSelection::MaxBytes(300)), vec![10, 12]);
+        assert_eq!(indexes(ImageSelection::All), vec![10, 11, 12]);
+        assert_eq!(ImageSelection::IconOnly.select(&[]).len(), 0);
+    }
+
+    #[test]
+    fn test_response_without_inline() {
+        let response: UpdateResponse = serde_json::from_str(r#"{
+            "code": "Update", "update_plugin": true, "update_skyline": false,
+            "plugin_name": "test_plugin", "new_plugin_version": "1.0.0", "new_skyline_version": null,
+            "required_files": [{ "install_location": "sd:/test.txt", "download_index": 0, "size": 5 }]
+        }"#).unwrap();
+
+        assert!(response.required_files[0].inline_data.is_none());
+    }
+
+    fn notify_response(version: &str, homepage: Option<&str>) -> UpdateResponse {
+        UpdateResponse {
+            code: ResponseCode::NotifyOnly,
+            plugin_name: "test_plugin".into(),
+            new_plugin_version: version.into(),
+            changelog: Some("Fixed everything".into()),
+            homepage: homepage.map(String::from),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_notify_once_per_version() {
+        let dir = std::env::temp_dir().join(format!("skyline-update-notified-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let installer = RecordingInstaller::default();
+
+        assert!(notify_update(&dir, &notify_response("1.1.0", None), &installer));
+        assert!(!notify_update(&dir, &notify_response("1.1.0", None), &installer));
+        assert!(notify_update(&dir, &notify_response("1.2.0", None), &installer));
+
+        assert_eq!(*installer.notified.borrow(), vec![
+            ("1.1.0".to_string(), Some("Fixed everything".to_string())),
+            ("1.2.0".to_string(), Some("Fixed everything".to_string())),
+        ]);
+        assert!(installer.installed.borrow().is_empty());
+
+        let _ = std::fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn test_notification_message() {
+        assert_eq!(
+            notification_message(&notify_response("1.1.0", Some("https://example.com"))),
+            "Version 1.1.0 of test_plugin is available.\n\nGet it from https://example.com"
+        );
+        assert_eq!(
+            notification_message(&notify_response("1.1.0", None)),
+            "Version 1.1.0 of test_plugin is available."
+        );
+
+        let response = UpdateResponse {
+            display_name: Some("Test Plugin".into()),
+            ..notify_response("1.1.0", None)
+        };
+        assert_eq!(notification_message(&response), "Version 1.1.0 of Test Plugin is available.");
+    }
+
+    #[test]
+    fn test_skyline_mismatch() {
+        let response = UpdateResponse {
+            new_plugin_version: "2.0.0".into(),
+            skyline_requirement: Some(">=0.3.0".into()),
+            ..Default::default()
+        };
+
+        assert_eq!(
+            skyline_mismatch(&response, Some("0.2.0")).map(|mismatch| mismatch.to_string()).as_deref(),
+            Some("Version 2.0.0 requires skyline >=0.3.0, but 0.2.0 is installed")
+        );
+        assert_eq!(skyline_mismatch(&response, Some("0.3.0")), None);
+        assert_eq!(skyline_mismatch(&response, None), None);
+        assert_eq!(skyline_mismatch(&UpdateResponse::default(), Some("0.2.0")), None);
+
+        // the server compared the version the client sent
+        let flagged = UpdateResponse { update_skyline: true, ..response };
+        assert_eq!(
+            skyline_mismatch(&flagged, None).map(|mismatch| mismatch.to_string()).as_deref(),
+            Some("Version 2.0.0 requires skyline >=0.3.0")
+        );
+        assert_eq!(skyline_mismatch(&flagged, Some("0.3.0")), None);
+
+        // the update brings the skyline it needs
+        let bundled = UpdateResponse { new_skyline_version: Some("0.3.1".into()), ..flagged };
+        assert_eq!(skyline_mismatch(&bundled, Some("0.2.0")), None);
+
+        let client = UpdateClient::new("127.0.0.1".parse().unwrap()).with_skyline_version("0.2.1");
+        assert_eq!(running_skyline_version(&client, &RecordingInstaller::default()).as_deref(), Some("0.2.1"));
+        match update_request(&client, "test_plugin", "1.0.0", false, running_skyline_version(&client, &DefaultInstaller), None) {
+            Request::Update { options: Some(options), .. } => assert_eq!(options.skyline_version.as_deref(), Some("0.2.1")),
+            request => panic!("unexpected request {:?}", request),
+        }
+    }
+
+    #[test]
+    fn test_outdated_skyline_not_installed() {
+        struct Allowing(RecordingInstaller);
+
+        impl Installer for Allowing {
+            fn should_update(&self, _: &UpdateResponse) -> bool {
+                true
+            }
+
+            fn install_file(&self, path: PathBuf, buf: Vec<u8>) -> Result<(), InstallError> {
+                self.0.install_file(path, buf)
+            }
+
+            fn allow_outdated_skyline(&self) -> bool {
+                true
+            }
+        }
+
+        let name = format!("skyline_outdated_{}", std::process::id());
+        let response = UpdateResponse {
+            plugin_name: name.clone(),
+            skyline_requirement: Some(">=0.3.0".into()),
+            ..inline_response(&[("sd:/skyline_outdated.txt", "a")])
+        };
+        let client = UpdateClient::new("127.0.0.1".parse().unwrap()).with_skyline_version("0.2.0");
+
+        let installer = RecordingInstaller::default();
+        let mut report = UpdateReport::default();
+        handle_response(&client, &name, &response, &installer, &mut report, false, true);
+        report.response = Some(response.clone());
+        assert_eq!(report.status(), Ok(UpdateStatus::SkylineOutdated));
+        assert!(report.skyline.is_some());
+        assert!(installer.installed.borrow().is_empty());
+
+        // installed anyway by installers which accept the mismatch, and still reported
+        let installer = Allowing(RecordingInstaller::default());
+        let mut report = UpdateReport::default();
+        handle_response(&client, &name, &response, &installer, &mut report, false, true);
+        assert!(report.installed);
+        assert!(report.skyline.is_some());
+        assert_eq!(installer.0.installed.borrow().len(), 1);
+
+        let _ = std::fs::remove_file(changes::record_path(&changes::installed_dir(), &name));
+    }
+
+    #[test]
+    fn test_request_client_version() {
+        assert!(updater_version().starts_with(env!("CARGO_PKG_VERSION")));
+
+        let client = UpdateClient::new("127.0.0.1".parse().unwrap());
+        let packet = serde_json::to_string(&update_request(&client, "test_plugin", "1.0.0", false, None, None)).unwrap();
+        match serde_json::from_str::<Request>(&packet).unwrap() {
+            Request::Update { options: Some(options), .. } => {
+                assert_eq!(options.client_version.as_deref(), Some(updater_version()));
+                assert_eq!(options.platform, None);
+            }
+            other => panic!("unexpected request {:?}", other),
+        }
+
+        let packet = serde_json::to_string(&update_request(&client.with_platform("emulator"), "test_plugin", "1.0.0", false, None, None)).unwrap();
+        match serde_json::from_str::<Request>(&packet).unwrap() {
+            Request::Update { options: Some(options), .. } => assert_eq!(options.platform.as_deref(), Some("emulator")),
+            other => panic!("unexpected request {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_request_channel() {
+        let client = UpdateClient::new("127.0.0.1".parse().unwrap());
+        match update_request(&client, "test_plugin", "1.0.0", true, None, None) {
+            Request::Update { beta, options: Some(options), .. } => {
+                assert_eq!(beta, Some(true));
+                assert_eq!(options.channel, None);
+            }
+            other => panic!("unexpected request {:?}", other),
+        }
+
+        match update_request(&client.with_channel("nightly"), "test_plugin", "1.0.0", false, None, None) {
+            Request::Update { options: Some(options), .. } => {
+                assert_eq!(options.protocol_version, Some(update_protocol::PROTOCOL_VERSION));
+                assert_eq!(options.channel.as_deref(), Some("nightly"));
+            }
+            other => panic!("unexpected request {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_separate_download_port() {
+        use std::net::TcpListener;
+
+        let main = TcpListener::bind("127.0.0.1:0").unwrap();
+        let main_port = main.local_addr().unwrap().port();
+        let downloads = std::iter::repeat_with(|| TcpListener::bind("127.0.0.1:0").unwrap())
+            .find(|listener| listener.local_addr().unwrap().port() != main_port + 1)
+            .unwrap();
+        let download_port = downloads.local_addr().unwrap().port();
+
+        let response = UpdateResponse {
+            code: ResponseCode::Update,
+            update_plugin: true,
+            plugin_name: "test_plugin".into(),
+            new_plugin_version: "1.0.0".into(),
+            required_files: vec![UpdateFile {
+                install_location: InstallLocation::AbsolutePath("sd:/test.txt".into()),
+                download_index: 0,
+                size: 5,
+                inline_data: None,
+                sha256: None,
+                signature: None,
+            }],
+            download_port: Some(download_port),
+            ..Default::default()
+        };
+
+        let server = std::thread::spawn(move || {
+            let (mut socket, _) = main.accept().unwrap();
+            let mut request = String::new();
+            std::io::BufReader::new(&socket).read_line(&mut request).unwrap();
+            socket.write_all(serde_json::to_string(&response).unwrap().as_bytes()).unwrap();
+            drop(socket);
+
+            let (mut socket, _) = downloads.accept().unwrap();
+            let mut index = [0; 8];
+            socket.read_exact(&mut index).unwrap();
+            assert_eq!(u64::from_be_bytes(index), 0);
+            socket.write_all(b"hello").unwrap();
+        });
+
+        let installer = RecordingInstaller::default();
+        let report = check_update_report_on(&UpdateClient::new("127.0.0.1".parse().unwrap()).with_port(main_port), "test_plugin", "0.9.0", BetaPreference::No, &installer);
+        server.join().unwrap();
+
+        assert!(report.installed);
+        assert_eq!(*installer.installed.borrow(), vec![(PathBuf::from("sd:/test.txt"), b"hello".to_vec())]);
+    }
+
+    #[test]
+    fn test_custom_port() {
+        use std::net::TcpListener;
+
+        // a free port whose next port is free too, as the download port is derived from it
+        let (main, downloads) = std::iter::repeat_with(|| {
+                let main = TcpListener::bind("127.0.0.1:0").unwrap();
+                let port = main.local_addr().unwrap().port().checked_add(1)?;
+                Some((main, TcpListener::bind(("127.0.0.1", port)).ok()?))
+            })
+            .flatten()
+            .next()
+            .unwrap();
+        let client = UpdateClient::new("127.0.0.1".parse().unwrap()).with_port(main.local_addr().unwrap().port());
+        assert_eq!(client.download_port(), downloads.local_addr().unwrap().port());
+
+        let response = UpdateResponse {
+            code: ResponseCode::Update,
+            update_plugin: true,
+            plugin_name: "test_plugin".into(),
+            new_plugin_version: "1.0.0".into(),
+            required_files: vec![UpdateFile {
+                install_location: InstallLocation::AbsolutePath("sd:/test.txt".into()),
+                download_index: 3,
+                size: 5,
+                inline_data: None,
+                sha256: None,
+                signature: None,
+            }],
+            ..Default::default()
+        };
+
+        let served = response.clone();
+        let server = std::thread::spawn(move || {
+            let (mut socket, _) = main.accept().unwrap();
+            let mut request = String::new();
+            std::io::BufReader::new(&socket).read_line(&mut request).unwrap();
+            socket.write_all(serde_json::to_string(&served).unwrap().as_bytes()).unwrap();
+            drop(socket);
+
+            let (mut socket, _) = downloads.accept().unwrap();
+            let mut index = [0; 8];
+            socket.read_exact(&mut index).unwrap();
+            assert_eq!(u64::from_be_bytes(index), 3);
+            socket.write_all(b"hello").unwrap();
+        });
+
+        let info = client.get_update_info("test_plugin", "0.9.0", false).unwrap();
+        assert_eq!(info, response);
+
+        let installer = RecordingInstaller::default();
+        assert!(client.custom_install_update(&info, &installer));
+        server.join().unwrap();
+        assert_eq!(*installer.installed.borrow(), vec![(PathBuf::from("sd:/test.txt"), b"hello".to_vec())]);
+    }
+
+    #[test]
+    fn test_download_progress() {
+        use std::net::TcpListener;
+
+        let downloads = TcpListener::bind("127.0.0.1:0").unwrap();
+        let data: Vec<u8> = (0..200_000).map(|i| i as u8).collect();
+        let response = UpdateResponse {
+            required_files: vec![
+                UpdateFile {
+                    install_location: InstallLocation::AbsolutePath("sd:/small.txt".into()),
+                    download_index: 0,
+                    size: 2,
+                    inline_data: Some(base64::encode("hi")),
+                    sha256: None,
+                    signature: None,
+                },
+                UpdateFile {
+                    install_location: InstallLocation::AbsolutePath("sd:/romfs.bin".into()),
+                    download_index: 1,
+                    size: data.len(),
+                    inline_data: None,
+                    sha256: None,
+                    signature: None,
+                },
+            ],
+            download_port: Some(downloads.local_addr().unwrap().port()),
+            ..Default::default()
+        };
+
+        let sent = data.clone();
+        let server = std::thread::spawn(move || {
+            let (mut socket, _) = downloads.accept().unwrap();
+            let mut index = [0; 8];
+            socket.read_exact(&mut index).unwrap();
+            for chunk in sent.chunks(30_000) {
+                socket.write_all(chunk).unwrap();
+                socket.flush().unwrap();
+            }
+        });
+
+        let installer = RecordingInstaller::default();
+        let files = update_files(&UpdateClient::new("127.0.0.1".parse().unwrap()), &response, &installer, &mut Timings::default());
+        server.join().unwrap();
+
+        assert!(files.iter().all(|file| file.outcome == FileOutcome::Installed));
+        let progress = installer.progress.borrow();
+        assert_eq!(progress[0], (0, 2, 2));
+
+        let downloaded: Vec<_> = progress[1..].iter().map(|&(index, downloaded, total)| {
+            assert_eq!((index, total), (1, data.len()));
+            downloaded
+        }).collect();
+        assert!(downloaded.len() > 1);
+        assert!(downloaded.windows(2).all(|pair| pair[0] < pair[1]));
+        assert_eq!(downloaded.last(), Some(&data.len()));
     }
 }
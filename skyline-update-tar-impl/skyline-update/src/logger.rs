@@ -0,0 +1,29 @@
+use log::{LevelFilter, Log, Metadata, Record, SetLoggerError};
+
+/// Prints every message at or above the maximum level to stdout, as the updater did before it
+/// logged through the `log` crate
+struct StdoutLogger;
+
+impl Log for StdoutLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= log::max_level()
+    }
+
+    fn log(&self, record: &Record) {
+        if self.enabled(record.metadata()) {
+            println!("{}", record.args());
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+static LOGGER: StdoutLogger = StdoutLogger;
+
+/// Print the updater's messages up to `level` to stdout, for using the updater outside of a
+/// skyline plugin, such as from a desktop tool. Fails if a logger was already installed.
+pub fn init_logger(level: LevelFilter) -> Result<(), SetLoggerError> {
+    log::set_logger(&LOGGER)?;
+    log::set_max_level(level);
+    Ok(())
+}
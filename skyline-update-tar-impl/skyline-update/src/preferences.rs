@@ -0,0 +1,155 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+use log::{info, warn};
+use serde::Deserialize;
+
+/// Whether a plugin is offered beta versions. Functions taking one also take a `bool`, which
+/// maps to `Yes` or `No`.
+///
+/// ```no_run
+/// use skyline_update::BetaPreference;
+///
+/// skyline_update::check_update("127.0.0.1".parse().unwrap(), "plugin_name", "0.1.0", BetaPreference::FromConfig);
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BetaPreference {
+    Yes,
+    No,
+    /// Whatever the user chose in `sd:/skyline-update/preferences.toml`, for this plugin or for
+    /// every plugin. No if the file is missing or can't be parsed.
+    FromConfig,
+}
+
+impl From<bool> for BetaPreference {
+    fn from(allow_beta: bool) -> Self {
+        if allow_beta {
+            BetaPreference::Yes
+        } else {
+            BetaPreference::No
+        }
+    }
+}
+
+impl BetaPreference {
+    /// Whether `plugin` is offered betas, reading `FromConfig` from the preferences file at `path`
+    pub(crate) fn allows_beta(self, path: &Path, plugin: &str) -> bool {
+        match self {
+            BetaPreference::Yes => true,
+            BetaPreference::No => false,
+            BetaPreference::FromConfig => Preferences::read(path).map_or(false, |preferences| preferences.allows_beta(plugin)),
+        }
+    }
+}
+
+/// The preferences file, a global default overridden per plugin:
+///
+/// ```toml
+/// beta = false
+///
+/// [plugins.plugin_name]
+/// beta = true
+/// ```
+#[derive(Debug, Default, Deserialize)]
+struct Preferences {
+    beta: Option<bool>,
+    #[serde(default)]
+    plugins: HashMap<String, PluginPreferences>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct PluginPreferences {
+    beta: Option<bool>,
+}
+
+impl Preferences {
+    fn read(path: &Path) -> Option<Self> {
+        let text = match std::fs::read_to_string(path) {
+            Ok(text) => text,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                info!("[updater] No preferences at {}, offering stable versions", path.display());
+                return None
+            }
+            Err(e) => {
+                warn!("[updater] Ignoring {}, it couldn't be read: {}", path.display(), e);
+                return None
+            }
+        };
+        match toml::from_str(&text) {
+            Ok(preferences) => Some(preferences),
+            Err(e) => {
+                warn!("[updater] Ignoring {}, it couldn't be parsed: {}", path.display(), e);
+                None
+            }
+        }
+    }
+
+    fn allows_beta(&self, plugin: &str) -> bool {
+        self.plugins.get(plugin)
+            .and_then(|preferences| preferences.beta)
+            .or(self.beta)
+            .unwrap_or(false)
+    }
+}
+
+/// Where users choose how every plugin using the updater is updated
+pub(crate) fn preferences_path() -> PathBuf {
+    if cfg!(target_os = "switch") {
+        PathBuf::from("sd:/skyline-update/preferences.toml")
+    } else {
+        std::env::temp_dir().join("skyline-update/preferences.toml")
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn allows_beta(preferences: &str, plugin: &str) -> bool {
+        let dir = std::env::temp_dir().join(format!("skyline-update-preferences-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join(format!("{}.toml", plugin));
+        std::fs::write(&path, preferences).unwrap();
+
+        let allowed = BetaPreference::FromConfig.allows_beta(&path, plugin);
+        let _ = std::fs::remove_file(path);
+        allowed
+    }
+
+    #[test]
+    fn test_beta_preference() {
+        let missing = Path::new("/nonexistent/skyline-update/preferences.toml");
+        assert!(!BetaPreference::FromConfig.allows_beta(missing, "a"));
+        assert!(BetaPreference::Yes.allows_beta(missing, "a"));
+        assert!(!BetaPreference::No.allows_beta(missing, "a"));
+        assert_eq!(BetaPreference::from(true), BetaPreference::Yes);
+        assert_eq!(BetaPreference::from(false), BetaPreference::No);
+
+        assert!(!allows_beta("", "empty"));
+        assert!(!allows_beta("beta = [", "unparseable"));
+        assert!(!allows_beta("beta = \"yes\"", "wrong_type"));
+        assert!(allows_beta("beta = true", "global"));
+        assert!(!allows_beta("beta = true\n[plugins.opt_out]\nbeta = false", "opt_out"));
+        assert!(allows_beta("beta = false\n[plugins.opt_in]\nbeta = true", "opt_in"));
+        assert!(allows_beta("[plugins.only_plugin]\nbeta = true", "only_plugin"));
+        assert!(!allows_beta("[plugins.other]\nbeta = true", "not_listed"));
+        assert!(allows_beta("beta = true\n[plugins.no_override]", "no_override"));
+    }
+
+    #[test]
+    fn test_partial_preferences() {
+        // keys the updater doesn't know are left alone
+        assert!(allows_beta("beta = true\ntheme = \"dark\"\n[plugins.extra]\nbeta = true\nnotes = 1", "extra"));
+        // one malformed entry fails the whole file, rather than half of it applying
+        assert!(!allows_beta("beta = true\n[plugins.bad_plugin]\nbeta = 1", "bad_plugin"));
+        assert!(!allows_beta("beta = true\n[plugins]\nbad_table = true", "bad_table"));
+        assert!(!allows_beta("beta = true\n[plugins.cut_off", "cut_off"));
+        assert!(!allows_beta("\u{0}\u{1}garbage\u{fffd}", "garbage"));
+
+        // a folder where the file should be
+        let dir = std::env::temp_dir().join(format!("skyline-update-preferences-dir-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        assert!(!BetaPreference::FromConfig.allows_beta(&dir, "a"));
+        let _ = std::fs::remove_dir(dir);
+    }
+}
@@ -0,0 +1,137 @@
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Arc, Mutex};
+
+use update_protocol::UpdateFile;
+
+use crate::{TempDownload, UpdateClient};
+
+enum Event {
+    Progress { index: u64, downloaded: usize },
+    Finished { index: u64, download: Result<TempDownload, String> },
+}
+
+/// Downloads the files of an update ahead of installing them, several at a time, see
+/// `UpdateClient::with_max_concurrent_downloads`. Only the thread which started it reports
+/// progress, so the installer is never called from the download threads.
+pub(crate) struct Prefetch {
+    /// Every file being downloaded, by download index
+    files: HashMap<u64, UpdateFile>,
+    events: mpsc::Receiver<Event>,
+    finished: HashMap<u64, Result<TempDownload, String>>,
+    /// Set once dropped, so the download threads don't start the files left
+    stop: Arc<AtomicBool>,
+}
+
+impl Prefetch {
+    /// Start downloading `files` in order over at most `connections` connections at a time
+    pub(crate) fn start(client: &UpdateClient, port: u16, files: Vec<UpdateFile>, connections: usize) -> Self {
+        let queue = Arc::new(Mutex::new(files.clone().into_iter()));
+        let (sender, events) = mpsc::channel();
+        let stop = Arc::new(AtomicBool::new(false));
+
+        for _ in 0..connections.min(files.len()) {
+            let client = client.clone();
+            let queue = Arc::clone(&queue);
+            let sender = sender.clone();
+            let stop = Arc::clone(&stop);
+            std::thread::spawn(move || loop {
+                if stop.load(Ordering::Relaxed) {
+                    break
+                }
+                let next = queue.lock().ok().and_then(|mut queue| queue.next());
+                let file = match next {
+                    Some(file) => file,
+                    None => break,
+                };
+
+                let index = file.download_index;
+                let progress = sender.clone();
+                let download = TempDownload::download(&client, port, index, file.size, |downloaded| {
+                    let _ = progress.send(Event::Progress { index, downloaded });
+                });
+                // a failed send means the update stopped and dropped the download, deleting it
+                if sender.send(Event::Finished { index, download }).is_err() {
+                    break
+                }
+            });
+        }
+
+        Prefetch {
+            files: files.into_iter().map(|file| (file.download_index, file)).collect(),
+            events,
+            finished: HashMap::new(),
+            stop,
+        }
+    }
+
+    /// Wait for the file downloaded from `index`, calling `on_progress` for every file
+    /// downloading meanwhile. None if it isn't being downloaded, Some(Err) with why if its
+    /// download failed.
+    pub(crate) fn wait<F>(&mut self, index: u64, mut on_progress: F) -> Option<Result<TempDownload, String>>
+        where F: FnMut(&UpdateFile, usize),
+    {
+        if !self.files.contains_key(&index) {
+            return None
+        }
+
+        loop {
+            if let Some(download) = self.finished.remove(&index) {
+                self.files.remove(&index);
+                return Some(download)
+            }
+            match self.events.recv() {
+                Ok(Event::Progress { index, downloaded }) => {
+                    if let Some(file) = self.files.get(&index) {
+                        on_progress(file, downloaded);
+                    }
+                }
+                Ok(Event::Finished { index, download }) => {
+                    self.finished.insert(index, download);
+                }
+                Err(_) => return Some(Err(String::from("the download thread stopped"))),
+            }
+        }
+    }
+}
+
+impl Drop for Prefetch {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test::{hashed_response, mock_client, InterruptedInstaller};
+    use crate::transport::test::MockTransport;
+    use crate::{update_files, FileOutcome, Timings, PORT};
+
+    #[test]
+    fn test_concurrent_downloads() {
+        let sd = std::env::temp_dir().join(format!("skyline-update-concurrent-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&sd);
+        let files: Vec<Vec<u8>> = (0..6).map(|i| vec![i; 100_000]).collect();
+        let transport = MockTransport::default().serve(PORT + 1, files.clone());
+        let response = hashed_response("concurrent", &sd, &files);
+        let client = mock_client(&transport).with_max_concurrent_downloads(3);
+        assert_eq!(UpdateClient::new("127.0.0.1".parse().unwrap()).with_max_concurrent_downloads(0).max_concurrent_downloads(), 1);
+
+        let outcomes: Vec<_> = update_files(&client, &response, &InterruptedInstaller { fail_on: None }, &mut Timings::default())
+            .into_iter()
+            .map(|file| file.outcome)
+            .collect();
+        assert_eq!(outcomes, vec![FileOutcome::Installed; files.len()]);
+
+        // every file was downloaded once, and installed in order where it belongs
+        let mut requested = transport.downloads(PORT + 1);
+        requested.sort_unstable();
+        assert_eq!(requested, (0..6).collect::<Vec<u64>>());
+        for (i, data) in files.iter().enumerate() {
+            assert_eq!(&std::fs::read(sd.join(format!("file{}.bin", i))).unwrap(), data);
+        }
+
+        let _ = std::fs::remove_dir_all(&sd);
+    }
+}
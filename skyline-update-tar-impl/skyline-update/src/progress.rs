@@ -0,0 +1,198 @@
+use std::cell::RefCell;
+use std::time::{Duration, Instant};
+
+use update_protocol::{UpdateFile, UpdateResponse};
+
+/// Shortest time between two lines shown for the same file, so the web applet isn't sent a
+/// message after every read from the download socket
+const UPDATE_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Download progress of an update as shown by `DefaultInstaller`, one line such as
+/// "Downloading file 2/5 — 43%" replaced as the download goes on
+#[derive(Debug)]
+pub(crate) struct DownloadProgress {
+    /// Download index of every file of the update, to number them
+    files: Vec<u64>,
+    /// File and percentage of the line shown last, and when it was shown
+    shown: Option<(u64, usize, Instant)>,
+}
+
+impl DownloadProgress {
+    pub(crate) fn new(response: &UpdateResponse) -> Self {
+        let files = response.required_files.iter().map(|file| file.download_index).collect();
+
+        DownloadProgress { files, shown: None }
+    }
+
+    /// Line to show once `bytes_downloaded` of `file` were downloaded, None if the percentage
+    /// didn't change or the last line is younger than `UPDATE_INTERVAL`. The first line of a
+    /// file and the one completing it are always shown.
+    pub(crate) fn update(&mut self, file: &UpdateFile, bytes_downloaded: usize, total_bytes: usize, now: Instant) -> Option<String> {
+        let percent = (bytes_downloaded * 100 / total_bytes.max(1)).min(100);
+        let show = match self.shown {
+            Some((index, shown, _)) if index == file.download_index && shown == percent => false,
+            Some((index, _, at)) if index == file.download_index && percent < 100 => now.duration_since(at) >= UPDATE_INTERVAL,
+            _ => true,
+        };
+        if !show {
+            return None
+        }
+        self.shown = Some((file.download_index, percent, now));
+
+        let position = self.files.iter().position(|&index| index == file.download_index).map_or(self.files.len(), |i| i + 1);
+        Some(line(position, self.files.len(), percent))
+    }
+
+    /// Whether a line was shown since the update started
+    pub(crate) fn was_shown(&self) -> bool {
+        self.shown.is_some()
+    }
+}
+
+fn line(position: usize, count: usize, percent: usize) -> String {
+    format!("Downloading file {}/{} \u{2014} {}%", position, count, percent)
+}
+
+/// Progress shown on the console, closed once the update stopped
+#[cfg(target_os = "switch")]
+struct Shown {
+    progress: DownloadProgress,
+    page: Option<skyline_web::WebSession>,
+}
+
+#[cfg(not(target_os = "switch"))]
+type Shown = DownloadProgress;
+
+thread_local! {
+    /// Progress of the update being downloaded on this thread by `DefaultInstaller`, which has
+    /// no place of its own to keep it
+    static CURRENT: RefCell<Option<Shown>> = RefCell::new(None);
+}
+
+/// Page showing the line sent by `show`, replaced on every message
+#[cfg(target_os = "switch")]
+const PAGE: &str = r#"<!DOCTYPE html>
+<html>
+<body style="background: #000; color: #fff; font-family: sans-serif; font-size: 32px; text-align: center; padding-top: 300px;">
+<div id="progress">Downloading update...</div>
+<script>
+    window.nx.addEventListener("message", function (e) {
+        document.getElementById("progress").textContent = e.data;
+    });
+</script>
+</body>
+</html>
+"#;
+
+/// Start showing the download progress of `response` on this thread
+#[cfg(target_os = "switch")]
+pub(crate) fn start(response: &UpdateResponse) {
+    let page = skyline_web::Webpage::new()
+        .htdocs_dir("skyline-update")
+        .file("index.html", &PAGE)
+        .open_session(skyline_web::Visibility::Default)
+        .map_err(|e| log::warn!("[updater] Failed to open the download progress page: {:?}", e))
+        .ok();
+    let shown = Shown { progress: DownloadProgress::new(response), page };
+    CURRENT.with(|current| *current.borrow_mut() = Some(shown));
+}
+
+/// Show that `bytes_downloaded` of `file` were downloaded, on the page opened by `start`
+#[cfg(target_os = "switch")]
+pub(crate) fn update(file: &UpdateFile, bytes_downloaded: usize, total_bytes: usize) {
+    CURRENT.with(|current| {
+        if let Some(shown) = current.borrow_mut().as_mut() {
+            if let Some(line) = shown.progress.update(file, bytes_downloaded, total_bytes, Instant::now()) {
+                log::debug!("[updater] {}", line);
+                if let Some(page) = &shown.page {
+                    page.send(&line);
+                }
+            }
+        }
+    })
+}
+
+/// Close the page opened by `start`
+#[cfg(target_os = "switch")]
+pub(crate) fn finish() {
+    let page = CURRENT.with(|current| current.borrow_mut().take()).and_then(|shown| shown.page);
+    if let Some(page) = page {
+        page.exit();
+        page.wait_exit();
+    }
+}
+
+/// Start showing the download progress of `response` on this thread
+#[cfg(not(target_os = "switch"))]
+pub(crate) fn start(response: &UpdateResponse) {
+    CURRENT.with(|current| *current.borrow_mut() = Some(DownloadProgress::new(response)));
+}
+
+/// Show that `bytes_downloaded` of `file` were downloaded, replacing the line printed last
+#[cfg(not(target_os = "switch"))]
+pub(crate) fn update(file: &UpdateFile, bytes_downloaded: usize, total_bytes: usize) {
+    use std::io::Write;
+
+    CURRENT.with(|current| {
+        if let Some(progress) = current.borrow_mut().as_mut() {
+            if let Some(line) = progress.update(file, bytes_downloaded, total_bytes, Instant::now()) {
+                let mut stdout = std::io::stdout();
+                let _ = write!(stdout, "\r{}", line);
+                let _ = stdout.flush();
+            }
+        }
+    })
+}
+
+/// End the line printed by `update`
+#[cfg(not(target_os = "switch"))]
+pub(crate) fn finish() {
+    if CURRENT.with(|current| current.borrow_mut().take()).map_or(false, |progress| progress.was_shown()) {
+        println!();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use update_protocol::InstallLocation;
+
+    fn response(count: u64) -> UpdateResponse {
+        UpdateResponse {
+            required_files: (0..count)
+                .map(|i| UpdateFile {
+                    install_location: InstallLocation::AbsolutePath(format!("sd:/{}.txt", i)),
+                    download_index: i + 10,
+                    size: 1000,
+                    inline_data: None,
+                    sha256: None,
+                    signature: None,
+                })
+                .collect(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_progress_lines() {
+        let response = response(5);
+        let mut progress = DownloadProgress::new(&response);
+        let file = &response.required_files[1];
+        let start = Instant::now();
+        assert!(!progress.was_shown());
+
+        assert_eq!(progress.update(file, 0, 1000, start).as_deref(), Some("Downloading file 2/5 \u{2014} 0%"));
+        assert!(progress.was_shown());
+
+        // throttled until `UPDATE_INTERVAL` passed, and never repeated
+        assert_eq!(progress.update(file, 430, 1000, start + Duration::from_millis(100)), None);
+        let later = start + UPDATE_INTERVAL;
+        assert_eq!(progress.update(file, 430, 1000, later).as_deref(), Some("Downloading file 2/5 \u{2014} 43%"));
+        assert_eq!(progress.update(file, 435, 1000, later + UPDATE_INTERVAL), None);
+
+        // completing a file and starting the next one are shown right away
+        assert_eq!(progress.update(file, 1000, 1000, later).as_deref(), Some("Downloading file 2/5 \u{2014} 100%"));
+        let next = &response.required_files[2];
+        assert_eq!(progress.update(next, 10, 1000, later).as_deref(), Some("Downloading file 3/5 \u{2014} 1%"));
+    }
+}
@@ -0,0 +1,282 @@
+use std::fmt;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+use log::warn;
+use sha2::{Digest, Sha256};
+use update_protocol::{UpdateFile, UpdateResponse};
+
+use crate::changes::record_path;
+use crate::storage::install_path;
+
+/// The files of an update which were fully installed, so an attempt at the same version after
+/// an interrupted one only fetches the rest
+#[derive(Debug, Clone, Default, PartialEq)]
+pub(crate) struct Progress {
+    pub version: String,
+    /// `(sha256, normalized install path)` of every file installed so far
+    pub files: Vec<(String, String)>,
+}
+
+impl Progress {
+    pub fn new(response: &UpdateResponse) -> Self {
+        Progress { version: response.new_plugin_version.clone(), files: vec![] }
+    }
+
+    /// Parse a record written by `to_string`, the version followed by one `<sha256> <path>`
+    /// per line
+    pub fn parse(text: &str) -> Option<Self> {
+        let mut lines = text.lines();
+        let version = lines.next()?.trim().to_owned();
+        let files = lines
+            .filter_map(|line| {
+                let mut parts = line.splitn(2, ' ');
+                Some((parts.next()?.to_owned(), parts.next()?.to_owned()))
+            })
+            .collect();
+
+        Some(Progress { version, files })
+    }
+
+    /// Whether the record is of the update offered by `response`: the same version, with
+    /// every recorded file still part of it with the same hash
+    pub fn matches(&self, response: &UpdateResponse) -> bool {
+        self.version == response.new_plugin_version && self.files.iter().all(|(sha256, path)| {
+            response.required_files.iter().any(|file| {
+                file.sha256.as_ref() == Some(sha256) && install_path(file).as_ref() == Some(path)
+            })
+        })
+    }
+
+    /// Whether `file` was recorded as installed with the same hash
+    pub fn contains(&self, file: &UpdateFile) -> bool {
+        match (file.sha256.as_ref(), install_path(file)) {
+            (Some(sha256), Some(path)) => self.files.iter().any(|recorded| recorded.0 == *sha256 && recorded.1 == path),
+            _ => false,
+        }
+    }
+
+    /// Record `file` as installed, files without a hash can't be checked later and aren't
+    pub fn push(&mut self, file: &UpdateFile) -> bool {
+        match (file.sha256.as_ref(), install_path(file)) {
+            (Some(sha256), Some(path)) => {
+                if !self.contains(file) {
+                    self.files.push((sha256.clone(), path));
+                }
+                true
+            }
+            _ => false,
+        }
+    }
+}
+
+impl fmt::Display for Progress {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "{}", self.version)?;
+        for (sha256, path) in &self.files {
+            writeln!(f, "{} {}", sha256, path)?;
+        }
+        Ok(())
+    }
+}
+
+/// Directory holding the progress of updates which didn't finish, next to the downloads
+pub(crate) fn progress_dir() -> PathBuf {
+    if cfg!(target_os = "switch") {
+        PathBuf::from("sd:/skyline-update/progress")
+    } else {
+        std::env::temp_dir().join("skyline-update/progress")
+    }
+}
+
+pub(crate) fn load_progress(dir: &Path, plugin_name: &str) -> Option<Progress> {
+    std::fs::read_to_string(record_path(dir, plugin_name))
+        .ok()
+        .and_then(|text| Progress::parse(&text))
+}
+
+pub(crate) fn save_progress(dir: &Path, plugin_name: &str, progress: &Progress) {
+    let result = std::fs::create_dir_all(dir)
+        .and_then(|_| std::fs::write(record_path(dir, plugin_name), progress.to_string()));
+    if result.is_err() {
+        warn!("[updater] Failed to record update progress of {}", plugin_name);
+    }
+}
+
+pub(crate) fn clear_progress(dir: &Path, plugin_name: &str) {
+    let _ = std::fs::remove_file(record_path(dir, plugin_name));
+}
+
+/// Lowercase hex SHA-256 of everything `reader` returns
+pub(crate) fn sha256_of<R: Read>(mut reader: R) -> std::io::Result<String> {
+    let mut hasher = Sha256::new();
+    std::io::copy(&mut reader, &mut hasher)?;
+
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+/// Hashes everything written through it, so a download is hashed as it is stored rather than
+/// read back from the SD card
+pub(crate) struct HashingWriter<W> {
+    inner: W,
+    hasher: Sha256,
+}
+
+impl<W: std::io::Write> HashingWriter<W> {
+    pub(crate) fn new(inner: W) -> Self {
+        HashingWriter { inner, hasher: Sha256::new() }
+    }
+
+    /// Lowercase hex SHA-256 of everything written
+    pub(crate) fn finish(self) -> String {
+        format!("{:x}", self.hasher.finalize())
+    }
+}
+
+impl<W: std::io::Write> std::io::Write for HashingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let len = self.inner.write(buf)?;
+        self.hasher.update(&buf[..len]);
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use update_protocol::InstallLocation;
+    use crate::test::{hashed_response, mock_client, InterruptedInstaller};
+    use crate::transport::test::MockTransport;
+    use crate::{already_installed, update_files, FileOutcome, Timings, UpdateError, PORT};
+
+    fn response(version: &str, files: &[(&str, &str)]) -> UpdateResponse {
+        UpdateResponse {
+            new_plugin_version: version.into(),
+            required_files: files.iter()
+                .enumerate()
+                .map(|(i, &(path, sha256))| UpdateFile {
+                    install_location: InstallLocation::AbsolutePath(path.into()),
+                    download_index: i as u64,
+                    size: 1,
+                    inline_data: None,
+                    sha256: Some(sha256.into()),
+                    signature: None,
+                })
+                .collect(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_progress_roundtrip() {
+        let response = response("1.0.0", &[("sd:/a.txt", "aa"), ("sd:/dir with spaces/b.txt", "bb")]);
+        let mut progress = Progress::new(&response);
+        assert!(progress.push(&response.required_files[0]));
+        assert!(progress.push(&response.required_files[1]));
+
+        let parsed = Progress::parse(&progress.to_string()).unwrap();
+        assert_eq!(parsed, progress);
+        assert!(parsed.contains(&response.required_files[1]));
+    }
+
+    #[test]
+    fn test_progress_invalidated() {
+        let installed = response("1.0.0", &[("sd:/a.txt", "aa"), ("sd:/b.txt", "bb")]);
+        let mut progress = Progress::new(&installed);
+        progress.push(&installed.required_files[0]);
+
+        assert!(progress.matches(&response("1.0.0", &[("sd:/a.txt", "aa"), ("sd:/b.txt", "cc")])));
+        // another version, or a recorded file which changed or is gone
+        assert!(!progress.matches(&response("1.0.1", &[("sd:/a.txt", "aa"), ("sd:/b.txt", "bb")])));
+        assert!(!progress.matches(&response("1.0.0", &[("sd:/a.txt", "ab"), ("sd:/b.txt", "bb")])));
+        assert!(!progress.matches(&response("1.0.0", &[("sd:/b.txt", "bb")])));
+
+        // without a hash there's nothing to check against later
+        let mut unhashed = installed.required_files[0].clone();
+        unhashed.sha256 = None;
+        assert!(!progress.push(&unhashed));
+        assert!(!progress.contains(&unhashed));
+    }
+
+    #[test]
+    fn test_hashing_writer() {
+        let mut out = vec![];
+        let mut writer = HashingWriter::new(&mut out);
+        std::io::Write::write_all(&mut writer, b"hel").unwrap();
+        std::io::Write::write_all(&mut writer, b"lo").unwrap();
+
+        assert_eq!(writer.finish(), sha256_of(&b"hello"[..]).unwrap());
+        assert_eq!(out, b"hello");
+    }
+
+    #[test]
+    fn test_sha256_of() {
+        assert_eq!(
+            sha256_of(&b"hello"[..]).unwrap(),
+            "2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824"
+        );
+    }
+
+    #[test]
+    fn test_resume_interrupted_update() {
+        let sd = std::env::temp_dir().join(format!("skyline-update-resume-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&sd);
+        let files: Vec<Vec<u8>> = (0..12).map(|i| vec![i; 1000]).collect();
+        let transport = MockTransport::default().serve(PORT + 1, files.clone());
+        let response = hashed_response("resume", &sd, &files);
+        // one download at a time, as downloads ahead would fetch files after the one failing
+        let client = mock_client(&transport);
+        let outcomes = |installer: &InterruptedInstaller| -> Vec<FileOutcome> {
+            update_files(&client, &response, installer, &mut Timings::default())
+                .into_iter()
+                .map(|file| file.outcome)
+                .collect()
+        };
+
+        // the 9th file fails to install
+        let first = outcomes(&InterruptedInstaller { fail_on: Some(sd.join("file8.bin")) });
+        assert_eq!(&first[..8], &vec![FileOutcome::Installed; 8][..]);
+        assert!(matches!(first[8], FileOutcome::Failed(UpdateError::Install { .. })));
+
+        let second = outcomes(&InterruptedInstaller { fail_on: None });
+        assert_eq!(&second[..8], &vec![FileOutcome::AlreadyInstalled; 8][..]);
+        assert_eq!(&second[8..], &vec![FileOutcome::Installed; 4][..]);
+
+        // only the files from the 9th on were downloaded again
+        assert_eq!(transport.downloads(PORT + 1), (0..9).chain(8..12).collect::<Vec<u64>>());
+        for (i, data) in files.iter().enumerate() {
+            assert_eq!(&std::fs::read(sd.join(format!("file{}.bin", i))).unwrap(), data);
+        }
+        assert_eq!(load_progress(&progress_dir(), &response.plugin_name), None);
+
+        let _ = std::fs::remove_dir_all(&sd);
+    }
+
+    #[test]
+    fn test_resume_checks_installed_files() {
+        let sd = std::env::temp_dir().join(format!("skyline-update-resume-changed-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&sd);
+        std::fs::create_dir_all(&sd).unwrap();
+        let files = vec![b"first".to_vec(), b"second".to_vec()];
+        let response = hashed_response("resume_changed", &sd, &files);
+        let installer = InterruptedInstaller { fail_on: None };
+
+        let mut progress = Progress::new(&response);
+        progress.push(&response.required_files[0]);
+        progress.push(&response.required_files[1]);
+        std::fs::write(sd.join("file0.bin"), b"first").unwrap();
+        std::fs::write(sd.join("file1.bin"), b"changed since").unwrap();
+
+        // recorded, but changed or removed on disk since
+        assert!(already_installed(&progress, &response.required_files[0], &installer));
+        assert!(!already_installed(&progress, &response.required_files[1], &installer));
+        std::fs::remove_file(sd.join("file0.bin")).unwrap();
+        assert!(!already_installed(&progress, &response.required_files[0], &installer));
+
+        let _ = std::fs::remove_dir_all(&sd);
+    }
+}
@@ -0,0 +1,121 @@
+use std::fs;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+use log::warn;
+
+use crate::{transaction, ArchiveDecision, InstallError, Installer, UpdateFile, UpdateResponse};
+
+/// Installs into a directory standing in for the SD card, `sd:/a/b` ends up at `root/a/b`.
+/// Every update is accepted, and rolled back if a file fails. Useful to try out an update
+/// server from a PC.
+///
+/// ```no_run
+/// let installer = skyline_update::SandboxInstaller::new(std::env::temp_dir().join("sd"));
+/// skyline_update::custom_check_update("127.0.0.1".parse().unwrap(), "plugin_name", "0.1.0", false, &installer);
+/// ```
+#[derive(Debug, Clone)]
+pub struct SandboxInstaller {
+    root: PathBuf,
+}
+
+impl SandboxInstaller {
+    pub fn new(root: impl Into<PathBuf>) -> Self {
+        SandboxInstaller { root: root.into() }
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    /// Where a file the console would install at `location` is installed in the sandbox
+    pub fn path(&self, location: &Path) -> PathBuf {
+        let location = location.to_string_lossy().replace('\\', "/");
+        let relative = match location.find(":/") {
+            Some(idx) => &location[idx + 2..],
+            None => &location[..],
+        };
+
+        self.root.join(relative.trim_start_matches('/'))
+    }
+
+    /// Sandbox path of `location`, with its directories created and the file it replaces
+    /// backed up
+    fn prepare(&self, location: &Path) -> Result<PathBuf, InstallError> {
+        let path = self.path(location);
+        let parent = path.parent().ok_or_else(|| InstallError::other(&path, "it has no parent directory"))?;
+        fs::create_dir_all(parent).map_err(|e| InstallError::new(&path, e).with_context("creating its directory"))?;
+        transaction::backup(&path).map_err(|e| InstallError::new(&path, e).with_context("backing up the file it replaces"))?;
+        Ok(path)
+    }
+}
+
+impl Installer for SandboxInstaller {
+    fn should_update(&self, _: &UpdateResponse) -> bool {
+        true
+    }
+
+    fn install_file(&self, path: PathBuf, buf: Vec<u8>) -> Result<(), InstallError> {
+        let path = self.prepare(&path)?;
+        fs::write(&path, buf).map_err(|e| InstallError::new(path, e))
+    }
+
+    fn install_file_streamed(&self, path: PathBuf, reader: &mut dyn Read, _size: usize) -> Result<(), InstallError> {
+        let path = self.prepare(&path)?;
+        fs::File::create(&path)
+            .and_then(|mut file| std::io::copy(reader, &mut file))
+            .map(drop)
+            .map_err(|e| InstallError::new(path, e))
+    }
+
+    fn installed_sha256(&self, path: &Path) -> Option<String> {
+        let file = fs::File::open(self.path(path)).ok()?;
+        crate::resume::sha256_of(std::io::BufReader::new(file)).ok()
+    }
+
+    /// The archive only exists inside the sandbox, so it is extracted here instead of by the client
+    fn handle_archive(&self, _: &UpdateFile, path: &Path) -> ArchiveDecision {
+        let archive = self.path(path);
+        if let Err(e) = crate::extract(&archive, &archive.with_extension(""), self.allow_case_collisions()) {
+            warn!("[updater] {}", e);
+        }
+
+        ArchiveDecision::LeaveArchived
+    }
+
+    fn begin_transaction(&self, response: &UpdateResponse) {
+        transaction::begin(&response.plugin_name);
+    }
+
+    fn commit(&self, _response: &UpdateResponse) {
+        transaction::commit();
+    }
+
+    fn rollback(&self, _response: &UpdateResponse) -> bool {
+        transaction::rollback()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_sandbox_path() {
+        let installer = SandboxInstaller::new("/sandbox");
+
+        assert_eq!(installer.path(Path::new("sd:/atmosphere/test.nro")), Path::new("/sandbox/atmosphere/test.nro"));
+        assert_eq!(installer.path(Path::new("rom:/data.bin")), Path::new("/sandbox/data.bin"));
+    }
+
+    #[test]
+    fn test_sandbox_streamed() {
+        let installer = SandboxInstaller::new(std::env::temp_dir().join(format!("skyline-update-sandbox-{}", std::process::id())));
+        let _ = fs::remove_dir_all(installer.root());
+
+        installer.install_file_streamed("sd:/plugin/a.txt".into(), &mut &b"streamed"[..], 8).unwrap();
+        assert_eq!(fs::read(installer.root().join("plugin/a.txt")).unwrap(), b"streamed");
+
+        let _ = fs::remove_dir_all(installer.root());
+    }
+}
@@ -0,0 +1,242 @@
+use std::convert::TryFrom;
+
+use ed25519_dalek::{PublicKey, Signature};
+use log::warn;
+use update_protocol::{UpdateFile, UpdateResponse};
+
+use crate::UpdateError;
+
+/// Check that every file of `response` was signed with the private key of `public_key`, before
+/// any of it is downloaded. Each signature covers the plugin, its version, where the file is
+/// installed, its SHA-256 and the files the update deletes (see `UpdateResponse::signed_message`),
+/// and the SHA-256 is checked against the file once downloaded, so a file the plugin author
+/// didn't sign is never installed, nor a signed one installed anywhere else.
+pub(crate) fn verify_response(response: &UpdateResponse, public_key: &[u8; 32]) -> Result<(), UpdateError> {
+    let key = PublicKey::from_bytes(public_key);
+
+    for file in &response.required_files {
+        let verified = key.as_ref()
+            .map_err(|e| format!("the public key is invalid: {}", e))
+            .and_then(|key| verify_file(key, response, file));
+        if let Err(reason) = verified {
+            warn!("[{} updater] Refusing to install update: file {} {}", response.plugin_name, file.download_index, reason);
+            return Err(UpdateError::Signature { index: file.download_index, reason })
+        }
+    }
+
+    Ok(())
+}
+
+fn verify_file(key: &PublicKey, response: &UpdateResponse, file: &UpdateFile) -> Result<(), String> {
+    let (message, signature) = match (response.signed_message(file), &file.signature) {
+        (Some(message), Some(signature)) => (message, signature),
+        (None, _) => return Err(String::from("has no hash to check its signature against")),
+        (_, None) => return Err(String::from("is not signed")),
+    };
+
+    let signature = base64::decode(signature)
+        .ok()
+        .and_then(|signature| Signature::try_from(&signature[..]).ok())
+        .ok_or_else(|| String::from("has a malformed signature"))?;
+
+    key.verify_strict(message.as_bytes(), &signature)
+        .map_err(|_| String::from("has a signature which doesn't match the public key"))
+}
+
+#[cfg(test)]
+pub(crate) mod test {
+    use super::*;
+    use std::path::PathBuf;
+    use ed25519_dalek::{Keypair, SecretKey, Signer};
+    use update_protocol::InstallLocation;
+    use crate::test::{download_response, inline_response, mock_client, test_file, RecordingInstaller};
+    use crate::transport::test::MockTransport;
+    use crate::{changes, resume, update, UpdateClient, PORT};
+
+    /// The key pair tests sign with
+    pub(crate) fn keypair() -> Keypair {
+        let secret = SecretKey::from_bytes(&[7; 32]).unwrap();
+        let public = PublicKey::from(&secret);
+        Keypair { secret, public }
+    }
+
+    /// Sign every file of `response` as a server given `keypair()` would
+    pub(crate) fn sign(response: &mut UpdateResponse) {
+        let messages: Vec<_> = response.required_files.iter().map(|file| response.signed_message(file).unwrap()).collect();
+        for (file, message) in response.required_files.iter_mut().zip(messages) {
+            file.signature = Some(base64::encode(&keypair().sign(message.as_bytes()).to_bytes()[..]));
+        }
+    }
+
+    fn response() -> UpdateResponse {
+        UpdateResponse {
+            plugin_name: "test_plugin".into(),
+            required_files: vec![UpdateFile {
+                install_location: InstallLocation::AbsolutePath("sd:/test.txt".into()),
+                download_index: 0,
+                size: 2,
+                inline_data: None,
+                sha256: Some("ab".repeat(32)),
+                signature: None,
+            }],
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_verify_response() {
+        let public_key = keypair().public.to_bytes();
+        let mut signed = response();
+        sign(&mut signed);
+        assert_eq!(verify_response(&signed, &public_key), Ok(()));
+
+        // signed by someone else
+        let other = SecretKey::from_bytes(&[8; 32]).unwrap();
+        assert!(matches!(verify_response(&signed, &PublicKey::from(&other).to_bytes()), Err(UpdateError::Signature { index: 0, .. })));
+
+        // a hash swapped after signing
+        let mut swapped = signed.clone();
+        swapped.required_files[0].sha256 = Some("cd".repeat(32));
+        assert!(verify_response(&swapped, &public_key).is_err());
+
+        // a signed file redirected elsewhere on the SD card
+        let mut moved = signed.clone();
+        moved.required_files[0].install_location = InstallLocation::AbsolutePath("sd:/atmosphere/package3".into());
+        assert!(matches!(verify_response(&moved, &public_key), Err(UpdateError::Signature { index: 0, .. })));
+
+        // files to delete added to a signed update
+        let mut deleting = signed.clone();
+        deleting.files_to_delete.push("sd:/atmosphere/contents".into());
+        assert!(matches!(verify_response(&deleting, &public_key), Err(UpdateError::Signature { index: 0, .. })));
+
+        // a signature carried over to another plugin or version
+        let mut renamed = signed.clone();
+        renamed.plugin_name = "other_plugin".into();
+        assert!(verify_response(&renamed, &public_key).is_err());
+        let mut downgraded = signed;
+        downgraded.new_plugin_version = "0.1.0".into();
+        assert!(verify_response(&downgraded, &public_key).is_err());
+    }
+
+    #[test]
+    fn test_unsigned_response() {
+        let public_key = keypair().public.to_bytes();
+        assert!(matches!(verify_response(&response(), &public_key), Err(UpdateError::Signature { index: 0, .. })));
+
+        let mut unhashed = response();
+        sign(&mut unhashed);
+        unhashed.required_files[0].sha256 = None;
+        assert!(verify_response(&unhashed, &public_key).is_err());
+
+        let mut malformed = response();
+        malformed.required_files[0].signature = Some(String::from("not base64!"));
+        assert!(verify_response(&malformed, &public_key).is_err());
+    }
+
+    #[test]
+    fn test_signed_update() {
+        let public_key = keypair().public.to_bytes();
+        let transport = MockTransport::default().reply(PORT + 1, "hello").reply(PORT + 1, "hellx");
+        let client = mock_client(&transport).with_public_key(&public_key);
+        let mut response = inline_response(&[("sd:/test.txt", "")]);
+        response.required_files[0] = UpdateFile { sha256: Some(resume::sha256_of(&b"hello"[..]).unwrap()), ..test_file(5, None) };
+        sign(&mut response);
+
+        let installer = RecordingInstaller::default();
+        let report = update(&client, &response, &installer);
+        assert!(report.installed, "{:?}", report.error);
+        assert_eq!(installer.installed.borrow()[0].1, b"hello");
+
+        // a file swapped on the server no longer matches the signed hash
+        let installer = RecordingInstaller::default();
+        let report = update(&client, &response, &installer);
+        assert!(transport.is_done());
+        assert!(matches!(report.error, Some(UpdateError::Checksum { index: 0, .. })), "{:?}", report.error);
+        assert!(installer.installed.borrow().is_empty());
+    }
+
+    #[test]
+    fn test_mock_signed_check() {
+        let name = format!("mock_signed_{}", std::process::id());
+        let public_key = keypair().public.to_bytes();
+        let check = |response: &UpdateResponse, download: Option<&str>| {
+            let mut transport = MockTransport::default().respond(PORT, response);
+            if let Some(data) = download {
+                transport = transport.reply(PORT + 1, data);
+            }
+            let installer = RecordingInstaller::default();
+            let report = mock_client(&transport).with_public_key(&public_key).custom_check_update_report(&name, "1.0.0", false, &installer);
+            let installed = installer.installed.borrow().clone();
+            (report, installed, transport.is_done())
+        };
+
+        // unsigned, refused before anyone is asked or anything is downloaded
+        let unsigned = download_response(&name, &[("sd:/a.txt", "hello")]);
+        let (report, installed, done) = check(&unsigned, Some("hello"));
+        assert!(matches!(report.error, Some(UpdateError::Signature { index: 0, .. })), "{:?}", report.error);
+        assert!(installed.is_empty());
+        assert!(!done);
+
+        // a signature moved onto another file's hash
+        let mut tampered = unsigned.clone();
+        sign(&mut tampered);
+        tampered.required_files[0].sha256 = Some(resume::sha256_of(&b"hellx"[..]).unwrap());
+        let (report, installed, _) = check(&tampered, Some("hellx"));
+        assert!(matches!(report.error, Some(UpdateError::Signature { index: 0, .. })), "{:?}", report.error);
+        assert!(installed.is_empty());
+
+        let mut signed = unsigned.clone();
+        sign(&mut signed);
+        let (report, installed, done) = check(&signed, Some("hello"));
+        assert!(report.installed, "{:?}", report.error);
+        assert_eq!(installed, vec![(PathBuf::from("sd:/a.txt"), b"hello".to_vec())]);
+        assert!(done);
+
+        let _ = std::fs::remove_file(changes::record_path(&changes::installed_dir(), &name));
+    }
+
+    #[test]
+    fn test_signed_relative_check() {
+        let name = format!("mock_signed_relative_{}", std::process::id());
+        let public_key = keypair().public.to_bytes();
+        // signed as the server sends them, before the client resolves or normalizes them
+        let mut response = download_response(&name, &[("", "hello"), ("sd:\\b//c.txt", "world")]);
+        response.required_files[0].install_location = InstallLocation::Relative("romfs/a.txt".into());
+        sign(&mut response);
+        let transport = MockTransport::default()
+            .respond(PORT, &response)
+            .reply(PORT + 1, "hello")
+            .reply(PORT + 1, "world");
+
+        let installer = RecordingInstaller { install_root: Some("sd:/switch/homebrew".into()), ..Default::default() };
+        let report = mock_client(&transport).with_public_key(&public_key).custom_check_update_report(&name, "1.0.0", false, &installer);
+        assert!(report.installed, "{:?}", report.error);
+        assert_eq!(*installer.installed.borrow(), vec![
+            (PathBuf::from("sd:/switch/homebrew/romfs/a.txt"), b"hello".to_vec()),
+            (PathBuf::from("sd:/b/c.txt"), b"world".to_vec()),
+        ]);
+        assert!(transport.is_done());
+
+        let _ = std::fs::remove_file(changes::record_path(&changes::installed_dir(), &name));
+    }
+
+    #[test]
+    fn test_unsigned_update() {
+        let public_key = keypair().public.to_bytes();
+        let client = UpdateClient::new("127.0.0.1".parse().unwrap()).with_public_key(&public_key);
+
+        // refused before anything is downloaded, so no server is needed
+        let mut response = inline_response(&[("sd:/test.txt", "hello")]);
+        response.required_files[0].sha256 = Some(resume::sha256_of(&b"hello"[..]).unwrap());
+        let installer = RecordingInstaller::default();
+        let report = update(&client, &response, &installer);
+        assert!(matches!(report.error, Some(UpdateError::Signature { index: 0, .. })), "{:?}", report.error);
+        assert!(!report.installed);
+        assert!(report.files.is_empty());
+        assert!(installer.installed.borrow().is_empty());
+
+        // clients without a key install it as before
+        let report = update(&UpdateClient::new("127.0.0.1".parse().unwrap()), &response, &installer);
+        assert!(report.installed, "{:?}", report.error);
+    }
+}
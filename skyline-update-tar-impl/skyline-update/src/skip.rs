@@ -0,0 +1,157 @@
+use std::path::{Path, PathBuf};
+
+use log::warn;
+use serde::{Deserialize, Serialize};
+use update_protocol::UpdateResponse;
+
+use crate::changes::record_path;
+
+/// Whether to install an update, see `Installer::update_choice`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UpdateChoice {
+    Install,
+    /// Ask again on the next check
+    NotNow,
+    /// Don't ask about this version again, only about newer ones
+    SkipVersion,
+}
+
+/// What is remembered about a plugin whose update was skipped:
+///
+/// ```toml
+/// skipped_version = "1.2.0"
+/// ```
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct SkipState {
+    skipped_version: Option<String>,
+}
+
+/// Directory remembering the version of each plugin the user chose to skip
+pub(crate) fn skipped_dir() -> PathBuf {
+    if cfg!(target_os = "switch") {
+        PathBuf::from("sd:/skyline-update/skipped")
+    } else {
+        std::env::temp_dir().join("skyline-update/skipped")
+    }
+}
+
+/// The version of a plugin the user chose to skip. A file which can't be parsed is logged and
+/// otherwise ignored, the user is asked again and it is overwritten if they skip again.
+pub(crate) fn skipped_version(dir: &Path, plugin_name: &str) -> Option<String> {
+    let path = record_path(dir, plugin_name);
+    let text = std::fs::read_to_string(&path).ok()?;
+    match toml::from_str::<SkipState>(&text) {
+        Ok(state) => state.skipped_version,
+        Err(e) => {
+            warn!("[updater] Ignoring {}, it couldn't be parsed: {}", path.display(), e);
+            None
+        }
+    }
+}
+
+/// Whether `response` offers the version of the plugin the user chose to skip
+pub(crate) fn is_skipped(dir: &Path, response: &UpdateResponse) -> bool {
+    skipped_version(dir, &response.plugin_name).map_or(false, |version| version == response.new_plugin_version)
+}
+
+/// Remember that the user doesn't want to be asked about the version `response` offers
+pub(crate) fn skip_version(dir: &Path, response: &UpdateResponse) {
+    let state = SkipState { skipped_version: Some(response.new_plugin_version.clone()) };
+    let written = toml::to_string(&state)
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))
+        .and_then(|text| std::fs::create_dir_all(dir).and_then(|_| std::fs::write(record_path(dir, &response.plugin_name), text)));
+    if written.is_err() {
+        warn!("[updater] Failed to remember the skipped version of {}", response.plugin_name);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test::{mock_client, versioned_response};
+    use crate::transport::test::MockTransport;
+    use crate::{InstallError, Installer, UpdatePrompt, UpdateStatus, PORT};
+
+    fn response(plugin_name: &str, version: &str) -> UpdateResponse {
+        UpdateResponse {
+            plugin_name: plugin_name.into(),
+            new_plugin_version: version.into(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_skip_version() {
+        let dir = std::env::temp_dir().join(format!("skyline-update-skip-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+
+        assert!(!is_skipped(&dir, &response("plugin", "1.1.0")));
+        skip_version(&dir, &response("plugin", "1.1.0"));
+        assert!(is_skipped(&dir, &response("plugin", "1.1.0")));
+        // newer versions and other plugins are still asked about
+        assert!(!is_skipped(&dir, &response("plugin", "1.2.0")));
+        assert!(!is_skipped(&dir, &response("other", "1.1.0")));
+
+        let _ = std::fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn test_skip_corrupted() {
+        let dir = std::env::temp_dir().join(format!("skyline-update-skip-corrupted-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+
+        std::fs::write(record_path(&dir, "plugin"), b"skipped_version = [\0\xff").unwrap();
+        assert_eq!(skipped_version(&dir, "plugin"), None);
+        assert!(!is_skipped(&dir, &response("plugin", "1.1.0")));
+
+        // skipping again replaces it
+        skip_version(&dir, &response("plugin", "1.1.0"));
+        assert_eq!(skipped_version(&dir, "plugin"), Some("1.1.0".into()));
+
+        let _ = std::fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn test_skip_version_choice() {
+        struct SkippingInstaller {
+            asked: std::cell::Cell<usize>,
+        }
+
+        impl Installer for SkippingInstaller {
+            fn should_update(&self, _: &UpdateResponse) -> bool {
+                unreachable!()
+            }
+
+            fn update_choice(&self, _: &UpdatePrompt) -> UpdateChoice {
+                self.asked.set(self.asked.get() + 1);
+                UpdateChoice::SkipVersion
+            }
+
+            fn install_file(&self, _: PathBuf, _: Vec<u8>) -> Result<(), InstallError> {
+                unreachable!()
+            }
+        }
+
+        let name = format!("skip_{}", std::process::id());
+        // sent along, so no metadata is asked for
+        let offer = |version| UpdateResponse { changelog: Some(String::new()), ..versioned_response(&name, version, None) };
+        let transport = MockTransport::default()
+            .respond(PORT, &offer("1.1.0"))
+            .respond(PORT, &offer("1.1.0"))
+            .respond(PORT, &offer("1.2.0"));
+        let client = mock_client(&transport);
+        let installer = SkippingInstaller { asked: std::cell::Cell::new(0) };
+
+        // skipping declines this time, and the same version isn't asked about again
+        assert_eq!(client.try_custom_check_update(&name, "1.0.0", false, &installer), Ok(UpdateStatus::Declined));
+        assert_eq!(client.try_custom_check_update(&name, "1.0.0", false, &installer), Ok(UpdateStatus::UpToDate));
+        assert_eq!(installer.asked.get(), 1);
+
+        // a newer version is
+        assert_eq!(client.try_custom_check_update(&name, "1.0.0", false, &installer), Ok(UpdateStatus::Declined));
+        assert_eq!(installer.asked.get(), 2);
+        assert!(transport.is_done());
+
+        let _ = std::fs::remove_file(record_path(&skipped_dir(), &name));
+    }
+}
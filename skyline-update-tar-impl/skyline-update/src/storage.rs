@@ -0,0 +1,237 @@
+use std::fmt;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+
+use update_protocol::{UpdateResponse, UpdateFile, InstallLocation};
+
+/// Storage used by the files of an update before and after installing it, in bytes
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct StorageEstimate {
+    pub before: u64,
+    pub after: u64,
+}
+
+impl fmt::Display for StorageEstimate {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} -> {}", format_size(self.before), format_size(self.after))
+    }
+}
+
+pub fn format_size(bytes: u64) -> String {
+    const UNITS: [&str; 4] = ["KB", "MB", "GB", "TB"];
+
+    if bytes < 1024 {
+        return format!("{} B", bytes)
+    }
+
+    let mut size = bytes as f64 / 1024.0;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+
+    format!("{:.1} {}", size, UNITS[unit])
+}
+
+/// Whether the file at `path` is a tar or zip archive, going by its extension in any case
+/// (`.tar`, `.TAR`, `.zip`). Paths without an extension aren't archives.
+pub(crate) fn is_archive(path: &Path) -> bool {
+    has_extension(path, "tar") || is_zip(path)
+}
+
+/// Whether the archive at `path` is a zip archive rather than a tar archive
+pub(crate) fn is_zip(path: &Path) -> bool {
+    has_extension(path, "zip")
+}
+
+fn has_extension(path: &Path, extension: &str) -> bool {
+    path.extension()
+        .and_then(|ext| ext.to_str())
+        .map_or(false, |ext| ext.eq_ignore_ascii_case(extension))
+}
+
+pub(crate) fn install_path(file: &UpdateFile) -> Option<String> {
+    match file.install_location.normalized() {
+        Ok(InstallLocation::AbsolutePath(path)) => Some(path),
+        _ => None,
+    }
+}
+
+/// Deepest existing directory containing `path`, taken before writing to it so a failed write
+/// can be told apart from the storage it was written to going away
+pub(crate) fn existing_ancestor(path: &Path) -> Option<PathBuf> {
+    path.ancestors()
+        .skip(1)
+        .find(|dir| dir.is_dir())
+        .map(Path::to_path_buf)
+}
+
+/// Whether a directory which existed before a failed write is now missing or can't be read,
+/// as happens when the SD card is pulled or remounted mid-update. Any other failure is
+/// specific to the file being written.
+pub(crate) fn storage_vanished(dir: &Path) -> bool {
+    match std::fs::read_dir(dir) {
+        Ok(_) => false,
+        Err(e) => matches!(e.kind(), ErrorKind::NotFound | ErrorKind::PermissionDenied),
+    }
+}
+
+fn dir_size(dir: &Path) -> u64 {
+    std::fs::read_dir(dir)
+        .map(|entries| {
+            entries.filter_map(|entry| entry.ok())
+                .map(|entry| {
+                    let path = entry.path();
+                    if path.is_dir() {
+                        dir_size(&path)
+                    } else {
+                        entry.metadata().map(|meta| meta.len()).unwrap_or(0)
+                    }
+                })
+                .sum()
+        })
+        .unwrap_or(0)
+}
+
+/// How much each file of the response currently takes up on disk, None where nothing is
+/// installed. Archives include the folder they were extracted to.
+pub fn current_footprint(response: &UpdateResponse) -> Vec<Option<u64>> {
+    response.required_files.iter()
+        .map(|file| {
+            let path = install_path(file)?;
+            let path = Path::new(&path);
+
+            let size = std::fs::metadata(path).ok().map(|meta| meta.len());
+            if is_archive(path) {
+                let extracted = path.with_extension("");
+                if size.is_some() || extracted.is_dir() {
+                    return Some(size.unwrap_or(0) + dir_size(&extracted))
+                }
+            }
+
+            size
+        })
+        .collect()
+}
+
+/// Estimate the storage used by the files of an update before and after installing it, from
+/// the current size of each file as returned by `current_footprint`. A file being replaced
+/// only counts its size difference. Archives stay on disk next to their extracted contents,
+/// which are assumed to be as large as the archive itself. Returns None if nothing of the
+/// plugin could be found on disk, as there is nothing to compare against.
+pub fn estimate_storage(response: &UpdateResponse, current: &[Option<u64>]) -> Option<StorageEstimate> {
+    if current.iter().all(Option::is_none) {
+        return None
+    }
+
+    // sizes come from the server, so don't trust them not to overflow
+    let before = current.iter().flatten().fold(0u64, |total, &size| total.saturating_add(size));
+    let after = response.required_files.iter()
+        .map(|file| {
+            let archive = install_path(file).map(|path| is_archive(Path::new(&path))).unwrap_or(false);
+            if archive {
+                (file.size as u64).saturating_mul(2)
+            } else {
+                file.size as u64
+            }
+        })
+        .fold(0u64, u64::saturating_add);
+
+    Some(StorageEstimate { before, after })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn response(files: &[(&str, usize)]) -> UpdateResponse {
+        UpdateResponse {
+            required_files: files.iter()
+                .enumerate()
+                .map(|(i, &(path, size))| UpdateFile {
+                    install_location: InstallLocation::AbsolutePath(path.into()),
+                    download_index: i as u64,
+                    size,
+                    inline_data: None,
+                    sha256: None,
+                    signature: None,
+                })
+                .collect(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_is_archive() {
+        assert!(is_archive(Path::new("sd:/ultimate/mods/base.tar")));
+        assert!(is_archive(Path::new("sd:/ultimate/mods/BASE.TAR")));
+        assert!(is_archive(Path::new("sd:/ultimate/mods/base.Tar")));
+        assert!(!is_archive(Path::new("sd:/skyline/plugins/libplugin")));
+        assert!(!is_archive(Path::new("sd:/plugin/config")));
+        assert!(!is_archive(Path::new("sd:/plugin/archive.tar.gz")));
+        assert!(!is_archive(Path::new("sd:/plugin/.tar")));
+
+        assert!(is_archive(Path::new("sd:/ultimate/mods/base.zip")));
+        assert!(is_zip(Path::new("sd:/ultimate/mods/BASE.ZIP")));
+        assert!(!is_zip(Path::new("sd:/ultimate/mods/base.tar")));
+    }
+
+    #[test]
+    fn test_estimate_replace() {
+        let response = response(&[("sd:/plugin.nro", 1500), ("sd:/config.toml", 100)]);
+
+        assert_eq!(
+            estimate_storage(&response, &[Some(1000), Some(100)]),
+            Some(StorageEstimate { before: 1100, after: 1600 })
+        );
+    }
+
+    #[test]
+    fn test_estimate_add() {
+        let response = response(&[("sd:/plugin.nro", 1000), ("sd:/new.txt", 50)]);
+
+        assert_eq!(
+            estimate_storage(&response, &[Some(1000), None]),
+            Some(StorageEstimate { before: 1000, after: 1050 })
+        );
+
+        // nothing installed yet, nothing to compare
+        assert_eq!(estimate_storage(&response, &[None, None]), None);
+    }
+
+    #[test]
+    fn test_estimate_archive() {
+        let response = response(&[("sd:/plugin.nro", 1000), ("sd:/ultimate/mods/romfs.tar", 4000)]);
+
+        // old archive of 3000 extracted to 3000 more
+        assert_eq!(
+            estimate_storage(&response, &[Some(1000), Some(6000)]),
+            Some(StorageEstimate { before: 7000, after: 9000 })
+        );
+    }
+
+    #[test]
+    fn test_format_size() {
+        assert_eq!(format_size(100), "100 B");
+        assert_eq!(format_size(1536), "1.5 KB");
+        assert_eq!(format_size(1181116006), "1.1 GB");
+        assert_eq!(
+            StorageEstimate { before: 1181116006, after: 1503238554 }.to_string(),
+            "1.1 GB -> 1.4 GB"
+        );
+    }
+
+    #[test]
+    fn test_storage_vanished() {
+        let dir = std::env::temp_dir().join(format!("skyline-update-vanished-{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("mods")).unwrap();
+
+        let root = existing_ancestor(&dir.join("mods/new/file.txt")).unwrap();
+        assert_eq!(root, dir.join("mods"));
+        assert!(!storage_vanished(&root));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert!(storage_vanished(&root));
+    }
+}
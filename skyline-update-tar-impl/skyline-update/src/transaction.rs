@@ -0,0 +1,153 @@
+use std::cell::RefCell;
+use std::path::{Path, PathBuf};
+
+use log::warn;
+
+/// Files replaced and created while installing an update, so a failed update can be undone.
+/// Replaced files are moved into a backup directory rather than overwritten, see
+/// `Installer::begin_transaction`.
+#[derive(Debug)]
+pub(crate) struct Transaction {
+    dir: PathBuf,
+    /// Every path written so far, with where the file it replaced was moved, None if there was
+    /// no file there
+    written: Vec<(PathBuf, Option<PathBuf>)>,
+}
+
+impl Transaction {
+    /// Start backing up the files replaced by an update of `plugin_name` into `dir`. Backups
+    /// left by an update which never finished are discarded.
+    pub(crate) fn begin(dir: &Path, plugin_name: &str) -> Self {
+        let dir = dir.join(plugin_name);
+        let _ = std::fs::remove_dir_all(&dir);
+
+        Transaction { dir, written: vec![] }
+    }
+
+    /// Move the file at `path` out of the way before it is overwritten. Paths written earlier in
+    /// the same transaction are already backed up.
+    pub(crate) fn backup(&mut self, path: &Path) -> std::io::Result<()> {
+        if self.written.iter().any(|(written, _)| written == path) {
+            return Ok(())
+        }
+
+        let backup = if path.is_file() {
+            std::fs::create_dir_all(&self.dir)?;
+            let backup = self.dir.join(format!("{}.bak", self.written.len()));
+            std::fs::rename(path, &backup)?;
+            Some(backup)
+        } else {
+            None
+        };
+        self.written.push((path.to_owned(), backup));
+
+        Ok(())
+    }
+
+    /// Keep every file written and delete the backups
+    pub(crate) fn commit(self) {
+        let _ = std::fs::remove_dir_all(&self.dir);
+    }
+
+    /// Delete every file written and restore the files they replaced, newest first. Returns
+    /// whether all of them were restored, the backups are kept otherwise.
+    pub(crate) fn rollback(self) -> bool {
+        let mut restored = true;
+        for (path, backup) in self.written.iter().rev() {
+            let result = match backup {
+                Some(backup) => std::fs::rename(backup, path),
+                None => std::fs::remove_file(path).or_else(|e| match e.kind() {
+                    std::io::ErrorKind::NotFound => Ok(()),
+                    _ => Err(e),
+                }),
+            };
+            if let Err(e) = result {
+                warn!("[updater] Failed to restore {}: {}", path.display(), e);
+                restored = false;
+            }
+        }
+
+        if restored {
+            let _ = std::fs::remove_dir_all(&self.dir);
+        } else {
+            warn!("[updater] The files which couldn't be restored are backed up in {}", self.dir.display());
+        }
+
+        restored
+    }
+}
+
+thread_local! {
+    /// Transaction of the update being installed on this thread by an installer without a place
+    /// of its own to keep it, such as `DefaultInstaller`
+    static CURRENT: RefCell<Option<Transaction>> = RefCell::new(None);
+}
+
+/// Start a transaction on this thread, see `Transaction::begin`
+pub(crate) fn begin(plugin_name: &str) {
+    CURRENT.with(|current| *current.borrow_mut() = Some(Transaction::begin(&backup_dir(), plugin_name)));
+}
+
+/// Back up `path` in this thread's transaction, if one was begun
+pub(crate) fn backup(path: &Path) -> std::io::Result<()> {
+    CURRENT.with(|current| match current.borrow_mut().as_mut() {
+        Some(transaction) => transaction.backup(path),
+        None => Ok(()),
+    })
+}
+
+/// Commit this thread's transaction, see `Transaction::commit`
+pub(crate) fn commit() {
+    if let Some(transaction) = CURRENT.with(|current| current.borrow_mut().take()) {
+        transaction.commit();
+    }
+}
+
+/// Roll back this thread's transaction, see `Transaction::rollback`. False if none was begun.
+pub(crate) fn rollback() -> bool {
+    CURRENT.with(|current| current.borrow_mut().take())
+        .map_or(false, Transaction::rollback)
+}
+
+/// Directory files replaced by an update are backed up in until it is installed
+pub(crate) fn backup_dir() -> PathBuf {
+    if cfg!(target_os = "switch") {
+        PathBuf::from("sd:/skyline-update/backups")
+    } else {
+        std::env::temp_dir().join("skyline-update/backups")
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_rollback() {
+        let dir = std::env::temp_dir().join(format!("skyline-update-transaction-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("replaced.txt"), b"old").unwrap();
+
+        let mut transaction = Transaction::begin(&dir.join("backups"), "plugin");
+        for name in &["replaced.txt", "created.txt", "replaced.txt"] {
+            transaction.backup(&dir.join(name)).unwrap();
+            std::fs::write(dir.join(name), b"new").unwrap();
+        }
+        assert!(transaction.rollback());
+
+        assert_eq!(std::fs::read(dir.join("replaced.txt")).unwrap(), b"old");
+        assert!(!dir.join("created.txt").exists());
+        assert!(!dir.join("backups/plugin").exists());
+
+        let mut transaction = Transaction::begin(&dir.join("backups"), "plugin");
+        transaction.backup(&dir.join("replaced.txt")).unwrap();
+        std::fs::write(dir.join("replaced.txt"), b"new").unwrap();
+        transaction.commit();
+
+        assert_eq!(std::fs::read(dir.join("replaced.txt")).unwrap(), b"new");
+        assert!(!dir.join("backups/plugin").exists());
+
+        let _ = std::fs::remove_dir_all(dir);
+    }
+}
@@ -0,0 +1,248 @@
+use std::fmt::Debug;
+use std::io::prelude::*;
+use std::net::{Shutdown, TcpStream, ToSocketAddrs};
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::UpdateError;
+
+/// A connection to the main or download port opened by a `Transport`
+pub trait Stream: Read + Write + Send + Debug {
+    /// Close the connection once the client is done with it
+    fn shutdown(&mut self) {}
+}
+
+impl Stream for TcpStream {
+    fn shutdown(&mut self) {
+        let _ = TcpStream::shutdown(self, Shutdown::Both);
+    }
+}
+
+/// How a client reaches the ports of an update server, `TcpTransport` unless it was given
+/// another with `UpdateClient::with_transport`. Requests and downloads are sent over the
+/// streams it opens the same way whatever they are, wrapped in TLS if the client uses it.
+pub trait Transport: Send + Sync + Debug {
+    /// Open a connection to `port` of `host`. Reads from it give up after `read_timeout`,
+    /// connecting and writes after `write_timeout`.
+    fn connect(&self, host: &str, port: u16, read_timeout: Duration, write_timeout: Duration) -> Result<Box<dyn Stream>, UpdateError>;
+}
+
+/// Connects over TCP, trying every address `host` resolves to in order
+#[derive(Debug, Clone, Copy, Default)]
+pub struct TcpTransport;
+
+impl Transport for TcpTransport {
+    fn connect(&self, host: &str, port: u16, read_timeout: Duration, write_timeout: Duration) -> Result<Box<dyn Stream>, UpdateError> {
+        let resolve_error = |reason: String| UpdateError::Resolve { host: host.to_owned(), reason };
+        let addrs: Vec<_> = (host, port).to_socket_addrs()
+            .map_err(|e| resolve_error(e.to_string()))?
+            .collect();
+
+        let mut error = resolve_error(String::from("no addresses found"));
+        for addr in addrs {
+            let stream = TcpStream::connect_timeout(&addr, write_timeout).and_then(|stream| {
+                stream.set_read_timeout(Some(read_timeout))?;
+                stream.set_write_timeout(Some(write_timeout))?;
+                Ok(stream)
+            });
+            match stream {
+                Ok(stream) => return Ok(Box::new(stream)),
+                Err(e) => error = UpdateError::Connect(format!("{}: {}", addr, e)),
+            }
+        }
+
+        Err(error)
+    }
+}
+
+/// The transport of a client, shared by its clones
+#[derive(Debug, Clone)]
+pub(crate) struct SharedTransport {
+    transport: Arc<dyn Transport>,
+    /// Whether it was given with `UpdateClient::with_transport` rather than the default
+    custom: bool,
+}
+
+impl SharedTransport {
+    pub(crate) fn new(transport: impl Transport + 'static) -> Self {
+        SharedTransport { transport: Arc::new(transport), custom: true }
+    }
+
+    #[cfg(any(test, feature = "tokio"))]
+    pub(crate) fn is_custom(&self) -> bool {
+        self.custom
+    }
+
+    pub(crate) fn get(&self) -> &dyn Transport {
+        &*self.transport
+    }
+}
+
+impl Default for SharedTransport {
+    fn default() -> Self {
+        SharedTransport { transport: Arc::new(TcpTransport), custom: false }
+    }
+}
+
+/// Transports are equal if they are shared, as with `CancelToken`. The default ones all are.
+impl PartialEq for SharedTransport {
+    fn eq(&self, other: &Self) -> bool {
+        let address = |shared: &SharedTransport| Arc::as_ptr(&shared.transport) as *const u8;
+        (!self.custom && !other.custom) || address(self) == address(other)
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod test {
+    use super::*;
+    use std::collections::{HashMap, VecDeque};
+    use std::convert::TryInto;
+    use std::io::Cursor;
+    use std::sync::Mutex;
+
+    /// Files of a download port by download index
+    type Files = Arc<Vec<Vec<u8>>>;
+
+    /// What the client wrote over a connection, shared with the stream it wrote to
+    type Written = Arc<Mutex<Vec<u8>>>;
+
+    /// Answers connections with replies queued by the test instead of a server. Every
+    /// connection to a port reads the next reply queued for it and records what the client
+    /// wrote. Ports without a reply left refuse the connection. Clones share their replies.
+    #[derive(Debug, Clone, Default)]
+    pub(crate) struct MockTransport {
+        replies: Arc<Mutex<HashMap<u16, VecDeque<Vec<u8>>>>>,
+        /// Files served by download index, see `serve`
+        files: Arc<Mutex<HashMap<u16, Files>>>,
+        sent: Arc<Mutex<Vec<(u16, Written)>>>,
+    }
+
+    impl MockTransport {
+        /// Queue `reply` for the next connection to `port`
+        pub(crate) fn reply(self, port: u16, reply: impl Into<Vec<u8>>) -> Self {
+            self.replies.lock().unwrap().entry(port).or_default().push_back(reply.into());
+            self
+        }
+
+        /// Queue `response` for the next connection to `port`, as a server would send it
+        pub(crate) fn respond(self, port: u16, response: &update_protocol::UpdateResponse) -> Self {
+            self.reply(port, serde_json::to_vec(response).unwrap())
+        }
+
+        /// Answer every download from `port` with the file at the index it asks for, as a
+        /// download port does, for downloads which don't happen in a fixed order
+        pub(crate) fn serve(self, port: u16, files: Vec<Vec<u8>>) -> Self {
+            self.files.lock().unwrap().insert(port, Arc::new(files));
+            self
+        }
+
+        /// The download indexes asked for from `port`, in the order the connections were opened
+        pub(crate) fn downloads(&self, port: u16) -> Vec<u64> {
+            self.sent().into_iter()
+                .filter(|(sent_to, _)| *sent_to == port)
+                .map(|(_, sent)| u64::from_be_bytes(sent[..8].try_into().unwrap()))
+                .collect()
+        }
+
+        /// What the client wrote over every connection so far, with the port it was opened to
+        pub(crate) fn sent(&self) -> Vec<(u16, Vec<u8>)> {
+            self.sent.lock().unwrap()
+                .iter()
+                .map(|(port, sent)| (*port, sent.lock().unwrap().clone()))
+                .collect()
+        }
+
+        /// Whether every queued reply was read
+        pub(crate) fn is_done(&self) -> bool {
+            self.replies.lock().unwrap().values().all(VecDeque::is_empty)
+        }
+    }
+
+    impl Transport for MockTransport {
+        fn connect(&self, _: &str, port: u16, _: Duration, _: Duration) -> Result<Box<dyn Stream>, UpdateError> {
+            let reply = match self.files.lock().unwrap().get(&port) {
+                Some(files) => Reply::Indexed(Arc::clone(files)),
+                None => self.replies.lock().unwrap()
+                    .get_mut(&port)
+                    .and_then(VecDeque::pop_front)
+                    .map(|reply| Reply::Queued(Cursor::new(reply)))
+                    .ok_or_else(|| UpdateError::Connect(format!("port {}: connection refused", port)))?,
+            };
+            let sent = Arc::new(Mutex::new(vec![]));
+            self.sent.lock().unwrap().push((port, Arc::clone(&sent)));
+
+            Ok(Box::new(MockStream { reply, sent }))
+        }
+    }
+
+    #[derive(Debug)]
+    enum Reply {
+        Queued(Cursor<Vec<u8>>),
+        /// Picked once the client wrote the index of the file it wants
+        Indexed(Files),
+    }
+
+    #[derive(Debug)]
+    struct MockStream {
+        reply: Reply,
+        sent: Written,
+    }
+
+    impl Read for MockStream {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            if let Reply::Indexed(files) = &self.reply {
+                let sent = self.sent.lock().unwrap();
+                let index = u64::from_be_bytes(sent[..8].try_into().unwrap());
+                let file = files.get(index as usize).cloned().unwrap_or_default();
+                drop(sent);
+                self.reply = Reply::Queued(Cursor::new(file));
+            }
+            match &mut self.reply {
+                Reply::Queued(reply) => reply.read(buf),
+                Reply::Indexed(_) => unreachable!(),
+            }
+        }
+    }
+
+    impl Write for MockStream {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.sent.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Stream for MockStream {}
+
+    #[test]
+    fn test_mock_transport() {
+        let transport = MockTransport::default().reply(1, "first").reply(1, "second");
+
+        let mut stream = transport.connect("host", 1, Duration::from_secs(1), Duration::from_secs(1)).unwrap();
+        stream.write_all(b"request").unwrap();
+        let mut reply = String::new();
+        stream.read_to_string(&mut reply).unwrap();
+        assert_eq!(reply, "first");
+        assert!(!transport.is_done());
+
+        assert!(transport.connect("host", 1, Duration::from_secs(1), Duration::from_secs(1)).is_ok());
+        assert!(matches!(transport.connect("host", 1, Duration::from_secs(1), Duration::from_secs(1)), Err(UpdateError::Connect(_))));
+        assert!(matches!(transport.connect("host", 2, Duration::from_secs(1), Duration::from_secs(1)), Err(UpdateError::Connect(_))));
+        assert_eq!(transport.sent(), vec![(1, b"request".to_vec()), (1, vec![])]);
+        assert!(transport.is_done());
+
+        // served files are picked by the index asked for, on every connection
+        let transport = MockTransport::default().serve(2, vec![b"zero".to_vec(), b"one".to_vec()]);
+        for &index in [1u64, 0, 1].iter() {
+            let mut stream = transport.connect("host", 2, Duration::from_secs(1), Duration::from_secs(1)).unwrap();
+            stream.write_all(&index.to_be_bytes()).unwrap();
+            let mut reply = vec![];
+            stream.read_to_end(&mut reply).unwrap();
+            assert_eq!(reply, if index == 0 { &b"zero"[..] } else { &b"one"[..] });
+        }
+        assert_eq!(transport.downloads(2), vec![1, 0, 1]);
+    }
+}
@@ -0,0 +1,158 @@
+use std::io::ErrorKind;
+use std::path::Path;
+
+use log::{info, warn};
+
+use crate::changes::{self, ChangeSet};
+use crate::{DefaultInstaller, Installer, UninstallError};
+
+/// Everything `uninstall` did
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct UninstallReport {
+    pub deleted: Vec<String>,
+    /// Recorded files which were already gone
+    pub missing: Vec<String>,
+    /// Recorded files which couldn't be deleted, with why. The record of the plugin is kept
+    /// while there are any, so uninstalling again retries them.
+    pub failed: Vec<(String, String)>,
+    /// Directories left empty and removed, up to the deepest one containing every file
+    pub dirs: Vec<String>,
+}
+
+impl UninstallReport {
+    /// Whether every recorded file is gone
+    pub fn is_complete(&self) -> bool {
+        self.failed.is_empty()
+    }
+}
+
+/// Delete every file the updater recorded for `plugin_name`, asking `installer` first, see
+/// `uninstall`
+pub(crate) fn uninstall_from<I>(dir: &Path, plugin_name: &str, installer: &I) -> Result<UninstallReport, UninstallError>
+    where I: Installer,
+{
+    let manifest = changes::load_manifest(dir, plugin_name).ok_or(UninstallError::NotInstalled)?;
+    if !installer.should_uninstall(plugin_name, &manifest) {
+        return Err(UninstallError::Declined)
+    }
+
+    // extracted archives are recorded with every file they held, and are only still there if
+    // the installer kept them
+    let paths: Vec<String> = manifest.files.iter()
+        .flat_map(|file| {
+            let archive = Some(&file.path).filter(|path| file.extracted.is_empty() || Path::new(path).exists());
+            archive.into_iter().chain(&file.extracted)
+        })
+        .cloned()
+        .collect();
+
+    let mut report = UninstallReport::default();
+    for path in &paths {
+        match std::fs::remove_file(path) {
+            Ok(()) => report.deleted.push(path.clone()),
+            Err(e) if e.kind() == ErrorKind::NotFound => report.missing.push(path.clone()),
+            Err(e) => {
+                warn!("[updater] Failed to delete {}: {}", path, e);
+                report.failed.push((path.clone(), e.to_string()));
+            }
+        }
+    }
+
+    if let Some(boundary) = (ChangeSet { removed: paths, ..Default::default() }).install_root() {
+        for path in report.deleted.iter().chain(&report.missing) {
+            report.dirs.extend(changes::prune_empty_dirs(path, &boundary));
+        }
+    }
+
+    if report.is_complete() {
+        changes::remove_manifest(dir, plugin_name);
+        info!("[updater] Uninstalled {}: {} files deleted, {} already gone", plugin_name, report.deleted.len(), report.missing.len());
+    } else {
+        warn!("[updater] Failed to delete {} files of {}, uninstalling again retries them", report.failed.len(), plugin_name);
+    }
+
+    Ok(report)
+}
+
+/// Delete every file the updater installed for `plugin_name`, as recorded once its last update
+/// was installed, including the files extracted from its archives. Directories left empty are
+/// removed as well, and so is the record once every file is gone. The `DefaultInstaller` asks
+/// the user first on the console.
+pub fn uninstall(plugin_name: &str) -> Result<UninstallReport, UninstallError> {
+    custom_uninstall(plugin_name, &DefaultInstaller)
+}
+
+/// `uninstall`, asking `installer` through `Installer::should_uninstall` before deleting anything
+pub fn custom_uninstall<I>(plugin_name: &str, installer: &I) -> Result<UninstallReport, UninstallError>
+    where I: Installer,
+{
+    uninstall_from(&changes::installed_dir(), plugin_name, installer)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::path::PathBuf;
+    use crate::test::{inline_response, tar_of};
+    use crate::{handle_response, InstallError, InstalledManifest, UpdateClient, UpdateReport, UpdateResponse, UpdateStatus};
+
+    #[test]
+    fn test_install_then_uninstall() {
+        struct Asking(std::cell::Cell<bool>);
+
+        impl Installer for Asking {
+            fn should_update(&self, _: &UpdateResponse) -> bool {
+                true
+            }
+
+            fn install_file(&self, path: PathBuf, buf: Vec<u8>) -> Result<(), InstallError> {
+                std::fs::create_dir_all(path.parent().unwrap()).map_err(|e| InstallError::new(&path, e))?;
+                std::fs::write(&path, buf).map_err(|e| InstallError::new(path, e))
+            }
+
+            fn should_uninstall(&self, _: &str, manifest: &InstalledManifest) -> bool {
+                assert_eq!(manifest.files.len(), 2);
+                self.0.get()
+            }
+        }
+
+        let dir = std::env::temp_dir().join(format!("skyline-update-uninstall-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let path = |relative: &str| dir.join(relative).to_string_lossy().into_owned();
+        let name = format!("uninstall_{}", std::process::id());
+        let mut response = inline_response(&[(&path("mods/my_mod/plugin.nro"), "nro"), (&path("mods/my_mod/romfs.tar"), &tar_of(&["fighter/a.bin", "ui/b.bin"]))]);
+        response.plugin_name = name.clone();
+        std::fs::create_dir_all(dir.join("mods/other")).unwrap();
+
+        let installer = Asking(std::cell::Cell::new(false));
+        let mut report = UpdateReport { response: Some(response.clone()), ..Default::default() };
+        handle_response(&UpdateClient::new("127.0.0.1".parse().unwrap()), &name, &response, &installer, &mut report, false, true);
+        // the archive was deleted once extracted, so only what it held is listed
+        let installed_paths = vec![
+            PathBuf::from(path("mods/my_mod/plugin.nro")),
+            dir.join("mods/my_mod/romfs/fighter/a.bin"),
+            dir.join("mods/my_mod/romfs/ui/b.bin"),
+        ];
+        assert_eq!(report.status(), Ok(UpdateStatus::Installed { response: response.clone(), installed_paths }));
+        assert!(dir.join("mods/my_mod/romfs/fighter/a.bin").exists());
+
+        // nothing is deleted without confirmation
+        assert_eq!(custom_uninstall(&name, &installer), Err(UninstallError::Declined));
+        assert!(dir.join("mods/my_mod/plugin.nro").exists());
+
+        std::fs::remove_file(dir.join("mods/my_mod/romfs/ui/b.bin")).unwrap();
+        installer.0.set(true);
+        let uninstalled = custom_uninstall(&name, &installer).unwrap();
+        assert!(uninstalled.is_complete());
+        assert_eq!(uninstalled.deleted.len(), 2);
+        assert_eq!(uninstalled.missing, vec![path("mods/my_mod/romfs/ui/b.bin")]);
+        // every folder it left empty is gone, up to the one holding the plugin's files
+        assert!(!dir.join("mods/my_mod/romfs").exists());
+        assert!(dir.join("mods/my_mod").exists() && dir.join("mods/other").exists());
+
+        assert_eq!(changes::load_manifest(&changes::installed_dir(), &name), None);
+        assert_eq!(custom_uninstall(&name, &installer), Err(UninstallError::NotInstalled));
+
+        let _ = std::fs::remove_dir_all(dir);
+    }
+}
@@ -0,0 +1,208 @@
+use std::path::Path;
+
+use log::{info, warn};
+use update_protocol::ResponseCode;
+
+use crate::{
+    completion_message, inline_file, resolve_locations, resume, send_update_request, signing, storage,
+    update_files, update_request, running_skyline_version, FileOutcome, Installer, UpdateClient,
+    UpdateFile, UpdateReport, UpdateResponse,
+};
+
+/// How a file of the installed version compares to what the server has for it
+#[derive(Debug, Clone, PartialEq)]
+pub enum FileStatus {
+    /// Its SHA-256 matches the server's
+    Intact,
+    /// It differs from the server's copy, such as a file corrupted or only partially updated
+    Mismatch { expected: String, actual: String },
+    Missing,
+    /// Can't be checked, as the server sent no hash for it or it is an archive which was
+    /// extracted and deleted
+    Unverifiable,
+}
+
+impl FileStatus {
+    /// Whether reinstalling the file would fix it
+    pub fn needs_repair(&self) -> bool {
+        matches!(self, FileStatus::Mismatch { .. } | FileStatus::Missing)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct VerifiedFile {
+    /// Where the file is installed
+    pub path: String,
+    pub status: FileStatus,
+}
+
+/// Result of `verify_install`
+#[derive(Debug, Clone, Default)]
+pub struct VerifyReport {
+    /// Version the files were compared against
+    pub version: String,
+    pub files: Vec<VerifiedFile>,
+    /// Outcome of reinstalling the files needing repair, None unless asked to or if every file
+    /// was fine
+    pub repair: Option<UpdateReport>,
+}
+
+impl VerifyReport {
+    /// Whether no file was found damaged or missing. Files which can't be checked don't count.
+    pub fn is_intact(&self) -> bool {
+        self.files.iter().all(|file| !file.status.needs_repair())
+    }
+
+    /// Files found damaged or missing
+    pub fn damaged(&self) -> impl Iterator<Item = &VerifiedFile> {
+        self.files.iter().filter(|file| file.status.needs_repair())
+    }
+}
+
+/// Compare `file`, resolved to where it is installed, against the server's copy
+fn verify_file<I: Installer>(file: &UpdateFile, installer: &I) -> VerifiedFile {
+    let path = storage::install_path(file).unwrap_or_default();
+    let expected = file.sha256.clone()
+        .or_else(|| inline_file(file).and_then(|buf| resume::sha256_of(&buf[..]).ok()));
+
+    let status = match installer.installed_sha256(Path::new(&path)) {
+        None if storage::is_archive(Path::new(&path)) => FileStatus::Unverifiable,
+        None => FileStatus::Missing,
+        Some(actual) => match expected {
+            Some(expected) if expected.eq_ignore_ascii_case(&actual) => FileStatus::Intact,
+            Some(expected) => FileStatus::Mismatch { expected, actual },
+            None => FileStatus::Unverifiable,
+        },
+    };
+
+    VerifiedFile { path, status }
+}
+
+/// See `crate::verify_install`
+pub(crate) fn verify_install<I>(client: &UpdateClient, name: &str, version: &str, repair: bool, installer: &I) -> Option<VerifyReport>
+    where I: Installer,
+{
+    // asked for as if nothing was installed, servers only send the files of a pinned version
+    // when it isn't the one installed
+    let request = update_request(client, name, "0.0.0", false, running_skyline_version(client, installer), Some(version));
+    let response = send_update_request(client, name, &request).response?;
+
+    if response.code != ResponseCode::Update || response.new_plugin_version != version {
+        warn!("[{} updater] The update server didn't send the files of {}, they can't be verified", name, version);
+        return None
+    }
+    let resolved = match resolve_locations(&response, installer) {
+        Ok(resolved) => resolved,
+        Err(e) => {
+            warn!("[{} updater] {}", name, e);
+            return None
+        }
+    };
+
+    let files: Vec<VerifiedFile> = resolved.required_files.iter()
+        .map(|file| verify_file(file, installer))
+        .collect();
+    let mut report = VerifyReport { version: version.to_owned(), files, repair: None };
+    info!("[{} updater] Verified {} files, {} damaged or missing", name, report.files.len(), report.damaged().count());
+
+    if repair && !report.is_intact() {
+        // only the damaged files are downloaded again, through the usual per-file path
+        let damaged = UpdateResponse {
+            required_files: response.required_files.iter()
+                .zip(&report.files)
+                .filter(|(_, verified)| verified.status.needs_repair())
+                .map(|(file, _)| file.clone())
+                .collect(),
+            ..response
+        };
+        report.repair = Some(repair_files(client, &damaged, installer));
+    }
+
+    Some(report)
+}
+
+/// Install the damaged files of the installed version again. Unlike an update, the manifest of
+/// what is installed is left as it is, as `damaged` only lists some of its files.
+fn repair_files<I>(client: &UpdateClient, damaged: &UpdateResponse, installer: &I) -> UpdateReport
+    where I: Installer,
+{
+    let mut report = UpdateReport {
+        response: Some(damaged.clone()),
+        ..Default::default()
+    };
+
+    let resolved = match resolve_locations(damaged, installer) {
+        Ok(resolved) => resolved,
+        Err(e) => {
+            warn!("[{} updater] Refusing to repair the installed files: {}", damaged.plugin_name, e);
+            report.error = Some(e);
+            return report
+        }
+    };
+    if let Some(public_key) = client.public_key() {
+        if let Err(e) = signing::verify_response(damaged, public_key) {
+            report.error = Some(e);
+            return report
+        }
+    }
+
+    report.files = update_files(client, &resolved, installer, &mut report.timings);
+    report.installed = report.files.iter().all(|file| file.outcome.succeeded());
+    if report.installed {
+        info!("[updater] {}", completion_message(&report.files));
+    } else {
+        report.error = report.files.iter().find_map(|file| match &file.outcome {
+            FileOutcome::Failed(e) => Some(e.clone()),
+            _ => None,
+        });
+    }
+
+    report
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test::{inline_response, mock_client, pinned_versions, RecordingInstaller};
+    use crate::transport::test::MockTransport;
+    use crate::PORT;
+
+    #[test]
+    fn test_verify_install() {
+        let dir = std::env::temp_dir().join(format!("skyline-update-verify-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = |name: &str| dir.join(name).to_string_lossy().into_owned();
+
+        let mut response = inline_response(&[(&path("intact.txt"), "a"), (&path("corrupted.txt"), "b"), (&path("missing.txt"), "c")]);
+        response.plugin_name = format!("verify_{}", std::process::id());
+        std::fs::write(path("intact.txt"), "a").unwrap();
+        std::fs::write(path("corrupted.txt"), "x").unwrap();
+
+        let transport = MockTransport::default().respond(PORT, &response).respond(PORT, &response);
+        let client = mock_client(&transport);
+        let statuses = |report: &VerifyReport| report.files.iter().map(|file| file.status.clone()).collect::<Vec<_>>();
+
+        let report = client.verify_install(&response.plugin_name, "1.0.0").unwrap();
+        assert_eq!(report.version, "1.0.0");
+        assert_eq!(statuses(&report), vec![
+            FileStatus::Intact,
+            FileStatus::Mismatch { expected: resume::sha256_of(&b"b"[..]).unwrap(), actual: resume::sha256_of(&b"x"[..]).unwrap() },
+            FileStatus::Missing,
+        ]);
+        assert!(!report.is_intact() && report.repair.is_none());
+        assert_eq!(std::fs::read(path("corrupted.txt")).unwrap(), b"x");
+
+        // repairing reinstalls only the damaged files
+        let installer = RecordingInstaller::default();
+        let report = client.custom_verify_install(&response.plugin_name, "1.0.0", true, &installer).unwrap();
+        assert_eq!(report.repair.map(|repair| repair.installed), Some(true));
+        let installed: Vec<_> = installer.installed.borrow().iter().map(|(path, _)| path.to_string_lossy().into_owned()).collect();
+        assert_eq!(installed, vec![path("corrupted.txt"), path("missing.txt")]);
+
+        // the version is asked for by pinning it
+        assert_eq!(pinned_versions(&transport), vec![Some("1.0.0".to_owned()); 2]);
+
+        let _ = std::fs::remove_dir_all(dir);
+    }
+}
@@ -0,0 +1,171 @@
+//! A small mutation fuzzer for the tests of the server's request handling and the client's
+//! response handling. Inputs are derived from the seed files of a corpus directory by random
+//! byte-level mutations, deterministically for a given seed so failures can be reproduced.
+//!
+//! `FUZZ_ITERATIONS` sets how many inputs each target is run with, `FUZZ_SEED` the seed.
+
+use std::panic::{self, AssertUnwindSafe};
+use std::path::Path;
+
+/// Inputs per target when `FUZZ_ITERATIONS` isn't set, enough to run with every `cargo test`
+pub const DEFAULT_ITERATIONS: usize = 2000;
+
+/// Fragments inserted into inputs, chosen to hit edge cases of JSON and the protocol types
+const TOKENS: &[&[u8]] = &[
+    b"{", b"}", b"[", b"]", b"\"", b"\\", b":", b",", b"\n", b"\0",
+    b"null", b"true", b"-1", b"0", b"1e999", b"18446744073709551616", b"-9223372036854775809",
+    b"\"\\ud800\"", b"\"\\u0000\"", b"\xff\xfe", b"\xc3", b"\xf0\x9f\x92",
+    b"\"Update\"", b"\"Ping\"", b"\"Metadata\"", b"\"sd:/\"", b"\"sd:/a/rom:/b\"", b"\"../..\"",
+];
+
+/// xorshift64*, good enough to pick mutations and small enough to not need a dependency
+pub struct Mutator {
+    state: u64,
+}
+
+impl Mutator {
+    pub fn new(seed: u64) -> Self {
+        Mutator { state: seed.max(1) }
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.state ^= self.state >> 12;
+        self.state ^= self.state << 25;
+        self.state ^= self.state >> 27;
+        self.state.wrapping_mul(0x2545_f491_4f6c_dd1d)
+    }
+
+    /// A number in `0..n`, `n` must be nonzero
+    pub fn below(&mut self, n: usize) -> usize {
+        (self.next_u64() % n as u64) as usize
+    }
+
+    /// Apply one to four random mutations to `input`, occasionally splicing in part of
+    /// another input of `corpus`
+    pub fn mutate(&mut self, input: &[u8], corpus: &[Vec<u8>]) -> Vec<u8> {
+        let mut data = input.to_vec();
+
+        for _ in 0..=self.below(4) {
+            let pos = self.below(data.len() + 1);
+            match self.below(8) {
+                // flip a bit
+                0 if !data.is_empty() => {
+                    let pos = pos.min(data.len() - 1);
+                    data[pos] ^= 1 << self.below(8);
+                }
+                // overwrite a byte, likely producing invalid UTF-8
+                1 if !data.is_empty() => {
+                    let pos = pos.min(data.len() - 1);
+                    data[pos] = self.next_u64() as u8;
+                }
+                2 => {
+                    let token = TOKENS[self.below(TOKENS.len())];
+                    data.splice(pos..pos, token.iter().copied());
+                }
+                // delete a range
+                3 => {
+                    let end = (pos + self.below(16) + 1).min(data.len());
+                    data.drain(pos..end);
+                }
+                // truncate, as if the connection was cut
+                4 => data.truncate(pos),
+                // repeat a range
+                5 => {
+                    let end = (pos + self.below(32) + 1).min(data.len());
+                    let range = data[pos..end].to_vec();
+                    data.splice(pos..pos, range);
+                }
+                // splice in the tail of another input
+                6 if !corpus.is_empty() => {
+                    let other = &corpus[self.below(corpus.len())];
+                    let from = self.below(other.len() + 1);
+                    data.truncate(pos);
+                    data.extend_from_slice(&other[from..]);
+                }
+                // deeply nested structures
+                _ => {
+                    let depth = self.below(512) + 1;
+                    let (open, close) = if self.below(2) == 0 { (b'[', b']') } else { (b'{', b'}') };
+                    let mut nested = vec![open; depth];
+                    nested.extend_from_slice(&data);
+                    nested.extend(std::iter::repeat(close).take(self.below(depth + 1)));
+                    data = nested;
+                }
+            }
+        }
+
+        data
+    }
+}
+
+/// Every file of a corpus directory, sorted by name so runs are reproducible
+pub fn load_corpus(dir: &Path) -> Vec<Vec<u8>> {
+    let mut paths: Vec<_> = std::fs::read_dir(dir)
+        .unwrap_or_else(|e| panic!("failed to read fuzz corpus {}: {}", dir.display(), e))
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.path())
+        .filter(|path| path.is_file())
+        .collect();
+    paths.sort();
+
+    paths.iter()
+        .map(|path| std::fs::read(path).unwrap())
+        .collect()
+}
+
+fn env_or(name: &str, default: u64) -> u64 {
+    std::env::var(name).ok()
+        .and_then(|value| value.parse().ok())
+        .unwrap_or(default)
+}
+
+/// Run `target` with every seed of `corpus` as is, then with mutated inputs. If the target
+/// panics the input is printed (as a Rust byte string, to be added to the corpus once fixed)
+/// before the panic is passed on.
+pub fn run<F>(corpus: &[Vec<u8>], mut target: F)
+    where F: FnMut(&[u8]),
+{
+    let iterations = env_or("FUZZ_ITERATIONS", DEFAULT_ITERATIONS as u64) as usize;
+    let seed = env_or("FUZZ_SEED", 0x5eed);
+    let mut mutator = Mutator::new(seed);
+
+    let mutated = (0..iterations).map(|_| {
+        if corpus.is_empty() {
+            mutator.mutate(&[], corpus)
+        } else {
+            let input = &corpus[mutator.below(corpus.len())];
+            mutator.mutate(input, corpus)
+        }
+    });
+
+    for input in corpus.iter().cloned().chain(mutated) {
+        if let Err(payload) = panic::catch_unwind(AssertUnwindSafe(|| target(&input))) {
+            println!("fuzz target panicked (FUZZ_SEED={}) on input: b\"{}\"", seed, escape(&input));
+            panic::resume_unwind(payload);
+        }
+    }
+}
+
+fn escape(input: &[u8]) -> String {
+    input.iter()
+        .flat_map(|&byte| std::ascii::escape_default(byte))
+        .map(char::from)
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_mutations_reproducible() {
+        let corpus = vec![b"\"Ping\"\n".to_vec(), br#"{"Update":{}}"#.to_vec()];
+        let inputs = |seed| {
+            let mut mutator = Mutator::new(seed);
+            (0..50).map(|_| mutator.mutate(&corpus[0], &corpus)).collect::<Vec<_>>()
+        };
+
+        assert_eq!(inputs(1), inputs(1));
+        assert_ne!(inputs(1), inputs(2));
+    }
+}
@@ -1,4 +1,80 @@
+use std::collections::HashMap;
 use std::fmt;
+
+/// Version of the protocol spoken by this crate. Clients from before versioning existed don't
+/// send one and are treated as version 1.
+///
+/// * 2 - inline files
+/// * 3 - `ResponseCode::NotifyOnly`
+/// * 4 - `InstallLocation::Relative`
+/// * 5 - `UpdateRequestOptions::pinned_version` and `UpdateResponse::rollback_version`
+/// * 6 - `UpdateRequestOptions::platform`
+/// * 7 - `UpdateRequestOptions::channel`
+/// * 8 - `UpdateResponse::keep_alive_downloads`
+/// * 9 - `ResponseCode::RateLimited` and `ResponseCode::ServerError`
+/// * 10 - `MISSING_FILE_LEN`
+/// * 11 - `ResponseCode::VersionNotFound`
+/// * 12 - `UpdateFile::signature` covers `UpdateResponse::signed_message`
+pub const PROTOCOL_VERSION: u32 = 12;
+
+/// First field of every `UpdateResponse::signed_message`, so a signature made for anything
+/// else is never accepted for a file
+const SIGNATURE_CONTEXT: &str = "skyline-update file signature";
+
+/// Sent instead of a download index as the first 8 bytes of a download connection to keep it
+/// open for several files, see `UpdateResponse::keep_alive_downloads`. Every index sent after
+/// it is answered with the file's 8-byte big endian length, then the file, until the client
+/// closes the connection. Servers close it instead of answering an index they don't host, or
+/// answer `MISSING_FILE_LEN` first.
+pub const KEEP_ALIVE_INDEX: u64 = u64::MAX;
+
+/// Sent instead of a file's length on a kept-alive download connection when the server no
+/// longer hosts the index asked for, such as a file which changed since the `UpdateResponse`
+/// naming it was sent. The server closes the connection after it, clients ask for the update
+/// again to learn the current files.
+pub const MISSING_FILE_LEN: u64 = u64::MAX;
+
+/// UDP port servers which opted into discovery answer `DISCOVERY_MAGIC` on
+pub const DISCOVERY_PORT: u16 = 45002;
+
+/// Datagram broadcast by clients looking for update servers on the local network, answered
+/// with a `DiscoveryResponse`
+pub const DISCOVERY_MAGIC: &[u8] = b"SKYLINE_UPDATE_DISCOVER";
+
+/// Release channel of clients which don't ask for betas or name a channel, see
+/// `UpdateRequestOptions::channel`
+pub const STABLE_CHANNEL: &str = "stable";
+
+/// Release channel of clients which ask for betas without naming a channel
+pub const BETA_CHANNEL: &str = "beta";
+
+/// The release channel a client asks for: the one it named, otherwise `BETA_CHANNEL` if it
+/// asked for betas and `STABLE_CHANNEL` if not
+pub fn requested_channel(beta: bool, channel: Option<&str>) -> &str {
+    match channel {
+        Some(channel) => channel,
+        None if beta => BETA_CHANNEL,
+        None => STABLE_CHANNEL,
+    }
+}
+
+/// Platforms a client can say it runs on, see `UpdateRequestOptions::platform`
+pub const PLATFORMS: [&str; 3] = ["switch", "emulator", "pc"];
+
+/// Platform of clients which don't say, as every client before protocol version 6 ran on the
+/// console or pretended to
+pub const DEFAULT_PLATFORM: &str = "switch";
+
+/// Whether a file marked for `platforms` is sent to clients on `platform`. Unmarked files are
+/// sent to every client, and a platform nobody marked a file for only receives those.
+pub fn matches_platform(platforms: Option<&[String]>, platform: &str) -> bool {
+    platforms.map_or(true, |platforms| platforms.iter().any(|marked| marked == platform))
+}
+
+/// Input mutation for the fuzz-style tests of the server and client, see the README
+#[cfg(feature = "fuzzing")]
+pub mod fuzz;
+
 use serde::{Serializer, Deserializer};
 use serde::{Serialize, Deserialize, de::{self, Visitor}};
 
@@ -10,12 +86,23 @@ pub struct VersionInfo {
 }
 
 #[non_exhaustive]
-#[derive(Serialize, Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub enum ResponseCode {
     NoUpdate,
     Update,
     PluginNotFound,
     InvalidRequest,
+    /// A newer version exists but has to be installed some other way, the response carries
+    /// the version and changelog but no files
+    NotifyOnly,
+    /// The server can't answer right now, ask again after `UpdateResponse::retry_after`
+    /// seconds (or later if it isn't set)
+    RateLimited,
+    /// The server failed to answer the request, `UpdateResponse::message` says why
+    ServerError,
+    /// The server hosts the plugin but not the version `UpdateRequestOptions::pinned_version`
+    /// asks for, `UpdateResponse::message` says which
+    VersionNotFound,
 }
 
 impl Default for ResponseCode {
@@ -24,7 +111,7 @@ impl Default for ResponseCode {
     }
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
 pub struct UpdateResponse {
     pub code: ResponseCode,
     pub update_plugin: bool,
@@ -32,10 +119,90 @@ pub struct UpdateResponse {
     pub plugin_name: String,
     pub new_plugin_version: String,
     pub new_skyline_version: Option<String>,
+    /// Files to install, in the order they must be installed in
     pub required_files: Vec<UpdateFile>,
+
+    /// Changelog of the new version, sent with `NotifyOnly` and `Update`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub changelog: Option<String>,
+
+    /// Where to get the new version, only sent with `NotifyOnly`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub homepage: Option<String>,
+
+    /// Skyline version requirement of the new version, see `parse_requirement`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub skyline_requirement: Option<String>,
+
+    /// Port to download files from, when it isn't the port after the one the request was
+    /// sent to (e.g. a server behind a port forward)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub download_port: Option<u16>,
+
+    /// Name to show users, when the plugin has one other than `plugin_name`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub display_name: Option<String>,
+
+    /// Known good version to roll back to if the newest one breaks, request it with
+    /// `UpdateRequestOptions::pinned_version`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rollback_version: Option<String>,
+
+    /// Whether the download port keeps connections opened with `KEEP_ALIVE_INDEX` open for
+    /// several files. Without it every file is downloaded over a connection of its own.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub keep_alive_downloads: bool,
+
+    /// Files earlier versions installed which clients delete once every file of the update is
+    /// installed, absolute paths or paths relative to the install root like
+    /// `InstallLocation::Relative`
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub files_to_delete: Vec<String>,
+
+    /// Seconds to wait before asking again, only sent with `RateLimited`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub retry_after: Option<u64>,
+
+    /// Why the server failed or rejected the request, for users, sent with `ServerError` and
+    /// optionally with `InvalidRequest` and `PluginNotFound`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub message: Option<String>,
 }
 
 impl UpdateResponse {
+    /// Name of the plugin as shown to users, `plugin_name` unless the plugin has a display name
+    pub fn display_name(&self) -> &str {
+        self.display_name.as_deref().unwrap_or(&self.plugin_name)
+    }
+
+    /// Whether the response offers an update without any files, which would leave the plugin
+    /// as it is while reporting it updated. Only a misconfigured server sends one.
+    pub fn is_empty_update(&self) -> bool {
+        self.code == ResponseCode::Update && self.required_files.is_empty()
+    }
+
+    /// What the signature of `file` covers since protocol version 12: the plugin and version
+    /// it belongs to, where it is installed, its hash and every file the update deletes, so none
+    /// of them can be changed without invalidating the signature. Each field is prefixed with
+    /// its length, so no two responses share a message. None for files sent without a hash.
+    pub fn signed_message(&self, file: &UpdateFile) -> Option<String> {
+        let sha256 = file.sha256.as_deref()?;
+        let location = match &file.install_location {
+            InstallLocation::AbsolutePath(path) => format!("absolute {}", path),
+            InstallLocation::Relative(path) => format!("relative {}", path),
+            InstallLocation::Unknown => String::from("unknown"),
+        };
+
+        let fields = [SIGNATURE_CONTEXT, &self.plugin_name, &self.new_plugin_version, &location, sha256];
+        let message = fields.iter()
+            .copied()
+            .chain(self.files_to_delete.iter().map(String::as_str))
+            .map(|field| format!("{}:{}\n", field.len(), field))
+            .collect();
+
+        Some(message)
+    }
+
     pub fn no_update() -> Self {
         Default::default()
     }
@@ -53,6 +220,33 @@ impl UpdateResponse {
             ..Default::default()
         }
     }
+
+    pub fn version_not_found() -> Self {
+        Self {
+            code: ResponseCode::VersionNotFound,
+            ..Default::default()
+        }
+    }
+
+    pub fn rate_limited(retry_after: u64) -> Self {
+        Self {
+            code: ResponseCode::RateLimited,
+            retry_after: Some(retry_after),
+            ..Default::default()
+        }
+    }
+
+    pub fn server_error(message: &str) -> Self {
+        Self {
+            code: ResponseCode::ServerError,
+            ..Default::default()
+        }.with_message(message)
+    }
+
+    /// The same response, saying why in `message`
+    pub fn with_message(self, message: &str) -> Self {
+        Self { message: Some(message.to_owned()), ..self }
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -62,23 +256,100 @@ pub struct PluginMetadata {
     pub images_index: u64,
     pub image_count: u64,
     pub changelog_index: u64,
+
+    #[serde(default)]
+    pub images: Vec<ImageEntry>,
+
+    #[serde(default)]
+    pub homepage: Option<String>,
+
+    /// Name to show users, when the plugin has one other than the name it is requested by
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub display_name: Option<String>,
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+/// A metadata image available from the download port
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ImageEntry {
+    pub download_index: u64,
+    /// Size of the image as served
+    pub size: usize,
+    /// Size of the server's source image, larger than `size` if the server shrunk it
+    #[serde(default)]
+    pub original_size: usize,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub struct UpdateFile {
     #[serde(deserialize_with = "deserialize_field_kind")]
     pub install_location: InstallLocation,
 
     pub download_index: u64,
     pub size: usize,
+
+    /// Base64-encoded file contents, sent in place of a download for small files when the
+    /// client advertised `accept_inline`. The file remains downloadable via `download_index`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub inline_data: Option<String>,
+
+    /// Lowercase hex SHA-256 of the file, for clients to refuse a corrupted download and to tell
+    /// which files of an interrupted update are already installed
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sha256: Option<String>,
+
+    /// Base64-encoded Ed25519 signature of `UpdateResponse::signed_message` for this file (of
+    /// `sha256` as sent, for clients older than protocol version 12), by servers given a signing
+    /// key, for clients built with the plugin author's public key to refuse files the author
+    /// didn't sign
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub signature: Option<String>,
 }
 
 #[non_exhaustive]
-#[derive(Serialize, Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
 pub struct UpdateRequestOptions {
+    /// `PROTOCOL_VERSION` of the client, None for clients which predate versioning
+    #[serde(default)]
+    pub protocol_version: Option<u32>,
 
+    /// The client can install files from `UpdateFile::inline_data`
+    #[serde(default)]
+    pub accept_inline: bool,
+
+    /// Version of the skyline loader the client is running, if known
+    #[serde(default)]
+    pub skyline_version: Option<String>,
+
+    /// Version and build of skyline-update the client was built with, see `updater_version`
+    #[serde(default)]
+    pub client_version: Option<String>,
+
+    /// Offer exactly this version instead of the newest, such as to roll back or to install a
+    /// version asked for by name. Also accepted as `target_version`. Servers from before
+    /// protocol version 5 ignore it.
+    #[serde(default, alias = "target_version")]
+    pub pinned_version: Option<String>,
+
+    /// Platform the client runs on, one of `PLATFORMS`, so the server only sends the files
+    /// meant for it. `DEFAULT_PLATFORM` if left out. Servers from before protocol version 6 send
+    /// every file.
+    #[serde(default)]
+    pub platform: Option<String>,
+
+    /// Flags of the plugin's own, such as which package or region the user picked, for the
+    /// server to choose files by. Servers ignore the options they don't know.
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub plugin_options: HashMap<String, String>,
+
+    /// Release channel to offer versions from, such as `"nightly"`, in place of `beta`. Clients
+    /// are offered the newest version on their channel or the stable channel. Servers from
+    /// before protocol version 7 only go by `beta`.
+    #[serde(default)]
+    pub channel: Option<String>,
 }
 
+// requests are parsed once per connection, so the options needn't be boxed
+#[allow(clippy::large_enum_variant)]
 #[non_exhaustive]
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub enum Request {
@@ -92,6 +363,111 @@ pub enum Request {
         plugin_name: String,
         beta: Option<bool>,
     },
+    /// Health check, answered with a `PingResponse`
+    Ping,
+    /// List the plugin directories which failed to load, answered with a `Vec<LoadFailure>`.
+    /// Requires the server's admin token.
+    LoadFailures {
+        token: String,
+    },
+    /// List the most recent plugin reloads, answered with a `Vec<ReloadSummary>`.
+    /// Requires the server's admin token.
+    ReloadHistory {
+        token: String,
+    },
+    /// Per-file download statistics, answered with a `Vec<DownloadStats>`. Requires the
+    /// server's admin token.
+    Downloads {
+        token: String,
+        /// Only list files which have had at least one incomplete download
+        incomplete_only: bool,
+    },
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct PingResponse {
+    pub plugin_count: usize,
+    pub load_failure_count: usize,
+    /// Version and build of the server, None for servers from before it was reported
+    #[serde(default)]
+    pub server_version: Option<String>,
+    /// The server is loading a new set of plugins, requests are still answered from the old set
+    #[serde(default)]
+    pub reload_in_progress: bool,
+}
+
+/// A server's answer to `DISCOVERY_MAGIC`, sent back to the address the datagram came from
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+pub struct DiscoveryResponse {
+    /// Name the server was given to tell it apart from others on the network
+    pub name: String,
+    /// Port update requests are accepted on, downloads are accepted on the port after it
+    pub port: u16,
+    /// Names of the plugins hosted, on any channel
+    pub plugins: Vec<String>,
+    pub server_version: Option<String>,
+}
+
+/// Download attempts of a single hosted file, aggregated since the server started
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+pub struct DownloadStats {
+    pub plugin: String,
+    /// Install location of the file
+    pub file: String,
+    pub size: usize,
+    pub attempts: u64,
+    pub completed: u64,
+    /// The client closed or reset the connection before receiving everything
+    pub peer_resets: u64,
+    /// The client stopped reading for longer than the server's stall timeout
+    pub stall_timeouts: u64,
+    /// Any other send error
+    pub errors: u64,
+    pub bytes_sent: u64,
+}
+
+impl DownloadStats {
+    pub fn incomplete(&self) -> u64 {
+        self.attempts.saturating_sub(self.completed)
+    }
+
+    /// Fraction of attempts which did not complete, 0 if there were none
+    pub fn incomplete_rate(&self) -> f64 {
+        if self.attempts == 0 {
+            0.0
+        } else {
+            self.incomplete() as f64 / self.attempts as f64
+        }
+    }
+}
+
+/// What the server was serving after a reload, and how that differs from before it
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+pub struct ReloadSummary {
+    /// Unix timestamp (seconds) of the reload
+    pub timestamp: u64,
+    pub plugin_count: usize,
+    pub file_count: usize,
+    pub total_bytes: u64,
+    pub added: Vec<String>,
+    pub removed: Vec<String>,
+    pub version_changed: Vec<VersionChange>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct VersionChange {
+    pub plugin: String,
+    pub old_version: String,
+    pub new_version: String,
+}
+
+/// A plugin directory the server could not load on its last reload
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct LoadFailure {
+    pub directory: String,
+    pub error: String,
+    /// Unix timestamp (seconds) of when the directory first failed with this error
+    pub timestamp: u64,
 }
 
 // For allowing deserialization of unknown
@@ -99,16 +475,200 @@ fn deserialize_field_kind<'de, D>(deserializer: D) -> Result<InstallLocation, D:
 where
     D: Deserializer<'de>,
 {
-    Ok(InstallLocation::deserialize(deserializer).unwrap_or(InstallLocation::Unknown))
+    deserializer.deserialize_any(FieldKindVisitor)
+}
+
+/// Accepts any value, so a kind of location this version doesn't know (whatever its shape)
+/// leaves the rest of the response readable
+struct FieldKindVisitor;
+
+impl<'de> Visitor<'de> for FieldKindVisitor {
+    type Value = InstallLocation;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("an install location")
+    }
+
+    fn visit_str<E: de::Error>(self, v: &str) -> Result<Self::Value, E> {
+        Ok(InstallLocation::AbsolutePath(v.to_owned()))
+    }
+
+    fn visit_bool<E: de::Error>(self, _: bool) -> Result<Self::Value, E> {
+        Ok(InstallLocation::Unknown)
+    }
+
+    fn visit_i64<E: de::Error>(self, _: i64) -> Result<Self::Value, E> {
+        Ok(InstallLocation::Unknown)
+    }
+
+    fn visit_u64<E: de::Error>(self, _: u64) -> Result<Self::Value, E> {
+        Ok(InstallLocation::Unknown)
+    }
+
+    fn visit_f64<E: de::Error>(self, _: f64) -> Result<Self::Value, E> {
+        Ok(InstallLocation::Unknown)
+    }
+
+    fn visit_unit<E: de::Error>(self) -> Result<Self::Value, E> {
+        Ok(InstallLocation::Unknown)
+    }
+
+    fn visit_seq<A: de::SeqAccess<'de>>(self, mut seq: A) -> Result<Self::Value, A::Error> {
+        while seq.next_element::<de::IgnoredAny>()?.is_some() {}
+        Ok(InstallLocation::Unknown)
+    }
+
+    fn visit_map<A: de::MapAccess<'de>>(self, mut map: A) -> Result<Self::Value, A::Error> {
+        let mut location = None;
+        let mut other_keys = false;
+        while let Some(key) = map.next_key::<MapKey>()? {
+            match (key, map.next_value::<MapValue>()?) {
+                (MapKey::Relative, MapValue::Str(path)) if location.is_none() => location = Some(path),
+                _ => other_keys = true,
+            }
+        }
+
+        match location {
+            Some(path) if !other_keys => Ok(InstallLocation::Relative(path)),
+            _ => Ok(InstallLocation::Unknown),
+        }
+    }
+}
+
+/// Key of a map-shaped install location, anything but `relative` is a kind this version
+/// doesn't know
+#[derive(Deserialize)]
+#[serde(field_identifier, rename_all = "lowercase")]
+enum MapKey {
+    Relative,
+    #[serde(other)]
+    Other,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum MapValue {
+    Str(String),
+    Other(de::IgnoredAny),
+}
+
+/// The map form of `InstallLocation::Relative` as written in a plugin.toml
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RelativeLocation {
+    relative: String,
 }
 
 #[non_exhaustive]
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum InstallLocation {
     AbsolutePath(String),
+    /// A path relative to the plugin's install root, which the client picks (by default the
+    /// game's `atmosphere/contents/<title id>` folder). Sent as `{"relative": "<path>"}`, which
+    /// clients from before protocol version 4 read as `Unknown`.
+    Relative(String),
     Unknown,
 }
 
+impl InstallLocation {
+    /// Normalize the path of an `AbsolutePath` or `Relative`, see `normalize_path`. Relative
+    /// paths may not have a scheme or start with a separator.
+    pub fn normalized(&self) -> Result<InstallLocation, PathError> {
+        match self {
+            InstallLocation::AbsolutePath(path) => Ok(InstallLocation::AbsolutePath(normalize_path(path)?)),
+            InstallLocation::Relative(path) => {
+                let normalized = normalize_path(path)?;
+                if normalized.contains(':') || normalized.starts_with('/') {
+                    return Err(PathError::NotRelative(path.clone()))
+                }
+
+                Ok(InstallLocation::Relative(normalized))
+            }
+            other => Ok(other.clone()),
+        }
+    }
+
+    /// Resolve a `Relative` location against `root`, such as `sd:/atmosphere/contents/01006A800016E000`.
+    /// Other locations are returned as they are.
+    pub fn resolve(&self, root: &str) -> Result<InstallLocation, PathError> {
+        match self.normalized()? {
+            InstallLocation::Relative(path) => {
+                Ok(InstallLocation::AbsolutePath(normalize_path(&format!("{}/{}", root, path))?))
+            }
+            other => Ok(other),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum PathError {
+    Empty,
+    MixedScheme(String),
+    NotRelative(String),
+}
+
+impl fmt::Display for PathError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PathError::Empty => f.write_str("install location is empty"),
+            PathError::MixedScheme(path) => write!(f, "install location '{}' mixes multiple schemes", path),
+            PathError::NotRelative(path) => write!(f, "relative install location '{}' is an absolute path", path),
+        }
+    }
+}
+
+impl std::error::Error for PathError {}
+
+/// Normalize an install path so equivalent spellings compare equal: backslashes become
+/// forward slashes, repeated separators are collapsed and trailing separators removed.
+/// A scheme such as `sd:` is only allowed at the start of the path.
+pub fn normalize_path(path: &str) -> Result<String, PathError> {
+    let path = path.trim().replace('\\', "/");
+    if path.is_empty() {
+        return Err(PathError::Empty)
+    }
+
+    let (scheme, rest) = match path.find(':') {
+        Some(idx) => (&path[..=idx], &path[idx + 1..]),
+        None => ("", &path[..]),
+    };
+    if scheme.contains('/') || rest.contains(':') {
+        return Err(PathError::MixedScheme(path.clone()))
+    }
+
+    let components = rest.split('/')
+        .filter(|component| !component.is_empty())
+        .collect::<Vec<_>>()
+        .join("/");
+
+    if scheme.is_empty() && !rest.starts_with('/') {
+        Ok(components)
+    } else {
+        Ok(format!("{}/{}", scheme, components))
+    }
+}
+
+/// Pairs of paths which differ only by case, and so overwrite each other on the console's
+/// case-insensitive SD card. Paths are compared normalized, identical paths aren't reported.
+pub fn case_collisions<'a, I>(paths: I) -> Vec<(String, String)>
+    where I: IntoIterator<Item = &'a str>,
+{
+    let mut seen: Vec<(String, String)> = vec![];
+    let mut collisions = vec![];
+
+    for path in paths {
+        let path = normalize_path(path).unwrap_or_else(|_| path.to_owned());
+        let folded = path.to_lowercase();
+        match seen.iter().find(|(other, _)| other == &folded) {
+            Some((_, first)) if first != &path => collisions.push((first.clone(), path)),
+            Some(_) => {}
+            None => seen.push((folded, path)),
+        }
+    }
+
+    collisions
+}
+
 struct InstallLocationVisitor;
 
 impl Serialize for InstallLocation {
@@ -117,7 +677,15 @@ impl Serialize for InstallLocation {
             S: Serializer {
         match self {
             InstallLocation::AbsolutePath(path) => serializer.serialize_str(path),
-            _ => todo!()
+            InstallLocation::Relative(path) => {
+                use serde::ser::SerializeMap;
+
+                let mut map = serializer.serialize_map(Some(1))?;
+                map.serialize_entry("relative", path)?;
+                map.end()
+            }
+            // deserializes back to `Unknown`, see `deserialize_field_kind`
+            InstallLocation::Unknown => serializer.serialize_unit(),
         }
     }
 }
@@ -131,8 +699,15 @@ impl<'de> Visitor<'de> for InstallLocationVisitor {
         Ok(InstallLocation::AbsolutePath(v.to_owned()))
     }
 
+    fn visit_map<A>(self, map: A) -> Result<Self::Value, A::Error>
+    where
+            A: de::MapAccess<'de>, {
+        let location = RelativeLocation::deserialize(de::value::MapAccessDeserializer::new(map))?;
+        Ok(InstallLocation::Relative(location.relative))
+    }
+
     fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
-        formatter.write_str("a valid semver version string")
+        formatter.write_str("an install path, or a map with a relative path")
     }
 }
 
@@ -140,6 +715,335 @@ impl<'de> Deserialize<'de> for InstallLocation {
     fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
     where
             D: Deserializer<'de> {
-        deserializer.deserialize_string(InstallLocationVisitor)
+        deserializer.deserialize_any(InstallLocationVisitor)
+    }
+}
+
+/// Parse a version requirement such as `>= 0.3.0` or `^1.2`. A bare version is taken as
+/// that version or newer, rather than semver's default of a caret requirement.
+pub fn parse_requirement(requirement: &str) -> Result<semver::VersionReq, semver::ReqParseError> {
+    match semver::Version::parse(requirement.trim()) {
+        Ok(version) => semver::VersionReq::parse(&format!(">={}", version)),
+        Err(_) => semver::VersionReq::parse(requirement),
+    }
+}
+
+/// Whether `version` satisfies `requirement`, None if either fails to parse. Pre-release
+/// versions only satisfy requirements which name a pre-release of the same version.
+pub fn requirement_satisfied(requirement: &str, version: &str) -> Option<bool> {
+    let requirement = parse_requirement(requirement).ok()?;
+    let version = semver::Version::parse(version.trim()).ok()?;
+
+    Some(requirement.matches(&version))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_normalize_path() {
+        let cases = [
+            ("sd:/atmosphere/contents/plugin.nro", Ok("sd:/atmosphere/contents/plugin.nro")),
+            ("sd:\\atmosphere\\contents\\plugin.nro", Ok("sd:/atmosphere/contents/plugin.nro")),
+            ("sd:/a//b", Ok("sd:/a/b")),
+            ("sd:a/b", Ok("sd:/a/b")),
+            ("sd://a\\/b///", Ok("sd:/a/b")),
+            ("sd:/ultimate/mods/", Ok("sd:/ultimate/mods")),
+            ("sd:/", Ok("sd:/")),
+            ("/a//b/", Ok("/a/b")),
+            ("relative//path\\file.txt", Ok("relative/path/file.txt")),
+            ("  sd:/padded.txt ", Ok("sd:/padded.txt")),
+            ("", Err(PathError::Empty)),
+            ("sd:/a/rom:/b", Err(PathError::MixedScheme("sd:/a/rom:/b".into()))),
+            ("/a/sd:/b", Err(PathError::MixedScheme("/a/sd:/b".into()))),
+        ];
+
+        for (input, expected) in cases.iter() {
+            let expected = expected.clone().map(String::from);
+            assert_eq!(normalize_path(input), expected, "normalizing {:?}", input);
+        }
+    }
+
+    #[test]
+    fn test_equivalent_paths() {
+        assert_eq!(normalize_path("sd:/a//b"), normalize_path("sd:/a/b"));
+        assert_eq!(normalize_path("sd:\\a\\b\\"), normalize_path("sd:/a/b"));
+    }
+
+    #[test]
+    fn test_version_fields() {
+        let options = UpdateRequestOptions {
+            client_version: Some("0.1.0 (abc1234, built 2024-01-01T00:00:00Z)".into()),
+            ..Default::default()
+        };
+        let options: UpdateRequestOptions = serde_json::from_str(&serde_json::to_string(&options).unwrap()).unwrap();
+        assert_eq!(options.client_version.as_deref(), Some("0.1.0 (abc1234, built 2024-01-01T00:00:00Z)"));
+
+        // older clients and servers leave them out
+        let options: UpdateRequestOptions = serde_json::from_str(r#"{"protocol_version":3,"accept_inline":true}"#).unwrap();
+        assert_eq!(options.client_version, None);
+        let ping: PingResponse = serde_json::from_str(r#"{"plugin_count":2,"load_failure_count":0}"#).unwrap();
+        assert_eq!(ping.server_version, None);
+    }
+
+    #[test]
+    fn test_rollback_fields() {
+        let options = UpdateRequestOptions { pinned_version: Some("1.1.0".into()), ..Default::default() };
+        let options: UpdateRequestOptions = serde_json::from_str(&serde_json::to_string(&options).unwrap()).unwrap();
+        assert_eq!(options.pinned_version.as_deref(), Some("1.1.0"));
+
+        let response = UpdateResponse { rollback_version: Some("1.1.0".into()), ..Default::default() };
+        let response: UpdateResponse = serde_json::from_str(&serde_json::to_string(&response).unwrap()).unwrap();
+        assert_eq!(response.rollback_version.as_deref(), Some("1.1.0"));
+
+        // older clients and servers leave them out
+        let options: UpdateRequestOptions = serde_json::from_str(r#"{"protocol_version":4,"accept_inline":true}"#).unwrap();
+        assert_eq!(options.pinned_version, None);
+        assert!(!serde_json::to_string(&UpdateResponse::no_update()).unwrap().contains("rollback_version"));
+
+        let options: UpdateRequestOptions = serde_json::from_str(r#"{"protocol_version":11,"target_version":"1.1.0"}"#).unwrap();
+        assert_eq!(options.pinned_version.as_deref(), Some("1.1.0"));
+    }
+
+    #[test]
+    fn test_keep_alive_field() {
+        let response = UpdateResponse { keep_alive_downloads: true, ..Default::default() };
+        let response: UpdateResponse = serde_json::from_str(&serde_json::to_string(&response).unwrap()).unwrap();
+        assert!(response.keep_alive_downloads);
+
+        // only sent when set, older servers leave it out
+        assert!(!serde_json::to_string(&UpdateResponse::no_update()).unwrap().contains("keep_alive_downloads"));
+        let response: UpdateResponse = serde_json::from_str(&serde_json::to_string(&UpdateResponse::no_update()).unwrap()).unwrap();
+        assert!(!response.keep_alive_downloads);
+    }
+
+    #[test]
+    fn test_error_fields() {
+        let response: UpdateResponse = serde_json::from_str(&serde_json::to_string(&UpdateResponse::rate_limited(30)).unwrap()).unwrap();
+        assert_eq!(response.code, ResponseCode::RateLimited);
+        assert_eq!(response.retry_after, Some(30));
+
+        let response: UpdateResponse = serde_json::from_str(&serde_json::to_string(&UpdateResponse::server_error("disk full")).unwrap()).unwrap();
+        assert_eq!(response.code, ResponseCode::ServerError);
+        assert_eq!(response.message.as_deref(), Some("disk full"));
+
+        let response = UpdateResponse::invalid_request().with_message("version '1.2' is not valid semver");
+        let response: UpdateResponse = serde_json::from_str(&serde_json::to_string(&response).unwrap()).unwrap();
+        assert_eq!(response.code, ResponseCode::InvalidRequest);
+        assert_eq!(response.message.as_deref(), Some("version '1.2' is not valid semver"));
+
+        // only sent when set
+        let response = serde_json::to_string(&UpdateResponse::no_update()).unwrap();
+        assert!(!response.contains("retry_after") && !response.contains("message"));
+    }
+
+    #[test]
+    fn test_plugin_options() {
+        let mut options = UpdateRequestOptions::default();
+        options.plugin_options.insert("package".into(), "lite".into());
+        options.plugin_options.insert("region".into(), "eu".into());
+        let request = Request::Update {
+            plugin_name: "plugin".into(),
+            plugin_version: "1.0.0".into(),
+            beta: None,
+            options: Some(options),
+        };
+        let request: Request = serde_json::from_str(&serde_json::to_string(&request).unwrap()).unwrap();
+        match request {
+            Request::Update { options: Some(options), .. } => {
+                assert_eq!(options.plugin_options.len(), 2);
+                assert_eq!(options.plugin_options["package"], "lite");
+                assert_eq!(options.plugin_options["region"], "eu");
+            }
+            request => panic!("unexpected request {:?}", request),
+        }
+
+        // left out when there are none, and by older clients
+        assert!(!serde_json::to_string(&UpdateRequestOptions::default()).unwrap().contains("plugin_options"));
+        let options: UpdateRequestOptions = serde_json::from_str(r#"{"protocol_version":6}"#).unwrap();
+        assert!(options.plugin_options.is_empty());
+    }
+
+    #[test]
+    fn test_requested_channel() {
+        assert_eq!(requested_channel(false, None), STABLE_CHANNEL);
+        assert_eq!(requested_channel(true, None), BETA_CHANNEL);
+        // a named channel wins over the beta flag
+        assert_eq!(requested_channel(true, Some("nightly")), "nightly");
+        assert_eq!(requested_channel(false, Some("nightly")), "nightly");
+
+        let options: UpdateRequestOptions = serde_json::from_str(r#"{"protocol_version":6}"#).unwrap();
+        assert_eq!(options.channel, None);
+    }
+
+    #[test]
+    fn test_matches_platform() {
+        let marked = vec![String::from("switch"), String::from("emulator")];
+        assert!(matches_platform(Some(&marked), "switch"));
+        assert!(matches_platform(Some(&marked), "emulator"));
+        assert!(!matches_platform(Some(&marked), "pc"));
+        assert!(!matches_platform(Some(&marked), "wii u"));
+        assert!(!matches_platform(Some(&[]), "switch"));
+        assert!(matches_platform(None, "pc"));
+        assert!(matches_platform(None, "wii u"));
+
+        let options: UpdateRequestOptions = serde_json::from_str(r#"{"protocol_version":5}"#).unwrap();
+        assert_eq!(options.platform, None);
+    }
+
+    #[test]
+    fn test_display_name() {
+        let mut response = UpdateResponse { plugin_name: "HDR-Release".into(), ..Default::default() };
+        assert_eq!(response.display_name(), "HDR-Release");
+        assert!(!serde_json::to_string(&response).unwrap().contains("display_name"));
+
+        response.display_name = Some("HewDraw Remix".into());
+        let response: UpdateResponse = serde_json::from_str(&serde_json::to_string(&response).unwrap()).unwrap();
+        assert_eq!(response.display_name(), "HewDraw Remix");
+        assert_eq!(response.plugin_name, "HDR-Release");
+    }
+
+    #[test]
+    fn test_unknown_install_location() {
+        for location in &[r#"{"Romfs":"a"}"#, r#"["a",{"b":[]}]"#, "7", "true", "null"] {
+            let json = format!(r#"{{"install_location":{},"download_index":0,"size":1}}"#, location);
+            let file: UpdateFile = serde_json::from_str(&json).unwrap();
+            assert_eq!(file.install_location, InstallLocation::Unknown);
+        }
+
+        let file = UpdateFile { install_location: InstallLocation::Unknown, download_index: 0, size: 1, inline_data: None, sha256: None, signature: None };
+        let file: UpdateFile = serde_json::from_str(&serde_json::to_string(&file).unwrap()).unwrap();
+        assert_eq!(file.install_location, InstallLocation::Unknown);
+    }
+
+    #[test]
+    fn test_signed_message() {
+        let file = UpdateFile {
+            install_location: InstallLocation::AbsolutePath("sd:/a.nro".into()),
+            download_index: 0,
+            size: 1,
+            inline_data: None,
+            sha256: Some("ab".repeat(32)),
+            signature: None,
+        };
+        let response = UpdateResponse {
+            plugin_name: "plugin".into(),
+            new_plugin_version: "1.0.0".into(),
+            files_to_delete: vec!["sd:/old.nro".into()],
+            ..Default::default()
+        };
+        let message = response.signed_message(&file).unwrap();
+
+        // every covered field changes the message
+        let moved = UpdateFile { install_location: InstallLocation::AbsolutePath("sd:/b.nro".into()), ..file.clone() };
+        let relative = UpdateFile { install_location: InstallLocation::Relative("sd:/a.nro".into()), ..file.clone() };
+        let rehashed = UpdateFile { sha256: Some("cd".repeat(32)), ..file.clone() };
+        for other in &[moved, relative, rehashed] {
+            assert_ne!(response.signed_message(other).unwrap(), message);
+        }
+        let responses = [
+            UpdateResponse { plugin_name: "other".into(), ..response.clone() },
+            UpdateResponse { new_plugin_version: "1.0.1".into(), ..response.clone() },
+            UpdateResponse { files_to_delete: vec![], ..response.clone() },
+            UpdateResponse { files_to_delete: vec!["sd:/old.nro".into(), "sd:/a.nro".into()], ..response.clone() },
+        ];
+        for other in &responses {
+            assert_ne!(other.signed_message(&file).unwrap(), message);
+        }
+
+        // fields can't be shifted into each other
+        let shifted = UpdateResponse { plugin_name: "plugin\n1".into(), new_plugin_version: ".0.0".into(), ..response.clone() };
+        assert_ne!(shifted.signed_message(&file).unwrap(), message);
+
+        assert_eq!(response.signed_message(&UpdateFile { sha256: None, ..file }), None);
+    }
+
+    #[test]
+    fn test_relative_install_location() {
+        let file = UpdateFile {
+            install_location: InstallLocation::Relative("romfs/skyline/plugins/libtest.nro".into()),
+            download_index: 0,
+            size: 1,
+            inline_data: None,
+            sha256: None,
+            signature: None,
+        };
+        let json = serde_json::to_string(&file).unwrap();
+        assert!(json.contains(r#"{"relative":"romfs/skyline/plugins/libtest.nro"}"#));
+        assert_eq!(serde_json::from_str::<UpdateFile>(&json).unwrap(), file);
+
+        // a map with anything else is a kind of location this version doesn't know
+        for location in &[r#"{"relative":"a","root":"b"}"#, r#"{"relative":7}"#, r#"{"relative":"a","relative":"b"}"#] {
+            let json = format!(r#"{{"install_location":{},"download_index":0,"size":1}}"#, location);
+            let file: UpdateFile = serde_json::from_str(&json).unwrap();
+            assert_eq!(file.install_location, InstallLocation::Unknown);
+        }
+
+        let location = InstallLocation::Relative("romfs\\skyline//plugins/".into());
+        assert_eq!(location.normalized(), Ok(InstallLocation::Relative("romfs/skyline/plugins".into())));
+        assert_eq!(
+            location.resolve("sd:/atmosphere/contents/01006A800016E000/"),
+            Ok(InstallLocation::AbsolutePath("sd:/atmosphere/contents/01006A800016E000/romfs/skyline/plugins".into()))
+        );
+        assert_eq!(
+            InstallLocation::AbsolutePath("sd:/a.txt".into()).resolve("sd:/b"),
+            Ok(InstallLocation::AbsolutePath("sd:/a.txt".into()))
+        );
+        for path in &["sd:/romfs/a.txt", "/romfs/a.txt"] {
+            assert_eq!(InstallLocation::Relative(path.to_string()).normalized(), Err(PathError::NotRelative(path.to_string())));
+        }
+    }
+
+    #[test]
+    fn test_download_stats_inconsistent() {
+        let stats = DownloadStats { attempts: 1, completed: 3, ..Default::default() };
+        assert_eq!(stats.incomplete(), 0);
+    }
+
+    #[test]
+    fn test_case_collisions() {
+        let paths = [
+            "sd:/Mods/foo.prc",
+            "sd:/mods/FOO.prc",
+            "sd:/mods/bar.prc",
+            "sd:\\mods\\bar.prc",
+            "sd:/mods/baz.prc",
+            "sd:/MODS/Foo.PRC",
+        ];
+
+        assert_eq!(case_collisions(paths.iter().copied()), vec![
+            ("sd:/Mods/foo.prc".to_owned(), "sd:/mods/FOO.prc".to_owned()),
+            ("sd:/Mods/foo.prc".to_owned(), "sd:/MODS/Foo.PRC".to_owned()),
+        ]);
+        assert!(case_collisions(vec!["sd:/a.txt", "sd:/b.txt"]).is_empty());
+    }
+
+    #[test]
+    fn test_requirements() {
+        let cases = [
+            // bare versions mean that version or newer
+            ("0.3.0", "0.3.0", true),
+            ("0.3.0", "1.2.0", true),
+            ("0.3.0", "0.2.9", false),
+            (">= 0.3.0", "0.3.1", true),
+            ("^0.3", "0.3.5", true),
+            ("^0.3", "0.4.0", false),
+            ("<1.0.0", "0.9.0", true),
+            ("*", "5.0.0", true),
+            // pre-releases only match requirements on a pre-release of the same version
+            (">=0.3.0", "0.4.0-beta.1", false),
+            (">=0.4.0-beta.1", "0.4.0-beta.2", true),
+            (">=0.4.0-beta.1", "0.4.0", true),
+            ("0.4.0-beta.1", "0.4.0-beta.2", true),
+            ("0.4.0-beta.2", "0.4.0-beta.1", false),
+        ];
+
+        for &(requirement, version, expected) in &cases {
+            assert_eq!(requirement_satisfied(requirement, version), Some(expected), "{} {}", requirement, version);
+        }
+
+        assert_eq!(requirement_satisfied("not a requirement", "1.0.0"), None);
+        assert_eq!(requirement_satisfied(">=1.0.0", "1.0"), None);
     }
 }
@@ -0,0 +1,115 @@
+//! Runs the update server against a copy of the example plugins. Shared by the `host` example
+//! and the integration test which keeps the examples working.
+
+use std::fs;
+use std::io::{self, BufRead, BufReader};
+use std::net::TcpStream;
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, Stdio};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+/// Servers launched by this process so far, so each gets a working directory of its own
+static LAUNCHED: AtomicUsize = AtomicUsize::new(0);
+
+/// The example plugins, in the layout of a server's `plugins` folder
+pub fn fixtures() -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR")).join("examples/host/plugins")
+}
+
+/// A running update server, stopped and cleaned up once dropped
+pub struct Host {
+    server: Child,
+    workdir: PathBuf,
+    pub port: u16,
+}
+
+impl Drop for Host {
+    fn drop(&mut self) {
+        let _ = self.server.kill();
+        let _ = self.server.wait();
+        let _ = fs::remove_dir_all(&self.workdir);
+    }
+}
+
+fn copy_dir(from: &Path, to: &Path) -> io::Result<()> {
+    fs::create_dir_all(to)?;
+    for entry in fs::read_dir(from)? {
+        let entry = entry?;
+        let target = to.join(entry.file_name());
+        if entry.file_type()?.is_dir() {
+            copy_dir(&entry.path(), &target)?;
+        } else {
+            fs::copy(entry.path(), target)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// The request and download ports in the server's `ports <port> <download port>` line
+fn listening_ports(line: &str) -> Option<(u16, u16)> {
+    let mut ports = line.strip_prefix("ports ")?.split(' ').map(str::parse);
+    match (ports.next()?, ports.next()?, ports.next()) {
+        (Ok(port), Ok(download_port), None) => Some((port, download_port)),
+        _ => None,
+    }
+}
+
+/// Start the `update-server` binary at `server` on free ports, in a new working directory
+/// holding a copy of the example plugins, as the server builds folder archives in a cache next
+/// to the plugins folder.
+/// Returns once the server accepts connections. The server's output is printed as it runs.
+pub fn launch(server: &Path) -> io::Result<Host> {
+    launch_with_args(server, &[])
+}
+
+/// `launch`, passing `args` to the server as well, such as `--tls-cert`
+pub fn launch_with_args(server: &Path, args: &[&str]) -> io::Result<Host> {
+    let launched = LAUNCHED.fetch_add(1, Ordering::SeqCst);
+    let workdir = std::env::temp_dir().join(format!("update-server-example-{}-{}", std::process::id(), launched));
+    let _ = fs::remove_dir_all(&workdir);
+    copy_dir(&fixtures(), &workdir.join("plugins"))?;
+
+    let mut child = Command::new(server)
+        .args(["--port", "0"])
+        .args(args)
+        .current_dir(&workdir)
+        .stdout(Stdio::piped())
+        .spawn()?;
+    let mut lines = BufReader::new(child.stdout.take().unwrap()).lines();
+    let mut host = Host { server: child, workdir, port: 0 };
+    let mut download_port = 0;
+
+    for line in &mut lines {
+        let line = line?;
+        println!("[server] {}", line);
+        if let Some((port, downloads)) = listening_ports(&line) {
+            host.port = port;
+            download_port = downloads;
+            break
+        }
+    }
+    if host.port == 0 {
+        return Err(io::Error::new(io::ErrorKind::Other, "the server exited before listening"))
+    }
+
+    // keep reading so the server never blocks on a full pipe
+    std::thread::spawn(move || {
+        for line in lines.map_while(Result::ok) {
+            println!("[server] {}", line);
+        }
+    });
+
+    // the ports are picked before the plugins are loaded, connections are refused until then
+    let start = Instant::now();
+    let accepting = |port| TcpStream::connect(("127.0.0.1", port)).is_ok();
+    while !accepting(host.port) || !accepting(download_port) {
+        if start.elapsed() > Duration::from_secs(30) {
+            return Err(io::Error::new(io::ErrorKind::TimedOut, "the server did not start accepting requests"))
+        }
+        std::thread::sleep(Duration::from_millis(50));
+    }
+
+    Ok(host)
+}
@@ -0,0 +1,50 @@
+//! Hosts the example plugins in `examples/host/plugins` on free ports until enter is pressed.
+//!
+//! ```text
+//! cargo build -p update-server
+//! cargo run -p update-server --example host
+//! ```
+//!
+//! Then install them into a sandbox with `cargo run -p skyline-update --features logger --example client -- 127.0.0.1 <port>`.
+
+mod launch;
+
+use std::path::PathBuf;
+
+/// The server binary given as the first argument, or the one built next to this example
+fn server_binary() -> Option<PathBuf> {
+    if let Some(path) = std::env::args_os().nth(1) {
+        return Some(path.into())
+    }
+
+    // target/<profile>/examples/host -> target/<profile>/update-server
+    let exe = std::env::current_exe().ok()?;
+    let path = exe.parent()?.parent()?.join(format!("update-server{}", std::env::consts::EXE_SUFFIX));
+    Some(path).filter(|path| path.exists())
+}
+
+fn main() {
+    let server = match server_binary() {
+        Some(server) => server,
+        None => {
+            println!("update-server was not found, build it first with `cargo build -p update-server`");
+            std::process::exit(1);
+        }
+    };
+
+    println!("Hosting the example plugins in {}", launch::fixtures().display());
+    let host = match launch::launch(&server) {
+        Ok(host) => host,
+        Err(e) => {
+            println!("Failed to start {}: {}", server.display(), e);
+            std::process::exit(1);
+        }
+    };
+
+    println!();
+    println!("Serving on port {}, install the plugins with:", host.port);
+    println!("    cargo run -p skyline-update --features logger --example client -- 127.0.0.1 {}", host.port);
+    println!("Press enter to stop the server");
+
+    let _ = std::io::stdin().read_line(&mut String::new());
+}
@@ -0,0 +1,265 @@
+//! Drives the server with the client as it shipped before protocol versioning, to make sure
+//! plugins built against it keep updating. The `v1` module is a verbatim copy of that
+//! client's protocol types and must not be changed to follow the current protocol.
+
+use super::*;
+
+mod v1 {
+    use std::fmt;
+    use serde::{Serializer, Deserializer};
+    use serde::{Serialize, Deserialize, ser, de::{self, Visitor}};
+
+    #[derive(Serialize, Deserialize, Debug, Clone)]
+    pub enum ResponseCode {
+        NoUpdate,
+        Update,
+        PluginNotFound,
+        InvalidRequest,
+    }
+
+    #[derive(Serialize, Deserialize, Debug, Clone)]
+    pub struct UpdateResponse {
+        pub code: ResponseCode,
+        pub update_plugin: bool,
+        pub update_skyline: bool,
+        pub plugin_name: String,
+        pub new_plugin_version: String,
+        pub new_skyline_version: Option<String>,
+        pub required_files: Vec<UpdateFile>,
+    }
+
+    #[derive(Serialize, Deserialize, Debug, Clone)]
+    pub struct PluginMetadata {
+        pub name: Option<String>,
+        pub description: Option<String>,
+        pub images_index: u64,
+        pub image_count: u64,
+        pub changelog_index: u64,
+    }
+
+    #[derive(Serialize, Deserialize, Debug, Clone)]
+    pub struct UpdateFile {
+        #[serde(deserialize_with = "deserialize_field_kind")]
+        pub install_location: InstallLocation,
+
+        pub download_index: u64,
+        pub size: usize,
+    }
+
+    #[derive(Serialize, Deserialize, Debug, Clone)]
+    pub struct UpdateRequestOptions {
+
+    }
+
+    #[derive(Serialize, Deserialize, Debug, Clone)]
+    pub enum Request {
+        Update {
+            plugin_name: String,
+            plugin_version: String,
+            beta: Option<bool>,
+            options: Option<UpdateRequestOptions>,
+        },
+        Metadata {
+            plugin_name: String,
+            beta: Option<bool>,
+        },
+    }
+
+    fn deserialize_field_kind<'de, D>(deserializer: D) -> Result<InstallLocation, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        Ok(InstallLocation::deserialize(deserializer).unwrap_or(InstallLocation::Unknown))
+    }
+
+    #[derive(Debug, Clone)]
+    pub enum InstallLocation {
+        AbsolutePath(String),
+        Unknown,
+    }
+
+    struct InstallLocationVisitor;
+
+    impl Serialize for InstallLocation {
+        fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+        where
+                S: Serializer {
+            match self {
+                InstallLocation::AbsolutePath(path) => serializer.serialize_str(path),
+                // left as `todo!()` in the shipped client, which never serialized it
+                InstallLocation::Unknown => Err(ser::Error::custom("unsupported install location")),
+            }
+        }
+    }
+
+    impl<'de> Visitor<'de> for InstallLocationVisitor {
+        type Value = InstallLocation;
+
+        fn visit_str<E>(self, v: &str) -> Result<Self::Value, E>
+        where
+                E: de::Error, {
+            Ok(InstallLocation::AbsolutePath(v.to_owned()))
+        }
+
+        fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+            formatter.write_str("a valid semver version string")
+        }
+    }
+
+    impl<'de> Deserialize<'de> for InstallLocation {
+        fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+        where
+                D: Deserializer<'de> {
+            deserializer.deserialize_string(InstallLocationVisitor)
+        }
+    }
+}
+
+/// The response a v1 client got, and the path and contents of every file it downloaded
+type V1Update = (v1::UpdateResponse, Vec<(String, Vec<u8>)>);
+
+/// The v1 client's update flow with its sockets replaced by the server's handlers: a
+/// newline-terminated JSON request, then one raw 8-byte index per file, read until close.
+fn v1_update(state: &ServerState, settings: &Settings, name: &str, version: &str) -> Option<V1Update> {
+    let packet = serde_json::to_string(&v1::Request::Update {
+        beta: Some(false),
+        plugin_name: name.to_owned(),
+        plugin_version: version.to_owned(),
+        options: None,
+    }).ok()?;
+
+    let string = handle_request(&format!("{}\n", packet), state, settings) + "\n";
+    let response: v1::UpdateResponse = serde_json::from_str(&string).ok()?;
+
+    let files = response.required_files.iter()
+        .map(|file| {
+            let data = handle_download(u64::to_be_bytes(file.download_index), state)?;
+            match &file.install_location {
+                v1::InstallLocation::AbsolutePath(path) => Some((path.clone(), data.read().ok()?.to_vec())),
+                _ => None
+            }
+        })
+        .collect::<Option<Vec<_>>>()?;
+
+    Some((response, files))
+}
+
+fn state() -> ServerState {
+    let files = [
+        ("sd:/atmosphere/contents/01006A800016E000/romfs/skyline/plugins/libtest.nro", vec![0xAB; 200 * 1024]),
+        ("sd:/test.txt", b"small file".to_vec()),
+    ];
+
+    let plugin = Plugin {
+        directory: PathBuf::from("plugins/test_plugin"),
+        name: "test_plugin".into(),
+        plugin_version: "1.0.0".parse().unwrap(),
+        files: files.iter()
+            .enumerate()
+            .map(|(i, (path, data))| PluginFile {
+                install: InstallLocation::AbsolutePath(path.to_string()),
+                data: served::ServedFile::from(data.clone()),
+                index: i as u64,
+                sha256: crate::manifest::sha256(data),
+                platform: None,
+            })
+            .collect(),
+        metadata_files: vec![],
+        metadata: PluginMetadata {
+            name: Some("Test Plugin".into()),
+            description: None,
+            images_index: 2,
+            image_count: 0,
+            changelog_index: 2,
+            images: vec![],
+            homepage: None,
+            display_name: None,
+        },
+        skyline_version: None,
+        channel: update_protocol::STABLE_CHANNEL.into(),
+        notify_only: false,
+        changelog: None,
+        version_ordering: Default::default(),
+        prerelease_on_stable: false,
+        rollback: false,
+        deleted_files: vec![],
+    };
+
+    ServerState {
+        plugins: vec![plugin],
+        load_failures: vec![],
+        index_blocks: vec![],
+        reload_history: VecDeque::new(),
+        downloads: Default::default(),
+        reload_in_progress: AtomicBool::new(false),
+        reload_error: None,
+    }
+}
+
+fn settings() -> Settings {
+    Settings {
+        inline: InlineConfig { threshold: INLINE_THRESHOLD, max_total: INLINE_MAX_TOTAL },
+        admin_token: None,
+        history_len: RELOAD_HISTORY_LEN,
+        download_workers: downloads::DOWNLOAD_WORKERS,
+        public_download_port: Some(62001),
+        bind_address: IpAddr::V4(Ipv4Addr::LOCALHOST),
+        port: PORT_NUM,
+        download_port: None,
+        watch_debounce: Duration::from_secs(WATCH_DEBOUNCE_SECS),
+        log_level: log::LevelFilter::Info,
+        tls: tls::Acceptor::default(),
+        signing_key: None,
+        plugins_dir: PathBuf::from(DEFAULT_PLUGINS_DIR),
+        withhold_outdated_skyline: false,
+    }
+}
+
+#[test]
+fn test_v1_update_cycle() {
+    let state = state();
+    let (response, files) = v1_update(&state, &settings(), "test_plugin", "0.9.0").unwrap();
+
+    assert!(matches!(response.code, v1::ResponseCode::Update));
+    assert_eq!(response.new_plugin_version, "1.0.0");
+    assert_eq!(files.len(), 2);
+    for ((path, data), file) in files.iter().zip(&state.plugins[0].files) {
+        assert!(matches!(&file.install, InstallLocation::AbsolutePath(expected) if expected == path));
+        assert_eq!(data, &*file.data.read().unwrap());
+    }
+}
+
+#[test]
+fn test_v1_gets_no_new_features() {
+    let request = r#"{"Update":{"plugin_name":"test_plugin","plugin_version":"0.9.0","beta":false,"options":null}}"#;
+    let response = handle_request(request, &state(), &settings());
+
+    assert!(!response.contains("inline_data"));
+    assert!(!response.contains("download_port"));
+    assert!(!response.contains("sha256"));
+}
+
+#[test]
+fn test_v1_no_update_and_not_found() {
+    let state = state();
+
+    let (response, files) = v1_update(&state, &settings(), "test_plugin", "1.0.0").unwrap();
+    assert!(matches!(response.code, v1::ResponseCode::NoUpdate));
+    assert!(files.is_empty());
+
+    let (response, _) = v1_update(&state, &settings(), "missing_plugin", "1.0.0").unwrap();
+    assert!(matches!(response.code, v1::ResponseCode::PluginNotFound));
+}
+
+#[test]
+fn test_v1_metadata() {
+    let packet = serde_json::to_string(&v1::Request::Metadata {
+        plugin_name: "test_plugin".into(),
+        beta: None,
+    }).unwrap();
+
+    let response = handle_request(&format!("{}\n", packet), &state(), &settings());
+    let metadata: v1::PluginMetadata = serde_json::from_str(&response).unwrap();
+
+    assert_eq!(metadata.name.as_deref(), Some("Test Plugin"));
+}
@@ -0,0 +1,111 @@
+use std::fs;
+use std::io;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+
+use color_eyre::eyre;
+use log::LevelFilter;
+use serde::{Deserialize, Serialize};
+
+/// File the server reads its configuration from unless `--config` names another
+pub const DEFAULT_CONFIG_FILE: &str = "server.toml";
+
+/// Settings read from `server.toml`, every one optional. The environment variables and command
+/// line flags for a setting win over the file, which wins over the defaults.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct ConfigFile {
+    /// Address both ports are bound to, `0.0.0.0` by default
+    pub bind_address: Option<IpAddr>,
+    /// Port requests are accepted on
+    pub port: Option<u16>,
+    /// Port downloads are accepted on, the port after `port` by default
+    pub download_port: Option<u16>,
+    pub plugins_dir: Option<PathBuf>,
+    /// How long changes to the plugins folder have to settle before plugins are reloaded
+    pub watch_debounce_secs: Option<u64>,
+    /// One of `off`, `error`, `warn`, `info`, `debug` or `trace`
+    pub log_level: Option<String>,
+}
+
+impl ConfigFile {
+    /// Read and check the file at `path`. A missing file is only an error if it was `required`,
+    /// such as a path given with `--config`, otherwise every setting is left to its default.
+    pub fn read(path: &Path, required: bool) -> eyre::Result<Self> {
+        let text = match fs::read_to_string(path) {
+            Ok(text) => text,
+            Err(e) if e.kind() == io::ErrorKind::NotFound && !required => return Ok(ConfigFile::default()),
+            Err(e) => eyre::bail!("Failed to read {}: {}", path.display(), e),
+        };
+
+        ConfigFile::parse(&text).map_err(|e| eyre::eyre!("Invalid {}: {}", path.display(), e))
+    }
+
+    fn parse(text: &str) -> eyre::Result<Self> {
+        let config: ConfigFile = toml::from_str(text)?;
+        config.log_level()?;
+        if let (Some(port), Some(download_port)) = (config.port, config.download_port) {
+            if port == download_port && port != 0 {
+                eyre::bail!("download_port is the same as port ({})", port);
+            }
+        }
+
+        Ok(config)
+    }
+
+    /// `log_level` as a filter, None if it isn't set
+    pub fn log_level(&self) -> eyre::Result<Option<LevelFilter>> {
+        self.log_level.as_deref()
+            .map(|level| level.parse().map_err(|_| {
+                eyre::eyre!("log_level '{}' is not one of off, error, warn, info, debug or trace", level)
+            }))
+            .transpose()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let config = ConfigFile::parse(r#"
+            bind_address = "127.0.0.1"
+            port = 46000
+            download_port = 47000
+            plugins_dir = "/srv/plugins"
+            watch_debounce_secs = 2
+            log_level = "debug"
+        "#).unwrap();
+        assert_eq!(config.bind_address, Some("127.0.0.1".parse().unwrap()));
+        assert_eq!(config.download_port, Some(47000));
+        assert_eq!(config.log_level().unwrap(), Some(LevelFilter::Debug));
+
+        assert_eq!(ConfigFile::parse("").unwrap(), ConfigFile::default());
+        let missing = std::env::temp_dir().join("update-server-missing-config.toml");
+        assert_eq!(ConfigFile::read(&missing, false).unwrap(), ConfigFile::default());
+        assert!(ConfigFile::read(&missing, true).is_err());
+    }
+
+    #[test]
+    fn test_invalid() {
+        let error = |text: &str| ConfigFile::parse(text).unwrap_err().to_string();
+
+        assert!(error("port = 70000").contains("port"));
+        assert!(error(r#"bind_address = "localhost""#).contains("bind_address"));
+        assert!(error(r#"log_level = "loud""#).contains("log_level 'loud'"));
+        assert!(error("prot = 46000").contains("unknown field `prot`"));
+        assert!(error("port = 46000\ndownload_port = 46000").contains("download_port"));
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let config = ConfigFile {
+            port: Some(46000),
+            plugins_dir: Some("plugins".into()),
+            log_level: Some("info".into()),
+            ..Default::default()
+        };
+        assert_eq!(ConfigFile::parse(&toml::to_string(&config).unwrap()).unwrap(), config);
+    }
+}
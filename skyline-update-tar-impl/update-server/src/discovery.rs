@@ -0,0 +1,93 @@
+use std::net::UdpSocket;
+use std::sync::RwLock;
+
+use color_eyre::eyre;
+use update_protocol::{DiscoveryResponse, DISCOVERY_MAGIC, DISCOVERY_PORT};
+
+use crate::{read_state, server_version, ServerState};
+
+/// Name servers answer discovery with unless `UPDATE_SERVER_NAME` says otherwise
+pub const DEFAULT_NAME: &str = "update-server";
+
+/// Longest datagram read, anything longer than `DISCOVERY_MAGIC` is ignored anyway
+const MAX_DATAGRAM_LEN: usize = 64;
+
+/// Answer discovery datagrams on `DISCOVERY_PORT` as `name`, for servers started with
+/// `--discoverable`
+pub fn answer_discovery(state: &RwLock<ServerState>, name: &str, port: u16) -> eyre::Result<()> {
+    let socket = UdpSocket::bind(("0.0.0.0", DISCOVERY_PORT))?;
+    serve_discovery(&socket, || {
+        let state = read_state(state);
+        discovery_response(name, port, state.plugins.iter().map(|plugin| plugin.name.as_str()))
+    })
+}
+
+/// Answer every `DISCOVERY_MAGIC` datagram received on `socket` with `respond()`, ignoring
+/// anything else
+fn serve_discovery<F>(socket: &UdpSocket, respond: F) -> eyre::Result<()>
+    where F: Fn() -> DiscoveryResponse,
+{
+    let mut buf = [0; MAX_DATAGRAM_LEN];
+    loop {
+        let (len, from) = socket.recv_from(&mut buf)?;
+        if &buf[..len] != DISCOVERY_MAGIC {
+            continue
+        }
+
+        let response = serde_json::to_vec(&respond())?;
+        if let Err(e) = socket.send_to(&response, from) {
+            println!("Failed to answer discovery from {}: {}", from, e);
+        }
+    }
+}
+
+/// Every plugin hosted is listed once, whatever the number of versions and channels
+fn discovery_response<'a>(name: &str, port: u16, plugins: impl Iterator<Item = &'a str>) -> DiscoveryResponse {
+    let mut plugins: Vec<String> = plugins.map(str::to_owned).collect();
+    plugins.sort();
+    plugins.dedup();
+
+    DiscoveryResponse {
+        name: name.to_owned(),
+        port,
+        plugins,
+        server_version: Some(server_version().to_owned()),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn test_discovery_response() {
+        let response = discovery_response("desk", 45000, vec!["b", "a", "b"].into_iter());
+        assert_eq!(response.name, "desk");
+        assert_eq!(response.port, 45000);
+        assert_eq!(response.plugins, vec!["a", "b"]);
+    }
+
+    #[test]
+    fn test_serve_discovery() {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = socket.local_addr().unwrap();
+        std::thread::spawn(move || serve_discovery(&socket, || discovery_response("desk", 46000, vec!["a"].into_iter())));
+
+        let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+        client.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let mut buf = [0; 1024];
+
+        // anything but the magic goes unanswered
+        client.send_to(b"SKYLINE_UPDATE", addr).unwrap();
+        client.send_to(DISCOVERY_MAGIC, addr).unwrap();
+        let (len, from) = client.recv_from(&mut buf).unwrap();
+        assert_eq!(from, addr);
+        let response: DiscoveryResponse = serde_json::from_slice(&buf[..len]).unwrap();
+        assert_eq!(response.port, 46000);
+        assert_eq!(response.plugins, vec!["a"]);
+
+        client.set_read_timeout(Some(Duration::from_millis(100))).unwrap();
+        assert!(client.recv_from(&mut buf).is_err());
+    }
+}
@@ -0,0 +1,360 @@
+use std::io::{self, prelude::*};
+use std::net::TcpStream;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use color_eyre::eyre;
+use update_protocol::{DownloadStats, Request};
+
+use crate::{served, tls};
+
+/// How much is written to a download socket at a time
+pub const CHUNK_SIZE: usize = 64 * 1024;
+
+/// A download is aborted once the client hasn't accepted any data for this long
+pub const STALL_TIMEOUT: Duration = Duration::from_secs(30);
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DisconnectReason {
+    Completed,
+    PeerReset,
+    StallTimeout,
+    Error,
+}
+
+impl DisconnectReason {
+    fn from_error(err: &io::Error) -> Self {
+        match err.kind() {
+            io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut => DisconnectReason::StallTimeout,
+            io::ErrorKind::ConnectionReset
+            | io::ErrorKind::ConnectionAborted
+            | io::ErrorKind::BrokenPipe
+            | io::ErrorKind::WriteZero => DisconnectReason::PeerReset,
+            _ => DisconnectReason::Error,
+        }
+    }
+}
+
+/// The outcome of sending a single file over the download port
+#[derive(Debug, Clone, PartialEq)]
+pub struct DownloadSession {
+    pub index: u64,
+    pub bytes_written: u64,
+    pub reason: DisconnectReason,
+}
+
+impl DownloadSession {
+    pub fn completed(&self) -> bool {
+        self.reason == DisconnectReason::Completed
+    }
+}
+
+/// Send everything `data` returns in chunks, keeping track of how much made it to the socket
+/// before it failed. A stall shows up as a write timeout, so the socket should have one set.
+pub fn send<W: Write, R: Read>(socket: &mut W, index: u64, mut data: R) -> DownloadSession {
+    let mut bytes_written = 0;
+    let mut reason = DisconnectReason::Completed;
+
+    let mut chunk = vec![0; CHUNK_SIZE];
+    'chunks: loop {
+        let len = match data.read(&mut chunk) {
+            Ok(0) => break,
+            Ok(len) => len,
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+            Err(err) => {
+                println!("Failed to read file for download {}: {}", index, err);
+                reason = DisconnectReason::Error;
+                break
+            }
+        };
+
+        let mut remaining = &chunk[..len];
+        while !remaining.is_empty() {
+            match socket.write(remaining) {
+                Ok(0) => {
+                    reason = DisconnectReason::PeerReset;
+                    break 'chunks
+                }
+                Ok(written) => {
+                    bytes_written += written as u64;
+                    remaining = &remaining[written..];
+                }
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+                Err(err) => {
+                    reason = DisconnectReason::from_error(&err);
+                    break 'chunks
+                }
+            }
+        }
+    }
+
+    if reason == DisconnectReason::Completed {
+        if let Err(err) = socket.flush() {
+            reason = DisconnectReason::from_error(&err);
+        }
+    }
+
+    DownloadSession { index, bytes_written, reason }
+}
+
+/// `send`, prefixed with the 8-byte big endian `len` of `data` for kept-alive connections, see
+/// `update_protocol::KEEP_ALIVE_INDEX`. A file which ends before `len` leaves the client
+/// waiting for the rest, so it is an error.
+pub fn send_framed<W: Write, R: Read>(socket: &mut W, index: u64, len: u64, data: R) -> DownloadSession {
+    if let Err(err) = socket.write_all(&u64::to_be_bytes(len)) {
+        return DownloadSession { index, bytes_written: 0, reason: DisconnectReason::from_error(&err) }
+    }
+
+    let mut session = send(socket, index, data.take(len));
+    if session.completed() && session.bytes_written != len {
+        session.reason = DisconnectReason::Error;
+    }
+    session
+}
+
+/// Default number of threads sending downloads
+pub const DOWNLOAD_WORKERS: usize = 16;
+
+/// Connections waiting for a free download worker, further connections are refused
+pub const DOWNLOAD_QUEUE_LEN: usize = 256;
+
+/// A download a worker has read the index of, ready to send
+pub struct DownloadJob {
+    pub socket: tls::Socket,
+    pub index: u64,
+    /// Opened when the download was accepted, so a reload doesn't change it midway
+    pub data: served::OpenFile,
+    pub plugin: String,
+    pub file: String,
+    pub tracker: Arc<DownloadTracker>,
+    /// Whether the connection started with `update_protocol::KEEP_ALIVE_INDEX`
+    pub keep_alive: bool,
+}
+
+impl DownloadJob {
+    /// Send the file, returning the socket to read the next index from if the connection is
+    /// kept alive and the file was sent in full, closing it otherwise
+    pub fn run(mut self) -> Option<tls::Socket> {
+        let len = self.data.len;
+        let session = if self.keep_alive {
+            send_framed(&mut self.socket, self.index, len, &mut self.data)
+        } else {
+            send(&mut self.socket, self.index, &mut self.data)
+        };
+        if !session.completed() {
+            println!(
+                "Download of {} ({}) stopped after {}/{} bytes: {:?}",
+                self.file, self.plugin, session.bytes_written, len, session.reason
+            );
+        }
+        self.tracker.record(&self.plugin, &self.file, len as usize, &session);
+
+        if self.keep_alive && session.completed() {
+            Some(self.socket)
+        } else {
+            // a TLS client only takes the end of the connection as the end of the file once told
+            let _ = self.socket.shutdown();
+            None
+        }
+    }
+}
+
+/// Download statistics per plugin file, shared between the download threads. Entries are
+/// keyed by plugin and install location so they survive reloads renumbering the files.
+#[derive(Default)]
+pub struct DownloadTracker {
+    stats: Mutex<Vec<DownloadStats>>,
+}
+
+impl DownloadTracker {
+    pub fn record(&self, plugin: &str, file: &str, size: usize, session: &DownloadSession) {
+        let mut stats = self.stats.lock().unwrap();
+
+        let position = stats.iter().position(|entry| entry.plugin == plugin && entry.file == file);
+        let entry = match position {
+            Some(i) => &mut stats[i],
+            None => {
+                stats.push(DownloadStats {
+                    plugin: plugin.to_owned(),
+                    file: file.to_owned(),
+                    ..Default::default()
+                });
+                stats.last_mut().unwrap()
+            }
+        };
+
+        entry.size = size;
+        entry.attempts += 1;
+        entry.bytes_sent += session.bytes_written;
+        match session.reason {
+            DisconnectReason::Completed => entry.completed += 1,
+            DisconnectReason::PeerReset => entry.peer_resets += 1,
+            DisconnectReason::StallTimeout => entry.stall_timeouts += 1,
+            DisconnectReason::Error => entry.errors += 1,
+        }
+    }
+
+    /// Copy of the current statistics, worst incomplete rate first
+    pub fn snapshot(&self, incomplete_only: bool) -> Vec<DownloadStats> {
+        let mut stats: Vec<DownloadStats> = self.stats.lock().unwrap()
+            .iter()
+            .filter(|entry| !incomplete_only || entry.incomplete() > 0)
+            .cloned()
+            .collect();
+
+        stats.sort_by(|a, b| {
+            b.incomplete_rate().partial_cmp(&a.incomplete_rate()).unwrap()
+                .then_with(|| b.attempts.cmp(&a.attempts))
+        });
+
+        stats
+    }
+}
+
+fn print_stats(stats: &[DownloadStats]) {
+    if stats.is_empty() {
+        println!("No downloads recorded");
+        return
+    }
+
+    for entry in stats {
+        println!(
+            "{} {} ({} bytes): {}/{} complete ({:.0}% incomplete), {} reset, {} stalled, {} errors, {} bytes sent",
+            entry.plugin, entry.file, entry.size, entry.completed, entry.attempts,
+            entry.incomplete_rate() * 100.0, entry.peer_resets, entry.stall_timeouts,
+            entry.errors, entry.bytes_sent
+        );
+    }
+}
+
+/// `update-server downloads [--incomplete]`: ask the running server for its download
+/// statistics, using the admin token from `UPDATE_SERVER_ADMIN_TOKEN`.
+pub fn run_downloads(port: u16, incomplete_only: bool) -> eyre::Result<()> {
+    let token = std::env::var("UPDATE_SERVER_ADMIN_TOKEN")
+        .map_err(|_| eyre::eyre!("UPDATE_SERVER_ADMIN_TOKEN must be set"))?;
+
+    let mut socket = TcpStream::connect(("127.0.0.1", port))?;
+    let packet = serde_json::to_string(&Request::Downloads { token, incomplete_only })?;
+    socket.write_all(format!("{}\n", packet).as_bytes())?;
+
+    let mut response = String::new();
+    socket.read_to_string(&mut response)?;
+
+    let stats: Vec<DownloadStats> = serde_json::from_str(&response)
+        .map_err(|_| eyre::eyre!("Unexpected response from server, is the admin token correct?"))?;
+    print_stats(&stats);
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::net::TcpListener;
+
+    /// A client which accepts `limit` bytes, then fails every write with `kind`
+    struct FailingClient {
+        limit: usize,
+        received: usize,
+        kind: io::ErrorKind,
+    }
+
+    impl Write for FailingClient {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            let len = buf.len().min(self.limit - self.received);
+            if len == 0 {
+                return Err(self.kind.into());
+            }
+            self.received += len;
+            Ok(len)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn failing(limit: usize, kind: io::ErrorKind) -> FailingClient {
+        FailingClient { limit, received: 0, kind }
+    }
+
+    #[test]
+    fn test_send_completed() {
+        let data = vec![0x55; CHUNK_SIZE * 3 + 10];
+        let mut client = vec![];
+
+        let session = send(&mut client, 0, &data[..]);
+        assert_eq!(session.reason, DisconnectReason::Completed);
+        assert_eq!(session.bytes_written, data.len() as u64);
+        assert_eq!(client, data);
+    }
+
+    #[test]
+    fn test_send_framed() {
+        let mut client = vec![];
+
+        let session = send_framed(&mut client, 0, 5, &b"hello"[..]);
+        assert!(session.completed());
+        assert_eq!(session.bytes_written, 5);
+        assert_eq!(client, [&5u64.to_be_bytes()[..], b"hello"].concat());
+
+        // shorter than the client was told
+        let session = send_framed(&mut vec![], 0, 10, &b"hello"[..]);
+        assert_eq!(session.reason, DisconnectReason::Error);
+    }
+
+    #[test]
+    fn test_send_disconnect_mid_transfer() {
+        let data = vec![0x55; CHUNK_SIZE * 4];
+
+        let session = send(&mut failing(CHUNK_SIZE * 2 + 100, io::ErrorKind::ConnectionReset), 1, &data[..]);
+        assert_eq!(session.reason, DisconnectReason::PeerReset);
+        assert_eq!(session.bytes_written, CHUNK_SIZE as u64 * 2 + 100);
+
+        let session = send(&mut failing(CHUNK_SIZE, io::ErrorKind::WouldBlock), 1, &data[..]);
+        assert_eq!(session.reason, DisconnectReason::StallTimeout);
+        assert_eq!(session.bytes_written, CHUNK_SIZE as u64);
+    }
+
+    #[test]
+    fn test_send_socket_closed() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (mut server, _) = listener.accept().unwrap();
+        server.set_write_timeout(Some(Duration::from_secs(5))).unwrap();
+
+        let mut buf = [0; 1024];
+        let reader = std::thread::spawn(move || {
+            client.read_exact(&mut buf).unwrap();
+        });
+
+        // much more than the socket buffers can hold, the client is gone long before the end
+        let data = vec![0x55; 64 * 1024 * 1024];
+        let session = send(&mut server, 0, &data[..]);
+        reader.join().unwrap();
+
+        assert!(!session.completed());
+        assert!(session.bytes_written < data.len() as u64);
+    }
+
+    #[test]
+    fn test_tracker() {
+        let tracker = DownloadTracker::default();
+        let session = |bytes_written, reason| DownloadSession { index: 0, bytes_written, reason };
+
+        tracker.record("a", "sd:/big.bin", 100, &session(100, DisconnectReason::Completed));
+        tracker.record("a", "sd:/big.bin", 100, &session(60, DisconnectReason::PeerReset));
+        tracker.record("a", "sd:/big.bin", 100, &session(60, DisconnectReason::StallTimeout));
+        tracker.record("a", "sd:/small.txt", 10, &session(10, DisconnectReason::Completed));
+
+        let all = tracker.snapshot(false);
+        assert_eq!(all.len(), 2);
+        assert_eq!(all[0].file, "sd:/big.bin");
+        assert_eq!((all[0].attempts, all[0].completed, all[0].peer_resets, all[0].stall_timeouts), (3, 1, 1, 1));
+        assert_eq!(all[0].bytes_sent, 220);
+
+        let incomplete = tracker.snapshot(true);
+        assert_eq!(incomplete.len(), 1);
+        assert_eq!(incomplete[0].incomplete(), 2);
+    }
+}
@@ -0,0 +1,142 @@
+//! Throws mutated requests at the main port's request handling and mutated frames at the
+//! download port's, from the seeds in `fuzz-corpus`. Any panic fails the test and prints the
+//! input; responses and request buffers must stay within their limits whatever the input.
+
+use super::*;
+use update_protocol::fuzz;
+
+fn corpus(name: &str) -> Vec<Vec<u8>> {
+    fuzz::load_corpus(&Path::new(env!("CARGO_MANIFEST_DIR")).join("../fuzz-corpus").join(name))
+}
+
+/// Every kind of plugin a request can be answered from, with admin requests enabled
+fn state() -> ServerState {
+    let plugin = |name: &str, version: &str, beta: bool, notify_only: bool, first_index: u64| Plugin {
+        directory: PathBuf::from(format!("plugins/{}-{}", name, version)),
+        name: name.into(),
+        plugin_version: version.parse().unwrap(),
+        files: vec![
+            PluginFile {
+                install: InstallLocation::AbsolutePath("sd:/test.txt".into()),
+                data: served::ServedFile::from(b"small file".to_vec()),
+                index: first_index,
+                sha256: crate::manifest::sha256(b"small file"),
+                platform: None,
+            },
+            PluginFile {
+                install: InstallLocation::AbsolutePath("sd:/ultimate/mods/test.tar".into()),
+                data: served::ServedFile::from(vec![0xAB; 64 * 1024]),
+                index: first_index + 1,
+                sha256: crate::manifest::sha256(&[0xAB; 64 * 1024]),
+                platform: None,
+            },
+        ],
+        metadata_files: vec![served::ServedFile::from(b"changelog".to_vec())],
+        metadata: PluginMetadata {
+            name: Some("Test Plugin".into()),
+            description: Some("A plugin".into()),
+            images_index: first_index + 2,
+            image_count: 0,
+            changelog_index: first_index + 2,
+            images: vec![],
+            homepage: Some("https://example.com".into()),
+            display_name: Some("Test Plugin".into()),
+        },
+        skyline_version: Some(update_protocol::parse_requirement(">=0.3.0").unwrap()),
+        channel: update_protocol::requested_channel(beta, None).into(),
+        notify_only,
+        changelog: Some("Fixed everything".into()),
+        version_ordering: Default::default(),
+        prerelease_on_stable: false,
+        rollback: false,
+        deleted_files: vec![],
+    };
+
+    let plugins = vec![
+        plugin("test_plugin", "1.0.0", false, false, 0),
+        plugin("test_plugin", "1.1.0-beta.1", true, false, 3),
+        plugin("notify_plugin", "2.0.0", false, true, 6),
+    ];
+
+    ServerState {
+        plugins,
+        load_failures: vec![LoadFailure { directory: "plugins/broken".into(), error: "bad toml".into(), timestamp: 0 }],
+        index_blocks: vec![],
+        reload_history: vec![ReloadSummary::default()].into(),
+        downloads: Default::default(),
+        reload_in_progress: AtomicBool::new(false),
+        reload_error: None,
+    }
+}
+
+fn settings() -> Settings {
+    Settings {
+        inline: InlineConfig { threshold: INLINE_THRESHOLD, max_total: INLINE_MAX_TOTAL },
+        admin_token: Some("secret".into()),
+        history_len: RELOAD_HISTORY_LEN,
+        download_workers: 1,
+        public_download_port: Some(62001),
+        bind_address: IpAddr::V4(Ipv4Addr::LOCALHOST),
+        port: PORT_NUM,
+        download_port: None,
+        watch_debounce: Duration::from_secs(WATCH_DEBOUNCE_SECS),
+        log_level: log::LevelFilter::Info,
+        tls: tls::Acceptor::default(),
+        signing_key: None,
+        plugins_dir: PathBuf::from(DEFAULT_PLUGINS_DIR),
+        withhold_outdated_skyline: false,
+    }
+}
+
+#[test]
+fn fuzz_requests() {
+    let state = state();
+    let settings = settings();
+    let pool = pool::BufferPool::new(1, pool::MAX_BUFFER_SIZE);
+
+    fuzz::run(&corpus("requests"), |input| {
+        let mut buf = pool.get();
+        pool::read_request_line(&mut &input[..], &mut buf).unwrap();
+        assert!(buf.len() <= pool::MAX_REQUEST_LEN);
+
+        let packet = String::from_utf8_lossy(&buf);
+        let response = handle_request(&packet, &state, &settings);
+        // nothing served is larger than the inline budget plus the file list around it
+        assert!(response.len() <= INLINE_MAX_TOTAL + 64 * 1024, "{} byte response", response.len());
+    });
+
+    assert_eq!(pool.high_water(), 1);
+}
+
+#[test]
+fn fuzz_download_frames() {
+    let state = state();
+
+    fuzz::run(&corpus("downloads"), |input| {
+        match read_download_index(&mut &input[..]) {
+            Some(index) => {
+                let data = handle_download(index, &state);
+                let (plugin, _) = download_label(u64::from_be_bytes(index), &state);
+                assert_eq!(data.is_some(), plugin != "(unknown)");
+            }
+            None => assert!(input.len() < 8),
+        }
+    });
+}
+
+#[test]
+fn test_oversized_request_line() {
+    let state = state();
+    let pool = pool::BufferPool::new(1, pool::MAX_BUFFER_SIZE);
+
+    // a request nested far deeper than serde_json allows, and longer than the line limit
+    let mut input = vec![b'['; pool::MAX_REQUEST_LEN * 2];
+    input.push(b'\n');
+
+    let mut buf = pool.get();
+    pool::read_request_line(&mut &input[..], &mut buf).unwrap();
+    assert_eq!(buf.len(), pool::MAX_REQUEST_LEN);
+
+    let response = handle_request(&String::from_utf8_lossy(&buf), &state, &settings());
+    assert_eq!(serde_json::from_str::<UpdateResponse>(&response).unwrap().code, ResponseCode::InvalidRequest);
+}
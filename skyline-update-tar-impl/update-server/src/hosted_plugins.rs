@@ -1,20 +1,35 @@
 use std::{io, fs};
-use semver::Version;
+use std::collections::HashSet;
+use semver::{Version, VersionReq};
 use std::path::{Path, PathBuf};
-use update_protocol::InstallLocation;
+use update_protocol::{InstallLocation, LoadFailure};
 use serde::{Serialize, Deserialize};
 use color_eyre::eyre;
 
+use crate::served::ServedFile;
+
 #[derive(Serialize, Deserialize, Clone)]
 pub struct PluginFile {
     pub install_location: InstallLocation,
     pub filename: PathBuf,
+    /// Entries with a lower priority are installed first, see `entry_order`
+    #[serde(default)]
+    pub priority: i32,
+    /// Only sent to clients on these platforms, see `update_protocol::PLATFORMS`. Sent to
+    /// every client if left out.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub platform: Option<Vec<String>>,
 }
 
 #[derive(Serialize, Deserialize, Clone)]
 pub struct PluginFolder {
     pub install_root_location: InstallLocation,
     pub root_name: PathBuf,
+    #[serde(default)]
+    pub priority: i32,
+    /// See `PluginFile::platform`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub platform: Option<Vec<String>>,
 }
 
 #[derive(Serialize, Deserialize, Clone)]
@@ -23,6 +38,7 @@ pub struct TomlMetadata {
     pub images: Option<Vec<PathBuf>>,
     pub description: Option<String>,
     pub changelog: Option<PathBuf>,
+    pub homepage: Option<String>,
 }
 
 #[derive(Serialize, Deserialize, Clone)]
@@ -32,16 +48,51 @@ pub struct PluginToml {
 
     pub name: String,
 
+    /// Name shown to users instead of `name`, which clients keep using to ask for the plugin
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub display_name: Option<String>,
+
     pub beta: Option<bool>,
 
+    /// Release channel to publish on, such as `"nightly"`, in place of `beta`. Only clients
+    /// asking for the channel are offered it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub channel: Option<String>,
+
     pub files: Vec<PluginFile>,
 
     pub folders: Option<Vec<PluginFolder>>,
 
-    #[serde(default, with = "version_parse_opt", skip_serializing_if = "Option::is_none")]
-    pub skyline_version: Option<Version>,
+    /// Requirement on the skyline version, see `update_protocol::parse_requirement`
+    #[serde(default, with = "requirement_parse_opt", skip_serializing_if = "Option::is_none")]
+    pub skyline_version: Option<VersionReq>,
 
     pub metadata: Option<TomlMetadata>,
+
+    /// Only tell users about new versions instead of installing them
+    #[serde(default)]
+    pub notify_only: bool,
+
+    #[serde(default)]
+    pub version_ordering: crate::versions::VersionOrdering,
+
+    /// Offer pre-release versions to clients which didn't ask for betas
+    #[serde(default)]
+    pub prerelease_on_stable: bool,
+
+    /// Allow install paths which only differ by case, see `check_case_collisions`
+    #[serde(default)]
+    pub allow_case_collisions: bool,
+
+    /// Offer this version to clients rolling back from a newer one which broke. Without a
+    /// marked version the previous stable version is offered.
+    #[serde(default)]
+    pub rollback: bool,
+
+    /// Files earlier versions installed which clients delete once they installed this one,
+    /// absolute paths or paths relative to the install root, see `check_deleted_files`
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub deleted_files: Vec<String>,
 }
 
 mod version_parse {
@@ -78,52 +129,290 @@ mod version_parse {
     }
 }
 
-mod version_parse_opt {
-    use semver::Version;
-    use serde::{Serializer, Deserializer};
+mod requirement_parse_opt {
+    use semver::VersionReq;
+    use serde::{Serializer, Deserializer, Deserialize, de};
 
-    pub fn serialize<S>(ver: &Option<Version>, ser: S) -> Result<S::Ok, S::Error>
+    pub fn serialize<S>(req: &Option<VersionReq>, ser: S) -> Result<S::Ok, S::Error>
         where S: Serializer
     {
-        ser.collect_str(ver.as_ref().unwrap())
+        ser.collect_str(req.as_ref().unwrap())
     }
 
-    pub fn deserialize<'de, D>(de: D) -> Result<Option<Version>, D::Error>
+    pub fn deserialize<'de, D>(de: D) -> Result<Option<VersionReq>, D::Error>
         where D: Deserializer<'de>
     {
-        Ok(super::version_parse::deserialize(de).ok())
+        let requirement = String::deserialize(de)?;
+        update_protocol::parse_requirement(&requirement)
+            .map(Some)
+            .map_err(|e| de::Error::custom(format!("invalid version requirement '{}': {}", requirement, e)))
     }
 }
 
 #[derive(Default)]
 pub struct Metadata {
     pub name: Option<String>,
-    pub images: Option<Vec<Vec<u8>>>,
+    pub images: Option<Vec<crate::images::ServedImage>>,
     pub description: Option<String>,
     pub changelog: Option<String>,
+    pub homepage: Option<String>,
+}
+
+/// A file or folder archive ready to be served
+pub struct HostedFile {
+    pub install: InstallLocation,
+    pub data: ServedFile,
+    pub sha256: String,
+    /// Only sent to clients on these platforms
+    pub platform: Option<Vec<String>>,
 }
 
 pub struct Plugin {
+    /// Plugin folder it was loaded from
+    pub directory: PathBuf,
     pub name: String,
+    pub display_name: Option<String>,
     pub plugin_version: Version,
-    pub files: Vec<(InstallLocation, Vec<u8>)>,
-    pub skyline_version: Version,
-    pub beta: bool,
+    pub files: Vec<HostedFile>,
+    pub skyline_version: Option<VersionReq>,
+    /// `channel` from the toml, otherwise the channel `beta` maps to
+    pub channel: String,
     pub metadata: Metadata,
+    pub notify_only: bool,
+    pub version_ordering: crate::versions::VersionOrdering,
+    pub prerelease_on_stable: bool,
+    pub rollback: bool,
+    /// Normalized `deleted_files` from the toml
+    pub deleted_files: Vec<String>,
+}
+
+/// Default maximum number of file and folder entries a single plugin may declare
+const MAX_FILES: usize = 4096;
+
+/// Default maximum estimated size of a plugin's serialized update response
+const MAX_RESPONSE_SIZE: usize = 1024 * 1024;
+
+/// Approximate JSON size of an `UpdateFile` not counting its install location
+const UPDATE_FILE_OVERHEAD: usize = 96;
+
+pub struct Limits {
+    pub max_files: usize,
+    pub max_response_size: usize,
+    pub max_image_size: usize,
+    /// Files up to this size are kept in memory, see `ServedFile`
+    pub memory_cache_limit: u64,
+}
+
+impl Limits {
+    /// Read the limits from `UPDATE_SERVER_MAX_FILES`/`UPDATE_SERVER_MAX_RESPONSE_SIZE`/
+    /// `UPDATE_SERVER_MAX_IMAGE_SIZE`/`UPDATE_SERVER_MEMORY_CACHE_LIMIT`
+    pub fn from_env() -> Self {
+        Limits {
+            max_files: crate::env_or("UPDATE_SERVER_MAX_FILES", MAX_FILES),
+            max_response_size: crate::env_or("UPDATE_SERVER_MAX_RESPONSE_SIZE", MAX_RESPONSE_SIZE),
+            max_image_size: crate::env_or("UPDATE_SERVER_MAX_IMAGE_SIZE", crate::images::MAX_IMAGE_SIZE),
+            memory_cache_limit: crate::env_or("UPDATE_SERVER_MEMORY_CACHE_LIMIT", crate::served::MEMORY_CACHE_LIMIT),
+        }
+    }
+}
+
+/// Folder archives are built in a directory per plugin directory in here, next to the plugins
+/// folder rather than in it so building them doesn't set off the watcher, see `archive_dir`
+pub const ARCHIVE_CACHE_DIR: &str = "cache/archives";
+
+fn install_location_len(location: &InstallLocation) -> usize {
+    location_path(location).map(str::len).unwrap_or(0)
+}
+
+/// The path of an absolute or relative install location. Relative paths are resolved by each
+/// client, so they are only compared against other relative paths.
+pub fn location_path(location: &InstallLocation) -> Option<&str> {
+    match location {
+        InstallLocation::AbsolutePath(path) | InstallLocation::Relative(path) => Some(path),
+        _ => None,
+    }
+}
+
+/// Reject plugins whose file list would make for an unreasonably large update response
+pub fn check_limits(plugin: &PluginToml, limits: &Limits) -> eyre::Result<()> {
+    let folders = plugin.folders.as_deref().unwrap_or_default();
+
+    let entries = plugin.files.len() + folders.len();
+    if entries > limits.max_files {
+        eyre::bail!(
+            "Plugin '{}' declares {} files, more than the limit of {}. Consider serving them as \
+             a folder, which is sent as a single archive. (override with UPDATE_SERVER_MAX_FILES)",
+            plugin.name, entries, limits.max_files
+        );
+    }
+
+    let estimate: usize = plugin.files.iter()
+        .map(|file| install_location_len(&file.install_location))
+        .chain(folders.iter().map(|folder| install_location_len(&folder.install_root_location) + ".tar".len()))
+        .map(|len| len + UPDATE_FILE_OVERHEAD)
+        .sum();
+    if estimate > limits.max_response_size {
+        eyre::bail!(
+            "Plugin '{}' would produce an update response of roughly {} bytes, more than the limit of {}. \
+             Consider serving its files as a folder, which is sent as a single archive. \
+             (override with UPDATE_SERVER_MAX_RESPONSE_SIZE)",
+            plugin.name, estimate, limits.max_response_size
+        );
+    }
+
+    Ok(())
+}
+
+/// Every platform clients can receive a different set of a plugin's entries on: the ones its
+/// entries are marked for, or `DEFAULT_PLATFORM` if none are. Clients on any other platform
+/// receive the unmarked entries, which every one of these receives as well.
+pub fn platform_variants(plugin: &PluginToml) -> Vec<String> {
+    let folders = plugin.folders.as_deref().unwrap_or_default();
+
+    let mut platforms: Vec<String> = plugin.files.iter()
+        .filter_map(|file| file.platform.as_ref())
+        .chain(folders.iter().filter_map(|folder| folder.platform.as_ref()))
+        .flatten()
+        .cloned()
+        .collect();
+    platforms.sort();
+    platforms.dedup();
+
+    if platforms.is_empty() {
+        platforms.push(update_protocol::DEFAULT_PLATFORM.to_owned());
+    }
+
+    platforms
 }
 
-fn to_file(PluginFile { install_location, filename }: PluginFile, dir: &Path) -> eyre::Result<(InstallLocation, Vec<u8>)> {
+/// Every path a plugin installs to on a console on `platform`: its files, and the contents of
+/// its folders as they end up once their archive is extracted
+pub fn installed_paths(plugin: &PluginToml, dir: &Path, platform: &str) -> eyre::Result<Vec<String>> {
+    let mut paths = vec![];
+
+    for file in &plugin.files {
+        if !update_protocol::matches_platform(file.platform.as_deref(), platform) {
+            continue
+        }
+        if let Some(path) = location_path(&file.install_location.normalized()?) {
+            paths.push(path.to_owned());
+        }
+    }
+
+    for folder in plugin.folders.as_deref().unwrap_or_default() {
+        if !update_protocol::matches_platform(folder.platform.as_deref(), platform) {
+            continue
+        }
+        let install_root = match location_path(&folder.install_root_location.normalized()?) {
+            Some(path) => path.to_owned(),
+            None => continue,
+        };
+
+        let folder_path = dir.join(&folder.root_name);
+        let archive_root = folder_path.parent().unwrap_or(dir);
+        for entry in walkdir::WalkDir::new(&folder_path) {
+            let entry = entry?;
+            if entry.file_type().is_dir() {
+                continue
+            }
+
+            let name = entry.path().strip_prefix(archive_root)?.to_string_lossy().replace('\\', "/");
+            paths.push(format!("{}/{}", install_root, name));
+        }
+    }
+
+    Ok(paths)
+}
+
+/// Reject plugins installing to paths which only differ by case, as they overwrite each other
+/// on the console's case-insensitive SD card, unless `allow_case_collisions` is set
+pub fn check_case_collisions(plugin: &PluginToml, paths: &[String]) -> eyre::Result<()> {
+    if plugin.allow_case_collisions {
+        return Ok(())
+    }
+
+    let collisions = update_protocol::case_collisions(paths.iter().map(String::as_str));
+    if let Some((first, second)) = collisions.first() {
+        eyre::bail!(
+            "Plugin '{}' installs to '{}' and '{}', which are the same file on the console's \
+             case-insensitive SD card{}. Set allow_case_collisions = true if this is intended.",
+            plugin.name, first, second,
+            if collisions.len() > 1 { format!(" ({} collisions in total)", collisions.len()) } else { String::new() }
+        );
+    }
+
+    Ok(())
+}
+
+/// Reject `deleted_files` paths clients would refuse to delete, returning them normalized.
+/// Files the same version installs can't be deleted either.
+pub fn check_deleted_files(plugin: &PluginToml) -> eyre::Result<Vec<String>> {
+    let installed: HashSet<String> = plugin.files.iter()
+        .filter_map(|file| location_path(&file.install_location))
+        .filter_map(|path| update_protocol::normalize_path(path).ok())
+        .collect();
+
+    plugin.deleted_files.iter()
+        .map(|path| {
+            let normalized = update_protocol::normalize_path(path)
+                .map_err(|e| eyre::eyre!("Plugin '{}' deletes invalid path '{}': {}", plugin.name, path, e))?;
+            if normalized.split('/').any(|component| component == "..") {
+                eyre::bail!("Plugin '{}' deletes '{}', which steps out of a folder with '..'", plugin.name, path);
+            }
+            if installed.contains(&normalized) {
+                eyre::bail!("Plugin '{}' both installs and deletes '{}'", plugin.name, path);
+            }
+            Ok(normalized)
+        })
+        .collect()
+}
+
+/// `check_case_collisions` for the entries sent to each platform, as files only sent to
+/// different platforms never end up on the same SD card
+pub fn check_platform_case_collisions(plugin: &PluginToml, dir: &Path) -> eyre::Result<()> {
+    for platform in platform_variants(plugin) {
+        check_case_collisions(plugin, &installed_paths(plugin, dir, &platform)?)?;
+    }
+
+    Ok(())
+}
+
+/// The order a plugin's entries are sent in `required_files`, as indexes into its `files`
+/// followed by its `folders`. Entries are sorted by `priority`, lowest first, and entries of
+/// equal priority keep their declaration order with files before folder archives.
+///
+/// Clients install files in this order, so any change to it is a breaking change.
+pub fn entry_order(plugin: &PluginToml) -> Vec<usize> {
+    let folders = plugin.folders.as_deref().unwrap_or_default();
+
+    let mut order: Vec<(i32, usize)> = plugin.files.iter()
+        .map(|file| file.priority)
+        .chain(folders.iter().map(|folder| folder.priority))
+        .enumerate()
+        .map(|(i, priority)| (priority, i))
+        .collect();
+
+    // stable, so ties stay in declaration order
+    order.sort_by_key(|&(priority, _)| priority);
+
+    order.into_iter().map(|(_, i)| i).collect()
+}
+
+fn to_file(PluginFile { install_location, filename, platform, .. }: PluginFile, dir: &Path, limits: &Limits) -> eyre::Result<HostedFile> {
     let path = if filename.is_absolute() {
         filename
     } else {
         dir.join(filename)
     };
 
-    Ok((install_location, fs::read(path)?))
+    let (data, sha256) = ServedFile::load(&path, limits.memory_cache_limit)
+        .map_err(|e| eyre::eyre!("Failed to read {}: {}", path.display(), e))?;
+    Ok(HostedFile { install: install_location, data, sha256, platform })
 }
 
-pub fn folder_to_plugin(dir: io::Result<fs::DirEntry>) -> eyre::Result<Option<Plugin>> {
-    let path = dir?.path();
+/// Load the plugin in the folder at `path`, None if it isn't a folder
+pub fn load_folder(path: &Path, limits: &Limits) -> eyre::Result<Option<Plugin>> {
+    let path = path.to_owned();
     if !path.is_dir() {
         return Ok(None)
     }
@@ -131,27 +420,53 @@ pub fn folder_to_plugin(dir: io::Result<fs::DirEntry>) -> eyre::Result<Option<Pl
 
     let plugin: PluginToml = toml::from_str(&fs::read_to_string(toml_path)?)?;
 
-    let PluginToml { version, name, files, folders, skyline_version, beta, metadata } =  plugin;
-
-    let mut files: Vec<(InstallLocation, Vec<u8>)> = files.into_iter().map(|file| to_file(file, &path)).collect::<eyre::Result<_>>()?;
+    check_limits(&plugin, limits)?;
+    check_platform_case_collisions(&plugin, &path)?;
+    let deleted_files = check_deleted_files(&plugin)?;
+    let order = entry_order(&plugin);
+    let platforms = platform_variants(&plugin);
+
+    let PluginToml {
+        version, name, display_name, files, folders, skyline_version, beta, channel, metadata, notify_only, version_ordering,
+        prerelease_on_stable, rollback, ..
+    } =  plugin;
+
+    let files = files.into_iter()
+        .map(|file| -> eyre::Result<_> {
+            Ok(PluginFile { install_location: file.install_location.normalized()?, ..file })
+        })
+        .collect::<eyre::Result<Vec<_>>>()?;
+    let folders = folders.unwrap_or_default()
+        .into_iter()
+        .map(|folder| -> eyre::Result<_> {
+            Ok(PluginFolder { install_root_location: folder.install_root_location.normalized()?, ..folder })
+        })
+        .collect::<eyre::Result<Vec<_>>>()?;
+
+    let mut files: Vec<HostedFile> = files.into_iter().map(|file| to_file(file, &path, limits)).collect::<eyre::Result<_>>()?;
 
     /* cwd joined with our current "plugin" I.E. mnt/..../HDR  */
     let plugin_path = &std::env::current_dir().unwrap().join(&path);        
     /* Name of current plugin */
     //let plugin_name = plugin_path.file_name().unwrap().to_str().unwrap();
 
+    let archive_dir = archive_dir(&path);
+    if !folders.is_empty() {
+        fs::create_dir_all(&archive_dir)?;
+    }
+
     /* Handle directories */
-    for folder in folders.unwrap_or_default() {
+    for folder in folders {
 
         /* cwd joined with current plugin joined with our current romfs folder  I.E. /mnt/..../HDR/HDR-Base   */
         let folder_dep_path = &plugin_path.join(Path::new(folder.root_name.to_str().unwrap()));
-        /* Name of current folder dep */
-        let folder_dep_name = folder_dep_path.file_name().unwrap().to_str().unwrap();
-
         let tar_name = folder_dep_path.file_stem().unwrap().to_str().unwrap().to_owned() + ".tar";
-        let tar_path = plugin_path.join(tar_name.clone());
+        let tar_path = archive_dir.join(&tar_name);
+        // built next to the archive and renamed over it, so downloads of the previous archive
+        // which are still running keep reading the file they opened
+        let building_path = archive_dir.join(tar_name + ".building");
 
-        let mut tar = tar::Builder::new(fs::File::create(tar_path.clone())?);
+        let mut tar = tar::Builder::new(fs::File::create(&building_path)?);
 
         /* recurse through folder and write files to the ZipWriter. */
         for file_from_folder in walkdir::WalkDir::new(folder_dep_path).contents_first(true) {
@@ -160,63 +475,174 @@ pub fn folder_to_plugin(dir: io::Result<fs::DirEntry>) -> eyre::Result<Option<Pl
                 continue;
             }
 
-            let curr_absolute_dir = file_from_folder.path().to_str().unwrap().to_string();
-            let curr_recurse_dir = &curr_absolute_dir.clone()[curr_absolute_dir.find("plugins").unwrap() ..];
-            //println!("Zipping: {}", curr_recurse_dir.clone());
-
-            let _ = tar.append_path_with_name(curr_recurse_dir, &curr_recurse_dir[curr_recurse_dir.find(folder_dep_name).unwrap()..]).unwrap();
+            /* path inside the tar, starting with the folder's name I.E. HDR-Base/...   */
+            let name_in_tar = file_from_folder.path().strip_prefix(plugin_path)?;
+            tar.append_path_with_name(file_from_folder.path(), name_in_tar)?;
         }
-        let _ = tar.finish()?;
+        tar.into_inner()?.sync_all()?;
+        fs::rename(&building_path, &tar_path)?;
 
-        let install_loc: &Path = match folder.install_root_location {
-            InstallLocation::AbsolutePath(ref p) => Path::new(p),
+        let install_loc = match folder.install_root_location {
+            InstallLocation::AbsolutePath(ref p) => InstallLocation::AbsolutePath(format!("{}.tar", p)),
+            InstallLocation::Relative(ref p) => InstallLocation::Relative(format!("{}.tar", p)),
             _ => {
                 println!("Install location unknown... {:#?}", folder.install_root_location);
-                Path::new("ERR")
+                InstallLocation::AbsolutePath(String::from("ERR.tar"))
             }
         };
-        let mut install_loc = install_loc.to_str().unwrap().to_string();
-        install_loc.push_str(".tar");
 
-        let file_data = ( InstallLocation::AbsolutePath(install_loc), fs::read(&tar_path)? );
-
-        files.push(file_data);
+        let (data, sha256) = ServedFile::load(&tar_path, limits.memory_cache_limit)?;
+        files.push(HostedFile { install: install_loc, data, sha256, platform: folder.platform });
 
     }
+
+    let mut entries: Vec<_> = files.into_iter().map(Some).collect();
+    let files: Vec<_> = order.iter()
+        .map(|&i| entries[i].take().unwrap())
+        .collect();
     
+    // files only sent to different platforms may share a destination
+    for platform in &platforms {
+        let mut destinations = HashSet::new();
+        for file in &files {
+            if !update_protocol::matches_platform(file.platform.as_deref(), platform) {
+                continue
+            }
+            if let Some(path) = location_path(&file.install) {
+                if !destinations.insert(path) {
+                    eyre::bail!("Plugin '{}' installs more than one file to '{}' on {}", name, path, platform);
+                }
+            }
+        }
+    }
+
     let metadata = metadata.map(|metadata| {
         Metadata {
             name: metadata.name,
-            images: metadata.images.map(|x| x.iter().map(|path| fs::read(path).unwrap_or_default()).collect()),
+            images: metadata.images.map(|x| {
+                x.iter()
+                    .filter_map(|image| {
+                        let data = read_metadata_file(&path, image)?;
+                        Some(crate::images::prepare(&path.join(image), data, limits.max_image_size))
+                    })
+                    .collect()
+            }),
             description: metadata.description,
-            changelog: metadata.changelog.map(|path| fs::read_to_string(path).ok()).flatten()
+            changelog: metadata.changelog.and_then(|changelog| {
+                let data = read_metadata_file(&path, &changelog)?;
+                String::from_utf8(data)
+                    .map_err(|e| println!("Changelog {} is not valid UTF-8: {}", path.join(&changelog).display(), e))
+                    .ok()
+            }),
+            homepage: metadata.homepage,
         }
     }).unwrap_or_default();
 
     Ok(Some(Plugin {
+        directory: path,
         name,
+        display_name,
         plugin_version: version,
         files,
-        skyline_version: skyline_version.unwrap_or("0.0.0".parse().unwrap()),
-        beta: beta.unwrap_or(false),
+        skyline_version,
+        channel: update_protocol::requested_channel(beta.unwrap_or(false), channel.as_deref()).to_owned(),
         metadata,
+        notify_only,
+        version_ordering,
+        prerelease_on_stable,
+        rollback,
+        deleted_files,
     }))
 }
 
-pub fn get() -> eyre::Result<Vec<Plugin>> {
-    Ok(
-        fs::read_dir("plugins")?
-            .filter_map(|entry| {
-                match folder_to_plugin(entry) {
-                    Ok(x) => x,
-                    Err(e) => {
-                        println!("{}", e);
-                        None
-                    }
-                }
-            })
-            .collect()
-    )
+/// A file named in the metadata of the plugin folder at `dir`, relative to that folder. Files
+/// which can't be read are logged and left out, rather than failing the whole plugin.
+fn read_metadata_file(dir: &Path, file: &Path) -> Option<Vec<u8>> {
+    let path = dir.join(file);
+    match fs::read(&path) {
+        Ok(data) => Some(data),
+        Err(e) => {
+            println!("Failed to read metadata file {}: {}", path.display(), e);
+            None
+        }
+    }
+}
+
+/// Load every plugin in `plugins_dir`, with the directories which failed to load
+pub fn get(plugins_dir: &Path) -> eyre::Result<(Vec<Plugin>, Vec<LoadFailure>)> {
+    let folders = fs::read_dir(plugins_dir)?
+        .map(|entry| entry.map(|entry| entry.path()))
+        .collect::<io::Result<Vec<_>>>()?;
+
+    Ok(get_folders(&folders))
+}
+
+/// Whether `path` is named after a version, such as `plugins/my_plugin/1.2.0`
+fn is_version_folder(path: &Path) -> bool {
+    path.file_name()
+        .and_then(|name| name.to_str())
+        .map_or(false, |name| name.parse::<Version>().is_ok())
+}
+
+/// The plugin folders in a folder of the plugins directory: the folder itself, or, for a
+/// plugin hosting several versions, its version folders (`plugins/my_plugin/1.2.0/plugin.toml`,
+/// `plugins/my_plugin/1.3.0/plugin.toml`) if it has no `plugin.toml` of its own
+pub fn plugin_folders(folder: &Path) -> Vec<PathBuf> {
+    if folder.join("plugin.toml").exists() {
+        return vec![folder.to_owned()]
+    }
+
+    let mut versions: Vec<_> = fs::read_dir(folder)
+        .map(|entries| entries.filter_map(|entry| entry.ok()).map(|entry| entry.path()).collect())
+        .unwrap_or_default();
+    versions.retain(|path| path.is_dir() && is_version_folder(path));
+    versions.sort();
+    if versions.is_empty() {
+        return vec![folder.to_owned()]
+    }
+
+    versions
+}
+
+/// Where the archives of the plugin folder at `path` are built: `ARCHIVE_CACHE_DIR` in the
+/// folder holding the plugins folder, wherever the server runs from. Every version folder of a
+/// plugin gets its own, inside one named after the plugin's folder.
+fn archive_dir(path: &Path) -> PathBuf {
+    let name = path.file_name().unwrap_or_default();
+    let (plugins_dir, cached) = match path.parent() {
+        Some(plugin) if is_version_folder(path) => (plugin.parent(), Path::new(plugin.file_name().unwrap_or_default()).join(name)),
+        parent => (parent, PathBuf::from(name)),
+    };
+    plugins_dir.and_then(Path::parent)
+        .unwrap_or_else(|| Path::new(""))
+        .join(ARCHIVE_CACHE_DIR)
+        .join(cached)
+}
+
+/// Load the plugins in `folders`, with the ones which failed to load. Paths which aren't
+/// folders, such as plugin folders which were deleted, are skipped. Folders hosting several
+/// versions load every version, see `plugin_folders`.
+pub fn get_folders(folders: &[PathBuf]) -> (Vec<Plugin>, Vec<LoadFailure>) {
+    let limits = Limits::from_env();
+
+    let mut plugins = vec![];
+    let mut failures = vec![];
+    for folder in folders.iter().flat_map(|folder| plugin_folders(folder)) {
+        match load_folder(&folder, &limits) {
+            Ok(Some(plugin)) => plugins.push(plugin),
+            Ok(None) => {}
+            Err(e) => {
+                println!("{}", e);
+                failures.push(LoadFailure {
+                    directory: folder.display().to_string(),
+                    error: e.to_string(),
+                    timestamp: crate::unix_time(),
+                });
+            }
+        }
+    }
+
+    (plugins, failures)
 }
 
 /*pub fn print_default() {
@@ -229,3 +655,287 @@ pub fn get() -> eyre::Result<Vec<Plugin>> {
         metadata: None,
     }).unwrap());
 }*/
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use update_protocol::DEFAULT_PLATFORM;
+
+    fn toml_with_files(count: usize) -> PluginToml {
+        let files: String = (0..count)
+            .map(|i| format!("[[files]]\ninstall_location = \"sd:/file{:04}.txt\"\nfilename = \"file{}.txt\"\n", i, i))
+            .collect();
+
+        toml::from_str(&format!("version = \"1.0.0\"\nname = \"big_plugin\"\n{}", files)).unwrap()
+    }
+
+    #[test]
+    fn test_too_many_files() {
+        let limits = Limits { max_files: 100, max_response_size: MAX_RESPONSE_SIZE, max_image_size: 0, memory_cache_limit: 0 };
+
+        assert!(check_limits(&toml_with_files(100), &limits).is_ok());
+        assert!(check_limits(&toml_with_files(101), &limits).is_err());
+    }
+
+    #[test]
+    fn test_response_too_large() {
+        let limits = Limits { max_files: MAX_FILES, max_response_size: 50 * (UPDATE_FILE_OVERHEAD + 16), max_image_size: 0, memory_cache_limit: 0 };
+
+        assert!(check_limits(&toml_with_files(50), &limits).is_ok());
+        assert!(check_limits(&toml_with_files(51), &limits).is_err());
+    }
+
+    #[test]
+    fn test_entry_order() {
+        let plugin: PluginToml = toml::from_str(r#"
+            version = "1.0.0"
+            name = "ordered_plugin"
+
+            [[files]]
+            install_location = "sd:/atmosphere/contents/01006A800016E000/romfs/skyline/plugins/libplugin.nro"
+            filename = "libplugin.nro"
+
+            [[files]]
+            install_location = "sd:/plugin/config.toml"
+            filename = "config.toml"
+
+            [[files]]
+            install_location = "sd:/plugin/first.txt"
+            filename = "first.txt"
+            priority = -1
+
+            [[folders]]
+            install_root_location = "sd:/ultimate/mods/base"
+            root_name = "base"
+
+            [[folders]]
+            install_root_location = "sd:/ultimate/mods/early"
+            root_name = "early"
+            priority = -1
+
+            [[folders]]
+            install_root_location = "sd:/ultimate/mods/extra"
+            root_name = "extra"
+        "#).unwrap();
+
+        // first.txt, early, libplugin.nro, config.toml, base, extra
+        assert_eq!(entry_order(&plugin), vec![2, 4, 0, 1, 3, 5]);
+    }
+
+    #[test]
+    fn test_relative_locations() {
+        let plugin: PluginToml = toml::from_str(r#"
+            version = "1.0.0"
+            name = "relative_plugin"
+            files = [
+                { install_location = { relative = "romfs/skyline/plugins/libplugin.nro" }, filename = "libplugin.nro" },
+                { install_location = "sd:/plugin/config.toml", filename = "config.toml" },
+            ]
+
+            [[folders]]
+            install_root_location = { relative = "romfs/arc" }
+            root_name = "arc"
+        "#).unwrap();
+
+        assert_eq!(plugin.files[0].install_location, InstallLocation::Relative("romfs/skyline/plugins/libplugin.nro".into()));
+
+        let dir = std::env::temp_dir().join(format!("update-server-relative-{}", std::process::id()));
+        fs::create_dir_all(dir.join("arc")).unwrap();
+        fs::write(dir.join("arc/a.txt"), "a").unwrap();
+        assert_eq!(installed_paths(&plugin, &dir, DEFAULT_PLATFORM).unwrap(), vec![
+            "romfs/skyline/plugins/libplugin.nro".to_owned(),
+            "sd:/plugin/config.toml".to_owned(),
+            "romfs/arc/arc/a.txt".to_owned(),
+        ]);
+        let _ = fs::remove_dir_all(dir);
+
+        assert!(toml::from_str::<PluginToml>(r#"
+            version = "1.0.0"
+            name = "relative_plugin"
+            files = [{ install_location = { root = "romfs/a.nro" }, filename = "a.nro" }]
+        "#).is_err());
+    }
+
+    #[test]
+    fn test_entry_order_default() {
+        assert_eq!(entry_order(&toml_with_files(5)), vec![0, 1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn test_skyline_requirement() {
+        let parse = |requirement: &str| {
+            toml::from_str::<PluginToml>(&format!(
+                "version = \"1.0.0\"\nname = \"test\"\nfiles = []\nskyline_version = \"{}\"",
+                requirement
+            ))
+        };
+
+        let bare = parse("0.3.0").unwrap().skyline_version.unwrap();
+        assert!(bare.matches(&"0.4.0".parse().unwrap()));
+        assert!(!bare.matches(&"0.2.0".parse().unwrap()));
+
+        let range = parse("^0.3").unwrap().skyline_version.unwrap();
+        assert!(!range.matches(&"0.4.0".parse().unwrap()));
+
+        assert!(parse("not a version").is_err());
+    }
+
+    #[test]
+    fn test_case_collisions() {
+        let dir = std::env::temp_dir().join(format!("update-server-case-{}", std::process::id()));
+        fs::create_dir_all(dir.join("romfs/fighter")).unwrap();
+        fs::write(dir.join("romfs/fighter/Model.nutexb"), "a").unwrap();
+        fs::write(dir.join("romfs/fighter/model.nutexb"), "b").unwrap();
+
+        let plugin = |files: &str, folders: &str| -> PluginToml {
+            toml::from_str(&format!("version = \"1.0.0\"\nname = \"test\"\n{}\n{}", files, folders)).unwrap()
+        };
+        let loose = r#"files = [
+            { install_location = "sd:/Mods/foo.prc", filename = "a.prc" },
+            { install_location = "sd:/mods/FOO.prc", filename = "b.prc" },
+        ]"#;
+        let distinct = r#"files = [{ install_location = "sd:/mods/foo.prc", filename = "a.prc" }]"#;
+        let folder = r#"[[folders]]
+            install_root_location = "sd:/ultimate/mods/skins"
+            root_name = "romfs""#;
+
+        // loose files
+        let loose = plugin(loose, "");
+        let error = check_case_collisions(&loose, &installed_paths(&loose, &dir, DEFAULT_PLATFORM).unwrap()).unwrap_err().to_string();
+        assert!(error.contains("sd:/Mods/foo.prc") && error.contains("sd:/mods/FOO.prc"), "{}", error);
+
+        // archive contents
+        let archived = plugin(distinct, folder);
+        let paths = installed_paths(&archived, &dir, DEFAULT_PLATFORM).unwrap();
+        assert!(paths.contains(&"sd:/ultimate/mods/skins/romfs/fighter/model.nutexb".to_owned()));
+        let error = check_case_collisions(&archived, &paths).unwrap_err().to_string();
+        assert!(error.contains("romfs/fighter/Model.nutexb") && error.contains("romfs/fighter/model.nutexb"), "{}", error);
+
+        // intentional
+        let mut allowed = archived;
+        allowed.allow_case_collisions = true;
+        assert!(check_case_collisions(&allowed, &paths).is_ok());
+
+        fs::remove_file(dir.join("romfs/fighter/Model.nutexb")).unwrap();
+        let archived = plugin(distinct, folder);
+        assert!(check_case_collisions(&archived, &installed_paths(&archived, &dir, DEFAULT_PLATFORM).unwrap()).is_ok());
+
+        let _ = fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn test_deleted_files() {
+        let plugin = |deleted: &str| -> PluginToml {
+            toml::from_str(&format!(
+                "version = \"1.0.0\"\nname = \"test\"\ndeleted_files = [{}]\nfiles = [{{ install_location = \"sd:/mods/new.prc\", filename = \"a.prc\" }}]",
+                deleted
+            )).unwrap()
+        };
+
+        assert_eq!(check_deleted_files(&plugin("")).unwrap(), Vec::<String>::new());
+        assert_eq!(
+            check_deleted_files(&plugin(r#""sd:/mods//old.prc", "fighter\\old.nutexb""#)).unwrap(),
+            vec!["sd:/mods/old.prc".to_owned(), "fighter/old.nutexb".to_owned()],
+        );
+        assert!(check_deleted_files(&plugin(r#""sd:/mods/../atmosphere/config.ini""#)).is_err());
+        assert!(check_deleted_files(&plugin(r#""""#)).is_err());
+        assert!(check_deleted_files(&plugin(r#""sd:/mods/new.prc""#)).is_err());
+    }
+
+    fn load(dir: &Path, toml: &str) -> eyre::Result<Option<Plugin>> {
+        fs::write(dir.join("plugin.toml"), toml).unwrap();
+        load_folder(dir, &Limits::from_env())
+    }
+
+    #[test]
+    fn test_folder_outside_plugins_dir() {
+        // the plugins folder isn't called "plugins", nor is it where the server runs
+        let root = std::env::temp_dir().join(format!("update-server-folder-{}", std::process::id()));
+        let dir = root.join("hosted/served");
+        fs::create_dir_all(dir.join("base/fighter")).unwrap();
+        fs::write(dir.join("base/fighter/param.prc"), "param").unwrap();
+
+        let plugin = load(&dir, r#"
+            version = "1.0.0"
+            name = "folder_plugin"
+            files = []
+            [[folders]]
+            install_root_location = "sd:/ultimate/mods/base"
+            root_name = "base"
+        "#).unwrap().unwrap();
+
+        let file = &plugin.files[0];
+        assert_eq!(file.install, InstallLocation::AbsolutePath("sd:/ultimate/mods/base.tar".into()));
+        // built in the archive cache next to the plugins folder rather than next to the folder
+        assert!(!dir.join("base.tar").exists());
+        assert!(root.join(ARCHIVE_CACHE_DIR).join("served/base.tar").exists());
+        let names: Vec<_> = tar::Archive::new(file.data.open().unwrap()).entries().unwrap()
+            .map(|entry| entry.unwrap().path().unwrap().display().to_string())
+            .collect();
+        assert_eq!(names, vec!["base/fighter/param.prc"]);
+
+        let _ = fs::remove_dir_all(root);
+    }
+
+    #[test]
+    fn test_archive_dir() {
+        assert_eq!(archive_dir(Path::new("plugins/my_plugin")), Path::new("cache/archives/my_plugin"));
+        assert_eq!(archive_dir(Path::new("plugins/my_plugin/1.2.0")), Path::new("cache/archives/my_plugin/1.2.0"));
+        assert_eq!(archive_dir(Path::new("/srv/plugins/my_plugin")), Path::new("/srv/cache/archives/my_plugin"));
+    }
+
+    #[test]
+    fn test_metadata_relative_to_folder() {
+        let dir = std::env::temp_dir().join(format!("update-server-metadata-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("CHANGELOG.md"), "# 1.0.0").unwrap();
+        fs::write(dir.join("icon.png"), "png").unwrap();
+
+        let plugin = load(&dir, r#"
+            version = "1.0.0"
+            name = "metadata_plugin"
+            files = []
+            [metadata]
+            changelog = "CHANGELOG.md"
+            images = ["icon.png", "missing.png"]
+        "#).unwrap().unwrap();
+
+        // read from the plugin's folder, leaving out the files which can't be read
+        assert_eq!(plugin.metadata.changelog.as_deref(), Some("# 1.0.0"));
+        let images = plugin.metadata.images.unwrap();
+        assert_eq!(images.len(), 1);
+        assert_eq!(images[0].data, b"png");
+
+        let _ = fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn test_platform_destinations() {
+        let dir = std::env::temp_dir().join(format!("update-server-platform-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("switch.nro"), "switch").unwrap();
+        fs::write(dir.join("emulator.nro"), "emulator").unwrap();
+
+        let toml = |second_platform: &str| format!(r#"
+            version = "1.0.0"
+            name = "platform_plugin"
+            files = [
+                {{ install_location = "sd:/plugin.nro", filename = "switch.nro", platform = ["switch"] }},
+                {{ install_location = "sd:/plugin.nro", filename = "emulator.nro", platform = {} }},
+            ]
+        "#, second_platform);
+
+        let plugin = load(&dir, &toml(r#"["emulator"]"#)).unwrap().unwrap();
+        let platforms: Vec<_> = plugin.files.iter().map(|file| (file.data.read().unwrap().to_vec(), file.platform.clone())).collect();
+        assert_eq!(platforms, vec![
+            (b"switch".to_vec(), Some(vec!["switch".to_owned()])),
+            (b"emulator".to_vec(), Some(vec!["emulator".to_owned()])),
+        ]);
+
+        // both reach a console
+        let error = load(&dir, &toml(r#"["emulator", "switch"]"#)).err().unwrap().to_string();
+        assert!(error.contains("sd:/plugin.nro' on switch"), "{}", error);
+
+        let _ = fs::remove_dir_all(dir);
+    }
+}
@@ -0,0 +1,115 @@
+use std::path::Path;
+
+/// Default size above which a metadata image is reported (and shrunk, with `image-processing`)
+pub const MAX_IMAGE_SIZE: usize = 512 * 1024;
+
+/// Largest width or height of a shrunk image
+#[cfg(feature = "image-processing")]
+const MAX_DIMENSION: u32 = 1280;
+
+/// A metadata image as served to consoles
+pub struct ServedImage {
+    pub data: Vec<u8>,
+    /// Size of the source file, which differs from `data` if the image was shrunk
+    pub original_size: usize,
+}
+
+/// Re-encode an image as JPEG, lowering the resolution and quality until it fits in
+/// `max_size`. None if it can't be decoded or doesn't fit even at the lowest setting.
+#[cfg(feature = "image-processing")]
+fn shrink(data: &[u8], max_size: usize) -> Option<Vec<u8>> {
+    use image::{imageops::FilterType, codecs::jpeg::JpegEncoder, ColorType};
+
+    let image = image::load_from_memory(data).ok()?;
+
+    let mut dimension = MAX_DIMENSION;
+    while dimension >= 64 {
+        let rgb = image.resize(dimension, dimension, FilterType::Triangle).to_rgb8();
+        for &quality in &[85, 70, 55, 40] {
+            let mut out = vec![];
+            JpegEncoder::new_with_quality(&mut out, quality)
+                .encode(&rgb, rgb.width(), rgb.height(), ColorType::Rgb8)
+                .ok()?;
+            if out.len() <= max_size {
+                return Some(out)
+            }
+        }
+        dimension /= 2;
+    }
+
+    None
+}
+
+#[cfg(not(feature = "image-processing"))]
+fn shrink(_: &[u8], _: usize) -> Option<Vec<u8>> {
+    None
+}
+
+/// Prepare a metadata image for serving, warning if it is larger than `max_size`. Oversized
+/// images are replaced by a shrunk copy when built with `image-processing`, the source file
+/// is never modified.
+pub fn prepare(path: &Path, data: Vec<u8>, max_size: usize) -> ServedImage {
+    let original_size = data.len();
+    if original_size <= max_size {
+        return ServedImage { data, original_size }
+    }
+
+    println!(
+        "Metadata image {} is {} KB, more than the limit of {} KB (UPDATE_SERVER_MAX_IMAGE_SIZE)",
+        path.display(), original_size / 1024, max_size / 1024
+    );
+
+    match shrink(&data, max_size) {
+        Some(shrunk) => {
+            println!("    serving a shrunk copy of {} KB", shrunk.len() / 1024);
+            ServedImage { data: shrunk, original_size }
+        }
+        None => ServedImage { data, original_size },
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_small_image_untouched() {
+        let image = prepare(Path::new("icon.png"), vec![1; 100], MAX_IMAGE_SIZE);
+
+        assert_eq!(image.data, vec![1; 100]);
+        assert_eq!(image.original_size, 100);
+    }
+
+    #[cfg(not(feature = "image-processing"))]
+    #[test]
+    fn test_oversized_image_warning_only() {
+        let image = prepare(Path::new("screenshot.png"), vec![1; 1024 * 1024], MAX_IMAGE_SIZE);
+
+        assert_eq!(image.data.len(), 1024 * 1024);
+        assert_eq!(image.original_size, 1024 * 1024);
+    }
+
+    #[cfg(feature = "image-processing")]
+    #[test]
+    fn test_oversized_image_shrunk() {
+        // noise compresses badly, so this is far over the limit as a PNG
+        let mut seed = 1u32;
+        let noise = image::RgbImage::from_fn(2000, 1500, |_, _| {
+            seed = seed.wrapping_mul(1664525).wrapping_add(1013904223);
+            let [r, g, b, _] = seed.to_le_bytes();
+            image::Rgb([r, g, b])
+        });
+        let mut png = vec![];
+        image::DynamicImage::ImageRgb8(noise)
+            .write_to(&mut png, image::ImageOutputFormat::Png)
+            .unwrap();
+        assert!(png.len() > MAX_IMAGE_SIZE);
+
+        let image = prepare(Path::new("screenshot.png"), png.clone(), MAX_IMAGE_SIZE);
+
+        assert!(image.data.len() <= MAX_IMAGE_SIZE);
+        assert_eq!(image.original_size, png.len());
+        let (width, height) = image::load_from_memory(&image.data).unwrap().to_rgb8().dimensions();
+        assert!(width <= MAX_DIMENSION && height <= MAX_DIMENSION);
+    }
+}
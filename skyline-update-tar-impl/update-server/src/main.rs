@@ -1,248 +1,2824 @@
 mod hosted_plugins;
+mod manifest;
+mod downloads;
+mod pool;
+mod images;
+mod validate;
+mod versions;
+mod self_check;
+mod supervisor;
+mod discovery;
+mod tls;
+mod signing;
+mod config;
+mod served;
 
-use notify::{Watcher, RecursiveMode, watcher};
-use std::sync::mpsc::channel;
+#[cfg(test)]
+mod compat_test;
+
+#[cfg(test)]
+mod fuzz_test;
+
+use notify::{DebouncedEvent, Watcher, RecursiveMode, watcher};
+use std::sync::mpsc::{channel, Receiver};
 use std::time::Duration;
 
 use std::fs;
-use std::sync::Arc;
-use std::path::Path;
-use std::net::TcpListener;
-use std::io::{prelude::*, BufReader};
+use std::sync::{Arc, PoisonError, RwLock, RwLockReadGuard, RwLockWriteGuard};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::collections::VecDeque;
+use std::time::{SystemTime, UNIX_EPOCH};
+use std::path::{Path, PathBuf};
+use std::net::{IpAddr, Ipv4Addr, TcpListener, TcpStream};
+use std::io::prelude::*;
 
 use color_eyre::eyre;
 
-use semver::Version;
-use update_protocol::{InstallLocation, Request, UpdateResponse, ResponseCode, UpdateFile, PluginMetadata};
+use semver::{Version, VersionReq};
+use versions::VersionOrdering;
+use update_protocol::{InstallLocation, Request, STABLE_CHANNEL, UpdateResponse, ResponseCode, UpdateFile, PluginMetadata, ImageEntry, PingResponse, LoadFailure, ReloadSummary, VersionChange};
 
 struct PluginFile {
     install: InstallLocation,
-    data: Arc<Vec<u8>>,
+    data: served::ServedFile,
     index: u64,
+    sha256: String,
+    /// Only sent to clients on these platforms, see `update_protocol::matches_platform`
+    platform: Option<Vec<String>>,
 }
 
 impl From<&PluginFile> for UpdateFile {
     fn from(file: &PluginFile) -> Self {
         UpdateFile {
-            size: file.data.len(),
-            download_index: file.index.clone(),
-            install_location: file.install.clone()
+            size: file.data.len() as usize,
+            download_index: file.index,
+            install_location: file.install.clone(),
+            inline_data: None,
+            sha256: Some(file.sha256.clone()),
+            signature: None,
         }
     }
 }
 
 struct Plugin {
+    /// Plugin folder it was loaded from, see `ServerState::index_blocks`
+    pub directory: PathBuf,
     pub name: String,
     pub plugin_version: Version,
     pub files: Vec<PluginFile>,
-    pub metadata_files: Vec<Arc<Vec<u8>>>,
+    pub metadata_files: Vec<served::ServedFile>,
     pub metadata: PluginMetadata,
-    pub skyline_version: Version,
-    pub beta: bool,
+    pub skyline_version: Option<VersionReq>,
+    /// Release channel the plugin is published on, see `update_protocol::requested_channel`
+    pub channel: String,
+    pub notify_only: bool,
+    pub changelog: Option<String>,
+    pub version_ordering: VersionOrdering,
+    pub prerelease_on_stable: bool,
+    /// Offered to clients rolling back, see `rollback_target`
+    pub rollback: bool,
+    /// Sent as `UpdateResponse::files_to_delete`
+    pub deleted_files: Vec<String>,
 }
 
-const PORT_NUM: u16 = 45000;
+impl Plugin {
+    fn beta_only(&self) -> bool {
+        versions::beta_only(&self.plugin_version, self.channel != STABLE_CHANNEL, self.prerelease_on_stable)
+    }
 
-fn setup_plugin_ports() -> eyre::Result<(Vec<Plugin>, Vec<Arc<Vec<u8>>>)> {
-    let plugins = hosted_plugins::get()?;
-
-    let mut i = 0;
-    let plugins: Vec<Plugin> = plugins.into_iter()
-        .map(|plugin|{
-            let hosted_plugins::Plugin {
-                name, plugin_version, files, skyline_version, beta, metadata
-            } = plugin;
-
-            let files = files.into_iter()
-                .map(|(install, data)|{
-                    let index = i;
-                    i += 1;
-                    Ok(PluginFile {
-                        install,
-                        index,
-                        data: Arc::new(data),
-                    })
-                })
-                .collect::<eyre::Result<_>>()?;
+    /// Whether clients on `channel` are offered this copy: stable clients only get stable
+    /// releases, clients on any other channel also get the copies published on theirs
+    fn offered_on(&self, channel: &str) -> bool {
+        if channel == STABLE_CHANNEL {
+            !self.beta_only()
+        } else {
+            self.channel == STABLE_CHANNEL || self.channel == channel
+        }
+    }
 
-            let hosted_plugins::Metadata {
-                name: meta_name, images, changelog, description
-            } = metadata;
+    /// Whether any file is installed relative to the client's install root, which clients
+    /// from before protocol version 4 can't resolve
+    fn has_relative_files(&self) -> bool {
+        self.files.iter().any(|file| matches!(file.install, InstallLocation::Relative(_)))
+    }
 
-            let image_count = images.as_ref().map(|x| x.len() as _).unwrap_or(0);
+    /// Whether `version` is older than the version being served
+    fn is_newer_than(&self, version: &Version) -> bool {
+        versions::compare(version, &self.plugin_version, self.version_ordering) == std::cmp::Ordering::Less
+    }
 
-            let metadata = PluginMetadata {
-                name: meta_name,
-                description,
-                images_index: i,
-                image_count,
-                changelog_index: i + image_count,
+    /// The file served at a download index, with its install location or which metadata file
+    /// it is, None if the index isn't one of this plugin's
+    fn download(&self, index: u64) -> Option<(&served::ServedFile, String)> {
+        if let Some(file) = self.files.iter().find(|file| file.index == index) {
+            let location = match &file.install {
+                InstallLocation::AbsolutePath(path) | InstallLocation::Relative(path) => path.clone(),
+                other => format!("{:?}", other),
             };
+            return Some((&file.data, location))
+        }
 
-            let metadata_files = images.into_iter()
-                .map(|images| images.into_iter())
-                .flatten()
-                .map(|image| Arc::new(image))
-                .chain(changelog.into_iter().map(|x| Arc::new(x.into_bytes())))
-                .collect();
-
-            Ok(Plugin {
-                name,
-                plugin_version,
-                skyline_version,
-                files,
-                metadata_files,
-                metadata,
-                beta
-            })
+        let offset = index.checked_sub(self.metadata.images_index)?;
+        let data = self.metadata_files.get(offset as usize)?;
+        if index == self.metadata.changelog_index {
+            Some((data, String::from("changelog")))
+        } else {
+            Some((data, format!("image {}", offset)))
+        }
+    }
+}
+
+/// Default main port, downloads are served on the port after it
+const PORT_NUM: u16 = 45000;
+
+/// Default size at or below which a file is sent inline to clients that accept it
+const INLINE_THRESHOLD: usize = 16 * 1024;
+
+/// Default cap on the total (base64-encoded) inline bytes in a single response
+const INLINE_MAX_TOTAL: usize = 256 * 1024;
+
+#[derive(Clone, Copy)]
+struct InlineConfig {
+    threshold: usize,
+    max_total: usize,
+}
+
+impl InlineConfig {
+    /// Read the limits from `UPDATE_SERVER_INLINE_THRESHOLD`/`UPDATE_SERVER_INLINE_MAX_TOTAL`,
+    /// a threshold of 0 disables inlining entirely.
+    fn from_env() -> Self {
+        InlineConfig {
+            threshold: env_or("UPDATE_SERVER_INLINE_THRESHOLD", INLINE_THRESHOLD),
+            max_total: env_or("UPDATE_SERVER_INLINE_MAX_TOTAL", INLINE_MAX_TOTAL),
+        }
+    }
+}
+
+/// Default number of reload summaries kept for the `ReloadHistory` request
+const RELOAD_HISTORY_LEN: usize = 16;
+
+/// Threads answering requests to the main port
+const REQUEST_WORKERS: usize = 8;
+
+/// Accepted connections to the main port waiting for a free request worker, beyond this
+/// connections are closed until the queue drains
+const REQUEST_QUEUE_LEN: usize = 256;
+
+/// How long a client has to send its request (and finish the TLS handshake), and to read the
+/// response, before a request worker gives up on it
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|time| time.as_secs())
+        .unwrap_or(0)
+}
+
+/// Read a numeric setting from the environment, falling back to `default` if unset or invalid
+fn env_or<T: std::str::FromStr>(name: &str, default: T) -> T {
+    std::env::var(name).ok()
+        .and_then(|value| value.parse().ok())
+        .unwrap_or(default)
+}
+
+/// Build the file list for an update response for a client on `platform` from the files of
+/// `plugins` in order, embedding small files while the response's inline budget allows.
+/// `inline` is None for clients that didn't ask for it, and file hashes are left out unless
+/// `hashes` is set.
+fn required_files(plugins: &[&Plugin], inline: Option<InlineConfig>, hashes: bool, platform: &str) -> Vec<UpdateFile> {
+    let mut budget = inline.map(|config| config.max_total).unwrap_or(0);
+
+    plugins.iter()
+        .flat_map(|plugin| plugin.files.iter())
+        .filter(|file| update_protocol::matches_platform(file.platform.as_deref(), platform))
+        .map(|file| {
+            let mut update_file = UpdateFile::from(file);
+            if !hashes {
+                update_file.sha256 = None;
+            }
+            if let Some(config) = inline {
+                let len = file.data.len() as usize;
+                let encoded_len = (len + 2) / 3 * 4;
+                if len <= config.threshold && encoded_len <= budget {
+                    // left to be downloaded if it can't be read
+                    if let Ok(data) = file.data.read() {
+                        budget -= encoded_len;
+                        update_file.inline_data = Some(base64::encode(&data[..]));
+                    }
+                }
+            }
+            update_file
         })
-        .collect::<eyre::Result<_>>()?;
+        .collect()
+}
+
+/// Carry over the timestamps of failures which are still ongoing, so a plugin which stays
+/// broken across reloads reports when it first broke. Directories that now load are dropped.
+fn merge_load_failures(previous: &[LoadFailure], current: Vec<LoadFailure>) -> Vec<LoadFailure> {
+    current.into_iter()
+        .map(|mut failure| {
+            let existing = previous.iter().find(|old| {
+                old.directory == failure.directory && old.error == failure.error
+            });
+            if let Some(existing) = existing {
+                failure.timestamp = existing.timestamp;
+            }
+            failure
+        })
+        .collect()
+}
+
+/// Name used to tell hosted plugins apart across reloads, copies on each channel are separate
+fn plugin_key(plugin: &Plugin) -> String {
+    if plugin.channel != STABLE_CHANNEL {
+        format!("{} ({})", plugin.name, plugin.channel)
+    } else {
+        plugin.name.clone()
+    }
+}
+
+fn plugin_versions(plugins: &[Plugin]) -> Vec<(String, Version)> {
+    plugins.iter()
+        .map(|plugin| (plugin_key(plugin), plugin.plugin_version.clone()))
+        .collect()
+}
+
+/// Summarize what is being served after a reload compared to the plugin versions served before it
+fn summarize_reload(previous: &[(String, Version)], plugins: &[Plugin]) -> ReloadSummary {
+    let current = plugin_versions(plugins);
+    let find = |list: &[(String, Version)], key: &str| {
+        list.iter().find(|(other, _)| other == key).map(|(_, version)| version.clone())
+    };
+
+    let mut summary = ReloadSummary {
+        timestamp: unix_time(),
+        plugin_count: plugins.len(),
+        file_count: plugins.iter().map(|plugin| plugin.files.len()).sum(),
+        total_bytes: plugins.iter()
+            .flat_map(|plugin| plugin.files.iter())
+            .map(|file| file.data.len())
+            .sum(),
+        ..Default::default()
+    };
+
+    for (key, version) in &current {
+        match find(previous, key) {
+            None => summary.added.push(key.clone()),
+            Some(old_version) if &old_version != version => summary.version_changed.push(VersionChange {
+                plugin: key.clone(),
+                old_version: old_version.to_string(),
+                new_version: version.to_string(),
+            }),
+            Some(_) => {}
+        }
+    }
+    summary.removed = previous.iter()
+        .filter(|(key, _)| find(&current, key).is_none())
+        .map(|(key, _)| key.clone())
+        .collect();
+
+    summary
+}
+
+fn log_reload(summary: &ReloadSummary) {
+    println!(
+        "Serving {} plugin(s), {} file(s), {} bytes",
+        summary.plugin_count, summary.file_count, summary.total_bytes
+    );
+    for plugin in &summary.added {
+        println!("    added {}", plugin);
+    }
+    for plugin in &summary.removed {
+        println!("    removed {}", plugin);
+    }
+    for change in &summary.version_changed {
+        println!("    {} {} -> {}", change.plugin, change.old_version, change.new_version);
+    }
+}
+
+/// Plugins and the plugin directories which failed to load
+type LoadedPlugins = (Vec<Plugin>, Vec<LoadFailure>);
+
+/// Download indexes reserved for the metadata files of each plugin folder, so reloading one
+/// folder never renumbers the images of another
+const INDEXES_PER_FOLDER: u64 = 1 << 20;
 
-    let files = plugins.iter()
-        .map(|plugin| plugin.files.iter().map(|file| Arc::clone(&file.data)))
+/// Download index of a plugin file, derived from the plugin, where the file is installed and
+/// its content. A reload keeps serving an unchanged file at the same index, so clients which
+/// were offered an update before it still download the right files, and never serves different
+/// content at an index clients were already told about.
+///
+/// Indexes have the top bit set and the one below it clear, so they can't collide with the
+/// index blocks of metadata files or `update_protocol::KEEP_ALIVE_INDEX`.
+fn stable_index(plugin_name: &str, install: &InstallLocation, sha256: &str) -> u64 {
+    let install = serde_json::to_string(install).unwrap_or_default();
+    let hash = manifest::sha256(format!("{}\0{}\0{}", plugin_name, install, sha256).as_bytes());
+    let hash = u64::from_str_radix(&hash[..16], 16).unwrap_or_default();
+    (1 << 63) | (hash & ((1 << 62) - 1))
+}
+
+/// Give every file of a loaded plugin its `stable_index`, and number its metadata files from
+/// the index block of its folder, the position of the folder in `index_blocks`. Folders seen
+/// for the first time are given the next block.
+fn serve_plugin(plugin: hosted_plugins::Plugin, index_blocks: &mut Vec<PathBuf>) -> eyre::Result<Plugin> {
+    let block = match index_blocks.iter().position(|folder| folder == &plugin.directory) {
+        Some(block) => block,
+        None => {
+            index_blocks.push(plugin.directory.clone());
+            index_blocks.len() - 1
+        }
+    };
+    let first_index = block as u64 * INDEXES_PER_FOLDER;
+
+    let hosted_plugins::Plugin {
+        directory, name, display_name, plugin_version, files, skyline_version, channel, metadata, notify_only,
+        version_ordering, prerelease_on_stable, rollback, deleted_files
+    } = plugin;
+
+    let files = files.into_iter()
+        .map(|hosted_plugins::HostedFile { install, data, sha256, platform }| PluginFile {
+            index: stable_index(&name, &install, &sha256),
+            install,
+            sha256,
+            data,
+            platform,
+        })
+        .collect();
+
+    let hosted_plugins::Metadata {
+        name: meta_name, images, changelog, description, homepage
+    } = metadata;
+
+    let image_count = images.as_ref().map(|x| x.len() as _).unwrap_or(0);
+
+    let image_entries = images.iter()
         .flatten()
+        .zip(first_index..)
+        .map(|(image, download_index)| ImageEntry {
+            download_index,
+            size: image.data.len(),
+            original_size: image.original_size,
+        })
+        .collect();
+
+    let metadata = PluginMetadata {
+        name: meta_name,
+        description,
+        images_index: first_index,
+        image_count,
+        changelog_index: first_index + image_count,
+        images: image_entries,
+        homepage,
+        display_name,
+    };
+
+    // the changelog index is always taken, by an empty file if there is no changelog,
+    // so it never points at the files of the next plugin
+    let metadata_files: Vec<_> = images.into_iter()
+        .flat_map(|images| images.into_iter())
+        .map(|image| served::ServedFile::from(image.data))
+        .chain(std::iter::once(served::ServedFile::from(changelog.clone().unwrap_or_default().into_bytes())))
         .collect();
+    if metadata_files.len() as u64 > INDEXES_PER_FOLDER {
+        eyre::bail!("Plugin '{}' serves more than {} metadata files", name, INDEXES_PER_FOLDER);
+    }
 
-    Ok((plugins, files))
+    Ok(Plugin {
+        directory,
+        name,
+        plugin_version,
+        skyline_version,
+        files,
+        metadata_files,
+        metadata,
+        channel,
+        notify_only,
+        changelog,
+        version_ordering,
+        prerelease_on_stable,
+        rollback,
+        deleted_files,
+    })
 }
-#[allow(unused_assignments)]
-fn main() -> eyre::Result<()> {
-    color_eyre::install()?;
 
-    //hosted_plugins::print_default();
+fn setup_plugin_ports(plugins_dir: &Path, index_blocks: &mut Vec<PathBuf>) -> eyre::Result<LoadedPlugins> {
+    let (plugins, load_failures) = hosted_plugins::get(plugins_dir)?;
+    let plugins = plugins.into_iter()
+        .map(|plugin| serve_plugin(plugin, index_blocks))
+        .collect::<eyre::Result<_>>()?;
 
-    let plugins_dir = Path::new("plugins");
-    if !plugins_dir.exists() {
-        fs::create_dir(plugins_dir)?;
+    if let Err(e) = manifest::scan_all(plugins_dir).and_then(|manifests| manifest::save(&manifests)) {
+        println!("Failed to save plugin manifest snapshot: {}", e);
     }
 
-    let (tx, rx) = channel();
+    Ok((plugins, load_failures))
+}
+
+/// `setup_plugin_ports` for only the plugins in `folders`, leaving every other plugin folder
+/// alone. Folders which no longer hold a plugin load nothing.
+fn setup_plugin_folders(folders: &[PathBuf], index_blocks: &mut Vec<PathBuf>) -> eyre::Result<LoadedPlugins> {
+    let (plugins, load_failures) = hosted_plugins::get_folders(folders);
+    let plugins = plugins.into_iter()
+        .map(|plugin| serve_plugin(plugin, index_blocks))
+        .collect::<eyre::Result<_>>()?;
 
-    let mut watcher = watcher(tx, Duration::from_secs(10)).unwrap();
+    if let Err(e) = manifest::update(folders) {
+        println!("Failed to save plugin manifest snapshot: {}", e);
+    }
 
-    watcher.watch("plugins", RecursiveMode::Recursive).unwrap();
+    Ok((plugins, load_failures))
+}
+/// Everything currently being served, replaced on every reload
+struct ServerState {
+    plugins: Vec<Plugin>,
+    load_failures: Vec<LoadFailure>,
+    /// Plugin folders in the order their download index blocks were given out, see
+    /// `serve_plugin`. Folders keep their block after they are removed, in case they return.
+    index_blocks: Vec<PathBuf>,
+    reload_history: VecDeque<ReloadSummary>,
+    /// Download statistics since the server started, shared with the download threads
+    downloads: Arc<downloads::DownloadTracker>,
+    /// Set while new plugins are loading, atomic so it can be set without blocking readers
+    reload_in_progress: AtomicBool,
+    /// Why the last reload failed, the plugins from before it are still served. Cleared by the
+    /// next reload which succeeds.
+    reload_error: Option<String>,
+}
 
-    let (mut plugins, mut files) = setup_plugin_ports()?;
-    let main_port = TcpListener::bind(("0.0.0.0", PORT_NUM))?;
-    let download_port = TcpListener::bind(("0.0.0.0", PORT_NUM + 1))?;
-    main_port.set_nonblocking(true)?;
-    download_port.set_nonblocking(true)?;
+/// Directory plugins are served from unless `--plugins-dir` says otherwise, relative to the
+/// working directory
+const DEFAULT_PLUGINS_DIR: &str = "plugins";
 
-    crossbeam::scope(move |scope|{
-        loop {
-            match rx.try_recv() {
-                Ok(notify::DebouncedEvent::Error(err, Some(path))) => {
-                    println!("File watch error at path {}: {}", path.display(), err);
-                }
-                Ok(notify::DebouncedEvent::Error(err, None)) => {
-                    println!("File watch error: {}", err);
-                }
-                Ok(event) => {
-                    /* dont refresh plugins on zip creation/write. This prevents infinite plugin refreshing with zip creation */
-                    match event {
-                        notify::DebouncedEvent::Create(path) => {
-                            if path.extension().unwrap_or_default() == "tar" {
-                                continue;
-                            }
-                        },
-                        notify::DebouncedEvent::Write(path) => {
-                            if path.extension().unwrap_or_default() == "tar" {
-                                continue;
-                            }
-                        },
-                        notify::DebouncedEvent::NoticeWrite(path) => {
-                            if path.extension().unwrap_or_default() == "tar" {
-                                continue;
-                            }
-                        }
-                        _ => ()
-                    };
-                    println!("Change detected: refreshing plugins...");
-                    // clear plugins (close sockets)
-                    plugins = Vec::with_capacity(0);
-                    // setup new plugins
-                    let (x, y) = setup_plugin_ports()?;
-                    plugins = x;
-                    files = y;
-                },
-                Err(_) => {}
+/// Seconds changes to the plugins folder have to settle before plugins are reloaded, unless
+/// `server.toml` says otherwise
+const WATCH_DEBOUNCE_SECS: u64 = 10;
+
+/// Settings which stay fixed for the lifetime of the server
+struct Settings {
+    inline: InlineConfig,
+    admin_token: Option<String>,
+    history_len: usize,
+    download_workers: usize,
+    /// Download port as seen by clients, if it isn't the main port + 1
+    public_download_port: Option<u16>,
+    /// Address both ports are bound to
+    bind_address: IpAddr,
+    /// Port requests are accepted on, downloads are accepted on the port after it unless
+    /// `download_port` says otherwise
+    port: u16,
+    download_port: Option<u16>,
+    /// How long changes to the plugins folder have to settle before plugins are reloaded
+    watch_debounce: Duration,
+    log_level: log::LevelFilter,
+    /// Wraps connections to both ports, see `--tls-cert`
+    tls: tls::Acceptor,
+    /// Signs the files of every update, see `UPDATE_SERVER_SIGNING_KEY`
+    signing_key: Option<signing::SigningKey>,
+    /// Directory the hosted plugins are loaded from and watched in, see `--plugins-dir`
+    plugins_dir: PathBuf,
+    /// Whether clients whose skyline is too old for an update are offered no update at all,
+    /// rather than the update flagged with `update_skyline`, see `UPDATE_SERVER_SKYLINE_POLICY`
+    withhold_outdated_skyline: bool,
+}
+
+impl Settings {
+    /// The settings from the environment variables, falling back to `config` and then the
+    /// defaults. `config` has already been checked by `ConfigFile::read`.
+    fn from_env(config: &config::ConfigFile) -> Self {
+        Settings {
+            inline: InlineConfig::from_env(),
+            admin_token: std::env::var("UPDATE_SERVER_ADMIN_TOKEN").ok().filter(|token| !token.is_empty()),
+            history_len: env_or("UPDATE_SERVER_RELOAD_HISTORY", RELOAD_HISTORY_LEN),
+            download_workers: env_or("UPDATE_SERVER_DOWNLOAD_WORKERS", downloads::DOWNLOAD_WORKERS).max(1),
+            public_download_port: std::env::var("UPDATE_SERVER_PUBLIC_DOWNLOAD_PORT").ok()
+                .and_then(|port| port.parse().ok()),
+            bind_address: config.bind_address.unwrap_or(IpAddr::V4(Ipv4Addr::UNSPECIFIED)),
+            port: std::env::var("UPDATE_SERVER_PORT").ok()
+                .and_then(|port| port.parse().ok())
+                .or(config.port)
+                .unwrap_or(PORT_NUM),
+            download_port: config.download_port,
+            watch_debounce: Duration::from_secs(config.watch_debounce_secs.unwrap_or(WATCH_DEBOUNCE_SECS)),
+            log_level: config.log_level().ok().flatten().unwrap_or(log::LevelFilter::Info),
+            tls: tls::Acceptor::default(),
+            signing_key: None,
+            plugins_dir: config.plugins_dir.clone().unwrap_or_else(|| PathBuf::from(DEFAULT_PLUGINS_DIR)),
+            withhold_outdated_skyline: std::env::var("UPDATE_SERVER_SKYLINE_POLICY").map_or(false, |policy| policy == "withhold"),
+        }
+    }
+
+    /// Port downloads are accepted on
+    fn download_port(&self) -> u16 {
+        self.download_port.unwrap_or(self.port + 1)
+    }
+
+    /// Download port sent to clients, if it isn't the main port + 1
+    fn advertised_download_port(&self) -> Option<u16> {
+        self.public_download_port
+            .or_else(|| Some(self.download_port()).filter(|&port| Some(port) != self.port.checked_add(1)))
+    }
+
+    /// The settings `server.toml` can hold, as in effect, for `--print-config`
+    fn effective_config(&self) -> config::ConfigFile {
+        config::ConfigFile {
+            bind_address: Some(self.bind_address),
+            port: Some(self.port),
+            download_port: Some(self.download_port()),
+            plugins_dir: Some(self.plugins_dir.clone()),
+            watch_debounce_secs: Some(self.watch_debounce.as_secs()),
+            log_level: Some(self.log_level.to_string().to_lowercase()),
+        }
+    }
+
+    fn is_admin(&self, token: &str) -> bool {
+        self.admin_token.as_deref() == Some(token)
+    }
+}
+
+/// The newest version of a plugin offered on the requested channel. Build metadata breaks ties
+/// if either of two copies of the plugin asks for it.
+fn select_plugin<'a>(plugins: &'a [Plugin], name: &str, channel: &str) -> Option<&'a Plugin> {
+    plugins.iter()
+        .filter(|plugin| plugin.name == name && plugin.offered_on(channel))
+        .max_by(|a, b| {
+            let ordering = if a.version_ordering == VersionOrdering::SemverWithBuild {
+                a.version_ordering
+            } else {
+                b.version_ordering
+            };
+            versions::compare(&a.plugin_version, &b.plugin_version, ordering)
+        })
+}
+
+/// The copy of a plugin at `version`, ignoring build metadata, offered on the requested channel,
+/// for clients pinning a version
+fn select_pinned<'a>(plugins: &'a [Plugin], name: &str, version: &Version, channel: &str) -> Option<&'a Plugin> {
+    // versions are equal whatever their build metadata, a copy with the exact build asked for
+    // is preferred over the newest of the others
+    let matching = || plugins.iter()
+        .filter(move |plugin| plugin.name == name && plugin.offered_on(channel) && plugin.plugin_version == *version);
+    matching().find(|plugin| plugin.plugin_version.build == version.build)
+        .or_else(|| matching().max_by(|a, b| versions::compare(&a.plugin_version, &b.plugin_version, a.version_ordering)))
+}
+
+/// The version clients on `latest`'s channel roll back to if `latest` breaks: the newest older
+/// copy marked `rollback`, otherwise the newest older stable copy. Notify-only copies can't be
+/// installed, so they are never offered.
+fn rollback_target<'a>(plugins: &'a [Plugin], latest: &Plugin, channel: &str) -> Option<&'a Plugin> {
+    let older = || plugins.iter().filter(move |plugin| {
+        plugin.name == latest.name
+            && plugin.offered_on(channel)
+            && !plugin.notify_only
+            && latest.is_newer_than(&plugin.plugin_version)
+    });
+    let newest = |a: &&Plugin, b: &&Plugin| versions::compare(&a.plugin_version, &b.plugin_version, latest.version_ordering);
+
+    older().filter(|plugin| plugin.rollback).max_by(newest)
+        .or_else(|| older().filter(|plugin| !plugin.beta_only()).max_by(newest))
+}
+
+/// Whether the client's skyline is too old for the plugin, false if either side is unknown
+fn needs_skyline_update(plugin: &Plugin, skyline_version: Option<&str>) -> bool {
+    let skyline_version = skyline_version.and_then(|version| version.parse::<Version>().ok());
+    match (&plugin.skyline_version, skyline_version) {
+        (Some(requirement), Some(version)) => !requirement.matches(&version),
+        _ => false,
+    }
+}
+
+/// Name of the plugin hosting skyline itself, whose files are sent along with updates which
+/// need a newer skyline than the client runs
+const SKYLINE_PLUGIN: &str = "skyline";
+
+/// The newest hosted skyline on `channel` which `plugin` runs on, to update skyline along with it
+fn skyline_build<'a>(plugins: &'a [Plugin], plugin: &Plugin, channel: &str) -> Option<&'a Plugin> {
+    let requirement = plugin.skyline_version.as_ref()?;
+    plugins.iter()
+        .filter(|skyline| skyline.name == SKYLINE_PLUGIN && skyline.offered_on(channel) && !skyline.notify_only)
+        .filter(|skyline| requirement.matches(&skyline.plugin_version))
+        .max_by(|a, b| versions::compare(&a.plugin_version, &b.plugin_version, a.version_ordering))
+}
+
+/// The response to a client behind a `notify_only` plugin. Clients from before `NotifyOnly`
+/// would take an update without files as installed, so they aren't told about it at all.
+fn notify_response(plugin: &Plugin, plugin_name: String, protocol_version: u32) -> UpdateResponse {
+    if protocol_version < 3 {
+        return UpdateResponse::no_update()
+    }
+
+    UpdateResponse {
+        code: ResponseCode::NotifyOnly,
+        plugin_name,
+        new_plugin_version: plugin.plugin_version.to_string(),
+        changelog: plugin.changelog.clone(),
+        homepage: plugin.metadata.homepage.clone(),
+        display_name: plugin.metadata.display_name.clone(),
+        ..Default::default()
+    }
+}
+
+/// `value` as JSON, or a `ServerError` response saying why it couldn't be encoded
+fn to_json<T: serde::Serialize>(value: &T) -> String {
+    serde_json::to_string(value).unwrap_or_else(|e| {
+        println!("Failed to encode a response: {}", e);
+        let response = UpdateResponse::server_error(&format!("the server failed to encode its response: {}", e));
+        serde_json::to_string(&response).unwrap_or_default()
+    })
+}
+
+/// Build the response to a single request line read from the main port. Every request is
+/// answered, with an `UpdateResponse` saying what went wrong if nothing else fits, so clients
+/// never wait on a server which has nothing to send.
+///
+/// Clients which predate protocol versioning never send `protocol_version` and must keep
+/// receiving exactly what they did before: only the v1 fields they know are relied upon
+/// (serde ignores the rest) and no feature they didn't ask for is enabled.
+fn handle_request(packet: &str, state: &ServerState, settings: &Settings) -> String {
+    let plugins = &state.plugins;
+    match serde_json::from_str::<Request>(packet) {
+        Ok(Request::Update { plugin_name, plugin_version, beta, options }) => {
+            let protocol_version = options.as_ref()
+                .and_then(|options| options.protocol_version)
+                .unwrap_or(1);
+            let channel = options.as_ref()
+                .and_then(|options| options.channel.clone())
+                .filter(|_| protocol_version >= 7);
+            let channel = update_protocol::requested_channel(beta.unwrap_or(false), channel.as_deref()).to_owned();
+            let skyline_version = options.as_ref()
+                .and_then(|options| options.skyline_version.clone());
+            let client_version = options.as_ref()
+                .and_then(|options| options.client_version.as_deref())
+                .unwrap_or("unknown");
+            let platform = options.as_ref()
+                .and_then(|options| options.platform.clone())
+                .unwrap_or_else(|| update_protocol::DEFAULT_PLATFORM.to_owned());
+            // not used to choose files yet, but known to everything building the response
+            let plugin_options = options.as_ref()
+                .map(|options| options.plugin_options.clone())
+                .unwrap_or_default();
+            println!(
+                "Update request for {} {} (protocol {}, skyline-update {})",
+                plugin_name, plugin_version, protocol_version, client_version
+            );
+            if !plugin_options.is_empty() {
+                let mut listed: Vec<_> = plugin_options.iter()
+                    .map(|(key, value)| format!("{}={}", key, value))
+                    .collect();
+                listed.sort();
+                println!("    options: {}", listed.join(", "));
             }
+            if channel != STABLE_CHANNEL {
+                println!("    channel: {}", channel);
+            }
+            let pinned = match options.as_ref().and_then(|options| options.pinned_version.as_deref()) {
+                Some(version) if protocol_version >= 5 => match version.parse::<Version>() {
+                    Ok(version) => Some(version),
+                    Err(_) => return to_json(&UpdateResponse::invalid_request()
+                        .with_message(&format!("pinned version '{}' is not valid semver", version))),
+                },
+                _ => None,
+            };
+            let inline = options
+                .filter(|options| protocol_version >= 2 && options.accept_inline && settings.inline.threshold > 0)
+                .map(|_| settings.inline);
+            let plugin = match &pinned {
+                Some(version) => select_pinned(plugins, &plugin_name, version, &channel),
+                None => select_plugin(plugins, &plugin_name, &channel),
+            };
+            // a pinned version is offered whether it is older or newer than the installed one
+            let offered = |plugin: &Plugin, current_version: &Version| if pinned.is_some() {
+                plugin.plugin_version != *current_version
+            } else {
+                plugin.is_newer_than(current_version)
+            };
+            let rollback_version = plugin
+                .filter(|_| pinned.is_none() && protocol_version >= 5)
+                .and_then(|plugin| rollback_target(plugins, plugin, &channel))
+                .map(|target| target.plugin_version.to_string());
 
-            while let Ok((socket, _)) = main_port.accept() {
-                let mut socket = BufReader::new(socket);
-                let plugins = &plugins;
-                let mut packet = String::new();
-                let _ = socket.read_line(&mut packet);
-                macro_rules! respond {
-                    ($expr:expr) => {{
-                        let response = $expr;
-                        let mut socket = socket.into_inner();
-                        let _ = socket.write(format!("{}\n", serde_json::to_string(&response).unwrap()).as_bytes());
-                        let _ = socket.shutdown(std::net::Shutdown::Both);
-                    }}
-                }
-                match serde_json::from_str::<Request>(&packet) {
-                    Ok(Request::Update { plugin_name, plugin_version, beta, .. }) => {
-                        let beta = beta.unwrap_or(false);
-                        let plugin = plugins.iter().filter(|plugin| {
-                            plugin.name == plugin_name && (beta || !plugin.beta)
-                        }).max_by_key(|plugin| &plugin.plugin_version);
-
-                        let response = if let Some(plugin) = plugin {
-                            if let Ok(current_version) = plugin_version.parse::<Version>() {
-                                if current_version < plugin.plugin_version {
-                                    UpdateResponse {
-                                        code: ResponseCode::Update,
-                                        update_plugin: true,
-                                        update_skyline: false,
-                                        plugin_name,
-                                        new_plugin_version: plugin.plugin_version.to_string(),
-                                        new_skyline_version: None,
-                                        required_files: plugin.files.iter().map(|file| file.into()).collect()
-                                    }
-                                } else {
-                                    UpdateResponse::no_update()
-                                }
-                            } else {
-                                UpdateResponse::invalid_request()
-                            }
-                        } else {
-                            UpdateResponse::plugin_not_found()
-                        };
+            let response = if let Some(plugin) = plugin {
+                if let Ok(current_version) = plugin_version.parse::<Version>() {
+                    if offered(plugin, &current_version) && plugin.notify_only {
+                        notify_response(plugin, plugin_name, protocol_version)
+                    } else if plugin.has_relative_files() && protocol_version < 4 {
+                        // they would refuse the whole update
+                        UpdateResponse::no_update()
+                    } else if offered(plugin, &current_version) {
+                        let update_skyline = needs_skyline_update(plugin, skyline_version.as_deref());
+                        let skyline = Some(plugins)
+                            .filter(|_| update_skyline)
+                            .and_then(|plugins| skyline_build(plugins, plugin, &channel));
+                        let skyline_requirement = plugin.skyline_version.as_ref()
+                            .filter(|_| protocol_version >= 2)
+                            .map(ToString::to_string);
+                        if update_skyline && skyline.is_none() && settings.withhold_outdated_skyline {
+                            println!("    withheld, skyline {} doesn't satisfy {}", skyline_version.as_deref().unwrap_or_default(), skyline_requirement.as_deref().unwrap_or_default());
+                            return to_json(&UpdateResponse {
+                                update_skyline,
+                                new_plugin_version: plugin.plugin_version.to_string(),
+                                skyline_requirement,
+                                ..UpdateResponse::no_update()
+                            })
+                        }
+                        // skyline is installed first, so the plugin never runs on the old one
+                        let sources: Vec<_> = skyline.into_iter().chain(std::iter::once(plugin)).collect();
 
-                        respond!(response);
-                    }
-                    Ok(Request::Metadata { plugin_name, beta, .. }) => {
-                        let beta = beta.unwrap_or(false);
-                        let plugin = plugins.iter().filter(|plugin| {
-                            plugin.name == plugin_name && (beta || !plugin.beta)
-                        }).max_by_key(|plugin| &plugin.plugin_version);
-
-                        if let Some(plugin) = plugin {
-                            respond!(&plugin.metadata)
+                        let mut response = UpdateResponse {
+                            code: ResponseCode::Update,
+                            update_plugin: true,
+                            update_skyline,
+                            plugin_name,
+                            new_plugin_version: plugin.plugin_version.to_string(),
+                            new_skyline_version: skyline.map(|skyline| skyline.plugin_version.to_string()),
+                            required_files: required_files(&sources, inline, protocol_version >= 2, &platform),
+                            skyline_requirement,
+                            download_port: settings.advertised_download_port().filter(|_| protocol_version >= 2),
+                            display_name: plugin.metadata.display_name.clone()
+                                .filter(|_| protocol_version >= 2),
+                            rollback_version,
+                            keep_alive_downloads: protocol_version >= 8,
+                            files_to_delete: plugin.deleted_files.clone(),
+                            // the changelog of the version offered, rather than whatever the
+                            // plugin's metadata would give for another channel
+                            changelog: plugin.changelog.clone().filter(|_| protocol_version >= 3),
+                            ..Default::default()
+                        };
+                        if let Some(key) = &settings.signing_key {
+                            key.sign_response(&mut response, protocol_version);
                         }
-                    }
-                    _ => respond!(UpdateResponse::invalid_request()),
-                }
-            }
 
-            while let Ok((mut socket, _)) = download_port.accept() {
-                let mut buf = [0; 8];
-                if let Ok(_) = socket.read_exact(&mut buf) {
-                    let index = u64::from_be_bytes(buf) as usize;
-                    if let Some(file) = files.get(index) {
-                        let data = Arc::clone(&file);
-                        scope.spawn(move |_| {
-                            let _ = socket.write_all(&data);
-                        });
+                        response
+                    } else {
+                        UpdateResponse { rollback_version, ..UpdateResponse::no_update() }
                     }
                 } else {
-                    println!("Failed to read index");
-                    let _ = socket.shutdown(std::net::Shutdown::Both);
+                    UpdateResponse::invalid_request()
+                        .with_message(&format!("version '{}' is not valid semver", plugin_version))
                 }
+            } else if pinned.is_some() && protocol_version >= 11 && plugins.iter().any(|plugin| plugin.name == plugin_name) {
+                UpdateResponse::version_not_found()
+                    .with_message(&not_found_message(plugins, &plugin_name, &channel, pinned.as_ref()))
+            } else {
+                let message = not_found_message(plugins, &plugin_name, &channel, pinned.as_ref());
+                missing_plugin_response(state, &message, protocol_version)
+            };
+            // such as a plugin whose files are all meant for other platforms
+            if response.is_empty_update() {
+                println!(
+                    "    warning: {} {} has no files for platform {}, clients will refuse the update",
+                    response.plugin_name, response.new_plugin_version, platform
+                );
             }
 
-            std::thread::sleep(Duration::from_millis(10));
+            to_json(&response)
+        }
+        Ok(Request::Metadata { plugin_name, beta, .. }) => {
+            let channel = update_protocol::requested_channel(beta.unwrap_or(false), None);
+            match select_plugin(plugins, &plugin_name, channel) {
+                Some(plugin) => to_json(&plugin.metadata),
+                None => to_json(&UpdateResponse::plugin_not_found().with_message(&not_found_message(plugins, &plugin_name, channel, None))),
+            }
+        }
+        Ok(Request::Ping) => {
+            to_json(&PingResponse {
+                plugin_count: plugins.len(),
+                load_failure_count: state.load_failures.len(),
+                server_version: Some(server_version().to_owned()),
+                reload_in_progress: state.reload_in_progress.load(Ordering::SeqCst),
+            })
+        }
+        Ok(Request::LoadFailures { token }) if settings.is_admin(&token) => to_json(&state.load_failures),
+        Ok(Request::ReloadHistory { token }) if settings.is_admin(&token) => to_json(&state.reload_history),
+        Ok(Request::Downloads { token, incomplete_only }) if settings.is_admin(&token) => {
+            to_json(&state.downloads.snapshot(incomplete_only))
+        }
+        Err(e) => to_json(&UpdateResponse::invalid_request().with_message(&format!("the request could not be parsed: {}", e))),
+        _ => to_json(&UpdateResponse::invalid_request()),
+    }
+}
+
+/// Seconds clients are asked to wait before asking again for a plugin which isn't served while
+/// plugins are reloading
+const RELOAD_RETRY_AFTER: u64 = 5;
+
+/// The answer to a request for a plugin which isn't served, saying `message` if it isn't found.
+/// Clients since protocol version 9 are asked to come back while a reload which may add it is
+/// running, and are told when plugins failed to load rather than that the plugin doesn't exist,
+/// as it may be one of them.
+fn missing_plugin_response(state: &ServerState, message: &str, protocol_version: u32) -> UpdateResponse {
+    if protocol_version < 9 {
+        UpdateResponse::plugin_not_found().with_message(message)
+    } else if state.reload_in_progress.load(Ordering::SeqCst) {
+        println!("    not found while reloading, asked to retry in {} seconds", RELOAD_RETRY_AFTER);
+        UpdateResponse::rate_limited(RELOAD_RETRY_AFTER)
+    } else if let Some(error) = &state.reload_error {
+        UpdateResponse::server_error(&format!("the plugin isn't served and the last reload failed: {}", error))
+    } else if !state.load_failures.is_empty() {
+        UpdateResponse::server_error(&format!(
+            "the plugin isn't served and {} plugin(s) failed to load, it may be one of them",
+            state.load_failures.len()
+        ))
+    } else {
+        UpdateResponse::plugin_not_found().with_message(message)
+    }
+}
+
+/// Why no copy of `name` is served for a request, suggesting a hosted plugin with a similar
+/// name for typos and wrong capitalization
+fn not_found_message(plugins: &[Plugin], name: &str, channel: &str, pinned: Option<&Version>) -> String {
+    if plugins.iter().any(|plugin| plugin.name == name) {
+        return match pinned {
+            Some(version) => format!("version {} of '{}' isn't hosted on the {} channel", version, name, channel),
+            None => format!("'{}' has no version on the {} channel", name, channel),
+        }
+    }
+
+    match similar_plugin_name(plugins, name) {
+        Some(similar) => format!("plugin '{}' isn't hosted, did you mean '{}'?", name, similar),
+        None => format!("plugin '{}' isn't hosted", name),
+    }
+}
+
+/// The hosted plugin name closest to `name`, ignoring case, if it is close enough to be a typo
+fn similar_plugin_name<'a>(plugins: &'a [Plugin], name: &str) -> Option<&'a str> {
+    let name = name.to_lowercase();
+    let max_distance = (name.chars().count() / 4).clamp(1, 3);
+    plugins.iter()
+        .map(|plugin| (edit_distance(&plugin.name.to_lowercase(), &name), plugin.name.as_str()))
+        .filter(|(distance, _)| *distance <= max_distance)
+        .min()
+        .map(|(_, similar)| similar)
+}
+
+/// Number of characters inserted, deleted or replaced to turn `a` into `b`
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut previous: Vec<usize> = (0..=b.len()).collect();
+    for (i, a) in a.chars().enumerate() {
+        let mut current = vec![i + 1];
+        for (j, b) in b.iter().enumerate() {
+            let replace = previous[j] + if a == *b { 0 } else { 1 };
+            current.push(replace.min(previous[j + 1] + 1).min(current[j] + 1));
+        }
+        previous = current;
+    }
+
+    previous[b.len()]
+}
+
+/// Read the 8-byte big endian download index a download starts with, None if the client sent
+/// fewer bytes. Anything sent after it is ignored.
+fn read_download_index<R: Read>(socket: &mut R) -> Option<[u8; 8]> {
+    let mut index = [0; 8];
+    socket.read_exact(&mut index).ok()?;
+    Some(index)
+}
+
+/// Look up the file to send for a raw 8-byte big endian download index
+fn handle_download(index: [u8; 8], state: &ServerState) -> Option<served::ServedFile> {
+    let index = u64::from_be_bytes(index);
+    state.plugins.iter().find_map(|plugin| plugin.download(index)).map(|(data, _)| data.clone())
+}
+
+/// The plugin and install location a download index belongs to, for the download statistics
+fn download_label(index: u64, state: &ServerState) -> (String, String) {
+    state.plugins.iter()
+        .find_map(|plugin| plugin.download(index).map(|(_, location)| (plugin_key(plugin), location)))
+        .unwrap_or_else(|| (String::from("(unknown)"), format!("index {}", index)))
+}
+
+/// Answer a single connection to the main port, using a pooled buffer for the request line
+/// and response. The state is only locked while the response is built, so a client slow to
+/// send or read doesn't hold up reloads.
+fn serve_request(socket: &mut tls::Socket, state: &RwLock<ServerState>, settings: &Settings, pool: &pool::BufferPool) {
+    let mut buf = pool.get();
+    let _ = pool::read_request_line(socket, &mut buf);
+
+    let packet = String::from_utf8_lossy(&buf);
+    let response = handle_request(&packet, &read_state(state), settings);
+    buf.clear();
+    buf.extend_from_slice(response.as_bytes());
+    buf.push(b'\n');
+    let _ = socket.write_all(&buf);
+    let _ = socket.shutdown();
+}
+
+/// Version of the server with the commit and time it was built
+fn server_version() -> &'static str {
+    concat!(env!("CARGO_PKG_VERSION"), " (", env!("BUILD_COMMIT"), ", built ", env!("BUILD_TIMESTAMP"), ")")
+}
+
+/// Remove `--port <port>` from the arguments, so it can be given before or after a command
+fn take_port_arg(args: &mut Vec<String>) -> eyre::Result<Option<u16>> {
+    take_value_arg(args, "--port")?
+        .map(|port| port.parse().map_err(|_| eyre::eyre!("--port requires a port number")))
+        .transpose()
+}
+
+/// Remove `flag <value>` from the arguments wherever it is, returning the value
+fn take_value_arg(args: &mut Vec<String>, flag: &str) -> eyre::Result<Option<String>> {
+    let position = match args.iter().position(|arg| arg == flag) {
+        Some(position) => position,
+        None => return Ok(None),
+    };
+
+    if position + 1 >= args.len() {
+        eyre::bail!("{} requires a value", flag);
+    }
+    let value = args.remove(position + 1);
+    args.remove(position);
+
+    Ok(Some(value))
+}
+
+/// Remove `flag` from the arguments wherever it is, returning whether it was given
+fn take_flag(args: &mut Vec<String>, flag: &str) -> bool {
+    let len = args.len();
+    args.retain(|arg| arg != flag);
+    args.len() != len
+}
+
+/// A free port on `address` whose next port is free too, for the main and download ports of
+/// `--port 0`
+fn free_port_pair(address: IpAddr) -> eyre::Result<u16> {
+    for _ in 0..100 {
+        let main = TcpListener::bind((address, 0))?;
+        let port = main.local_addr()?.port();
+        if port < u16::MAX && TcpListener::bind((address, port + 1)).is_ok() {
+            return Ok(port)
+        }
+    }
+
+    eyre::bail!("Failed to find two free consecutive ports")
+}
+
+fn main() -> eyre::Result<()> {
+    color_eyre::install()?;
+
+    let mut args: Vec<String> = std::env::args().collect();
+    let config = match take_value_arg(&mut args, "--config")? {
+        Some(path) => config::ConfigFile::read(Path::new(&path), true)?,
+        None => config::ConfigFile::read(Path::new(config::DEFAULT_CONFIG_FILE), false)?,
+    };
+    let mut settings = Settings::from_env(&config);
+    skyline_update::init_logger(settings.log_level).map_err(|e| eyre::eyre!("{}", e))?;
+    if let Some(port) = take_port_arg(&mut args)? {
+        settings.port = port;
+    }
+    let tls_cert = take_value_arg(&mut args, "--tls-cert")?;
+    let tls_key = take_value_arg(&mut args, "--tls-key")?;
+    if let Some(plugins_dir) = take_value_arg(&mut args, "--plugins-dir")? {
+        settings.plugins_dir = PathBuf::from(plugins_dir);
+    }
+    // answering broadcasts is only wanted on a local network, so it is never on by default
+    let discovery_name = if take_flag(&mut args, "--discoverable") {
+        Some(std::env::var("UPDATE_SERVER_NAME").ok()
+            .filter(|name| !name.is_empty())
+            .unwrap_or_else(|| discovery::DEFAULT_NAME.to_owned()))
+    } else {
+        None
+    };
+    if settings.port == u16::MAX && settings.download_port.is_none() {
+        eyre::bail!("Port {} leaves no room for the download port", settings.port);
+    }
+    if settings.download_port == Some(settings.port) && settings.port != 0 {
+        eyre::bail!("The download port can't be the same as the port ({})", settings.port);
+    }
+    if take_flag(&mut args, "--print-config") {
+        print!("{}", toml::to_string(&settings.effective_config())?);
+        return Ok(());
+    }
+
+    if args.get(1).map(String::as_str) == Some("--version") {
+        println!("update-server {}", server_version());
+        return Ok(());
+    }
+    if args.get(1).map(String::as_str) == Some("diff") {
+        return manifest::run_diff(&settings.plugins_dir, args.get(2).map(String::as_str));
+    }
+    if args.get(1).map(String::as_str) == Some("downloads") {
+        let incomplete_only = args.iter().skip(2).any(|arg| arg == "--incomplete");
+        return downloads::run_downloads(settings.port, incomplete_only);
+    }
+    if args.get(1).map(String::as_str) == Some("keygen") {
+        return signing::run_keygen(Path::new(args.get(2).map(String::as_str).unwrap_or(".")));
+    }
+    if args.get(1).map(String::as_str) == Some("validate") {
+        return validate::run_validate(&settings.plugins_dir);
+    }
+    if args.get(1).map(String::as_str) == Some("self-check") {
+        let status = self_check::run_self_check(settings.port, &args[2..])?;
+        std::process::exit(status.code());
+    }
+
+    //hosted_plugins::print_default();
+
+    println!("update-server {}", server_version());
+
+    if !settings.plugins_dir.exists() {
+        fs::create_dir_all(&settings.plugins_dir)?;
+    }
+    println!("Serving plugins from {}", fs::canonicalize(&settings.plugins_dir)?.display());
+
+    if settings.port == 0 {
+        settings.port = free_port_pair(settings.bind_address)?;
+    }
+    // plaintext unless asked for, as clients only speak TLS when built with it
+    settings.tls = tls::Acceptor::new(tls_cert.as_deref(), tls_key.as_deref())?;
+    settings.signing_key = signing::SigningKey::from_env()?;
+    println!("Listening on {} port {}, downloads on port {}", settings.bind_address, settings.port, settings.download_port());
+    // for scripts and tests launching the server on `--port 0`, kept as it is across versions
+    println!("ports {} {}", settings.port, settings.download_port());
+    if settings.tls.is_tls() {
+        println!("Serving both ports over TLS");
+    }
+    if let Some(key) = &settings.signing_key {
+        let public_key: String = key.public_key().iter().map(|byte| format!("{:02x}", byte)).collect();
+        println!("Signing updates with public key {}", public_key);
+    }
+    if let Some(name) = &discovery_name {
+        println!("Answering discovery on UDP port {} as {}", update_protocol::DISCOVERY_PORT, name);
+    }
+
+    let mut index_blocks = vec![];
+    let (plugins, load_failures) = setup_plugin_ports(&settings.plugins_dir, &mut index_blocks)?;
+    let summary = summarize_reload(&[], &plugins);
+    log_reload(&summary);
+
+    let mut reload_history = VecDeque::with_capacity(settings.history_len);
+    reload_history.push_back(summary);
+
+    let state = RwLock::new(ServerState {
+        plugins,
+        load_failures,
+        index_blocks,
+        reload_history,
+        downloads: Default::default(),
+        reload_in_progress: AtomicBool::new(false),
+        reload_error: None,
+    });
+
+    let request_buffers = pool::BufferPool::new(pool::REQUEST_BUFFERS, pool::MAX_BUFFER_SIZE);
+    let (requests, request_queue) = crossbeam::channel::bounded::<TcpStream>(REQUEST_QUEUE_LEN);
+    let (downloads, download_queue) = crossbeam::channel::bounded::<TcpStream>(downloads::DOWNLOAD_QUEUE_LEN);
+    let policy = supervisor::RestartPolicy::default();
+
+    let state = &state;
+    let settings = &settings;
+    let request_buffers = &request_buffers;
+    let requests = &requests;
+    let downloads = &downloads;
+    let policy = &policy;
+    let discovery_name = discovery_name.as_deref();
+
+    crossbeam::scope(move |scope|{
+        for _ in 0..REQUEST_WORKERS {
+            let request_queue = request_queue.clone();
+            scope.spawn(move |_| {
+                exit_on_escalation(supervisor::supervise("request worker", policy, || {
+                    run_request_worker(&request_queue, state, settings, request_buffers);
+                    Ok(())
+                }));
+            });
+        }
+
+        for _ in 0..settings.download_workers {
+            let download_queue = download_queue.clone();
+            scope.spawn(move |_| {
+                exit_on_escalation(supervisor::supervise("download worker", policy, || {
+                    run_download_worker(&download_queue, &settings.tls, state);
+                    Ok(())
+                }));
+            });
+        }
+
+        scope.spawn(move |_| {
+            let mut restarted = false;
+            exit_on_escalation(supervisor::supervise("plugin watcher", policy, || {
+                // changes may have been missed while the watcher was down
+                if restarted {
+                    println!("Watcher restarted: refreshing plugins...");
+                    reload_plugins(state, settings, None)?;
+                }
+                restarted = true;
+                watch_plugins(state, settings)
+            }));
+        });
+
+        scope.spawn(move |_| {
+            exit_on_escalation(supervisor::supervise("request acceptor", policy, || {
+                accept_requests(settings, requests)
+            }));
+        });
+
+        scope.spawn(move |_| {
+            exit_on_escalation(supervisor::supervise("download acceptor", policy, || {
+                accept_downloads(settings, downloads)
+            }));
+        });
+
+        if let Some(name) = discovery_name {
+            scope.spawn(move |_| {
+                exit_on_escalation(supervisor::supervise("discovery responder", policy, || {
+                    discovery::answer_discovery(state, name, settings.port)
+                }));
+            });
+        }
+    }).unwrap();
+
+    Ok(())
+}
+
+/// Exit with a nonzero code once a task crashes too often, so a service manager can restart the
+/// whole server
+fn exit_on_escalation(result: Result<(), supervisor::Escalation>) {
+    if let Err(escalation) = result {
+        println!("{}, exiting", escalation);
+        std::process::exit(1);
+    }
+}
+
+/// The state is only ever replaced whole, so it is still usable if a task panicked holding the lock
+fn read_state(state: &RwLock<ServerState>) -> RwLockReadGuard<'_, ServerState> {
+    state.read().unwrap_or_else(PoisonError::into_inner)
+}
+
+fn write_state(state: &RwLock<ServerState>) -> RwLockWriteGuard<'_, ServerState> {
+    state.write().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// Reload the plugins in `folders`, or every plugin if it is None
+fn reload_plugins(state: &RwLock<ServerState>, settings: &Settings, folders: Option<&[PathBuf]>) -> eyre::Result<()> {
+    reload_plugins_with(state, settings, folders, |index_blocks| match folders {
+        Some(folders) => setup_plugin_folders(folders, index_blocks),
+        None => setup_plugin_ports(&settings.plugins_dir, index_blocks),
+    })
+}
+
+/// Whether `directory`, as reported in a `LoadFailure`, is one of `folders` or one of their
+/// version folders
+fn is_one_of(folders: &[PathBuf], directory: &str) -> bool {
+    folders.iter().any(|folder| Path::new(directory).starts_with(folder))
+}
+
+/// Replace the plugins loaded from `folders`, or every plugin if it is None, with the ones
+/// returned by `load`. Only the watcher's thread ever waits on `load`, the state is locked for
+/// writing just long enough to swap in the result.
+fn reload_plugins_with<F>(state: &RwLock<ServerState>, settings: &Settings, folders: Option<&[PathBuf]>, load: F) -> eyre::Result<()>
+    where F: FnOnce(&mut Vec<PathBuf>) -> eyre::Result<LoadedPlugins>,
+{
+    let (previous, mut index_blocks) = {
+        let state = read_state(state);
+        state.reload_in_progress.store(true, Ordering::SeqCst);
+        (plugin_versions(&state.plugins), state.index_blocks.clone())
+    };
+    // the old plugins keep being served until the new ones are ready
+    let loaded = load(&mut index_blocks);
+
+    let mut state = write_state(state);
+    state.reload_in_progress.store(false, Ordering::SeqCst);
+    let (plugins, mut load_failures) = match loaded {
+        Ok(loaded) => loaded,
+        Err(e) => {
+            state.reload_error = Some(e.to_string());
+            return Err(e)
+        }
+    };
+    state.reload_error = None;
+    state.index_blocks = index_blocks;
+    match folders {
+        Some(folders) => {
+            state.plugins.retain(|plugin| !folders.iter().any(|folder| plugin.directory.starts_with(folder)));
+            state.plugins.extend(plugins);
+            // failures of the other folders still stand
+            let untouched = state.load_failures.iter()
+                .filter(|failure| !is_one_of(folders, &failure.directory))
+                .cloned();
+            load_failures = untouched.chain(load_failures).collect();
         }
-    }).unwrap()
+        None => state.plugins = plugins,
+    }
+
+    let summary = summarize_reload(&previous, &state.plugins);
+    log_reload(&summary);
+    if state.reload_history.len() >= settings.history_len {
+        state.reload_history.pop_front();
+    }
+    state.reload_history.push_back(summary);
+
+    state.load_failures = merge_load_failures(&state.load_failures, load_failures);
+    if !state.load_failures.is_empty() {
+        println!("{} plugin(s) failed to load", state.load_failures.len());
+    }
+
+    Ok(())
+}
+
+/// Reload the plugins whenever the plugins folder changes
+fn watch_plugins(state: &RwLock<ServerState>, settings: &Settings) -> eyre::Result<()> {
+    let (tx, rx) = channel();
+    let mut watcher = watcher(tx, settings.watch_debounce)?;
+    watcher.watch(&settings.plugins_dir, RecursiveMode::Recursive)?;
+
+    // the watcher reports absolute paths, resolving links on some platforms
+    let watched = [std::env::current_dir()?.join(&settings.plugins_dir), fs::canonicalize(&settings.plugins_dir)?];
+    coalesce_reloads(&rx, &watched, &settings.plugins_dir, |folders| reload_plugins(state, settings, folders))?;
+
+    eyre::bail!("File watcher stopped")
+}
+
+/// Whether a watcher event calls for a reload, logging watch errors
+fn triggers_reload(event: &DebouncedEvent) -> bool {
+    match event {
+        DebouncedEvent::Error(err, Some(path)) => {
+            println!("File watch error at path {}: {}", path.display(), err);
+            false
+        }
+        DebouncedEvent::Error(err, None) => {
+            println!("File watch error: {}", err);
+            false
+        }
+        /* dont refresh plugins on zip creation/write. This prevents infinite plugin refreshing with zip creation */
+        DebouncedEvent::Create(path)
+        | DebouncedEvent::Write(path)
+        | DebouncedEvent::NoticeWrite(path) => path.extension().unwrap_or_default() != "tar",
+        _ => true,
+    }
+}
+
+/// The plugin folders touched by a burst of watcher events
+struct ChangedFolders<'a> {
+    /// The plugins folder as the watcher may report paths in it
+    watched: &'a [PathBuf],
+    plugins_dir: &'a Path,
+    /// Set by events which can't be pinned on a plugin folder, such as a rescan
+    everything: bool,
+    folders: Vec<PathBuf>,
+}
+
+impl<'a> ChangedFolders<'a> {
+    fn new(watched: &'a [PathBuf], plugins_dir: &'a Path) -> Self {
+        ChangedFolders { watched, plugins_dir, everything: false, folders: vec![] }
+    }
+
+    fn is_empty(&self) -> bool {
+        !self.everything && self.folders.is_empty()
+    }
+
+    /// Note the plugin folders `event` touched, if it calls for a reload at all
+    fn add(&mut self, event: &DebouncedEvent) {
+        if !triggers_reload(event) {
+            return
+        }
+
+        let paths = match event {
+            DebouncedEvent::Create(path)
+            | DebouncedEvent::Write(path)
+            | DebouncedEvent::NoticeWrite(path)
+            | DebouncedEvent::Chmod(path)
+            | DebouncedEvent::Remove(path)
+            | DebouncedEvent::NoticeRemove(path) => vec![path],
+            DebouncedEvent::Rename(from, to) => vec![from, to],
+            _ => vec![],
+        };
+        if paths.is_empty() {
+            self.everything = true;
+        }
+
+        for path in paths {
+            // the first component under the plugins folder is the plugin's folder
+            let folder = self.watched.iter()
+                .find_map(|watched| path.strip_prefix(watched).ok())
+                .and_then(|path| path.components().next());
+            match folder {
+                Some(folder) => {
+                    let folder = self.plugins_dir.join(folder);
+                    if !self.folders.contains(&folder) {
+                        self.folders.push(folder);
+                    }
+                }
+                None => self.everything = true,
+            }
+        }
+    }
+
+    /// The folders to reload, None for every plugin, leaving none behind
+    fn take(&mut self) -> Option<Vec<PathBuf>> {
+        let folders = std::mem::take(&mut self.folders);
+        if std::mem::take(&mut self.everything) {
+            None
+        } else {
+            Some(folders)
+        }
+    }
+}
+
+fn describe_folders(folders: Option<&[PathBuf]>) -> String {
+    match folders {
+        Some(folders) => folders.iter().map(|folder| folder.display().to_string()).collect::<Vec<_>>().join(", "),
+        None => String::from("plugins"),
+    }
+}
+
+/// Call `reload` once per burst of watcher events until `events` disconnects, with the plugin
+/// folders they touched or None to reload every plugin. `watched` are the forms of the plugins
+/// folder the watcher may report paths in. A reload reads the folders as they are once it starts, so
+/// every event queued by then is covered by it. Events arriving while it loads may belong to a
+/// copy which wasn't finished when it was read, so they cause exactly one more reload, however
+/// many there are.
+fn coalesce_reloads<F>(events: &Receiver<DebouncedEvent>, watched: &[PathBuf], plugins_dir: &Path, mut reload: F) -> eyre::Result<()>
+    where F: FnMut(Option<&[PathBuf]>) -> eyre::Result<()>,
+{
+    let mut changed = ChangedFolders::new(watched, plugins_dir);
+    while let Ok(event) = events.recv() {
+        changed.add(&event);
+        if changed.is_empty() {
+            continue
+        }
+
+        events.try_iter().for_each(|event| changed.add(&event));
+        let mut folders = changed.take();
+        println!("Change detected: refreshing {}...", describe_folders(folders.as_deref()));
+        loop {
+            reload(folders.as_deref())?;
+
+            events.try_iter().for_each(|event| changed.add(&event));
+            if changed.is_empty() {
+                break
+            }
+            folders = changed.take();
+            println!("Changed while reloading: refreshing {} again...", describe_folders(folders.as_deref()));
+        }
+    }
+
+    Ok(())
+}
+
+fn accept_requests(settings: &Settings, requests: &crossbeam::channel::Sender<TcpStream>) -> eyre::Result<()> {
+    serve_requests(&TcpListener::bind((settings.bind_address, settings.port))?, requests)
+}
+
+/// Hand every connection to the main port to the request workers, without reading anything
+/// from it, so a stalled client only ever holds up the worker answering it
+fn serve_requests(listener: &TcpListener, requests: &crossbeam::channel::Sender<TcpStream>) -> eyre::Result<()> {
+    for socket in listener.incoming() {
+        match socket {
+            Ok(socket) => if requests.try_send(socket).is_err() {
+                println!("Request queue is full, refusing connection");
+            },
+            Err(e) => println!("Failed to accept connection: {}", e),
+        }
+    }
+
+    Ok(())
+}
+
+/// Answer the connections the request acceptor hands over until it stops. Clients which don't
+/// send their request within `REQUEST_TIMEOUT` get the answer to whatever they sent.
+fn run_request_worker(requests: &crossbeam::channel::Receiver<TcpStream>, state: &RwLock<ServerState>, settings: &Settings, pool: &pool::BufferPool) {
+    for socket in requests.iter() {
+        let _ = socket.set_read_timeout(Some(REQUEST_TIMEOUT));
+        let _ = socket.set_write_timeout(Some(REQUEST_TIMEOUT));
+        match settings.tls.accept(socket) {
+            Ok(mut socket) => serve_request(&mut socket, state, settings, pool),
+            Err(e) => println!("Failed to accept connection: {}", e),
+        }
+    }
+}
+
+fn accept_downloads(settings: &Settings, downloads: &crossbeam::channel::Sender<TcpStream>) -> eyre::Result<()> {
+    serve_downloads(&TcpListener::bind((settings.bind_address, settings.download_port()))?, downloads)
+}
+
+/// Hand every connection to the download port to the download workers, without reading
+/// anything from it, so a client stalling its handshake or index only holds up one worker
+fn serve_downloads(listener: &TcpListener, downloads: &crossbeam::channel::Sender<TcpStream>) -> eyre::Result<()> {
+    for socket in listener.incoming() {
+        match socket {
+            Ok(socket) => if downloads.try_send(socket).is_err() {
+                println!("Download queue is full, refusing download");
+            },
+            Err(e) => println!("Failed to accept download: {}", e),
+        }
+    }
+
+    Ok(())
+}
+
+/// The download a connection to the download port asks for, None (closing it) if the client
+/// doesn't finish its handshake and send an index within `REQUEST_TIMEOUT`
+fn accept_download(socket: TcpStream, tls: &tls::Acceptor, state: &RwLock<ServerState>) -> Option<downloads::DownloadJob> {
+    let _ = socket.set_read_timeout(Some(REQUEST_TIMEOUT));
+    let _ = socket.set_write_timeout(Some(REQUEST_TIMEOUT));
+    let mut socket = match tls.accept(socket) {
+        Ok(socket) => socket,
+        Err(e) => {
+            println!("Failed to accept download: {}", e);
+            return None
+        }
+    };
+
+    // a kept-alive connection starts with `KEEP_ALIVE_INDEX`, then the first index
+    let (buf, keep_alive) = match read_download_index(&mut socket) {
+        Some(buf) if u64::from_be_bytes(buf) == update_protocol::KEEP_ALIVE_INDEX => (read_download_index(&mut socket), true),
+        buf => (buf, false),
+    };
+    match buf {
+        Some(buf) => download_job(socket, buf, keep_alive, &read_state(state)),
+        None => {
+            println!("Failed to read index");
+            let _ = socket.shutdown();
+            None
+        }
+    }
+}
+
+/// A download of the file at a raw 8-byte big endian index, None (closing the socket) if there
+/// is no such file or it can't be opened. Kept-alive connections are told the file is missing
+/// with `update_protocol::MISSING_FILE_LEN` first.
+fn download_job(socket: tls::Socket, index: [u8; 8], keep_alive: bool, state: &ServerState) -> Option<downloads::DownloadJob> {
+    let missing = |mut socket: tls::Socket| {
+        if keep_alive {
+            let _ = socket.write_all(&update_protocol::MISSING_FILE_LEN.to_be_bytes());
+        }
+        let _ = socket.shutdown();
+        None
+    };
+    let data = match handle_download(index, state) {
+        Some(data) => data,
+        None => return missing(socket),
+    };
+    let index = u64::from_be_bytes(index);
+    let (plugin, file) = download_label(index, state);
+    let data = match data.open() {
+        Ok(data) => data,
+        Err(e) => {
+            println!("Failed to open {} ({}) for download: {}", file, plugin, e);
+            return missing(socket)
+        }
+    };
+    let _ = socket.set_write_timeout(Some(downloads::STALL_TIMEOUT));
+
+    Some(downloads::DownloadJob {
+        socket,
+        index,
+        data,
+        plugin,
+        file,
+        tracker: Arc::clone(&state.downloads),
+        keep_alive,
+    })
+}
+
+/// Send the downloads of the connections `serve_downloads` hands over, serving every file
+/// asked for on a kept-alive connection before taking the next connection
+fn run_download_worker(downloads: &crossbeam::channel::Receiver<TcpStream>, tls: &tls::Acceptor, state: &RwLock<ServerState>) {
+    for socket in downloads.iter() {
+        let mut kept_alive = accept_download(socket, tls, state).and_then(downloads::DownloadJob::run);
+        while let Some(socket) = kept_alive {
+            kept_alive = next_download(socket, state).and_then(downloads::DownloadJob::run);
+        }
+    }
+}
+
+/// The next file asked for on a kept-alive connection, None once the client closed it, stayed
+/// idle for longer than `downloads::STALL_TIMEOUT` or asked for a file that doesn't exist
+fn next_download(mut socket: tls::Socket, state: &RwLock<ServerState>) -> Option<downloads::DownloadJob> {
+    let _ = socket.set_read_timeout(Some(downloads::STALL_TIMEOUT));
+    let index = read_download_index(&mut socket)?;
+    download_job(socket, index, true, &read_state(state))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn plugin_with_sizes(sizes: &[usize]) -> Plugin {
+        Plugin {
+            directory: PathBuf::from("plugins/test_plugin"),
+            name: "test_plugin".into(),
+            plugin_version: "1.0.0".parse().unwrap(),
+            files: sizes.iter()
+                .enumerate()
+                .map(|(i, &size)| PluginFile {
+                    install: InstallLocation::AbsolutePath(format!("sd:/file{}", i)),
+                    sha256: manifest::sha256(&vec![0x55; size]),
+                    data: served::ServedFile::from(vec![0x55; size]),
+                    index: i as u64,
+                    platform: None,
+                })
+                .collect(),
+            metadata_files: vec![],
+            metadata: PluginMetadata {
+                name: None,
+                description: None,
+                images_index: 0,
+                image_count: 0,
+                changelog_index: 0,
+                images: vec![],
+                homepage: None,
+                display_name: None,
+            },
+            skyline_version: None,
+            channel: STABLE_CHANNEL.into(),
+            notify_only: false,
+            changelog: None,
+            version_ordering: VersionOrdering::Semver,
+            prerelease_on_stable: false,
+            rollback: false,
+            deleted_files: vec![],
+        }
+    }
+
+    fn test_state(plugins: Vec<Plugin>) -> ServerState {
+        ServerState {
+            plugins,
+            load_failures: vec![],
+            index_blocks: vec![],
+            reload_history: VecDeque::new(),
+            downloads: Default::default(),
+            reload_in_progress: AtomicBool::new(false),
+            reload_error: None,
+        }
+    }
+
+    fn test_settings() -> Settings {
+        Settings {
+            inline: InlineConfig { threshold: INLINE_THRESHOLD, max_total: INLINE_MAX_TOTAL },
+            admin_token: None,
+            history_len: RELOAD_HISTORY_LEN,
+            download_workers: 1,
+            public_download_port: None,
+            bind_address: IpAddr::V4(Ipv4Addr::LOCALHOST),
+            port: PORT_NUM,
+            download_port: None,
+            watch_debounce: Duration::from_secs(WATCH_DEBOUNCE_SECS),
+            log_level: log::LevelFilter::Info,
+            tls: tls::Acceptor::default(),
+            signing_key: None,
+            plugins_dir: PathBuf::from(DEFAULT_PLUGINS_DIR),
+            withhold_outdated_skyline: false,
+        }
+    }
+
+    #[test]
+    fn test_request_buffers_bounded() {
+        let state = RwLock::new(test_state(vec![plugin_with_sizes(&[10, 20])]));
+        let settings = test_settings();
+        let pool = pool::BufferPool::new(4, pool::MAX_BUFFER_SIZE);
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+
+        for i in 0..300 {
+            let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+            let version = if i % 2 == 0 { "0.9.0" } else { "1.0.0" };
+            let packet = serde_json::to_string(&Request::Update {
+                plugin_name: "test_plugin".into(),
+                plugin_version: version.into(),
+                beta: None,
+                options: None,
+            }).unwrap();
+            client.write_all(format!("{}\n", packet).as_bytes()).unwrap();
+
+            let (socket, _) = listener.accept().unwrap();
+            serve_request(&mut tls::Socket::Plain(socket), &state, &settings, &pool);
+
+            let mut response = String::new();
+            client.read_to_string(&mut response).unwrap();
+            let response: UpdateResponse = serde_json::from_str(&response).unwrap();
+            assert_eq!(response.required_files.len(), if i % 2 == 0 { 2 } else { 0 });
+        }
+
+        assert_eq!(pool.high_water(), 1);
+        assert_eq!(pool.idle(), 1);
+    }
+
+    fn failure(directory: &str, error: &str, timestamp: u64) -> LoadFailure {
+        LoadFailure { directory: directory.into(), error: error.into(), timestamp }
+    }
+
+    #[test]
+    fn test_merge_load_failures() {
+        let previous = vec![failure("plugins/broken", "bad toml", 100), failure("plugins/fixed", "bad toml", 100)];
+
+        // still broken keeps its original timestamp, fixed directory is cleared
+        let merged = merge_load_failures(&previous, vec![failure("plugins/broken", "bad toml", 200)]);
+        assert_eq!(merged, vec![failure("plugins/broken", "bad toml", 100)]);
+
+        // a different error counts as a new failure
+        let merged = merge_load_failures(&previous, vec![failure("plugins/broken", "missing file", 300)]);
+        assert_eq!(merged, vec![failure("plugins/broken", "missing file", 300)]);
+
+        assert!(merge_load_failures(&previous, vec![]).is_empty());
+    }
+
+    #[test]
+    fn test_port_arg() {
+        let args = |args: &[&str]| args.iter().map(|arg| arg.to_string()).collect::<Vec<_>>();
+
+        let mut before = args(&["update-server", "--port", "46000", "downloads", "--incomplete"]);
+        assert_eq!(take_port_arg(&mut before).unwrap(), Some(46000));
+        assert_eq!(before, args(&["update-server", "downloads", "--incomplete"]));
+
+        let mut after = args(&["update-server", "self-check", "--port", "46000"]);
+        assert_eq!(take_port_arg(&mut after).unwrap(), Some(46000));
+        assert_eq!(after, args(&["update-server", "self-check"]));
+
+        let mut none = args(&["update-server", "validate"]);
+        assert_eq!(take_port_arg(&mut none).unwrap(), None);
+        assert_eq!(none, args(&["update-server", "validate"]));
+
+        assert!(take_port_arg(&mut args(&["update-server", "--port"])).is_err());
+        assert!(take_port_arg(&mut args(&["update-server", "--port", "not-a-port"])).is_err());
+    }
+
+    fn named_plugin(name: &str, version: &str, beta: bool, sizes: &[usize]) -> Plugin {
+        Plugin {
+            directory: Path::new("plugins").join(name),
+            name: name.into(),
+            plugin_version: version.parse().unwrap(),
+            channel: update_protocol::requested_channel(beta, None).into(),
+            ..plugin_with_sizes(sizes)
+        }
+    }
+
+    #[test]
+    fn test_download_port() {
+        let mut settings = test_settings();
+        assert_eq!(settings.download_port(), PORT_NUM + 1);
+        assert_eq!(settings.advertised_download_port(), None);
+
+        // clients are told about a download port which isn't the next one
+        settings.download_port = Some(47000);
+        assert_eq!(settings.download_port(), 47000);
+        assert_eq!(settings.advertised_download_port(), Some(47000));
+        settings.public_download_port = Some(62001);
+        assert_eq!(settings.advertised_download_port(), Some(62001));
+
+        let config = settings.effective_config();
+        assert_eq!(config.download_port, Some(47000));
+        assert_eq!(config.log_level.as_deref(), Some("info"));
+    }
+
+    #[test]
+    fn test_free_port_pair() {
+        let port = free_port_pair(IpAddr::V4(Ipv4Addr::UNSPECIFIED)).unwrap();
+        assert!(TcpListener::bind(("0.0.0.0", port)).is_ok());
+        assert!(TcpListener::bind(("0.0.0.0", port + 1)).is_ok());
+    }
+
+    #[test]
+    fn test_ping_server_version() {
+        let state = test_state(vec![named_plugin("a", "1.0.0", false, &[10])]);
+        let response = handle_request(&serde_json::to_string(&Request::Ping).unwrap(), &state, &test_settings());
+        let ping: PingResponse = serde_json::from_str(&response).unwrap();
+
+        assert_eq!(ping.plugin_count, 1);
+        assert_eq!(ping.server_version.as_deref(), Some(server_version()));
+        assert!(server_version().starts_with(env!("CARGO_PKG_VERSION")));
+    }
+
+    fn send_request(addr: std::net::SocketAddr, packet: &str) -> String {
+        let mut client = TcpStream::connect(addr).unwrap();
+        client.write_all(format!("{}\n", packet).as_bytes()).unwrap();
+        let mut response = String::new();
+        client.read_to_string(&mut response).unwrap();
+        response
+    }
+
+    /// Answer requests to `state` on a free port with request workers, as the server does. The
+    /// acceptor and workers never return, so everything they borrow has to outlive the test.
+    fn serve_in_background(state: &'static RwLock<ServerState>) -> std::net::SocketAddr {
+        let settings: &'static _ = Box::leak(Box::new(test_settings()));
+        let pool: &'static _ = Box::leak(Box::new(pool::BufferPool::new(4, pool::MAX_BUFFER_SIZE)));
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let (requests, request_queue) = crossbeam::channel::bounded(REQUEST_QUEUE_LEN);
+        for _ in 0..REQUEST_WORKERS {
+            let request_queue = request_queue.clone();
+            std::thread::spawn(move || run_request_worker(&request_queue, state, settings, pool));
+        }
+        std::thread::spawn(move || serve_requests(&listener, &requests));
+
+        addr
+    }
+
+    #[test]
+    fn test_stalled_client() {
+        let state: &'static _ = Box::leak(Box::new(RwLock::new(test_state(vec![named_plugin("test_plugin", "1.0.0", false, &[10])]))));
+        let addr = serve_in_background(state);
+
+        // connects and never finishes its request
+        let mut stalled = TcpStream::connect(addr).unwrap();
+        stalled.write_all(b"{\"Update\":").unwrap();
+
+        let start = std::time::Instant::now();
+        let response: UpdateResponse = serde_json::from_str(&send_request(addr, &update_request("0.9.0", Some(3)))).unwrap();
+        assert!(start.elapsed() < Duration::from_secs(1), "request took {:?}", start.elapsed());
+        assert_eq!(response.code, ResponseCode::Update);
+
+        // the stalled client is given up on rather than waited on forever
+        let mut response = String::new();
+        stalled.set_read_timeout(Some(REQUEST_TIMEOUT * 2)).unwrap();
+        stalled.read_to_string(&mut response).unwrap();
+        assert_eq!(serde_json::from_str::<UpdateResponse>(&response).unwrap().code, ResponseCode::InvalidRequest);
+    }
+
+    fn serve_downloads_in_background(state: &'static RwLock<ServerState>) -> std::net::SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (downloads, download_queue) = crossbeam::channel::bounded(4);
+        for _ in 0..2 {
+            let download_queue = download_queue.clone();
+            std::thread::spawn(move || run_download_worker(&download_queue, &tls::Acceptor::default(), state));
+        }
+        std::thread::spawn(move || serve_downloads(&listener, &downloads));
+
+        addr
+    }
+
+    #[test]
+    fn test_stalled_download() {
+        let state: &'static _ = Box::leak(Box::new(RwLock::new(test_state(vec![plugin_with_sizes(&[10])]))));
+        let addr = serve_downloads_in_background(state);
+
+        // connects and never finishes its index
+        let mut stalled = TcpStream::connect(addr).unwrap();
+        stalled.write_all(&[0; 4]).unwrap();
+
+        let start = std::time::Instant::now();
+        let mut client = TcpStream::connect(addr).unwrap();
+        client.set_read_timeout(Some(Duration::from_secs(2))).unwrap();
+        client.write_all(&0u64.to_be_bytes()).unwrap();
+        let mut data = vec![];
+        client.read_to_end(&mut data).unwrap();
+        assert!(start.elapsed() < Duration::from_secs(1), "download took {:?}", start.elapsed());
+        assert_eq!(data, vec![0x55; 10]);
+
+        // the stalled client is given up on rather than waited on forever
+        let mut rest = vec![];
+        stalled.set_read_timeout(Some(REQUEST_TIMEOUT * 2)).unwrap();
+        stalled.read_to_end(&mut rest).unwrap();
+        assert!(rest.is_empty());
+    }
+
+    #[test]
+    fn test_missing_metadata_answered() {
+        let state: &'static _ = Box::leak(Box::new(RwLock::new(test_state(vec![named_plugin("test_plugin", "1.0.0", false, &[10])]))));
+        let addr = serve_in_background(state);
+
+        let mut client = TcpStream::connect(addr).unwrap();
+        client.set_read_timeout(Some(Duration::from_secs(2))).unwrap();
+        let request = serde_json::to_string(&Request::Metadata { plugin_name: "missing_plugin".into(), beta: None }).unwrap();
+        client.write_all(format!("{}\n", request).as_bytes()).unwrap();
+
+        let start = std::time::Instant::now();
+        let mut response = String::new();
+        client.read_to_string(&mut response).unwrap();
+        assert!(start.elapsed() < Duration::from_secs(1), "response took {:?}", start.elapsed());
+        let response: UpdateResponse = serde_json::from_str(&response).unwrap();
+        assert_eq!(response.code, ResponseCode::PluginNotFound);
+        assert_eq!(response.message.as_deref(), Some("plugin 'missing_plugin' isn't hosted"));
+    }
+
+    #[test]
+    fn test_failed_reload_reported() {
+        let state = RwLock::new(test_state(vec![named_plugin("test_plugin", "1.0.0", false, &[10])]));
+        assert!(reload_plugins_with(&state, &test_settings(), None, |_| eyre::bail!("plugins: permission denied")).is_err());
+
+        let mut options = update_protocol::UpdateRequestOptions::default();
+        options.protocol_version = Some(9);
+        let request = serde_json::to_string(&Request::Update {
+            plugin_name: "missing_plugin".into(),
+            plugin_version: "1.0.0".into(),
+            beta: None,
+            options: Some(options),
+        }).unwrap();
+
+        // the plugins from before the reload are still served
+        let response: UpdateResponse = serde_json::from_str(&handle_request(&update_request("0.9.0", Some(9)), &read_state(&state), &test_settings())).unwrap();
+        assert_eq!(response.code, ResponseCode::Update);
+        let response: UpdateResponse = serde_json::from_str(&handle_request(&request, &read_state(&state), &test_settings())).unwrap();
+        assert_eq!(response.code, ResponseCode::ServerError);
+        assert_eq!(response.message.as_deref(), Some("the plugin isn't served and the last reload failed: plugins: permission denied"));
+
+        reload_plugins_with(&state, &test_settings(), None, |_| Ok((vec![], vec![]))).unwrap();
+        assert_eq!(read_state(&state).reload_error, None);
+    }
+
+    #[test]
+    fn test_requests_answered_during_reload() {
+        let state: &'static _ = Box::leak(Box::new(RwLock::new(test_state(vec![named_plugin("test_plugin", "1.0.0", false, &[10])]))));
+        let addr = serve_in_background(state);
+
+        let (loading, load_started) = channel();
+        let reload = std::thread::spawn(move || {
+            reload_plugins_with(state, &test_settings(), None, |_| {
+                loading.send(()).unwrap();
+                std::thread::sleep(Duration::from_secs(2));
+                Ok((vec![named_plugin("test_plugin", "2.0.0", false, &[10, 20])], vec![]))
+            })
+        });
+        load_started.recv().unwrap();
+
+        let ping = serde_json::to_string(&Request::Ping).unwrap();
+        for _ in 0..20 {
+            let start = std::time::Instant::now();
+            let response: UpdateResponse = serde_json::from_str(&send_request(addr, &update_request("0.9.0", Some(3)))).unwrap();
+            assert!(start.elapsed() < Duration::from_millis(250), "request took {:?}", start.elapsed());
+            assert_eq!(response.new_plugin_version, "1.0.0");
+            assert_eq!(response.required_files.len(), 1);
+        }
+        let status: PingResponse = serde_json::from_str(&send_request(addr, &ping)).unwrap();
+        assert!(status.reload_in_progress);
+
+        reload.join().unwrap().unwrap();
+        let response: UpdateResponse = serde_json::from_str(&send_request(addr, &update_request("0.9.0", Some(3)))).unwrap();
+        assert_eq!(response.new_plugin_version, "2.0.0");
+        let status: PingResponse = serde_json::from_str(&send_request(addr, &ping)).unwrap();
+        assert!(!status.reload_in_progress);
+    }
+
+    #[test]
+    fn test_keep_alive_downloads() {
+        let state: &'static _ = Box::leak(Box::new(RwLock::new(test_state(vec![plugin_with_sizes(&[10, 20, 30])]))));
+        let addr = serve_downloads_in_background(state);
+
+        // every index is sent up front, each file arrives prefixed with its length
+        let mut client = TcpStream::connect(addr).unwrap();
+        client.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        for index in [update_protocol::KEEP_ALIVE_INDEX, 0, 1, 2].iter() {
+            client.write_all(&index.to_be_bytes()).unwrap();
+        }
+        for &size in [10, 20, 30].iter() {
+            let mut len = [0; 8];
+            client.read_exact(&mut len).unwrap();
+            assert_eq!(u64::from_be_bytes(len), size as u64);
+            let mut data = vec![0; size];
+            client.read_exact(&mut data).unwrap();
+            assert_eq!(data, vec![0x55; size]);
+        }
+
+        // the connection is closed after a file that doesn't exist
+        client.write_all(&3u64.to_be_bytes()).unwrap();
+        let mut rest = vec![];
+        client.read_to_end(&mut rest).unwrap();
+        assert_eq!(rest, update_protocol::MISSING_FILE_LEN.to_be_bytes());
+
+        // one file per connection, without a length, as before
+        let mut client = TcpStream::connect(addr).unwrap();
+        client.write_all(&1u64.to_be_bytes()).unwrap();
+        let mut data = vec![];
+        client.read_to_end(&mut data).unwrap();
+        assert_eq!(data, vec![0x55; 20]);
+
+        let stats = read_state(state).downloads.snapshot(false);
+        assert_eq!(stats.iter().map(|entry| entry.completed).sum::<u64>(), 4);
+    }
+
+    #[test]
+    fn test_reload_while_downloading() {
+        const SIZE: usize = 8 * 1024 * 1024;
+        let state: &'static _ = Box::leak(Box::new(RwLock::new(test_state(vec![plugin_with_sizes(&[SIZE])]))));
+        let addr = serve_downloads_in_background(state);
+
+        let mut client = TcpStream::connect(addr).unwrap();
+        client.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        client.write_all(&0u64.to_be_bytes()).unwrap();
+        let mut data = vec![0; 1024];
+        client.read_exact(&mut data).unwrap();
+
+        // swap in a new version of the file partway through, as a reload does
+        {
+            let mut state = state.write().unwrap();
+            state.plugins[0].files[0].data = served::ServedFile::from(vec![0xaa; 10]);
+        }
+
+        // the download in flight finishes with the file it started with
+        client.read_to_end(&mut data).unwrap();
+        assert_eq!(data.len(), SIZE);
+        assert!(data.iter().all(|&byte| byte == 0x55));
+
+        // and the next one gets the new file
+        let mut client = TcpStream::connect(addr).unwrap();
+        client.write_all(&0u64.to_be_bytes()).unwrap();
+        let mut data = vec![];
+        client.read_to_end(&mut data).unwrap();
+        assert_eq!(data, vec![0xaa; 10]);
+    }
+
+    /// Run `f` on a new thread, returning the `/proc` status file of the thread
+    #[cfg(target_os = "linux")]
+    fn spawn_traced(f: impl FnOnce() + Send + 'static) -> PathBuf {
+        let (thread, path) = crossbeam::channel::bounded(1);
+        std::thread::spawn(move || {
+            // links to `<pid>/task/<tid>`
+            thread.send(fs::read_link("/proc/thread-self").unwrap()).unwrap();
+            f()
+        });
+
+        Path::new("/proc").join(path.recv().unwrap()).join("status")
+    }
+
+    /// How many times the thread with the `/proc` status file `status` was switched to so far
+    #[cfg(target_os = "linux")]
+    fn context_switches(status: &Path) -> u64 {
+        fs::read_to_string(status).unwrap()
+            .lines()
+            .filter(|line| line.contains("ctxt_switches:"))
+            .filter_map(|line| line.split(':').nth(1)?.trim().parse::<u64>().ok())
+            .sum()
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn test_idle_without_polling() {
+        let state: &'static _ = Box::leak(Box::new(RwLock::new(test_state(vec![plugin_with_sizes(&[10])]))));
+        let settings: &'static _ = Box::leak(Box::new(test_settings()));
+        let pool: &'static _ = Box::leak(Box::new(pool::BufferPool::new(4, pool::MAX_BUFFER_SIZE)));
+        let request_listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let download_listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let (requests, request_queue) = crossbeam::channel::bounded(REQUEST_QUEUE_LEN);
+        let (downloads, download_queue) = crossbeam::channel::bounded(downloads::DOWNLOAD_QUEUE_LEN);
+
+        let threads = [
+            spawn_traced(move || drop(serve_requests(&request_listener, &requests))),
+            spawn_traced(move || run_request_worker(&request_queue, state, settings, pool)),
+            spawn_traced(move || drop(serve_downloads(&download_listener, &downloads))),
+            spawn_traced(move || run_download_worker(&download_queue, &tls::Acceptor::default(), state)),
+        ];
+        std::thread::sleep(Duration::from_millis(100));
+        let before: Vec<_> = threads.iter().map(|status| context_switches(status)).collect();
+
+        // every thread sleeps in accept or recv until there is work, where polling every
+        // 10ms would have woken each of them about 50 times
+        std::thread::sleep(Duration::from_millis(500));
+        for (status, before) in threads.iter().zip(before) {
+            let woken = context_switches(status) - before;
+            assert!(woken < 5, "{} woke up {} times while idle", status.display(), woken);
+        }
+    }
+
+    #[test]
+    fn test_coalesce_reloads() {
+        let state = RwLock::new(test_state(vec![named_plugin("test_plugin", "1.0.0", false, &[10])]));
+        let (tx, rx) = channel();
+        let file = |name: &str| Path::new("plugins/test_plugin").join(name);
+
+        // the start of copying 2.0.0 over 1.0.0, queued before the first reload begins
+        tx.send(DebouncedEvent::Create(file("plugin.toml"))).unwrap();
+        tx.send(DebouncedEvent::Write(file("plugin.toml"))).unwrap();
+        tx.send(DebouncedEvent::Remove(file("old.nro"))).unwrap();
+        tx.send(DebouncedEvent::Write(file("romfs.tar"))).unwrap();
+        tx.send(DebouncedEvent::Create(file("plugin.nro"))).unwrap();
+
+        // the rest of the copy lands while the first reload reads the half-copied folder, and
+        // the second reload only writes the server's own archive
+        let mut copies = vec![
+            ("2.0.0-partial", vec![
+                DebouncedEvent::Write(file("plugin.nro")),
+                DebouncedEvent::Write(file("plugin.nro")),
+                DebouncedEvent::Create(file("readme.txt")),
+            ]),
+            ("2.0.0", vec![DebouncedEvent::Write(file("romfs.tar"))]),
+        ];
+        let mut tx = Some(tx);
+        let mut loaded = vec![];
+        let watched = [PathBuf::from("plugins")];
+        coalesce_reloads(&rx, &watched, Path::new("plugins"), |folders| reload_plugins_with(&state, &test_settings(), folders, |_| {
+            assert_eq!(folders, Some(&[PathBuf::from("plugins/test_plugin")][..]));
+            let (version, events) = copies.remove(0);
+            loaded.push(version);
+            for event in events {
+                tx.as_ref().unwrap().send(event).unwrap();
+            }
+            // nothing is left to copy, stop watching
+            if copies.is_empty() {
+                tx = None;
+            }
+            Ok((vec![named_plugin("test_plugin", version, false, &[10])], vec![]))
+        })).unwrap();
+
+        // one reload for the queued events and one for all those during it
+        assert_eq!(loaded, vec!["2.0.0-partial", "2.0.0"]);
+        let state = read_state(&state);
+        assert_eq!(state.plugins[0].plugin_version.to_string(), "2.0.0");
+        assert_eq!(state.reload_history.len(), 2);
+    }
+
+    #[test]
+    fn test_changed_folders() {
+        let watched = [PathBuf::from("/srv/plugins")];
+        let mut changed = ChangedFolders::new(&watched, Path::new("plugins"));
+
+        changed.add(&DebouncedEvent::Write("/srv/plugins/a/plugin.toml".into()));
+        changed.add(&DebouncedEvent::Create("/srv/plugins/a/romfs/fighter/param.prc".into()));
+        changed.add(&DebouncedEvent::Rename("/srv/plugins/b".into(), "/srv/plugins/c".into()));
+        // archives aren't changes of their own
+        changed.add(&DebouncedEvent::Write("/srv/plugins/d/romfs.tar".into()));
+        assert_eq!(changed.take(), Some(vec![PathBuf::from("plugins/a"), PathBuf::from("plugins/b"), PathBuf::from("plugins/c")]));
+        assert!(changed.is_empty());
+
+        // anything which isn't inside a plugin folder reloads them all
+        changed.add(&DebouncedEvent::Write("/srv/plugins/a/plugin.toml".into()));
+        changed.add(&DebouncedEvent::Chmod("/srv/plugins".into()));
+        assert_eq!(changed.take(), None);
+        changed.add(&DebouncedEvent::Rescan);
+        assert_eq!(changed.take(), None);
+    }
+
+    #[test]
+    fn test_reload_one_folder() {
+        let mut a = named_plugin("a", "1.0.0", false, &[10]);
+        let mut b = named_plugin("b", "1.0.0", false, &[10, 20]);
+        a.files[0].index = 0;
+        b.files.iter_mut().zip(INDEXES_PER_FOLDER..).for_each(|(file, index)| file.index = index);
+        let mut state = test_state(vec![a, b]);
+        state.index_blocks = vec![PathBuf::from("plugins/a"), PathBuf::from("plugins/b")];
+        state.load_failures = vec![
+            LoadFailure { directory: "plugins/b".into(), error: "old".into(), timestamp: 1 },
+            LoadFailure { directory: "plugins/c".into(), error: "bad toml".into(), timestamp: 2 },
+        ];
+        let state = RwLock::new(state);
+
+        let folders = [PathBuf::from("plugins/b")];
+        reload_plugins_with(&state, &test_settings(), Some(&folders), |index_blocks| {
+            // b keeps its block
+            assert_eq!(index_blocks.iter().position(|folder| folder == Path::new("plugins/b")), Some(1));
+            Ok((vec![named_plugin("b", "2.0.0", false, &[10])], vec![]))
+        }).unwrap();
+
+        let state = read_state(&state);
+        let versions: Vec<_> = state.plugins.iter().map(|plugin| format!("{} {}", plugin.name, plugin.plugin_version)).collect();
+        assert_eq!(versions, vec!["a 1.0.0", "b 2.0.0"]);
+        // a is served from the same index, the failures of other folders are kept
+        assert!(handle_download(0u64.to_be_bytes(), &state).is_some());
+        assert_eq!(download_label(0, &state), ("a".to_owned(), "sd:/file0".to_owned()));
+        assert_eq!(state.load_failures.len(), 1);
+        assert_eq!((state.load_failures[0].directory.as_str(), state.load_failures[0].timestamp), ("plugins/c", 2));
+        assert_eq!(state.reload_history.back().unwrap().version_changed.len(), 1);
+    }
+
+    #[test]
+    fn test_index_blocks() {
+        let plugin = |directory: &str| hosted_plugins::Plugin {
+            directory: directory.into(),
+            name: "test".into(),
+            display_name: None,
+            plugin_version: "1.0.0".parse().unwrap(),
+            files: vec![hosted_plugins::HostedFile {
+                install: InstallLocation::AbsolutePath("sd:/file".into()),
+                data: served::ServedFile::from(vec![1]),
+                sha256: manifest::sha256(&[1]),
+                platform: None,
+            }],
+            skyline_version: None,
+            channel: STABLE_CHANNEL.into(),
+            metadata: Default::default(),
+            notify_only: false,
+            version_ordering: Default::default(),
+            prerelease_on_stable: false,
+            rollback: false,
+            deleted_files: vec![],
+        };
+
+        let mut index_blocks = vec![PathBuf::from("plugins/a")];
+        let b = serve_plugin(plugin("plugins/b"), &mut index_blocks).unwrap();
+        assert_eq!(b.files[0].index, stable_index("test", &InstallLocation::AbsolutePath("sd:/file".into()), &manifest::sha256(&[1])));
+        assert_eq!(b.metadata.changelog_index, INDEXES_PER_FOLDER);
+        let a = serve_plugin(plugin("plugins/a"), &mut index_blocks).unwrap();
+        assert_eq!(a.metadata.changelog_index, 0);
+        assert_eq!(index_blocks.len(), 2);
+
+        let state = test_state(vec![a, b]);
+        assert_eq!(download_label(INDEXES_PER_FOLDER, &state).1, "changelog");
+        assert!(handle_download((INDEXES_PER_FOLDER + 1).to_be_bytes(), &state).is_none());
+    }
+
+    #[test]
+    fn test_stable_indexes() {
+        let hosted = |files: &[(&str, &str)]| hosted_plugins::Plugin {
+            directory: "plugins/test_plugin".into(),
+            name: "test_plugin".into(),
+            display_name: None,
+            plugin_version: "1.0.0".parse().unwrap(),
+            files: files.iter()
+                .map(|(location, data)| hosted_plugins::HostedFile {
+                    install: InstallLocation::AbsolutePath((*location).into()),
+                    data: served::ServedFile::from(data.as_bytes().to_vec()),
+                    sha256: manifest::sha256(data.as_bytes()),
+                    platform: None,
+                })
+                .collect(),
+            skyline_version: None,
+            channel: STABLE_CHANNEL.into(),
+            metadata: Default::default(),
+            notify_only: false,
+            version_ordering: Default::default(),
+            prerelease_on_stable: false,
+            rollback: false,
+            deleted_files: vec![],
+        };
+        let mut index_blocks = vec![];
+        let plugin = serve_plugin(hosted(&[("sd:/a.txt", "aaa"), ("sd:/b.txt", "bbb")]), &mut index_blocks).unwrap();
+        let state: &'static _ = Box::leak(Box::new(RwLock::new(test_state(vec![plugin]))));
+        write_state(state).index_blocks = index_blocks;
+
+        let response: UpdateResponse = serde_json::from_str(&handle_request(&update_request("0.9.0", None), &read_state(state), &test_settings())).unwrap();
+        let index_of = |location: &str| response.required_files.iter()
+            .find(|file| file.install_location == InstallLocation::AbsolutePath(location.into()))
+            .unwrap()
+            .download_index;
+
+        // a reload between the handshake and the download changes a.txt and adds a file before b.txt
+        reload_plugins_with(state, &test_settings(), None, |index_blocks| {
+            let plugin = hosted(&[("sd:/new.txt", "new"), ("sd:/b.txt", "bbb"), ("sd:/a.txt", "AAAA")]);
+            Ok((vec![serve_plugin(plugin, index_blocks)?], vec![]))
+        }).unwrap();
+
+        let addr = serve_downloads_in_background(state);
+
+        // the unchanged file is still served at the index the client was given
+        let mut client = TcpStream::connect(addr).unwrap();
+        client.write_all(&index_of("sd:/b.txt").to_be_bytes()).unwrap();
+        let mut data = vec![];
+        client.read_to_end(&mut data).unwrap();
+        assert_eq!(data, b"bbb");
+
+        // the changed one is reported missing rather than served with its new content
+        let mut client = TcpStream::connect(addr).unwrap();
+        client.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        for index in [update_protocol::KEEP_ALIVE_INDEX, index_of("sd:/b.txt"), index_of("sd:/a.txt")].iter() {
+            client.write_all(&index.to_be_bytes()).unwrap();
+        }
+        let mut data = vec![];
+        client.read_to_end(&mut data).unwrap();
+        let mut expected = 3u64.to_be_bytes().to_vec();
+        expected.extend_from_slice(b"bbb");
+        expected.extend_from_slice(&update_protocol::MISSING_FILE_LEN.to_be_bytes());
+        assert_eq!(data, expected);
+    }
+
+    #[test]
+    fn test_metadata_downloads() {
+        let dir = std::env::temp_dir().join(format!("update-server-metadata-{}", std::process::id())).join("metadata_plugin");
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("plugin.nro"), "plugin").unwrap();
+        fs::write(dir.join("icon.png"), vec![1; 100]).unwrap();
+        fs::write(dir.join("screenshot.png"), vec![2; 200]).unwrap();
+        fs::write(dir.join("changelog.md"), "* fixed everything").unwrap();
+        fs::write(dir.join("plugin.toml"), format!(r#"
+            version = "1.0.0"
+            name = "metadata_plugin"
+            files = [{{ install_location = "sd:/plugin.nro", filename = "plugin.nro" }}]
+            [metadata]
+            images = [{:?}, {:?}]
+            changelog = {:?}
+        "#, dir.join("icon.png"), dir.join("screenshot.png"), dir.join("changelog.md"))).unwrap();
+
+        // another plugin is loaded first, so the metadata isn't numbered from 0
+        let load = || hosted_plugins::load_folder(&dir, &hosted_plugins::Limits::from_env()).unwrap().unwrap();
+        let mut index_blocks = vec![];
+        let other = serve_plugin(hosted_plugins::Plugin { directory: "plugins/other".into(), name: "other_plugin".into(), ..load() }, &mut index_blocks).unwrap();
+        let plugin = serve_plugin(load(), &mut index_blocks).unwrap();
+        let state: &'static _ = Box::leak(Box::new(RwLock::new(test_state(vec![other, plugin]))));
+        let addr = serve_in_background(state);
+        let download_addr = serve_downloads_in_background(state);
+
+        let request = serde_json::to_string(&Request::Metadata { plugin_name: "metadata_plugin".into(), beta: None }).unwrap();
+        let metadata: PluginMetadata = serde_json::from_str(&send_request(addr, &request)).unwrap();
+        assert_eq!(metadata.image_count, 2);
+        let download = |index: u64| {
+            let mut client = TcpStream::connect(download_addr).unwrap();
+            client.write_all(&index.to_be_bytes()).unwrap();
+            let mut data = vec![];
+            client.read_to_end(&mut data).unwrap();
+            data
+        };
+
+        // each advertised index serves the file on disk it stands for
+        assert_eq!(download(metadata.images_index), fs::read(dir.join("icon.png")).unwrap());
+        assert_eq!(download(metadata.images_index + 1), fs::read(dir.join("screenshot.png")).unwrap());
+        assert_eq!(download(metadata.changelog_index), fs::read(dir.join("changelog.md")).unwrap());
+
+        let _ = fs::remove_dir_all(dir.parent().unwrap());
+    }
+
+    #[test]
+    fn test_failed_reload_keeps_plugins() {
+        let state = RwLock::new(test_state(vec![named_plugin("test_plugin", "1.0.0", false, &[10])]));
+
+        assert!(reload_plugins_with(&state, &test_settings(), None, |_| eyre::bail!("deliberate failure")).is_err());
+        let state = read_state(&state);
+        assert_eq!(state.plugins[0].plugin_version.to_string(), "1.0.0");
+        assert!(!state.reload_in_progress.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn test_reload_summary() {
+        let first = vec![named_plugin("a", "1.0.0", false, &[10, 20])];
+        let summary = summarize_reload(&[], &first);
+        assert_eq!(summary.added, vec!["a"]);
+        assert_eq!((summary.plugin_count, summary.file_count, summary.total_bytes), (1, 2, 30));
+
+        let second = vec![
+            named_plugin("a", "1.1.0", false, &[10]),
+            named_plugin("a", "1.2.0", true, &[10]),
+            named_plugin("b", "1.0.0", false, &[5]),
+        ];
+        let summary = summarize_reload(&plugin_versions(&first), &second);
+        assert_eq!(summary.added, vec!["a (beta)", "b"]);
+        assert!(summary.removed.is_empty());
+        assert_eq!(summary.version_changed, vec![VersionChange {
+            plugin: "a".into(),
+            old_version: "1.0.0".into(),
+            new_version: "1.1.0".into(),
+        }]);
+
+        let summary = summarize_reload(&plugin_versions(&second), &second[2..]);
+        assert_eq!(summary.removed, vec!["a", "a (beta)"]);
+        assert!(summary.added.is_empty() && summary.version_changed.is_empty());
+    }
+
+    #[test]
+    fn test_inline_mixed() {
+        let plugin = plugin_with_sizes(&[100, 64 * 1024, 2048]);
+        let config = InlineConfig { threshold: INLINE_THRESHOLD, max_total: INLINE_MAX_TOTAL };
+        let files = required_files(&[&plugin], Some(config), true, update_protocol::DEFAULT_PLATFORM);
+
+        assert_eq!(base64::decode(files[0].inline_data.as_ref().unwrap()).unwrap(), vec![0x55; 100]);
+        assert_eq!(files[0].sha256, Some(manifest::sha256(&[0x55; 100])));
+        assert!(files[1].inline_data.is_none());
+        assert!(files[2].inline_data.is_some());
+        assert!(files.iter().enumerate().all(|(i, file)| file.download_index == i as u64));
+    }
+
+    #[test]
+    fn test_inline_budget() {
+        let plugin = plugin_with_sizes(&[3000, 3000, 3000]);
+        let config = InlineConfig { threshold: INLINE_THRESHOLD, max_total: 8192 };
+        let files = required_files(&[&plugin], Some(config), true, update_protocol::DEFAULT_PLATFORM);
+
+        assert_eq!(files.iter().filter(|file| file.inline_data.is_some()).count(), 2);
+    }
+
+    #[test]
+    fn test_inline_old_client() {
+        let plugin = plugin_with_sizes(&[100, 200]);
+
+        assert!(required_files(&[&plugin], None, false, update_protocol::DEFAULT_PLATFORM).iter().all(|file| file.inline_data.is_none() && file.sha256.is_none()));
+    }
+
+    fn update_request(version: &str, protocol_version: Option<u32>) -> String {
+        let mut options = update_protocol::UpdateRequestOptions::default();
+        options.protocol_version = protocol_version;
+
+        serde_json::to_string(&Request::Update {
+            plugin_name: "test_plugin".into(),
+            plugin_version: version.into(),
+            beta: None,
+            options: Some(options),
+        }).unwrap()
+    }
+
+    #[test]
+    fn test_missing_plugin_responses() {
+        let mut state = test_state(vec![]);
+        let respond = |state: &ServerState, protocol_version| {
+            let response = handle_request(&update_request("1.0.0", Some(protocol_version)), state, &test_settings());
+            serde_json::from_str::<UpdateResponse>(&response).unwrap()
+        };
+        assert_eq!(respond(&state, 9).code, ResponseCode::PluginNotFound);
+
+        state.load_failures.push(failure("plugins/test_plugin", "bad toml", 0));
+        let response = respond(&state, 9);
+        assert_eq!(response.code, ResponseCode::ServerError);
+        assert!(response.message.unwrap().contains("1 plugin(s) failed to load"));
+
+        state.reload_in_progress.store(true, Ordering::SeqCst);
+        let response = respond(&state, 9);
+        assert_eq!(response.code, ResponseCode::RateLimited);
+        assert_eq!(response.retry_after, Some(RELOAD_RETRY_AFTER));
+
+        // older clients don't know the codes
+        assert_eq!(respond(&state, 8).code, ResponseCode::PluginNotFound);
+    }
+
+    #[test]
+    fn test_rejection_messages() {
+        let state = test_state(vec![named_plugin("test_plugin", "1.0.0", false, &[10])]);
+        let respond = |packet: &str| {
+            let response = handle_request(packet, &state, &test_settings());
+            serde_json::from_str::<UpdateResponse>(&response).unwrap()
+        };
+
+        let response = respond(&update_request("1.2", Some(3)));
+        assert_eq!(response.code, ResponseCode::InvalidRequest);
+        assert_eq!(response.message.as_deref(), Some("version '1.2' is not valid semver"));
+
+        let response = respond("not json");
+        assert_eq!(response.code, ResponseCode::InvalidRequest);
+        assert!(response.message.unwrap().starts_with("the request could not be parsed: "));
+
+        let request = |name: &str| serde_json::to_string(&Request::Update {
+            plugin_name: name.into(),
+            plugin_version: "1.0.0".into(),
+            beta: None,
+            options: None,
+        }).unwrap();
+        let response = respond(&request("Test_Plugn"));
+        assert_eq!(response.code, ResponseCode::PluginNotFound);
+        assert_eq!(response.message.as_deref(), Some("plugin 'Test_Plugn' isn't hosted, did you mean 'test_plugin'?"));
+        assert_eq!(respond(&request("unrelated")).message.as_deref(), Some("plugin 'unrelated' isn't hosted"));
+    }
+
+    #[test]
+    fn test_not_found_message() {
+        let plugins = vec![named_plugin("test_plugin", "1.0.0", false, &[10])];
+
+        assert_eq!(not_found_message(&plugins, "test_plugin", "nightly", None), "'test_plugin' has no version on the nightly channel");
+        let version = "2.0.0".parse().unwrap();
+        assert_eq!(
+            not_found_message(&plugins, "test_plugin", STABLE_CHANNEL, Some(&version)),
+            format!("version 2.0.0 of 'test_plugin' isn't hosted on the {} channel", STABLE_CHANNEL)
+        );
+        assert_eq!(similar_plugin_name(&plugins, "TEST_PLUGIN"), Some("test_plugin"));
+        assert_eq!(similar_plugin_name(&plugins, "test"), None);
+        assert_eq!(edit_distance("kitten", "sitting"), 3);
+        assert_eq!(edit_distance("", "abc"), 3);
+    }
+
+    #[test]
+    fn test_signed_response() {
+        let state = test_state(vec![plugin_with_sizes(&[10, 20])]);
+        let mut settings = test_settings();
+        settings.signing_key = Some(signing::SigningKey::from_secret(&[7; 32]).unwrap());
+
+        let response = handle_request(&update_request("0.9.0", Some(3)), &state, &settings);
+        let response: UpdateResponse = serde_json::from_str(&response).unwrap();
+        let key = settings.signing_key.as_ref().unwrap();
+        for file in &response.required_files {
+            assert_eq!(file.signature.as_ref(), Some(&key.sign(file.sha256.as_ref().unwrap())));
+        }
+        let response = handle_request(&update_request("0.9.0", Some(update_protocol::PROTOCOL_VERSION)), &state, &settings);
+        let response: UpdateResponse = serde_json::from_str(&response).unwrap();
+        for file in &response.required_files {
+            assert_eq!(file.signature.as_ref(), Some(&key.sign(&response.signed_message(file).unwrap())));
+        }
+
+        // without a key, or for clients which aren't sent hashes, nothing is signed
+        let response = handle_request(&update_request("0.9.0", Some(3)), &state, &test_settings());
+        let response: UpdateResponse = serde_json::from_str(&response).unwrap();
+        assert!(response.required_files.iter().all(|file| file.signature.is_none()));
+        let response = handle_request(&update_request("0.9.0", Some(1)), &state, &settings);
+        let response: UpdateResponse = serde_json::from_str(&response).unwrap();
+        assert!(response.required_files.iter().all(|file| file.signature.is_none()));
+    }
+
+    #[test]
+    fn test_update_changelog() {
+        let mut plugin = plugin_with_sizes(&[10]);
+        plugin.changelog = Some("Fixed everything".into());
+        let state = test_state(vec![plugin]);
+
+        let response = handle_request(&update_request("0.9.0", Some(3)), &state, &test_settings());
+        let response: UpdateResponse = serde_json::from_str(&response).unwrap();
+        assert_eq!(response.code, ResponseCode::Update);
+        assert_eq!(response.changelog.as_deref(), Some("Fixed everything"));
+
+        // clients from before the field was added don't get it
+        let response = handle_request(&update_request("0.9.0", Some(2)), &state, &test_settings());
+        let response: UpdateResponse = serde_json::from_str(&response).unwrap();
+        assert_eq!(response.code, ResponseCode::Update);
+        assert_eq!(response.changelog, None);
+    }
+
+    #[test]
+    fn test_notify_only() {
+        let mut plugin = plugin_with_sizes(&[10]);
+        plugin.notify_only = true;
+        plugin.changelog = Some("Fixed everything".into());
+        plugin.metadata.homepage = Some("https://example.com/test_plugin".into());
+        let state = test_state(vec![plugin]);
+
+        let response = handle_request(&update_request("0.9.0", Some(3)), &state, &test_settings());
+        let response: UpdateResponse = serde_json::from_str(&response).unwrap();
+        assert_eq!(response.code, ResponseCode::NotifyOnly);
+        assert_eq!(response.new_plugin_version, "1.0.0");
+        assert_eq!(response.changelog.as_deref(), Some("Fixed everything"));
+        assert_eq!(response.homepage.as_deref(), Some("https://example.com/test_plugin"));
+        assert!(response.required_files.is_empty());
+
+        // up to date, or too old to understand NotifyOnly
+        for request in &[update_request("1.0.0", Some(3)), update_request("0.9.0", Some(2)), update_request("0.9.0", None)] {
+            let response = handle_request(request, &state, &test_settings());
+            assert_eq!(serde_json::from_str::<UpdateResponse>(&response).unwrap(), UpdateResponse::no_update());
+        }
+    }
+
+    #[test]
+    fn test_relative_files() {
+        let mut plugin = plugin_with_sizes(&[10, 10]);
+        plugin.files[1].install = InstallLocation::Relative("romfs/file1".into());
+        let state = test_state(vec![plugin]);
+
+        let response = handle_request(&update_request("0.9.0", Some(4)), &state, &test_settings());
+        let response: UpdateResponse = serde_json::from_str(&response).unwrap();
+        assert_eq!(response.code, ResponseCode::Update);
+        assert_eq!(response.required_files[1].install_location, InstallLocation::Relative("romfs/file1".into()));
+
+        // too old to resolve them
+        for request in &[update_request("0.9.0", Some(3)), update_request("0.9.0", None)] {
+            let response = handle_request(request, &state, &test_settings());
+            assert_eq!(serde_json::from_str::<UpdateResponse>(&response).unwrap(), UpdateResponse::no_update());
+        }
+    }
+
+    fn platform_request(platform: Option<&str>) -> String {
+        let mut options = update_protocol::UpdateRequestOptions::default();
+        options.protocol_version = Some(6);
+        options.platform = platform.map(String::from);
+
+        serde_json::to_string(&Request::Update {
+            plugin_name: "test_plugin".into(),
+            plugin_version: "0.9.0".into(),
+            beta: None,
+            options: Some(options),
+        }).unwrap()
+    }
+
+    #[test]
+    fn test_platform_files() {
+        let mut plugin = plugin_with_sizes(&[10, 10, 10, 10, 10]);
+        let marked = [None, Some(vec!["switch"]), Some(vec!["emulator"]), Some(vec!["switch", "emulator"]), Some(vec!["pc"])];
+        for (file, platforms) in plugin.files.iter_mut().zip(&marked) {
+            file.platform = platforms.as_ref().map(|platforms| platforms.iter().map(|&platform| platform.to_owned()).collect());
+        }
+        let state = test_state(vec![plugin]);
+
+        let sent = |platform: Option<&str>| -> Vec<u64> {
+            let response = handle_request(&platform_request(platform), &state, &test_settings());
+            serde_json::from_str::<UpdateResponse>(&response).unwrap()
+                .required_files.iter()
+                .map(|file| file.download_index)
+                .collect()
+        };
+
+        assert_eq!(sent(Some("switch")), vec![0, 1, 3]);
+        assert_eq!(sent(Some("emulator")), vec![0, 2, 3]);
+        assert_eq!(sent(Some("pc")), vec![0, 4]);
+        // unknown platforms only get unmarked files, and clients which don't say are consoles
+        assert_eq!(sent(Some("wii u")), vec![0]);
+        assert_eq!(sent(None), vec![0, 1, 3]);
+    }
+
+    #[test]
+    fn test_display_name() {
+        let mut plugin = plugin_with_sizes(&[10]);
+        plugin.metadata.display_name = Some("Test Plugin".into());
+        let state = test_state(vec![plugin]);
+
+        let response = handle_request(&update_request("0.9.0", Some(3)), &state, &test_settings());
+        let response: UpdateResponse = serde_json::from_str(&response).unwrap();
+        assert_eq!(response.plugin_name, "test_plugin");
+        assert_eq!(response.display_name(), "Test Plugin");
+
+        // clients which predate protocol versioning get the v1 response
+        let response = handle_request(&update_request("0.9.0", None), &state, &test_settings());
+        assert!(!response.contains("display_name"));
+
+        let metadata = Request::Metadata { plugin_name: "test_plugin".into(), beta: None };
+        let metadata = handle_request(&serde_json::to_string(&metadata).unwrap(), &state, &test_settings());
+        let metadata: PluginMetadata = serde_json::from_str(&metadata).unwrap();
+        assert_eq!(metadata.display_name.as_deref(), Some("Test Plugin"));
+    }
+
+    #[test]
+    fn test_skyline_requirement() {
+        let mut plugin = plugin_with_sizes(&[10]);
+        plugin.skyline_version = Some(update_protocol::parse_requirement("0.3.0").unwrap());
+
+        assert!(needs_skyline_update(&plugin, Some("0.2.0")));
+        assert!(!needs_skyline_update(&plugin, Some("0.3.0")));
+        assert!(!needs_skyline_update(&plugin, None));
+        assert!(!needs_skyline_update(&plugin_with_sizes(&[10]), Some("0.2.0")));
+
+        // pre-release skylines only satisfy ranges naming a pre-release of the same version
+        assert!(needs_skyline_update(&plugin, Some("0.3.0-beta.1")));
+        assert!(needs_skyline_update(&plugin, Some("0.4.0-beta.1")));
+        plugin.skyline_version = Some(update_protocol::parse_requirement(">=0.3.0-beta.2").unwrap());
+        assert!(needs_skyline_update(&plugin, Some("0.3.0-beta.1")));
+        assert!(!needs_skyline_update(&plugin, Some("0.3.0-beta.2")));
+        assert!(!needs_skyline_update(&plugin, Some("0.3.0")));
+        assert!(!needs_skyline_update(&plugin, Some("0.4.0")));
+
+        // versions which don't parse are unknown
+        assert!(!needs_skyline_update(&plugin, Some("latest")));
+    }
+
+    #[test]
+    fn test_skyline_requirement_response() {
+        let mut plugin = plugin_with_sizes(&[10]);
+        plugin.skyline_version = Some(update_protocol::parse_requirement("0.3.0").unwrap());
+        let state = test_state(vec![plugin]);
+        let request = |skyline_version: Option<&str>| {
+            let mut options = update_protocol::UpdateRequestOptions::default();
+            options.protocol_version = Some(update_protocol::PROTOCOL_VERSION);
+            options.skyline_version = skyline_version.map(str::to_owned);
+            let request = Request::Update { plugin_name: "test_plugin".into(), plugin_version: "0.9.0".into(), beta: None, options: Some(options) };
+            let response = handle_request(&serde_json::to_string(&request).unwrap(), &state, &test_settings());
+            serde_json::from_str::<UpdateResponse>(&response).unwrap()
+        };
+
+        let response = request(Some("0.2.0"));
+        assert!(response.update_skyline);
+        assert!(response.skyline_requirement.is_some());
+
+        // unknown or satisfied, the update goes ahead
+        assert!(!request(Some("0.3.0")).update_skyline);
+        assert!(!request(None).update_skyline);
+    }
+
+    #[test]
+    fn test_skyline_policy() {
+        let mut plugin = plugin_with_sizes(&[10]);
+        plugin.skyline_version = Some(update_protocol::parse_requirement("0.3.0").unwrap());
+        let mut skyline = named_plugin(SKYLINE_PLUGIN, "0.3.1", false, &[5]);
+        skyline.files[0].install = InstallLocation::AbsolutePath("sd:/atmosphere/contents/01006A800016E000/exefs/subsdk9".into());
+        let request = |state: &ServerState, settings: &Settings, skyline_version: Option<&str>| {
+            let mut options = update_protocol::UpdateRequestOptions::default();
+            options.protocol_version = Some(update_protocol::PROTOCOL_VERSION);
+            options.skyline_version = skyline_version.map(str::to_owned);
+            let request = Request::Update { plugin_name: "test_plugin".into(), plugin_version: "0.9.0".into(), beta: None, options: Some(options) };
+            serde_json::from_str::<UpdateResponse>(&handle_request(&serde_json::to_string(&request).unwrap(), state, settings)).unwrap()
+        };
+        let mut withhold = test_settings();
+        withhold.withhold_outdated_skyline = true;
+
+        // withheld from clients known to run a skyline which is too old
+        let state = test_state(vec![plugin]);
+        let response = request(&state, &withhold, Some("0.2.0"));
+        assert_eq!(response.code, ResponseCode::NoUpdate);
+        assert!(response.update_skyline && response.required_files.is_empty());
+        assert_eq!(response.new_plugin_version, "1.0.0");
+        assert!(response.skyline_requirement.is_some());
+        assert_eq!(request(&state, &withhold, Some("0.3.0")).code, ResponseCode::Update);
+        assert_eq!(request(&state, &withhold, None).code, ResponseCode::Update);
+        assert_eq!(request(&state, &test_settings(), Some("0.2.0")).code, ResponseCode::Update);
+
+        // a hosted skyline the plugin runs on is sent first, whatever the policy
+        let mut state = state;
+        state.plugins.push(named_plugin(SKYLINE_PLUGIN, "0.2.9", false, &[5]));
+        state.plugins.push(skyline);
+        for settings in [test_settings(), withhold].iter() {
+            let response = request(&state, settings, Some("0.2.0"));
+            assert_eq!(response.code, ResponseCode::Update);
+            assert!(response.update_skyline);
+            assert_eq!(response.new_skyline_version.as_deref(), Some("0.3.1"));
+            let locations: Vec<_> = response.required_files.iter().map(|file| file.install_location.clone()).collect();
+            assert_eq!(locations, vec![
+                InstallLocation::AbsolutePath("sd:/atmosphere/contents/01006A800016E000/exefs/subsdk9".into()),
+                InstallLocation::AbsolutePath("sd:/file0".into()),
+            ]);
+        }
+        assert_eq!(request(&state, &test_settings(), Some("0.3.0")).new_skyline_version, None);
+    }
+
+    fn versioned(version: &str, beta: bool) -> Plugin {
+        named_plugin("test_plugin", version, beta, &[10])
+    }
+
+    fn selected(plugins: &[Plugin], beta: bool) -> Option<String> {
+        on_channel(plugins, update_protocol::requested_channel(beta, None))
+    }
+
+    #[test]
+    fn test_select_prerelease_channels() {
+        let plugins = vec![versioned("1.1.0", false), versioned("1.2.0-beta.1", false)];
+        assert_eq!(selected(&plugins, false).as_deref(), Some("1.1.0"));
+        assert_eq!(selected(&plugins, true).as_deref(), Some("1.2.0-beta.1"));
+
+        let mut plugins = plugins;
+        plugins[1].prerelease_on_stable = true;
+        assert_eq!(selected(&plugins, false).as_deref(), Some("1.2.0-beta.1"));
+
+        // a beta plugin with a release version still needs the beta channel
+        let plugins = vec![versioned("1.1.0", false), versioned("1.2.0", true)];
+        assert_eq!(selected(&plugins, false).as_deref(), Some("1.1.0"));
+        assert_eq!(selected(&plugins, true).as_deref(), Some("1.2.0"));
+    }
+
+    #[test]
+    fn test_select_build_metadata() {
+        // plain semver sees the same version, so a nightly build is never an update to the release
+        let plugins = vec![versioned("1.2.0", false), versioned("1.2.0+nightly.20240101", true)];
+        let plugin = select_plugin(&plugins, "test_plugin", update_protocol::BETA_CHANNEL).unwrap();
+        assert!(!plugin.is_newer_than(&"1.2.0".parse().unwrap()));
+
+        let mut nightly = versioned("1.2.0+nightly.20240101", true);
+        nightly.version_ordering = VersionOrdering::SemverWithBuild;
+        let plugins = vec![nightly, versioned("1.2.0", false)];
+        assert_eq!(selected(&plugins, true).as_deref(), Some("1.2.0+nightly.20240101"));
+        assert_eq!(selected(&plugins, false).as_deref(), Some("1.2.0"));
+        assert!(plugins[0].is_newer_than(&"1.2.0".parse().unwrap()));
+        assert!(!plugins[0].is_newer_than(&"1.2.0+nightly.20240101".parse().unwrap()));
+    }
+
+    fn on_channel(plugins: &[Plugin], channel: &str) -> Option<String> {
+        select_plugin(plugins, "test_plugin", channel).map(|plugin| plugin.plugin_version.to_string())
+    }
+
+    #[test]
+    fn test_select_named_channel() {
+        let mut nightly = versioned("1.3.0-nightly.1", false);
+        nightly.channel = "nightly".into();
+        let plugins = vec![versioned("1.1.0", false), versioned("1.2.0-beta.1", true), nightly];
+
+        assert_eq!(on_channel(&plugins, STABLE_CHANNEL).as_deref(), Some("1.1.0"));
+        assert_eq!(on_channel(&plugins, "beta").as_deref(), Some("1.2.0-beta.1"));
+        assert_eq!(on_channel(&plugins, "nightly").as_deref(), Some("1.3.0-nightly.1"));
+        // unknown channels still get stable releases
+        assert_eq!(on_channel(&plugins, "canary").as_deref(), Some("1.1.0"));
+    }
+
+    fn channel_request(channel: Option<&str>, beta: bool, protocol_version: u32) -> String {
+        let mut options = update_protocol::UpdateRequestOptions::default();
+        options.protocol_version = Some(protocol_version);
+        options.channel = channel.map(str::to_owned);
+
+        serde_json::to_string(&Request::Update {
+            plugin_name: "test_plugin".into(),
+            plugin_version: "1.0.0".into(),
+            beta: Some(beta),
+            options: Some(options),
+        }).unwrap()
+    }
+
+    #[test]
+    fn test_channel_request() {
+        let mut nightly = versioned("1.3.0-nightly.1", false);
+        nightly.channel = "nightly".into();
+        let state = test_state(vec![versioned("1.1.0", false), versioned("1.2.0-beta.1", true), nightly]);
+        let offered = |packet: String| {
+            let response: UpdateResponse = serde_json::from_str(&handle_request(&packet, &state, &test_settings())).unwrap();
+            response.new_plugin_version
+        };
+
+        assert_eq!(offered(channel_request(Some("nightly"), false, 7)), "1.3.0-nightly.1");
+        // beta maps to the beta channel and a named channel wins over it
+        assert_eq!(offered(channel_request(None, true, 7)), "1.2.0-beta.1");
+        assert_eq!(offered(channel_request(Some("nightly"), true, 7)), "1.3.0-nightly.1");
+        assert_eq!(offered(channel_request(None, false, 7)), "1.1.0");
+        // channels aren't part of older protocol versions
+        assert_eq!(offered(channel_request(Some("nightly"), false, 6)), "1.1.0");
+    }
+
+    fn rollback_of(plugins: &[Plugin], beta: bool) -> Option<String> {
+        let channel = update_protocol::requested_channel(beta, None);
+        let latest = select_plugin(plugins, "test_plugin", channel)?;
+        rollback_target(plugins, latest, channel).map(|plugin| plugin.plugin_version.to_string())
+    }
+
+    #[test]
+    fn test_rollback_target() {
+        // the previous stable version by default, skipping pre-releases
+        let mut plugins = vec![
+            versioned("1.0.0", false),
+            versioned("1.1.0", false),
+            versioned("1.2.0-beta.1", false),
+            versioned("1.2.0", false),
+        ];
+        assert_eq!(rollback_of(&plugins, false).as_deref(), Some("1.1.0"));
+        assert_eq!(rollback_of(&plugins[..1], false), None);
+
+        // a marked version wins over newer ones
+        plugins[0].rollback = true;
+        assert_eq!(rollback_of(&plugins, false).as_deref(), Some("1.0.0"));
+
+        // marking the newest version itself doesn't make it its own rollback
+        plugins[0].rollback = false;
+        plugins[3].rollback = true;
+        assert_eq!(rollback_of(&plugins, false).as_deref(), Some("1.1.0"));
+
+        // beta clients roll back to a marked beta, but never by default
+        let mut plugins = vec![versioned("1.0.0", false), versioned("1.1.0", true), versioned("1.2.0", true)];
+        assert_eq!(rollback_of(&plugins, true).as_deref(), Some("1.0.0"));
+        plugins[1].rollback = true;
+        assert_eq!(rollback_of(&plugins, true).as_deref(), Some("1.1.0"));
+        assert_eq!(rollback_of(&plugins, false), None);
+    }
+
+    fn pinned_request(version: &str, pinned: &str, protocol_version: u32) -> String {
+        let mut options = update_protocol::UpdateRequestOptions::default();
+        options.protocol_version = Some(protocol_version);
+        options.pinned_version = Some(pinned.into());
+
+        serde_json::to_string(&Request::Update {
+            plugin_name: "test_plugin".into(),
+            plugin_version: version.into(),
+            beta: None,
+            options: Some(options),
+        }).unwrap()
+    }
+
+    #[test]
+    fn test_version_folders() {
+        let plugins_dir = std::env::temp_dir().join(format!("update-server-versions-{}", std::process::id()));
+        for version in ["1.0.0", "1.1.0"].iter() {
+            let dir = plugins_dir.join("test_plugin").join(version);
+            fs::create_dir_all(&dir).unwrap();
+            fs::write(dir.join("plugin.nro"), format!("plugin {}", version)).unwrap();
+            fs::write(dir.join("plugin.toml"), format!(r#"
+                version = "{}"
+                name = "test_plugin"
+                files = [{{ install_location = "sd:/plugin.nro", filename = "plugin.nro" }}]
+            "#, version)).unwrap();
+        }
+
+        let (plugins, failures) = hosted_plugins::get(&plugins_dir).unwrap();
+        assert!(failures.is_empty());
+        let mut index_blocks = vec![];
+        let plugins = plugins.into_iter().map(|plugin| serve_plugin(plugin, &mut index_blocks).unwrap()).collect();
+        let mut state = test_state(plugins);
+        state.index_blocks = index_blocks;
+        let state = RwLock::new(state);
+
+        let served = |request: &str| {
+            let state = read_state(&state);
+            let response: UpdateResponse = serde_json::from_str(&handle_request(request, &state, &test_settings())).unwrap();
+            let data = handle_download(response.required_files[0].download_index.to_be_bytes(), &state).unwrap();
+            (response.new_plugin_version, String::from_utf8(data.read().unwrap().to_vec()).unwrap())
+        };
+        // the newest version by default, an older one when pinned
+        assert_eq!(served(&update_request("0.9.0", Some(5))), ("1.1.0".to_owned(), "plugin 1.1.0".to_owned()));
+        assert_eq!(served(&pinned_request("1.1.0", "1.0.0", 5)), ("1.0.0".to_owned(), "plugin 1.0.0".to_owned()));
+
+        // a change inside a version folder reloads the plugin's folder, and every version in it
+        let folders = [plugins_dir.join("test_plugin")];
+        reload_plugins_with(&state, &test_settings(), Some(&folders), |index_blocks| {
+            let (plugins, failures) = hosted_plugins::get_folders(&folders);
+            Ok((plugins.into_iter().map(|plugin| serve_plugin(plugin, index_blocks)).collect::<eyre::Result<_>>()?, failures))
+        }).unwrap();
+        let versions: Vec<_> = read_state(&state).plugins.iter().map(|plugin| plugin.plugin_version.to_string()).collect();
+        assert_eq!(versions, vec!["1.0.0", "1.1.0"]);
+        assert_eq!(served(&pinned_request("1.1.0", "1.0.0", 5)).1, "plugin 1.0.0");
+
+        let _ = fs::remove_dir_all(plugins_dir);
+    }
+
+    #[test]
+    fn test_pinned_rollback() {
+        let state = test_state(vec![versioned("1.0.0", false), versioned("1.1.0", false), versioned("1.2.0", false)]);
+        let respond = |request: &str| -> UpdateResponse {
+            serde_json::from_str(&handle_request(request, &state, &test_settings())).unwrap()
+        };
+
+        // the rollback version is sent with updates and when up to date
+        let response = respond(&update_request("1.0.0", Some(5)));
+        assert_eq!(response.code, ResponseCode::Update);
+        assert_eq!(response.rollback_version.as_deref(), Some("1.1.0"));
+        let response = respond(&update_request("1.2.0", Some(5)));
+        assert_eq!(response.code, ResponseCode::NoUpdate);
+        assert_eq!(response.rollback_version.as_deref(), Some("1.1.0"));
+        assert_eq!(respond(&update_request("1.0.0", Some(4))).rollback_version, None);
+
+        // a pinned version is offered even though it is older
+        let response = respond(&pinned_request("1.2.0", "1.1.0", 5));
+        assert_eq!(response.code, ResponseCode::Update);
+        assert_eq!(response.new_plugin_version, "1.1.0");
+        assert_eq!(response.rollback_version, None);
+        assert_eq!(respond(&pinned_request("1.1.0", "1.1.0", 5)).code, ResponseCode::NoUpdate);
+        assert_eq!(respond(&pinned_request("1.2.0", "0.5.0", 5)).code, ResponseCode::PluginNotFound);
+        assert_eq!(respond(&pinned_request("1.2.0", "not a version", 5)).code, ResponseCode::InvalidRequest);
+
+        // newer clients are told it is the version which is missing, not the plugin
+        let response = respond(&pinned_request("1.2.0", "0.5.0", 11));
+        assert_eq!(response.code, ResponseCode::VersionNotFound);
+        assert_eq!(response.message.as_deref(), Some("version 0.5.0 of 'test_plugin' isn't hosted on the stable channel"));
+        let request = pinned_request("1.2.0", "0.5.0", 11).replace("test_plugin", "other_plugin");
+        assert_eq!(respond(&request).code, ResponseCode::PluginNotFound);
+        let request = pinned_request("1.2.0", "1.1.0", 11).replace("pinned_version", "target_version");
+        assert_eq!(respond(&request).new_plugin_version, "1.1.0");
+
+        // clients from before pinning get the newest version
+        let response = respond(&pinned_request("1.0.0", "1.1.0", 4));
+        assert_eq!(response.new_plugin_version, "1.2.0");
+    }
+
+    #[test]
+    fn test_pinned_build_metadata() {
+        let mut plugins = vec![versioned("1.1.0+nightly.2", false), versioned("1.1.0+nightly.1", false), versioned("1.2.0", false)];
+        plugins.iter_mut().for_each(|plugin| plugin.version_ordering = VersionOrdering::SemverWithBuild);
+        let state = test_state(plugins);
+        let pinned = |pinned: &str| -> String {
+            let response: UpdateResponse = serde_json::from_str(&handle_request(&pinned_request("1.2.0", pinned, 5), &state, &test_settings())).unwrap();
+            response.new_plugin_version
+        };
+
+        // the exact build if it is hosted, otherwise the newest build of the version
+        assert_eq!(pinned("1.1.0+nightly.1"), "1.1.0+nightly.1");
+        assert_eq!(pinned("1.1.0"), "1.1.0+nightly.2");
+        assert_eq!(pinned("1.1.0+nightly.9"), "1.1.0+nightly.2");
+    }
 }
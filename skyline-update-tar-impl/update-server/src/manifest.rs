@@ -0,0 +1,357 @@
+use std::fs;
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+use serde::{Serialize, Deserialize};
+use sha2::{Sha256, Digest};
+
+use color_eyre::eyre;
+
+use crate::hosted_plugins::PluginToml;
+
+/// Snapshot of the manifests of the currently loaded plugins, written on every (re)load
+pub const SNAPSHOT_PATH: &str = "cache/manifest.json";
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ManifestFile {
+    pub install_location: String,
+    pub size: u64,
+    pub sha256: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ManifestFolder {
+    pub install_location: String,
+    /// Name of the folder, which is the top level directory inside its archive
+    #[serde(default)]
+    pub root_name: String,
+    /// Hash over the relative paths and contents of every file in the folder
+    pub fingerprint: String,
+}
+
+/// Everything the server would serve for a single plugin directory
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct PluginManifest {
+    pub directory: String,
+    pub name: String,
+    pub version: String,
+    pub files: Vec<ManifestFile>,
+    pub folders: Vec<ManifestFolder>,
+}
+
+pub fn sha256(data: &[u8]) -> String {
+    format!("{:x}", Sha256::digest(data))
+}
+
+/// `sha256` of everything `reader` returns, with its length, without holding it in memory
+pub fn sha256_reader<R: Read>(mut reader: R) -> io::Result<(String, u64)> {
+    let mut hasher = Sha256::new();
+    let len = io::copy(&mut reader, &mut hasher)?;
+    Ok((format!("{:x}", hasher.finalize()), len))
+}
+
+/// The install path of a location, relative locations are kept relative (without a scheme)
+fn location_string(location: &update_protocol::InstallLocation) -> eyre::Result<String> {
+    match location.normalized()? {
+        update_protocol::InstallLocation::AbsolutePath(path) => Ok(path),
+        update_protocol::InstallLocation::Relative(path) => Ok(path),
+        other => eyre::bail!("Unsupported install location {:?}", other),
+    }
+}
+
+fn resolve(dir: &Path, filename: &Path) -> PathBuf {
+    if filename.is_absolute() {
+        filename.to_owned()
+    } else {
+        dir.join(filename)
+    }
+}
+
+pub fn folder_fingerprint(folder: &Path) -> eyre::Result<String> {
+    let mut entries = vec![];
+    for entry in walkdir::WalkDir::new(folder) {
+        let entry = entry?;
+        if entry.path().is_dir() {
+            continue;
+        }
+
+        let relative = entry.path().strip_prefix(folder)?.to_string_lossy().replace('\\', "/");
+        entries.push((relative, sha256_reader(fs::File::open(entry.path())?)?.0));
+    }
+    entries.sort();
+
+    let mut hasher = Sha256::new();
+    for (relative, hash) in entries {
+        hasher.update(relative.as_bytes());
+        hasher.update([0u8]);
+        hasher.update(hash.as_bytes());
+    }
+
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+/// Build the manifest of a plugin directory from what is currently on disk, without
+/// building any archives.
+pub fn scan(dir: &Path) -> eyre::Result<PluginManifest> {
+    let plugin: PluginToml = toml::from_str(&fs::read_to_string(dir.join("plugin.toml"))?)?;
+
+    let files = plugin.files.iter()
+        .map(|file| -> eyre::Result<_> {
+            let (sha256, size) = sha256_reader(fs::File::open(resolve(dir, &file.filename))?)?;
+            Ok(ManifestFile {
+                install_location: location_string(&file.install_location)?,
+                size,
+                sha256,
+            })
+        })
+        .collect::<eyre::Result<_>>()?;
+
+    let folders = plugin.folders.iter()
+        .flatten()
+        .map(|folder| -> eyre::Result<_> {
+            let root = resolve(dir, &folder.root_name);
+            Ok(ManifestFolder {
+                install_location: location_string(&folder.install_root_location)? + ".tar",
+                root_name: root.file_name().map(|name| name.to_string_lossy().into_owned()).unwrap_or_default(),
+                fingerprint: folder_fingerprint(&root)?,
+            })
+        })
+        .collect::<eyre::Result<_>>()?;
+
+    Ok(PluginManifest {
+        directory: dir.display().to_string(),
+        name: plugin.name,
+        version: plugin.version.to_string(),
+        files,
+        folders,
+    })
+}
+
+/// Scan every plugin directory, skipping the ones which fail to load
+pub fn scan_all(plugins_dir: &Path) -> eyre::Result<Vec<PluginManifest>> {
+    Ok(
+        fs::read_dir(plugins_dir)?
+            .filter_map(|entry| entry.ok())
+            .map(|entry| entry.path())
+            .filter(|path| path.is_dir())
+            .flat_map(|path| crate::hosted_plugins::plugin_folders(&path))
+            .filter_map(|path| scan(&path).ok())
+            .collect()
+    )
+}
+
+pub fn save(manifests: &[PluginManifest]) -> eyre::Result<()> {
+    let path = Path::new(SNAPSHOT_PATH);
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+
+    Ok(fs::write(path, serde_json::to_string_pretty(manifests)?)?)
+}
+
+pub fn load() -> eyre::Result<Vec<PluginManifest>> {
+    Ok(serde_json::from_str(&fs::read_to_string(SNAPSHOT_PATH)?)?)
+}
+
+/// Rescan only `folders` in the saved snapshot, after a reload of just those plugin folders
+pub fn update(folders: &[PathBuf]) -> eyre::Result<()> {
+    let mut manifests = load().unwrap_or_default();
+    manifests.retain(|manifest| !folders.iter().any(|folder| Path::new(&manifest.directory).starts_with(folder)));
+    manifests.extend(
+        folders.iter()
+            .filter(|folder| folder.is_dir())
+            .flat_map(|folder| crate::hosted_plugins::plugin_folders(folder))
+            .filter_map(|folder| scan(&folder).ok())
+    );
+
+    save(&manifests)
+}
+
+#[derive(Debug, Default, PartialEq)]
+pub struct ManifestDiff {
+    pub added: Vec<String>,
+    pub removed: Vec<String>,
+    pub modified: Vec<String>,
+    pub old_version: Option<String>,
+    pub new_version: Option<String>,
+    /// Folders whose archive would be rebuilt on reload
+    pub rebuilt_folders: Vec<String>,
+}
+
+impl ManifestDiff {
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty() && self.modified.is_empty()
+            && self.rebuilt_folders.is_empty() && self.old_version == self.new_version
+    }
+}
+
+/// Compare the loaded manifest of a plugin against the one on disk. Either side being
+/// None means the plugin is newly added or was removed entirely.
+pub fn diff(old: Option<&PluginManifest>, new: Option<&PluginManifest>) -> ManifestDiff {
+    let old_files = old.map(|manifest| &manifest.files[..]).unwrap_or_default();
+    let new_files = new.map(|manifest| &manifest.files[..]).unwrap_or_default();
+    let old_folders = old.map(|manifest| &manifest.folders[..]).unwrap_or_default();
+    let new_folders = new.map(|manifest| &manifest.folders[..]).unwrap_or_default();
+
+    let find = |files: &[ManifestFile], location: &str| {
+        files.iter().position(|file| file.install_location == location)
+    };
+
+    let mut diff = ManifestDiff {
+        old_version: old.map(|manifest| manifest.version.clone()),
+        new_version: new.map(|manifest| manifest.version.clone()),
+        ..Default::default()
+    };
+
+    for file in new_files {
+        match find(old_files, &file.install_location) {
+            None => diff.added.push(file.install_location.clone()),
+            Some(i) if old_files[i].sha256 != file.sha256 => diff.modified.push(file.install_location.clone()),
+            Some(_) => {}
+        }
+    }
+    diff.removed.extend(
+        old_files.iter()
+            .filter(|file| find(new_files, &file.install_location).is_none())
+            .map(|file| file.install_location.clone())
+    );
+
+    for folder in new_folders {
+        match old_folders.iter().find(|old| old.install_location == folder.install_location) {
+            Some(old) if old.fingerprint == folder.fingerprint => {}
+            Some(_) => diff.rebuilt_folders.push(folder.install_location.clone()),
+            None => diff.added.push(folder.install_location.clone()),
+        }
+    }
+    diff.removed.extend(
+        old_folders.iter()
+            .filter(|old| !new_folders.iter().any(|folder| folder.install_location == old.install_location))
+            .map(|folder| folder.install_location.clone())
+    );
+
+    diff
+}
+
+fn print_diff(name: &str, diff: &ManifestDiff) {
+    println!("{}:", name);
+    if diff.is_empty() {
+        println!("    no changes");
+        return
+    }
+
+    if diff.old_version != diff.new_version {
+        println!(
+            "    version: {} -> {}",
+            diff.old_version.as_deref().unwrap_or("(none)"),
+            diff.new_version.as_deref().unwrap_or("(none)")
+        );
+    }
+    for path in &diff.added {
+        println!("    + {}", path);
+    }
+    for path in &diff.removed {
+        println!("    - {}", path);
+    }
+    for path in &diff.modified {
+        println!("    ~ {}", path);
+    }
+    for path in &diff.rebuilt_folders {
+        println!("    ~ {} (archive will be rebuilt)", path);
+    }
+}
+
+/// `update-server diff [plugin]`: show what a reload would change compared to the last
+/// loaded snapshot. Works whether or not the server is running.
+pub fn run_diff(plugins_dir: &Path, plugin: Option<&str>) -> eyre::Result<()> {
+    let loaded = load().map_err(|e| eyre::eyre!("Failed to read snapshot {}: {}", SNAPSHOT_PATH, e))?;
+    let on_disk = scan_all(plugins_dir)?;
+
+    let mut names: Vec<&str> = loaded.iter().chain(on_disk.iter())
+        .map(|manifest| manifest.name.as_str())
+        .filter(|name| plugin.map(|plugin| plugin == *name).unwrap_or(true))
+        .collect();
+    names.sort();
+    names.dedup();
+
+    if names.is_empty() {
+        eyre::bail!("Plugin '{}' not found", plugin.unwrap_or_default());
+    }
+
+    for name in names {
+        let old = loaded.iter().find(|manifest| manifest.name == name);
+        let new = on_disk.iter().find(|manifest| manifest.name == name);
+        print_diff(name, &diff(old, new));
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn file(location: &str, contents: &str) -> ManifestFile {
+        ManifestFile {
+            install_location: location.into(),
+            size: contents.len() as u64,
+            sha256: sha256(contents.as_bytes()),
+        }
+    }
+
+    fn manifest(version: &str, files: Vec<ManifestFile>, folders: Vec<ManifestFolder>) -> PluginManifest {
+        PluginManifest {
+            directory: "plugins/test_plugin".into(),
+            name: "test_plugin".into(),
+            version: version.into(),
+            files,
+            folders,
+        }
+    }
+
+    fn folder(location: &str, fingerprint: &str) -> ManifestFolder {
+        ManifestFolder { install_location: location.into(), root_name: "romfs".into(), fingerprint: fingerprint.into() }
+    }
+
+    #[test]
+    fn test_diff_unchanged() {
+        let old = manifest("1.0.0", vec![file("sd:/a.txt", "a")], vec![folder("sd:/romfs.tar", "x")]);
+
+        assert!(diff(Some(&old), Some(&old.clone())).is_empty());
+    }
+
+    #[test]
+    fn test_diff_files() {
+        let old = manifest("1.0.0", vec![file("sd:/a.txt", "a"), file("sd:/b.txt", "b")], vec![]);
+        let new = manifest("1.1.0", vec![file("sd:/a.txt", "changed"), file("sd:/c.txt", "c")], vec![]);
+
+        let diff = diff(Some(&old), Some(&new));
+        assert_eq!(diff.added, vec!["sd:/c.txt"]);
+        assert_eq!(diff.removed, vec!["sd:/b.txt"]);
+        assert_eq!(diff.modified, vec!["sd:/a.txt"]);
+        assert_eq!(diff.old_version.as_deref(), Some("1.0.0"));
+        assert_eq!(diff.new_version.as_deref(), Some("1.1.0"));
+    }
+
+    #[test]
+    fn test_diff_folders() {
+        let old = manifest("1.0.0", vec![], vec![folder("sd:/a.tar", "1"), folder("sd:/b.tar", "2")]);
+        let new = manifest("1.0.0", vec![], vec![folder("sd:/a.tar", "changed"), folder("sd:/c.tar", "3")]);
+
+        let diff = diff(Some(&old), Some(&new));
+        assert_eq!(diff.rebuilt_folders, vec!["sd:/a.tar"]);
+        assert_eq!(diff.added, vec!["sd:/c.tar"]);
+        assert_eq!(diff.removed, vec!["sd:/b.tar"]);
+    }
+
+    #[test]
+    fn test_diff_plugin_added_removed() {
+        let plugin = manifest("1.0.0", vec![file("sd:/a.txt", "a")], vec![]);
+
+        let added = diff(None, Some(&plugin));
+        assert_eq!(added.added, vec!["sd:/a.txt"]);
+        assert_eq!(added.old_version, None);
+
+        let removed = diff(Some(&plugin), None);
+        assert_eq!(removed.removed, vec!["sd:/a.txt"]);
+        assert_eq!(removed.new_version, None);
+    }
+}
@@ -0,0 +1,155 @@
+use std::io::{self, prelude::*};
+use std::ops::{Deref, DerefMut};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use crossbeam::queue::ArrayQueue;
+
+/// Number of idle request buffers kept around for reuse
+pub const REQUEST_BUFFERS: usize = 64;
+
+/// Buffers which grew beyond this are freed instead of going back into the pool, so a
+/// single oversized request doesn't pin its memory for the lifetime of the server
+pub const MAX_BUFFER_SIZE: usize = 16 * 1024;
+
+/// Longest request line the main port will read, anything past it is ignored
+pub const MAX_REQUEST_LEN: usize = 64 * 1024;
+
+/// A fixed number of reusable byte buffers. Taking a buffer from an empty pool allocates a
+/// new one, so the pool never blocks, it only bounds how many idle buffers are retained.
+pub struct BufferPool {
+    free: ArrayQueue<Vec<u8>>,
+    max_buffer_size: usize,
+    in_use: AtomicUsize,
+    high_water: AtomicUsize,
+}
+
+impl BufferPool {
+    pub fn new(buffers: usize, max_buffer_size: usize) -> Self {
+        BufferPool {
+            free: ArrayQueue::new(buffers),
+            max_buffer_size,
+            in_use: AtomicUsize::new(0),
+            high_water: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn get(&self) -> PooledBuffer<'_> {
+        let in_use = self.in_use.fetch_add(1, Ordering::Relaxed) + 1;
+        self.high_water.fetch_max(in_use, Ordering::Relaxed);
+
+        PooledBuffer {
+            pool: self,
+            buf: self.free.pop().unwrap_or_default(),
+        }
+    }
+
+    /// Most buffers which were ever handed out at the same time
+    #[cfg(test)]
+    pub fn high_water(&self) -> usize {
+        self.high_water.load(Ordering::Relaxed)
+    }
+
+    /// Idle buffers currently held by the pool
+    #[cfg(test)]
+    pub fn idle(&self) -> usize {
+        self.free.len()
+    }
+}
+
+/// A buffer borrowed from a `BufferPool`, returned to it (emptied) when dropped
+pub struct PooledBuffer<'a> {
+    pool: &'a BufferPool,
+    buf: Vec<u8>,
+}
+
+impl Deref for PooledBuffer<'_> {
+    type Target = Vec<u8>;
+
+    fn deref(&self) -> &Vec<u8> {
+        &self.buf
+    }
+}
+
+impl DerefMut for PooledBuffer<'_> {
+    fn deref_mut(&mut self) -> &mut Vec<u8> {
+        &mut self.buf
+    }
+}
+
+impl Drop for PooledBuffer<'_> {
+    fn drop(&mut self) {
+        self.pool.in_use.fetch_sub(1, Ordering::Relaxed);
+
+        let mut buf = std::mem::take(&mut self.buf);
+        if buf.capacity() <= self.pool.max_buffer_size {
+            buf.clear();
+            // if the pool is already full the buffer is simply freed
+            let _ = self.pool.free.push(buf);
+        }
+    }
+}
+
+/// Read up to and including the first newline into `buf`, stopping at `MAX_REQUEST_LEN`
+/// bytes or when the peer stops sending.
+pub fn read_request_line<R: Read>(socket: &mut R, buf: &mut Vec<u8>) -> io::Result<()> {
+    let mut chunk = [0; 512];
+    while buf.len() < MAX_REQUEST_LEN {
+        let len = match socket.read(&mut chunk) {
+            Ok(0) => break,
+            Ok(len) => len.min(MAX_REQUEST_LEN - buf.len()),
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+
+        if let Some(newline) = chunk[..len].iter().position(|&byte| byte == b'\n') {
+            buf.extend_from_slice(&chunk[..=newline]);
+            break
+        }
+        buf.extend_from_slice(&chunk[..len]);
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_buffers_reused() {
+        let pool = BufferPool::new(1, 1024);
+
+        pool.get().extend_from_slice(b"hello");
+        assert_eq!(pool.idle(), 1);
+
+        let buf = pool.get();
+        assert!(buf.is_empty());
+        assert!(buf.capacity() >= 5);
+        assert_eq!(pool.high_water(), 1);
+    }
+
+    #[test]
+    fn test_oversized_buffers_dropped() {
+        let pool = BufferPool::new(2, 1024);
+
+        pool.get().resize(4096, 0);
+        assert_eq!(pool.idle(), 0);
+
+        // more returned buffers than the pool holds
+        {
+            let _buffers: Vec<_> = (0..4).map(|_| pool.get()).collect();
+        }
+        assert_eq!(pool.idle(), 2);
+    }
+
+    #[test]
+    fn test_read_request_line() {
+        let mut buf = vec![];
+        read_request_line(&mut &b"\"Ping\"\nignored"[..], &mut buf).unwrap();
+        assert_eq!(buf, b"\"Ping\"\n");
+
+        let mut buf = vec![];
+        read_request_line(&mut &vec![b'a'; MAX_REQUEST_LEN * 2][..], &mut buf).unwrap();
+        assert_eq!(buf.len(), MAX_REQUEST_LEN);
+    }
+}
@@ -0,0 +1,329 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use color_eyre::eyre;
+use semver::Version;
+use skyline_update::{
+    ExitStatus, FileOutcome, InstallLocation, PluginStatus, SandboxInstaller, StatusLine, UpdateClient,
+    UpdateReport, DEFAULT_INSTALL_ROOT,
+};
+
+use crate::manifest::{self, PluginManifest};
+
+/// Version the self-check pretends to have installed, so every plugin is offered an update
+const PRETEND_VERSION: &str = "0.0.0";
+
+/// Where in the sandbox a manifest install location ends up. Relative locations are kept
+/// without a scheme in the manifest, and the sandbox installs them under the default root.
+fn sandbox_path(installer: &SandboxInstaller, location: &str) -> PathBuf {
+    let location = if location.contains(':') {
+        InstallLocation::AbsolutePath(location.to_owned())
+    } else {
+        InstallLocation::Relative(location.to_owned())
+    };
+    match location.resolve(DEFAULT_INSTALL_ROOT) {
+        Ok(InstallLocation::AbsolutePath(path)) => installer.path(Path::new(&path)),
+        _ => installer.root().to_owned(),
+    }
+}
+
+/// Compare everything the sandbox received against what the manifest says the server hosts,
+/// returning the first mismatch
+fn verify(installer: &SandboxInstaller, manifest: &PluginManifest) -> Result<(), String> {
+    for file in &manifest.files {
+        let path = sandbox_path(installer, &file.install_location);
+        let data = fs::read(&path).map_err(|_| format!("{} was not installed", file.install_location))?;
+        if manifest::sha256(&data) != file.sha256 {
+            return Err(format!("{} does not match the hosted file", file.install_location))
+        }
+    }
+
+    for folder in &manifest.folders {
+        let archive = sandbox_path(installer, &folder.install_location);
+        let extracted = archive.with_extension("").join(&folder.root_name);
+        let fingerprint = manifest::folder_fingerprint(&extracted)
+            .map_err(|_| format!("{} was not extracted", folder.install_location))?;
+        if fingerprint != folder.fingerprint {
+            return Err(format!("contents of {} do not match the hosted folder", folder.install_location))
+        }
+    }
+
+    Ok(())
+}
+
+/// Why a plugin which didn't install fully failed
+fn install_failure(report: &UpdateReport) -> (ExitStatus, String) {
+    let error = report.files.iter().find_map(|file| match &file.outcome {
+        FileOutcome::Failed(e) => Some(e.clone()),
+        _ => None,
+    });
+
+    match error.or_else(|| report.status().err()) {
+        Some(e) => (ExitStatus::from(&e), e.to_string()),
+        None => (ExitStatus::Install, String::from("update was not installed")),
+    }
+}
+
+/// Run a full check-and-install of one plugin against the local server into a fresh sandbox.
+/// The status is `Success` if the plugin passed.
+fn check_plugin(port: u16, manifest: &PluginManifest, sandbox: &Path) -> PluginStatus {
+    let _ = fs::remove_dir_all(sandbox);
+    let installer = SandboxInstaller::new(sandbox);
+
+    let report = UpdateClient::new("127.0.0.1".parse().unwrap()).with_port(port).custom_check_update_report(
+        &manifest.name,
+        PRETEND_VERSION,
+        true,
+        &installer,
+    );
+
+    let result = match report.response {
+        None => Err((
+            report.error.as_ref().map(ExitStatus::from).unwrap_or(ExitStatus::Server),
+            String::from("no valid response from the server"),
+        )),
+        Some(ref response) if response.new_plugin_version != manifest.version => Err((ExitStatus::Server, format!(
+            "server offered version {} instead of {}",
+            response.new_plugin_version, manifest.version
+        ))),
+        Some(_) if !report.installed => Err(install_failure(&report)),
+        Some(_) => verify(&installer, manifest).map_err(|e| (ExitStatus::Install, e)),
+    };
+
+    let _ = fs::remove_dir_all(sandbox);
+
+    let mut status = PluginStatus::from_report(&manifest.name, PRETEND_VERSION, &report);
+    match result {
+        Ok(()) => {
+            status.status = ExitStatus::Success;
+            status.error = None;
+        }
+        Err((exit_status, error)) => {
+            status.status = exit_status;
+            status.error = Some(error);
+        }
+    }
+
+    status
+}
+
+/// The manifest the server should be serving for each plugin name: the highest version,
+/// betas included, as the self-check asks for betas
+fn latest_manifests(manifests: Vec<PluginManifest>) -> Vec<PluginManifest> {
+    let version = |manifest: &PluginManifest| manifest.version.parse::<Version>().ok();
+
+    let mut latest: Vec<PluginManifest> = vec![];
+    for manifest in manifests {
+        match latest.iter_mut().find(|existing| existing.name == manifest.name) {
+            Some(existing) if version(&manifest) > version(existing) => *existing = manifest,
+            Some(_) => {}
+            None => latest.push(manifest),
+        }
+    }
+    latest.sort_by(|a, b| a.name.cmp(&b.name));
+
+    latest
+}
+
+/// Check every plugin (or only `plugin`), printing pass/fail per plugin
+fn run_once(port: u16, plugin: Option<&str>) -> eyre::Result<StatusLine> {
+    let start = Instant::now();
+    let manifests = manifest::load()
+        .map_err(|e| eyre::eyre!("Failed to read snapshot {}: {}", manifest::SNAPSHOT_PATH, e))?;
+    let manifests: Vec<_> = latest_manifests(manifests)
+        .into_iter()
+        .filter(|manifest| plugin.map(|plugin| plugin == manifest.name).unwrap_or(true))
+        .collect();
+
+    if manifests.is_empty() {
+        eyre::bail!("Plugin '{}' not found", plugin.unwrap_or_default());
+    }
+
+    let sandbox = std::env::temp_dir().join(format!("update-server-self-check-{}", std::process::id()));
+    let results: Vec<_> = manifests.iter()
+        .map(|manifest| (manifest, check_plugin(port, manifest, &sandbox)))
+        .collect();
+
+    println!("Self-check results:");
+    for (manifest, status) in &results {
+        match &status.error {
+            None => println!("    PASS {} {}", manifest.name, manifest.version),
+            Some(e) => println!("    FAIL {} {}: {}", manifest.name, manifest.version, e),
+        }
+    }
+
+    let failed = results.iter().filter(|(_, status)| status.status != ExitStatus::Success).count();
+    println!("{}/{} plugins passed", results.len() - failed, results.len());
+
+    Ok(StatusLine {
+        plugins: results.into_iter().map(|(_, status)| status).collect(),
+        duration: start.elapsed(),
+    })
+}
+
+const USAGE: &str = "usage: update-server [--port <port>] self-check [plugin] [--every <minutes>] [--json]
+
+    --every <minutes>  repeat the self-check until stopped
+    --json             print a single-line JSON status after every self-check, with the status of
+                       each plugin, versions, bytes installed and duration";
+
+/// `update-server self-check [plugin] [--every <minutes>] [--json]`: install every hosted
+/// plugin from the running server into a sandbox and verify it against the manifest snapshot.
+/// Returns the status to exit with, the most severe of any plugin, unless run periodically, in
+/// which case it keeps going until stopped. `port` is the server's main port.
+pub fn run_self_check(port: u16, args: &[String]) -> eyre::Result<ExitStatus> {
+    let mut plugin = None;
+    let mut every = None;
+    let mut json = false;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        if arg == "--help" {
+            println!("{}\n\n{}", USAGE, ExitStatus::help());
+            return Ok(ExitStatus::Success)
+        } else if arg == "--json" {
+            json = true;
+        } else if arg == "--every" {
+            match args.next().and_then(|minutes| minutes.parse::<u64>().ok()) {
+                Some(minutes) => every = Some(Duration::from_secs(minutes * 60)),
+                None => {
+                    println!("--every requires a number of minutes\n\n{}", USAGE);
+                    return Ok(ExitStatus::Usage)
+                }
+            }
+        } else {
+            plugin = Some(arg.as_str());
+        }
+    }
+
+    let run = || -> eyre::Result<ExitStatus> {
+        let status = run_once(port, plugin)?;
+        if json {
+            println!("{}", status.to_json());
+        }
+        Ok(status.status())
+    };
+
+    match every {
+        Some(interval) => loop {
+            if let Err(e) = run() {
+                println!("Self-check failed to run: {}", e);
+            }
+            std::thread::sleep(interval);
+        },
+        None => run(),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use skyline_update::{ArchiveDecision, Installer, UpdateFile};
+    use crate::manifest::{ManifestFile, ManifestFolder};
+
+    fn sandbox(name: &str) -> SandboxInstaller {
+        let root = std::env::temp_dir().join(format!("update-server-self-check-test-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        SandboxInstaller::new(root)
+    }
+
+    fn manifest(files: Vec<ManifestFile>, folders: Vec<ManifestFolder>) -> PluginManifest {
+        PluginManifest {
+            directory: "plugins/test_plugin".into(),
+            name: "test_plugin".into(),
+            version: "1.0.0".into(),
+            files,
+            folders,
+        }
+    }
+
+    fn file(location: &str, contents: &str) -> ManifestFile {
+        ManifestFile {
+            install_location: location.into(),
+            size: contents.len() as u64,
+            sha256: manifest::sha256(contents.as_bytes()),
+        }
+    }
+
+    #[test]
+    fn test_verify_files() {
+        let installer = sandbox("files");
+        installer.install_file("sd:/plugin/a.txt".into(), b"a".to_vec()).unwrap();
+
+        assert_eq!(verify(&installer, &manifest(vec![file("sd:/plugin/a.txt", "a")], vec![])), Ok(()));
+        assert!(verify(&installer, &manifest(vec![file("sd:/plugin/a.txt", "changed")], vec![])).is_err());
+        assert!(verify(&installer, &manifest(vec![file("sd:/plugin/missing.txt", "a")], vec![])).is_err());
+
+        let _ = fs::remove_dir_all(installer.root());
+    }
+
+    #[test]
+    fn test_verify_archive() {
+        let installer = sandbox("archive");
+
+        let source = installer.root().join("source/romfs");
+        fs::create_dir_all(&source).unwrap();
+        fs::write(source.join("data.txt"), "data").unwrap();
+
+        let mut builder = tar::Builder::new(vec![]);
+        builder.append_path_with_name(source.join("data.txt"), "romfs/data.txt").unwrap();
+        let archive = builder.into_inner().unwrap();
+
+        let location = "sd:/ultimate/mods/base.tar";
+        let update_file = UpdateFile {
+            install_location: update_protocol::InstallLocation::AbsolutePath(location.into()),
+            download_index: 0,
+            size: archive.len(),
+            inline_data: None,
+            sha256: None,
+            signature: None,
+        };
+        installer.install_file(location.into(), archive).unwrap();
+        assert_eq!(installer.handle_archive(&update_file, Path::new(location)), ArchiveDecision::LeaveArchived);
+
+        let folder = ManifestFolder {
+            install_location: location.into(),
+            root_name: "romfs".into(),
+            fingerprint: manifest::folder_fingerprint(&source).unwrap(),
+        };
+        assert_eq!(verify(&installer, &manifest(vec![], vec![folder.clone()])), Ok(()));
+
+        let wrong = ManifestFolder { fingerprint: "0".into(), ..folder };
+        assert!(verify(&installer, &manifest(vec![], vec![wrong])).is_err());
+
+        let _ = fs::remove_dir_all(installer.root());
+    }
+
+    #[test]
+    fn test_install_failure() {
+        use skyline_update::{FileReport, UpdateError};
+
+        let failed = |error: UpdateError| UpdateReport {
+            files: vec![FileReport {
+                install_location: InstallLocation::Unknown,
+                outcome: FileOutcome::Failed(error),
+                archive: None,
+                extracted: vec![],
+            }],
+            ..Default::default()
+        };
+
+        assert_eq!(install_failure(&failed(UpdateError::Download { index: 3, reason: String::new() })).0, ExitStatus::Network);
+        assert_eq!(install_failure(&failed(UpdateError::Install { path: "a".into(), reason: "b".into() })).0, ExitStatus::Install);
+        assert_eq!(install_failure(&UpdateReport::default()).0, ExitStatus::Server);
+    }
+
+    #[test]
+    fn test_latest_manifests() {
+        let versioned = |name: &str, version: &str| PluginManifest {
+            name: name.into(),
+            version: version.into(),
+            ..manifest(vec![], vec![])
+        };
+
+        let latest = latest_manifests(vec![versioned("b", "1.0.0"), versioned("a", "1.0.0"), versioned("a", "1.2.0-beta")]);
+        let versions: Vec<_> = latest.iter().map(|manifest| (manifest.name.as_str(), manifest.version.as_str())).collect();
+
+        assert_eq!(versions, vec![("a", "1.2.0-beta"), ("b", "1.0.0")]);
+    }
+}
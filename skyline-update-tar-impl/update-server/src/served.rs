@@ -0,0 +1,143 @@
+use std::fs;
+use std::io::{self, prelude::*};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+/// Default size up to which a hosted file is kept in memory, larger files are read from disk
+/// on every download
+pub const MEMORY_CACHE_LIMIT: u64 = 64 * 1024;
+
+/// A file served on the download port. Small files are kept in memory, larger ones stay on
+/// disk and are streamed from it when downloaded, so the server's memory use doesn't grow with
+/// the size of the plugins folder.
+#[derive(Clone)]
+pub enum ServedFile {
+    Memory(Arc<Vec<u8>>),
+    Disk {
+        path: Arc<PathBuf>,
+        /// Size when the file was loaded, which clients are told to expect
+        len: u64,
+    },
+}
+
+impl From<Vec<u8>> for ServedFile {
+    fn from(data: Vec<u8>) -> Self {
+        ServedFile::Memory(Arc::new(data))
+    }
+}
+
+impl ServedFile {
+    /// Serve the file at `path` along with its sha256, from memory if it is at most
+    /// `memory_limit` bytes. Larger files are hashed without reading them into memory.
+    pub fn load(path: &Path, memory_limit: u64) -> io::Result<(ServedFile, String)> {
+        let mut file = fs::File::open(path)?;
+        if file.metadata()?.len() <= memory_limit {
+            let mut data = vec![];
+            file.read_to_end(&mut data)?;
+            let sha256 = crate::manifest::sha256(&data);
+            return Ok((ServedFile::from(data), sha256))
+        }
+
+        let (sha256, len) = crate::manifest::sha256_reader(file)?;
+        Ok((ServedFile::Disk { path: Arc::new(path.to_owned()), len }, sha256))
+    }
+
+    pub fn len(&self) -> u64 {
+        match self {
+            ServedFile::Memory(data) => data.len() as u64,
+            ServedFile::Disk { len, .. } => *len,
+        }
+    }
+
+    /// The whole file, such as to send it inline, read from disk if it isn't kept in memory
+    pub fn read(&self) -> io::Result<Arc<Vec<u8>>> {
+        match self {
+            ServedFile::Memory(data) => Ok(Arc::clone(data)),
+            ServedFile::Disk { .. } => {
+                let mut data = vec![];
+                self.open()?.read_to_end(&mut data)?;
+                Ok(Arc::new(data))
+            }
+        }
+    }
+
+    /// Open the file to send it. A file on disk which changed size since it was loaded is
+    /// refused, as clients were told the old size, until the reload the change causes.
+    pub fn open(&self) -> io::Result<OpenFile> {
+        match self {
+            ServedFile::Memory(data) => Ok(OpenFile {
+                len: data.len() as u64,
+                reader: Box::new(io::Cursor::new(SharedBytes(Arc::clone(data)))),
+            }),
+            ServedFile::Disk { path, len } => {
+                let file = fs::File::open(&**path)?;
+                let current_len = file.metadata()?.len();
+                if current_len != *len {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("{} is {} bytes, {} when it was loaded", path.display(), current_len, len),
+                    ))
+                }
+
+                Ok(OpenFile { len: *len, reader: Box::new(file.take(*len)) })
+            }
+        }
+    }
+}
+
+/// A served file opened for a download. Files on disk stay readable through it when a reload
+/// replaces them, so a download in progress finishes with the file it started with.
+pub struct OpenFile {
+    pub len: u64,
+    reader: Box<dyn Read + Send>,
+}
+
+impl Read for OpenFile {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.reader.read(buf)
+    }
+}
+
+struct SharedBytes(Arc<Vec<u8>>);
+
+impl AsRef<[u8]> for SharedBytes {
+    fn as_ref(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_load() {
+        let dir = std::env::temp_dir().join(format!("update-server-served-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("file.bin");
+        fs::write(&path, vec![0x55; 100]).unwrap();
+
+        let (small, sha256) = ServedFile::load(&path, 100).unwrap();
+        assert!(matches!(small, ServedFile::Memory(_)));
+        assert_eq!(sha256, crate::manifest::sha256(&[0x55; 100]));
+
+        let (large, sha256) = ServedFile::load(&path, 99).unwrap();
+        assert!(matches!(large, ServedFile::Disk { len: 100, .. }));
+        assert_eq!(sha256, crate::manifest::sha256(&[0x55; 100]));
+        assert_eq!(*large.read().unwrap(), vec![0x55; 100]);
+
+        // opened before the file is replaced, as a reload does
+        let mut opened = large.open().unwrap();
+        fs::write(dir.join("new.bin"), vec![0xaa; 100]).unwrap();
+        fs::rename(dir.join("new.bin"), &path).unwrap();
+        let mut data = vec![];
+        opened.read_to_end(&mut data).unwrap();
+        assert_eq!(data, vec![0x55; 100]);
+
+        // a size clients weren't told about
+        fs::write(&path, vec![0x55; 50]).unwrap();
+        assert_eq!(large.open().err().unwrap().kind(), io::ErrorKind::InvalidData);
+
+        let _ = fs::remove_dir_all(dir);
+    }
+}
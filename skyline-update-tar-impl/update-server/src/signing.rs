@@ -0,0 +1,173 @@
+use std::fs;
+use std::io::Write;
+use std::path::Path;
+
+use color_eyre::eyre;
+use ed25519_dalek::{Keypair, PublicKey, SecretKey, Signer};
+use update_protocol::UpdateResponse;
+
+/// File `update-server keygen` writes the private key to, which `UPDATE_SERVER_SIGNING_KEY`
+/// points the server at
+pub const SECRET_KEY_FILE: &str = "signing.key";
+
+/// File `update-server keygen` writes the public key to, 32 raw bytes for plugins to embed with
+/// `include_bytes!`
+pub const PUBLIC_KEY_FILE: &str = "signing.pub";
+
+/// The key every file sent with its SHA-256 is signed with, see `UpdateFile::signature`
+pub struct SigningKey {
+    keypair: Keypair,
+}
+
+impl SigningKey {
+    /// The key for a 32-byte private key
+    pub fn from_secret(secret: &[u8]) -> eyre::Result<Self> {
+        let secret = SecretKey::from_bytes(secret)
+            .map_err(|e| eyre::eyre!("Invalid signing key: {}", e))?;
+        let public = PublicKey::from(&secret);
+
+        Ok(SigningKey { keypair: Keypair { secret, public } })
+    }
+
+    /// Read a private key written by `update-server keygen`
+    pub fn load(path: &Path) -> eyre::Result<Self> {
+        let secret = fs::read(path)
+            .map_err(|e| eyre::eyre!("Failed to read signing key {}: {}", path.display(), e))?;
+        SigningKey::from_secret(&secret)
+    }
+
+    /// The key at `UPDATE_SERVER_SIGNING_KEY`, None if unset
+    pub fn from_env() -> eyre::Result<Option<Self>> {
+        match std::env::var("UPDATE_SERVER_SIGNING_KEY") {
+            Ok(path) if !path.is_empty() => SigningKey::load(Path::new(&path)).map(Some),
+            _ => Ok(None),
+        }
+    }
+
+    pub fn public_key(&self) -> [u8; 32] {
+        self.keypair.public.to_bytes()
+    }
+
+    /// Base64 signature of `message`, as sent in `UpdateFile::signature`
+    pub fn sign(&self, message: &str) -> String {
+        base64::encode(&self.keypair.sign(message.as_bytes()).to_bytes()[..])
+    }
+
+    /// Sign every file of `response` which comes with its hash, see
+    /// `UpdateResponse::signed_message`. Clients from before protocol version 12 only check
+    /// the hash was signed. Files of clients which aren't sent hashes stay unsigned, as there
+    /// is nothing for them to check the signature against.
+    pub fn sign_response(&self, response: &mut UpdateResponse, protocol_version: u32) {
+        let messages: Vec<_> = response.required_files.iter()
+            .map(|file| if protocol_version >= 12 {
+                response.signed_message(file)
+            } else {
+                file.sha256.clone()
+            })
+            .collect();
+
+        for (file, message) in response.required_files.iter_mut().zip(messages) {
+            file.signature = message.map(|message| self.sign(&message));
+        }
+    }
+}
+
+/// `update-server keygen [folder]`: write a new private key and its public key into `folder`,
+/// refusing to replace existing keys
+pub fn run_keygen(dir: &Path) -> eyre::Result<()> {
+    let secret = SecretKey::generate(&mut rand::rngs::OsRng);
+    let key = SigningKey::from_secret(secret.as_bytes())?;
+
+    let secret_path = dir.join(SECRET_KEY_FILE);
+    let public_path = dir.join(PUBLIC_KEY_FILE);
+    for path in [&secret_path, &public_path].iter() {
+        if path.exists() {
+            eyre::bail!("{} already exists, move it away first to replace the keys", path.display());
+        }
+    }
+
+    write_secret(&secret_path, secret.as_bytes())?;
+    fs::write(&public_path, key.public_key())?;
+
+    println!("Wrote the private key to {}, keep it secret", secret_path.display());
+    println!("Wrote the public key to {}", public_path.display());
+    println!("Start the server with UPDATE_SERVER_SIGNING_KEY={} to sign updates", secret_path.display());
+    println!("Plugins check them with skyline_update::check_update_signed(ip, include_bytes!(\"{}\"), ...)", PUBLIC_KEY_FILE);
+
+    Ok(())
+}
+
+/// Write the private key readable by the owner alone, where the platform allows
+fn write_secret(path: &Path, secret: &[u8]) -> eyre::Result<()> {
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+
+    options.open(path)?.write_all(secret)?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use ed25519_dalek::{Signature, Verifier};
+    use update_protocol::UpdateFile;
+    use std::convert::TryFrom;
+
+    #[test]
+    fn test_sign_response() {
+        let key = SigningKey::from_secret(&[7; 32]).unwrap();
+        let file = UpdateFile {
+            install_location: update_protocol::InstallLocation::AbsolutePath("sd:/test.txt".into()),
+            download_index: 0,
+            size: 1,
+            inline_data: None,
+            sha256: Some("ab".repeat(32)),
+            signature: None,
+        };
+        let unsigned = UpdateResponse {
+            plugin_name: "test_plugin".into(),
+            new_plugin_version: "1.0.0".into(),
+            required_files: vec![file.clone(), UpdateFile { sha256: None, ..file }],
+            ..Default::default()
+        };
+        let public = PublicKey::from_bytes(&key.public_key()).unwrap();
+        let signature = |file: &UpdateFile| {
+            let signature = base64::decode(file.signature.as_ref().unwrap()).unwrap();
+            Signature::try_from(&signature[..]).unwrap()
+        };
+
+        let mut response = unsigned.clone();
+        key.sign_response(&mut response, update_protocol::PROTOCOL_VERSION);
+        let message = response.signed_message(&response.required_files[0]).unwrap();
+        assert!(public.verify(message.as_bytes(), &signature(&response.required_files[0])).is_ok());
+        assert!(public.verify("ab".repeat(32).as_bytes(), &signature(&response.required_files[0])).is_err());
+        assert_eq!(response.required_files[1].signature, None);
+
+        // older clients check the signature against the hash alone
+        let mut response = unsigned;
+        key.sign_response(&mut response, 11);
+        assert!(public.verify("ab".repeat(32).as_bytes(), &signature(&response.required_files[0])).is_ok());
+        assert!(public.verify("cd".repeat(32).as_bytes(), &signature(&response.required_files[0])).is_err());
+        assert_eq!(response.required_files[1].signature, None);
+    }
+
+
+    #[test]
+    fn test_keygen() {
+        let dir = std::env::temp_dir().join(format!("update-server-keygen-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+
+        run_keygen(&dir).unwrap();
+        let key = SigningKey::load(&dir.join(SECRET_KEY_FILE)).unwrap();
+        assert_eq!(fs::read(dir.join(PUBLIC_KEY_FILE)).unwrap(), key.public_key());
+
+        // existing keys are never replaced
+        assert!(run_keygen(&dir).is_err());
+
+        let _ = fs::remove_dir_all(dir);
+    }
+}
@@ -0,0 +1,183 @@
+use std::any::Any;
+use std::collections::VecDeque;
+use std::panic::{self, AssertUnwindSafe};
+use std::time::{Duration, Instant};
+
+use color_eyre::eyre;
+
+/// How a supervised task is restarted when it panics or returns an error
+#[derive(Debug, Clone, Copy)]
+pub struct RestartPolicy {
+    /// Delay before the first restart, doubled for every restart still inside `window`
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+    /// Give up once the task has crashed more than this many times within `window`
+    pub max_restarts: usize,
+    pub window: Duration,
+}
+
+impl Default for RestartPolicy {
+    fn default() -> Self {
+        RestartPolicy {
+            initial_backoff: Duration::from_millis(500),
+            max_backoff: Duration::from_secs(30),
+            max_restarts: 5,
+            window: Duration::from_secs(5 * 60),
+        }
+    }
+}
+
+/// A task which crashed too often to keep restarting
+#[derive(Debug)]
+pub struct Escalation {
+    pub task: String,
+    pub crashes: usize,
+    pub last_error: String,
+}
+
+impl std::fmt::Display for Escalation {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "task '{}' crashed {} times, last with: {}", self.task, self.crashes, self.last_error)
+    }
+}
+
+fn panic_message(payload: &(dyn Any + Send)) -> String {
+    if let Some(message) = payload.downcast_ref::<&str>() {
+        format!("panic: {}", message)
+    } else if let Some(message) = payload.downcast_ref::<String>() {
+        format!("panic: {}", message)
+    } else {
+        String::from("panic")
+    }
+}
+
+/// Run `task` until it returns `Ok`, restarting it with backoff whenever it panics or returns
+/// an error. Anything the task sets up (listeners, watchers) should be created inside it, so
+/// a restart starts from scratch. Returns an `Escalation` once the task crashes more than
+/// `policy.max_restarts` times within `policy.window`.
+pub fn supervise<F>(name: &str, policy: &RestartPolicy, mut task: F) -> Result<(), Escalation>
+    where F: FnMut() -> eyre::Result<()>
+{
+    let mut crashes: VecDeque<Instant> = VecDeque::new();
+
+    loop {
+        let error = match panic::catch_unwind(AssertUnwindSafe(&mut task)) {
+            Ok(Ok(())) => return Ok(()),
+            Ok(Err(e)) => format!("error: {}", e),
+            Err(payload) => panic_message(&*payload),
+        };
+
+        let now = Instant::now();
+        while crashes.front().map(|&crash| now.duration_since(crash) > policy.window).unwrap_or(false) {
+            crashes.pop_front();
+        }
+        crashes.push_back(now);
+
+        if crashes.len() > policy.max_restarts {
+            return Err(Escalation { task: name.to_owned(), crashes: crashes.len(), last_error: error })
+        }
+
+        let backoff = policy.initial_backoff
+            .checked_mul(1u32 << (crashes.len() - 1).min(16))
+            .unwrap_or(policy.max_backoff)
+            .min(policy.max_backoff);
+        println!("Task '{}' crashed ({}), restarting in {:?}", name, error, backoff);
+        std::thread::sleep(backoff);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::net::{TcpListener, TcpStream};
+
+    fn fast_policy(max_restarts: usize) -> RestartPolicy {
+        RestartPolicy {
+            initial_backoff: Duration::from_millis(1),
+            max_backoff: Duration::from_millis(4),
+            max_restarts,
+            window: Duration::from_secs(60),
+        }
+    }
+
+    #[test]
+    fn test_restart_after_panic() {
+        let mut runs = 0;
+        let result = supervise("flaky", &fast_policy(5), || {
+            runs += 1;
+            if runs < 3 {
+                panic!("deliberate panic {}", runs);
+            }
+            Ok(())
+        });
+
+        assert!(result.is_ok());
+        assert_eq!(runs, 3);
+    }
+
+    #[test]
+    fn test_restart_after_error() {
+        let mut runs = 0;
+        let result = supervise("failing", &fast_policy(5), || {
+            runs += 1;
+            if runs == 1 {
+                eyre::bail!("deliberate error");
+            }
+            Ok(())
+        });
+
+        assert!(result.is_ok());
+        assert_eq!(runs, 2);
+    }
+
+    #[test]
+    fn test_escalate_repeated_crashes() {
+        let mut runs = 0;
+        let escalation = supervise("broken", &fast_policy(3), || -> eyre::Result<()> {
+            runs += 1;
+            panic!("always panics");
+        }).unwrap_err();
+
+        // the first run plus 3 restarts
+        assert_eq!(runs, 4);
+        assert_eq!(escalation.task, "broken");
+        assert_eq!(escalation.crashes, 4);
+        assert_eq!(escalation.last_error, "panic: always panics");
+    }
+
+    #[test]
+    fn test_crashes_outside_window_forgiven() {
+        let policy = RestartPolicy { window: Duration::from_millis(0), ..fast_policy(1) };
+
+        let mut runs = 0;
+        let result = supervise("occasional", &policy, || {
+            runs += 1;
+            if runs < 5 {
+                panic!("crash {}", runs);
+            }
+            Ok(())
+        });
+
+        assert!(result.is_ok());
+    }
+
+    #[test]
+    fn test_listener_rebound_on_restart() {
+        let port = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+
+        let mut runs = 0;
+        let result = supervise("acceptor", &fast_policy(5), || -> eyre::Result<()> {
+            runs += 1;
+            let listener = TcpListener::bind(("127.0.0.1", port))?;
+            let _client = TcpStream::connect(("127.0.0.1", port))?;
+            listener.accept()?;
+            if runs < 3 {
+                panic!("crash while holding the listener");
+            }
+            Ok(())
+        });
+
+        assert!(result.is_ok());
+        assert_eq!(runs, 3);
+    }
+}
@@ -0,0 +1,167 @@
+use std::io::{self, prelude::*};
+use std::net::{Shutdown, TcpStream};
+use std::time::Duration;
+
+use color_eyre::eyre;
+
+/// An accepted connection to the main or download port, wrapped in TLS if the server was given
+/// a certificate
+pub enum Socket {
+    Plain(TcpStream),
+    #[cfg(feature = "tls")]
+    Tls(Box<rustls::StreamOwned<rustls::ServerConnection, TcpStream>>),
+}
+
+impl Socket {
+    fn tcp(&self) -> &TcpStream {
+        match self {
+            Socket::Plain(socket) => socket,
+            #[cfg(feature = "tls")]
+            Socket::Tls(stream) => &stream.sock,
+        }
+    }
+
+    pub fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.tcp().set_read_timeout(timeout)
+    }
+
+    pub fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.tcp().set_write_timeout(timeout)
+    }
+
+    /// Close the connection, telling a TLS client first so it can tell the end of a download
+    /// from a truncated one
+    pub fn shutdown(&mut self) -> io::Result<()> {
+        match self {
+            Socket::Plain(socket) => socket.shutdown(Shutdown::Both),
+            #[cfg(feature = "tls")]
+            Socket::Tls(stream) => {
+                stream.conn.send_close_notify();
+                while stream.conn.wants_write() {
+                    if stream.conn.write_tls(&mut stream.sock).is_err() {
+                        break
+                    }
+                }
+                stream.sock.shutdown(Shutdown::Both)
+            }
+        }
+    }
+}
+
+impl Read for Socket {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Socket::Plain(socket) => socket.read(buf),
+            #[cfg(feature = "tls")]
+            Socket::Tls(stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for Socket {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Socket::Plain(socket) => socket.write(buf),
+            #[cfg(feature = "tls")]
+            Socket::Tls(stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Socket::Plain(socket) => socket.flush(),
+            #[cfg(feature = "tls")]
+            Socket::Tls(stream) => stream.flush(),
+        }
+    }
+}
+
+/// Wraps the connections accepted on both ports, in TLS when the server was started with
+/// `--tls-cert` and `--tls-key`, as plaintext otherwise
+#[derive(Clone, Default)]
+pub struct Acceptor {
+    #[cfg(feature = "tls")]
+    config: Option<std::sync::Arc<rustls::ServerConfig>>,
+}
+
+impl Acceptor {
+    /// Serve TLS with the PEM certificate chain at `cert` and private key at `key`, or
+    /// plaintext if neither is given
+    pub fn new(cert: Option<&str>, key: Option<&str>) -> eyre::Result<Self> {
+        match (cert, key) {
+            (None, None) => Ok(Acceptor::default()),
+            (Some(cert), Some(key)) => Acceptor::load(cert, key),
+            _ => eyre::bail!("--tls-cert and --tls-key must be given together"),
+        }
+    }
+
+    #[cfg(feature = "tls")]
+    fn load(cert: &str, key: &str) -> eyre::Result<Self> {
+        use std::fs::File;
+        use std::io::BufReader;
+
+        let certs: Vec<_> = rustls_pemfile::certs(&mut BufReader::new(File::open(cert)?))?
+            .into_iter()
+            .map(rustls::Certificate)
+            .collect();
+        if certs.is_empty() {
+            eyre::bail!("No certificate found in {}", cert);
+        }
+
+        let mut keys = BufReader::new(File::open(key)?);
+        let key = loop {
+            match rustls_pemfile::read_one(&mut keys)? {
+                Some(rustls_pemfile::Item::PKCS8Key(key))
+                | Some(rustls_pemfile::Item::RSAKey(key))
+                | Some(rustls_pemfile::Item::ECKey(key)) => break rustls::PrivateKey(key),
+                Some(_) => continue,
+                None => eyre::bail!("No private key found in {}", key),
+            }
+        };
+
+        let config = rustls::ServerConfig::builder()
+            .with_safe_defaults()
+            .with_no_client_auth()
+            .with_single_cert(certs, key)?;
+
+        Ok(Acceptor { config: Some(std::sync::Arc::new(config)) })
+    }
+
+    #[cfg(not(feature = "tls"))]
+    fn load(_cert: &str, _key: &str) -> eyre::Result<Self> {
+        eyre::bail!("This server was built without TLS support, rebuild it with `--features tls`")
+    }
+
+    pub fn is_tls(&self) -> bool {
+        #[cfg(feature = "tls")]
+        return self.config.is_some();
+        #[cfg(not(feature = "tls"))]
+        return false;
+    }
+
+    /// Wrap an accepted connection. The TLS handshake happens on the first read or write.
+    pub fn accept(&self, socket: TcpStream) -> io::Result<Socket> {
+        #[cfg(feature = "tls")]
+        if let Some(config) = &self.config {
+            let conn = rustls::ServerConnection::new(std::sync::Arc::clone(config))
+                .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+            return Ok(Socket::Tls(Box::new(rustls::StreamOwned::new(conn, socket))))
+        }
+
+        Ok(Socket::Plain(socket))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_acceptor_args() {
+        assert!(!Acceptor::new(None, None).unwrap().is_tls());
+        assert!(Acceptor::new(Some("cert.pem"), None).is_err());
+        assert!(Acceptor::new(None, Some("key.pem")).is_err());
+        // missing files are reported, and without the feature so is asking for TLS at all
+        assert!(Acceptor::new(Some("/nonexistent/cert.pem"), Some("/nonexistent/key.pem")).is_err());
+    }
+}
@@ -0,0 +1,199 @@
+use std::fs;
+use std::path::Path;
+
+use color_eyre::eyre;
+
+use crate::hosted_plugins::{self, Limits, PluginToml};
+use crate::manifest;
+
+/// Metadata images of the plugin in `dir` larger than the limit, and metadata files which can't
+/// be read, as warnings
+fn metadata_warnings(plugin: &PluginToml, dir: &Path, limits: &Limits) -> Vec<String> {
+    let images = || plugin.metadata.iter().flat_map(|metadata| metadata.images.iter().flatten());
+    let changelog = plugin.metadata.iter().flat_map(|metadata| &metadata.changelog);
+    let missing = images().chain(changelog)
+        .map(|file| dir.join(file))
+        .filter(|path| !path.is_file())
+        .map(|path| format!("metadata file {} can't be read and is left out", path.display()));
+
+    missing.chain(images()
+        .filter_map(|path| {
+            let path = dir.join(path);
+            let size = fs::metadata(&path).ok()?.len() as usize;
+            if size > limits.max_image_size {
+                Some(format!(
+                    "metadata image {} is {} KB, more than the limit of {} KB",
+                    path.display(), size / 1024, limits.max_image_size / 1024
+                ))
+            } else {
+                None
+            }
+        }))
+        .collect()
+}
+
+/// The plugin's name, followed by its display name if it has one
+fn plugin_names(plugin: &PluginToml) -> String {
+    match &plugin.display_name {
+        Some(display_name) => format!("{} ({:?})", plugin.name, display_name),
+        None => plugin.name.clone(),
+    }
+}
+
+/// A display name which changes nothing, as a warning
+fn redundant_display_name(plugin: &PluginToml) -> Option<String> {
+    plugin.display_name.as_ref()
+        .filter(|display_name| **display_name == plugin.name)
+        .map(|_| "display_name is the same as name and can be left out".to_owned())
+}
+
+/// Platforms no client is known to send, and entries only sent to emulators with nothing
+/// installed in their place on the console, as warnings
+fn platform_warnings(plugin: &PluginToml) -> Vec<String> {
+    let entries: Vec<(&str, &[String])> = plugin.files.iter()
+        .map(|file| (&file.install_location, &file.platform))
+        .chain(plugin.folders.iter().flatten().map(|folder| (&folder.install_root_location, &folder.platform)))
+        .filter_map(|(location, platform)| Some((hosted_plugins::location_path(location)?, platform.as_deref()?)))
+        .collect();
+    let marked_for = |path: &str, platform: &str| entries.iter()
+        .any(|&(other, platforms)| other == path && platforms.iter().any(|marked| marked == platform));
+
+    let mut warnings = vec![];
+    for &(path, platforms) in &entries {
+        for platform in platforms.iter().filter(|platform| !update_protocol::PLATFORMS.contains(&platform.as_str())) {
+            warnings.push(format!(
+                "'{}' is marked for unknown platform '{}', known platforms are {}",
+                path, platform, update_protocol::PLATFORMS.join(", ")
+            ));
+        }
+        if platforms.iter().any(|platform| platform == "emulator") && !marked_for(path, "switch") {
+            warnings.push(format!("'{}' is only installed on emulators, with no switch counterpart", path));
+        }
+    }
+
+    warnings
+}
+
+/// Check a plugin directory the way the server would load it, without building any archives.
+/// Returns the names and warnings of a plugin which would load.
+fn validate_plugin(dir: &Path, limits: &Limits) -> eyre::Result<(String, Vec<String>)> {
+    let plugin: PluginToml = toml::from_str(&fs::read_to_string(dir.join("plugin.toml"))?)?;
+    hosted_plugins::check_limits(&plugin, limits)?;
+    hosted_plugins::check_platform_case_collisions(&plugin, dir)?;
+    hosted_plugins::check_deleted_files(&plugin)?;
+    manifest::scan(dir)?;
+
+    let mut warnings = metadata_warnings(&plugin, dir, limits);
+    warnings.extend(redundant_display_name(&plugin));
+    warnings.extend(platform_warnings(&plugin));
+    warnings.extend(crate::versions::describe(
+        &plugin.version,
+        update_protocol::requested_channel(plugin.beta.unwrap_or(false), plugin.channel.as_deref()) != update_protocol::STABLE_CHANNEL,
+        plugin.version_ordering,
+        plugin.prerelease_on_stable,
+    ));
+
+    Ok((plugin_names(&plugin), warnings))
+}
+
+/// `update-server validate`: report every plugin which would fail to load, and anything
+/// worth fixing in the ones that would. Fails if any plugin would fail to load.
+pub fn run_validate(plugins_dir: &Path) -> eyre::Result<()> {
+    let limits = Limits::from_env();
+
+    let mut dirs: Vec<_> = fs::read_dir(plugins_dir)?
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.path())
+        .filter(|path| path.is_dir())
+        .collect();
+    dirs.sort();
+
+    let mut errors = 0;
+    for dir in dirs {
+        match validate_plugin(&dir, &limits) {
+            Ok((names, warnings)) if warnings.is_empty() => println!("{}: {} ok", dir.display(), names),
+            Ok((names, warnings)) => {
+                println!("{}: {} ok, with warnings", dir.display(), names);
+                for warning in warnings {
+                    println!("    warning: {}", warning);
+                }
+            }
+            Err(e) => {
+                errors += 1;
+                println!("{}: error: {}", dir.display(), e);
+            }
+        }
+    }
+
+    if errors > 0 {
+        eyre::bail!("{} plugin(s) would fail to load", errors);
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_metadata_warnings() {
+        let dir = std::env::temp_dir().join(format!("update-server-validate-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("icon.png"), vec![0; 100]).unwrap();
+        fs::write(dir.join("screenshot.png"), vec![0; 4096]).unwrap();
+
+        // relative to the plugin folder, as the server reads them
+        let plugin: PluginToml = toml::from_str(
+            "version = \"1.0.0\"\nname = \"test\"\nfiles = []\n[metadata]\nimages = [\"icon.png\", \"screenshot.png\"]\nchangelog = \"CHANGELOG.md\"\n"
+        ).unwrap();
+        let limits = Limits { max_files: 10, max_response_size: 1024, max_image_size: 1024, memory_cache_limit: 0 };
+
+        let warnings = metadata_warnings(&plugin, &dir, &limits);
+        assert_eq!(warnings.len(), 2);
+        assert!(warnings[0].contains("CHANGELOG.md"));
+        assert!(warnings[1].contains("screenshot.png"));
+
+        let _ = fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn test_display_name() {
+        let plugin = |display_name: &str| -> PluginToml {
+            toml::from_str(&format!("version = \"1.0.0\"\nname = \"HDR-Release\"\n{}files = []\n", display_name)).unwrap()
+        };
+
+        let named = plugin("display_name = \"HewDraw Remix\"\n");
+        assert_eq!(plugin_names(&named), "HDR-Release (\"HewDraw Remix\")");
+        assert_eq!(redundant_display_name(&named), None);
+
+        assert_eq!(plugin_names(&plugin("")), "HDR-Release");
+        assert_eq!(redundant_display_name(&plugin("")), None);
+        assert!(redundant_display_name(&plugin("display_name = \"HDR-Release\"\n")).is_some());
+    }
+
+    #[test]
+    fn test_platform_warnings() {
+        let plugin: PluginToml = toml::from_str(r#"
+            version = "1.0.0"
+            name = "test"
+            files = [
+                { install_location = "sd:/plugin.nro", filename = "switch.nro", platform = ["switch"] },
+                { install_location = "sd:/plugin.nro", filename = "emulator.nro", platform = ["emulator"] },
+                { install_location = "sd:/config.toml", filename = "config.toml" },
+                { install_location = "sd:/emulator.toml", filename = "emulator.toml", platform = ["emulator", "ps4"] },
+            ]
+
+            [[folders]]
+            install_root_location = "sd:/ultimate/mods/base"
+            root_name = "base"
+            platform = ["emulator"]
+        "#).unwrap();
+
+        let warnings = platform_warnings(&plugin);
+        assert_eq!(warnings.len(), 3, "{:?}", warnings);
+        assert!(warnings[0].contains("'ps4'"), "{}", warnings[0]);
+        assert!(warnings[1].contains("sd:/emulator.toml"), "{}", warnings[1]);
+        assert!(warnings[2].contains("sd:/ultimate/mods/base"), "{}", warnings[2]);
+    }
+}
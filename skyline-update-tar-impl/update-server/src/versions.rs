@@ -0,0 +1,131 @@
+use std::cmp::Ordering;
+
+use semver::Version;
+use serde::{Serialize, Deserialize};
+
+/// How the versions of a plugin are compared, set with `version_ordering` in plugin.toml
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum VersionOrdering {
+    /// Plain semver: `1.2.0-beta.1 < 1.2.0` and build metadata is ignored, so `1.2.0+nightly`
+    /// is the same version as `1.2.0`
+    Semver,
+    /// Semver, with versions which only differ in build metadata ordered by their build
+    /// metadata compared as text, and no build metadata before any. Meant for nightly builds
+    /// versioned like `1.2.0+nightly.20240101`.
+    SemverWithBuild,
+}
+
+impl Default for VersionOrdering {
+    fn default() -> Self {
+        VersionOrdering::Semver
+    }
+}
+
+fn build_string(version: &Version) -> String {
+    version.build.iter()
+        .map(ToString::to_string)
+        .collect::<Vec<_>>()
+        .join(".")
+}
+
+pub fn compare(a: &Version, b: &Version, ordering: VersionOrdering) -> Ordering {
+    match (a.cmp(b), ordering) {
+        (Ordering::Equal, VersionOrdering::SemverWithBuild) => build_string(a).cmp(&build_string(b)),
+        (order, _) => order,
+    }
+}
+
+/// Whether a version is only offered to clients asking for betas: versions of beta plugins,
+/// and pre-releases unless the plugin allows them on the stable channel
+pub fn beta_only(version: &Version, beta: bool, prerelease_on_stable: bool) -> bool {
+    beta || (version.is_prerelease() && !prerelease_on_stable)
+}
+
+/// Describe the policy applied to a plugin's versions, for `update-server validate`
+pub fn describe(version: &Version, beta: bool, ordering: VersionOrdering, prerelease_on_stable: bool) -> Vec<String> {
+    let mut notes = vec![];
+
+    match ordering {
+        VersionOrdering::Semver if !version.build.is_empty() => notes.push(format!(
+            "build metadata '{}' is ignored when comparing versions, set version_ordering = \"semver_with_build\" to order builds",
+            build_string(version)
+        )),
+        VersionOrdering::SemverWithBuild => notes.push(String::from(
+            "versions which only differ in build metadata are ordered by their build metadata"
+        )),
+        _ => {}
+    }
+
+    if version.is_prerelease() && !beta {
+        if prerelease_on_stable {
+            notes.push(format!("pre-release {} is offered on the stable channel", version));
+        } else {
+            notes.push(format!("pre-release {} is only offered on the beta channel", version));
+        }
+    }
+
+    notes
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn version(version: &str) -> Version {
+        version.parse().unwrap()
+    }
+
+    #[test]
+    fn test_compare() {
+        use VersionOrdering::*;
+        use Ordering::*;
+
+        let cases = [
+            // pre-release vs release
+            ("1.2.0-beta.1", "1.2.0", Semver, Less),
+            ("1.2.0-beta.1", "1.2.0", SemverWithBuild, Less),
+            ("1.2.0-beta.2", "1.2.0-beta.1", Semver, Greater),
+            ("1.2.0-beta.1", "1.1.0", Semver, Greater),
+            // build metadata ties
+            ("1.2.0+nightly.20240101", "1.2.0", Semver, Equal),
+            ("1.2.0+nightly.20240101", "1.2.0", SemverWithBuild, Greater),
+            ("1.2.0+nightly.20240102", "1.2.0+nightly.20240101", Semver, Equal),
+            ("1.2.0+nightly.20240102", "1.2.0+nightly.20240101", SemverWithBuild, Greater),
+            ("1.2.0+nightly.20240101", "1.2.0+nightly.20240101", SemverWithBuild, Equal),
+            // build metadata never outweighs the version itself
+            ("1.2.0+nightly.20240101", "1.2.1", SemverWithBuild, Less),
+            ("1.2.0-beta.1+nightly.2", "1.2.0+nightly.1", SemverWithBuild, Less),
+        ];
+
+        for &(a, b, ordering, expected) in &cases {
+            assert_eq!(compare(&version(a), &version(b), ordering), expected, "{} {} {:?}", a, b, ordering);
+            assert_eq!(compare(&version(b), &version(a), ordering), expected.reverse(), "{} {} {:?}", b, a, ordering);
+        }
+    }
+
+    #[test]
+    fn test_beta_only() {
+        let cases = [
+            // version, beta plugin, prerelease_on_stable, beta only
+            ("1.2.0", false, false, false),
+            ("1.2.0", true, false, true),
+            ("1.2.0-beta.1", false, false, true),
+            ("1.2.0-beta.1", false, true, false),
+            ("1.2.0-beta.1", true, true, true),
+            ("1.2.0+nightly.1", false, false, false),
+        ];
+
+        for &(v, beta, prerelease_on_stable, expected) in &cases {
+            assert_eq!(beta_only(&version(v), beta, prerelease_on_stable), expected, "{} {} {}", v, beta, prerelease_on_stable);
+        }
+    }
+
+    #[test]
+    fn test_describe() {
+        assert!(describe(&version("1.2.0"), false, VersionOrdering::Semver, false).is_empty());
+        assert_eq!(describe(&version("1.2.0+nightly.1"), false, VersionOrdering::Semver, false).len(), 1);
+        assert_eq!(describe(&version("1.2.0-beta.1"), false, VersionOrdering::SemverWithBuild, false).len(), 2);
+        assert!(describe(&version("1.2.0-beta.1"), true, VersionOrdering::Semver, false).is_empty());
+    }
+}
@@ -0,0 +1,44 @@
+//! Runs the async client of skyline-update against the `host` example of this crate, the way
+//! `examples.rs` does for the blocking client.
+
+#[path = "../examples/host/launch.rs"]
+mod launch;
+
+use std::fs;
+use std::path::Path;
+
+use skyline_update::{SandboxInstaller, UpdateClient};
+
+#[tokio::test]
+async fn test_async_client() {
+    let host = launch::launch(Path::new(env!("CARGO_BIN_EXE_update-server"))).unwrap();
+    let sandbox = std::env::temp_dir().join(format!("update-server-async-sd-{}", std::process::id()));
+    let _ = fs::remove_dir_all(&sandbox);
+
+    let client = UpdateClient::new("127.0.0.1".parse().unwrap()).with_port(host.port);
+    let installer = SandboxInstaller::new(&sandbox);
+
+    let info = client.get_update_info_async("example_loose_files", "0.9.0", false).await.unwrap();
+    assert_eq!(info.code, update_protocol::ResponseCode::Update);
+
+    for name in &["example_loose_files", "example_folder"] {
+        let status = client.try_custom_check_update_async(name, "0.9.0", false, &installer).await;
+        assert_eq!(status.map(|status| status.is_installed()), Ok(true), "{}", name);
+    }
+
+    let fixtures = launch::fixtures();
+    let installed = |path: &str| fs::read(sandbox.join(path)).unwrap();
+    assert_eq!(
+        installed("atmosphere/contents/01006A800016E000/romfs/skyline/plugins/libexample_loose_files.nro"),
+        fs::read(fixtures.join("loose_files/libexample_loose_files.nro")).unwrap()
+    );
+    assert_eq!(
+        installed("ultimate/mods/example-romfs/example-romfs/ui/message/msg_example.txt"),
+        fs::read(fixtures.join("folder_archive/example-romfs/ui/message/msg_example.txt")).unwrap()
+    );
+
+    let up_to_date = client.get_update_info_async("example_folder", "1.0.0", false).await.unwrap();
+    assert_eq!(up_to_date.code, update_protocol::ResponseCode::NoUpdate);
+
+    let _ = fs::remove_dir_all(sandbox);
+}
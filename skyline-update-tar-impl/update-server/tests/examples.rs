@@ -0,0 +1,50 @@
+//! Runs the `host` example of this crate and the `client` example of skyline-update against each
+//! other, so the examples keep working.
+
+#[path = "../examples/host/launch.rs"]
+mod launch;
+
+#[path = "../../skyline-update/examples/client/walkthrough.rs"]
+mod walkthrough;
+
+use std::fs;
+use std::path::Path;
+
+use skyline_update::UpdateClient;
+
+#[test]
+fn test_example_walkthrough() {
+    let host = launch::launch(Path::new(env!("CARGO_BIN_EXE_update-server"))).unwrap();
+    let sandbox = std::env::temp_dir().join(format!("update-server-example-sd-{}", std::process::id()));
+    let _ = fs::remove_dir_all(&sandbox);
+
+    let client = UpdateClient::new("127.0.0.1".parse().unwrap()).with_port(host.port);
+    let reports = walkthrough::run(&client, &sandbox);
+
+    let statuses: Vec<_> = reports.iter().map(|(name, report)| (name.as_str(), report.status().map(|status| status.is_installed()))).collect();
+    assert_eq!(statuses, vec![
+        ("example_loose_files", Ok(true)),
+        ("example_folder", Ok(true)),
+    ]);
+
+    let fixtures = launch::fixtures();
+    let installed = |path: &str| fs::read(sandbox.join(path)).unwrap();
+    assert_eq!(
+        installed("atmosphere/contents/01006A800016E000/romfs/skyline/plugins/libexample_loose_files.nro"),
+        fs::read(fixtures.join("loose_files/libexample_loose_files.nro")).unwrap()
+    );
+    assert_eq!(
+        installed("example_loose_files/config.toml"),
+        fs::read(fixtures.join("loose_files/config.toml")).unwrap()
+    );
+    assert_eq!(
+        installed("ultimate/mods/example-romfs/example-romfs/ui/message/msg_example.txt"),
+        fs::read(fixtures.join("folder_archive/example-romfs/ui/message/msg_example.txt")).unwrap()
+    );
+
+    // the versions served are now installed
+    let up_to_date = client.get_update_info("example_folder", "1.0.0", false).unwrap();
+    assert_eq!(up_to_date.code, update_protocol::ResponseCode::NoUpdate);
+
+    let _ = fs::remove_dir_all(sandbox);
+}
@@ -0,0 +1,94 @@
+//! Runs the `host` example of this crate over TLS with a self-signed certificate. Only built
+//! with the `tls` feature: `cargo test -p update-server --features tls --test tls`.
+
+// only `launch_with_args` is used here, every server is started with a certificate
+#[allow(dead_code)]
+#[path = "../examples/host/launch.rs"]
+mod launch;
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use skyline_update::{SandboxInstaller, TlsConfig, UpdateClient, UpdateError};
+
+/// A certificate for `localhost` written next to its key into a new folder, returned along
+/// with the certificate's PEM
+fn self_signed(name: &str) -> (PathBuf, String) {
+    let dir = std::env::temp_dir().join(format!("update-server-tls-{}-{}", name, std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+
+    let certificate = rcgen::generate_simple_self_signed(vec![String::from("localhost")]).unwrap();
+    let pem = certificate.serialize_pem().unwrap();
+    fs::write(dir.join("cert.pem"), &pem).unwrap();
+    fs::write(dir.join("key.pem"), certificate.serialize_private_key_pem()).unwrap();
+
+    (dir, pem)
+}
+
+fn launch_tls(dir: &Path) -> launch::Host {
+    let cert = dir.join("cert.pem");
+    let key = dir.join("key.pem");
+    launch::launch_with_args(
+        Path::new(env!("CARGO_BIN_EXE_update-server")),
+        &["--tls-cert", cert.to_str().unwrap(), "--tls-key", key.to_str().unwrap()],
+    ).unwrap()
+}
+
+#[test]
+fn test_tls_install() {
+    let (dir, pem) = self_signed("install");
+    let host = launch_tls(&dir);
+    let sandbox = dir.join("sd");
+
+    // both the request and every download go over TLS
+    let client = UpdateClient::with_host("127.0.0.1")
+        .with_port(host.port)
+        .with_tls(TlsConfig::new("localhost").with_pinned_certificate(pem.as_bytes()));
+    let status = client.try_custom_check_update("example_folder", "0.9.0", false, &SandboxInstaller::new(&sandbox));
+    assert!(status.unwrap().is_installed());
+    assert!(sandbox.join("ultimate/mods/example-romfs/example-romfs/ui/message/msg_example.txt").exists());
+
+    drop(host);
+    let _ = fs::remove_dir_all(dir);
+}
+
+#[test]
+fn test_tls_wrong_server_name() {
+    let (dir, pem) = self_signed("server-name");
+    let host = launch_tls(&dir);
+
+    // the certificate is trusted, but isn't valid for the name asked for
+    let client = UpdateClient::with_host("127.0.0.1")
+        .with_port(host.port)
+        .with_tls(TlsConfig::new("updates.example.com").with_pinned_certificate(pem.as_bytes()));
+    match client.try_check_update("example_folder", "0.9.0", false) {
+        Err(UpdateError::Tls(_)) => {}
+        other => panic!("expected a failed handshake, got {:?}", other),
+    }
+
+    drop(host);
+    let _ = fs::remove_dir_all(dir);
+}
+
+#[test]
+fn test_tls_untrusted_certificate() {
+    let (dir, _) = self_signed("untrusted");
+    let host = launch_tls(&dir);
+
+    // a self-signed certificate isn't issued by any of the webpki roots
+    let client = UpdateClient::with_host("127.0.0.1")
+        .with_port(host.port)
+        .with_tls(TlsConfig::new("localhost"));
+    match client.try_check_update("example_folder", "0.9.0", false) {
+        Err(UpdateError::Tls(_)) => {}
+        other => panic!("expected a failed handshake, got {:?}", other),
+    }
+
+    // nor does a plaintext client get anything out of the server
+    let plaintext = UpdateClient::with_host("127.0.0.1").with_port(host.port);
+    assert!(plaintext.try_check_update("example_folder", "0.9.0", false).is_err());
+
+    drop(host);
+    let _ = fs::remove_dir_all(dir);
+}
@@ -2,6 +2,11 @@ use std::fmt;
 use serde::{Serializer, Deserializer};
 use serde::{Serialize, Deserialize, de::{self, Visitor}};
 
+/// Sent instead of a download index as the first 8 bytes of a download connection to keep it
+/// open for several files. Every index sent after it is answered with the file's 8-byte big
+/// endian length, then the file, until the client closes the connection.
+pub const KEEP_ALIVE_INDEX: u64 = u64::MAX;
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct VersionInfo {
     pub plugin_name: String,
@@ -33,6 +38,12 @@ pub struct UpdateResponse {
     pub new_plugin_version: String,
     pub new_skyline_version: Option<String>,
     pub required_files: Vec<UpdateFile>,
+    /// Whether download connections opened with `KEEP_ALIVE_INDEX` are kept open
+    #[serde(default)]
+    pub keep_alive_downloads: bool,
+    /// Absolute paths of files earlier versions installed which this version no longer has
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub files_to_delete: Vec<String>,
 }
 
 impl UpdateResponse {
@@ -74,9 +85,11 @@ pub struct UpdateFile {
 }
 
 #[non_exhaustive]
-#[derive(Serialize, Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
 pub struct UpdateRequestOptions {
-
+    /// Version of the skyline loader the client is running, if known
+    #[serde(default)]
+    pub skyline_version: Option<String>,
 }
 
 #[non_exhaustive]
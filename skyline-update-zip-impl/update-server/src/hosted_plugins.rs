@@ -44,6 +44,10 @@ pub struct PluginToml {
     pub skyline_version: Option<Version>,
 
     pub metadata: Option<TomlMetadata>,
+
+    /// Absolute paths of files earlier versions installed, which clients delete on updating
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub deleted_files: Vec<String>,
 }
 
 mod version_parse {
@@ -109,9 +113,10 @@ pub struct Plugin {
     pub name: String,
     pub plugin_version: Version,
     pub files: Vec<(InstallLocation, Vec<u8>)>,
-    pub skyline_version: Version,
+    pub skyline_version: Option<Version>,
     pub beta: bool,
     pub metadata: Metadata,
+    pub deleted_files: Vec<String>,
 }
 
 fn to_file(PluginFile { install_location, filename }: PluginFile, dir: &Path) -> eyre::Result<(InstallLocation, Vec<u8>)> {
@@ -133,7 +138,7 @@ pub fn folder_to_plugin(dir: io::Result<fs::DirEntry>) -> eyre::Result<Option<Pl
 
     let plugin: PluginToml = toml::from_str(&fs::read_to_string(toml_path)?)?;
 
-    let PluginToml { version, name, files, folders, skyline_version, beta, metadata } =  plugin;
+    let PluginToml { version, name, files, folders, skyline_version, beta, metadata, deleted_files } =  plugin;
 
     let mut files: Vec<(InstallLocation, Vec<u8>)> = files.into_iter().map(|file| to_file(file, &path)).collect::<eyre::Result<_>>()?;
 
@@ -199,9 +204,10 @@ pub fn folder_to_plugin(dir: io::Result<fs::DirEntry>) -> eyre::Result<Option<Pl
         name,
         plugin_version: version,
         files,
-        skyline_version: skyline_version.unwrap_or("0.0.0".parse().unwrap()),
+        skyline_version,
         beta: beta.unwrap_or(false),
         metadata,
+        deleted_files,
     }))
 }
 
@@ -229,5 +235,6 @@ pub fn get() -> eyre::Result<Vec<Plugin>> {
         skyline_version: None,
         beta: Some(false),
         metadata: None,
+        deleted_files: vec![],
     }).unwrap());
 }*/
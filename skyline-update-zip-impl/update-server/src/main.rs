@@ -12,8 +12,8 @@ use std::io::{prelude::*, BufReader};
 
 use color_eyre::eyre;
 
-use semver::Version;
-use update_protocol::{InstallLocation, Request, UpdateResponse, ResponseCode, UpdateFile, PluginMetadata};
+use semver::{Version, VersionReq};
+use update_protocol::{InstallLocation, Request, UpdateResponse, ResponseCode, UpdateFile, PluginMetadata, UpdateRequestOptions};
 
 struct PluginFile {
     install: InstallLocation,
@@ -37,20 +37,68 @@ struct Plugin {
     pub files: Vec<PluginFile>,
     pub metadata_files: Vec<Arc<Vec<u8>>>,
     pub metadata: PluginMetadata,
-    pub skyline_version: Version,
+    pub skyline_version: Option<Version>,
     pub beta: bool,
+    pub deleted_files: Vec<String>,
+}
+
+/* whether the client's skyline is older than the plugin needs, false if either side is unknown.
+   pre-release skylines only satisfy a pre-release requirement of the same version */
+fn needs_skyline_update(plugin: &Plugin, skyline_version: Option<&str>) -> bool {
+    let skyline_version = skyline_version.and_then(|version| version.trim().parse::<Version>().ok());
+    match (&plugin.skyline_version, skyline_version) {
+        (Some(required), Some(version)) => VersionReq::parse(&format!(">={}", required))
+            .map(|requirement| !requirement.matches(&version))
+            .unwrap_or(false),
+        _ => false,
+    }
+}
+
+fn update_response(plugins: &[Plugin], plugin_name: String, plugin_version: String, beta: Option<bool>, options: Option<UpdateRequestOptions>) -> UpdateResponse {
+    let beta = beta.unwrap_or(false);
+    let skyline_version = options.and_then(|options| options.skyline_version);
+    let plugin = plugins.iter().filter(|plugin| {
+        plugin.name == plugin_name && (beta || !plugin.beta)
+    }).max_by_key(|plugin| &plugin.plugin_version);
+
+    if let Some(plugin) = plugin {
+        if let Ok(current_version) = plugin_version.parse::<Version>() {
+            if current_version < plugin.plugin_version {
+                UpdateResponse {
+                    code: ResponseCode::Update,
+                    update_plugin: true,
+                    update_skyline: needs_skyline_update(plugin, skyline_version.as_deref()),
+                    plugin_name,
+                    new_plugin_version: plugin.plugin_version.to_string(),
+                    new_skyline_version: None,
+                    required_files: plugin.files.iter().map(|file| file.into()).collect(),
+                    keep_alive_downloads: true,
+                    files_to_delete: plugin.deleted_files.clone(),
+                }
+            } else {
+                UpdateResponse::no_update()
+            }
+        } else {
+            UpdateResponse::invalid_request()
+        }
+    } else {
+        UpdateResponse::plugin_not_found()
+    }
 }
 
 const PORT_NUM: u16 = 45000;
 
 fn setup_plugin_ports() -> eyre::Result<(Vec<Plugin>, Vec<Arc<Vec<u8>>>)> {
-    let plugins = hosted_plugins::get()?;
+    serve_plugins(hosted_plugins::get()?)
+}
 
+/* give every file and metadata file of the plugins a download index, returning the files in index order */
+fn serve_plugins(plugins: Vec<hosted_plugins::Plugin>) -> eyre::Result<(Vec<Plugin>, Vec<Arc<Vec<u8>>>)> {
     let mut i = 0;
     let plugins: Vec<Plugin> = plugins.into_iter()
         .map(|plugin|{
             let hosted_plugins::Plugin {
-                name, plugin_version, files, skyline_version, beta, metadata
+                name, plugin_version, files, skyline_version, beta, metadata, deleted_files
             } = plugin;
 
             let files = files.into_iter()
@@ -79,12 +127,14 @@ fn setup_plugin_ports() -> eyre::Result<(Vec<Plugin>, Vec<Arc<Vec<u8>>>)> {
                 changelog_index: i + image_count,
             };
 
-            let metadata_files = images.into_iter()
+            /* the changelog index is always taken, by an empty file if there is no changelog, so it never points at the next plugin's files */
+            let metadata_files: Vec<_> = images.into_iter()
                 .map(|images| images.into_iter())
                 .flatten()
                 .map(|image| Arc::new(image))
-                .chain(changelog.into_iter().map(|x| Arc::new(x.into_bytes())))
+                .chain(std::iter::once(Arc::new(changelog.unwrap_or_default().into_bytes())))
                 .collect();
+            i += metadata_files.len() as u64;
 
             Ok(Plugin {
                 name,
@@ -93,13 +143,15 @@ fn setup_plugin_ports() -> eyre::Result<(Vec<Plugin>, Vec<Arc<Vec<u8>>>)> {
                 files,
                 metadata_files,
                 metadata,
-                beta
+                beta,
+                deleted_files,
             })
         })
         .collect::<eyre::Result<_>>()?;
 
+    /* served in the order indexes were given out, so each file sits at its index */
     let files = plugins.iter()
-        .map(|plugin| plugin.files.iter().map(|file| Arc::clone(&file.data)))
+        .map(|plugin| plugin.files.iter().map(|file| Arc::clone(&file.data)).chain(plugin.metadata_files.iter().cloned()))
         .flatten()
         .collect();
 
@@ -182,35 +234,8 @@ fn main() -> eyre::Result<()> {
                     }}
                 }
                 match serde_json::from_str::<Request>(&packet) {
-                    Ok(Request::Update { plugin_name, plugin_version, beta, .. }) => {
-                        let beta = beta.unwrap_or(false);
-                        let plugin = plugins.iter().filter(|plugin| {
-                            plugin.name == plugin_name && (beta || !plugin.beta)
-                        }).max_by_key(|plugin| &plugin.plugin_version);
-
-                        let response = if let Some(plugin) = plugin {
-                            if let Ok(current_version) = plugin_version.parse::<Version>() {
-                                if current_version < plugin.plugin_version {
-                                    UpdateResponse {
-                                        code: ResponseCode::Update,
-                                        update_plugin: true,
-                                        update_skyline: false,
-                                        plugin_name,
-                                        new_plugin_version: plugin.plugin_version.to_string(),
-                                        new_skyline_version: None,
-                                        required_files: plugin.files.iter().map(|file| file.into()).collect()
-                                    }
-                                } else {
-                                    UpdateResponse::no_update()
-                                }
-                            } else {
-                                UpdateResponse::invalid_request()
-                            }
-                        } else {
-                            UpdateResponse::plugin_not_found()
-                        };
-
-                        respond!(response);
+                    Ok(Request::Update { plugin_name, plugin_version, beta, options }) => {
+                        respond!(update_response(plugins, plugin_name, plugin_version, beta, options));
                     }
                     Ok(Request::Metadata { plugin_name, beta, .. }) => {
                         let beta = beta.unwrap_or(false);
@@ -229,6 +254,26 @@ fn main() -> eyre::Result<()> {
             while let Ok((mut socket, _)) = download_port.accept() {
                 let mut buf = [0; 8];
                 if let Ok(_) = socket.read_exact(&mut buf) {
+                    if u64::from_be_bytes(buf) == update_protocol::KEEP_ALIVE_INDEX {
+                        let files = files.clone();
+                        scope.spawn(move |_| {
+                            /* send each file asked for after its length, until the client closes the connection or goes idle */
+                            let _ = socket.set_read_timeout(Some(Duration::from_secs(30)));
+                            while let Ok(_) = socket.read_exact(&mut buf) {
+                                let file = match files.get(u64::from_be_bytes(buf) as usize) {
+                                    Some(file) => file,
+                                    None => break,
+                                };
+                                let sent = socket.write_all(&(file.len() as u64).to_be_bytes())
+                                    .and_then(|_| socket.write_all(file));
+                                if sent.is_err() {
+                                    break;
+                                }
+                            }
+                        });
+                        continue;
+                    }
+
                     let index = u64::from_be_bytes(buf) as usize;
                     if let Some(file) = files.get(index) {
                         let data = Arc::clone(&file);
@@ -246,3 +291,121 @@ fn main() -> eyre::Result<()> {
         }
     }).unwrap()
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn test_plugin(skyline_version: Option<&str>) -> Plugin {
+        Plugin {
+            name: "test_plugin".into(),
+            plugin_version: "1.0.0".parse().unwrap(),
+            files: vec![PluginFile {
+                install: InstallLocation::AbsolutePath("sd:/test.txt".into()),
+                data: Arc::new(b"hello".to_vec()),
+                index: 0,
+            }],
+            metadata_files: vec![],
+            metadata: PluginMetadata { name: None, description: None, images_index: 1, image_count: 0, changelog_index: 1 },
+            skyline_version: skyline_version.map(|version| version.parse().unwrap()),
+            beta: false,
+            deleted_files: vec![],
+        }
+    }
+
+    #[test]
+    fn test_skyline_requirement() {
+        let plugin = test_plugin(Some("0.3.0"));
+        assert!(needs_skyline_update(&plugin, Some("0.2.0")));
+        assert!(!needs_skyline_update(&plugin, Some("0.3.0")));
+        assert!(!needs_skyline_update(&plugin, None));
+        assert!(!needs_skyline_update(&test_plugin(None), Some("0.2.0")));
+
+        // pre-release skylines only satisfy a pre-release requirement of the same version
+        assert!(needs_skyline_update(&plugin, Some("0.3.0-beta.1")));
+        assert!(needs_skyline_update(&plugin, Some("0.4.0-beta.1")));
+        let plugin = test_plugin(Some("0.3.0-beta.2"));
+        assert!(needs_skyline_update(&plugin, Some("0.3.0-beta.1")));
+        assert!(!needs_skyline_update(&plugin, Some("0.3.0-beta.2")));
+        assert!(!needs_skyline_update(&plugin, Some("0.3.0")));
+        assert!(!needs_skyline_update(&plugin, Some("0.4.0")));
+
+        // versions which don't parse are unknown
+        assert!(!needs_skyline_update(&plugin, Some("latest")));
+    }
+
+    #[test]
+    fn test_skyline_requirement_response() {
+        let plugins = vec![test_plugin(Some("0.3.0"))];
+        let request = |skyline_version: Option<&str>| {
+            let mut options = UpdateRequestOptions::default();
+            options.skyline_version = skyline_version.map(str::to_owned);
+            update_response(&plugins, "test_plugin".into(), "0.9.0".into(), None, Some(options))
+        };
+
+        assert!(request(Some("0.2.0")).update_skyline);
+
+        // unknown or satisfied, the update goes ahead without skyline
+        assert!(!request(Some("0.3.0")).update_skyline);
+        assert!(!request(None).update_skyline);
+        assert!(!update_response(&plugins, "test_plugin".into(), "0.9.0".into(), None, None).update_skyline);
+    }
+
+    #[test]
+    fn test_deleted_files() {
+        let toml = r#"
+            name = "test_plugin"
+            version = "1.0.0"
+            files = []
+            deleted_files = ["sd:/atmosphere/contents/01006A800016E000/romfs/skyline/plugins/old.nro"]
+        "#;
+        let plugin_toml: hosted_plugins::PluginToml = toml::from_str(toml).unwrap();
+        let plugins = vec![Plugin { deleted_files: plugin_toml.deleted_files, ..test_plugin(None) }];
+
+        let response = update_response(&plugins, "test_plugin".into(), "0.9.0".into(), None, None);
+        assert_eq!(response.files_to_delete, vec!["sd:/atmosphere/contents/01006A800016E000/romfs/skyline/plugins/old.nro"]);
+
+        // nothing to delete without an update
+        let response = update_response(&plugins, "test_plugin".into(), "1.0.0".into(), None, None);
+        assert!(response.files_to_delete.is_empty());
+    }
+
+    fn hosted_plugin(name: &str, images: Option<Vec<&str>>, changelog: Option<&str>) -> hosted_plugins::Plugin {
+        hosted_plugins::Plugin {
+            name: name.into(),
+            plugin_version: "1.0.0".parse().unwrap(),
+            files: vec![(InstallLocation::AbsolutePath(format!("sd:/{}.nro", name)), name.as_bytes().to_vec())],
+            skyline_version: None,
+            beta: false,
+            metadata: hosted_plugins::Metadata {
+                images: images.map(|images| images.into_iter().map(|image| image.as_bytes().to_vec()).collect()),
+                changelog: changelog.map(str::to_owned),
+                ..Default::default()
+            },
+            deleted_files: vec![],
+        }
+    }
+
+    #[test]
+    fn test_metadata_indexes() {
+        let (plugins, files) = serve_plugins(vec![
+            hosted_plugin("first_plugin", Some(vec!["image 1", "image 2"]), Some("changes")),
+            hosted_plugin("second_plugin", None, None),
+            hosted_plugin("third_plugin", Some(vec!["image 3"]), Some("more changes")),
+        ]).unwrap();
+        let file = |index: u64| String::from_utf8(files[index as usize].to_vec()).unwrap();
+
+        for (plugin, images, changelog) in [
+            (&plugins[0], vec!["image 1", "image 2"], "changes"),
+            (&plugins[1], vec![], ""),
+            (&plugins[2], vec!["image 3"], "more changes"),
+        ].iter() {
+            let metadata = &plugin.metadata;
+            assert_eq!(metadata.image_count, images.len() as u64);
+            let served: Vec<_> = (0..metadata.image_count).map(|i| file(metadata.images_index + i)).collect();
+            assert_eq!(&served, images);
+            assert_eq!(file(metadata.changelog_index), *changelog);
+            assert_eq!(file(plugin.files[0].index), plugin.name);
+        }
+    }
+}